@@ -1,5 +1,3 @@
-use cc;
-
 fn main() {
     cc::Build::new().file("src/snow2_fast.c").compile("c_snow2");
     println!("cargo:rerun-if-changed=src/snow2.h");
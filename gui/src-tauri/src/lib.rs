@@ -610,7 +610,7 @@ async fn list_sequence_contents(app: tauri::AppHandle, folder: String, key: Opti
                     }
                 }
             } else {
-                if let Ok(entries) = pack_v1::run_list_v1_data(&path_str) {
+                if let Ok(entries) = pack_v1::run_list_v1_data(&path_str, &mabi_pack2::common::PackSettings::default()) {
                     for e in entries {
                         all_entries.push(AggregateEntry {
                             name: e.name, source_archive: path_str.clone(), salt_used: "N/A".into(), entries_salt_used: "N/A".into(),
@@ -650,7 +650,7 @@ async fn list_pack_contents(app: tauri::AppHandle, input: String, key: Option<St
     let salts = load_salts();
     
     if input.to_lowercase().ends_with(".pack") { 
-        match pack_v1::run_list_v1_data(&input) {
+        match pack_v1::run_list_v1_data(&input, &mabi_pack2::common::PackSettings::default()) {
             Ok(data_entries) => {
                 let entries = data_entries.into_iter().map(|e| AggregateEntry {
                     name: e.name, source_archive: input.clone(), salt_used: "N/A".into(), entries_salt_used: "N/A".into(),
@@ -763,7 +763,7 @@ async fn create_archive(app: tauri::AppHandle, input: String, output: String, ke
             e.to_string()
         })
     } else {
-        pack::run_pack(&input, &output, &key, fmts, config.auto_convert_dds, actual_iv, prefix, Some(&cb)).map_err(|e: anyhow::Error| {
+        pack::run_pack(&input, &output, &key, fmts, config.auto_convert_dds, actual_iv, prefix, Some(&cb)).map(|_| ()).map_err(|e: anyhow::Error| {
             log::error!("[GUI] .it creation failed: {}", e);
             e.to_string()
         })
@@ -792,7 +792,7 @@ async fn extract_pack_to(app: tauri::AppHandle, input: String, output: String, k
     let _ = fs::create_dir_all(&output);
 
     if input.to_lowercase().ends_with(".pack") {
-        pack_v1::run_extract_v1(&input, &output).map_err(|e| format!("Legacy .pack extraction failed: {}", e))
+        pack_v1::run_extract_v1(&input, &output, &mabi_pack2::common::PackSettings::default()).map_err(|e| format!("Legacy .pack extraction failed: {}", e))
     } else {
         extract::run_extract_with_key_search(&input, &output, key, &salts, filters, Some(config.region_key), config.auto_convert_png, Some(&cb)).map(|_| ()).map_err(|e| format!("Extraction failed: {}", e))
     }
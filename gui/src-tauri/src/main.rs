@@ -324,7 +324,7 @@ fn run_cli_logic() -> Result<()> {
             debug!("[CLI] Packing modern .it with key {} and {} compression overrides.", key, filters.len());
             let wrap = sub_matches.get_flag("wrap-data");
             let path_prefix = if wrap { Some("data") } else { None };
-            return pack::run_pack(input, output, key, filters, false, 0, path_prefix, None);
+            return pack::run_pack(input, output, key, filters, false, 0, path_prefix, None).map(|_| ());
         }
     }
 
@@ -340,7 +340,7 @@ fn run_cli_logic() -> Result<()> {
             return Ok(());
         } else {
             debug!("[CLI] Listing modern .it with search.");
-            return list::run_list_with_key_search(input, key, &salts, output);
+            return list::run_list_with_key_search(input, key, &salts, output).map(|_| ());
         }
     }
 
@@ -0,0 +1,189 @@
+// backup.rs - Backups of files overwritten by extraction, and restoring them
+
+use crate::common;
+use anyhow::{Context, Error};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Written into the backup folder by `extract --backup-dir`.
+pub const MANIFEST_FILE: &str = "backup_manifest.json";
+
+/// What an extraction changed in its output folder, so `run_restore` can undo it.
+/// Paths are relative to `target` and use `/`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Output folder the backup belongs to.
+    #[serde(default)]
+    pub target: String,
+    /// Files copied into the backup folder before being overwritten.
+    #[serde(default)]
+    pub backed_up: BTreeSet<String>,
+    /// Files that didn't exist before; restoring deletes them.
+    #[serde(default)]
+    pub created: BTreeSet<String>,
+}
+
+fn rel_key(rel: &str) -> String {
+    rel.replace('\\', "/")
+}
+
+fn native(root: &Path, rel: &str) -> PathBuf {
+    root.join(rel.replace(['/', '\\'], std::path::MAIN_SEPARATOR_STR))
+}
+
+/// Why the manifest path `rel` can't be restored or deleted under `root` (see
+/// `common::unsafe_path_reason`). A link left where the file goes is replaced, not
+/// followed, so then only the folder above it has to resolve inside `root`. Manifests
+/// are checked whatever `--allow-unsafe-paths` says.
+fn restore_path_reason(root: &str, rel: &str) -> Option<String> {
+    let name_len = rel.rsplit(['/', '\\']).next().map_or(0, str::len);
+    let checked = if native(Path::new(root), rel).is_symlink() { &rel[..rel.len() - name_len] } else { rel };
+    common::unsafe_path_reason(root, checked, &common::PackSettings::default())
+}
+
+fn target_id(target: &str) -> String {
+    std::fs::canonicalize(target).map(|p| p.to_string_lossy().into_owned()).unwrap_or_else(|_| target.to_string())
+}
+
+impl BackupManifest {
+    /// The manifest in `backup_dir` (empty if there is none).
+    pub fn load(backup_dir: &str) -> Result<Self, Error> {
+        let path = Path::new(backup_dir).join(MANIFEST_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).context(format!("reading {} failed", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BackupManifest::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Load the manifest for extracting into `target`; a backup folder serves one target only.
+    pub fn open(backup_dir: &str, target: &str) -> Result<Self, Error> {
+        let mut manifest = Self::load(backup_dir)?;
+        let id = target_id(target);
+        if manifest.target.is_empty() {
+            manifest.target = id;
+        } else if manifest.target != id {
+            return Err(Error::msg(format!("Backup folder '{}' belongs to '{}', not '{}'", backup_dir, manifest.target, id)));
+        }
+        Ok(manifest)
+    }
+
+    pub fn save(&self, backup_dir: &str) -> Result<(), Error> {
+        std::fs::create_dir_all(backup_dir)?;
+        std::fs::write(Path::new(backup_dir).join(MANIFEST_FILE), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Call before writing `root/rel`: copies an existing file into `backup_dir` unless it
+    /// already has a backup or was created by an earlier extraction. Returns whether a copy was made.
+    pub fn before_write(&mut self, root: &str, backup_dir: &str, rel: &str) -> Result<bool, Error> {
+        let key = rel_key(rel);
+        let existing = native(Path::new(root), &key);
+        if !existing.is_file() || self.backed_up.contains(&key) || self.created.contains(&key) {
+            return Ok(false);
+        }
+        let backup = native(Path::new(backup_dir), &key);
+        if let Some(parent) = backup.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(&existing, &backup).map_err(|e| Error::msg(format!("backing up to {} failed: {}", backup.display(), e)))?;
+        debug!("[BACKUP] {} -> {}", existing.display(), backup.display());
+        self.backed_up.insert(key);
+        Ok(true)
+    }
+
+    /// Call after writing `root/rel`: a file that now exists without a backup is new.
+    pub fn after_write(&mut self, root: &str, rel: &str) {
+        let key = rel_key(rel);
+        if !self.backed_up.contains(&key) && native(Path::new(root), &key).exists() {
+            self.created.insert(key);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub restored: usize,
+    pub deleted: usize,
+}
+
+/// Undo extractions recorded in `backup_dir`: copy the backed-up files back into the
+/// target folder and delete the files the extractions created (pruning folders left empty).
+/// Without a manifest every file in `backup_dir` is copied back into `target`.
+/// `target` overrides the folder recorded in the manifest. With `dry_run` nothing is changed.
+pub fn run_restore(backup_dir: &str, target: Option<&str>, dry_run: bool) -> Result<RestoreReport, Error> {
+    if !Path::new(backup_dir).is_dir() {
+        return Err(Error::msg(format!("Backup folder '{}' not found", backup_dir)));
+    }
+    let has_manifest = Path::new(backup_dir).join(MANIFEST_FILE).is_file();
+    let manifest = if has_manifest {
+        BackupManifest::load(backup_dir)?
+    } else {
+        let files = WalkDir::new(backup_dir)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| e.path().strip_prefix(backup_dir).map(|p| rel_key(&p.to_string_lossy())))
+            .collect::<Result<BTreeSet<String>, _>>()?;
+        BackupManifest { backed_up: files, ..BackupManifest::default() }
+    };
+    let target = match target {
+        Some(t) => t.to_string(),
+        None if !manifest.target.is_empty() => manifest.target.clone(),
+        None => return Err(Error::msg(format!("'{}' has no {}; pass the folder to restore into", backup_dir, MANIFEST_FILE))),
+    };
+    let root = Path::new(&target);
+    // A hand-edited or foreign manifest must not reach files outside the target folder.
+    for rel in manifest.backed_up.iter().chain(&manifest.created) {
+        if let Some(reason) = restore_path_reason(&target, rel) {
+            return Err(Error::msg(format!("Refusing to restore '{}' into '{}': {}", rel, target, reason)));
+        }
+    }
+    info!("[RESTORE] {} '{}' from '{}'{}", if dry_run { "Would restore" } else { "Restoring" }, target, backup_dir, if has_manifest { "" } else { " (no manifest)" });
+
+    let mut report = RestoreReport::default();
+    for rel in &manifest.backed_up {
+        let (from, to) = (native(Path::new(backup_dir), rel), native(root, rel));
+        debug!("[RESTORE] {} -> {}", from.display(), to.display());
+        if !dry_run {
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Extraction may have left a link here (dedupe, symlink entries); don't write through it.
+            if to.is_symlink() {
+                std::fs::remove_file(&to)?;
+            }
+            std::fs::copy(&from, &to).context(format!("restoring {} failed", to.display()))?;
+        }
+        report.restored += 1;
+    }
+    for rel in &manifest.created {
+        let path = native(root, rel);
+        if std::fs::symlink_metadata(&path).is_err() {
+            continue;
+        }
+        debug!("[RESTORE] Deleting {}", path.display());
+        if !dry_run {
+            std::fs::remove_file(&path).context(format!("deleting {} failed", path.display()))?;
+            let mut dir = path.parent();
+            while let Some(d) = dir {
+                if d == root || !d.starts_with(root) || std::fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+        report.deleted += 1;
+    }
+    if !dry_run && has_manifest {
+        if let Err(e) = std::fs::rename(Path::new(backup_dir).join(MANIFEST_FILE), Path::new(backup_dir).join(format!("{}.restored", MANIFEST_FILE))) {
+            warn!("[RESTORE] Could not retire the manifest: {}", e);
+        }
+    }
+    info!("[RESTORE] {} file(s) restored, {} created file(s) deleted", report.restored, report.deleted);
+    Ok(report)
+}
@@ -2,18 +2,20 @@
 
 use clap::{Command, Arg, ArgAction};
 use anyhow::Result;
+use std::ffi::OsString;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 use rayon::prelude::*;
 use simplelog::{CombinedLogger, WriteLogger, TermLogger, LevelFilter, ConfigBuilder, TerminalMode, ColorChoice, SharedLogger};
-use log::{debug, info};
+use log::{debug, info, warn};
 
 // Correct library name from Cargo.toml
-use mabi_pack2::{load_salts, extract, list, pack};
+use mabi_pack2::{load_salts_with_settings, backup, checksum, codec, common, common_ext, dedupe, describe, doctor, edit, encryption, extract, hook, key_cache, list, names, pack, reader, remote, run_summary, snapshot, verify, vfs};
 
 #[cfg(windows)]
 use std::os::windows::process::CommandExt;
@@ -57,10 +59,558 @@ fn num_cpus() -> usize {
     std::thread::available_parallelism().map(|n| n.get() * 2).unwrap_or(8)
 }
 
+/// `--report FILE`: record what the command did as JSON.
+fn report_arg() -> Arg<'static> {
+    Arg::new("report").long("report").value_name("FILE").help("Write a JSON summary (entries, bytes, salt, warnings) to FILE")
+}
+
+fn export_keys_arg() -> Arg<'static> {
+    Arg::new("export-keys").long("export-keys").value_name("FILE").help("Write the cipher settings and every entry's key and derived file key as JSON to FILE")
+}
+
+/// `--export-keys FILE`: reopen `input` and dump its keys (see `PackReader::key_export`).
+/// `cli_key` is normally the salt that just worked, so the search succeeds at once.
+fn export_keys(sub_matches: &clap::ArgMatches, input: &str, cli_key: Option<String>, salts: &[String], settings: &common::PackSettings) -> Result<()> {
+    if let Some(path) = sub_matches.get_one::<String>("export-keys") {
+        let export = mabi_pack2::reader::PackReader::open_with_settings(input, cli_key, salts, settings)?.key_export();
+        export.write_json(path)?;
+        println!("Exported keys of {} entries to '{}'.", export.entries.len(), path);
+    }
+    Ok(())
+}
+
+fn print_protected(summary: &common::OperationSummary) {
+    if !summary.protected.is_empty() {
+        println!("Left {} protected file(s) untouched:", summary.protected.len());
+        for path in &summary.protected {
+            println!("  {}", path);
+        }
+    }
+}
+
+fn print_rejected(summary: &common::OperationSummary) {
+    if !summary.rejected.is_empty() {
+        println!("Rejected {} entry path(s) leaving the output folder (--allow-unsafe-paths writes them):", summary.rejected.len());
+        for path in &summary.rejected {
+            println!("  {}", path);
+        }
+    }
+}
+
+/// Locked output files that were scheduled for replacement or left as they were.
+fn print_locked(summary: &common::OperationSummary) {
+    if !summary.scheduled.is_empty() {
+        println!("{} locked file(s) will be replaced at the next reboot:", summary.scheduled.len());
+        for path in &summary.scheduled {
+            println!("  {}", path);
+        }
+    }
+    if !summary.locked.is_empty() {
+        println!("Could not replace {} file(s) in use by another process (close the game and extract again, or use --retry-locked / --schedule-on-reboot):", summary.locked.len());
+        for path in &summary.locked {
+            println!("  {}", path);
+        }
+    }
+}
+
+/// Per-extension table printed at the end of an extraction, most files first.
+fn print_extension_stats(summary: &common::OperationSummary) {
+    if summary.by_extension.is_empty() {
+        return;
+    }
+    let mut rows: Vec<_> = summary.by_extension.iter().collect();
+    rows.sort_by(|a, b| b.1.files.cmp(&a.1.files).then_with(|| a.0.cmp(b.0)));
+    println!("Extracted {} file(s), {}:", summary.entries, mabi_pack2::info::human_size(summary.bytes));
+    for (ext, count) in rows {
+        println!("  {:<12} {:>8}  {:>10}", ext, count.files, mabi_pack2::info::human_size(count.bytes));
+    }
+}
+
+/// Entry warnings printed before the rest are only counted (all of them go to `--report`).
+const SHOWN_ENTRY_WARNINGS: usize = 20;
+
+/// Log the run's suspicious entries, then write `--report`.
+fn write_report(sub_matches: &clap::ArgMatches, summary: &common::OperationSummary) -> Result<()> {
+    run_summary::record_operation(summary);
+    let suspicious = &summary.entry_warnings;
+    if !suspicious.is_empty() {
+        warn!("[CHECK] {} suspicious entr{} (processed anyway):", suspicious.len(), if suspicious.len() == 1 { "y" } else { "ies" });
+        for w in suspicious.iter().take(SHOWN_ENTRY_WARNINGS) {
+            warn!("[CHECK]   {}: {}", w.entry, w.detail);
+        }
+        if suspicious.len() > SHOWN_ENTRY_WARNINGS {
+            warn!("[CHECK]   ... and {} more (all listed by --report)", suspicious.len() - SHOWN_ENTRY_WARNINGS);
+        }
+    }
+    if let Some(path) = sub_matches.get_one::<String>("report") {
+        summary.write_json(path)?;
+        debug!("[CLI] Report written to '{}'", path);
+    }
+    Ok(())
+}
+
+/// `--protect GLOB` (repeatable): existing files that extraction must not overwrite.
+fn protect_arg() -> Arg<'static> {
+    Arg::new("protect")
+        .long("protect")
+        .value_name("GLOB")
+        .action(ArgAction::Append)
+        .help("Never overwrite existing files matching this glob (e.g. \"*.ini\", \"config/\"); repeatable")
+}
+
+/// `--as-name NAME`: the pack file name keys derive from when the pack comes from stdin
+/// (`-i -`), goes to stdout (`-o -`), or was renamed on disk.
+fn as_name_arg() -> Arg<'static> {
+    Arg::new("as-name")
+        .long("as-name")
+        .value_name("PACK_NAME")
+        .help("File name to derive keys from: the name a renamed pack was packed under, or the pack's name when it is read from stdin (-i -) or written to stdout (-o -)")
+}
+
+/// `--as-name` for a pack on disk: keys derive from that name first, like `--original-name`.
+fn apply_as_name(sub_matches: &clap::ArgMatches, settings: &mut common::PackSettings) -> Result<()> {
+    let Some(name) = sub_matches.get_one::<String>("as-name") else { return Ok(()) };
+    if sub_matches.get_many::<String>("input").is_some_and(|inputs| inputs.count() > 1) {
+        anyhow::bail!("--as-name names a single pack; it can't be used with several -i packs");
+    }
+    settings.original_name = Some(common::get_final_file_name(name)?);
+    Ok(())
+}
+
+/// A spooled copy of stdin when `-i -` was given, named after `--as-name`. For a pack on
+/// disk, `--as-name` is applied with `apply_as_name` instead.
+fn stdin_spool(sub_matches: &clap::ArgMatches, settings: &mut common::PackSettings) -> Result<Option<common::StdioSpool>> {
+    if sub_matches.get_one::<String>("input").map(|s| s.as_str()) != Some("-") {
+        apply_as_name(sub_matches, settings)?;
+        return Ok(None);
+    }
+    let name = sub_matches.get_one::<String>("as-name")
+        .ok_or_else(|| anyhow::anyhow!("Reading a pack from stdin (-i -) needs --as-name PACK_NAME: keys derive from the file name"))?;
+    // Spool paths are new every run; don't fill the key cache with them.
+    key_cache::disable();
+    Ok(Some(common::StdioSpool::from_reader(name, &mut std::io::stdin().lock())?))
+}
+
+/// A mirror of the pack at `-i http(s)://...`, or `None` for a local input. Range requests
+/// fetch its header and entries table and, when `exclude` is given, the stored bytes of
+/// the entries matching the filters and none of `exclude`. `cli_key` becomes the salt that
+/// opened it, so list and extract can read the mirror like a pack on disk.
+fn remote_mirror(sub_matches: &clap::ArgMatches, cli_key: &mut Option<String>, all_salts: &mut Vec<String>, settings: &common::PackSettings, exclude: Option<&[String]>) -> Result<Option<remote::RemotePack>> {
+    let Some(url) = sub_matches.get_one::<String>("input").filter(|i| remote::is_url(i)) else { return Ok(None) };
+    let mut pack = remote::RemotePack::open(url, sub_matches.get_one::<String>("as-name").map(|s| s.as_str()), settings)?;
+    let hit = pack.search_keys(cli_key.clone(), all_salts, settings)?;
+    // Mirror paths are new every run; don't fill the key cache with them.
+    key_cache::disable();
+    if let Some(exclude) = exclude {
+        let filters = extract::compile_filters(&filter_values(sub_matches)?, settings.filters)?;
+        let exclude = extract::compile_filters(exclude, settings.filters)?;
+        pack.fetch_entries(hit.content_offset, hit.entries.iter().filter(|e| {
+            (filters.is_empty() || filters.iter().any(|re| re.is_match(&e.name))) && !exclude.iter().any(|re| re.is_match(&e.name))
+        }))?;
+    }
+    info!("[REMOTE] Downloaded {} of {} bytes from '{}'", pack.downloaded(), pack.len(), url);
+    if !all_salts.contains(&hit.entries_salt) {
+        all_salts.push(hit.entries_salt.clone());
+    }
+    *cli_key = Some(hit.header_salt);
+    Ok(Some(pack))
+}
+
+/// `--filter-file FILE` (repeatable): filters read from FILE, one per line.
+fn filter_file_arg() -> Arg<'static> {
+    Arg::new("filter-file")
+        .long("filter-file")
+        .value_name("FILE")
+        .action(ArgAction::Append)
+        .help("Read filters from FILE, one per line ('#' starts a comment, 'glob:' marks a glob); repeatable")
+}
+
+/// `--glob PATTERN` (repeatable): a filter written as a glob instead of a regex.
+fn glob_arg() -> Arg<'static> {
+    Arg::new("glob")
+        .short('g')
+        .long("glob")
+        .value_name("PATTERN")
+        .action(ArgAction::Append)
+        .help("Only take entries matching this glob, e.g. \"gfx/char/**/*.dds\" (same as -f \"glob:PATTERN\"); repeatable, combined with -f")
+}
+
+/// Every `--filter` value, then every `--glob` as a `glob:` filter, then the patterns of
+/// every `--filter-file`. An entry matching any of them is taken.
+fn filter_values(sub_matches: &clap::ArgMatches) -> Result<Vec<String>> {
+    let mut filters: Vec<String> = sub_matches.get_many::<String>("filter").map_or(Vec::new(), |v| v.cloned().collect());
+    filters.extend(sub_matches.get_many::<String>("glob").into_iter().flatten().map(|g| format!("{}{}", extract::GLOB_FILTER_PREFIX, g)));
+    for path in sub_matches.get_many::<String>("filter-file").into_iter().flatten() {
+        let patterns = extract::read_filter_file(path)?;
+        debug!("[CLI] {} filter(s) read from '{}'", patterns.len(), path);
+        filters.extend(patterns);
+    }
+    Ok(filters)
+}
+
+/// `--exclude PATTERN` (repeatable): entries or input files to leave out.
+fn exclude_arg() -> Arg<'static> {
+    Arg::new("exclude")
+        .short('x')
+        .long("exclude")
+        .value_name("PATTERN")
+        .action(ArgAction::Append)
+        .help("Leave out entries matching this regex ('glob:' marks a glob, e.g. \"glob:sound/\"); repeatable, applied after --filter")
+}
+
+/// `--sanitize-names MODE`: see `names::SanitizeMode`.
+fn sanitize_names_arg() -> Arg<'static> {
+    Arg::new("sanitize-names")
+        .long("sanitize-names")
+        .value_name("MODE")
+        .default_value("none")
+        .help("Rename entries Windows can't create (reserved characters, CON/NUL/..., trailing dots): none, percent or replace; originals are kept in .mabi-names.json for 'pack'")
+}
+
+/// The `--sanitize-names` mode; `--untrusted` turns on `percent` when none was chosen.
+fn sanitize_mode(sub_matches: &clap::ArgMatches, settings: &common::PackSettings) -> Result<names::SanitizeMode> {
+    let mode = sub_matches.get_one::<String>("sanitize-names").unwrap().parse()?;
+    Ok(if mode == names::SanitizeMode::None && settings.untrusted { names::SanitizeMode::Percent } else { mode })
+}
+
+/// How link entries are extracted; `--untrusted` always leaves them out.
+fn symlink_policy(sub_matches: Option<&clap::ArgMatches>, settings: &common::PackSettings) -> Result<extract::SymlinkPolicy> {
+    if settings.untrusted {
+        return Ok(extract::SymlinkPolicy::Skip);
+    }
+    sub_matches.map_or(Ok(extract::SymlinkPolicy::default()), |m| m.get_one::<String>("symlinks").unwrap().parse())
+}
+
+/// `--content-addressed[=INDEX]`: store files by digest (see `dedupe::ObjectStore`).
+fn content_addressed_arg() -> Arg<'static> {
+    Arg::new("content-addressed")
+        .long("content-addressed")
+        .value_name("INDEX")
+        .min_values(0)
+        .require_equals(true)
+        .default_missing_value("")
+        .conflicts_with("protect")
+        .help("Write each distinct content once as objects/ab/cdef... (its MD5) plus an md5sum-style INDEX of entry names (default: OUTPUT/index.md5)")
+}
+
+/// Write the `--content-addressed` index once every entry has been stored.
+fn finish_object_store(sub_matches: &clap::ArgMatches, store: &dedupe::ObjectStore, output: &str) -> Result<()> {
+    let index = match sub_matches.get_one::<String>("content-addressed").map(|s| s.as_str()) {
+        None => return Ok(()),
+        Some("") => Path::new(output).join("index.md5").to_string_lossy().into_owned(),
+        Some(path) => path.to_string(),
+    };
+    let lines = store.write_index(&index)?;
+    let (stored, reused) = store.counts();
+    println!("Stored {} new object(s), {} already present; index of {} entries written to '{}'.", stored, reused, lines, index);
+    Ok(())
+}
+
+/// `--pack-parallel N`: packs processed at once, sharing the `--threads` entry workers.
+fn pack_parallel_arg() -> Arg<'static> {
+    Arg::new("pack-parallel")
+        .long("pack-parallel")
+        .value_name("N")
+        .default_value("1")
+        .help("Packs processed at once, sharing the --threads entry workers (0 = one per CPU core); raise it for many small packs")
+}
+
+/// `input` itself, or the `.it` packs directly inside it (sorted) when it is a folder.
+fn it_archives(input: &str) -> Result<Vec<String>> {
+    if !Path::new(input).is_dir() {
+        return Ok(vec![input.to_string()]);
+    }
+    let mut found: Vec<String> = std::fs::read_dir(input)?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("it")))
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    found.sort();
+    if found.is_empty() {
+        anyhow::bail!("No .it archives found in '{}'", input);
+    }
+    Ok(found)
+}
+
+/// Noun-verb spellings and the flat subcommand (plus implied arguments) each one runs.
+/// The flat names stay the canonical ones so existing scripts keep working.
+const NOUN_VERB_COMMANDS: &[(&str, &str, &[&str])] = &[
+    ("pack", "create", &["pack"]),
+    ("pack", "extract", &["extract"]),
+    ("pack", "list", &["list"]),
+    ("pack", "info", &["info"]),
+    ("pack", "describe", &["describe-format"]),
+    ("pack", "fingerprint", &["fingerprint"]),
+    ("pack", "verify", &["verify"]),
+    ("pack", "update", &["update"]),
+    ("entry", "cat", &["extract", "--to-stdout"]),
+    ("entry", "cp", &["cp"]),
+    ("entry", "mv", &["mv"]),
+    ("entry", "set-flags", &["set-flags"]),
+    ("key", "search", &["which-key"]),
+    ("key", "derive", &["keys"]),
+    ("salt", "list", &["salts"]),
+    ("keystream", "export", &["keystream"]),
+];
+
+/// Global options that take a separate value (`--opt VALUE`), skipped when looking for the subcommand.
+fn global_value_options(cli: &Command) -> Vec<String> {
+    cli.get_arguments()
+        .filter(|a| a.is_global_set() && a.is_takes_value_set() && !a.is_require_equals_set())
+        .flat_map(|a| a.get_long().map(|l| format!("--{}", l)).into_iter().chain(a.get_short().map(|s| format!("-{}", s))))
+        .collect()
+}
+
+/// Rewrite `key search ...` style invocations to the flat subcommand they stand for.
+fn expand_noun_verb(cli: &Command, args: Vec<OsString>) -> Vec<OsString> {
+    let value_options = global_value_options(cli);
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].to_string_lossy();
+        if value_options.iter().any(|o| o == arg.as_ref()) {
+            i += 2;
+        } else if arg.starts_with('-') {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    let (Some(noun), Some(verb)) = (args.get(i), args.get(i + 1)) else { return args };
+    let Some((_, _, flat)) = NOUN_VERB_COMMANDS.iter().find(|(n, v, _)| noun == n && verb == v) else { return args };
+    let mut expanded = args[..i].to_vec();
+    expanded.extend(flat.iter().map(OsString::from));
+    expanded.extend_from_slice(&args[i + 2..]);
+    expanded
+}
+
+/// The salt from `--key` or, with `--key-id`, the salt registered under that alias.
+fn cli_key_arg(sub_matches: &clap::ArgMatches, settings: &common::PackSettings) -> Result<Option<String>> {
+    if let Some(id) = sub_matches.get_one::<String>("key-id") {
+        return Ok(Some(mabi_pack2::resolve_key_id(id, settings)?));
+    }
+    Ok(sub_matches.get_one::<String>("key").map(|s| s.to_string()))
+}
+
+/// `--script FILE`: a Rhai hook (see `hook::ScriptHook`), in builds with the `scripting` feature.
+fn script_arg() -> Arg<'static> {
+    Arg::new("script")
+        .long("script")
+        .value_name("FILE")
+        .help("Rhai script whose rename(name) / transform(name, data) functions rewrite entry names and contents (needs a build with --features scripting)")
+}
+
+/// The hook loaded from `--script`, if one was given.
+fn script_hook(sub_matches: &clap::ArgMatches, direction: hook::HookDirection, settings: &common::PackSettings) -> Result<Option<Arc<dyn hook::EntryHook>>> {
+    let Some(path) = sub_matches.get_one::<String>("script") else { return Ok(None) };
+    if settings.untrusted {
+        anyhow::bail!("--script can't be used with --untrusted");
+    }
+    #[cfg(feature = "scripting")]
+    return Ok(Some(Arc::new(hook::ScriptHook::load(path, direction)?)));
+    #[cfg(not(feature = "scripting"))]
+    {
+        let _ = direction;
+        anyhow::bail!("--script {}: this build has no scripting support (rebuild with --features scripting)", path)
+    }
+}
+
+/// `--codec EXT=CODEC` (repeatable) and `--codecs FILE`: content codecs (see `codec::CodecRegistry`).
+fn codec_args() -> [Arg<'static>; 2] {
+    [
+        Arg::new("codec")
+            .long("codec")
+            .value_name("EXT=CODEC")
+            .action(ArgAction::Append)
+            .conflicts_with("script")
+            .help("Keep .EXT entries in their game format but work with friendlier files on disk: utf8 (UTF-16 text as UTF-8), png (DDS textures as PNG) or raw; repeatable"),
+        Arg::new("codecs")
+            .long("codecs")
+            .value_name("FILE")
+            .conflicts_with("script")
+            .help("Read --codec rules from FILE, one EXT=CODEC per line"),
+    ]
+}
+
+/// The hook for `--script`, or else for the `--codec` / `--codecs` rules.
+fn entry_hook(sub_matches: &clap::ArgMatches, direction: hook::HookDirection, settings: &common::PackSettings) -> Result<Option<Arc<dyn hook::EntryHook>>> {
+    if let Some(script) = script_hook(sub_matches, direction, settings)? {
+        return Ok(Some(script));
+    }
+    let mut registry = match sub_matches.get_one::<String>("codecs") {
+        Some(path) => codec::CodecRegistry::load(path)?,
+        None => codec::CodecRegistry::new(),
+    };
+    for rule in sub_matches.get_many::<String>("codec").into_iter().flatten() {
+        registry.add_rule(rule)?;
+    }
+    Ok((!registry.is_empty()).then(|| Arc::new(registry.hook(direction)) as Arc<dyn hook::EntryHook>))
+}
+
+/// A 16-byte key given to `--ARG` as 32 hex digits.
+fn parse_key_hex(arg: &str, hex: &str) -> Result<[u8; 16]> {
+    let bytes: Vec<u8> = (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect::<Option<_>>()
+        .filter(|b: &Vec<u8>| b.len() == 16)
+        .ok_or_else(|| anyhow::anyhow!("--{} expects 32 hex digits, got '{}'", arg, hex))?;
+    Ok(bytes.try_into().unwrap())
+}
+
+/// Digests for `extract --verify-content`: the `manifest` file, or the ones stored in the
+/// pack by `pack --content-hashes` when no manifest was given.
+fn load_content_hashes(input: &str, manifest: &str) -> Result<verify::ContentHashes> {
+    if !manifest.is_empty() {
+        return verify::load_manifest(manifest);
+    }
+    let file = std::fs::File::open(input)?;
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    match pack::PackMetadata::read_from(&mmap) {
+        Some(meta) if !meta.content_hashes.is_empty() => Ok(meta.content_hashes),
+        _ => anyhow::bail!("'{}' has no stored content digests; pass --verify-content=MANIFEST", input),
+    }
+}
+
+/// Arguments shared by `cp` and `mv`.
+fn transfer_command(name: &'static str, about: &'static str) -> Command<'static> {
+    Command::new(name)
+        .about(about)
+        .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Source .it pack").required(true))
+        .arg(Arg::new("output").short('o').long("output").value_name("PACK_NAME").help("Destination .it pack (created if missing)").required(true))
+        .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+        .arg(Arg::new("dest-key").long("dest-key").value_name("KEY_SALT").help("Salt of the destination pack (default: the source pack's salt)").required(false))
+        .arg(
+            Arg::new("filter")
+                .short('f')
+                .long("filter")
+                .value_name("FILTER")
+                .help("Only transfer entries matching this regex (default: all entries)")
+                .required(false)
+                .action(ArgAction::Append)
+        )
+}
+
+/// One `A|D|M|P  name  (detail)` line per change of a `compare` or `diff`.
+fn print_changes(cmp: &snapshot::Comparison) {
+    for c in &cmp.changes {
+        let detail = match c.kind {
+            snapshot::ChangeKind::Added => c.new_pack.clone().unwrap_or_else(|| format!("{} bytes", c.new_size.unwrap_or(0))),
+            snapshot::ChangeKind::Removed => c.old_pack.clone().unwrap_or_else(|| format!("{} bytes", c.old_size.unwrap_or(0))),
+            snapshot::ChangeKind::Modified => format!("{} -> {} bytes", c.old_size.unwrap_or(0), c.new_size.unwrap_or(0)),
+            snapshot::ChangeKind::Moved => format!("{} -> {}", c.old_pack.as_deref().unwrap_or(""), c.new_pack.as_deref().unwrap_or("")),
+        };
+        println!("{}  {}  ({})", c.kind.code(), c.name, detail);
+    }
+}
+
+/// Collects the key-search trace for `--explain-search` and `--timings`.
+struct SearchDiagnostics {
+    /// Leaked so the Ctrl-C handler can still read it while the search runs.
+    trace: Option<&'static common::SearchTrace>,
+    explain: bool,
+    timings: bool,
+    start: Instant,
+}
+
+impl SearchDiagnostics {
+    fn from_matches(sub_matches: &clap::ArgMatches) -> Self {
+        let explain = sub_matches.get_flag("explain-search");
+        let timings = sub_matches.get_flag("timings");
+        let trace: Option<&'static common::SearchTrace> = (explain || timings).then(|| &*Box::leak(Box::default()));
+        if let Some(trace) = trace.filter(|_| explain) {
+            // Interrupting a long search still explains what was tried up to that point.
+            let installed = ctrlc::set_handler(move || {
+                let searches = common::search_progress();
+                if !searches.is_empty() {
+                    let (done, total) = searches.values().fold((0, 0), |(d, t), &(sd, st)| (d + sd, t + st));
+                    let attempts = trace.lock().map(|v| v.clone()).unwrap_or_default();
+                    eprintln!("Interrupted after {} of {} salt/name combinations ({} search(es) running); partial search report:", done, total, searches.len());
+                    eprint!("{}", common::format_search_report(&attempts));
+                }
+                key_cache::flush();
+                std::process::exit(130);
+            });
+            if let Err(e) = installed {
+                warn!("[KEY_SEARCH] Could not install the Ctrl-C handler: {}", e);
+            }
+        }
+        SearchDiagnostics { trace, explain, timings, start: Instant::now() }
+    }
+
+    /// Print the requested reports to stderr: timings always, the explanation only on failure.
+    fn report<T>(self, result: &Result<T>) {
+        let wall = self.start.elapsed();
+        let Some(trace) = self.trace else { return };
+        let attempts = trace.lock().map(|v| v.clone()).unwrap_or_default();
+        if self.explain && result.is_err() {
+            eprint!("{}", common::format_search_report(&attempts));
+        }
+        if self.timings {
+            eprint!("{}", common::format_timing_report(&attempts, wall));
+        }
+    }
+}
+
+/// For `--prefer-salt` / `--choose-salt`: run the exhaustive search and pick one of the
+/// combinations that validated. Returns `None` when neither flag is set or the input is a
+/// legacy .pack (no salts involved), so the caller falls back to the normal search.
+fn pick_key_combination(sub_matches: &clap::ArgMatches, input: &str, cli_key: Option<String>, salts: &[String], settings: &common::PackSettings) -> Result<Option<common_ext::KeySearchHit>> {
+    let prefer = sub_matches.get_one::<String>("prefer-salt").map(|s| s.as_str());
+    let choose = sub_matches.get_flag("choose-salt");
+    if prefer.is_none() && !choose {
+        return Ok(None);
+    }
+
+    let file = std::fs::File::open(input)?;
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    if mmap.len() >= 4 && (&mmap[0..4] == b"PACK" || &mmap[0..4] == b"MABI") {
+        return Ok(None);
+    }
+
+    let matches = common_ext::search_all_keys(&mmap, input, cli_key, salts, None, settings)?;
+    if matches.hits.is_empty() {
+        let header_only: Vec<String> = matches.header_only.iter()
+            .map(|m| format!("'{}' (name '{}', offset 0x{:X})", m.header_salt, m.name_variant, m.header_offset))
+            .collect();
+        // The match most likely to be genuine decides the advice.
+        let Some(likely) = matches.header_only.iter().min_by(|a, b| a.false_positive.total_cmp(&b.false_positive)) else {
+            anyhow::bail!("No salt validated the header of '{}'.", input);
+        };
+        anyhow::bail!("Header of '{}' validated with {} but no salt decoded the entries table. {}", input, header_only.join(", "), likely.advice());
+    }
+
+    let mut hits = matches.hits;
+    if choose && hits.len() > 1 && std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+        eprintln!("{} key combinations validate '{}':", hits.len(), input);
+        for (i, h) in hits.iter().enumerate() {
+            eprintln!("  [{}] HEADER='{}' ENTRIES='{}' name='{}' offset=0x{:X} ({} entries)", i + 1, h.header_salt, h.entries_salt, h.name_variant, h.header_offset, h.entries.len());
+        }
+        eprint!("Use which? [1]: ");
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        let choice = match line.trim() {
+            "" => 1,
+            n => n.parse::<usize>().ok().filter(|n| (1..=hits.len()).contains(n))
+                .ok_or_else(|| anyhow::anyhow!("Invalid choice '{}'", n))?,
+        };
+        return Ok(Some(hits.swap_remove(choice - 1)));
+    }
+    Ok(common_ext::select_hit(hits, prefer))
+}
+
 fn main() -> Result<()> {
+    let started = std::time::Instant::now();
+    let result = run();
+    // Key cache records are saved once per run, not per archive.
+    key_cache::flush();
+    run_summary::finish(&result, started.elapsed())?;
+    result
+}
+
+fn run() -> Result<()> {
     #[cfg(windows)]
     register_shell_menu();
-    let matches = Command::new("mabi-pack2")
+    let cli = Command::new("mabi-pack2")
         .version("1.3.7")
         .author("regomne <fallingsunz@gmail.com>")
         .arg(
@@ -70,12 +620,148 @@ fn main() -> Result<()> {
                 .action(ArgAction::Count)
                 .help("Sets the verbosity level"),
         )
+        .arg(
+            Arg::new("no-key-cache")
+                .long("no-key-cache")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Don't read or update the salt success history used to order key searches"),
+        )
+        .arg(
+            Arg::new("original-name")
+                .long("original-name")
+                .value_name("NAME")
+                .global(true)
+                .help("File name the pack was published under, for renamed packs (keys derive from the name)"),
+        )
+        .arg(
+            Arg::new("guess-name")
+                .long("guess-name")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("When no salt opens a pack under its name, retry with likely original names (extra extensions cut, NNN_full.it, NNN_to_MMM.it, language.it...) and report the one that worked"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Open packs whose entries table has records with bad checksums, as long as most records pass; verify and extract report every bad record"),
+        )
+        .arg(
+            Arg::new("scan-offsets")
+                .long("scan-offsets")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("When the usual header offsets fail, probe every offset in the first 4 KiB and every 1 KiB boundary up to 1 MiB"),
+        )
+        .arg(
+            Arg::new("deep-scan")
+                .long("deep-scan")
+                .value_name("START..END")
+                .global(true)
+                .min_values(0)
+                .require_equals(true)
+                .default_missing_value("0..0x100000")
+                .help("Like --scan-offsets, but probe every byte offset in START..END (default: the first 1 MiB): --deep-scan[=0..4096]"),
+        )
+        .arg(
+            Arg::new("wait-for-data")
+                .long("wait-for-data")
+                .value_name("SECONDS")
+                .global(true)
+                .min_values(0)
+                .require_equals(true)
+                .default_missing_value("30")
+                .help("The pack is still downloading: wait for entry data past its end, giving up once it hasn't grown for SECONDS (default 30): --wait-for-data[=SECONDS]"),
+        )
+        .arg(
+            Arg::new("auto-escalate")
+                .long("auto-escalate")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("When the key search fails, run the --scan-offsets scan, then try every entries-table offset behind the headers it finds (slow)"),
+        )
+        .arg(
+            Arg::new("fixed-strings")
+                .long("fixed-strings")
+                .short('F')
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Treat --filter values as literal text to find in entry names, not regexes"),
+        )
+        .arg(
+            Arg::new("regex-size-limit")
+                .long("regex-size-limit")
+                .value_name("BYTES")
+                .global(true)
+                .help("Reject --filter regexes that compile to more than BYTES (default: 1048576)"),
+        )
+        .arg(
+            Arg::new("untrusted")
+                .long("untrusted")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Strictest settings for packs from unknown sources: no network, capped entry counts/sizes/names, unknown flag bits, legacy .pack files and --script refused, names sanitized, links skipped"),
+        )
+        .arg(
+            Arg::new("unknown-flags")
+                .long("unknown-flags")
+                .value_name("POLICY")
+                .global(true)
+                .help("Entries with flag bits this tool doesn't know: ignore, warn (default: process and report them) or error (refuse the pack)"),
+        )
+        .arg(
+            Arg::new("allow-unsafe-paths")
+                .long("allow-unsafe-paths")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Write entries whose paths lead outside the output folder ('..', absolute names, links) instead of rejecting them"),
+        )
+        .arg(
+            Arg::new("max-name-len")
+                .long("max-name-len")
+                .value_name("CHARS")
+                .global(true)
+                .help("Accept entry names up to this length when decoding entries tables (default: 1024 for v2 packs)"),
+        )
+        .arg(
+            Arg::new("max-entry-size")
+                .long("max-entry-size")
+                .value_name("BYTES")
+                .global(true)
+                .help("Accept entries up to this extracted size when decoding entries tables (default: 500000000 for v2 packs)"),
+        )
+        .arg(
+            Arg::new("json-summary")
+                .long("json-summary")
+                .value_name("FILE")
+                .global(true)
+                .min_values(0)
+                .require_equals(true)
+                .default_missing_value("-")
+                .help("After the command, write a JSON summary of the run (entries, bytes, salts tried, keys and offsets found, per-file errors, elapsed time) to FILE, or to stdout without =FILE")
+        )
+        .arg(
+            Arg::new("entry-checksum")
+                .long("entry-checksum")
+                .value_name("ALGORITHM")
+                .global(true)
+                .help("Experimental: write and validate entries table checksums with standard (the game's, default), md5 or keyed:SECRET (HMAC-MD5); packs written with anything but standard don't load in the game")
+        )
+        .after_help("Noun-verb forms: pack create|extract|list|info|verify|update, entry cat|cp|mv|set-flags, key search, salt list.\nShort aliases: x (extract), l (list), p (pack).")
         .subcommand(
             Command::new("pack")
                 .about("Create a .it pack")
-                .arg(Arg::new("input").short('i').long("input").value_name("FOLDER").help("Set the input folder to pack").required(true))
-                .arg(Arg::new("output").short('o').long("output").value_name("PACK_NAME").help("Set the output .it file name").required(true))
-                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Set the key for the .it file encryption").required(true))
+                .alias("p")
+                .arg(Arg::new("input").short('i').long("input").value_name("FOLDER").help("Set the input folder (or .zip file) to pack").required_unless_present("from-it"))
+                .arg(Arg::new("output").short('o').long("output").value_name("PACK_NAME").help("Set the output .it file name (- writes the pack to stdout; needs --as-name)").required(true))
+                .arg(as_name_arg())
+                .arg(Arg::new("from-it").long("from-it").value_name("PACK").help("Start from the entries of an existing .it pack (may be the output itself); files from --input replace or add to them"))
+                .arg(Arg::new("key-old").long("key-old").value_name("KEY_SALT").requires("from-it").help("Salt to try first when opening the --from-it pack (default: the known salts)"))
+                .arg(Arg::new("remove").long("remove").value_name("REGEX").requires("from-it").action(ArgAction::Append).help("Leave out entries of the --from-it pack matching REGEX; repeatable"))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Set the key for the .it file encryption").required_unless_present("key-id"))
+                .arg(Arg::new("key-id").long("key-id").value_name("ALIAS").conflicts_with("key").help("Use the salt named ALIAS in salts.txt (lines of the form 'SALT<TAB>ALIAS')"))
                 .arg(
                     Arg::new("iv")
                         .long("iv")
@@ -98,13 +784,30 @@ fn main() -> Result<()> {
                         .action(ArgAction::SetTrue)
                         .help("Automatically wrap files in a virtual 'data/' root folder")
                 )
+                .arg(exclude_arg().help("Leave out input files whose entry name matches this regex ('glob:' marks a glob, e.g. \"glob:.git/\", \"glob:Thumbs.db\"); repeatable"))
+                .arg(script_arg())
+                .args(codec_args())
+                .arg(Arg::new("no-metadata").long("no-metadata").action(ArgAction::SetTrue).help("Don't store the tool version and pack options in the archive"))
+                .arg(Arg::new("keep-empty-dirs").long("keep-empty-dirs").action(ArgAction::SetTrue).help("Store empty folders as zero-length marker entries so extract recreates them"))
+                .arg(Arg::new("store-symlinks").long("store-symlinks").action(ArgAction::SetTrue).help("Store symbolic links as link entries (an extension the game client doesn't read) instead of the files they point to"))
+                .arg(Arg::new("allow-empty").long("allow-empty").action(ArgAction::SetTrue).help("Write a pack with no entries if the input folder has nothing to pack"))
+                .arg(Arg::new("allow-output-in-input").long("allow-output-in-input").action(ArgAction::SetTrue).help("Allow an output inside the input folder; the output itself is left out of the pack"))
+                .arg(Arg::new("no-name-map").long("no-name-map").action(ArgAction::SetTrue).help("Pack files under their names on disk even if the folder has a .mabi-names.json from 'extract --sanitize-names'"))
+                .arg(Arg::new("content-hashes").long("content-hashes").action(ArgAction::SetTrue).conflicts_with("no-metadata").help("Store an MD5 of every entry in the archive for 'extract --verify-content'"))
+                .arg(Arg::new("level").long("level").value_name("0-9").help("zlib level for compressed entries: 1 is fastest, 9 smallest, 0 stores them uncompressed (default: 6)"))
+                .arg(Arg::new("store").long("store").action(ArgAction::SetTrue).conflicts_with("level").help("Store every entry uncompressed (same as --level 0)"))
+                .arg(report_arg())
         )
         .subcommand(
             Command::new("extract")
+                .alias("x")
                 .about("Extract a .it pack.")
-                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Set the input pack name to extract").required(true))
-                .arg(Arg::new("output").short('o').long("output").value_name("FOLDER").help("Set the output folder (optional, auto-generated if omitted)").required(false))
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").action(ArgAction::Append)
+                    .help("Set the input pack name to extract (- reads the pack from stdin; needs --as-name; an http(s):// URL downloads only what is extracted). Repeat to extract several packs as one overlay: later packs override earlier ones").required(true))
+                .arg(as_name_arg())
+                .arg(Arg::new("output").short('o').long("output").value_name("FOLDER").help("Set the output folder, or a .zip file to write (optional, auto-generated if omitted)").required(false))
                 .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(Arg::new("key-id").long("key-id").value_name("ALIAS").conflicts_with("key").help("Use the salt named ALIAS in salts.txt (lines of the form 'SALT<TAB>ALIAS')"))
                 .arg(
                     Arg::new("filter")
                         .short('f')
@@ -113,14 +816,104 @@ fn main() -> Result<()> {
                         .help("Set a filter when extracting")
                         .required(false)
                         .action(ArgAction::Append)
-                ),
+                )
+                .arg(glob_arg())
+                .arg(filter_file_arg())
+                .arg(exclude_arg())
+                .arg(Arg::new("to-stdout").long("to-stdout").action(ArgAction::SetTrue).help("Write decoded entries to stdout instead of a folder (logs go to stderr)"))
+                .arg(
+                    Arg::new("framing")
+                        .long("framing")
+                        .value_name("FRAMING")
+                        .help("Delimiting for --to-stdout: none, length (u32 name len, name, u64 data len, data) or tar")
+                        .default_value("none")
+                        .requires("to-stdout")
+                )
+                .arg(Arg::new("manifest-only").long("manifest-only").action(ArgAction::SetTrue).conflicts_with("to-stdout")
+                    .help("Only find the keys and decode the entries table, and write every entry's name, sizes, flags, checksum and data offset as JSON to -o FILE (default: stdout); no entry data is read"))
+                .arg(Arg::new("with-keys").long("with-keys").action(ArgAction::SetTrue).requires("manifest-only").help("Include each entry's key and derived file key in the --manifest-only output"))
+                .arg(Arg::new("explain-search").long("explain-search").action(ArgAction::SetTrue).conflicts_with("to-stdout").help("If no key combination works, print every salt/offset tried and the stage where it failed"))
+                .arg(Arg::new("timings").long("timings").action(ArgAction::SetTrue).conflicts_with("to-stdout").help("Print time spent per salt and per key-search phase"))
+                .arg(report_arg().conflicts_with("to-stdout"))
+                .arg(Arg::new("prefer-salt").long("prefer-salt").value_name("SALT").conflicts_with("to-stdout").help("Try every salt and, if several validate, use this one"))
+                .arg(Arg::new("choose-salt").long("choose-salt").action(ArgAction::SetTrue).conflicts_with("to-stdout").help("Try every salt and ask which to use if several validate"))
+                .arg(
+                    Arg::new("verify-content")
+                        .long("verify-content")
+                        .value_name("MANIFEST")
+                        .min_values(0)
+                        .require_equals(true)
+                        .default_missing_value("")
+                        .conflicts_with("to-stdout")
+                        .help("Check each written file against the digests stored by 'pack --content-hashes', or against an md5sum-style MANIFEST")
+                )
+                .arg(Arg::new("on-case-collision").long("on-case-collision").value_name("POLICY").default_value("suffix").help("When entries differ only in case on a case-insensitive filesystem, or share a --path-template output: suffix, error, skip or overwrite"))
+                .arg(Arg::new("symlinks").long("symlinks").value_name("POLICY").default_value("materialize").help("How to extract link entries from 'pack --store-symlinks': materialize, recreate or skip"))
+                .arg(Arg::new("path-template").long("path-template").value_name("TEMPLATE").conflicts_with("to-stdout").help("Output path per entry from {path}, {dir}, {top}, {name}, {stem}, {ext} and {flat}, e.g. \"{ext}/{name}\""))
+                .arg(protect_arg().conflicts_with("to-stdout"))
+                .arg(Arg::new("backup-dir").long("backup-dir").value_name("PATH").conflicts_with("to-stdout").help("Copy each file that would be overwritten into PATH (same relative path) first"))
+                .arg(content_addressed_arg().conflicts_with_all(&["to-stdout", "path-template", "backup-dir", "verify-content"]))
+                .arg(sanitize_names_arg().conflicts_with_all(&["to-stdout", "content-addressed"]))
+                .arg(
+                    Arg::new("retry-locked")
+                        .long("retry-locked")
+                        .value_name("TIMES")
+                        .min_values(0)
+                        .require_equals(true)
+                        .default_missing_value("5")
+                        .conflicts_with_all(&["to-stdout", "content-addressed"])
+                        .help("Retry files another process (the game client) has locked, a second apart, after the rest is written (default: 5 times)")
+                )
+                .arg(Arg::new("schedule-on-reboot").long("schedule-on-reboot").action(ArgAction::SetTrue).conflicts_with_all(&["to-stdout", "content-addressed"]).help("Windows: write files that stay locked next to the originals as NAME.mabi-new and replace them at the next reboot (needs administrator rights)"))
+                .arg(script_arg().conflicts_with("to-stdout"))
+                .args(codec_args().map(|arg| arg.conflicts_with("to-stdout")))
+                .arg(Arg::new("table-order").long("table-order").action(ArgAction::SetTrue).help("Write entries in entries-table order instead of reading the pack front to back"))
+                .arg(Arg::new("save-index").long("save-index").value_name("FILE").help("Save the keys and decoded entries table to FILE so later runs can skip the key search (--use-index)"))
+                .arg(Arg::new("use-index").long("use-index").value_name("FILE").help("Take keys and entries from an index saved by --save-index; falls back to searching if the pack changed"))
+                .arg(export_keys_arg().conflicts_with("to-stdout")),
         )
         .subcommand(
             Command::new("list")
+                .alias("l")
                 .about("Output the file list of a .it pack.")
-                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Set the input pack name").required(true))
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Set the input pack name (- reads the pack from stdin; needs --as-name; an http(s):// URL downloads only the header and entries table)").required(true))
+                .arg(as_name_arg())
                 .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(Arg::new("key-id").long("key-id").value_name("ALIAS").conflicts_with("key").help("Use the salt named ALIAS in salts.txt (lines of the form 'SALT<TAB>ALIAS')"))
                 .arg(Arg::new("output").short('o').long("output").value_name("LIST_FILE_NAME").help("Output to file (optional)").required(false))
+                .arg(
+                    Arg::new("filter")
+                        .short('f')
+                        .long("filter")
+                        .value_name("FILTER")
+                        .help("Only list entries matching this regex (default: all entries)")
+                        .required(false)
+                        .action(ArgAction::Append)
+                )
+                .arg(glob_arg())
+                .arg(filter_file_arg())
+                .arg(Arg::new("explain-search").long("explain-search").action(ArgAction::SetTrue).help("If no key combination works, print every salt/offset tried and the stage where it failed"))
+                .arg(Arg::new("timings").long("timings").action(ArgAction::SetTrue).help("Print time spent per salt and per key-search phase"))
+                .arg(report_arg())
+                .arg(Arg::new("prefer-salt").long("prefer-salt").value_name("SALT").help("Try every salt and, if several validate, use this one"))
+                .arg(Arg::new("choose-salt").long("choose-salt").action(ArgAction::SetTrue).help("Try every salt and ask which to use if several validate"))
+                .arg(Arg::new("validate-data").long("validate-data").action(ArgAction::SetTrue).help("Also check each entry's stored data and flag entries that would fail to extract"))
+                .arg(
+                    Arg::new("json-lines")
+                        .long("json-lines")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(&["validate-data", "prefer-salt", "choose-salt", "explain-search", "timings"])
+                        .help("Write one JSON object per entry (name, checksum, flags, offset, sizes), streamed as the entries table is decoded (logs go to stderr)")
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .default_value("text")
+                        .conflicts_with_all(&["json-lines", "validate-data", "prefer-salt", "choose-salt", "explain-search", "timings"])
+                        .help("text (one name per line), json (one document with the salts used and every entry's checksum, flags, offset and sizes), or csv/tsv (name, flags, byte offset, sizes and key per row); logs go to stderr for all but text")
+                )
+                .arg(export_keys_arg())
         )
         .subcommand(
             Command::new("convert")
@@ -161,8 +954,191 @@ fn main() -> Result<()> {
                         .required(false)
                         .default_value("1")
                 )
+                .arg(
+                    Arg::new("dedupe-output")
+                        .long("dedupe-output")
+                        .value_name("MODE")
+                        .min_values(0)
+                        .require_equals(true)
+                        .default_missing_value("hardlink")
+                        .help("Hard-link (default) or skip files whose content was already extracted from an earlier archive: --dedupe-output[=hardlink|skip]")
+                )
+                .arg(Arg::new("on-case-collision").long("on-case-collision").value_name("POLICY").default_value("suffix").help("When entries differ only in case on a case-insensitive filesystem: suffix, error, skip or overwrite"))
+                .arg(protect_arg())
+                .arg(content_addressed_arg().conflicts_with_all(&["no-merge", "dedupe-output"]))
+                .arg(sanitize_names_arg().conflicts_with("content-addressed"))
+        )
+        .subcommand(
+            Command::new("set-flags")
+                .alias("touch")
+                .about("Change the flags of existing entries, re-encoding their data and checksums.")
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Set the input .it pack").required(true))
+                .arg(Arg::new("output").short('o').long("output").value_name("PACK_NAME").help("Write the result here instead of modifying the input in place").required(false))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(
+                    Arg::new("filter")
+                        .short('f')
+                        .long("filter")
+                        .value_name("FILTER")
+                        .help("Only change entries matching this regex (default: all entries)")
+                        .required(false)
+                        .action(ArgAction::Append)
+                )
+                .arg(Arg::new("set").long("set").value_name("FLAGS").help("Flags to set: compressed, all-encrypted, head-encrypted or a number").required(false))
+                .arg(Arg::new("clear").long("clear").value_name("FLAGS").help("Flags to clear: compressed, all-encrypted, head-encrypted or a number").required(false))
+        )
+        .subcommand(
+            Command::new("daemon")
+                .about("Serve list, extract and pack requests as line-delimited JSON-RPC, keeping salts and opened packs between requests.")
+                .arg(Arg::new("socket").long("socket").value_name("PATH").required(false).help("Listen on this socket file (Unix) or loopback TCP address such as 127.0.0.1:7878 [default: ~/.mabi-pack2/daemon.sock on Unix, 127.0.0.1:7878 elsewhere]"))
+                .arg(Arg::new("token-file").long("token-file").value_name("PATH").required(false).help("Write the request token here, readable by you only [default: daemon.token next to the key cache]"))
+        )
+        .subcommand(
+            Command::new("compact")
+                .about("Rewrite a .it pack with its entry data back to back, reclaiming the gaps left by removed or replaced entries.")
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Set the input .it pack").required(true))
+                .arg(Arg::new("output").short('o').long("output").value_name("PACK_NAME").help("Write the result here instead of modifying the input in place").required(false))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+        )
+        .subcommand(
+            Command::new("update")
+                .about("Bring an existing .it pack in line with a folder, re-encoding only the files that changed.")
+                .arg(Arg::new("input").short('i').long("input").value_name("FOLDER").help("Set the folder the pack was built from").required(true))
+                .arg(Arg::new("output").short('o').long("output").value_name("PACK_NAME").help("Set the .it pack to update in place").required(true))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(Arg::new("compress-format").short('f').long("compress-format").value_name("EXTENSION").action(ArgAction::Append).help("Add an extension to compress for added files"))
+                .arg(Arg::new("wrap-data").long("wrap-data").action(ArgAction::SetTrue).help("The folder's files live under 'data/' in the pack (default: as recorded when it was packed)"))
+                .arg(Arg::new("prune").long("prune").action(ArgAction::SetTrue).help("Remove entries that have no file in the folder"))
+                .arg(Arg::new("dry-run").short('n').long("dry-run").action(ArgAction::SetTrue).help("List what would change without writing the pack"))
+        )
+        .subcommand(transfer_command("cp", "Copy entries of one .it pack into another."))
+        .subcommand(transfer_command("mv", "Move entries of one .it pack into another, rewriting both packs."))
+        .subcommand(
+            Command::new("info")
+                .about("Show keys, layout, totals and build info of a .it pack.")
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Set the input .it pack").required(true))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(export_keys_arg())
+                .arg(Arg::new("layout").long("layout").action(ArgAction::SetTrue).help("Also map each 1024-byte block range to its entry, with gaps and slack space"))
+        )
+        .subcommand(
+            Command::new("fingerprint")
+                .about("Print a stable hash of a pack's entry names and sizes (not content) to identify client pack versions.")
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Set the input .it pack (repeat for several)").required(true).action(ArgAction::Append))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+        )
+        .subcommand(
+            Command::new("describe-format")
+                .about("Describe every structure of a .it pack (header, entry records, padding, data blocks, footer) with offsets.")
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Set the input .it pack").required(true))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(Arg::new("format").long("format").value_name("FORMAT").default_value("markdown").help("markdown (a summary and one table row per structure and field) or json"))
+                .arg(Arg::new("output").short('o').long("output").value_name("FILE").help("Write the description to a file instead of stdout"))
+        )
+        .subcommand(
+            Command::new("tree-size")
+                .about("Show a percent-bar breakdown of a .it pack by directory and extension.")
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Set the input .it pack").required(true))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(Arg::new("depth").short('d').long("depth").value_name("N").default_value("2").help("Number of directory levels to break down"))
+                .arg(Arg::new("top").short('n').long("top").value_name("N").default_value("10").help("Largest directories/extensions shown per level; the rest are summed"))
+                .arg(Arg::new("stored").long("stored").action(ArgAction::SetTrue).help("Use stored (compressed) sizes instead of extracted sizes"))
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Undo extractions made with 'extract --backup-dir' using that backup folder.")
+                .arg(Arg::new("backup-dir").short('b').long("backup-dir").value_name("PATH").help("The backup folder given to 'extract --backup-dir'").required(true))
+                .arg(Arg::new("output").short('o').long("output").value_name("FOLDER").help("Folder to restore (default: the one recorded in the backup; required for a backup without manifest)"))
+                .arg(Arg::new("dry-run").long("dry-run").action(ArgAction::SetTrue).help("Only report what would be restored and deleted"))
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Check that every entry of one or more .it packs decodes.")
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_OR_FOLDER").help("A .it pack, or a folder whose .it packs are all checked").required(true))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(Arg::new("deep").long("deep").action(ArgAction::SetTrue).help("Decode every entry completely and compare it with the stored content hashes, if any (default: decrypt the first block only)"))
+                .arg(as_name_arg())
+                .arg(Arg::new("threads").long("threads").value_name("N").default_value("0").help("Entries checked in parallel (0 = one per CPU core)"))
+                .arg(pack_parallel_arg())
+        )
+        .subcommand(
+            Command::new("roundtrip")
+                .about("Pack a folder to a temporary .it file, extract it again and report every file that doesn't come back identical.")
+                .arg(Arg::new("input").short('i').long("input").value_name("FOLDER").help("Set the folder to check").required(true))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Set the key to pack with").required_unless_present("key-id"))
+                .arg(Arg::new("key-id").long("key-id").value_name("ALIAS").conflicts_with("key").help("Use the salt named ALIAS in salts.txt (lines of the form 'SALT<TAB>ALIAS')"))
+                .arg(Arg::new("compress-format").short('f').long("compress-format").value_name("EXTENSION").action(ArgAction::Append).help("Add an extension to compress in .it"))
+        )
+        .subcommand(
+            Command::new("snapshot")
+                .about("Record every entry (size, flags, content MD5) of a client's .it packs as JSON, for 'compare'.")
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_OR_FOLDER").help("A .it pack, or a folder (e.g. the client's package folder) whose .it packs are all recorded").required(true))
+                .arg(Arg::new("output").short('o').long("output").value_name("SNAPSHOT_JSON").help("Snapshot file to write").required(true))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(Arg::new("threads").long("threads").value_name("N").default_value("0").help("Entries decoded in parallel (0 = one per CPU core)"))
+                .arg(pack_parallel_arg())
+        )
+        .subcommand(
+            Command::new("compare")
+                .about("Report added, removed, modified and moved entries between two snapshots.")
+                .arg(Arg::new("old").value_name("OLD_JSON").help("Snapshot of the older client").required(true))
+                .arg(Arg::new("new").value_name("NEW_JSON").help("Snapshot of the newer client").required(true))
+                .arg(Arg::new("output").short('o').long("output").value_name("FILE").help("Also write the changes as JSON to FILE"))
+                .arg(Arg::new("summary").long("summary").action(ArgAction::SetTrue).help("Print only the counts, not every changed entry"))
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("Report entries added, removed and changed between two .it packs.")
+                .arg(Arg::new("old").short('a').value_name("OLD_PACK").help("The older pack").required(true))
+                .arg(Arg::new("new").short('b').value_name("NEW_PACK").help("The newer pack").required(true))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Salt to try first on both packs"))
+                .arg(Arg::new("content").long("content").action(ArgAction::SetTrue)
+                    .help("Decode entries whose stored bytes differ and compare their content byte for byte (without it, a file re-encrypted with a new key counts as changed)"))
+                .arg(Arg::new("output").short('o').long("output").value_name("FILE").help("Also write the changes as JSON to FILE"))
+                .arg(Arg::new("summary").long("summary").action(ArgAction::SetTrue).help("Print only the counts, not every changed entry"))
+        )
+        .subcommand(
+            Command::new("salts")
+                .about("Print the salts key searches try: built-in ones, then salts.txt (with --key-id aliases).")
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Check salts, network access, the key cache, write permissions and long-path support, and optionally whether a pack's header decodes.")
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Also probe this .it pack's header with every known salt"))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").requires("input").help("Salt to try first on the pack"))
+        )
+        .subcommand(
+            Command::new("which-key")
+                .about("Report which salts, offset and cipher mode unlock a .it pack.")
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Set the input .it pack").required(true))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(Arg::new("all").long("all").action(ArgAction::SetTrue).help("Keep searching after the first success and report every combination that validates"))
+        )
+        .subcommand(
+            Command::new("keystream")
+                .about("Write raw SNOW2 keystream bytes for an entry's file key, for decrypting data with external tools.")
+                .arg(Arg::new("output").short('o').long("output").value_name("FILE").help("File to write the keystream to").required(true))
+                .arg(Arg::new("file-key").long("file-key").value_name("HEX").help("File key (32 hex digits, as in --export-keys)").required_unless_present("input").conflicts_with("input"))
+                .arg(Arg::new("iv").long("iv").value_name("IV0").default_value("0").help("IV the pack uses (with --file-key)"))
+                .arg(Arg::new("mode").long("mode").value_name("MODE").default_value("Sub").help("Cipher mode the pack uses (with --file-key): Sub, Xor, ModernBE, ModernLE, LegacyBE or LegacyLE"))
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Take the key, IV and mode of --entry in this .it pack instead").requires("entry"))
+                .arg(Arg::new("entry").long("entry").value_name("NAME").help("Entry whose key to use (with -i); --bytes defaults to its encrypted length").requires("input"))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Specific key to try first (optional).").required(false))
+                .arg(Arg::new("bytes").short('n').long("bytes").value_name("N").help("How many keystream bytes to write").required_unless_present("entry"))
+                .arg(Arg::new("skip").long("skip").value_name("N").default_value("0").help("Start this many bytes into the keystream"))
         )
-        .get_matches();
+        .subcommand(
+            Command::new("keys")
+                .about("Print the header/entries offsets and keys derived from a pack name and salt, and an entry's file key, in hex.")
+                .arg(Arg::new("name").long("name").value_name("PACK_NAME").help("Pack file name to derive from (default: the name -i opened with)").required_unless_present("input"))
+                .arg(Arg::new("key").short('k').long("key").value_name("KEY_SALT").help("Salt to derive with; with -i, tried first when opening the pack").required_unless_present("input"))
+                .arg(Arg::new("entries-salt").long("entries-salt").value_name("SALT").help("Salt for the entries key when it differs from --key").requires("key"))
+                .arg(Arg::new("input").short('i').long("input").value_name("PACK_NAME").help("Open this .it pack for its salts, name variant and entry keys"))
+                .arg(Arg::new("entry").long("entry").value_name("NAME").help("Also derive this entry's file key (its key comes from -i or --entry-key)"))
+                .arg(Arg::new("entry-key").long("entry-key").value_name("HEX").help("The entry's 16-byte key (32 hex digits) instead of reading it from -i").requires("entry"))
+        );
+    let args = expand_noun_verb(&cli, std::env::args_os().collect());
+    let matches = cli.get_matches_from(args);
 
     let verbose_level = matches.get_count("verbose");
     let mut loggers: Vec<Box<dyn SharedLogger>> = Vec::new();
@@ -174,10 +1150,21 @@ fn main() -> Result<()> {
         _ => (LevelFilter::Trace, LevelFilter::Trace),
     };
 
+    // Keep stdout clean when it carries entry data.
+    let to_stdout = matches.get_one::<String>("json-summary").is_some_and(|t| t == "-")
+        || matches.subcommand_matches("extract").is_some_and(|m| {
+            m.get_flag("to-stdout") || (m.get_flag("manifest-only") && m.get_one::<String>("output").is_none_or(|o| o == "-"))
+        })
+        || matches.subcommand_matches("list").is_some_and(|m| {
+            (m.get_flag("json-lines") || m.get_one::<String>("format").is_some_and(|f| f != "text")) && !m.contains_id("output")
+        })
+        || matches.subcommand_matches("describe-format").is_some_and(|m| !m.contains_id("output"))
+        || matches.subcommand_matches("fingerprint").is_some()
+        || matches.subcommand_matches("pack").is_some_and(|m| m.get_one::<String>("output").is_some_and(|o| o == "-"));
     loggers.push(TermLogger::new(
         console_log_level,
         ConfigBuilder::new().build(),
-        TerminalMode::Mixed,
+        if to_stdout { TerminalMode::Stderr } else { TerminalMode::Mixed },
         ColorChoice::Auto,
     ));
 
@@ -188,25 +1175,196 @@ fn main() -> Result<()> {
     }
     
     let _ = CombinedLogger::init(loggers);
+    if let Some(target) = matches.get_one::<String>("json-summary") {
+        run_summary::enable(matches.subcommand_name().unwrap_or_default(), target);
+    }
+
+    // --untrusted: settings.untrusted keeps the run offline and uses the strictest limits.
+    if matches.get_flag("untrusted") {
+        if matches.get_flag("allow-unsafe-paths") {
+            anyhow::bail!("--allow-unsafe-paths can't be used with --untrusted");
+        }
+        if matches.get_one::<String>("unknown-flags").is_some_and(|p| p != "error") {
+            anyhow::bail!("--untrusted always refuses unknown flag bits; drop --unknown-flags");
+        }
+        if matches.get_flag("force") {
+            anyhow::bail!("--force can't be used with --untrusted");
+        }
+        debug!("[UNTRUSTED] Offline, entry limits capped, unknown flags, scripts and legacy .pack files refused");
+    }
+
+    let parse_limit = |name: &str| -> Result<Option<u32>> {
+        matches.get_one::<String>(name)
+            .map(|v| v.parse::<u32>().map_err(|_| anyhow::anyhow!("--{} expects a number, got '{}'", name, v)))
+            .transpose()
+    };
+    let mut settings = common::PackSettings {
+        entry_limits: common::EntryLimitOverrides {
+            max_name_len: parse_limit("max-name-len")?,
+            max_entry_size: parse_limit("max-entry-size")?,
+        },
+        original_name: matches.get_one::<String>("original-name").map(|n| common::get_final_file_name(n)).transpose()?,
+        filters: extract::FilterSettings {
+            fixed_strings: matches.get_flag("fixed-strings"),
+            size_limit: parse_limit("regex-size-limit")?.map_or(extract::FilterSettings::DEFAULT_SIZE_LIMIT, |n| n as usize),
+        },
+        entry_checksum: matches.get_one::<String>("entry-checksum").map(|spec| checksum::from_spec(spec)).transpose()?,
+        deep_scan: matches.get_one::<String>("deep-scan").map(|r| common::parse_offset_range(r)).transpose()?,
+        allow_unsafe_paths: matches.get_flag("allow-unsafe-paths"),
+        untrusted: matches.get_flag("untrusted"),
+        unknown_flags: matches.get_one::<String>("unknown-flags").map(|p| p.parse()).transpose()?.unwrap_or_default(),
+        guess_names: matches.get_flag("guess-name"),
+        force: matches.get_flag("force"),
+        escalation: if matches.get_flag("auto-escalate") {
+            common::Escalation::Full
+        } else if matches.get_flag("scan-offsets") || matches.contains_id("deep-scan") {
+            common::Escalation::ScanOffsets
+        } else {
+            common::Escalation::Off
+        },
+        wait_for_data: matches.get_one::<String>("wait-for-data")
+            .map(|v| v.parse::<u64>().map(std::time::Duration::from_secs).map_err(|_| anyhow::anyhow!("--wait-for-data expects a number of seconds, got '{}'", v)))
+            .transpose()?,
+    };
 
     let mut all_salts: Vec<String> = Vec::new();
-    if matches.subcommand_matches("extract").is_some()
-        || matches.subcommand_matches("list").is_some()
-        || matches.subcommand_matches("batch").is_some()
-    {
-        all_salts = load_salts();
+    const SALTED_COMMANDS: &[&str] = &["extract", "list", "batch", "set-flags", "compact", "update", "cp", "mv", "which-key", "info", "fingerprint", "describe-format", "tree-size", "verify", "snapshot", "keystream", "keys", "diff", "daemon"];
+    if matches.subcommand_name().is_some_and(|name| SALTED_COMMANDS.contains(&name)) {
+        all_salts = load_salts_with_settings(&settings);
+        if !matches.get_flag("no-key-cache") {
+            key_cache::enable(&key_cache::default_path());
+        }
     }
 
     if let Some(sub_matches) = matches.subcommand_matches("list") {
-        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
-        let input_fname = sub_matches.get_one::<String>("input").unwrap();
+        let mut cli_key = cli_key_arg(sub_matches, &settings)?;
+        let no_exclude: &[String] = &[];
+        let remote = remote_mirror(sub_matches, &mut cli_key, &mut all_salts, &settings, sub_matches.get_flag("validate-data").then_some(no_exclude))?;
+        let spool = if remote.is_none() { stdin_spool(sub_matches, &mut settings)? } else { None };
+        let input_fname = remote.as_ref().map(|r| r.path()).or(spool.as_ref().map(|s| s.path()))
+            .map_or_else(|| sub_matches.get_one::<String>("input").unwrap().clone(), |p| p.to_string());
+        let input_fname = &input_fname;
         let output_path = sub_matches.get_one::<String>("output").map(|s| s.as_str());
+        let filters = filter_values(sub_matches)?;
         
-        list::run_list_with_key_search(input_fname, cli_key, &all_salts, output_path)?;
+        match sub_matches.get_one::<String>("format").unwrap().as_str() {
+            "text" => {}
+            "json" => {
+                let mut writer: Box<dyn Write> = match output_path {
+                    Some(out_path) => Box::new(std::io::BufWriter::new(std::fs::File::create(out_path)?)),
+                    None => Box::new(std::io::stdout()),
+                };
+                let summary = list::run_list_json(input_fname, cli_key, &all_salts, &settings, filters, &mut writer)?;
+                write_report(sub_matches, &summary)?;
+                return export_keys(sub_matches, input_fname, summary.salt, &all_salts, &settings);
+            }
+            format @ ("csv" | "tsv") => {
+                let mut writer: Box<dyn Write> = match output_path {
+                    Some(out_path) => Box::new(std::io::BufWriter::new(std::fs::File::create(out_path)?)),
+                    None => Box::new(std::io::stdout()),
+                };
+                let sep = if format == "csv" { ',' } else { '\t' };
+                let summary = list::run_list_table(input_fname, cli_key, &all_salts, &settings, filters, sep, &mut writer)?;
+                write_report(sub_matches, &summary)?;
+                return export_keys(sub_matches, input_fname, summary.salt, &all_salts, &settings);
+            }
+            other => anyhow::bail!("Unknown list format '{}' (expected text, json, csv or tsv)", other),
+        }
+
+        if sub_matches.get_flag("json-lines") {
+            let mut writer: Box<dyn Write> = match output_path {
+                Some(out_path) => Box::new(std::io::BufWriter::new(std::fs::File::create(out_path)?)),
+                None => Box::new(std::io::stdout()),
+            };
+            let summary = list::run_list_json_lines(input_fname, cli_key, &all_salts, &settings, filters, &mut writer)?;
+            write_report(sub_matches, &summary)?;
+            return export_keys(sub_matches, input_fname, summary.salt, &all_salts, &settings);
+        }
+
+        if sub_matches.get_flag("validate-data") {
+            let bad = list::run_list_validate_data(input_fname, cli_key.clone(), &all_salts, &settings, output_path, filters)?;
+            export_keys(sub_matches, input_fname, cli_key, &all_salts, &settings)?;
+            if bad > 0 {
+                anyhow::bail!("{} entries failed data validation", bad);
+            }
+            return Ok(());
+        }
+
+        if let Some(hit) = pick_key_combination(sub_matches, input_fname, cli_key.clone(), &all_salts, &settings)? {
+            let mut writer: Box<dyn Write> = match output_path {
+                Some(out_path) => Box::new(std::fs::File::create(out_path)?),
+                None => Box::new(std::io::stdout()),
+            };
+            let entries = list::filter_entries(hit.entries.clone(), &extract::compile_filters(&filters, settings.filters)?);
+            let names: Vec<String> = entries.iter().map(|e| e.name.clone()).collect();
+            list::perform_listing(&mut writer, &names)?;
+            let summary = common::OperationSummary {
+                entries: entries.len(),
+                bytes: entries.iter().map(|e| e.original_size as u64).sum(),
+                entry_warnings: common::entry_warnings(&entries, &settings),
+                ..common::OperationSummary::default()
+            };
+            write_report(sub_matches, &summary.with_salts(&hit.header_salt, &hit.entries_salt))?;
+            return export_keys(sub_matches, input_fname, Some(hit.header_salt), &all_salts, &settings);
+        }
+
+        let diagnostics = SearchDiagnostics::from_matches(sub_matches);
+        let result = list::run_list_with_key_search_traced(input_fname, cli_key, &all_salts, &settings, output_path, diagnostics.trace, filters);
+        diagnostics.report(&result);
+        let summary = result?;
+        write_report(sub_matches, &summary)?;
+        export_keys(sub_matches, input_fname, summary.salt, &all_salts, &settings)?;
     } else if let Some(sub_matches) = matches.subcommand_matches("extract") {
-        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
-        let input_fname = sub_matches.get_one::<String>("input").unwrap();
+        let mut cli_key = cli_key_arg(sub_matches, &settings)?;
+        let inputs: Vec<String> = sub_matches.get_many::<String>("input").unwrap().cloned().collect();
+        if inputs.len() > 1 {
+            const SINGLE_PACK: &[&str] = &["to-stdout", "manifest-only", "prefer-salt", "choose-salt", "export-keys", "verify-content", "save-index", "use-index", "explain-search", "timings"];
+            if let Some(id) = SINGLE_PACK.iter().find(|id| sub_matches.value_source(id) == Some(clap::ValueSource::CommandLine)) {
+                anyhow::bail!("--{} works on one pack; give a single -i", id);
+            }
+            if inputs.iter().any(|i| i == "-" || remote::is_url(i)) || !sub_matches.contains_id("output") {
+                anyhow::bail!("Extracting several packs as an overlay needs -o and packs on disk (not -i - or URLs)");
+            }
+        }
+        let exclude: Vec<String> = sub_matches.get_many::<String>("exclude").map_or(Vec::new(), |v| v.cloned().collect());
+        let remote = remote_mirror(sub_matches, &mut cli_key, &mut all_salts, &settings, (!sub_matches.get_flag("manifest-only")).then_some(&exclude[..]))?;
+        let spool = if remote.is_none() { stdin_spool(sub_matches, &mut settings)? } else { None };
+        let input_fname = remote.as_ref().map(|r| r.path()).or(spool.as_ref().map(|s| s.path()))
+            .map_or_else(|| sub_matches.get_one::<String>("input").unwrap().clone(), |p| p.to_string());
+        let input_fname = &input_fname;
         let output_arg = sub_matches.get_one::<String>("output");
+
+        if sub_matches.get_flag("manifest-only") {
+            let filters = filter_values(sub_matches)?;
+            let exclude = sub_matches.get_many::<String>("exclude").map_or(Vec::new(), |v| v.cloned().collect());
+            let with_keys = sub_matches.get_flag("with-keys");
+            match output_arg.filter(|o| *o != "-") {
+                Some(path) => {
+                    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+                    let manifest = extract::run_extract_manifest(input_fname, cli_key, &all_salts, &settings, filters, exclude, with_keys, &mut out)?;
+                    println!("Wrote the layout of {} entries to '{}'.", manifest.entries.len(), path);
+                }
+                None => {
+                    let stdout = std::io::stdout();
+                    extract::run_extract_manifest(input_fname, cli_key, &all_salts, &settings, filters, exclude, with_keys, &mut std::io::BufWriter::new(stdout.lock()))?;
+                }
+            }
+            return Ok(());
+        }
+
+        if sub_matches.get_flag("to-stdout") {
+            let framing: extract::StreamFraming = sub_matches.get_one::<String>("framing").unwrap().parse()?;
+            let filters = filter_values(sub_matches)?;
+            let stdout = std::io::stdout();
+            let mut out = std::io::BufWriter::new(stdout.lock());
+            let exclude = sub_matches.get_many::<String>("exclude").map_or(Vec::new(), |v| v.cloned().collect());
+            let summary = extract::run_extract_to_writer(input_fname, cli_key, &all_salts, &settings, filters, exclude, framing, &mut out)?;
+            run_summary::record_operation(&summary);
+            if summary.failed > 0 {
+                anyhow::bail!("{} entries failed to decode and were left out of the stream", summary.failed);
+            }
+            return Ok(());
+        }
         
         // Auto-generate output folder if missing
         let output_path = match output_arg {
@@ -218,39 +1376,155 @@ fn main() -> Result<()> {
             }
         };
         
-        let filters: Vec<String> = sub_matches.get_many::<String>("filter").map_or(Vec::new(), |v| v.map(|s| s.to_string()).collect());
+        let filters = filter_values(sub_matches)?;
         
-        extract::run_extract_with_key_search(
-            input_fname,
-            &output_path,
-            cli_key,
-            &all_salts,
+        let verify_hashes = match sub_matches.get_one::<String>("verify-content") {
+            Some(manifest) => Some(load_content_hashes(input_fname, manifest)?),
+            None => None,
+        };
+        let diagnostics = SearchDiagnostics::from_matches(sub_matches);
+        let objects = dedupe::ObjectStore::new();
+        let zip_output = vfs::is_zip_path(&output_path).then(|| vfs::ZipFs::create(&output_path)).transpose()?;
+        let script = entry_hook(sub_matches, hook::HookDirection::Extract, &settings)?;
+        let opts = extract::ExtractOptions {
             filters,
-            None,
-            false,
-            None
-        )?;
+            search_trace: diagnostics.trace,
+            verify_hashes: verify_hashes.as_ref(),
+            case_collisions: sub_matches.get_one::<String>("on-case-collision").unwrap().parse()?,
+            path_template: sub_matches.get_one::<String>("path-template").cloned(),
+            symlinks: symlink_policy(Some(sub_matches), &settings)?,
+            protect: sub_matches.get_many::<String>("protect").map_or(Vec::new(), |v| v.cloned().collect()),
+            backup_dir: sub_matches.get_one::<String>("backup-dir").cloned(),
+            objects: sub_matches.contains_id("content-addressed").then_some(&objects),
+            sanitize_names: sanitize_mode(sub_matches, &settings)?,
+            sink: zip_output.as_ref().map(|z| z as &dyn vfs::Vfs),
+            retry_locked: sub_matches.get_one::<String>("retry-locked")
+                .map_or(Ok(0), |v| v.parse::<u32>().map_err(|_| anyhow::anyhow!("--retry-locked expects a number, got '{}'", v)))?,
+            schedule_on_reboot: sub_matches.get_flag("schedule-on-reboot"),
+            table_order: sub_matches.get_flag("table-order"),
+            exclude: sub_matches.get_many::<String>("exclude").map_or(Vec::new(), |v| v.cloned().collect()),
+            save_index: sub_matches.get_one::<String>("save-index").cloned(),
+            use_index: sub_matches.get_one::<String>("use-index").cloned(),
+            hook: script.as_deref(),
+            settings: settings.clone(),
+            ..extract::ExtractOptions::default()
+        };
+        let finish_zip = |ok: bool| -> Result<()> {
+            match &zip_output {
+                Some(zip) if ok => vfs::Vfs::finish(zip),
+                Some(_) => Ok(std::fs::remove_file(&output_path)?),
+                None => Ok(()),
+            }
+        };
+
+        if inputs.len() > 1 {
+            let result = extract::run_extract_overlay(&inputs, &output_path, cli_key, &all_salts, &opts);
+            finish_zip(result.is_ok())?;
+            let (summary, packs) = result?;
+            finish_object_store(sub_matches, &objects, &output_path)?;
+            for pack in &packs {
+                println!("{}: {} entries extracted, {} shadowed by later packs", pack.path, pack.extracted, pack.shadowed);
+            }
+            print_extension_stats(&summary);
+            print_protected(&summary);
+            print_rejected(&summary);
+            print_locked(&summary);
+            return write_report(sub_matches, &summary);
+        }
+
+        if let Some(hit) = pick_key_combination(sub_matches, input_fname, cli_key.clone(), &all_salts, &settings)? {
+            let result = extract::run_extract_with_hit(input_fname, &output_path, &hit, &opts);
+            finish_zip(result.is_ok())?;
+            let summary = result?;
+            finish_object_store(sub_matches, &objects, &output_path)?;
+            print_extension_stats(&summary);
+            print_protected(&summary);
+            print_rejected(&summary);
+            print_locked(&summary);
+            write_report(sub_matches, &summary)?;
+            return export_keys(sub_matches, input_fname, Some(hit.header_salt), &all_salts, &settings);
+        }
+
+        let result = extract::run_extract_with_options(input_fname, &output_path, cli_key, &all_salts, &opts);
+        diagnostics.report(&result);
+        finish_zip(result.is_ok())?;
+        let summary = result?;
+        finish_object_store(sub_matches, &objects, &output_path)?;
+        print_extension_stats(&summary);
+        print_protected(&summary);
+        print_rejected(&summary);
+        print_locked(&summary);
+        write_report(sub_matches, &summary)?;
+        export_keys(sub_matches, input_fname, summary.salt, &all_salts, &settings)?;
     } else if let Some(sub_matches) = matches.subcommand_matches("pack") {
-        let input = sub_matches.get_one::<String>("input").unwrap();
-        let output = sub_matches.get_one::<String>("output").unwrap();
-        
+        let input = sub_matches.get_one::<String>("input").map_or("", |s| s.as_str());
+        let stdout_spool = match sub_matches.get_one::<String>("output").map(|s| s.as_str()) {
+            Some("-") => Some(common::StdioSpool::new(sub_matches.get_one::<String>("as-name")
+                .ok_or_else(|| anyhow::anyhow!("Writing a pack to stdout (-o -) needs --as-name PACK_NAME: keys derive from the file name"))?)?),
+            _ => None,
+        };
+        let output = stdout_spool.as_ref().map_or_else(|| sub_matches.get_one::<String>("output").unwrap().clone(), |s| s.path().to_string());
+        let output = &output;
+        let base = sub_matches.get_one::<String>("from-it").map(|path| pack::BasePack {
+            path: path.clone(),
+            key: sub_matches.get_one::<String>("key-old").cloned(),
+            salts: load_salts_with_settings(&settings),
+            remove: sub_matches.get_many::<String>("remove").map_or(Vec::new(), |v| v.cloned().collect()),
+        });
+
         if output.to_lowercase().ends_with(".pack") {
+            if base.is_some() {
+                anyhow::bail!("--from-it only writes .it packs");
+            }
+            if ["exclude", "script", "codec", "codecs"].iter().any(|id| sub_matches.contains_id(id)) {
+                anyhow::bail!("--exclude, --script and --codec only apply to .it packs");
+            }
+            if let Some(rel) = pack::output_in_input(input, output) {
+                anyhow::bail!("The output '{}' lies inside the input folder '{}' (as '{}') and would be packed into itself; write it elsewhere", output, input, rel.display());
+            }
             info!("[CLI] Creating legacy .pack archive: {}", output);
             mabi_pack2::pack_v1::run_pack_v1(input, output, 1)?;
         } else {
             let iv = sub_matches.get_one::<String>("iv").and_then(|s| s.parse::<u32>().ok()).unwrap_or(0);
             let wrap = sub_matches.get_flag("wrap-data");
-            let path_prefix = if wrap { Some("data") } else { None };
-            pack::run_pack(
-                input,
-                output,
-                sub_matches.get_one::<String>("key").expect("Key required"),
-                sub_matches.get_many::<String>("compress-format").map_or(Vec::new(), |v| v.map(|s| s.as_str()).collect()),
-                false,
+            let level = if sub_matches.get_flag("store") {
+                0
+            } else {
+                sub_matches.get_one::<String>("level")
+                    .map_or(Ok(pack::DEFAULT_LEVEL), |v| v.parse::<u32>().ok().filter(|l| *l <= 9).ok_or_else(|| anyhow::anyhow!("--level expects a number from 0 to 9, got '{}'", v)))?
+            };
+            let opts = pack::PackOptions {
+                compress_ext: sub_matches.get_many::<String>("compress-format").map_or(Vec::new(), |v| v.map(|s| s.to_string()).collect()),
                 iv,
-                path_prefix,
-                None
-            )?;
+                path_prefix: wrap.then(|| "data".to_string()),
+                embed_metadata: !sub_matches.get_flag("no-metadata"),
+                content_hashes: sub_matches.get_flag("content-hashes"),
+                keep_empty_dirs: sub_matches.get_flag("keep-empty-dirs"),
+                store_symlinks: sub_matches.get_flag("store-symlinks"),
+                allow_empty: sub_matches.get_flag("allow-empty"),
+                allow_output_in_input: sub_matches.get_flag("allow-output-in-input"),
+                level,
+                restore_names: !sub_matches.get_flag("no-name-map"),
+                base,
+                exclude: sub_matches.get_many::<String>("exclude").map_or(Vec::new(), |v| v.cloned().collect()),
+                hook: entry_hook(sub_matches, hook::HookDirection::Pack, &settings)?,
+                settings: settings.clone(),
+                ..pack::PackOptions::default()
+            };
+            let skey = cli_key_arg(sub_matches, &settings)?.expect("Key required");
+            let summary = if vfs::is_zip_path(input) && Path::new(input).is_file() {
+                pack::run_pack_from_vfs(&vfs::ZipFs::open(input)?, output, &skey, &opts, None)?
+            } else {
+                pack::run_pack_with_options(input, output, &skey, &opts, None)?
+            };
+            write_report(sub_matches, &summary)?;
+        }
+        if let Ok(meta) = std::fs::metadata(output) {
+            run_summary::record_written(sub_matches.get_one::<String>("output").unwrap(), meta.len());
+        }
+        if let Some(spool) = &stdout_spool {
+            let written = spool.copy_to(&mut std::io::stdout().lock())?;
+            debug!("[CLI] Wrote {} pack bytes to stdout", written);
         }
     } else if let Some(sub_matches) = matches.subcommand_matches("convert") {
         let input = sub_matches.get_one::<String>("input").unwrap();
@@ -274,6 +1548,17 @@ fn main() -> Result<()> {
             .and_then(|s| s.parse::<usize>().ok())
             .map(|n| if n == 0 { num_cpus() } else { n })
             .unwrap_or(1);
+        let output_dedupe = match sub_matches.get_one::<String>("dedupe-output") {
+            Some(mode) => Some(dedupe::OutputDedupe::new(mode.parse()?)),
+            None => None,
+        };
+        let case_collisions: extract::CaseCollisionPolicy = sub_matches.get_one::<String>("on-case-collision").unwrap().parse()?;
+        let sanitize_names = sanitize_mode(sub_matches, &settings)?;
+        let symlinks = symlink_policy(None, &settings)?;
+        let protect: Vec<String> = sub_matches.get_many::<String>("protect").map_or(Vec::new(), |v| v.cloned().collect());
+        let protected_total = AtomicUsize::new(0);
+        let objects = dedupe::ObjectStore::new();
+        let objects_ref = sub_matches.contains_id("content-addressed").then_some(&objects);
 
         let mut archives: Vec<_> = std::fs::read_dir(input)?
             .filter_map(Result::ok)
@@ -312,8 +1597,7 @@ fn main() -> Result<()> {
 
                 let arc_label = archive_name.clone();
                 let progress_cb: &extract::ProgressFn = &move |done, count, _msg| {
-                    if count > 0 {
-                        let pct = done * 100 / count;
+                    if let Some(pct) = (done * 100).checked_div(count) {
                         print!("\r  [{}/{}] {} — {}%   ", idx + 1, total, arc_label, pct);
                         let _ = std::io::stdout().flush();
                     }
@@ -322,23 +1606,29 @@ fn main() -> Result<()> {
                 print!("[{}/{}] {} ...", idx + 1, total, archive_name);
                 let _ = std::io::stdout().flush();
 
-                match extract::run_extract_with_key_search(
-                    fname,
-                    &out_dir,
-                    cached_salt.clone(),
-                    &all_salts,
-                    filters.clone(),
-                    None,
-                    false,
-                    Some(progress_cb),
-                ) {
-                    Ok(found_salt) => {
-                        if found_salt != "LEGACY_MABI" && found_salt != "LEGACY_PACK" && found_salt != "LOGUE_PACK" {
+                let opts = extract::ExtractOptions {
+                    filters: filters.clone(),
+                    progress_cb: Some(progress_cb),
+                    dedupe: output_dedupe.as_ref(),
+                    objects: objects_ref,
+                    case_collisions,
+                    sanitize_names,
+                    symlinks,
+                    protect: protect.clone(),
+                    settings: settings.clone(),
+                    ..extract::ExtractOptions::default()
+                };
+                match common::catch_panic(fname, || extract::run_extract_with_options(fname, &out_dir, cached_salt.clone(), &all_salts, &opts)) {
+                    Ok(summary) => {
+                        protected_total.fetch_add(summary.protected.len(), Ordering::Relaxed);
+                        run_summary::record_operation(&summary);
+                        if let Some(found_salt) = summary.salt {
                             cached_salt = Some(found_salt);
                         }
                         println!("\r[{}/{}] {} done                    ", idx + 1, total, archive_name);
                     }
                     Err(e) => {
+                        run_summary::record_file_error(fname, &e);
                         println!("\r[{}/{}] {} ERROR: {}          ", idx + 1, total, archive_name, e);
                     }
                 }
@@ -367,27 +1657,372 @@ fn main() -> Result<()> {
                         };
                         let _ = std::fs::create_dir_all(&out_dir);
 
-                        let result = extract::run_extract_with_key_search(
-                            fname,
-                            &out_dir,
-                            cli_key.clone(),
-                            salts_ref,
-                            filters_ref.clone(),
-                            None,
-                            false,
-                            None, // no per-file progress in parallel mode
-                        );
+                        // no per-file progress in parallel mode
+                        let opts = extract::ExtractOptions {
+                            filters: filters_ref.clone(),
+                            dedupe: output_dedupe.as_ref(),
+                            objects: objects_ref,
+                            case_collisions,
+                            sanitize_names,
+                            symlinks,
+                            protect: protect.clone(),
+                            settings: settings.clone(),
+                            ..extract::ExtractOptions::default()
+                        };
+                        let result = common::catch_panic(fname, || extract::run_extract_with_options(fname, &out_dir, cli_key.clone(), salts_ref, &opts));
 
                         let n = completed.fetch_add(1, Ordering::Relaxed) + 1;
                         match result {
-                            Ok(_)  => println!("[{}/{}] {} done", n, total, archive_name),
-                            Err(e) => println!("[{}/{}] {} ERROR: {}", n, total, archive_name, e),
+                            Ok(summary) => {
+                                protected_total.fetch_add(summary.protected.len(), Ordering::Relaxed);
+                                run_summary::record_operation(&summary);
+                                println!("[{}/{}] {} done", n, total, archive_name)
+                            }
+                            Err(e) => {
+                                run_summary::record_file_error(fname, &e);
+                                println!("[{}/{}] {} ERROR: {}", n, total, archive_name, e)
+                            }
                         }
                     });
                 });
         }
 
+        if let Some(d) = &output_dedupe {
+            let (files, bytes) = d.saved();
+            let verb = if d.mode() == dedupe::DedupeMode::Skip { "skipped" } else { "linked" };
+            info!("[DEDUPE] {} duplicate file(s) {}, {} bytes saved", files, verb, bytes);
+        }
+        finish_object_store(sub_matches, &objects, output)?;
+        let protected = protected_total.load(Ordering::Relaxed);
+        if protected > 0 {
+            info!("[PROTECT] {} protected file(s) left untouched across the batch", protected);
+        }
         info!("Batch complete: {} archives -> '{}'", total, output);
+    } else if let Some(sub_matches) = matches.subcommand_matches("set-flags") {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let output = sub_matches.get_one::<String>("output").map(|s| s.as_str());
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+        let filters: Vec<String> = sub_matches.get_many::<String>("filter").map_or(Vec::new(), |v| v.map(|s| s.to_string()).collect());
+        let set = sub_matches.get_one::<String>("set").map_or(Ok(0), |s| edit::parse_flags(s))?;
+        let clear = sub_matches.get_one::<String>("clear").map_or(Ok(0), |s| edit::parse_flags(s))?;
+        if set == 0 && clear == 0 {
+            anyhow::bail!("Nothing to do: pass --set and/or --clear");
+        }
+
+        let changed = edit::run_set_flags(input, output, cli_key, &all_salts, &settings, filters, set, clear)?;
+        println!("Updated flags on {} entries.", changed);
+    } else if let Some(sub_matches) = matches.subcommand_matches("daemon") {
+        let socket = sub_matches.get_one::<String>("socket").cloned().unwrap_or_else(mabi_pack2::daemon::default_socket);
+        let token_file = sub_matches.get_one::<String>("token-file").map(std::path::PathBuf::from).unwrap_or_else(mabi_pack2::daemon::default_token_path);
+        let token = mabi_pack2::daemon::new_token()?;
+        mabi_pack2::daemon::write_token(&token_file, &token)?;
+        info!("[DAEMON] Request token written to '{}'", token_file.display());
+        let daemon = std::sync::Arc::new(mabi_pack2::daemon::Daemon::new(all_salts.clone(), settings.clone(), token));
+        mabi_pack2::daemon::serve(daemon, &socket)?;
+        info!("[DAEMON] Shut down");
+    } else if let Some(sub_matches) = matches.subcommand_matches("compact") {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let output = sub_matches.get_one::<String>("output").map(|s| s.as_str());
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+
+        let summary = edit::run_compact(input, output, cli_key, &all_salts, &settings)?;
+        if summary.gap_bytes == 0 && output.is_none() {
+            println!("'{}' has no gaps; nothing to reclaim.", input);
+        } else {
+            println!("Reclaimed {} bytes ({} -> {}, {} in gaps).", summary.reclaimed(), summary.before, summary.after, summary.gap_bytes);
+        }
+    } else if let Some(sub_matches) = matches.subcommand_matches("update") {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let output = sub_matches.get_one::<String>("output").unwrap();
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+        let opts = edit::UpdateOptions {
+            compress_ext: sub_matches.get_many::<String>("compress-format").map_or(Vec::new(), |v| v.cloned().collect()),
+            path_prefix: sub_matches.get_flag("wrap-data").then(|| "data".to_string()),
+            prune: sub_matches.get_flag("prune"),
+            dry_run: sub_matches.get_flag("dry-run"),
+            settings: settings.clone(),
+        };
+
+        let summary = edit::run_update(input, output, cli_key, &all_salts, &opts)?;
+        for (label, names) in [("changed", &summary.changed), ("added", &summary.added), ("removed", &summary.removed)] {
+            for name in names {
+                println!("{:>8} {}", label, name);
+            }
+        }
+        let verb = if summary.is_empty() { "is up to date" } else if opts.dry_run { "would be updated" } else { "updated" };
+        println!("'{}' {}: {} changed, {} added, {} removed, {} unchanged.",
+            output, verb, summary.changed.len(), summary.added.len(), summary.removed.len(), summary.unchanged);
+    } else if let Some((name @ ("cp" | "mv"), sub_matches)) = matches.subcommand() {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let output = sub_matches.get_one::<String>("output").unwrap();
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+        let dest_key = sub_matches.get_one::<String>("dest-key").map(|s| s.to_string());
+        let filters: Vec<String> = sub_matches.get_many::<String>("filter").map_or(Vec::new(), |v| v.map(|s| s.to_string()).collect());
+
+        let moved = name == "mv";
+        let count = edit::run_transfer_entries(input, output, cli_key, dest_key, &all_salts, &settings, filters, moved)?;
+        println!("{} {} entries to '{}'.", if moved { "Moved" } else { "Copied" }, count, output);
+    } else if let Some(sub_matches) = matches.subcommand_matches("info") {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+        mabi_pack2::info::run_info(input, cli_key.clone(), &all_salts, &settings, sub_matches.get_flag("layout"), &mut std::io::stdout())?;
+        export_keys(sub_matches, input, cli_key, &all_salts, &settings)?;
+    } else if let Some(sub_matches) = matches.subcommand_matches("fingerprint") {
+        let inputs: Vec<String> = sub_matches.get_many::<String>("input").unwrap().cloned().collect();
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+        mabi_pack2::info::run_fingerprint(&inputs, cli_key, &all_salts, &settings, &mut std::io::stdout())?;
+    } else if let Some(sub_matches) = matches.subcommand_matches("describe-format") {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+        let format = match sub_matches.get_one::<String>("format").unwrap().as_str() {
+            "json" => describe::DescribeFormat::Json,
+            "markdown" => describe::DescribeFormat::Markdown,
+            other => anyhow::bail!("Unknown describe format '{}' (expected markdown or json)", other),
+        };
+        let mut writer: Box<dyn Write> = match sub_matches.get_one::<String>("output") {
+            Some(out_path) => Box::new(std::io::BufWriter::new(std::fs::File::create(out_path)?)),
+            None => Box::new(std::io::stdout()),
+        };
+        describe::run_describe_format(input, cli_key, &all_salts, &settings, format, &mut writer)?;
+        writer.flush()?;
+    } else if let Some(sub_matches) = matches.subcommand_matches("tree-size") {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+        let depth: usize = sub_matches.get_one::<String>("depth").unwrap().parse()?;
+        let top: usize = sub_matches.get_one::<String>("top").unwrap().parse()?;
+        if depth == 0 || top == 0 {
+            anyhow::bail!("--depth and --top must be at least 1");
+        }
+        mabi_pack2::info::run_tree_size(input, cli_key, &all_salts, &settings, depth, top, sub_matches.get_flag("stored"), &mut std::io::stdout())?;
+    } else if let Some(sub_matches) = matches.subcommand_matches("restore") {
+        let backup_dir = sub_matches.get_one::<String>("backup-dir").unwrap();
+        let target = sub_matches.get_one::<String>("output").map(|s| s.as_str());
+        let dry_run = sub_matches.get_flag("dry-run");
+        let report = backup::run_restore(backup_dir, target, dry_run)?;
+        println!("{} {} file(s), {} {} created file(s)",
+            if dry_run { "Would restore" } else { "Restored" }, report.restored,
+            if dry_run { "would delete" } else { "deleted" }, report.deleted);
+    } else if let Some(sub_matches) = matches.subcommand_matches("verify") {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+        let threads: usize = sub_matches.get_one::<String>("threads").unwrap().parse()?;
+        let pack_parallel: usize = sub_matches.get_one::<String>("pack-parallel").unwrap().parse()?;
+        let archives = it_archives(input)?;
+        if sub_matches.contains_id("as-name") && archives.len() > 1 {
+            anyhow::bail!("--as-name names a single pack, but '{}' holds {}", input, archives.len());
+        }
+        apply_as_name(sub_matches, &mut settings)?;
+        let report = verify::run_verify(&archives, cli_key, &all_salts, &settings, sub_matches.get_flag("deep"), threads, pack_parallel)?;
+        for f in &report.failures {
+            if f.entry.is_empty() {
+                println!("{}: {}", f.archive, f.reason);
+            } else {
+                println!("{}: {}: {}", f.archive, f.entry, f.reason);
+            }
+        }
+        for pack in &report.packs {
+            match (pack.passed(), pack.entries) {
+                (true, n) => println!("PASS {} ({} entries)", pack.archive, n),
+                (false, 0) => println!("FAIL {} (cannot open)", pack.archive),
+                (false, n) => println!("FAIL {} ({} of {} entries)", pack.archive, pack.failed, n),
+            }
+        }
+        let failed_packs = report.packs.iter().filter(|p| !p.passed()).count();
+        info!("[VERIFY] {} archive(s), {} entries checked, {} failure(s)", report.archives, report.entries, report.failures.len());
+        if !report.failures.is_empty() {
+            anyhow::bail!("{} verification failure(s) in {} of {} pack(s)", report.failures.len(), failed_packs, report.archives);
+        }
+    } else if let Some(sub_matches) = matches.subcommand_matches("roundtrip") {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let skey = cli_key_arg(sub_matches, &settings)?.expect("Key required");
+        let opts = pack::PackOptions {
+            compress_ext: sub_matches.get_many::<String>("compress-format").map_or(Vec::new(), |v| v.cloned().collect()),
+            settings: settings.clone(),
+            ..pack::PackOptions::default()
+        };
+        let report = verify::run_roundtrip(input, &skey, &opts)?;
+        for issue in &report.issues {
+            println!("{}", issue);
+        }
+        if !report.issues.is_empty() {
+            anyhow::bail!("{} discrepancy(ies) after packing and extracting '{}' ({} files)", report.issues.len(), input, report.files);
+        }
+        println!("Round trip OK: {} file(s), {} bytes came back identical.", report.files, report.bytes);
+    } else if let Some(sub_matches) = matches.subcommand_matches("snapshot") {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let output = sub_matches.get_one::<String>("output").unwrap();
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+        let threads: usize = sub_matches.get_one::<String>("threads").unwrap().parse()?;
+        let pack_parallel: usize = sub_matches.get_one::<String>("pack-parallel").unwrap().parse()?;
+        let snap = snapshot::run_snapshot(&it_archives(input)?, cli_key, &all_salts, &settings, threads, pack_parallel)?;
+        snap.write_json(output)?;
+        let entries: usize = snap.packs.values().map(|p| p.entries.len()).sum();
+        println!("Recorded {} entries from {} pack(s) in '{}'.", entries, snap.packs.len(), output);
+        for (pack, reason) in &snap.failed {
+            println!("{}: {}", pack, reason);
+        }
+        if !snap.failed.is_empty() {
+            anyhow::bail!("{} pack(s) could not be opened", snap.failed.len());
+        }
+    } else if let Some(sub_matches) = matches.subcommand_matches("compare") {
+        let old = snapshot::Snapshot::load(sub_matches.get_one::<String>("old").unwrap())?;
+        let new = snapshot::Snapshot::load(sub_matches.get_one::<String>("new").unwrap())?;
+        let cmp = snapshot::compare(&old, &new);
+        for pack in &cmp.packs_added {
+            println!("+ pack {}", pack);
+        }
+        for pack in &cmp.packs_removed {
+            println!("- pack {}", pack);
+        }
+        if !sub_matches.get_flag("summary") {
+            print_changes(&cmp);
+        }
+        println!("{} added, {} removed, {} modified, {} moved, {} unchanged.",
+            cmp.count(snapshot::ChangeKind::Added), cmp.count(snapshot::ChangeKind::Removed),
+            cmp.count(snapshot::ChangeKind::Modified), cmp.count(snapshot::ChangeKind::Moved), cmp.unchanged);
+        if let Some(path) = sub_matches.get_one::<String>("output") {
+            cmp.write_json(path)?;
+        }
+    } else if let Some(sub_matches) = matches.subcommand_matches("diff") {
+        let cli_key = sub_matches.get_one::<String>("key").cloned();
+        let old = reader::PackReader::open_with_settings(sub_matches.get_one::<String>("old").unwrap(), cli_key.clone(), &all_salts, &settings)?;
+        let new = reader::PackReader::open_with_settings(sub_matches.get_one::<String>("new").unwrap(), cli_key, &all_salts, &settings)?;
+        let cmp = snapshot::diff_packs(&old, &new, sub_matches.get_flag("content"));
+        if !sub_matches.get_flag("summary") {
+            print_changes(&cmp);
+        }
+        println!("{} added, {} removed, {} changed, {} unchanged.",
+            cmp.count(snapshot::ChangeKind::Added), cmp.count(snapshot::ChangeKind::Removed),
+            cmp.count(snapshot::ChangeKind::Modified), cmp.unchanged);
+        if let Some(path) = sub_matches.get_one::<String>("output") {
+            cmp.write_json(path)?;
+        }
+    } else if let Some(sub_matches) = matches.subcommand_matches("which-key") {
+        let input = sub_matches.get_one::<String>("input").unwrap();
+        let cli_key = sub_matches.get_one::<String>("key").map(|s| s.to_string());
+        let file = std::fs::File::open(input)?;
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        if mmap.len() >= 4 && (&mmap[0..4] == b"PACK" || &mmap[0..4] == b"MABI") {
+            println!("'{}' is a legacy .pack archive; no salt is needed.", input);
+            return Ok(());
+        }
+
+        let (hits, header_only) = if sub_matches.get_flag("all") {
+            let matches = common_ext::search_all_keys(&mmap, input, cli_key, &all_salts, None, &settings)?;
+            (matches.hits, matches.header_only)
+        } else {
+            (vec![common_ext::search_keys(&mmap, input, cli_key, &all_salts, None, &settings)?], Vec::new())
+        };
+        println!("{:<24} {:<24} {:<20} {:>10} {:>4} {:<9} {:>7}", "HEADER SALT", "ENTRIES SALT", "NAME", "OFFSET", "IV0", "MODE", "ENTRIES");
+        for h in &hits {
+            println!("{:<24} {:<24} {:<20} {:>10} {:>4} {:<9} {:>7}", h.header_salt, h.entries_salt, h.name_variant, format!("0x{:X}", h.header_offset), h.iv0, format!("{:?}", h.mode), h.entries.len());
+        }
+        for m in &header_only {
+            println!("{:<24} {:<24} {:<20} {:>10} {:>4} {:<9} {:>7}", m.header_salt, "(header only)", m.name_variant, format!("0x{:X}", m.header_offset), "-", "-", "-");
+        }
+        for m in &header_only {
+            println!("  '{}' at 0x{:X}: best first record {:.2} with '{}' ({} name units). {}",
+                m.header_salt, m.header_offset, m.record_score, m.best_entries_salt, m.name_units, m.advice());
+        }
+        if hits.is_empty() {
+            anyhow::bail!("No key combination unlocks '{}'.", input);
+        }
+    } else if let Some(sub_matches) = matches.subcommand_matches("keystream") {
+        let output = sub_matches.get_one::<String>("output").unwrap();
+        let parse_count = |name: &str| -> Result<Option<u64>> {
+            sub_matches.get_one::<String>(name)
+                .map(|v| v.parse::<u64>().map_err(|_| anyhow::anyhow!("--{} expects a number, got '{}'", name, v)))
+                .transpose()
+        };
+        let (file_key, iv0, mode, default_len) = match sub_matches.get_one::<String>("input") {
+            Some(input) => {
+                let reader = reader::PackReader::open_with_settings(input, sub_matches.get_one::<String>("key").cloned(), &all_salts, &settings)?;
+                let name = sub_matches.get_one::<String>("entry").unwrap();
+                let ent = reader.find(name).ok_or_else(|| anyhow::anyhow!("No entry '{}' in '{}'", name, input))?;
+                let range = reader.block_range(ent)?;
+                (range.file_key, range.iv0, range.mode, Some(range.encrypted_len))
+            }
+            None => {
+                let file_key = parse_key_hex("file-key", sub_matches.get_one::<String>("file-key").unwrap())?;
+                let iv = sub_matches.get_one::<String>("iv").unwrap();
+                let iv0 = iv.parse::<u32>().map_err(|_| anyhow::anyhow!("--iv expects a number, got '{}'", iv))?;
+                (file_key, iv0, sub_matches.get_one::<String>("mode").unwrap().parse()?, None)
+            }
+        };
+        let len = match (parse_count("bytes")?, default_len) {
+            (Some(n), _) => n,
+            (None, Some(0)) => anyhow::bail!("Entry '{}' is not encrypted; pass --bytes to export keystream anyway", sub_matches.get_one::<String>("entry").unwrap()),
+            (None, n) => n.unwrap_or_default(),
+        };
+        let skip = parse_count("skip")?.unwrap_or_default();
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(output)?);
+        encryption::write_keystream(&file_key, iv0, mode, skip, len, &mut writer)?;
+        writer.flush()?;
+        println!("Wrote {} keystream bytes (IV {}, mode {:?}, from byte {}) to '{}'.", len, iv0, mode, skip, output);
+    } else if let Some(sub_matches) = matches.subcommand_matches("keys") {
+        let reader = sub_matches.get_one::<String>("input")
+            .map(|input| reader::PackReader::open_with_settings(input, sub_matches.get_one::<String>("key").cloned(), &all_salts, &settings))
+            .transpose()?;
+        let name = match (sub_matches.get_one::<String>("name"), &reader) {
+            (Some(name), _) if name.is_empty() => String::new(),
+            (Some(name), _) => common::get_final_file_name(name)?,
+            (None, Some(r)) => r.name_variant.clone(),
+            (None, None) => unreachable!("clap requires --name without -i"),
+        };
+        let (header_salt, entries_salt) = match (sub_matches.get_one::<String>("key"), &reader) {
+            (Some(salt), _) => (salt.clone(), sub_matches.get_one::<String>("entries-salt").unwrap_or(salt).clone()),
+            (None, Some(r)) => (r.header_salt.clone(), r.entries_salt.clone()),
+            (None, None) => unreachable!("clap requires --key without -i"),
+        };
+        if name.is_empty() && (header_salt.is_empty() || entries_salt.is_empty()) {
+            anyhow::bail!("An empty name needs a non-empty salt to derive keys from");
+        }
+        let keys = encryption::DerivedKeys::new(&name, &header_salt, &entries_salt);
+        println!("Name:            {}", if name.is_empty() { "<empty>" } else { &name });
+        println!("Header salt:     {}", header_salt);
+        println!("Entries salt:    {}", entries_salt);
+        println!("Header offset:   0x{:X} ({})", keys.header_offset, keys.header_offset);
+        println!("Entries offset:  header + 0x{:X} ({}) = 0x{:X}", keys.entries_offset, keys.entries_offset, keys.header_offset + keys.entries_offset);
+        println!("Header key:      {}", reader::hex(&keys.header_key));
+        println!("Entries key:     {}", reader::hex(&keys.entries_key));
+        if let Some(r) = &reader {
+            println!("Pack header at:  0x{:X} (IV {}, mode {:?})", r.header_offset, r.iv0, r.mode);
+        }
+        if let Some(entry) = sub_matches.get_one::<String>("entry") {
+            let (entry_name, entry_key) = match (sub_matches.get_one::<String>("entry-key"), &reader) {
+                (Some(hex), _) => (entry.clone(), parse_key_hex("entry-key", hex)?),
+                (None, Some(r)) => {
+                    let ent = r.find(entry).ok_or_else(|| anyhow::anyhow!("No entry '{}' in '{}'", entry, r.path))?;
+                    (ent.name.clone(), ent.key)
+                }
+                (None, None) => anyhow::bail!("--entry needs -i or --entry-key"),
+            };
+            println!("Entry:           {}", entry_name);
+            println!("Entry key:       {}", reader::hex(&entry_key));
+            println!("File key:        {}", reader::hex(&encryption::gen_file_key(&entry_name, &entry_key)));
+        }
+    } else if let Some(sub_matches) = matches.subcommand_matches("doctor") {
+        let input = sub_matches.get_one::<String>("input").map(|s| s.as_str());
+        let findings = doctor::run_doctor(input, sub_matches.get_one::<String>("key").cloned(), &settings, &mut std::io::stdout())?;
+        let failed = findings.iter().filter(|f| f.severity == doctor::Severity::Fail).count();
+        let warned = findings.iter().filter(|f| f.severity == doctor::Severity::Warn).count();
+        if failed > 0 {
+            anyhow::bail!("{} check(s) failed; see the hints above", failed);
+        }
+        println!();
+        println!("{}", if warned == 0 { "No problems found.".to_string() } else { format!("{} check(s) need attention; see the hints above.", warned) });
+    } else if matches.subcommand_matches("salts").is_some() {
+        for salt in mabi_pack2::HARDCODED_SALTS {
+            println!("{}\tbuilt-in", salt);
+        }
+        if let Ok(text) = std::fs::read_to_string("salts.txt") {
+            for (salt, alias) in text.lines().filter_map(mabi_pack2::parse_salt_line) {
+                match alias {
+                    Some(alias) => println!("{}\tsalts.txt\t{}", salt, alias),
+                    None => println!("{}\tsalts.txt", salt),
+                }
+            }
+        }
     } else {
         info!("No subcommand provided. Use --help for usage information.");
     }
@@ -141,8 +141,8 @@ fn scan_one(path: &Path, salts: &[String]) -> Option<ArchiveRecord> {
                     if let Ok(Some((header, off, found_iv0, found_mode))) =
                         try_footer(&mut rd, &fname, skey, size, *iv0, *mode)
                     {
-                        if let Ok((_, entries, _)) = common::read_meta_iv_mode(&fname, skey, &mut rd, off, found_iv0, found_mode) {
-                            if common::validate_entries(&entries).is_ok() {
+                        if let Ok((_, entries, _)) = common::read_meta_iv_mode(&fname, skey, &mut rd, off, found_iv0, found_mode, &common::PackSettings::default()) {
+                            if common::validate_entries(&entries, &common::PackSettings::default()).is_ok() {
                                 return Some(make_record(
                                     path, size_bytes, fs_created_secs, fs_modified_secs,
                                     skey, found_iv0, found_mode, &header, entries.len() as u32,
@@ -159,8 +159,8 @@ fn scan_one(path: &Path, salts: &[String]) -> Option<ArchiveRecord> {
                     &mut rd, &fname, skey, formula_offset, *iv0, *mode,
                 ) {
                     let mut rd2 = Cursor::new(&mmap[..]);
-                    if let Ok((_, entries, _)) = common::read_meta_iv_mode(&fname, skey, &mut rd2, formula_offset, *iv0, *mode) {
-                        if common::validate_entries(&entries).is_ok() {
+                    if let Ok((_, entries, _)) = common::read_meta_iv_mode(&fname, skey, &mut rd2, formula_offset, *iv0, *mode, &common::PackSettings::default()) {
+                        if common::validate_entries(&entries, &common::PackSettings::default()).is_ok() {
                             return Some(make_record(
                                 path, size_bytes, fs_created_secs, fs_modified_secs,
                                 skey, *iv0, *mode, &header, entries.len() as u32,
@@ -177,8 +177,8 @@ fn scan_one(path: &Path, salts: &[String]) -> Option<ArchiveRecord> {
                         &mut rd, &fname, skey, shift, *iv0, *mode,
                     ) {
                         let mut rd2 = Cursor::new(&mmap[..]);
-                        if let Ok((_, entries, _)) = common::read_meta_iv_mode(&fname, skey, &mut rd2, shift, *iv0, *mode) {
-                            if common::validate_entries(&entries).is_ok() {
+                        if let Ok((_, entries, _)) = common::read_meta_iv_mode(&fname, skey, &mut rd2, shift, *iv0, *mode, &common::PackSettings::default()) {
+                            if common::validate_entries(&entries, &common::PackSettings::default()).is_ok() {
                                 return Some(make_record(
                                     path, size_bytes, fs_created_secs, fs_modified_secs,
                                     skey, *iv0, *mode, &header, entries.len() as u32,
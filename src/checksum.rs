@@ -0,0 +1,106 @@
+// checksum.rs - Entries table checksum algorithms: the game's sum and experimental variants
+
+use crate::common::FileEntry;
+use anyhow::Error;
+use std::sync::Arc;
+
+/// Computes the 32-bit checksum stored with each entries table record. The pack writer
+/// stores it and every reader checks it, so both sides must use the same algorithm (see
+/// `common::PackSettings::entry_checksum`). Only `StandardChecksum` produces packs the game opens.
+pub trait EntryChecksum: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+    fn checksum(&self, ent: &FileEntry) -> u32;
+}
+
+/// The game's checksum: flags + offset + sizes + byte sum of the entry key (wrapping).
+#[derive(Debug)]
+pub struct StandardChecksum;
+
+impl EntryChecksum for StandardChecksum {
+    fn name(&self) -> &'static str { "standard" }
+
+    fn checksum(&self, ent: &FileEntry) -> u32 {
+        let key_sum = ent.key.iter().fold(0u32, |s, v| s.wrapping_add(*v as u32));
+        ent.flags.wrapping_add(ent.offset).wrapping_add(ent.original_size).wrapping_add(ent.raw_size).wrapping_add(key_sum)
+    }
+}
+
+/// The record's fields and name, in the order the stronger sums hash them.
+fn record_bytes(ent: &FileEntry) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(32 + ent.name.len());
+    for v in [ent.flags, ent.offset, ent.original_size, ent.raw_size] {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+    buf.extend_from_slice(&ent.key);
+    buf.extend_from_slice(ent.name.as_bytes());
+    buf
+}
+
+fn first_word(digest: [u8; 16]) -> u32 {
+    u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// MD5 of the record (name included), cut to 32 bits. Catches swapped or renamed records
+/// that the standard sum can't see.
+#[derive(Debug)]
+pub struct Md5Checksum;
+
+impl EntryChecksum for Md5Checksum {
+    fn name(&self) -> &'static str { "md5" }
+
+    fn checksum(&self, ent: &FileEntry) -> u32 {
+        first_word(md5::compute(record_bytes(ent)).0)
+    }
+}
+
+/// HMAC-MD5 of the record under a secret, cut to 32 bits: only holders of the secret can
+/// write records that validate.
+#[derive(Debug)]
+pub struct KeyedChecksum {
+    inner_pad: Vec<u8>,
+    outer_pad: Vec<u8>,
+}
+
+impl KeyedChecksum {
+    pub fn new(secret: &[u8]) -> Self {
+        let mut block = [0u8; 64];
+        if secret.len() > 64 {
+            block[..16].copy_from_slice(&md5::compute(secret).0);
+        } else {
+            block[..secret.len()].copy_from_slice(secret);
+        }
+        KeyedChecksum {
+            inner_pad: block.iter().map(|b| b ^ 0x36).collect(),
+            outer_pad: block.iter().map(|b| b ^ 0x5C).collect(),
+        }
+    }
+}
+
+impl EntryChecksum for KeyedChecksum {
+    fn name(&self) -> &'static str { "keyed" }
+
+    fn checksum(&self, ent: &FileEntry) -> u32 {
+        let mut inner = md5::Context::new();
+        inner.consume(&self.inner_pad);
+        inner.consume(record_bytes(ent));
+        let mut outer = md5::Context::new();
+        outer.consume(&self.outer_pad);
+        outer.consume(inner.compute().0);
+        first_word(outer.compute().0)
+    }
+}
+
+/// An algorithm from its `--entry-checksum` name: `standard`, `md5` or `keyed:SECRET`.
+pub fn from_spec(spec: &str) -> Result<Arc<dyn EntryChecksum>, Error> {
+    if let Some(secret) = spec.strip_prefix("keyed:") {
+        if secret.is_empty() {
+            return Err(Error::msg("keyed entry checksum needs a secret (keyed:SECRET)"));
+        }
+        return Ok(Arc::new(KeyedChecksum::new(secret.as_bytes())));
+    }
+    match spec.to_lowercase().as_str() {
+        "standard" => Ok(Arc::new(StandardChecksum)),
+        "md5" => Ok(Arc::new(Md5Checksum)),
+        other => Err(Error::msg(format!("Unknown entry checksum '{}' (expected standard, md5 or keyed:SECRET)", other))),
+    }
+}
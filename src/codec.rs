@@ -0,0 +1,254 @@
+// codec.rs - Content codecs: friendlier files on disk, game-native formats in the pack
+
+use crate::hook::{EntryHook, HookDirection};
+use anyhow::Error;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::sync::Arc;
+
+/// Converts entry content between the format stored in packs and the one written to disk.
+/// `decode` runs on extract and `encode` on pack; content a codec doesn't recognise
+/// passes through unchanged both ways, so `encode(decode(x)) == x` for lossless codecs.
+pub trait ContentCodec: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+    /// Extension (without the dot) decoded files get on disk, or `None` to keep the entry's.
+    fn disk_extension(&self) -> Option<&'static str>;
+    fn decode(&self, name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error>;
+    fn encode(&self, name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error>;
+}
+
+/// Leaves content as stored; a later rule replaces an earlier one, so `xml=raw` after a
+/// codecs file turns its `xml` rule off.
+#[derive(Debug)]
+pub struct RawCodec;
+
+impl ContentCodec for RawCodec {
+    fn name(&self) -> &'static str { "raw" }
+    fn disk_extension(&self) -> Option<&'static str> { None }
+    fn decode(&self, _name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error> { Ok(data) }
+    fn encode(&self, _name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error> { Ok(data) }
+}
+
+const UTF16LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+
+/// UTF-16LE text (with a byte order mark, as the game stores XML) becomes UTF-8 with a
+/// byte order mark, and an `encoding="utf-16"` declaration says `utf-8`. Only files that
+/// start with the UTF-8 mark are encoded back, so text that was UTF-8 in the pack stays so.
+#[derive(Debug)]
+pub struct Utf8Codec;
+
+/// Swap the encoding named in an XML declaration at the start of `text`, keeping its case.
+fn swap_declared_encoding(text: &str, from: &str, to: &str) -> String {
+    let Some(end) = text.starts_with("<?xml").then(|| text.find("?>")).flatten() else { return text.to_string() };
+    let prolog = &text[..end];
+    for quote in ['"', '\''] {
+        for (f, t) in [(from.to_lowercase(), to.to_lowercase()), (from.to_uppercase(), to.to_uppercase())] {
+            let needle = format!("encoding={}{}{}", quote, f, quote);
+            if let Some(at) = prolog.find(&needle) {
+                return format!("{}encoding={}{}{}{}", &text[..at], quote, t, quote, &text[at + needle.len()..]);
+            }
+        }
+    }
+    text.to_string()
+}
+
+impl ContentCodec for Utf8Codec {
+    fn name(&self) -> &'static str { "utf8" }
+    fn disk_extension(&self) -> Option<&'static str> { None }
+
+    fn decode(&self, name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if !data.starts_with(UTF16LE_BOM) || !data.len().is_multiple_of(2) {
+            return Ok(data);
+        }
+        let units: Vec<u16> = data[2..].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        let text = String::from_utf16(&units).map_err(|_| Error::msg(format!("{}: not valid UTF-16", name)))?;
+        let mut out = UTF8_BOM.to_vec();
+        out.extend_from_slice(swap_declared_encoding(&text, "utf-16", "utf-8").as_bytes());
+        Ok(out)
+    }
+
+    fn encode(&self, name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let Some(body) = data.strip_prefix(UTF8_BOM) else { return Ok(data) };
+        let text = std::str::from_utf8(body).map_err(|_| Error::msg(format!("{}: starts with a UTF-8 byte order mark but is not valid UTF-8", name)))?;
+        let mut out = UTF16LE_BOM.to_vec();
+        for unit in swap_declared_encoding(text, "utf-8", "utf-16").encode_utf16() {
+            out.extend_from_slice(&unit.to_le_bytes());
+        }
+        Ok(out)
+    }
+}
+
+/// DDS textures become PNG images on disk and are compressed back to BC3 (DXT5) with
+/// mipmaps when packed, as `pack --auto-dds` does. Lossy: a round trip recompresses.
+#[derive(Debug)]
+pub struct PngCodec;
+
+/// Decode the top mip level of a DDS texture into a PNG image.
+pub fn dds_to_png(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let dds = image_dds::ddsfile::Dds::read(&mut Cursor::new(data)).map_err(|e| Error::msg(format!("DDS read failed: {:?}", e)))?;
+    let img = image_dds::image_from_dds(&dds, 0).map_err(|e| Error::msg(format!("DDS decode failed: {:?}", e)))?;
+    let mut buf = Cursor::new(Vec::new());
+    img.write_to(&mut buf, image::ImageFormat::Png).map_err(|e| Error::msg(format!("PNG encode failed: {:?}", e)))?;
+    Ok(buf.into_inner())
+}
+
+/// Compress a PNG image into a BC3 (DXT5) DDS texture with generated mipmaps.
+pub fn png_to_dds(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let img = image::load_from_memory_with_format(data, image::ImageFormat::Png).map_err(|e| Error::msg(format!("Failed to open PNG: {}", e)))?.to_rgba8();
+    let dds = image_dds::dds_from_image(&img, image_dds::ImageFormat::BC3RgbaUnormSrgb, image_dds::Quality::Fast, image_dds::Mipmaps::GeneratedAutomatic)
+        .map_err(|e| Error::msg(format!("DDS conversion failed: {:?}", e)))?;
+    let mut buf = Cursor::new(Vec::new());
+    dds.write(&mut buf).map_err(|e| Error::msg(format!("DDS write failed: {:?}", e)))?;
+    Ok(buf.into_inner())
+}
+
+impl ContentCodec for PngCodec {
+    fn name(&self) -> &'static str { "png" }
+    fn disk_extension(&self) -> Option<&'static str> { Some("png") }
+
+    fn decode(&self, _name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if !data.starts_with(b"DDS ") {
+            return Ok(data);
+        }
+        dds_to_png(&data)
+    }
+
+    fn encode(&self, _name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if !data.starts_with(b"\x89PNG") {
+            return Ok(data);
+        }
+        png_to_dds(&data)
+    }
+}
+
+/// Names accepted by `builtin`.
+pub const BUILTIN_CODECS: &[&str] = &["raw", "utf8", "png"];
+
+/// One of the codecs that ship with the crate, by name (see `BUILTIN_CODECS`).
+pub fn builtin(name: &str) -> Result<Arc<dyn ContentCodec>, Error> {
+    match name.trim().to_lowercase().as_str() {
+        "raw" => Ok(Arc::new(RawCodec)),
+        "utf8" | "utf-8" => Ok(Arc::new(Utf8Codec)),
+        "png" => Ok(Arc::new(PngCodec)),
+        other => Err(Error::msg(format!("Unknown codec '{}' (expected one of: {})", other, BUILTIN_CODECS.join(", ")))),
+    }
+}
+
+/// Which codec applies to entries with each extension.
+#[derive(Debug, Clone, Default)]
+pub struct CodecRegistry {
+    by_ext: BTreeMap<String, Arc<dyn ContentCodec>>,
+}
+
+fn normalize_ext(ext: &str) -> String {
+    ext.trim().trim_start_matches('.').to_lowercase()
+}
+
+fn extension(name: &str) -> Option<String> {
+    let file = name.rsplit(['\\', '/']).next().unwrap_or(name);
+    file.rsplit_once('.').map(|(_, ext)| ext.to_lowercase())
+}
+
+fn with_extension(name: &str, ext: &str) -> String {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    format!("{}.{}", stem, ext)
+}
+
+impl CodecRegistry {
+    pub fn new() -> Self {
+        CodecRegistry::default()
+    }
+
+    /// Use `codec` for entries ending in `.ext`, replacing any earlier rule for it. Two
+    /// extensions can't share a disk extension: packing couldn't tell them apart.
+    pub fn register(&mut self, ext: &str, codec: Arc<dyn ContentCodec>) -> Result<(), Error> {
+        let ext = normalize_ext(ext);
+        if ext.is_empty() {
+            return Err(Error::msg("Codec rule needs an extension"));
+        }
+        if let Some(disk) = codec.disk_extension().filter(|d| *d != ext) {
+            if let Some((other, _)) = self.by_ext.iter().find(|(e, c)| **e != ext && c.disk_extension() == Some(disk)) {
+                return Err(Error::msg(format!("Codec rules for .{} and .{} both write .{} files", other, ext, disk)));
+            }
+        }
+        self.by_ext.insert(ext, codec);
+        Ok(())
+    }
+
+    /// Add a rule of the form `EXT=CODEC` (`dds=png`, `.xml = utf8`) with a built-in codec.
+    pub fn add_rule(&mut self, rule: &str) -> Result<(), Error> {
+        let (ext, name) = rule.split_once('=').ok_or_else(|| Error::msg(format!("Codec rule '{}' is not EXT=CODEC", rule)))?;
+        self.register(ext, builtin(name)?)
+    }
+
+    /// Read rules from a codecs file: one `EXT=CODEC` per line, `#` starts a comment.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path).map_err(|e| Error::msg(format!("Reading codecs file {} failed: {}", path, e)))?;
+        let mut registry = CodecRegistry::new();
+        for (no, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if !line.is_empty() {
+                registry.add_rule(line).map_err(|e| Error::msg(format!("{}:{}: {}", path, no + 1, e)))?;
+            }
+        }
+        Ok(registry)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_ext.is_empty()
+    }
+
+    /// The codec for an entry name, by its extension.
+    pub fn codec_for(&self, entry_name: &str) -> Option<&Arc<dyn ContentCodec>> {
+        self.by_ext.get(&extension(entry_name)?)
+    }
+
+    /// The file name an entry is extracted to.
+    pub fn disk_name(&self, entry_name: &str) -> Option<String> {
+        let disk = self.codec_for(entry_name)?.disk_extension()?;
+        (extension(entry_name)? != disk).then(|| with_extension(entry_name, disk))
+    }
+
+    /// The entry name a file on disk is packed as: the inverse of `disk_name`. Files whose
+    /// extension has a rule of its own keep their name.
+    pub fn entry_name(&self, disk_name: &str) -> Option<String> {
+        let ext = extension(disk_name)?;
+        if self.by_ext.contains_key(&ext) {
+            return None;
+        }
+        let (entry_ext, _) = self.by_ext.iter().find(|(_, c)| c.disk_extension() == Some(ext.as_str()))?;
+        Some(with_extension(disk_name, entry_ext))
+    }
+
+    /// The registry as an `EntryHook` for `ExtractOptions::hook` or `PackOptions::hook`.
+    pub fn hook(self, direction: HookDirection) -> CodecHook {
+        CodecHook { registry: self, direction }
+    }
+}
+
+/// Applies a `CodecRegistry`: decodes and renames entries on extract, renames files and
+/// encodes their content on pack.
+#[derive(Debug)]
+pub struct CodecHook {
+    registry: CodecRegistry,
+    direction: HookDirection,
+}
+
+impl EntryHook for CodecHook {
+    fn rename(&self, name: &str) -> Result<Option<String>, Error> {
+        Ok(match self.direction {
+            HookDirection::Extract => self.registry.disk_name(name),
+            HookDirection::Pack => self.registry.entry_name(name),
+        })
+    }
+
+    /// `name` is the entry name on both sides, so the same rule is found either way.
+    fn transform(&self, name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let Some(codec) = self.registry.codec_for(name) else { return Ok(data) };
+        match self.direction {
+            HookDirection::Extract => codec.decode(name, data),
+            HookDirection::Pack => codec.encode(name, data),
+        }
+    }
+}
@@ -1,13 +1,20 @@
 // common.rs
 
+use crate::checksum::{self, EntryChecksum};
 use crate::encryption;
+use crate::run_summary;
 use anyhow::Error;
 use byte_slice_cast::AsSliceOf;
 use byteorder::{LittleEndian, ReadBytesExt};
+use once_cell::sync::Lazy;
 use std::io::{Cursor, Read, Seek, SeekFrom, ErrorKind as IoErrorKind};
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
-use log::{debug, trace};
+use log::{debug, info, trace};
 
 #[derive(Debug, Clone)]
 pub struct FileHeader { pub checksum: u32, pub version: u8, pub file_cnt: u32 }
@@ -44,15 +51,191 @@ pub struct FileEntry { pub name: String, pub checksum: u32, pub flags: u32, pub
 pub const FLAG_COMPRESSED: u32 = 1;
 pub const FLAG_ALL_ENCRYPTED: u32 = 2;
 pub const FLAG_HEAD_ENCRYPTED: u32 = 4;
+/// Extension (`pack --store-symlinks`): the payload is the UTF-8 target path of a
+/// symbolic link, stored uncompressed and unencrypted.
+pub const FLAG_SYMLINK: u32 = 8;
 
 pub trait StreamPositionProvider { fn current_stream_position(&self) -> u64; }
 impl<'a, R: Read> StreamPositionProvider for encryption::Snow2Decoder<'a, R> { fn current_stream_position(&self) -> u64 { self.current_stream_position() } }
 impl<T: StreamPositionProvider + ?Sized> StreamPositionProvider for &mut T { fn current_stream_position(&self) -> u64 { (**self).current_stream_position() } }
 
+/// Sanity limits applied while decoding the entries table. Implausible values mean the
+/// salt or offset being tried is wrong, so decoding stops early.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryLimits {
+    /// Largest name length field accepted by the parser (UTF-16 units).
+    pub max_name_units: u32,
+    /// Largest decoded name (UTF-8 bytes) accepted as plausible.
+    pub max_name_bytes: usize,
+    /// Largest `original_size` accepted as plausible.
+    pub max_original_size: u32,
+    /// Largest entry count a header may claim.
+    pub max_entries: u32,
+}
+
+impl EntryLimits {
+    /// Limits used for `.it` header versions up to 2 (every known client pack).
+    pub const V2: EntryLimits = EntryLimits { max_name_units: 4096, max_name_bytes: 1024, max_original_size: 500_000_000, max_entries: u32::MAX };
+    /// Relaxed limits for newer header versions, whose layout is not settled yet.
+    pub const V3: EntryLimits = EntryLimits { max_name_units: 32_767, max_name_bytes: 32_767 * 3, max_original_size: u32::MAX, max_entries: u32::MAX };
+    /// Caps for `--untrusted`; every limit in effect is lowered to these.
+    pub const UNTRUSTED: EntryLimits = EntryLimits { max_name_units: 1024, max_name_bytes: 1024, max_original_size: 256 << 20, max_entries: 200_000 };
+
+    pub fn for_version(version: u8) -> Self {
+        if version <= 2 { Self::V2 } else { Self::V3 }
+    }
+
+    /// Limits for `version` with the overrides in `settings` applied.
+    pub fn effective(version: u8, settings: &PackSettings) -> Self {
+        let mut limits = Self::for_version(version);
+        let overrides = settings.entry_limits;
+        if let Some(n) = overrides.max_name_len {
+            limits.max_name_units = n;
+            limits.max_name_bytes = n as usize * 3;
+        }
+        if let Some(n) = overrides.max_entry_size {
+            limits.max_original_size = n;
+        }
+        if settings.untrusted {
+            limits.max_name_units = limits.max_name_units.min(Self::UNTRUSTED.max_name_units);
+            limits.max_name_bytes = limits.max_name_bytes.min(Self::UNTRUSTED.max_name_bytes);
+            limits.max_original_size = limits.max_original_size.min(Self::UNTRUSTED.max_original_size);
+            limits.max_entries = limits.max_entries.min(Self::UNTRUSTED.max_entries);
+        }
+        limits
+    }
+
+    /// Whether a decoded entry looks like real data rather than a wrong-key artifact.
+    pub fn plausible(&self, ent: &FileEntry) -> bool {
+        !ent.name.is_empty() && ent.name.len() <= self.max_name_bytes && ent.original_size <= self.max_original_size
+    }
+}
+
+/// CLI overrides (`--max-name-len`, `--max-entry-size`) for unusual community packs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EntryLimitOverrides {
+    pub max_name_len: Option<u32>,
+    pub max_entry_size: Option<u32>,
+}
+
+/// The global options that change how packs are opened and read, built once by the CLI
+/// and handed down through `ExtractOptions`, `PackOptions` and the other entry points.
+/// The default is what the game itself accepts.
+#[derive(Debug, Clone, Default)]
+pub struct PackSettings {
+    pub entry_limits: EntryLimitOverrides,
+    /// `--original-name`: the file name a renamed pack was published under. Keys derive
+    /// from the name, so the key search tries it before the real one.
+    pub original_name: Option<String>,
+    /// How `--filter` patterns are compiled (`--fixed-strings`, `--regex-size-limit`).
+    pub filters: crate::extract::FilterSettings,
+    /// `--entry-checksum`: the algorithm packs are written and entries tables validated
+    /// with. `None` is the game's (`checksum::StandardChecksum`).
+    pub entry_checksum: Option<Arc<dyn EntryChecksum>>,
+    /// `--deep-scan`: the offset scan probes every byte of this range instead.
+    pub deep_scan: Option<std::ops::Range<u64>>,
+    /// `--allow-unsafe-paths`: `output_path` accepts paths leaving the output folder.
+    pub allow_unsafe_paths: bool,
+    /// `--untrusted`: entry limits are capped at `EntryLimits::UNTRUSTED`, packs with flag
+    /// bits this tool doesn't know are refused (see `check_entry_policy`) and nothing is
+    /// fetched from the network (remote salts, `RemotePack`, `doctor`).
+    pub untrusted: bool,
+    /// `--unknown-flags`: what happens to entries with flag bits outside `KNOWN_FLAGS`.
+    pub unknown_flags: UnknownFlagsPolicy,
+    /// `--guess-name`: when every usual name fails, the key search goes on with
+    /// `original_name_guesses` (see `key_name_variants`).
+    pub guess_names: bool,
+    /// `--force`: accept an entries table in which some records fail their checksum, as
+    /// long as most of them pass (a wrong key fails nearly all of them).
+    /// `PackReader::checksum_failures` lists the bad records.
+    pub force: bool,
+    /// `--scan-offsets` / `--auto-escalate`: how far a key search goes after the usual
+    /// header offsets fail.
+    pub escalation: Escalation,
+    /// `--wait-for-data`: entry data past the end of the pack is waited for (the file is
+    /// still downloading) until it stops growing for this long.
+    pub wait_for_data: Option<Duration>,
+}
+
+impl PackSettings {
+    /// Entry table checksum of `ent` with the `entry_checksum` algorithm.
+    pub fn checksum(&self, ent: &FileEntry) -> u32 {
+        match &self.entry_checksum {
+            Some(algo) => algo.checksum(ent),
+            None => ent.calc_checksum(),
+        }
+    }
+
+    /// Name of the `entry_checksum` algorithm, unless it is the game's.
+    pub fn entry_checksum_name(&self) -> Option<&'static str> {
+        self.entry_checksum.as_ref().map(|algo| algo.name()).filter(|name| *name != "standard")
+    }
+}
+
+/// What to do with entries carrying flag bits outside `KNOWN_FLAGS` (`--unknown-flags`).
+/// The bits may mark a format feature this tool doesn't implement yet, so the data
+/// might not decode the way it is meant to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownFlagsPolicy {
+    /// Process the entry as if the bits weren't there, without a word.
+    Ignore,
+    /// Process it and report it among the suspicious entries.
+    #[default]
+    Warn,
+    /// Refuse the pack.
+    Error,
+}
+
+impl std::str::FromStr for UnknownFlagsPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_lowercase().as_str() {
+            "ignore" => Ok(UnknownFlagsPolicy::Ignore),
+            "warn" => Ok(UnknownFlagsPolicy::Warn),
+            "error" | "fail" => Ok(UnknownFlagsPolicy::Error),
+            other => Err(Error::msg(format!("Unknown --unknown-flags policy '{}' (expected ignore, warn or error)", other))),
+        }
+    }
+}
+
+/// The `--unknown-flags` policy in effect; always `Error` under `--untrusted`.
+pub fn unknown_flags_policy(settings: &PackSettings) -> UnknownFlagsPolicy {
+    if settings.untrusted {
+        return UnknownFlagsPolicy::Error;
+    }
+    settings.unknown_flags
+}
+
+/// Fail if an entry carries flag bits outside `KNOWN_FLAGS` and the policy is
+/// `UnknownFlagsPolicy::Error`, or if under `--untrusted` in `settings` the decoded table
+/// has more entries than allowed.
+pub fn check_entry_policy<'a>(entries: impl IntoIterator<Item = &'a FileEntry>, settings: &PackSettings) -> Result<(), Error> {
+    let refuse_flags = unknown_flags_policy(settings) == UnknownFlagsPolicy::Error;
+    if !refuse_flags && !settings.untrusted {
+        return Ok(());
+    }
+    let mut count = 0u64;
+    for ent in entries {
+        if refuse_flags && ent.flags & !KNOWN_FLAGS != 0 {
+            let why = if settings.untrusted { "refused with --untrusted" } else { "refused with --unknown-flags error" };
+            return Err(Error::msg(format!("Entry {} has unknown flag bits 0x{:x} ({})", ent.name, ent.flags & !KNOWN_FLAGS, why)));
+        }
+        count += 1;
+    }
+    if settings.untrusted && count > EntryLimits::UNTRUSTED.max_entries as u64 {
+        return Err(Error::msg(format!("Pack has {} entries, more than the {} allowed with --untrusted", count, EntryLimits::UNTRUSTED.max_entries)));
+    }
+    Ok(())
+}
+
 impl FileEntry {
     pub fn new<R>(reader: &mut R) -> Result<Self, std::io::Error> where R: Read + StreamPositionProvider + ?Sized, {
+        Self::new_with_limits(reader, &EntryLimits::V2)
+    }
+
+    pub fn new_with_limits<R>(reader: &mut R, limits: &EntryLimits) -> Result<Self, std::io::Error> where R: Read + StreamPositionProvider + ?Sized, {
         let str_len_u32 = reader.read_u32::<LittleEndian>()?;
-        if str_len_u32 == 0 || str_len_u32 > 4096 { return Err(std::io::Error::new(IoErrorKind::InvalidData, format!("Suspicious filename length: {}", str_len_u32))); }
+        if str_len_u32 == 0 || str_len_u32 > limits.max_name_units { return Err(std::io::Error::new(IoErrorKind::InvalidData, format!("Suspicious filename length: {}", str_len_u32))); }
         let mut fname_bytes = vec![0u8; str_len_u32 as usize * 2];
         reader.read_exact(&mut fname_bytes)?;
         let fname_string = String::from_utf16(fname_bytes.as_slice_of::<u16>().map_err(|_| std::io::Error::new(IoErrorKind::InvalidData, "filename bytes not aligned"))?).map_err(|e| std::io::Error::new(IoErrorKind::InvalidData, e))?;
@@ -65,35 +248,593 @@ impl FileEntry {
         reader.read_exact(&mut key)?;
         Ok(FileEntry { name: fname_string, checksum, flags, offset, original_size, raw_size, key })
     }
+
+    /// A zero-length entry whose name ends in a path separator, written by
+    /// `pack --keep-empty-dirs` to record an empty folder.
+    pub fn is_dir_marker(&self) -> bool {
+        self.original_size == 0 && self.raw_size == 0 && (self.name.ends_with('\\') || self.name.ends_with('/'))
+    }
+
+    /// The game's entry table checksum: flags + offset + sizes + byte sum of the entry key.
+    /// `PackSettings::checksum` applies `--entry-checksum`.
+    pub fn calc_checksum(&self) -> u32 {
+        checksum::StandardChecksum.checksum(self)
+    }
+
+    /// Odd but recoverable things about this entry (see `EntryWarningKind`). Unknown flag
+    /// bits are left out when `settings` ignores them.
+    pub fn warnings(&self, settings: &PackSettings) -> Vec<EntryWarning> {
+        let mut found = Vec::new();
+        let mut warn = |kind, detail: String| found.push(EntryWarning { entry: self.name.clone(), kind, detail });
+        if self.flags & FLAG_COMPRESSED != 0 && self.raw_size == 0 && !self.is_dir_marker() {
+            warn(EntryWarningKind::EmptyCompressed, format!("compressed but stores no data ({} bytes expected)", self.original_size));
+        }
+        if self.flags & !KNOWN_FLAGS != 0 && unknown_flags_policy(settings) != UnknownFlagsPolicy::Ignore {
+            warn(EntryWarningKind::UnknownFlags, format!("unknown flag bits 0x{:X} (flags 0x{:X})", self.flags & !KNOWN_FLAGS, self.flags));
+        }
+        if let Some(reason) = windows_name_problem(&self.name) {
+            warn(EntryWarningKind::ReservedName, reason);
+        }
+        found
+    }
+}
+
+/// Every flag bit this tool understands.
+pub const KNOWN_FLAGS: u32 = FLAG_COMPRESSED | FLAG_ALL_ENCRYPTED | FLAG_HEAD_ENCRYPTED | FLAG_SYMLINK;
+
+/// `flags` as text, e.g. `compressed|head-encrypted`; unknown bits are kept as hex and
+/// no flags at all gives an empty string.
+pub fn flag_names(flags: u32) -> String {
+    let mut names: Vec<String> = [
+        (FLAG_COMPRESSED, "compressed"),
+        (FLAG_ALL_ENCRYPTED, "all-encrypted"),
+        (FLAG_HEAD_ENCRYPTED, "head-encrypted"),
+        (FLAG_SYMLINK, "symlink"),
+    ].iter().filter(|(bit, _)| flags & bit != 0).map(|(_, name)| name.to_string()).collect();
+    if flags & !KNOWN_FLAGS != 0 {
+        names.push(format!("0x{:x}", flags & !KNOWN_FLAGS));
+    }
+    names.join("|")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryWarningKind {
+    /// `FLAG_COMPRESSED` set with `raw_size == 0`: extracts as an empty file.
+    EmptyCompressed,
+    /// Flag bits other than `KNOWN_FLAGS`; they are ignored (see `UnknownFlagsPolicy`).
+    UnknownFlags,
+    /// The name can't be created as-is on Windows.
+    ReservedName,
+    /// The record fails its entries table checksum; only seen with `--force`.
+    BadChecksum,
+}
+
+/// A suspicious but recoverable entry, collected into `OperationSummary::entry_warnings`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct EntryWarning {
+    pub entry: String,
+    pub kind: EntryWarningKind,
+    pub detail: String,
+}
+
+/// Warnings for every entry in `entries`.
+pub fn entry_warnings<'a>(entries: impl IntoIterator<Item = &'a FileEntry>, settings: &PackSettings) -> Vec<EntryWarning> {
+    entries.into_iter().flat_map(|ent| ent.warnings(settings)).collect()
+}
+
+/// Why a path segment of `name` can't be a Windows file name: reserved characters,
+/// device names (`CON`, `COM1.txt`, ...) or a trailing dot or space.
+fn windows_name_problem(name: &str) -> Option<String> {
+    for seg in name.split(['\\', '/']).filter(|s| !s.is_empty()) {
+        if let Some(c) = seg.chars().find(|&c| crate::names::is_reserved_char(c)) {
+            return Some(format!("'{}' contains {:?}, which Windows does not allow in names", seg, c));
+        }
+        if crate::names::is_device_name(seg) {
+            return Some(format!("'{}' is a reserved Windows device name", seg));
+        }
+        if seg.ends_with('.') || seg.ends_with(' ') {
+            return Some(format!("'{}' ends with a dot or space, which Windows drops", seg));
+        }
+    }
+    None
 }
 
 pub fn get_final_file_name(fname: &str) -> Result<String, Error> {
     Path::new(fname).file_name().ok_or_else(|| Error::msg("not a valid file path")).map(|s| s.to_str().unwrap_or("").to_owned())
 }
 
-pub fn validate_entries(entries: &[FileEntry]) -> Result<(), Error> {
-    for (idx, ent) in entries.iter().enumerate() {
-        let key_sum = ent.key.iter().fold(0u32, |s, v| s.wrapping_add(*v as u32));
-        let calculated_sum = ent.flags.wrapping_add(ent.offset).wrapping_add(ent.original_size).wrapping_add(ent.raw_size).wrapping_add(key_sum);
-        if calculated_sum != ent.checksum {
-            trace!("[ENTRIES] Entry {} checksum wrong. Name='{}'. Calc: 0x{:X}, Entry: 0x{:X}.", idx, ent.name, calculated_sum, ent.checksum);
-            return Err(Error::msg(format!("entry checksum wrong, file name: {}", ent.name)));
+/// Most names `canonical_name_guesses` returns, so a failing search stays bounded.
+pub const MAX_NAME_GUESSES: usize = 4;
+
+static COPY_EXTENSION: Lazy<regex::Regex> = Lazy::new(|| regex::Regex::new(r"(?i)\.(bak|old|orig|tmp|part|crdownload|download)$").unwrap());
+static COPY_SUFFIX: Lazy<regex::Regex> = Lazy::new(|| regex::Regex::new(r"(?i)(\s*-\s*copy(\s*\(\d+\))?|[ _-]copy|\s*\(\d+\)|\s*\[\d+\]|~\d+)$").unwrap());
+
+/// Names a renamed copy of a pack probably had: backup and download extensions
+/// (`.bak`, `.crdownload`) and copy suffixes (`(1)`, ` - Copy`) stripped, then the
+/// lowercase form. The name itself is not included.
+pub fn canonical_name_guesses(fname: &str) -> Vec<String> {
+    let mut name = fname.to_string();
+    let mut guesses: Vec<String> = Vec::new();
+    loop {
+        let stripped = COPY_EXTENSION.replace(&name, "").into_owned();
+        let stripped = match stripped.len().checked_sub(3).filter(|&i| stripped.is_char_boundary(i) && stripped[i..].eq_ignore_ascii_case(".it")) {
+            Some(i) => format!("{}{}", COPY_SUFFIX.replace(&stripped[..i], ""), &stripped[i..]),
+            None => COPY_SUFFIX.replace(&stripped, "").into_owned(),
+        };
+        if stripped == name || stripped.is_empty() {
+            break;
+        }
+        name = stripped;
+        guesses.push(name.clone());
+    }
+    // Only the fully stripped name and its lowercase form are worth a full search each.
+    let mut out: Vec<String> = guesses.pop().into_iter().collect();
+    let base = out.first().map_or(fname, |s| s.as_str()).to_lowercase();
+    if base != fname && !out.contains(&base) {
+        out.push(base);
+    }
+    out.extend(guesses.into_iter().rev());
+    out.truncate(MAX_NAME_GUESSES);
+    out
+}
+
+/// File names to derive keys from for `fname_str`, in search order: `--original-name`,
+/// the real name, `extra` (a region override), `data.it`, the empty name, the guesses
+/// from `canonical_name_guesses`, then with `--guess-name` those of `original_name_guesses`.
+pub fn key_name_variants(fname_str: &str, extra: Option<String>, settings: &PackSettings) -> Result<Vec<String>, Error> {
+    let mut variants = usual_name_variants(fname_str, extra, settings.original_name.clone())?;
+    if settings.guess_names {
+        for name in original_name_guesses(fname_str)? {
+            if !variants.contains(&name) {
+                variants.push(name);
+            }
+        }
+    }
+    Ok(variants)
+}
+
+fn usual_name_variants(fname_str: &str, extra: Option<String>, original: Option<String>) -> Result<Vec<String>, Error> {
+    let real = get_final_file_name(fname_str)?;
+    let guesses = canonical_name_guesses(&real);
+    let mut variants: Vec<String> = Vec::new();
+    for name in original.into_iter().chain(std::iter::once(real)).chain(extra).chain(["data.it".to_string(), String::new()]).chain(guesses) {
+        if !variants.contains(&name) {
+            variants.push(name);
+        }
+    }
+    Ok(variants)
+}
+
+/// How far a key search goes after the usual header offsets fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Escalation {
+    /// Stop and suggest `--auto-escalate`.
+    #[default]
+    Off,
+    /// `--scan-offsets`: also probe every offset from `scan_header_offsets`.
+    ScanOffsets,
+    /// `--auto-escalate`: the offset scan, then every entries-table offset behind the
+    /// headers it found (`BRUTE_ENTRIES_WINDOW`).
+    Full,
+}
+
+/// Every offset below this is probed for a header by `--scan-offsets`.
+pub const SCAN_OFFSETS_WINDOW: u64 = 4096;
+/// Past `SCAN_OFFSETS_WINDOW`, `--scan-offsets` probes 1024-byte boundaries up to here.
+pub const SCAN_OFFSETS_LIMIT: u64 = 1 << 20;
+/// Bytes after a header that `--auto-escalate` tries as the start of the entries table.
+pub const BRUTE_ENTRIES_WINDOW: u64 = 1024;
+
+/// Header offsets probed by `--scan-offsets` in a file of `len` bytes, ascending: every
+/// byte below `SCAN_OFFSETS_WINDOW`, then every 1024-byte boundary below `SCAN_OFFSETS_LIMIT`.
+/// With `--deep-scan` in `settings`, every byte of its range instead.
+pub fn scan_header_offsets(len: u64, settings: &PackSettings) -> Vec<u64> {
+    let end = len.saturating_sub(9);
+    if let Some(range) = &settings.deep_scan {
+        return (range.start..range.end.min(end)).collect();
+    }
+    (0..SCAN_OFFSETS_WINDOW.min(end))
+        .chain((SCAN_OFFSETS_WINDOW..SCAN_OFFSETS_LIMIT.min(end)).step_by(1024))
+        .collect()
+}
+
+/// Fixed names Mabinogi publishes packs under, tried by `--guess-name`.
+pub const KNOWN_PACK_NAMES: &[&str] = &["language.it", "data_00000.it"];
+
+/// Most names `original_name_guesses` returns: each one is a full salt search.
+pub const MAX_ORIGINAL_GUESSES: usize = 16;
+
+/// Names a pack renamed beyond `canonical_name_guesses` may have been published under,
+/// lowercase and in search order: the name cut after its first `.it` (or given one),
+/// Mabinogi package names built from the numbers in it (`NNN_full.it`, `NNN_to_MMM.it`,
+/// `data_NNNNN.it`), then `KNOWN_PACK_NAMES`. Names the search tries anyway are left out.
+pub fn original_name_guesses(fname_str: &str) -> Result<Vec<String>, Error> {
+    let lower = get_final_file_name(fname_str)?.to_lowercase();
+    let cut = match lower.match_indices(".it").find(|(i, _)| matches!(lower.as_bytes().get(i + 3), None | Some(b'.'))) {
+        Some((i, _)) => lower[..i + 3].to_string(),
+        None => format!("{}.it", lower.split('.').next().unwrap_or_default()),
+    };
+    let numbers: Vec<u64> = cut.split(|c: char| !c.is_ascii_digit())
+        .filter(|run| !run.is_empty() && run.len() <= 9)
+        .filter_map(|run| run.parse().ok())
+        .collect();
+    let mut names = vec![cut];
+    names.extend(numbers.windows(2).map(|w| format!("{}_to_{}.it", w[0], w[1])));
+    for n in &numbers {
+        names.push(format!("{}_full.it", n));
+        names.push(format!("data_{:05}.it", n));
+    }
+    names.extend(KNOWN_PACK_NAMES.iter().map(|n| n.to_string()));
+
+    let tried = usual_name_variants(fname_str, None, None)?;
+    let mut guesses: Vec<String> = Vec::new();
+    for name in names {
+        if name != ".it" && !tried.contains(&name) && !guesses.contains(&name) {
+            guesses.push(name);
+        }
+    }
+    guesses.truncate(MAX_ORIGINAL_GUESSES);
+    Ok(guesses)
+}
+
+/// Log which guessed name and salts opened `fname_str`, when `name` came from
+/// `original_name_guesses`, so the name can be passed with `--original-name` next time.
+pub fn report_name_guess(fname_str: &str, name: &str, header_salt: &str, entries_salt: &str, settings: &PackSettings) {
+    if settings.guess_names && original_name_guesses(fname_str).is_ok_and(|g| g.iter().any(|n| n == name)) {
+        info!("[KEY_SEARCH] '{}' opens as original name '{}' (HEADER='{}', ENTRIES='{}'); pass --original-name {} to skip guessing",
+            fname_str, name, header_salt, entries_salt, name);
+    }
+}
+
+/// An entries table record whose stored checksum doesn't match its contents.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct EntryChecksumFailure {
+    /// Position of the record in the entries table.
+    pub index: usize,
+    pub name: String,
+    /// The checksum stored with the record.
+    pub expected: u32,
+    /// The checksum its contents give (`PackSettings::checksum`).
+    pub actual: u32,
+}
+
+impl std::fmt::Display for EntryChecksumFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "entry {} checksum 0x{:X} != stored 0x{:X}", self.index, self.actual, self.expected)
+    }
+}
+
+/// Stops at the first bad record, which is all a key search needs; see
+/// `validate_all_entries` for the full list.
+pub fn validate_entries(entries: &[FileEntry], settings: &PackSettings) -> Result<(), Error> {
+    match checksum_failures(entries, settings).next() {
+        Some(failure) => {
+            trace!("[ENTRIES] Entry {} checksum wrong. Name='{}'. Calc: 0x{:X}, Entry: 0x{:X}.", failure.index, failure.name, failure.actual, failure.expected);
+            Err(Error::msg(format!("entry checksum wrong, file name: {}", failure.name)))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Every record of `entries` whose checksum is wrong, in table order.
+pub fn validate_all_entries(entries: &[FileEntry], settings: &PackSettings) -> Vec<EntryChecksumFailure> {
+    checksum_failures(entries, settings).collect()
+}
+
+fn checksum_failures<'a>(entries: &'a [FileEntry], settings: &'a PackSettings) -> impl Iterator<Item = EntryChecksumFailure> + 'a {
+    entries.iter().enumerate().filter_map(|(index, ent)| {
+        let actual = settings.checksum(ent);
+        (actual != ent.checksum).then(|| EntryChecksumFailure { index, name: ent.name.clone(), expected: ent.checksum, actual })
+    })
+}
+
+/// Whether a decoded entries table is taken as the right one: every checksum passes,
+/// or with `--force` in `settings`, more than half of them do.
+pub fn entries_accepted(entries: &[FileEntry], settings: &PackSettings) -> bool {
+    if !settings.force {
+        return validate_entries(entries, settings).is_ok();
+    }
+    let failed = checksum_failures(entries, settings).count();
+    if failed > 0 && failed * 2 < entries.len() {
+        debug!("[ENTRIES] Accepting a table with {} bad checksum(s) in {} records (--force)", failed, entries.len());
+    }
+    failed * 2 < entries.len().max(1)
+}
+
+/// How far a single header/entries probe got before it failed (ordered by progress).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProbeStage { HeaderRead, HeaderChecksum, EntriesRead, EntriesChecksum, Success }
+
+impl ProbeStage {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            ProbeStage::HeaderRead => "header read",
+            ProbeStage::HeaderChecksum => "header checksum",
+            ProbeStage::EntriesRead => "entries read",
+            ProbeStage::EntriesChecksum => "entries checksum",
+            ProbeStage::Success => "ok",
+        }
+    }
+}
+
+/// Outcome of a pack, extract or list run, for library callers and the CLI's `--report`.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct OperationSummary {
+    /// Entries packed, extracted or listed.
+    pub entries: usize,
+    /// Entries that could not be processed and were skipped.
+    pub failed: usize,
+    /// Decoded size of those entries.
+    pub bytes: u64,
+    /// Salt of the header (and of the entries table when it differs); `None` for legacy packs.
+    pub salt: Option<String>,
+    pub entries_salt: Option<String>,
+    /// `mabi`, `logue` or `pack` when the input was a legacy .pack archive.
+    pub legacy_format: Option<&'static str>,
+    /// Problems that didn't stop the run (also sent to the log).
+    pub warnings: Vec<String>,
+    /// Existing output files left untouched because they matched `--protect`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub protected: Vec<String>,
+    /// Entries not written because their path would leave the output folder.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rejected: Vec<String>,
+    /// Output files another process kept locked; they were left as they were.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub locked: Vec<String>,
+    /// Locked output files staged to replace the originals at the next reboot.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub scheduled: Vec<String>,
+    /// Existing files copied to `--backup-dir` before being overwritten.
+    pub backed_up: usize,
+    /// Files and decoded bytes per extension (see `extension_of`); filled by extraction.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub by_extension: BTreeMap<String, ExtensionCount>,
+    /// Suspicious entries that were still processed (see `FileEntry::warnings`).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub entry_warnings: Vec<EntryWarning>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct ExtensionCount {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Lowercase extension of an entry name with its dot (`.xml`), or `(none)`.
+pub fn extension_of(name: &str) -> String {
+    let base = name.rsplit(['\\', '/']).next().unwrap_or(name);
+    match base.rfind('.') {
+        Some(i) if i > 0 => base[i..].to_lowercase(),
+        _ => "(none)".to_string(),
+    }
+}
+
+impl OperationSummary {
+    pub fn with_salts(mut self, header_salt: &str, entries_salt: &str) -> Self {
+        self.salt = Some(header_salt.to_string());
+        self.entries_salt = Some(entries_salt.to_string());
+        self
+    }
+
+    pub fn legacy(format: &'static str, entries: &[FileEntry]) -> Self {
+        let mut summary = OperationSummary {
+            entries: entries.len(),
+            bytes: entries.iter().map(|e| e.original_size as u64).sum(),
+            legacy_format: Some(format),
+            ..OperationSummary::default()
+        };
+        for ent in entries.iter().filter(|e| !e.is_dir_marker()) {
+            summary.count_extension(&ent.name, ent.original_size as u64);
+        }
+        // Legacy tables only know the compressed flag, so the unknown-flags policy never applies.
+        summary.entry_warnings = entry_warnings(entries, &PackSettings::default());
+        summary
+    }
+
+    /// Add one file of `bytes` to `by_extension`.
+    pub fn count_extension(&mut self, name: &str, bytes: u64) {
+        let slot = self.by_extension.entry(extension_of(name)).or_default();
+        slot.files += 1;
+        slot.bytes += bytes;
+    }
+
+    /// Add the counts and lists of another run into this one (salts are kept as they are).
+    pub fn absorb(&mut self, other: OperationSummary) {
+        self.entries += other.entries;
+        self.failed += other.failed;
+        self.bytes += other.bytes;
+        self.backed_up += other.backed_up;
+        self.warnings.extend(other.warnings);
+        self.protected.extend(other.protected);
+        self.rejected.extend(other.rejected);
+        self.locked.extend(other.locked);
+        self.scheduled.extend(other.scheduled);
+        self.entry_warnings.extend(other.entry_warnings);
+        for (ext, count) in other.by_extension {
+            let slot = self.by_extension.entry(ext).or_default();
+            slot.files += count.files;
+            slot.bytes += count.bytes;
+        }
+    }
+
+    /// Write the summary as pretty-printed JSON.
+    pub fn write_json(&self, path: &str) -> Result<(), Error> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// One (salt, offset, iv0, mode) combination tried during a key search.
+#[derive(Debug, Clone)]
+pub struct ProbeAttempt {
+    pub name_variant: String,
+    pub header_salt: String,
+    pub entries_salt: Option<String>,
+    pub offset: u64,
+    pub iv0: u32,
+    pub mode: encryption::Snow2Mode,
+    pub stage: ProbeStage,
+    pub times: ProbeTimes,
+}
+
+/// Wall time spent in each phase of one probe.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProbeTimes {
+    pub header: Duration,
+    pub entries_decode: Duration,
+    pub validation: Duration,
+}
+
+/// Collector for probe attempts; shared between search worker threads.
+pub type SearchTrace = Mutex<Vec<ProbeAttempt>>;
+
+/// How often `wait_for_len` checks whether the pack grew.
+pub const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// With `--wait-for-data` in `settings`, block until the stream behind `rd` is at least
+/// `needed` bytes long, failing once it hasn't grown for the idle time. Returns at once otherwise.
+pub fn wait_for_len<R: Seek>(rd: &mut R, needed: u64, what: &str, settings: &PackSettings) -> Result<(), Error> {
+    let Some(idle) = settings.wait_for_data else { return Ok(()) };
+    let mut len = rd.seek(SeekFrom::End(0))?;
+    if len >= needed {
+        return Ok(());
+    }
+    info!("[WAIT] {} needs {} bytes of the pack, {} there so far; waiting for the download...", what, needed, len);
+    let mut grown_at = Instant::now();
+    while len < needed {
+        if grown_at.elapsed() >= idle {
+            return Err(Error::msg(format!("{} needs {} bytes of the pack, but it stopped growing at {} bytes for {:.0?}", what, needed, len, idle)));
+        }
+        std::thread::sleep(WAIT_POLL_INTERVAL);
+        let now = rd.seek(SeekFrom::End(0))?;
+        if now > len {
+            len = now;
+            grown_at = Instant::now();
         }
     }
+    debug!("[WAIT] {} is available", what);
     Ok(())
 }
 
-pub fn try_read_and_validate_header_iv<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &str, offset: u64, iv0: u32, mode: encryption::Snow2Mode) -> Result<Option<(FileHeader, u64)>, Error> {
-    rd.seek(SeekFrom::Start(offset))?;
+/// `--deep-scan` without a range: every byte up to `SCAN_OFFSETS_LIMIT`.
+pub const DEEP_SCAN_DEFAULT: std::ops::Range<u64> = 0..SCAN_OFFSETS_LIMIT;
+
+/// Parse a `START..END` byte range (either end decimal or `0x` hex), such as `0..4096`.
+pub fn parse_offset_range(spec: &str) -> Result<std::ops::Range<u64>, Error> {
+    let bad = || Error::msg(format!("'{}' is not a START..END byte range (e.g. 0..4096 or 0x400..0x10000)", spec));
+    let (start, end) = spec.split_once("..").ok_or_else(bad)?;
+    let parse = |v: &str| {
+        let v = v.trim();
+        match v.strip_prefix("0x").or_else(|| v.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => v.parse::<u64>(),
+        }.map_err(|_| bad())
+    };
+    let range = parse(start)?..parse(end)?;
+    if range.is_empty() {
+        return Err(Error::msg(format!("The range '{}' is empty", spec)));
+    }
+    Ok(range)
+}
+
+/// How often a running key search logs how far it got.
+pub const SEARCH_PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// (finished, total) salt/name combinations of every key search in progress, by
+/// `SearchProgress::id`. Searches run side by side under `--pack-parallel` and the daemon.
+static ACTIVE_SEARCHES: Mutex<BTreeMap<u64, (u64, u64)>> = Mutex::new(BTreeMap::new());
+static NEXT_SEARCH_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Counts the salt/name combinations a key search has finished and, once the search runs
+/// longer than `SEARCH_PROGRESS_INTERVAL`, logs the count and the latest combination at
+/// that interval. While one is alive `search_progress()` reports its counts.
+pub struct SearchProgress {
+    id: u64,
+    total: u64,
+    done: std::sync::atomic::AtomicU64,
+    last_report: Mutex<Instant>,
+}
+
+impl SearchProgress {
+    pub fn new(total: u64) -> Self {
+        let id = NEXT_SEARCH_ID.fetch_add(1, Ordering::Relaxed);
+        ACTIVE_SEARCHES.lock().unwrap().insert(id, (0, total));
+        SearchProgress { id, total, done: Default::default(), last_report: Mutex::new(Instant::now()) }
+    }
+
+    /// Key of this search in `search_progress()`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Combinations marked as tried so far.
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// Mark the combination of `salt` and `name` as tried.
+    pub fn tick(&self, salt: &str, name: &str) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(active) = ACTIVE_SEARCHES.lock().unwrap().get_mut(&self.id) {
+            active.0 = active.0.max(done);
+        }
+        let mut last = self.last_report.lock().unwrap();
+        if last.elapsed() >= SEARCH_PROGRESS_INTERVAL {
+            *last = Instant::now();
+            info!("[KEY_SEARCH] Tried {} of {} salt/name combinations ({}%), latest: salt '{}' for '{}'",
+                done, self.total, done * 100 / self.total.max(1), salt, name);
+        }
+    }
+}
+
+impl Drop for SearchProgress {
+    fn drop(&mut self) {
+        ACTIVE_SEARCHES.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// (finished, total) combinations of every key search running now, by `SearchProgress::id`,
+/// e.g. for a report when the user interrupts them.
+pub fn search_progress() -> BTreeMap<u64, (u64, u64)> {
+    ACTIVE_SEARCHES.lock().unwrap().clone()
+}
+
+fn record_probe(trace: Option<&SearchTrace>, fname: &str, header_salt: &str, entries_salt: Option<&str>, offset: u64, iv0: u32, mode: encryption::Snow2Mode, stage: ProbeStage, times: ProbeTimes) {
+    if let Some(t) = trace {
+        if let Ok(mut v) = t.lock() {
+            v.push(ProbeAttempt { name_variant: fname.to_string(), header_salt: header_salt.to_string(), entries_salt: entries_salt.map(|s| s.to_string()), offset, iv0, mode, stage, times });
+        }
+    }
+}
+
+/// Read and validate the header at `offset`, reporting which step failed.
+pub fn probe_header_iv<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &str, offset: u64, iv0: u32, mode: encryption::Snow2Mode) -> Result<FileHeader, ProbeStage> {
+    rd.seek(SeekFrom::Start(offset)).map_err(|_| ProbeStage::HeaderRead)?;
     let key = encryption::gen_header_key(fname, skey);
     let mut dec_stream = encryption::Snow2Decoder::new_iv_mode(&key, iv0, mode, rd);
-    if let Ok(header) = FileHeader::new(&mut dec_stream) {
-        if validate_header(&header).is_ok() { return Ok(Some((header, offset + 9))); }
+    let header = FileHeader::new(&mut dec_stream).map_err(|_| ProbeStage::HeaderRead)?;
+    validate_header(&header).map_err(|_| ProbeStage::HeaderChecksum)?;
+    Ok(header)
+}
+
+pub fn try_read_and_validate_header_iv<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &str, offset: u64, iv0: u32, mode: encryption::Snow2Mode) -> Result<Option<(FileHeader, u64)>, Error> {
+    rd.seek(SeekFrom::Start(offset))?;
+    Ok(probe_header_iv(rd, fname, skey, offset, iv0, mode).ok().map(|header| (header, offset + 9)))
+}
+
+fn try_header_traced<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &str, offset: u64, iv0: u32, mode: encryption::Snow2Mode, trace: Option<&SearchTrace>) -> Option<FileHeader> {
+    let start = Instant::now();
+    let res = probe_header_iv(rd, fname, skey, offset, iv0, mode);
+    let times = ProbeTimes { header: start.elapsed(), ..Default::default() };
+    match res {
+        Ok(header) => { record_probe(trace, fname, skey, None, offset, iv0, mode, ProbeStage::EntriesRead, times); Some(header) }
+        Err(stage) => { record_probe(trace, fname, skey, None, offset, iv0, mode, stage, times); None }
     }
-    Ok(None)
 }
 
-pub fn find_header_unified<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &str) -> Result<Option<(FileHeader, u64, u32, encryption::Snow2Mode)>, Error> {
+pub fn find_header_unified<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &str, settings: &PackSettings) -> Result<Option<(FileHeader, u64, u32, encryption::Snow2Mode)>, Error> {
     let size = rd.seek(SeekFrom::End(0))?;
     let modes = [encryption::Snow2Mode::Sub, encryption::Snow2Mode::Xor, encryption::Snow2Mode::ModernBE, encryption::Snow2Mode::ModernLE, encryption::Snow2Mode::LegacyBE, encryption::Snow2Mode::LegacyLE];
     
@@ -111,8 +852,8 @@ pub fn find_header_unified<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey:
                         if (off as u64) < size - 9 {
                             if let Ok(Some((header, _))) = try_read_and_validate_header_iv(rd, fname, skey, off as u64, *iv0, *mode) { 
                                 // Deep validation: verify entries before accepting
-                                if let Ok((_, entries, _)) = read_meta_iv_mode(fname, skey, rd, off as u64, *iv0, *mode) {
-                                    if validate_entries(&entries).is_ok() {
+                                if let Ok((_, entries, _)) = read_meta_iv_mode(fname, skey, rd, off as u64, *iv0, *mode, settings) {
+                                    if entries_accepted(&entries, settings) {
                                         return Ok(Some((header, off as u64, *iv0, *mode)));
                                     }
                                 }
@@ -124,8 +865,8 @@ pub fn find_header_unified<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey:
             // Priority 2: Generated offset
             let f_off = encryption::gen_header_offset(fname) as u64;
             if let Ok(Some((header, _))) = try_read_and_validate_header_iv(rd, fname, skey, f_off, *iv0, *mode) { 
-                if let Ok((_, entries, _)) = read_meta_iv_mode(fname, skey, rd, f_off, *iv0, *mode) {
-                    if validate_entries(&entries).is_ok() {
+                if let Ok((_, entries, _)) = read_meta_iv_mode(fname, skey, rd, f_off, *iv0, *mode, settings) {
+                    if entries_accepted(&entries, settings) {
                         return Ok(Some((header, f_off, *iv0, *mode))); 
                     }
                 }
@@ -133,8 +874,8 @@ pub fn find_header_unified<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey:
             // Priority 3: Shifts
             for shift in &[0, 108, 109] {
                 if let Ok(Some((header, _))) = try_read_and_validate_header_iv(rd, fname, skey, *shift, *iv0, *mode) { 
-                    if let Ok((_, entries, _)) = read_meta_iv_mode(fname, skey, rd, *shift, *iv0, *mode) {
-                        if validate_entries(&entries).is_ok() {
+                    if let Ok((_, entries, _)) = read_meta_iv_mode(fname, skey, rd, *shift, *iv0, *mode, settings) {
+                        if entries_accepted(&entries, settings) {
                             return Ok(Some((header, *shift, *iv0, *mode)));
                         }
                     }
@@ -145,22 +886,25 @@ pub fn find_header_unified<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey:
     Ok(None)
 }
 
-pub fn read_meta_iv_mode<RUND: Read + Seek>(fname: &str, skey: &str, rd: &mut RUND, header_offset: u64, iv0: u32, mode: encryption::Snow2Mode) -> Result<(FileHeader, Vec<FileEntry>, u64), Error> {
+pub fn read_meta_iv_mode<RUND: Read + Seek>(fname: &str, skey: &str, rd: &mut RUND, header_offset: u64, iv0: u32, mode: encryption::Snow2Mode, settings: &PackSettings) -> Result<(FileHeader, Vec<FileEntry>, u64), Error> {
     let header = try_read_and_validate_header_iv(rd, fname, skey, header_offset, iv0, mode)?.map(|(h, _)| h).ok_or_else(|| Error::msg("Header validation failed"))?;
+    let limits = EntryLimits::effective(header.version, settings);
+    if header.file_cnt > limits.max_entries {
+        debug!("[ENTRIES] Header claims {} entries, more than the limit of {}", header.file_cnt, limits.max_entries);
+        return Err(Error::msg("Too many entries"));
+    }
     let e_key = encryption::gen_entries_key(fname, skey);
-    let e_off_gen = encryption::gen_entries_offset(fname) as u64;
-    let mut candidate_e_offs = vec![header_offset + 9, header_offset + e_off_gen, encryption::gen_header_offset(fname) as u64 + e_off_gen];
-    candidate_e_offs.sort_unstable(); candidate_e_offs.dedup();
+    let candidate_e_offs = entries_offset_candidates(fname, header_offset);
     for off in candidate_e_offs {
         if rd.seek(SeekFrom::Start(off)).is_err() { continue; }
         let mut e_dec = encryption::Snow2Decoder::new_iv_mode(&e_key, iv0, mode, rd);
         let mut entries = Vec::with_capacity(header.file_cnt as usize);
         let mut success = true;
         for _ in 0..header.file_cnt {
-            match FileEntry::new(&mut e_dec) { 
+            match FileEntry::new_with_limits(&mut e_dec, &limits) { 
                 Ok(ent) => {
                     // Stricter validation: entry name must be plausible
-                    if ent.name.is_empty() || ent.name.len() > 1024 || ent.original_size > 500_000_000 {
+                    if !limits.plausible(&ent) {
                         success = false;
                         break;
                     }
@@ -169,25 +913,150 @@ pub fn read_meta_iv_mode<RUND: Read + Seek>(fname: &str, skey: &str, rd: &mut RU
                 Err(_) => { success = false; break; } 
             }
         }
-        if success && !entries.is_empty() && validate_entries(&entries).is_ok() { 
-            let pos = rd.stream_position().unwrap_or(0);
-            let content_offset = (pos + 1023) & !1023u64;
+        // A header with no files is a valid empty pack; otherwise an empty table means nothing decoded.
+        if success && (!entries.is_empty() || header.file_cnt == 0) && entries_accepted(&entries, settings) { 
+            let content_offset = content_offset_after_table(rd, &entries, iv0, mode);
             return Ok((header, entries, content_offset)); 
         }
     }
     Err(Error::msg("Failed entries"))
 }
 
+/// Render a traced key search as a table of every (name variant, salt, offset) combination
+/// tried and the furthest stage it reached, followed by a short diagnosis.
+pub fn format_search_report(attempts: &[ProbeAttempt]) -> String {
+    use std::collections::BTreeMap;
+    use std::fmt::Write as _;
+
+    // (variant, header salt, entries salt, offset) -> (iv/mode combos tried, furthest stage)
+    let mut rows: BTreeMap<(String, String, String, u64), (usize, ProbeStage)> = BTreeMap::new();
+    let mut stage_counts: BTreeMap<ProbeStage, usize> = BTreeMap::new();
+    for a in attempts {
+        let key = (a.name_variant.clone(), a.header_salt.clone(), a.entries_salt.clone().unwrap_or_else(|| "-".to_string()), a.offset);
+        let row = rows.entry(key).or_insert((0, ProbeStage::HeaderRead));
+        row.0 += 1;
+        row.1 = row.1.max(a.stage);
+        *stage_counts.entry(a.stage).or_insert(0) += 1;
+    }
+    // Header probes that passed are superseded by their entries-phase rows.
+    let entries_tried: std::collections::HashSet<(String, String, u64)> = rows.keys()
+        .filter(|k| k.2 != "-").map(|k| (k.0.clone(), k.1.clone(), k.3)).collect();
+    let mut rows: Vec<_> = rows.into_iter()
+        .filter(|(k, _)| k.2 != "-" || !entries_tried.contains(&(k.0.clone(), k.1.clone(), k.3)))
+        .collect();
+    rows.sort_by(|a, b| b.1.1.cmp(&a.1.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut out = String::new();
+    let _ = writeln!(out, "Key search report: {} probe(s), {} combination(s)", attempts.len(), rows.len());
+    let _ = writeln!(out, "{:<16} {:<24} {:<24} {:>10} {:>6}  FAILED AT", "NAME", "HEADER SALT", "ENTRIES SALT", "OFFSET", "TRIED");
+    for ((variant, h_salt, e_salt, offset), (tried, stage)) in &rows {
+        let variant = if variant.is_empty() { "<empty>" } else { variant.as_str() };
+        let _ = writeln!(out, "{:<16} {:<24} {:<24} {:>10} {:>6}  {}", variant, h_salt, e_salt, format!("0x{:X}", offset), tried, stage.describe());
+    }
+    let _ = writeln!(out);
+    for (stage, count) in &stage_counts {
+        let _ = writeln!(out, "  {:<17} {}", format!("{}:", stage.describe()), count);
+    }
+
+    let furthest = stage_counts.keys().next_back().copied();
+    let verdict = match furthest {
+        None => "No combinations were attempted (empty salt list?).",
+        Some(ProbeStage::HeaderRead) => "No header could be read at any offset: the file is likely truncated or not an .it archive.",
+        Some(ProbeStage::HeaderChecksum) => "Headers decrypted but never validated: the salt is wrong or missing from salts.txt, or the archive was renamed (keys derive from the file name).",
+        Some(ProbeStage::EntriesRead) => "A header validated but the entries table could not be decoded: the entries salt is unknown or the table is truncated.",
+        Some(ProbeStage::EntriesChecksum) => "Entries decoded but failed their checksums: the entries salt is wrong or the file is corrupt.",
+        Some(ProbeStage::Success) => "A working combination was found.",
+    };
+    let _ = writeln!(out, "Diagnosis: {}", verdict);
+    out
+}
+
+/// Summarise where a traced key search spent its time, per phase and per salt.
+/// `wall` is the elapsed time of the whole run; summed probe times can exceed it
+/// when probes ran in parallel.
+pub fn format_timing_report(attempts: &[ProbeAttempt], wall: Duration) -> String {
+    use std::collections::BTreeMap;
+    use std::fmt::Write as _;
+
+    #[derive(Default)]
+    struct Totals { probes: usize, header: Duration, decode: Duration, validation: Duration }
+    impl Totals {
+        fn add(&mut self, t: &ProbeTimes) {
+            self.probes += 1;
+            self.header += t.header;
+            self.decode += t.entries_decode;
+            self.validation += t.validation;
+        }
+        fn total(&self) -> Duration { self.header + self.decode + self.validation }
+    }
+
+    let mut all = Totals::default();
+    let mut per_salt: BTreeMap<&str, Totals> = BTreeMap::new();
+    for a in attempts {
+        all.add(&a.times);
+        per_salt.entry(a.header_salt.as_str()).or_default().add(&a.times);
+    }
+    let mut per_salt: Vec<_> = per_salt.into_iter().collect();
+    per_salt.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then_with(|| a.0.cmp(b.0)));
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let mut out = String::new();
+    let _ = writeln!(out, "Key search timings: {:.2} ms total run, {} probe(s), {} thread(s)", ms(wall), all.probes, rayon::current_num_threads());
+    let _ = writeln!(out, "  header probe:   {:>10.2} ms", ms(all.header));
+    let _ = writeln!(out, "  entries decode: {:>10.2} ms", ms(all.decode));
+    let _ = writeln!(out, "  validation:     {:>10.2} ms", ms(all.validation));
+    let _ = writeln!(out, "{:<24} {:>7} {:>11} {:>11} {:>11} {:>11}", "HEADER SALT", "PROBES", "HEADER ms", "DECODE ms", "VALID ms", "TOTAL ms");
+    for (salt, t) in &per_salt {
+        let _ = writeln!(out, "{:<24} {:>7} {:>11.2} {:>11.2} {:>11.2} {:>11.2}", salt, t.probes, ms(t.header), ms(t.decode), ms(t.validation), ms(t.total()));
+    }
+    out
+}
+
 /// Like `find_header_unified` but skips deep entries validation.
 /// Used as Phase 1 of the two-phase salt search: validates the header checksum only.
 pub fn find_header_only<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &str) -> Result<Option<(FileHeader, u64, u32, encryption::Snow2Mode)>, Error> {
+    find_header_only_traced(rd, fname, skey, None)
+}
+
+/// `find_header_only` that records every offset/iv0/mode probe into `trace`.
+/// A header that validates is recorded at `EntriesRead` until the entries phase reports back.
+pub fn find_header_only_traced<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &str, trace: Option<&SearchTrace>) -> Result<Option<(FileHeader, u64, u32, encryption::Snow2Mode)>, Error> {
+    run_summary::count_salt_tried();
+    let mut found = None;
+    scan_header_candidates(rd, fname, skey, |rd, off, iv0, mode| {
+        if let Some(header) = try_header_traced(rd, fname, skey, off, iv0, mode, trace) {
+            found = Some((header, off, iv0, mode));
+            return true;
+        }
+        false
+    })?;
+    Ok(found)
+}
+
+/// Every (offset, iv0, mode) at which the header validates with `skey`, in probe order.
+/// Unlike `find_header_only` this keeps going after the first hit.
+pub fn find_all_headers<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &str) -> Result<Vec<(FileHeader, u64, u32, encryption::Snow2Mode)>, Error> {
+    let mut found: Vec<(FileHeader, u64, u32, encryption::Snow2Mode)> = Vec::new();
+    scan_header_candidates(rd, fname, skey, |rd, off, iv0, mode| {
+        if !found.iter().any(|(_, o, i, m)| *o == off && *i == iv0 && *m == mode) {
+            if let Ok(header) = probe_header_iv(rd, fname, skey, off, iv0, mode) {
+                found.push((header, off, iv0, mode));
+            }
+        }
+        false
+    })?;
+    Ok(found)
+}
+
+/// Feed each candidate header location to `visit` in priority order until it returns true.
+fn scan_header_candidates<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &str, mut visit: impl FnMut(&mut RUND, u64, u32, encryption::Snow2Mode) -> bool) -> Result<(), Error> {
     let size = rd.seek(SeekFrom::End(0))?;
 
     // Fast path: NA common case — Sub mode, iv0=0, formula offset.
     // Hits on the very first try for all known NA archives.
     let f_off = encryption::gen_header_offset(fname) as u64;
-    if let Ok(Some((header, _))) = try_read_and_validate_header_iv(rd, fname, skey, f_off, 0, encryption::Snow2Mode::Sub) {
-        return Ok(Some((header, f_off, 0, encryption::Snow2Mode::Sub)));
+    if visit(rd, f_off, 0, encryption::Snow2Mode::Sub) {
+        return Ok(());
     }
 
     // Full fallback for other regions/formats (KR, TW, footer-pointer archives, etc.)
@@ -203,47 +1072,128 @@ pub fn find_header_only<RUND: Read + Seek>(rd: &mut RUND, fname: &str, skey: &st
                     let mut cur = Cursor::new(f_bytes);
                     let mut dec = encryption::Snow2Decoder::new_iv_mode(&key, *iv0, *mode, &mut cur);
                     if let Ok(off) = dec.read_u32::<LittleEndian>() {
-                        if (off as u64) < size - 9 {
-                            if let Ok(Some((header, _))) = try_read_and_validate_header_iv(rd, fname, skey, off as u64, *iv0, *mode) {
-                                return Ok(Some((header, off as u64, *iv0, *mode)));
-                            }
+                        if (off as u64) < size - 9 && visit(rd, off as u64, *iv0, *mode) {
+                            return Ok(());
                         }
                     }
                 }
             }
             // Skip Sub+iv0=0+formula — already tried in fast path above
-            if !(*iv0 == 0 && matches!(mode, encryption::Snow2Mode::Sub)) {
-                if let Ok(Some((header, _))) = try_read_and_validate_header_iv(rd, fname, skey, f_off, *iv0, *mode) {
-                    return Ok(Some((header, f_off, *iv0, *mode)));
-                }
+            if !(*iv0 == 0 && matches!(mode, encryption::Snow2Mode::Sub)) && visit(rd, f_off, *iv0, *mode) {
+                return Ok(());
             }
             for shift in &[0u64, 108, 109] {
-                if let Ok(Some((header, _))) = try_read_and_validate_header_iv(rd, fname, skey, *shift, *iv0, *mode) {
-                    return Ok(Some((header, *shift, *iv0, *mode)));
+                if visit(rd, *shift, *iv0, *mode) {
+                    return Ok(());
                 }
             }
         }
     }
-    Ok(None)
+    Ok(())
+}
+
+/// Where the entries table may start for a header at `header_offset`, in ascending order.
+pub fn entries_offset_candidates(fname: &str, header_offset: u64) -> Vec<u64> {
+    let e_off_gen = encryption::gen_entries_offset(fname) as u64;
+    let mut offs = vec![header_offset + 9, header_offset + e_off_gen, encryption::gen_header_offset(fname) as u64 + e_off_gen];
+    offs.sort_unstable(); offs.dedup();
+    offs
+}
+
+/// True when `data` starts with a zlib header and the stream inflates without error so far.
+pub fn looks_like_zlib(data: &[u8]) -> bool {
+    if data.len() < 2 || (data[0] & 0x0F) != 8 || !((data[0] as u16) << 8 | data[1] as u16).is_multiple_of(31) {
+        return false;
+    }
+    let mut out = vec![0u8; 4096];
+    flate2::Decompress::new(true).decompress(data, &mut out, flate2::FlushDecompress::None).is_ok()
+}
+
+/// Content bases to try, in order, when entry data is not at the end of the entries
+/// table rounded up to 1024 (`assumed`): one block later or earlier, half a block
+/// earlier, the next 4096 boundary and two blocks later.
+pub fn content_offset_candidates(assumed: u64) -> Vec<u64> {
+    let mut offs = Vec::new();
+    for off in [assumed, assumed + 1024, assumed.saturating_sub(1024), assumed.saturating_sub(512), (assumed + 4095) & !4095, assumed + 2048] {
+        if !offs.contains(&off) {
+            offs.push(off);
+        }
+    }
+    offs
+}
+
+/// The content base at which the lowest compressed entry decrypts to a zlib stream,
+/// trying `content_offset_candidates(assumed)` in order. Keeps `assumed` when it passes,
+/// when no candidate does, or when there is no compressed entry to test.
+pub fn detect_content_offset<R: Read + Seek>(rd: &mut R, entries: &[FileEntry], iv0: u32, mode: encryption::Snow2Mode, assumed: u64) -> u64 {
+    let Some(probe) = entries.iter()
+        .filter(|e| e.flags & FLAG_COMPRESSED != 0 && e.flags & FLAG_SYMLINK == 0 && e.raw_size >= 2)
+        .min_by_key(|e| e.offset) else { return assumed };
+    let fkey = encryption::gen_file_key(&probe.name, &probe.key);
+    let mut head = vec![0u8; probe.raw_size.min(1024) as usize];
+    let fits = |rd: &mut R, head: &mut Vec<u8>, base: u64| -> bool {
+        if rd.seek(SeekFrom::Start(base + probe.offset as u64 * 1024)).is_err() || rd.read_exact(head).is_err() {
+            return false;
+        }
+        // Try the stored state first, then the other one (flags are sometimes wrong).
+        let encrypted = probe.flags & (FLAG_ALL_ENCRYPTED | FLAG_HEAD_ENCRYPTED) != 0;
+        let mut decrypted = head.clone();
+        encryption::snow2_decrypt_mode(&fkey, iv0, mode, &mut decrypted);
+        let (first, second) = if encrypted { (&decrypted, &*head) } else { (&*head, &decrypted) };
+        looks_like_zlib(first) || looks_like_zlib(second)
+    };
+    let candidates = content_offset_candidates(assumed);
+    match candidates.iter().copied().find(|&base| fits(rd, &mut head, base)) {
+        Some(base) if base != assumed => {
+            info!("[CONTENT_OFFSET] '{}' only decodes with content at 0x{:X} instead of 0x{:X}; using 0x{:X}", probe.name, base, assumed, base);
+            base
+        }
+        Some(_) => assumed,
+        None => {
+            debug!("[CONTENT_OFFSET] '{}' decodes at none of {:X?}; keeping 0x{:X}", probe.name, candidates, assumed);
+            assumed
+        }
+    }
+}
+
+/// The content base for an entries table `rd` has just been read to the end of: the
+/// next 1024 boundary, unless `detect_content_offset` finds the data elsewhere.
+pub fn content_offset_after_table<R: Read + Seek>(rd: &mut R, entries: &[FileEntry], iv0: u32, mode: encryption::Snow2Mode) -> u64 {
+    let pos = rd.stream_position().unwrap_or(0);
+    detect_content_offset(rd, entries, iv0, mode, (pos + 1023) & !1023u64)
 }
 
 /// Like `read_meta_iv_mode` but decrypts the entries table with a separate salt.
 /// Supports archives where the header salt and entries salt differ.
-pub fn read_meta_iv_mode_two_key<RUND: Read + Seek>(fname: &str, header_skey: &str, entries_skey: &str, rd: &mut RUND, header_offset: u64, iv0: u32, mode: encryption::Snow2Mode) -> Result<(FileHeader, Vec<FileEntry>, u64), Error> {
+pub fn read_meta_iv_mode_two_key<RUND: Read + Seek>(fname: &str, header_skey: &str, entries_skey: &str, rd: &mut RUND, header_offset: u64, iv0: u32, mode: encryption::Snow2Mode, settings: &PackSettings) -> Result<(FileHeader, Vec<FileEntry>, u64), Error> {
+    read_meta_iv_mode_two_key_traced(fname, header_skey, entries_skey, rd, header_offset, iv0, mode, settings, None)
+}
+
+/// `read_meta_iv_mode_two_key` that records the furthest stage reached into `trace`.
+pub fn read_meta_iv_mode_two_key_traced<RUND: Read + Seek>(fname: &str, header_skey: &str, entries_skey: &str, rd: &mut RUND, header_offset: u64, iv0: u32, mode: encryption::Snow2Mode, settings: &PackSettings, trace: Option<&SearchTrace>) -> Result<(FileHeader, Vec<FileEntry>, u64), Error> {
+    let mut times = ProbeTimes::default();
+    let start = Instant::now();
     let header = try_read_and_validate_header_iv(rd, fname, header_skey, header_offset, iv0, mode)?.map(|(h, _)| h).ok_or_else(|| Error::msg("Header validation failed"))?;
+    times.header = start.elapsed();
+    let limits = EntryLimits::effective(header.version, settings);
     let e_key = encryption::gen_entries_key(fname, entries_skey);
-    let e_off_gen = encryption::gen_entries_offset(fname) as u64;
-    let mut candidate_e_offs = vec![header_offset + 9, header_offset + e_off_gen, encryption::gen_header_offset(fname) as u64 + e_off_gen];
-    candidate_e_offs.sort_unstable(); candidate_e_offs.dedup();
+    let candidate_e_offs = if header.file_cnt > limits.max_entries {
+        debug!("[ENTRIES] Header claims {} entries, more than the limit of {}", header.file_cnt, limits.max_entries);
+        Vec::new()
+    } else {
+        entries_offset_candidates(fname, header_offset)
+    };
+    let mut furthest = ProbeStage::EntriesRead;
     for off in candidate_e_offs {
         if rd.seek(SeekFrom::Start(off)).is_err() { continue; }
+        let start = Instant::now();
         let mut e_dec = encryption::Snow2Decoder::new_iv_mode(&e_key, iv0, mode, rd);
         let mut entries = Vec::with_capacity(header.file_cnt as usize);
         let mut success = true;
         for _ in 0..header.file_cnt {
-            match FileEntry::new(&mut e_dec) {
+            match FileEntry::new_with_limits(&mut e_dec, &limits) {
                 Ok(ent) => {
-                    if ent.name.is_empty() || ent.name.len() > 1024 || ent.original_size > 500_000_000 {
+                    if !limits.plausible(&ent) {
                         success = false; break;
                     }
                     entries.push(ent);
@@ -251,19 +1201,79 @@ pub fn read_meta_iv_mode_two_key<RUND: Read + Seek>(fname: &str, header_skey: &s
                 Err(_) => { success = false; break; }
             }
         }
-        if success && !entries.is_empty() && validate_entries(&entries).is_ok() {
-            let pos = rd.stream_position().unwrap_or(0);
-            return Ok((header, entries, (pos + 1023) & !1023u64));
+        times.entries_decode += start.elapsed();
+        if success && (!entries.is_empty() || header.file_cnt == 0) {
+            let start = Instant::now();
+            let valid = entries_accepted(&entries, settings);
+            times.validation += start.elapsed();
+            if valid {
+                record_probe(trace, fname, header_skey, Some(entries_skey), header_offset, iv0, mode, ProbeStage::Success, times);
+                let content_offset = content_offset_after_table(rd, &entries, iv0, mode);
+                return Ok((header, entries, content_offset));
+            }
+            furthest = ProbeStage::EntriesChecksum;
         }
     }
+    record_probe(trace, fname, header_skey, Some(entries_skey), header_offset, iv0, mode, furthest, times);
     Err(Error::msg("Failed entries"))
 }
 
-pub fn read_meta<RUND: Read + Seek>(fname: &str, skey: &str, rd: &mut RUND, h_off: u64) -> Result<(FileHeader, Vec<FileEntry>, u32, encryption::Snow2Mode, u64), Error> {
+/// The entries-table offset (see `entries_offset_candidates`) at which `entries_skey`
+/// decodes a plausible first record with a valid checksum. Unlike `read_meta_iv_mode_two_key`
+/// only one record is decoded, so a huge table can be streamed with `stream_entries` right away.
+pub fn probe_first_entry<RUND: Read + Seek>(rd: &mut RUND, fname: &str, entries_skey: &str, header: &FileHeader, header_offset: u64, iv0: u32, mode: encryption::Snow2Mode, settings: &PackSettings) -> Option<u64> {
+    let candidates = entries_offset_candidates(fname, header_offset);
+    if header.file_cnt == 0 {
+        return candidates.first().copied();
+    }
+    let limits = EntryLimits::effective(header.version, settings);
+    if header.file_cnt > limits.max_entries {
+        return None;
+    }
+    let e_key = encryption::gen_entries_key(fname, entries_skey);
+    candidates.into_iter().find(|&off| {
+        if rd.seek(SeekFrom::Start(off)).is_err() { return false; }
+        let mut e_dec = encryption::Snow2Decoder::new_iv_mode(&e_key, iv0, mode, rd);
+        FileEntry::new_with_limits(&mut e_dec, &limits).is_ok_and(|ent| limits.plausible(&ent) && settings.checksum(&ent) == ent.checksum)
+    })
+}
+
+/// Decode the entries table at `entries_offset` one record at a time, handing each to
+/// `visit` as soon as it is read and checked. Stops with an error at the first bad
+/// record (entries before it have been visited). Returns the number of entries.
+pub fn stream_entries<RUND: Read + Seek>(
+    rd: &mut RUND,
+    fname: &str,
+    entries_skey: &str,
+    header: &FileHeader,
+    entries_offset: u64,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+    settings: &PackSettings,
+    mut visit: impl FnMut(FileEntry) -> Result<(), Error>,
+) -> Result<usize, Error> {
+    let limits = EntryLimits::effective(header.version, settings);
+    if header.file_cnt > limits.max_entries {
+        return Err(Error::msg(format!("header claims {} entries, more than the limit of {}", header.file_cnt, limits.max_entries)));
+    }
+    let e_key = encryption::gen_entries_key(fname, entries_skey);
+    rd.seek(SeekFrom::Start(entries_offset))?;
+    let mut e_dec = encryption::Snow2Decoder::new_iv_mode(&e_key, iv0, mode, rd);
+    for idx in 0..header.file_cnt as usize {
+        let ent = FileEntry::new_with_limits(&mut e_dec, &limits).map_err(|e| Error::msg(format!("entry {} of {} unreadable: {}", idx + 1, header.file_cnt, e)))?;
+        if !limits.plausible(&ent) || settings.checksum(&ent) != ent.checksum {
+            return Err(Error::msg(format!("entry {} of {} ('{}') failed validation", idx + 1, header.file_cnt, ent.name)));
+        }
+        visit(ent)?;
+    }
+    Ok(header.file_cnt as usize)
+}
+
+pub fn read_meta<RUND: Read + Seek>(fname: &str, skey: &str, rd: &mut RUND, h_off: u64, settings: &PackSettings) -> Result<(FileHeader, Vec<FileEntry>, u32, encryption::Snow2Mode, u64), Error> {
     let modes = [encryption::Snow2Mode::Sub, encryption::Snow2Mode::Xor, encryption::Snow2Mode::ModernBE, encryption::Snow2Mode::ModernLE, encryption::Snow2Mode::LegacyBE, encryption::Snow2Mode::LegacyLE];
     for iv in &[1, 0] { 
         for mode in &modes {
-            if let Ok(res) = read_meta_iv_mode(fname, skey, rd, h_off, *iv, *mode) {
+            if let Ok(res) = read_meta_iv_mode(fname, skey, rd, h_off, *iv, *mode, settings) {
                 return Ok((res.0, res.1, *iv, *mode, res.2));
             }
         }
@@ -273,10 +1283,184 @@ pub fn read_meta<RUND: Read + Seek>(fname: &str, skey: &str, rd: &mut RUND, h_of
 
 
 
-pub fn write_file_to_disk(root_dir: &str, rel_path: &str, content: &[u8]) -> Result<(), Error> {
-    let full_path = Path::new(root_dir).join(rel_path.replace(['/', '\\'], &std::path::MAIN_SEPARATOR.to_string()));
+/// Whether files in `dir` are looked up case-insensitively (default on Windows and macOS).
+/// Probes by creating a temporary file, named per call so concurrent extractions into the
+/// same folder don't share one; assumes case-sensitive if the probe fails.
+pub fn is_case_insensitive_dir(dir: &Path) -> bool {
+    static PROBES: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let id = format!("{}-{}", std::process::id(), PROBES.fetch_add(1, Ordering::Relaxed));
+    let probe = dir.join(format!(".mabi-pack2-Case-{}", id));
+    if std::fs::write(&probe, b"").is_err() {
+        return false;
+    }
+    let upper = dir.join(format!(".MABI-PACK2-CASE-{}", id));
+    let insensitive = upper.exists();
+    let _ = std::fs::remove_file(&probe);
+    insensitive
+}
+
+/// Run `f` for one entry or archive (`what` names it), turning a panic inside it into an
+/// error, so one malformed entry that trips an overflow or bounds check in the cipher or
+/// inflate code fails on its own instead of taking down every other worker.
+pub fn catch_panic<T>(what: &str, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Err(Error::msg(format!("panicked while processing {}: {}", what, message)))
+    })
+}
+
+/// Run `f` on every archive, `pack_parallel` of them at a time, and return the results in
+/// archive order. Every call gets the same pool of `threads` workers for its per-entry work,
+/// so many small packs can overlap while a few huge ones share the entry workers.
+/// Both counts use 0 for one per CPU core.
+pub fn map_packs<T: Send>(
+    archives: &[String],
+    pack_parallel: usize,
+    threads: usize,
+    f: impl Fn(&String, &rayon::ThreadPool) -> T + Sync,
+) -> Result<Vec<T>, Error> {
+    use rayon::prelude::*;
+    let entry_pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+    if pack_parallel == 1 {
+        return Ok(archives.iter().map(|a| f(a, &entry_pool)).collect());
+    }
+    let pack_pool = rayon::ThreadPoolBuilder::new().num_threads(pack_parallel).build()?;
+    Ok(pack_pool.install(|| archives.par_iter().map(|a| f(a, &entry_pool)).collect()))
+}
+
+/// Whether `err` means another process holds the file open (a running game client on
+/// Windows: sharing or lock violation, or a mapped executable).
+pub fn is_locked_error(err: &std::io::Error) -> bool {
+    if cfg!(windows) {
+        matches!(err.raw_os_error(), Some(32) | Some(33) | Some(1224))
+    } else {
+        matches!(err.kind(), IoErrorKind::ResourceBusy | IoErrorKind::ExecutableFileBusy)
+    }
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn MoveFileExW(existing: *const u16, new: *const u16, flags: u32) -> i32;
+}
+
+/// Ask Windows to move `staged` over `target` at the next reboot, before anything can
+/// lock it again. Needs administrator rights.
+#[cfg(windows)]
+pub fn replace_on_reboot(staged: &Path, target: &Path) -> Result<(), Error> {
+    use std::os::windows::ffi::OsStrExt;
+    const MOVEFILE_REPLACE_EXISTING: u32 = 0x1;
+    const MOVEFILE_DELAY_UNTIL_REBOOT: u32 = 0x4;
+    let wide = |p: &Path| -> Vec<u16> { p.as_os_str().encode_wide().chain(std::iter::once(0)).collect() };
+    let (from, to) = (wide(&std::path::absolute(staged)?), wide(&std::path::absolute(target)?));
+    if unsafe { MoveFileExW(from.as_ptr(), to.as_ptr(), MOVEFILE_REPLACE_EXISTING | MOVEFILE_DELAY_UNTIL_REBOOT) } == 0 {
+        return Err(Error::new(std::io::Error::last_os_error()).context(format!("scheduling {} to replace {} failed", staged.display(), target.display())));
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn replace_on_reboot(_staged: &Path, _target: &Path) -> Result<(), Error> {
+    Err(Error::msg("replacing files at reboot is only supported on Windows"))
+}
+
+/// Why writing the entry path `rel_path` (either separator) under `root_dir` would leave
+/// `root_dir`, or `None` if it stays inside. Absolute paths, drive letters and `..` above
+/// the root are refused as written; the deepest part of the path that already exists is
+/// then canonicalized, which catches links to somewhere else. Always `None` with
+/// `--allow-unsafe-paths` in `settings`.
+pub fn unsafe_path_reason(root_dir: &str, rel_path: &str, settings: &PackSettings) -> Option<String> {
+    if settings.allow_unsafe_paths {
+        return None;
+    }
+    if rel_path.starts_with(['/', '\\']) || rel_path.split(['/', '\\']).next().is_some_and(|first| first.contains(':')) {
+        return Some("absolute path".to_string());
+    }
+    let mut depth = 0usize;
+    for seg in rel_path.split(['/', '\\']) {
+        match seg {
+            "" | "." => {}
+            ".." if depth == 0 => return Some("'..' climbs out of the output folder".to_string()),
+            ".." => depth -= 1,
+            _ => depth += 1,
+        }
+    }
+
+    // Nothing exists yet (e.g. the output folder is created later): the check above is all there is.
+    let Ok(root) = Path::new(root_dir).canonicalize() else { return None };
+    let full = Path::new(root_dir).join(rel_path.replace(['/', '\\'], std::path::MAIN_SEPARATOR_STR));
+    let existing = full.ancestors().find(|p| std::fs::symlink_metadata(p).is_ok())?;
+    match existing.canonicalize() {
+        Ok(resolved) if resolved.starts_with(&root) => None,
+        Ok(resolved) => Some(format!("resolves to {} outside the output folder", resolved.display())),
+        Err(_) => Some(format!("{} is a link that can't be resolved", existing.display())),
+    }
+}
+
+/// `root_dir` joined with the entry path `rel_path`, or an error if that would leave
+/// `root_dir` (see `unsafe_path_reason`) and `settings` doesn't allow unsafe paths.
+pub fn output_path(root_dir: &str, rel_path: &str, settings: &PackSettings) -> Result<PathBuf, Error> {
+    if let Some(reason) = unsafe_path_reason(root_dir, rel_path, settings) {
+        return Err(Error::msg(format!("Refusing to write {} outside '{}': {}", rel_path, root_dir, reason)));
+    }
+    Ok(Path::new(root_dir).join(rel_path.replace(['/', '\\'], std::path::MAIN_SEPARATOR_STR)))
+}
+
+pub fn write_file_to_disk(root_dir: &str, rel_path: &str, content: &[u8], settings: &PackSettings) -> Result<(), Error> {
+    let full_path = output_path(root_dir, rel_path, settings)?;
     if let Some(parent) = full_path.parent() {
         let _ = std::fs::create_dir_all(parent);
     }
     std::fs::write(&full_path, content).map_err(Error::new)
 }
+
+/// A pack passed through a pipe (`-i -`, `-o -`), spooled to a temporary file named
+/// `name` because keys derive from the file name and packs are written with seeks.
+/// The temporary folder is removed on drop.
+#[derive(Debug)]
+pub struct StdioSpool {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl StdioSpool {
+    /// An empty spool for a pack to be written as `name`.
+    pub fn new(name: &str) -> Result<Self, Error> {
+        let name = get_final_file_name(name)?;
+        if name.is_empty() {
+            return Err(Error::msg("--as-name needs a file name"));
+        }
+        let unique_id = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        let dir = std::env::temp_dir().join(format!("mabi_stdio_{}_{}", std::process::id(), unique_id));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(name);
+        Ok(StdioSpool { dir, path })
+    }
+
+    /// Spool everything `input` yields (stdin) as `name`.
+    pub fn from_reader(name: &str, input: &mut dyn Read) -> Result<Self, Error> {
+        let spool = Self::new(name)?;
+        let copied = std::io::copy(input, &mut std::fs::File::create(&spool.path)?)?;
+        debug!("[STDIO] Spooled {} bytes from stdin to '{}'", copied, spool.path());
+        Ok(spool)
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap_or_default()
+    }
+
+    /// Copy the spooled pack to `out` (stdout). Returns the bytes written.
+    pub fn copy_to(&self, out: &mut dyn std::io::Write) -> Result<u64, Error> {
+        let copied = std::io::copy(&mut std::fs::File::open(&self.path)?, out)?;
+        out.flush()?;
+        Ok(copied)
+    }
+}
+
+impl Drop for StdioSpool {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
@@ -1,16 +1,16 @@
 // common_ext.rs - Advanced Search and UI Helper Module
 
-use crate::{common, list, extract, pack, pack_v1, encryption};
+use crate::{codec, common, list, extract, key_cache, pack, pack_v1, encryption, run_summary};
 use anyhow::{Error};
 use rayon::prelude::*;
 use std::fs::{File as StdFile};
-use std::io::Cursor;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{Cursor, Read};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use memmap2::Mmap;
-use image::ImageFormat;
 use base64::{engine::general_purpose, Engine as _};
 use log::{debug, trace, info, warn};
-use image_dds::image_from_dds;
 
 pub fn get_preview_ext(entry_name: &str) -> Option<&str> {
     let name = entry_name.to_lowercase();
@@ -55,7 +55,7 @@ pub fn get_entry_data_exact(
     let mut rd = Cursor::new(&mmap[..]);
     let name_variant = common::get_final_file_name(archive_path)?;
 
-    let (_header, entries, content_start) = common::read_meta_iv_mode_two_key(&name_variant, salt, entries_salt, &mut rd, h_off, iv0, mode)?;
+    let (_header, entries, content_start) = common::read_meta_iv_mode_two_key(&name_variant, salt, entries_salt, &mut rd, h_off, iv0, mode, &common::PackSettings::default())?;
 
     let norm = entry_name.replace('\\', "/");
     if let Some(ent) = entries.iter().find(|e| e.name == entry_name || e.name.replace('\\', "/") == norm) {
@@ -73,7 +73,7 @@ pub fn get_entry_data(archive_path: &str, entry_name: &str, key: Option<String>)
 
     if archive_path.to_lowercase().ends_with(".pack") {
         debug!("[ENTRY_DATA] Handling unencrypted .pack file.");
-        let entries = pack_v1::run_list_v1_data(archive_path)?;
+        let entries = pack_v1::run_list_v1_data(archive_path, &common::PackSettings::default())?;
         if let Some(ent) = entries.iter().find(|e| e.name == entry_name) {
             let data = pack_v1::extract_single_v1(&mmap, ent)?;
             return Ok((data, 0, encryption::Snow2Mode::Sub, ent.clone()));
@@ -101,14 +101,7 @@ pub fn get_preview_base64_from_data(entry_name: &str, data: &[u8]) -> Result<Str
             return Err(Error::msg(format!("Suspicious DDS length ({} bytes)", data.len())));
         }
         debug!("[PREVIEW_BASE64] Handling DDS format via image_dds");
-        let dds = image_dds::ddsfile::Dds::read(&mut Cursor::new(data))
-            .map_err(|e| Error::msg(format!("DDS read failed: {:?}", e)))?;
-        let img = image_from_dds(&dds, 0)
-            .map_err(|e| Error::msg(format!("DDS decode failed: {:?}", e)))?;
-        let mut buf = std::io::Cursor::new(Vec::new());
-        img.write_to(&mut buf, ImageFormat::Png)
-            .map_err(|e| Error::msg(format!("PNG encode failed: {:?}", e)))?;
-        return Ok(general_purpose::STANDARD.encode(buf.into_inner()));
+        return Ok(general_purpose::STANDARD.encode(codec::dds_to_png(data)?));
     }
 
     Ok(general_purpose::STANDARD.encode(data))
@@ -125,7 +118,7 @@ pub fn run_advanced_list(
     cli_skey: Option<String>,
     loaded_salts: &[String],
     output_file_path: Option<&str>,
-) -> Result<(), Error> {
+) -> Result<common::OperationSummary, Error> {
     list::run_list_with_key_search(fname_str, cli_skey, loaded_salts, output_file_path)
 }
 
@@ -142,47 +135,467 @@ pub fn run_list_with_key_search_data(
 
     if mmap.len() >= 4 && (&mmap[0..4] == b"PACK" || &mmap[0..4] == b"MABI") {
         debug!("[GUI_LIST] Legacy .pack/MABI detected.");
-        let entries = crate::pack_v1::run_list_v1_data(fname_str)?;
+        let entries = crate::pack_v1::run_list_v1_data(fname_str, &common::PackSettings::default())?;
         return Ok((entries, "UNENCRYPTED".to_string(), "UNENCRYPTED".to_string(), 0, 0, encryption::Snow2Mode::Sub, 0));
     }
 
+    let hit = search_keys(&mmap, fname_str, cli_skey, loaded_salts, region_key, &common::PackSettings::default())?;
+    Ok((hit.entries, hit.header_salt, hit.entries_salt, hit.iv0, hit.header_offset, hit.mode, hit.content_offset))
+}
+
+/// Parameters that unlocked an archive during the two-phase salt search.
+#[derive(Debug, Clone)]
+pub struct KeySearchHit {
+    pub entries: Vec<common::FileEntry>,
+    pub header_salt: String,
+    pub entries_salt: String,
+    /// File name the keys were derived from (the real name, a region override, `data.it` or empty).
+    pub name_variant: String,
+    pub iv0: u32,
+    pub header_offset: u64,
+    pub mode: encryption::Snow2Mode,
+    pub content_offset: u64,
+}
+
+/// The combination an earlier search recorded for the archive at `fname_str`, if it still
+/// decodes `data`. A CLI key that is neither of the cached salts bypasses the cache.
+pub fn cached_key_hit(data: &[u8], fname_str: &str, cli_skey: Option<&str>, settings: &common::PackSettings) -> Option<KeySearchHit> {
+    let cached = key_cache::cached_pack(fname_str)?;
+    if cli_skey.is_some_and(|k| k != cached.header_salt && k != cached.entries_salt) {
+        return None;
+    }
+    let mut rd = Cursor::new(data);
+    match common::read_meta_iv_mode_two_key(&cached.name_variant, &cached.header_salt, &cached.entries_salt, &mut rd, cached.header_offset, cached.iv0, cached.mode, settings) {
+        Ok((_, entries, content_offset)) => {
+            info!("[KEY_CACHE] Reusing cached keys for '{}': HEADER='{}', ENTRIES='{}'", fname_str, cached.header_salt, cached.entries_salt);
+            Some(KeySearchHit {
+                entries,
+                header_salt: cached.header_salt,
+                entries_salt: cached.entries_salt,
+                name_variant: cached.name_variant,
+                iv0: cached.iv0,
+                header_offset: cached.header_offset,
+                mode: cached.mode,
+                content_offset,
+            })
+        }
+        Err(_) => {
+            debug!("[KEY_CACHE] Cached keys no longer open '{}'; searching", fname_str);
+            None
+        }
+    }
+}
+
+/// Two-phase salt search over an in-memory archive image. The archive at `fname_str`
+/// is only inspected for a cached result (`cached_key_hit`); names derive from it.
+pub fn search_keys(
+    data: &[u8],
+    fname_str: &str,
+    cli_skey: Option<String>,
+    loaded_salts: &[String],
+    region_key: Option<String>,
+    settings: &common::PackSettings,
+) -> Result<KeySearchHit, Error> {
+    if let Some(hit) = cached_key_hit(data, fname_str, cli_skey.as_deref(), settings) {
+        run_summary::record_key(fname_str, data.len() as u64, &hit);
+        return Ok(hit);
+    }
     let mut keys_to_try: Vec<String> = Vec::new();
+    let has_cli_key = cli_skey.is_some();
     if let Some(key) = cli_skey { keys_to_try.push(key); }
     for salt in loaded_salts {
         if !keys_to_try.contains(salt) { keys_to_try.push(salt.clone()); }
     }
 
     let final_fname = common::get_final_file_name(fname_str)?;
-    let mut name_variants = vec![final_fname.clone()];
-    if let Some(r) = region_key { if !name_variants.contains(&r) { name_variants.push(r); } }
-    name_variants.push("data.it".to_string());
-    name_variants.push("".to_string());
+    key_cache::prioritize(&final_fname, &mut keys_to_try[usize::from(has_cli_key)..]);
+    let name_variants = common::key_name_variants(fname_str, region_key, settings)?;
 
-    for name in name_variants {
+    // Headers that validated without any salt decoding their entries, for the diagnosis below.
+    let header_only = Mutex::new(Vec::new());
+    let progress = common::SearchProgress::new((name_variants.len() * keys_to_try.len()) as u64);
+    for name in &name_variants {
         // Two-phase search: Phase 1 finds the header salt, Phase 2 finds the entries salt.
-        // Header salt is tried first for entries (common case: same salt for both).
-        let res = keys_to_try.par_iter().find_map_any(|header_skey| {
-            let mut rd = Cursor::new(&mmap[..]);
-            if let Ok(Some((_header, h_off, iv0, mode))) = common::find_header_only(&mut rd, &name, header_skey) {
+        // Header salt is tried first for entries (common case: same salt for both). Salts
+        // are probed on all cores, but a hit only wins over hits from salts later in
+        // `keys_to_try`, so the CLI key and remembered salts keep their priority.
+        let res = keys_to_try.par_iter().find_map_first(|header_skey| {
+            let mut rd = Cursor::new(data);
+            if let Ok(Some((header, h_off, iv0, mode))) = common::find_header_only(&mut rd, name, header_skey) {
                 let entries_candidates: Vec<&str> = std::iter::once(header_skey.as_str())
                     .chain(keys_to_try.iter().filter(|s| s.as_str() != header_skey.as_str()).map(|s| s.as_str()))
                     .collect();
                 for entries_skey in entries_candidates {
-                    let mut rd2 = Cursor::new(&mmap[..]);
-                    if let Ok((_, entries, c_off)) = common::read_meta_iv_mode_two_key(&name, header_skey, entries_skey, &mut rd2, h_off, iv0, mode) {
-                        info!("[GUI_LIST] >>> SUCCESS! HEADER='{}', ENTRIES='{}'", header_skey, entries_skey);
-                        return Some((entries, header_skey.clone(), entries_skey.to_string(), iv0, h_off, mode, c_off));
+                    let mut rd2 = Cursor::new(data);
+                    if let Ok((_, entries, c_off)) = common::read_meta_iv_mode_two_key(name, header_skey, entries_skey, &mut rd2, h_off, iv0, mode, settings) {
+                        info!("[KEY_SEARCH] >>> SUCCESS! HEADER='{}', ENTRIES='{}'", header_skey, entries_skey);
+                        return Some(KeySearchHit {
+                            entries,
+                            header_salt: header_skey.clone(),
+                            entries_salt: entries_skey.to_string(),
+                            name_variant: name.clone(),
+                            iv0,
+                            header_offset: h_off,
+                            mode,
+                            content_offset: c_off,
+                        });
                     }
                 }
+                header_only.lock().unwrap().push((name.clone(), header_skey.clone(), header, h_off, iv0, mode));
             }
+            progress.tick(header_skey, name);
             None
         });
-        if let Some(r) = res { return Ok(r); }
+        if let Some(r) = res {
+            common::report_name_guess(fname_str, &r.name_variant, &r.header_salt, &r.entries_salt, settings);
+            key_cache::record_success(&final_fname, &r.header_salt, &r.entries_salt);
+            key_cache::record_pack(fname_str, &r.name_variant, &r.header_salt, &r.entries_salt, r.header_offset, r.iv0, r.mode);
+            run_summary::record_key(fname_str, data.len() as u64, &r);
+            return Ok(r);
+        }
     }
 
+    drop(progress);
+    for (name, salt, header, h_off, iv0, mode) in header_only.into_inner().unwrap() {
+        let m = assess_header_only(data, &name, &salt, &header, h_off, iv0, mode, &keys_to_try);
+        warn!("[KEY_SEARCH] Header validated with '{}' (name '{}', offset 0x{:X}) but no salt decoded the entries. {}", salt, name, h_off, m.advice());
+    }
+    if let Some(r) = escalate_search(data, &name_variants, &keys_to_try, settings) {
+        common::report_name_guess(fname_str, &r.name_variant, &r.header_salt, &r.entries_salt, settings);
+        key_cache::record_success(&final_fname, &r.header_salt, &r.entries_salt);
+        key_cache::record_pack(fname_str, &r.name_variant, &r.header_salt, &r.entries_salt, r.header_offset, r.iv0, r.mode);
+        run_summary::record_key(fname_str, data.len() as u64, &r);
+        return Ok(r);
+    }
     Err(Error::msg("Search exhausted all regional variants."))
 }
 
+const SNOW2_MODES: [encryption::Snow2Mode; 6] = [encryption::Snow2Mode::Sub, encryption::Snow2Mode::Xor, encryption::Snow2Mode::ModernBE, encryption::Snow2Mode::ModernLE, encryption::Snow2Mode::LegacyBE, encryption::Snow2Mode::LegacyLE];
+
+/// Whether the 9 header bytes at the start of `raw` decrypt with the first 12 bytes of
+/// keystream `ks` to a header with a matching checksum. The keystream doesn't depend on
+/// where the header is, so the offset scan sets up SNOW2 once per salt instead of per offset.
+fn header_checksum_matches(raw: &[u8], ks: &[u8], mode: encryption::Snow2Mode) -> bool {
+    let mut plain = [0u8; 12];
+    plain[..9].copy_from_slice(&raw[..9]);
+    for (word, key) in plain.chunks_exact_mut(4).zip(ks.chunks_exact(4)) {
+        let enc = u32::from_le_bytes(word.try_into().unwrap());
+        let k = u32::from_le_bytes(key.try_into().unwrap());
+        let dec = if mode == encryption::Snow2Mode::Sub { enc.wrapping_sub(k) } else { enc ^ k };
+        word.copy_from_slice(&dec.to_le_bytes());
+    }
+    let checksum = u32::from_le_bytes(plain[0..4].try_into().unwrap());
+    let file_cnt = u32::from_le_bytes(plain[5..9].try_into().unwrap());
+    checksum == (plain[4] as u32).wrapping_add(file_cnt)
+}
+
+/// What a key search does once the usual header offsets failed, as far as
+/// `settings.escalation` allows. Stage 1 probes every offset from `common::scan_header_offsets`
+/// with every name, salt, IV and mode; stage 2 (`Escalation::Full` only) decodes the entries
+/// table at every offset in `common::BRUTE_ENTRIES_WINDOW` behind each header stage 1 found.
+/// With escalation off this only logs how to turn it on.
+pub fn escalate_search(data: &[u8], name_variants: &[String], keys_to_try: &[String], settings: &common::PackSettings) -> Option<KeySearchHit> {
+    let level = settings.escalation;
+    if level == common::Escalation::Off {
+        info!("[ESCALATE] Standard search failed. Rerun with --auto-escalate to scan every header offset and then brute-force the entries offset (slow).");
+        return None;
+    }
+    let stages = if level == common::Escalation::Full { 2 } else { 1 };
+    let offsets = common::scan_header_offsets(data.len() as u64, settings);
+    info!("[ESCALATE] Stage 1/{}: scanning {} header offsets with {} salt(s) and {} name(s)...", stages, offsets.len(), keys_to_try.len(), name_variants.len());
+    let start = Instant::now();
+    let header_only = Mutex::new(Vec::new());
+    let progress = common::SearchProgress::new((keys_to_try.len() * name_variants.len()) as u64);
+    for name in name_variants {
+        // As in the standard search, a hit only wins over hits from salts later in
+        // `keys_to_try`; `best` lets workers on those later salts stop early.
+        let best = AtomicUsize::new(usize::MAX);
+        let res = keys_to_try.par_iter().enumerate().find_map_first(|(i, header_skey)| {
+            let key = encryption::gen_header_key(name, header_skey);
+            let streams: Vec<(u32, encryption::Snow2Mode, Vec<u8>)> = [0u32, 1].into_iter()
+                .flat_map(|iv0| SNOW2_MODES.into_iter().map(move |mode| (iv0, mode)))
+                .map(|(iv0, mode)| {
+                    let mut ks = Vec::with_capacity(12);
+                    encryption::write_keystream(&key, iv0, mode, 0, 12, &mut ks).expect("writing to a Vec");
+                    (iv0, mode, ks)
+                })
+                .collect();
+            let mut rd = Cursor::new(data);
+            for &h_off in &offsets {
+                if best.load(Ordering::Relaxed) < i {
+                    return None;
+                }
+                for &(iv0, mode, ref ks) in &streams {
+                    if !header_checksum_matches(&data[h_off as usize..], ks, mode) {
+                        continue;
+                    }
+                    // Confirm with the decoder the rest of the search uses.
+                    let Ok(Some((header, _))) = common::try_read_and_validate_header_iv(&mut rd, name, header_skey, h_off, iv0, mode) else { continue };
+                    debug!("[ESCALATE] Header at 0x{:X} validated with '{}' (name '{}', IV={}, Mode={:?})", h_off, header_skey, name, iv0, mode);
+                    let entries_candidates = std::iter::once(header_skey).chain(keys_to_try.iter().filter(|s| *s != header_skey));
+                    for entries_skey in entries_candidates {
+                        if let Ok((_, entries, c_off)) = common::read_meta_iv_mode_two_key(name, header_skey, entries_skey, &mut rd, h_off, iv0, mode, settings) {
+                            best.fetch_min(i, Ordering::Relaxed);
+                            return Some(KeySearchHit {
+                                entries,
+                                header_salt: header_skey.clone(),
+                                entries_salt: entries_skey.clone(),
+                                name_variant: name.clone(),
+                                iv0,
+                                header_offset: h_off,
+                                mode,
+                                content_offset: c_off,
+                            });
+                        }
+                    }
+                    header_only.lock().unwrap().push((name.clone(), header_skey.clone(), header, h_off, iv0, mode));
+                }
+            }
+            progress.tick(header_skey, name);
+            None
+        });
+        if let Some(hit) = res {
+            info!("[ESCALATE] >>> SUCCESS in stage 1 after {:.1?}: name '{}', HEADER='{}', ENTRIES='{}', Offset=0x{:X}, IV={}, Mode={:?}",
+                start.elapsed(), hit.name_variant, hit.header_salt, hit.entries_salt, hit.header_offset, hit.iv0, hit.mode);
+            return Some(hit);
+        }
+    }
+    drop(progress);
+    let headers = header_only.into_inner().unwrap();
+    info!("[ESCALATE] Stage 1/{} failed after {:.1?}: {} header(s) validated, none with a readable entries table.", stages, start.elapsed(), headers.len());
+    if level != common::Escalation::Full {
+        if !headers.is_empty() {
+            info!("[ESCALATE] Rerun with --auto-escalate to brute-force the entries offset behind them.");
+        }
+        return None;
+    }
+
+    info!("[ESCALATE] Stage 2/2: trying every entries offset within {} bytes of {} header(s) with {} salt(s)...", common::BRUTE_ENTRIES_WINDOW, headers.len(), keys_to_try.len());
+    let start = Instant::now();
+    for (name, header_skey, header, h_off, iv0, mode) in headers {
+        let first = h_off + 9;
+        let res = keys_to_try.par_iter().find_map_first(|entries_skey| {
+            (first..first + common::BRUTE_ENTRIES_WINDOW).find_map(|e_off| {
+                let mut rd = Cursor::new(data);
+                let mut entries = Vec::with_capacity(header.file_cnt as usize);
+                common::stream_entries(&mut rd, &name, entries_skey, &header, e_off, iv0, mode, settings, |ent| { entries.push(ent); Ok(()) }).ok()?;
+                let content_offset = common::content_offset_after_table(&mut rd, &entries, iv0, mode);
+                Some((e_off, KeySearchHit {
+                    entries,
+                    header_salt: header_skey.clone(),
+                    entries_salt: entries_skey.clone(),
+                    name_variant: name.clone(),
+                    iv0,
+                    header_offset: h_off,
+                    mode,
+                    content_offset,
+                }))
+            })
+        });
+        if let Some((e_off, hit)) = res {
+            info!("[ESCALATE] >>> SUCCESS in stage 2 after {:.1?}: name '{}', HEADER='{}', ENTRIES='{}', Offset=0x{:X}, entries at 0x{:X}, IV={}, Mode={:?}",
+                start.elapsed(), hit.name_variant, hit.header_salt, hit.entries_salt, hit.header_offset, e_off, hit.iv0, hit.mode);
+            return Some(hit);
+        }
+    }
+    info!("[ESCALATE] Stage 2/2 failed after {:.1?}: no salt decoded an entries table. The pack needs another salt or its original name (--guess-name).", start.elapsed());
+    None
+}
+
+/// A header that validated although no salt decoded the entries table behind it,
+/// with a guess at why (see `assess_header_only`).
+#[derive(Debug, Clone)]
+pub struct HeaderOnlyMatch {
+    pub name_variant: String,
+    pub header_salt: String,
+    pub header_offset: u64,
+    /// Entries salt whose first decrypted record looked most like a real entry.
+    pub best_entries_salt: String,
+    /// Name length field of that record (UTF-16 units).
+    pub name_units: u32,
+    /// How much that record looks like a real entry, 0.0-1.0 (see `score_entry_record`).
+    pub record_score: f64,
+    /// Confidence, 0.0-1.0, that the header only validated by coincidence.
+    pub false_positive: f64,
+}
+
+impl HeaderOnlyMatch {
+    /// What to try next, in one sentence.
+    pub fn advice(&self) -> String {
+        if self.false_positive >= 0.5 {
+            format!("The header most likely validated by coincidence ({:.0}% confidence): the archive needs another salt or its original file name (keys derive from the name).", self.false_positive * 100.0)
+        } else if self.record_score >= RECORD_DECODED {
+            format!("Salt '{}' decrypts the first entry but the table fails later: it is probably truncated or exceeds the entry limits (--max-name-len / --max-entry-size).", self.best_entries_salt)
+        } else {
+            format!("The header looks genuine ({:.0}% confidence): the entries table uses a salt that isn't in salts.txt.", (1.0 - self.false_positive) * 100.0)
+        }
+    }
+}
+
+/// `score_entry_record` at or above this means the record decrypted correctly.
+const RECORD_DECODED: f64 = 0.6;
+
+/// Score the first bytes of a decrypted entries table by how much they look like an
+/// entry record: a plausible name length followed by printable UTF-16 path characters.
+/// Returns the name length field and a score from 0.0 (noise) to 1.0.
+pub fn score_entry_record(rec: &[u8]) -> (u32, f64) {
+    if rec.len() < 6 {
+        return (0, 0.0);
+    }
+    let units = u32::from_le_bytes(rec[..4].try_into().unwrap());
+    let length = match units {
+        1..=260 => 1.0,
+        261..=1024 => 0.5,
+        _ => return (units, 0.0),
+    };
+    let chars: Vec<u16> = rec[4..].chunks_exact(2).take(units as usize).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    let sum: f64 = chars.iter().map(|&c| match c {
+        0x20..=0x7E => 1.0,
+        0xD800..=0xDFFF | 0xFFFE | 0xFFFF => 0.0,
+        // Non-ASCII names (Korean, Japanese) exist but are rare.
+        0xA0.. => 0.5,
+        _ => 0.0,
+    }).sum();
+    (units, length * sum / chars.len() as f64)
+}
+
+/// Decrypt the first entry record behind a validated header with every salt in
+/// `entries_salts` and judge whether the header match was a coincidence (its file
+/// count can't fit in the archive and nothing decodes) or genuine (the entries
+/// table just uses another salt, or breaks after a good first record).
+pub fn assess_header_only(
+    data: &[u8],
+    name: &str,
+    header_salt: &str,
+    header: &common::FileHeader,
+    header_offset: u64,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+    entries_salts: &[String],
+) -> HeaderOnlyMatch {
+    let mut best = (String::new(), 0u32, 0.0f64);
+    for salt in entries_salts {
+        let key = encryption::gen_entries_key(name, salt);
+        for off in common::entries_offset_candidates(name, header_offset) {
+            let Some(tail) = data.get(off as usize..) else { continue };
+            let mut rd = Cursor::new(tail);
+            let mut dec = encryption::Snow2Decoder::new_iv_mode(&key, iv0, mode, &mut rd);
+            let mut rec = [0u8; 4 + 2 * 128];
+            let mut filled = 0;
+            while filled < rec.len() {
+                match dec.read(&mut rec[filled..]) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => filled += n,
+                }
+            }
+            let (units, score) = score_entry_record(&rec[..filled]);
+            if score > best.2 || best.0.is_empty() {
+                best = (salt.clone(), units, score);
+            }
+        }
+    }
+
+    // A coincidental checksum match leaves a random file count, which almost never
+    // fits in the archive: every record takes at least 42 bytes.
+    let fits = header.file_cnt > 0 && header.file_cnt as u64 * 42 <= data.len() as u64;
+    let header_plausibility = if !fits { 0.0 } else if header.version <= 2 { 1.0 } else { 0.5 };
+    let false_positive = if best.2 >= RECORD_DECODED { (1.0 - best.2) * 0.2 } else { 1.0 - 0.9 * header_plausibility };
+    debug!("[KEY_SEARCH] Header-only match '{}' at 0x{:X}: version {}, {} files, best record {:.2} with '{}' -> false positive {:.2}",
+        header_salt, header_offset, header.version, header.file_cnt, best.2, best.0, false_positive);
+    HeaderOnlyMatch {
+        name_variant: name.to_string(),
+        header_salt: header_salt.to_string(),
+        header_offset,
+        best_entries_salt: best.0,
+        name_units: best.1,
+        record_score: best.2,
+        false_positive,
+    }
+}
+
+/// Every combination found by `search_all_keys`.
+#[derive(Debug, Clone, Default)]
+pub struct KeySearchMatches {
+    /// Combinations that validated both header and entries, in search priority order.
+    pub hits: Vec<KeySearchHit>,
+    /// Headers that validated but whose entries no salt decoded.
+    pub header_only: Vec<HeaderOnlyMatch>,
+}
+
+/// Like `search_keys` but does not stop at the first success: every salt is tried as the
+/// header salt at every header location under every name variant, and every working
+/// entries salt is kept.
+pub fn search_all_keys(
+    data: &[u8],
+    fname_str: &str,
+    cli_skey: Option<String>,
+    loaded_salts: &[String],
+    region_key: Option<String>,
+    settings: &common::PackSettings,
+) -> Result<KeySearchMatches, Error> {
+    let mut keys_to_try: Vec<String> = Vec::new();
+    if let Some(key) = cli_skey { keys_to_try.push(key); }
+    for salt in loaded_salts {
+        if !keys_to_try.contains(salt) { keys_to_try.push(salt.clone()); }
+    }
+
+    let name_variants = common::key_name_variants(fname_str, region_key, settings)?;
+
+    let mut matches = KeySearchMatches::default();
+    for name in &name_variants {
+        let per_salt: Vec<(Vec<KeySearchHit>, Vec<HeaderOnlyMatch>)> = keys_to_try.par_iter().map(|header_skey| {
+            let mut rd = Cursor::new(data);
+            let headers = common::find_all_headers(&mut rd, name, header_skey).unwrap_or_default();
+            let mut hits = Vec::new();
+            let mut header_only = Vec::new();
+            for (header, h_off, iv0, mode) in headers {
+                let before = hits.len();
+                for entries_skey in std::iter::once(header_skey).chain(keys_to_try.iter().filter(|s| *s != header_skey)) {
+                    let mut rd2 = Cursor::new(data);
+                    if let Ok((_, entries, c_off)) = common::read_meta_iv_mode_two_key(name, header_skey, entries_skey, &mut rd2, h_off, iv0, mode, settings) {
+                        hits.push(KeySearchHit {
+                            entries,
+                            header_salt: header_skey.clone(),
+                            entries_salt: entries_skey.clone(),
+                            name_variant: name.clone(),
+                            iv0,
+                            header_offset: h_off,
+                            mode,
+                            content_offset: c_off,
+                        });
+                    }
+                }
+                if hits.len() == before {
+                    header_only.push(assess_header_only(data, name, header_skey, &header, h_off, iv0, mode, &keys_to_try));
+                }
+            }
+            (hits, header_only)
+        }).collect();
+        for (hits, header_only) in per_salt {
+            matches.hits.extend(hits);
+            matches.header_only.extend(header_only);
+        }
+    }
+    debug!("[KEY_SEARCH] Exhaustive search: {} full match(es), {} header-only match(es)", matches.hits.len(), matches.header_only.len());
+    Ok(matches)
+}
+
+/// Pick one combination out of `hits`: the first whose header or entries salt equals
+/// `prefer_salt`, otherwise the first in priority order.
+pub fn select_hit(mut hits: Vec<KeySearchHit>, prefer_salt: Option<&str>) -> Option<KeySearchHit> {
+    if hits.is_empty() { return None; }
+    let idx = prefer_salt
+        .and_then(|p| hits.iter().position(|h| h.header_salt == p || h.entries_salt == p))
+        .unwrap_or_else(|| {
+            if let Some(p) = prefer_salt { warn!("[KEY_SEARCH] Preferred salt '{}' did not validate; using first match.", p); }
+            0
+        });
+    if hits.len() > 1 {
+        info!("[KEY_SEARCH] {} key combinations validated; using HEADER='{}', ENTRIES='{}'", hits.len(), hits[idx].header_salt, hits[idx].entries_salt);
+    }
+    Some(hits.swap_remove(idx))
+}
+
 pub fn convert(input: &str, output: &str, key: Option<String>, wrap_data: bool) -> Result<(), Error> {
     info!("[CONVERT] Converting '{}' -> '{}'", input, output);
     let unique_id = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
@@ -196,11 +609,12 @@ pub fn convert(input: &str, output: &str, key: Option<String>, wrap_data: bool)
 
     if input.to_lowercase().ends_with(".pack") {
         debug!("[CONVERT] Extracting source .pack");
-        pack_v1::run_extract_v1(input, &tmp_path)?;
+        pack_v1::run_extract_v1(input, &tmp_path, &common::PackSettings::default())?;
     } else {
         debug!("[CONVERT] Extracting source .it");
         let salts = crate::load_salts();
-        discovered_salt = extract::run_extract_with_key_search(input, &tmp_path, key.clone(), &salts, vec![], None, false, None)?;    }
+        discovered_salt = extract::run_extract_with_key_search(input, &tmp_path, key.clone(), &salts, vec![], None, false, None)?.salt.unwrap_or_default();
+    }
 
     // Only wrap if the extracted tree doesn't already have a data/ subfolder
     let already_wrapped = std::fs::read_dir(&tmp)
@@ -254,7 +668,7 @@ pub fn run_full_sequence(folder: &str, output: &str, key: Option<String>) -> Res
         let path_str = path.to_str().unwrap();
         debug!("[SEQUENCE] Processing archive: {}", path_str);
         if path_str.to_lowercase().ends_with(".pack") {
-            pack_v1::run_extract_v1(path_str, &tmp_path)?;
+            pack_v1::run_extract_v1(path_str, &tmp_path, &common::PackSettings::default())?;
         } else {
             // Force using provided key if possible, then search with DEEP validation
             extract::run_extract_with_key_search(path_str, &tmp_path, key.clone(), &salts, vec![], None, false, None)?;
@@ -317,10 +731,10 @@ pub fn run_batch_extract(
             std::fs::create_dir_all(&out_dir)?;
             let key_to_use = cached_salt.clone().or_else(|| cli_key.clone());
             if fname.to_lowercase().ends_with(".pack") {
-                let _ = pack_v1::run_extract_v1(fname, &out_dir);
+                let _ = pack_v1::run_extract_v1(fname, &out_dir, &common::PackSettings::default());
             } else {
-                match extract::run_extract_with_key_search(fname, &out_dir, key_to_use, &salts, filters.clone(), None, false, None) {
-                    Ok(salt) => { cached_salt = Some(salt); }
+                match common::catch_panic(fname, || extract::run_extract_with_key_search(fname, &out_dir, key_to_use, &salts, filters.clone(), None, false, None)) {
+                    Ok(summary) => { cached_salt = summary.salt.or(cached_salt.take()); }
                     Err(e) => warn!("[BATCH] Failed {}: {}", archive_name, e),
                 }
             }
@@ -346,10 +760,10 @@ pub fn run_batch_extract(
                     };
                     let _ = std::fs::create_dir_all(&out_dir);
                     if fname.to_lowercase().ends_with(".pack") {
-                        let _ = pack_v1::run_extract_v1(fname, &out_dir);
+                        let _ = pack_v1::run_extract_v1(fname, &out_dir, &common::PackSettings::default());
                     } else {
                         let key = cli_key.clone();
-                        match extract::run_extract_with_key_search(fname, &out_dir, key, &salts, filters.clone(), None, false, None) {
+                        match common::catch_panic(fname, || extract::run_extract_with_key_search(fname, &out_dir, key, &salts, filters.clone(), None, false, None)) {
                             Ok(_) => {}
                             Err(e) => warn!("[BATCH] Failed {}: {}", archive_name, e),
                         }
@@ -361,5 +775,6 @@ pub fn run_batch_extract(
     }
 
     info!("[BATCH] All {} archives processed.", total);
+    key_cache::flush();
     Ok(())
 }
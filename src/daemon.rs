@@ -0,0 +1,378 @@
+// daemon.rs - Long-running JSON-RPC server that keeps salts and opened packs warm for frontends
+
+//! One JSON-RPC 2.0 request per line, one response per line, over a socket file (the default
+//! on Unix) or a loopback TCP address (`127.0.0.1:7878`):
+//!
+//! ```text
+//! {"jsonrpc":"2.0","id":1,"token":"5f0c...","method":"list","params":{"pack":"data_00.it","filters":["\\.xml$"]}}
+//! {"jsonrpc":"2.0","id":1,"result":{"entries":[{"name":"db/a.xml","size":5,"stored_size":16,"flags":1}]}}
+//! ```
+//!
+//! Every request carries the `token` the daemon wrote to its token file at startup, which
+//! only the user can read. A line that isn't a JSON-RPC request, or has the wrong token, is
+//! answered with an error and the connection is closed, so a browser posting to the TCP
+//! port can't get a request body through after its HTTP header lines.
+//!
+//! Methods: `ping`, `list` (`pack`, `key`, `filters`), `extract` (`pack`, `output`, `key`,
+//! `filters`), `pack` (`input`, `output`, `key`, `compress`), `close` (`pack`) and
+//! `shutdown`. A pack's keys are searched once; later requests reuse the opened pack until
+//! its size or modification time changes.
+
+use crate::common::PackSettings;
+use crate::common_ext::KeySearchHit;
+use crate::extract::{self, ExtractOptions};
+use crate::pack::{self, PackOptions};
+use crate::reader::PackReader;
+use anyhow::Error;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The request was valid but the operation failed (no salt opens the pack, I/O...).
+pub const OPERATION_FAILED: i64 = -32000;
+/// The request's `token` is missing or doesn't match the daemon's.
+pub const UNAUTHORIZED: i64 = -32001;
+
+/// Token file written next to the key cache: `%APPDATA%\mabi-pack2` or `~/.mabi-pack2`.
+pub const TOKEN_FILE: &str = "daemon.token";
+/// Socket file created next to the token file when `daemon` is given no `--socket`.
+pub const SOCKET_FILE: &str = "daemon.sock";
+
+/// A request failure with its JSON-RPC error code.
+#[derive(Debug)]
+struct RpcError(i64, String);
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        RpcError(OPERATION_FAILED, format!("{:#}", e))
+    }
+}
+
+struct OpenPack {
+    len: u64,
+    modified: Option<SystemTime>,
+    reader: Arc<PackReader>,
+}
+
+/// The server state shared by every connection: the salts, the settings packs are read
+/// with and the packs opened so far.
+pub struct Daemon {
+    salts: Vec<String>,
+    settings: PackSettings,
+    token: String,
+    packs: Mutex<HashMap<String, OpenPack>>,
+    stop: AtomicBool,
+}
+
+fn file_stamp(path: &str) -> Result<(u64, Option<SystemTime>), Error> {
+    let meta = std::fs::metadata(path).map_err(|e| Error::msg(format!("'{}': {}", path, e)))?;
+    Ok((meta.len(), meta.modified().ok()))
+}
+
+fn error_response(id: Value, code: i64, message: String) -> String {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }).to_string()
+}
+
+/// Whether two tokens match, comparing every byte so the time taken doesn't tell how much
+/// of a guess was right.
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len() && expected.bytes().zip(given.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// A fresh random token: 32 bytes from the OS, hex-encoded.
+pub fn new_token() -> Result<String, Error> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::msg(format!("No OS randomness for the daemon token: {}", e)))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Default token file location, next to the key cache.
+pub fn default_token_path() -> PathBuf {
+    crate::key_cache::default_path().with_file_name(TOKEN_FILE)
+}
+
+/// Default `--socket`: a socket file next to the token file on Unix, `127.0.0.1:7878` elsewhere.
+pub fn default_socket() -> String {
+    if cfg!(unix) {
+        crate::key_cache::default_path().with_file_name(SOCKET_FILE).to_string_lossy().into_owned()
+    } else {
+        "127.0.0.1:7878".to_string()
+    }
+}
+
+/// Write `token` to `path`, replacing an earlier token file. On Unix the file is created
+/// readable by its owner only; on Windows it inherits the per-user `%APPDATA%` permissions.
+pub fn write_token(path: &Path, token: &str) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() { std::fs::create_dir_all(parent)?; }
+    }
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Error::msg(format!("'{}': {}", path.display(), e))),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path).map_err(|e| Error::msg(format!("'{}': {}", path.display(), e)))?;
+    file.write_all(token.as_bytes())?;
+    Ok(())
+}
+
+fn str_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params.get(name).and_then(Value::as_str).ok_or_else(|| RpcError(INVALID_PARAMS, format!("missing string parameter '{}'", name)))
+}
+
+fn opt_str_param(params: &Value, name: &str) -> Result<Option<String>, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(RpcError(INVALID_PARAMS, format!("parameter '{}' must be a string", name))),
+    }
+}
+
+fn list_param(params: &Value, name: &str) -> Result<Vec<String>, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::Array(items)) => items.iter()
+            .map(|v| v.as_str().map(str::to_string).ok_or_else(|| RpcError(INVALID_PARAMS, format!("parameter '{}' must be a list of strings", name))))
+            .collect(),
+        Some(_) => Err(RpcError(INVALID_PARAMS, format!("parameter '{}' must be a list of strings", name))),
+    }
+}
+
+impl Daemon {
+    /// A daemon that answers requests carrying `token` (see `new_token` and `write_token`).
+    pub fn new(salts: Vec<String>, settings: PackSettings, token: String) -> Self {
+        Daemon { salts, settings, token, packs: Mutex::new(HashMap::new()), stop: AtomicBool::new(false) }
+    }
+
+    /// Packs held open for later requests.
+    pub fn open_packs(&self) -> usize {
+        self.packs.lock().unwrap().len()
+    }
+
+    /// Whether a `shutdown` request was handled.
+    pub fn stopping(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /// The opened pack at `path`, searching its keys only when it isn't open yet or the
+    /// file changed since. `key` is tried first on a fresh open.
+    fn reader(&self, path: &str, key: Option<String>) -> Result<Arc<PackReader>, Error> {
+        let (len, modified) = file_stamp(path)?;
+        if let Some(open) = self.packs.lock().unwrap().get(path).filter(|p| p.len == len && p.modified == modified) {
+            debug!("[DAEMON] Reusing the open '{}'", path);
+            return Ok(open.reader.clone());
+        }
+        let reader = Arc::new(PackReader::open_with_settings(path, key, &self.salts, &self.settings)?);
+        self.packs.lock().unwrap().insert(path.to_string(), OpenPack { len, modified, reader: reader.clone() });
+        Ok(reader)
+    }
+
+    fn list(&self, params: &Value) -> Result<Value, RpcError> {
+        let reader = self.reader(str_param(params, "pack")?, opt_str_param(params, "key")?)?;
+        let filters = extract::make_regex(list_param(params, "filters")?, &self.settings)?;
+        let entries: Vec<Value> = reader.select(&filters).into_iter()
+            .map(|e| json!({ "name": e.name.replace('\\', "/"), "size": e.original_size, "stored_size": e.raw_size, "flags": e.flags }))
+            .collect();
+        Ok(json!({ "entries": entries }))
+    }
+
+    fn extract(&self, params: &Value) -> Result<Value, RpcError> {
+        let path = str_param(params, "pack")?;
+        let output = str_param(params, "output")?;
+        let reader = self.reader(path, opt_str_param(params, "key")?)?;
+        let hit = KeySearchHit {
+            entries: reader.entries.clone(),
+            header_salt: reader.header_salt.clone(),
+            entries_salt: reader.entries_salt.clone(),
+            name_variant: reader.name_variant.clone(),
+            iv0: reader.iv0,
+            header_offset: reader.header_offset,
+            mode: reader.mode,
+            content_offset: reader.content_offset,
+        };
+        let opts = ExtractOptions { filters: list_param(params, "filters")?, settings: self.settings.clone(), ..ExtractOptions::default() };
+        let summary = extract::run_extract_with_hit(path, output, &hit, &opts)?;
+        Ok(serde_json::to_value(summary).map_err(Error::from)?)
+    }
+
+    fn pack(&self, params: &Value) -> Result<Value, RpcError> {
+        let input = str_param(params, "input")?;
+        let output = str_param(params, "output")?;
+        let key = str_param(params, "key")?;
+        let opts = PackOptions { compress_ext: list_param(params, "compress")?, settings: self.settings.clone(), ..PackOptions::default() };
+        let summary = pack::run_pack_with_options(input, output, key, &opts, None)?;
+        self.packs.lock().unwrap().remove(output);
+        Ok(serde_json::to_value(summary).map_err(Error::from)?)
+    }
+
+    fn dispatch(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "ping" => Ok(json!({ "version": env!("CARGO_PKG_VERSION"), "open_packs": self.open_packs() })),
+            "list" => self.list(params),
+            "extract" => self.extract(params),
+            "pack" => self.pack(params),
+            "close" => Ok(json!({ "closed": self.packs.lock().unwrap().remove(str_param(params, "pack")?).is_some() })),
+            "shutdown" => {
+                self.stop.store(true, Ordering::Relaxed);
+                Ok(json!({ "stopping": true }))
+            }
+            other => Err(RpcError(METHOD_NOT_FOUND, format!("unknown method '{}'", other))),
+        }
+    }
+
+    /// Parse one line into an authenticated JSON-RPC request, or the error response that
+    /// ends the connection.
+    fn check_request(&self, line: &str) -> Result<Value, String> {
+        let request: Value = serde_json::from_str(line).map_err(|e| error_response(Value::Null, PARSE_ERROR, e.to_string()))?;
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") || request.get("method").and_then(Value::as_str).is_none() {
+            return Err(error_response(id, INVALID_REQUEST, "not a JSON-RPC 2.0 request with a method".to_string()));
+        }
+        if !request.get("token").and_then(Value::as_str).is_some_and(|t| token_matches(&self.token, t)) {
+            warn!("[DAEMON] Rejected a request without the daemon token");
+            return Err(error_response(id, UNAUTHORIZED, "missing or wrong token".to_string()));
+        }
+        Ok(request)
+    }
+
+    /// Answer one request line. Notifications (requests without an `id`) get no answer,
+    /// unless the line is rejected.
+    pub fn handle_line(&self, line: &str) -> Option<String> {
+        match self.check_request(line) {
+            Ok(request) => self.answer(&request),
+            Err(response) => Some(response),
+        }
+    }
+
+    fn answer(&self, request: &Value) -> Option<String> {
+        let method = request.get("method").and_then(Value::as_str).unwrap_or_default();
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+        debug!("[DAEMON] {} {}", method, params);
+        let result = self.dispatch(method, &params);
+        let id = request.get("id").cloned()?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(RpcError(code, message)) => {
+                warn!("[DAEMON] Request {} failed: {}", id, message);
+                json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+            }
+        }.to_string())
+    }
+
+    /// Serve one connection until the client closes it (or asks to shut down). The first
+    /// rejected line (see `check_request`) is answered and closes the connection.
+    pub fn serve_connection<S: std::io::Read + Write>(&self, stream: S) -> Result<(), Error> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            if !line.trim().is_empty() {
+                let (response, close) = match self.check_request(line.trim()) {
+                    Ok(request) => (self.answer(&request), false),
+                    Err(response) => (Some(response), true),
+                };
+                if let Some(response) = response {
+                    let out = reader.get_mut();
+                    out.write_all(response.as_bytes())?;
+                    out.write_all(b"\n")?;
+                    out.flush()?;
+                }
+                if close {
+                    break;
+                }
+            }
+            line.clear();
+            if self.stopping() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Accept connections on `socket` until a `shutdown` request, each on its own thread.
+/// `socket` is, on Unix, the path of a socket file (a socket already there is replaced; any
+/// other file is refused) or a loopback TCP address (`127.0.0.1:7878`). Requests read and
+/// write any path the user can, so other hosts must not reach the daemon even with a token.
+pub fn serve(daemon: Arc<Daemon>, socket: &str) -> Result<(), Error> {
+    if let Ok(addr) = socket.parse::<std::net::SocketAddr>() {
+        if !addr.ip().is_loopback() {
+            return Err(Error::msg(format!("Refusing to listen on {}: the daemon only accepts loopback addresses such as 127.0.0.1:7878", addr)));
+        }
+        let listener = std::net::TcpListener::bind(addr)?;
+        info!("[DAEMON] Listening on {}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            if daemon.stopping() {
+                break;
+            }
+            let stream = stream?;
+            let daemon = daemon.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = daemon.serve_connection(&stream) {
+                    warn!("[DAEMON] Connection failed: {}", e);
+                }
+                if daemon.stopping() {
+                    // Wake the accept loop so it sees the flag.
+                    let _ = std::net::TcpStream::connect(addr);
+                }
+            });
+        }
+        return Ok(());
+    }
+    serve_unix(daemon, socket)
+}
+
+#[cfg(unix)]
+fn serve_unix(daemon: Arc<Daemon>, path: &str) -> Result<(), Error> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() { std::fs::create_dir_all(parent)?; }
+    }
+    // Only a socket left behind by an earlier run is replaced; anything else at the path is kept.
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(Error::msg(format!("'{}' exists and is not a socket; choose another --socket path", path)));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("[DAEMON] Listening on {}", path);
+    for stream in listener.incoming() {
+        if daemon.stopping() {
+            break;
+        }
+        let stream = stream?;
+        let daemon = daemon.clone();
+        let path = path.to_string();
+        std::thread::spawn(move || {
+            if let Err(e) = daemon.serve_connection(&stream) {
+                warn!("[DAEMON] Connection failed: {}", e);
+            }
+            if daemon.stopping() {
+                let _ = UnixStream::connect(&path);
+            }
+        });
+    }
+    let _ = std::fs::remove_file(path);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_unix(_daemon: Arc<Daemon>, path: &str) -> Result<(), Error> {
+    Err(Error::msg(format!("'{}' is not an address; on this system --socket takes HOST:PORT, e.g. 127.0.0.1:7878", path)))
+}
@@ -0,0 +1,173 @@
+// dedupe.rs - Cross-archive output deduplication for batch extraction
+
+use crate::{common, verify};
+use anyhow::Error;
+use log::{debug, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// What to do with a file whose content was already extracted elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupeMode {
+    /// Hard-link the new path to the earlier copy (falls back to writing on failure).
+    Hardlink,
+    /// Don't write the duplicate at all.
+    Skip,
+}
+
+impl std::str::FromStr for DedupeMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_lowercase().as_str() {
+            "" | "hardlink" | "link" => Ok(DedupeMode::Hardlink),
+            "skip" => Ok(DedupeMode::Skip),
+            other => Err(Error::msg(format!("Unknown dedupe mode '{}' (expected hardlink or skip)", other))),
+        }
+    }
+}
+
+type ContentKey = (u64, [u8; 16]);
+
+#[derive(Default)]
+struct Index {
+    by_content: HashMap<ContentKey, PathBuf>,
+    by_path: HashMap<PathBuf, ContentKey>,
+}
+
+/// Remembers the content of every file written during a batch so later archives
+/// can link to (or skip) identical files. Shared between extraction threads.
+pub struct OutputDedupe {
+    mode: DedupeMode,
+    index: Mutex<Index>,
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl OutputDedupe {
+    pub fn new(mode: DedupeMode) -> Self {
+        OutputDedupe { mode, index: Mutex::new(Index::default()), files: AtomicU64::new(0), bytes: AtomicU64::new(0) }
+    }
+
+    /// Write `content` to `root_dir/rel_path` unless an identical file was already written.
+    /// Returns `true` when the file was linked or skipped instead of written.
+    pub fn write(&self, root_dir: &str, rel_path: &str, content: &[u8], settings: &common::PackSettings) -> Result<bool, Error> {
+        let path = common::output_path(root_dir, rel_path, settings)?;
+        let key: ContentKey = (content.len() as u64, md5::compute(content).0);
+
+        let existing = self.index.lock().unwrap().by_content.get(&key).cloned();
+        if let Some(existing) = existing {
+            if existing == path || self.mode == DedupeMode::Skip || self.link(&existing, &path) {
+                debug!("[DEDUPE] {} is identical to {}", path.display(), existing.display());
+                self.files.fetch_add(1, Ordering::Relaxed);
+                self.bytes.fetch_add(content.len() as u64, Ordering::Relaxed);
+                return Ok(true);
+            }
+        }
+
+        // The path may be a link to another copy from an earlier archive; replace it
+        // rather than writing through it.
+        let _ = std::fs::remove_file(&path);
+        common::write_file_to_disk(root_dir, rel_path, content, settings)?;
+
+        let mut index = self.index.lock().unwrap();
+        if let Some(old_key) = index.by_path.insert(path.clone(), key) {
+            if index.by_content.get(&old_key) == Some(&path) {
+                index.by_content.remove(&old_key);
+            }
+        }
+        index.by_content.entry(key).or_insert(path);
+        Ok(false)
+    }
+
+    fn link(&self, existing: &Path, path: &Path) -> bool {
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let _ = std::fs::remove_file(path);
+        match std::fs::hard_link(existing, path) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("[DEDUPE] Cannot link {} -> {}: {}; writing a copy", path.display(), existing.display(), e);
+                false
+            }
+        }
+    }
+
+    pub fn mode(&self) -> DedupeMode {
+        self.mode
+    }
+
+    /// Number of duplicate files linked or skipped, and their total size.
+    pub fn saved(&self) -> (u64, u64) {
+        (self.files.load(Ordering::Relaxed), self.bytes.load(Ordering::Relaxed))
+    }
+}
+
+/// `extract --content-addressed`: every distinct content is stored once as
+/// `objects/ab/cdef...` (its MD5), and an md5sum-style index maps entry names to
+/// digests. Extracting several packs or client versions into the same folder only adds
+/// the objects that are new. Shared between extraction threads.
+pub struct ObjectStore {
+    index: Mutex<BTreeMap<String, String>>,
+    stored: AtomicU64,
+    reused: AtomicU64,
+}
+
+impl Default for ObjectStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ObjectStore {
+    /// Folder under the output root holding the objects.
+    pub const OBJECTS_DIR: &'static str = "objects";
+
+    pub fn new() -> Self {
+        ObjectStore { index: Mutex::new(BTreeMap::new()), stored: AtomicU64::new(0), reused: AtomicU64::new(0) }
+    }
+
+    /// Relative path of the object holding content with digest `hash`.
+    pub fn object_path(hash: &str) -> String {
+        format!("{}/{}/{}", Self::OBJECTS_DIR, &hash[..2], &hash[2..])
+    }
+
+    /// Store `content` under `root_dir` unless an object with its digest exists, and
+    /// index it as `name`. Returns the digest.
+    pub fn write(&self, root_dir: &str, name: &str, content: &[u8]) -> Result<String, Error> {
+        let hash = verify::md5_hex(content);
+        let rel_path = Self::object_path(&hash);
+        let path = Path::new(root_dir).join(rel_path.replace('/', std::path::MAIN_SEPARATOR_STR));
+        let same_size = std::fs::metadata(&path).is_ok_and(|m| m.len() == content.len() as u64);
+        if same_size {
+            debug!("[OBJECTS] {} already stored as {}", name, rel_path);
+            self.reused.fetch_add(1, Ordering::Relaxed);
+        } else {
+            common::write_file_to_disk(root_dir, &rel_path, content, &common::PackSettings::default())?;
+            self.stored.fetch_add(1, Ordering::Relaxed);
+        }
+        self.index.lock().unwrap().insert(verify::normalize_name(name), hash.clone());
+        Ok(hash)
+    }
+
+    /// Write the index to `path` as `<md5>  <name>` lines sorted by name. Entries of an
+    /// index already at `path` are kept unless this run indexed the same name. Returns
+    /// the number of lines written.
+    pub fn write_index(&self, path: &str) -> Result<usize, Error> {
+        let mut merged = if Path::new(path).exists() { verify::load_manifest(path)? } else { BTreeMap::new() };
+        merged.extend(self.index.lock().unwrap().iter().map(|(name, hash)| (name.clone(), hash.clone())));
+        let text: String = merged.iter().map(|(name, hash)| format!("{}  {}\n", hash, name)).collect();
+        if let Some(parent) = Path::new(path).parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        std::fs::write(path, text)?;
+        Ok(merged.len())
+    }
+
+    /// Number of objects written, and of entries whose object was already stored.
+    pub fn counts(&self) -> (u64, u64) {
+        (self.stored.load(Ordering::Relaxed), self.reused.load(Ordering::Relaxed))
+    }
+}
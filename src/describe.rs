@@ -0,0 +1,256 @@
+// describe.rs - Annotated map of every structure in a pack, decoded with the reader's own parsers
+
+use crate::common::{self, EntryLimits, FileEntry, FileHeader, FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED, FLAG_HEAD_ENCRYPTED};
+use crate::info::{pack_layout, BLOCK_SIZE};
+use crate::reader::{hex, PackReader};
+use crate::{encryption, pack};
+use anyhow::Error;
+use byteorder::{LittleEndian, ReadBytesExt};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Cursor, Seek, SeekFrom, Write};
+
+/// One decoded field of a structure, at its absolute file offset.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldInfo {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+    pub value: String,
+}
+
+/// A byte range of the pack and what it holds. `kind` is one of `filler`, `header`,
+/// `entry`, `padding`, `data`, `gap`, `metadata` or `footer`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Structure {
+    pub kind: &'static str,
+    pub offset: u64,
+    pub size: u64,
+    pub label: String,
+    pub note: String,
+    pub fields: Vec<FieldInfo>,
+}
+
+/// Every structure of a pack in file order, plus the keys used to decode them.
+#[derive(Debug, Clone, Serialize)]
+pub struct FormatDescription {
+    pub pack: String,
+    pub file_len: u64,
+    pub name_variant: String,
+    pub header_salt: String,
+    pub entries_salt: String,
+    pub iv0: u32,
+    pub mode: String,
+    pub structures: Vec<Structure>,
+}
+
+/// Output format of `run_describe_format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescribeFormat {
+    Json,
+    Markdown,
+}
+
+fn field(name: &str, offset: u64, size: u64, value: String) -> FieldInfo {
+    FieldInfo { name: name.to_string(), offset, size, value }
+}
+
+fn region(kind: &'static str, offset: u64, size: u64, label: &str, note: String) -> Structure {
+    Structure { kind, offset, size, label: label.to_string(), note, fields: Vec::new() }
+}
+
+/// Decode the header, entries table and footer of an opened pack again with the same
+/// parsers the search uses (`FileHeader::new`, `probe_first_entry`, `FileEntry::new_with_limits`)
+/// and record where each field came from. The content area is mapped with `pack_layout`.
+pub fn describe_pack(reader: &PackReader) -> Result<FormatDescription, Error> {
+    let bytes = reader.bytes();
+    let len = bytes.len() as u64;
+    let mut cur = Cursor::new(bytes);
+    let (iv0, mode) = (reader.iv0, reader.mode);
+    let header_key = encryption::gen_header_key(&reader.name_variant, &reader.header_salt);
+    let mut structures = Vec::new();
+
+    if reader.header_offset > 0 {
+        structures.push(region("filler", 0, reader.header_offset, "before header", "not read; the header offset comes from the file name".to_string()));
+    }
+
+    let h = reader.header_offset;
+    cur.seek(SeekFrom::Start(h))?;
+    let header = FileHeader::new(&mut encryption::Snow2Decoder::new_iv_mode(&header_key, iv0, mode, &mut cur))?;
+    let expected = (header.version as u32).wrapping_add(header.file_cnt);
+    let mut hdr = region("header", h, 9, "file header", format!("SNOW2, header key from name '{}' + header salt", reader.name_variant));
+    hdr.fields = vec![
+        field("checksum", h, 4, checksum_value(header.checksum, expected)),
+        field("version", h + 4, 1, header.version.to_string()),
+        field("file_cnt", h + 5, 4, header.file_cnt.to_string()),
+    ];
+    structures.push(hdr);
+
+    let entries_offset = common::probe_first_entry(&mut cur, &reader.name_variant, &reader.entries_salt, &header, h, iv0, mode, &reader.settings)
+        .ok_or_else(|| Error::msg("Entries table not found at any candidate offset"))?;
+    if entries_offset > h + 9 {
+        structures.push(region("filler", h + 9, entries_offset - (h + 9), "before entries table", "not read; the entries offset comes from the file name".to_string()));
+    }
+
+    let limits = EntryLimits::effective(header.version, &reader.settings);
+    let entries_key = encryption::gen_entries_key(&reader.name_variant, &reader.entries_salt);
+    cur.seek(SeekFrom::Start(entries_offset))?;
+    let mut dec = encryption::Snow2Decoder::new_iv_mode(&entries_key, iv0, mode, &mut cur);
+    let mut entries = Vec::with_capacity(header.file_cnt as usize);
+    for idx in 0..header.file_cnt as usize {
+        let start = entries_offset + dec.current_stream_position();
+        let ent = FileEntry::new_with_limits(&mut dec, &limits)
+            .map_err(|e| Error::msg(format!("entry {} of {} unreadable: {}", idx + 1, header.file_cnt, e)))?;
+        let size = entries_offset + dec.current_stream_position() - start;
+        structures.push(entry_record(&ent, idx, start, size, reader.content_offset, &reader.settings));
+        entries.push(ent);
+    }
+    let table_end = entries_offset + dec.current_stream_position();
+    if reader.content_offset > table_end {
+        structures.push(region("padding", table_end, reader.content_offset - table_end, "after entries table",
+            format!("rounds the content offset up to a {}-byte block", BLOCK_SIZE)));
+    }
+
+    let content_end = reader.content_end();
+    let by_name: HashMap<&str, &FileEntry> = entries.iter().map(|e| (e.name.as_str(), e)).collect();
+    for row in pack_layout(&entries, content_end - reader.content_offset).rows {
+        let offset = reader.content_offset + row.first_block * BLOCK_SIZE;
+        let size = (row.blocks * BLOCK_SIZE).min(content_end.saturating_sub(offset));
+        if row.owners.is_empty() {
+            structures.push(region("gap", offset, size, &format!("blocks {}..{}", row.first_block, row.first_block + row.blocks), "not referenced by any entry".to_string()));
+            continue;
+        }
+        let ent = by_name[row.owners[0].as_str()];
+        let encrypted = if ent.flags & FLAG_ALL_ENCRYPTED != 0 {
+            row.used
+        } else if ent.flags & FLAG_HEAD_ENCRYPTED != 0 {
+            row.used.min(1024)
+        } else {
+            0
+        };
+        let mut note = format!("flags {}; {} of {} bytes encrypted with the file key", flag_value(ent.flags), encrypted, row.used);
+        if row.overlaps {
+            note.push_str("; overlaps earlier data");
+        }
+        let mut data = region("data", offset, size, &row.owners.join(", "), note);
+        data.fields.push(field("payload", offset, row.used, if ent.flags & FLAG_COMPRESSED != 0 { "zlib stream".to_string() } else { "stored".to_string() }));
+        if size > row.used {
+            data.fields.push(field("slack", offset + row.used, size - row.used, "block padding".to_string()));
+        }
+        structures.push(data);
+    }
+
+    let mut tail = content_end;
+    if len >= 12 && &bytes[len as usize - 8..len as usize - 4] == pack::META_MAGIC {
+        let json_len = u32::from_le_bytes(bytes[len as usize - 12..len as usize - 8].try_into()?) as u64;
+        let value = match reader.metadata() {
+            Some(meta) => serde_json::to_string(&meta)?,
+            None => "unparsable".to_string(),
+        };
+        let mut meta = region("metadata", content_end, len - 4 - content_end, "build-info record", "written by pack; not encrypted".to_string());
+        meta.fields = vec![
+            field("json", content_end, json_len, value),
+            field("json_len", len - 12, 4, json_len.to_string()),
+            field("magic", len - 8, 4, String::from_utf8_lossy(pack::META_MAGIC).into_owned()),
+        ];
+        structures.push(meta);
+        tail = len - 4;
+    }
+    if len >= 4 && tail < len - 4 {
+        structures.push(region("gap", tail, len - 4 - tail, "before footer", "not read".to_string()));
+    }
+    if len >= 4 {
+        cur.seek(SeekFrom::Start(len - 4))?;
+        let pointer = encryption::Snow2Decoder::new_iv_mode(&header_key, iv0, mode, &mut cur).read_u32::<LittleEndian>()?;
+        let mut footer = region("footer", len - 4, 4, "header pointer", "SNOW2 with the header key".to_string());
+        let check = if pointer as u64 == reader.header_offset { "matches the header" } else { "does not match the header" };
+        footer.fields.push(field("header_offset", len - 4, 4, format!("0x{:X} ({})", pointer, check)));
+        structures.push(footer);
+    }
+
+    Ok(FormatDescription {
+        pack: reader.path.clone(),
+        file_len: len,
+        name_variant: reader.name_variant.clone(),
+        header_salt: reader.header_salt.clone(),
+        entries_salt: reader.entries_salt.clone(),
+        iv0,
+        mode: format!("{:?}", mode),
+        structures,
+    })
+}
+
+fn checksum_value(stored: u32, expected: u32) -> String {
+    if stored == expected {
+        format!("0x{:08X} (ok)", stored)
+    } else {
+        format!("0x{:08X} (expected 0x{:08X})", stored, expected)
+    }
+}
+
+fn flag_value(flags: u32) -> String {
+    match common::flag_names(flags) {
+        names if names.is_empty() => format!("0x{:X}", flags),
+        names => format!("0x{:X} ({})", flags, names),
+    }
+}
+
+fn entry_record(ent: &FileEntry, idx: usize, start: u64, size: u64, content_offset: u64, settings: &common::PackSettings) -> Structure {
+    let name_bytes = ent.name.encode_utf16().count() as u64 * 2;
+    let mut s = region("entry", start, size, &ent.name, format!("entry {}; SNOW2 with the entries key", idx + 1));
+    let mut at = start;
+    let mut next = |name: &str, width: u64, value: String| {
+        let f = field(name, at, width, value);
+        at += width;
+        f
+    };
+    s.fields = vec![
+        next("name_len", 4, format!("{} UTF-16 units", name_bytes / 2)),
+        next("name", name_bytes, ent.name.clone()),
+        next("checksum", 4, checksum_value(ent.checksum, settings.checksum(ent))),
+        next("flags", 4, flag_value(ent.flags)),
+        next("offset", 4, format!("block {} (0x{:X})", ent.offset, content_offset + ent.offset as u64 * BLOCK_SIZE)),
+        next("original_size", 4, ent.original_size.to_string()),
+        next("raw_size", 4, ent.raw_size.to_string()),
+        next("key", 16, hex(&ent.key)),
+    ];
+    s
+}
+
+/// Write `desc` as a markdown document: a summary, then one table row per structure
+/// followed by a row per field.
+pub fn write_markdown(desc: &FormatDescription, out: &mut dyn Write) -> Result<(), Error> {
+    writeln!(out, "# Layout of `{}`", desc.pack)?;
+    writeln!(out)?;
+    writeln!(out, "- File size: {} bytes", desc.file_len)?;
+    writeln!(out, "- Key name: `{}`", desc.name_variant)?;
+    writeln!(out, "- Header salt: `{}`", desc.header_salt)?;
+    writeln!(out, "- Entries salt: `{}`", desc.entries_salt)?;
+    writeln!(out, "- IV / mode: {} / {}", desc.iv0, desc.mode)?;
+    writeln!(out)?;
+    writeln!(out, "| Offset | Size | Structure | Value |")?;
+    writeln!(out, "|---|---|---|---|")?;
+    let cell = |s: &str| s.replace('|', "\\|").replace('\n', " ");
+    for s in &desc.structures {
+        let note = if s.note.is_empty() { String::new() } else { format!(" ({})", cell(&s.note)) };
+        writeln!(out, "| 0x{:08X} | {} | **{}** {} | {} |", s.offset, s.size, s.kind, cell(&s.label), note.trim_start())?;
+        for f in &s.fields {
+            writeln!(out, "| 0x{:08X} | {} | &nbsp;&nbsp;{} | {} |", f.offset, f.size, f.name, cell(&f.value))?;
+        }
+    }
+    Ok(())
+}
+
+/// Open `input` and write its structure description (see `describe_pack`).
+pub fn run_describe_format(input: &str, cli_key: Option<String>, loaded_salts: &[String], settings: &common::PackSettings, format: DescribeFormat, out: &mut dyn Write) -> Result<(), Error> {
+    let reader = PackReader::open_with_settings(input, cli_key, loaded_salts, settings)?;
+    let desc = describe_pack(&reader)?;
+    match format {
+        DescribeFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, &desc)?;
+            writeln!(out)?;
+        }
+        DescribeFormat::Markdown => write_markdown(&desc, out)?,
+    }
+    Ok(())
+}
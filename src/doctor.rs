@@ -0,0 +1,247 @@
+// doctor.rs - Environment and pack checks to run first when something doesn't work
+
+use crate::key_cache::KeyCache;
+use crate::{common, key_cache, HARDCODED_SALTS, SALTS_URL};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use std::fmt;
+use std::io::{Cursor, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// The outcome of one check, with what to do about it when it isn't fine.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, detail: String) -> Self {
+        Finding { check, severity: Severity::Ok, detail, hint: None }
+    }
+
+    fn warn(check: &'static str, detail: String, hint: &str) -> Self {
+        Finding { check, severity: Severity::Warn, detail, hint: Some(hint.to_string()) }
+    }
+
+    fn fail(check: &'static str, detail: String, hint: &str) -> Self {
+        Finding { check, severity: Severity::Fail, detail, hint: Some(hint.to_string()) }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tag = match self.severity {
+            Severity::Ok => " ok ",
+            Severity::Warn => "warn",
+            Severity::Fail => "FAIL",
+        };
+        write!(f, "[{}] {:<11} {}", tag, self.check, self.detail)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       {:<11} -> {}", "", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// The built-in salts, then new ones from the salts.txt and remote list texts, with how
+/// many each of those added.
+fn gather_salts(local: Option<&str>, remote: Option<&str>) -> (Vec<String>, usize, usize) {
+    let mut salts: Vec<String> = HARDCODED_SALTS.iter().map(|s| s.to_string()).collect();
+    let mut add = |text: &str| {
+        let before = salts.len();
+        for (salt, _) in text.lines().filter_map(crate::parse_salt_line) {
+            if !salts.contains(&salt) {
+                salts.push(salt);
+            }
+        }
+        salts.len() - before
+    };
+    let local = local.map_or(0, &mut add);
+    let remote = remote.map_or(0, add);
+    (salts, local, remote)
+}
+
+/// Fetch the remote salts list, or why it couldn't be fetched.
+fn fetch_remote() -> Result<String, String> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(std::time::Duration::from_secs(5))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response = client.get(SALTS_URL).send().map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+    response.text().map_err(|e| e.to_string())
+}
+
+fn check_network(settings: &common::PackSettings) -> (Finding, Option<String>) {
+    if settings.untrusted {
+        return (Finding::ok("network", "skipped: offline mode (--untrusted)".to_string()), None);
+    }
+    match fetch_remote() {
+        Ok(text) => (Finding::ok("network", format!("fetched the salts list from {}", SALTS_URL)), Some(text)),
+        Err(e) => (
+            Finding::warn("network", format!("could not fetch {}: {}", SALTS_URL, e),
+                "Check the connection or proxy. Built-in salts and salts.txt still work; newer salts may be missing."),
+            None,
+        ),
+    }
+}
+
+fn check_salts(salts_file: &Path, remote: Option<&str>) -> (Vec<Finding>, Vec<String>) {
+    let mut findings = Vec::new();
+    let text = std::fs::read_to_string(salts_file);
+    let (salts, local, remote_added) = gather_salts(text.as_deref().ok(), remote);
+    match text {
+        Ok(text) if text.lines().filter_map(crate::parse_salt_line).next().is_none() => findings.push(Finding::warn("salts.txt",
+            format!("'{}' holds no salts", salts_file.display()), "Add one salt per line (optionally SALT<TAB>alias), or delete the file.")),
+        Ok(_) => findings.push(Finding::ok("salts.txt", format!("'{}' adds {} salt(s)", salts_file.display(), local))),
+        Err(e) if salts_file.exists() => findings.push(Finding::warn("salts.txt",
+            format!("'{}' can't be read: {}", salts_file.display(), e), "Fix its permissions or delete it.")),
+        Err(_) => findings.push(Finding::ok("salts.txt", format!("none at '{}' (optional, for salts not built in)", salts_file.display()))),
+    }
+    findings.push(Finding::ok("salts", format!("{} salt(s) available: {} built in, {} from salts.txt, {} from the remote list",
+        salts.len(), HARDCODED_SALTS.len(), local, remote_added)));
+    (findings, salts)
+}
+
+fn check_key_cache(path: &Path) -> Finding {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) if !path.exists() => return Finding::ok("key cache", format!("none yet at '{}' (written after the first successful search)", path.display())),
+        Err(e) => return Finding::warn("key cache", format!("'{}' can't be read: {}", path.display(), e), "Fix its permissions, delete it, or run with --no-key-cache."),
+    };
+    match serde_json::from_str::<KeyCache>(&text) {
+        Ok(cache) => {
+            let gone = cache.packs.keys().filter(|p| !Path::new(p).exists()).count();
+            Finding::ok("key cache", format!("'{}': {} salt record(s), {} pack record(s) ({} for packs no longer there)",
+                path.display(), cache.salts.len(), cache.packs.len(), gone))
+        }
+        Err(e) => Finding::warn("key cache", format!("'{}' is not valid ({}) and is ignored", path.display(), e),
+            "Delete it; it is rebuilt by the next successful search."),
+    }
+}
+
+/// Whether a file can be created in `dir` (the folder is created first if missing).
+fn probe_write(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".mabi-pack2-doctor-{}", std::process::id()));
+    std::fs::write(&probe, b"probe")?;
+    std::fs::remove_file(&probe)
+}
+
+fn check_writable(check: &'static str, dir: &Path, hint: &str) -> Finding {
+    match probe_write(dir) {
+        Ok(()) => Finding::ok(check, format!("'{}' is writable", dir.display())),
+        Err(e) => Finding::warn(check, format!("can't write to '{}': {}", dir.display(), e), hint),
+    }
+}
+
+/// Paths longer than this fail on Windows unless long paths are enabled.
+const MAX_SHORT_PATH: usize = 260;
+
+fn check_long_paths(base: &Path) -> Finding {
+    let root = base.join(format!("mabi_doctor_{}", std::process::id()));
+    let mut deep = root.clone();
+    while deep.as_os_str().len() <= MAX_SHORT_PATH + 20 {
+        deep.push("long-path-check-0123456789");
+    }
+    let result = std::fs::create_dir_all(&deep).and_then(|_| std::fs::write(deep.join("probe.txt"), b"probe"));
+    let _ = std::fs::remove_dir_all(&root);
+    match result {
+        Ok(()) => Finding::ok("long paths", format!("paths over {} characters work", MAX_SHORT_PATH)),
+        Err(e) => Finding::warn("long paths", format!("a {}-character path failed: {}", deep.as_os_str().len(), e),
+            "Enable LongPathsEnabled under HKLM\\SYSTEM\\CurrentControlSet\\Control\\FileSystem, or extract to a short folder such as C:\\out."),
+    }
+}
+
+/// Open `path` and look for a salt that decodes its header (and the entries table with
+/// the same salt), without a full key search.
+pub fn check_pack(path: &str, salts: &[String], settings: &common::PackSettings) -> Vec<Finding> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) => return vec![Finding::fail("pack", format!("can't open '{}': {}", path, e), "Check the path, and close the game if it holds the file.")],
+    };
+    let data = match unsafe { Mmap::map(&file) } {
+        Ok(data) => data,
+        Err(e) => return vec![Finding::fail("pack", format!("can't map '{}': {}", path, e), "Copy the pack to a local disk and try again.")],
+    };
+    let mut findings = vec![Finding::ok("pack", format!("'{}' is readable ({} bytes)", path, data.len()))];
+    if data.len() >= 4 && (&data[0..4] == b"PACK" || &data[0..4] == b"MABI") {
+        findings.push(Finding::ok("header", "legacy .pack archive; no salt needed".to_string()));
+        return findings;
+    }
+    let names = match common::key_name_variants(path, None, settings) {
+        Ok(names) => names,
+        Err(e) => {
+            findings.push(Finding::fail("header", format!("no key name from the file name: {}", e), "Give the pack its original .it file name."));
+            return findings;
+        }
+    };
+    // A header alone validates by chance now and then, so prefer a salt that also decodes
+    // the entries table with itself.
+    let hits: Vec<_> = names.iter().flat_map(|name| {
+        salts.par_iter().filter_map(|salt| {
+            let (header, offset, iv0, mode) = common::find_header_only(&mut Cursor::new(&data[..]), name, salt).ok().flatten()?;
+            let table = common::read_meta_iv_mode_two_key(name, salt, salt, &mut Cursor::new(&data[..]), offset, iv0, mode, settings).is_ok();
+            Some((name.clone(), salt.clone(), header, offset, iv0, mode, table))
+        }).collect::<Vec<_>>()
+    }).collect();
+    findings.push(match hits.iter().find(|h| h.6).or(hits.first()) {
+        Some((name, salt, header, offset, iv0, mode, table)) => {
+            let detail = format!("salt '{}' decodes it (name '{}', offset 0x{:X}, {} files, IV {}, mode {:?}){}",
+                salt, name, offset, header.file_cnt, iv0, mode, if hits.len() > 1 { format!("; {} salt/name pairs match", hits.len()) } else { String::new() });
+            if *table {
+                Finding::ok("header", detail)
+            } else {
+                Finding::warn("header", detail + ", but not the entries table",
+                    "The entries table may use another salt (run which-key), or the header match is a coincidence.")
+            }
+        }
+        None => Finding::fail("header", format!("none of the {} salt(s) decodes the header", salts.len()),
+            "Keys derive from the file name: use the name the game gives it (or --guess-name). Otherwise add the salt to salts.txt, or try --explain-search / --auto-escalate."),
+    });
+    findings
+}
+
+/// Run every environment check (and `check_pack` for `pack`, with `cli_key` tried first),
+/// print the findings to `out` and return them.
+pub fn run_doctor(pack: Option<&str>, cli_key: Option<String>, settings: &common::PackSettings, out: &mut dyn Write) -> Result<Vec<Finding>, anyhow::Error> {
+    let cwd = std::env::current_dir()?;
+    let temp = std::env::temp_dir();
+    let cache_path = key_cache::default_path();
+
+    let (network, remote) = check_network(settings);
+    let (salt_findings, mut salts) = check_salts(&cwd.join("salts.txt"), remote.as_deref());
+    let mut findings = vec![network];
+    findings.extend(salt_findings);
+    findings.push(check_key_cache(&cache_path));
+    findings.push(check_writable("work folder", &cwd, "extract writes next to the pack by default; pass -o with a folder you can write to."));
+    findings.push(check_writable("temp folder", &temp, "Set TMP/TEMP (TMPDIR elsewhere) to a writable folder; stdin input and roundtrip need it."));
+    if let Some(dir) = cache_path.parent() {
+        findings.push(check_writable("cache dir", dir, "Searches can't be remembered; run with --no-key-cache to silence this."));
+    }
+    findings.push(check_long_paths(&temp));
+    if let Some(pack) = pack {
+        if let Some(key) = cli_key {
+            salts.retain(|s| *s != key);
+            salts.insert(0, key);
+        }
+        findings.extend(check_pack(pack, &salts, settings));
+    }
+
+    for finding in &findings {
+        writeln!(out, "{}", finding)?;
+    }
+    Ok(findings)
+}
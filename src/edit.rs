@@ -0,0 +1,418 @@
+// edit.rs - Archive Modification Module
+
+use crate::common::{self, FileEntry, FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED, FLAG_HEAD_ENCRYPTED};
+use crate::extract::make_regex;
+use crate::info;
+use crate::names::{self, NameMap};
+use crate::pack::{self, PackWriter};
+use crate::pack_lock::PackLock;
+use crate::reader::PackReader;
+use crate::verify;
+use crate::vfs::{DiskFs, Vfs};
+use anyhow::{Context, Error};
+use log::{debug, info};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Parse a flag list such as `compressed,head-encrypted` or a numeric value (`6`, `0x4`).
+pub fn parse_flags(spec: &str) -> Result<u32, Error> {
+    let mut flags = 0u32;
+    for part in spec.split([',', '|', '+']).map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        flags |= match part.to_lowercase().as_str() {
+            "compressed" | "c" => FLAG_COMPRESSED,
+            "all-encrypted" | "all_encrypted" | "a" => FLAG_ALL_ENCRYPTED,
+            "head-encrypted" | "head_encrypted" | "h" => FLAG_HEAD_ENCRYPTED,
+            other => {
+                let parsed = match other.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => other.parse::<u32>(),
+                };
+                parsed.map_err(|_| Error::msg(format!("Unknown entry flag '{}'", part)))?
+            }
+        };
+    }
+    Ok(flags)
+}
+
+/// Rewrite `input` with the flags of every entry matching `filters_cli` changed to
+/// `(flags | set) & !clear`. Changed entries are decoded and re-encoded for their new
+/// flags (checksums recomputed); all other payloads are copied verbatim.
+/// Writes in place when `output` is `None`. Returns the number of entries changed.
+pub fn run_set_flags(
+    input: &str,
+    output: Option<&str>,
+    cli_key: Option<String>,
+    loaded_salts: &[String],
+    settings: &common::PackSettings,
+    filters_cli: Vec<String>,
+    set: u32,
+    clear: u32,
+) -> Result<usize, Error> {
+    let filters = make_regex(filters_cli, settings)?;
+    let _lock = PackLock::acquire(output.unwrap_or(input), "set-flags")?;
+    let reader = PackReader::open_with_settings(input, cli_key, loaded_salts, settings)?;
+
+    let new_flags: Vec<u32> = reader.entries.iter().map(|ent| {
+        if filters.is_empty() || filters.iter().any(|re| re.is_match(&ent.name)) {
+            (ent.flags | set) & !clear
+        } else {
+            ent.flags
+        }
+    }).collect();
+    let changed = reader.entries.iter().zip(&new_flags).filter(|(ent, f)| ent.flags != **f).count();
+    if changed == 0 {
+        info!("[SET_FLAGS] No entry flags changed; '{}' left untouched.", input);
+        return Ok(0);
+    }
+
+    let out_path = output.map(|o| o.to_string()).unwrap_or_else(|| format!("{}.tmp", input));
+    let key_name = match output {
+        Some(o) => common::get_final_file_name(o)?,
+        None => reader.name_variant.clone(),
+    };
+    let names: Vec<String> = reader.entries.iter().map(|e| e.name.clone()).collect();
+    let mut writer = PackWriter::create(&out_path, &key_name, &reader.header_salt, &reader.entries_salt, reader.iv0, reader.mode, &names)?;
+    writer.set_entry_checksum(settings.entry_checksum.clone());
+
+    for (ent, flags) in reader.entries.iter().zip(new_flags) {
+        if ent.flags == flags {
+            writer.add_raw(ent.clone(), reader.read_raw(ent)?)?;
+            continue;
+        }
+        debug!("[SET_FLAGS] '{}': 0x{:X} -> 0x{:X}", ent.name, ent.flags, flags);
+        let plain = reader.read_entry(ent).context(format!("decoding {} failed", ent.name))?;
+        let content = pack::encode_entry_data(&ent.name, &ent.key, &plain, flags, reader.iv0, reader.mode)?;
+        let mut new_ent = ent.clone();
+        new_ent.flags = flags;
+        new_ent.original_size = plain.len() as u32;
+        writer.add_raw(new_ent, &content)?;
+    }
+    writer.finish()?;
+
+    if output.is_none() {
+        drop(reader);
+        std::fs::rename(&out_path, input).context(format!("replacing {} failed", input))?;
+    }
+
+    info!("[SET_FLAGS] Updated flags on {} entries -> '{}'", changed, output.unwrap_or(input));
+    Ok(changed)
+}
+
+/// What `run_compact` did: the pack's size before and after, and its gaps before.
+#[derive(Debug, Clone, Default)]
+pub struct CompactSummary {
+    pub entries: usize,
+    pub before: u64,
+    pub after: u64,
+    /// Bytes in blocks no entry owned before compacting.
+    pub gap_bytes: u64,
+}
+
+impl CompactSummary {
+    pub fn reclaimed(&self) -> u64 {
+        self.before.saturating_sub(self.after)
+    }
+}
+
+/// Rewrite `input` with its payloads packed back to back, dropping the dead blocks that
+/// removed and replaced entries leave behind. Payloads are copied verbatim in table order
+/// (entries sharing data keep sharing it) and the metadata record is kept. Writes in place
+/// when `output` is `None`, and leaves a pack without gaps untouched.
+pub fn run_compact(input: &str, output: Option<&str>, cli_key: Option<String>, loaded_salts: &[String], settings: &common::PackSettings) -> Result<CompactSummary, Error> {
+    let _lock = PackLock::acquire(output.unwrap_or(input), "compact")?;
+    let reader = PackReader::open_with_settings(input, cli_key, loaded_salts, settings)?;
+    let layout = info::pack_layout(&reader.entries, reader.content_end() - reader.content_offset);
+    let before = std::fs::metadata(input)?.len();
+    let mut summary = CompactSummary { entries: reader.entries.len(), before, after: before, gap_bytes: layout.gap_bytes };
+    if layout.gap_bytes == 0 && output.is_none() {
+        info!("[COMPACT] '{}' has no gaps; left untouched.", input);
+        return Ok(summary);
+    }
+
+    let out_path = output.map(|o| o.to_string()).unwrap_or_else(|| format!("{}.tmp", input));
+    let key_name = match output {
+        Some(o) => common::get_final_file_name(o)?,
+        None => reader.name_variant.clone(),
+    };
+    let names: Vec<String> = reader.entries.iter().map(|e| e.name.clone()).collect();
+    let mut writer = PackWriter::create(&out_path, &key_name, &reader.header_salt, &reader.entries_salt, reader.iv0, reader.mode, &names)?;
+    writer.set_entry_checksum(settings.entry_checksum.clone());
+    let mut written: HashMap<(u32, u32), usize> = HashMap::new();
+    for (i, ent) in reader.entries.iter().enumerate() {
+        match written.get(&(ent.offset, ent.raw_size)) {
+            Some(&owner) if ent.raw_size > 0 => writer.add_shared(ent.clone(), owner)?,
+            _ => {
+                writer.add_raw(ent.clone(), reader.read_raw(ent)?)?;
+                written.insert((ent.offset, ent.raw_size), i);
+            }
+        }
+    }
+    if let Some(meta) = reader.metadata() {
+        writer.set_metadata(&meta)?;
+    }
+    writer.finish()?;
+
+    if output.is_none() {
+        drop(reader);
+        std::fs::rename(&out_path, input).context(format!("replacing {} failed", input))?;
+    }
+    summary.after = std::fs::metadata(output.unwrap_or(input))?.len();
+    info!("[COMPACT] {} entries, {} -> {} bytes -> '{}'", summary.entries, summary.before, summary.after, output.unwrap_or(input));
+    Ok(summary)
+}
+
+/// Copy the entries of `source` matching `filters_cli` into `dest`. A missing `dest` is
+/// created (salt `dest_key`, or the source's salts); an existing one is rewritten with
+/// same-named entries replaced. Payloads are copied verbatim when both packs share IV
+/// and cipher mode, otherwise re-encoded. With `remove_from_source` the entries are
+/// then dropped from a rewritten `source`, making this a move.
+/// Returns the number of entries transferred.
+pub fn run_transfer_entries(
+    source: &str,
+    dest: &str,
+    cli_key: Option<String>,
+    dest_key: Option<String>,
+    loaded_salts: &[String],
+    settings: &common::PackSettings,
+    filters_cli: Vec<String>,
+    remove_from_source: bool,
+) -> Result<usize, Error> {
+    if std::fs::canonicalize(source).ok() == std::fs::canonicalize(dest).ok() {
+        return Err(Error::msg(format!("Source and destination are the same pack '{}'", source)));
+    }
+    let filters = make_regex(filters_cli, settings)?;
+    let operation = if remove_from_source { "mv" } else { "cp" };
+    let _locks = (PackLock::acquire(dest, operation)?, remove_from_source.then(|| PackLock::acquire(source, operation)).transpose()?);
+    let src = PackReader::open_with_settings(source, cli_key, loaded_salts, settings)?;
+    let picked: Vec<&FileEntry> = src.select(&filters);
+    if picked.is_empty() {
+        return Err(Error::msg(format!("No entries of '{}' match the filters", source)));
+    }
+    let src_meta = src.metadata();
+
+    // Destination: existing entries (minus the replaced ones) followed by the transferred ones.
+    let existing = if Path::new(dest).exists() {
+        Some(PackReader::open_with_settings(dest, dest_key.clone(), loaded_salts, settings).context(format!("opening destination {} failed", dest))?)
+    } else {
+        None
+    };
+    let incoming: HashSet<&str> = picked.iter().map(|e| e.name.as_str()).collect();
+    let kept: Vec<&FileEntry> = existing.as_ref()
+        .map(|d| d.entries.iter().filter(|e| !incoming.contains(e.name.as_str())).collect())
+        .unwrap_or_default();
+    let names: Vec<String> = kept.iter().chain(&picked).map(|e| e.name.clone()).collect();
+
+    let (header_salt, entries_salt, iv0, mode) = match &existing {
+        Some(d) => (d.header_salt.clone(), d.entries_salt.clone(), d.iv0, d.mode),
+        None => {
+            let salt = dest_key.clone();
+            (salt.clone().unwrap_or_else(|| src.header_salt.clone()), salt.unwrap_or_else(|| src.entries_salt.clone()), src.iv0, src.mode)
+        }
+    };
+    let dest_tmp = format!("{}.tmp", dest);
+    let mut writer = PackWriter::create(&dest_tmp, &common::get_final_file_name(dest)?, &header_salt, &entries_salt, iv0, mode, &names)?;
+    writer.set_entry_checksum(settings.entry_checksum.clone());
+    let mut dest_meta = match &existing {
+        Some(d) => d.metadata(),
+        None => src_meta.clone().map(|mut m| { m.content_hashes.clear(); m }),
+    };
+    if let Some(d) = &existing {
+        for ent in &kept {
+            writer.add_raw((*ent).clone(), d.read_raw(ent)?)?;
+        }
+    }
+    for ent in &picked {
+        if (src.iv0, src.mode) == (iv0, mode) {
+            writer.add_raw((*ent).clone(), src.read_raw(ent)?)?;
+        } else {
+            let plain = src.read_entry(ent).context(format!("decoding {} failed", ent.name))?;
+            writer.add_raw((*ent).clone(), &pack::encode_entry_data(&ent.name, &ent.key, &plain, ent.flags, iv0, mode)?)?;
+        }
+        let digest = src_meta.as_ref().and_then(|m| m.content_hashes.get(&verify::normalize_name(&ent.name)));
+        if let Some(meta) = dest_meta.as_mut() {
+            match digest {
+                Some(d) => { meta.content_hashes.insert(verify::normalize_name(&ent.name), d.clone()); }
+                None => { meta.content_hashes.remove(&verify::normalize_name(&ent.name)); }
+            }
+        }
+    }
+    if let Some(meta) = &dest_meta {
+        writer.set_metadata(meta)?;
+    }
+    writer.finish()?;
+    let (transferred, kept_count) = (picked.len(), kept.len());
+    drop(existing);
+    std::fs::rename(&dest_tmp, dest).context(format!("replacing {} failed", dest))?;
+    info!("[TRANSFER] {} entries from '{}' -> '{}' ({} kept from destination)", transferred, source, dest, kept_count);
+
+    if remove_from_source {
+        let remaining: Vec<&FileEntry> = src.entries.iter().filter(|e| !incoming.contains(e.name.as_str())).collect();
+        let remaining_names: Vec<String> = remaining.iter().map(|e| e.name.clone()).collect();
+        let src_tmp = format!("{}.tmp", source);
+        let mut writer = PackWriter::create(&src_tmp, &src.name_variant, &src.header_salt, &src.entries_salt, src.iv0, src.mode, &remaining_names)?;
+        writer.set_entry_checksum(settings.entry_checksum.clone());
+        for ent in &remaining {
+            writer.add_raw((*ent).clone(), src.read_raw(ent)?)?;
+        }
+        if let Some(mut meta) = src_meta {
+            let moved: HashSet<String> = incoming.iter().map(|n| verify::normalize_name(n)).collect();
+            meta.content_hashes.retain(|name, _| !moved.contains(name));
+            writer.set_metadata(&meta)?;
+        }
+        writer.finish()?;
+        drop(src);
+        std::fs::rename(&src_tmp, source).context(format!("replacing {} failed", source))?;
+        info!("[TRANSFER] Removed {} entries from '{}' ({} left)", transferred, source, remaining_names.len());
+    }
+    Ok(transferred)
+}
+
+/// Settings for `run_update`.
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOptions {
+    /// Extensions compressed (for added files) in addition to the built-in list and
+    /// the ones recorded in the pack's metadata.
+    pub compress_ext: Vec<String>,
+    /// Folder the input files live under in the pack (default: the recorded `path_prefix`).
+    pub path_prefix: Option<String>,
+    /// Drop entries that have no file in the input folder (folder markers are kept).
+    pub prune: bool,
+    /// Only compare; leave the pack as it is.
+    pub dry_run: bool,
+    /// How the pack is opened (see `common::PackSettings`).
+    pub settings: common::PackSettings,
+}
+
+/// What `run_update` found, and wrote unless it was a dry run. Entry names as in the pack.
+#[derive(Debug, Clone, Default)]
+pub struct UpdateSummary {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: usize,
+}
+
+impl UpdateSummary {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Bring `pack_path` in line with `input_folder` without repacking everything. A file
+/// counts as changed when its size differs from its entry's, or its content differs
+/// from the MD5 recorded in the pack's metadata (or, without one, from the decoded
+/// entry). Changed files are re-encoded with their entry's flags and key, new files are
+/// appended the way `pack` stores them, and all other payloads are copied verbatim.
+/// The pack keeps its salts, IV and cipher mode, and is not rewritten when nothing differs.
+pub fn run_update(
+    input_folder: &str,
+    pack_path: &str,
+    cli_key: Option<String>,
+    loaded_salts: &[String],
+    opts: &UpdateOptions,
+) -> Result<UpdateSummary, Error> {
+    if !Path::new(input_folder).is_dir() {
+        return Err(Error::msg(format!("'{}' is not a folder", input_folder)));
+    }
+    let _lock = (!opts.dry_run).then(|| PackLock::acquire(pack_path, "update")).transpose()?;
+    let reader = PackReader::open_with_settings(pack_path, cli_key, loaded_salts, &opts.settings)?;
+    let meta = reader.metadata();
+    let hashes = meta.as_ref().map(|m| m.content_hashes.clone()).unwrap_or_default();
+    let path_prefix = opts.path_prefix.clone().or_else(|| meta.as_ref().and_then(|m| m.path_prefix.clone()));
+    let compress_ext: Vec<String> = opts.compress_ext.iter().chain(meta.iter().flat_map(|m| &m.compress_ext)).cloned().collect();
+    let compress_ext: Vec<&str> = compress_ext.iter().map(|s| s.as_str()).collect();
+
+    let source = DiskFs::new(input_folder);
+    let mut source_names = source.list()?;
+    source_names.retain(|n| n != names::NAME_MAP_FILE);
+    let file_names: Vec<(String, String)> = pack::entry_names(source_names, path_prefix.as_deref(), &NameMap::load(input_folder)?)
+        .into_iter()
+        .map(|(n, archive)| (n, archive.replace('/', "\\")))
+        .collect();
+
+    // Compare every file with the entry of the same name (ignoring case and slash style).
+    let by_name: HashMap<String, &FileEntry> = reader.entries.iter().map(|e| (verify::normalize_name(&e.name).to_lowercase(), e)).collect();
+    let mut summary = UpdateSummary::default();
+    let mut replaced: HashMap<String, String> = HashMap::new();
+    let mut added: Vec<(String, String)> = Vec::new();
+    for (disk_name, archive_name) in &file_names {
+        let key = verify::normalize_name(archive_name).to_lowercase();
+        let Some(ent) = by_name.get(&key) else {
+            added.push((disk_name.clone(), archive_name.clone()));
+            continue;
+        };
+        let size = std::fs::metadata(Path::new(input_folder).join(disk_name))?.len();
+        let same = size == ent.original_size as u64 && {
+            let data = source.read(disk_name)?;
+            match hashes.get(&verify::normalize_name(&ent.name)) {
+                Some(digest) => verify::md5_hex(&data).eq_ignore_ascii_case(digest),
+                None => reader.read_entry(ent).is_ok_and(|plain| plain == data),
+            }
+        };
+        if same {
+            summary.unchanged += 1;
+        } else {
+            debug!("[UPDATE] Changed: {}", ent.name);
+            summary.changed.push(ent.name.clone());
+            replaced.insert(key, disk_name.clone());
+        }
+    }
+    let in_folder: HashSet<String> = file_names.iter().map(|(_, a)| verify::normalize_name(a).to_lowercase()).collect();
+    let removed: HashSet<&str> = reader.entries.iter()
+        .filter(|e| opts.prune && !e.is_dir_marker() && !in_folder.contains(&verify::normalize_name(&e.name).to_lowercase()))
+        .map(|e| e.name.as_str())
+        .collect();
+    summary.removed = reader.entries.iter().filter(|e| removed.contains(e.name.as_str())).map(|e| e.name.clone()).collect();
+    summary.added = added.iter().map(|(_, a)| a.clone()).collect();
+    info!("[UPDATE] '{}': {} changed, {} added, {} removed, {} unchanged",
+        pack_path, summary.changed.len(), summary.added.len(), summary.removed.len(), summary.unchanged);
+    if summary.is_empty() || opts.dry_run {
+        return Ok(summary);
+    }
+
+    let kept: Vec<&FileEntry> = reader.entries.iter().filter(|e| !removed.contains(e.name.as_str())).collect();
+    let names: Vec<String> = kept.iter().map(|e| e.name.clone()).chain(summary.added.iter().cloned()).collect();
+    let tmp_path = format!("{}.tmp", pack_path);
+    let mut writer = PackWriter::create(&tmp_path, &reader.name_variant, &reader.header_salt, &reader.entries_salt, reader.iv0, reader.mode, &names)?;
+    writer.set_entry_checksum(opts.settings.entry_checksum.clone());
+    let mut new_meta = meta.clone();
+    let record_hashes = new_meta.as_ref().is_some_and(|m| !m.content_hashes.is_empty());
+    let level = meta.as_ref().and_then(|m| m.level).unwrap_or(pack::DEFAULT_LEVEL);
+    let mut record = |name: &str, plain: &[u8]| {
+        if let Some(m) = new_meta.as_mut().filter(|m| !m.content_hashes.is_empty()) {
+            m.content_hashes.insert(verify::normalize_name(name), verify::md5_hex(plain));
+        }
+    };
+    for ent in &kept {
+        let Some(disk_name) = replaced.get(&verify::normalize_name(&ent.name).to_lowercase()) else {
+            writer.add_raw((*ent).clone(), reader.read_raw(ent)?)?;
+            continue;
+        };
+        let plain = source.read(disk_name)?;
+        let content = pack::encode_entry_data(&ent.name, &ent.key, &plain, ent.flags, reader.iv0, reader.mode)
+            .context(format!("packing {} failed", ent.name))?;
+        let mut new_ent = (*ent).clone();
+        new_ent.original_size = plain.len() as u32;
+        record(&ent.name, &plain);
+        writer.add_raw(new_ent, &content)?;
+    }
+    // New files are stored the way `pack` stores them, at the pack's recorded level.
+    for (disk_name, archive_name) in &added {
+        let (ent, content, digest) = source.read(disk_name)
+            .and_then(|plain| pack::pack_file(plain, disk_name, archive_name, pack::need_compress(disk_name, &compress_ext), level, false, false, "", "", reader.iv0, record_hashes))
+            .context(format!("packing {} failed", archive_name))?;
+        if let (Some(m), Some(digest)) = (new_meta.as_mut(), digest) {
+            m.content_hashes.insert(verify::normalize_name(&ent.name), digest);
+        }
+        writer.add_raw(ent, &content)?;
+    }
+    if let Some(m) = new_meta.as_mut() {
+        let dropped: HashSet<String> = removed.iter().map(|r| verify::normalize_name(r)).collect();
+        m.content_hashes.retain(|name, _| !dropped.contains(name));
+        writer.set_metadata(m)?;
+    }
+    writer.finish()?;
+    drop(reader);
+    std::fs::rename(&tmp_path, pack_path).context(format!("replacing {} failed", pack_path))?;
+    Ok(summary)
+}
@@ -1,7 +1,7 @@
 // encryption.rs - Snow2 C-Binding Implementation with Word-Based Cipher Logic
 
-use std::io::{self, Read, Write, Seek, SeekFrom};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, BufRead, Read, Write, Seek, SeekFrom};
+use byteorder::{LittleEndian, WriteBytesExt};
 
 #[link(name = "c_snow2", kind = "static")]
 extern "C" {
@@ -9,13 +9,34 @@ extern "C" {
     fn c_snow2_generate_keystream(state_table: *mut u32, stream: *mut u32);
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum Snow2Mode { Sub, Xor, ModernBE, ModernLE, LegacyBE, LegacyLE }
 
+impl std::str::FromStr for Snow2Mode {
+    type Err = anyhow::Error;
+    /// The names `info` and `--export-keys` print (`Sub`, `ModernBE`...), in any case.
+    fn from_str(s: &str) -> Result<Self, anyhow::Error> {
+        match s.to_lowercase().as_str() {
+            "sub" => Ok(Snow2Mode::Sub),
+            "xor" => Ok(Snow2Mode::Xor),
+            "modernbe" => Ok(Snow2Mode::ModernBE),
+            "modernle" => Ok(Snow2Mode::ModernLE),
+            "legacybe" => Ok(Snow2Mode::LegacyBE),
+            "legacyle" => Ok(Snow2Mode::LegacyLE),
+            other => Err(anyhow::Error::msg(format!("Unknown cipher mode '{}' (expected Sub, Xor, ModernBE, ModernLE, LegacyBE or LegacyLE)", other))),
+        }
+    }
+}
+
+/// Raw bytes pulled from the source per `BufRead::fill_buf` refill (a whole number of words).
+const DECODER_WINDOW: usize = 8192;
+
 pub struct Snow2Decoder<'a, R: Read> {
     state_table: [u32; 18],
     keystream: [u32; 16],
     cur_index: usize,
+    /// Keystream blocks generated since the key was loaded; `keystream` holds the last one.
+    keystream_blocks: u64,
     pub rd: &'a mut R,
     key_ref: Vec<u8>,
     iv0: u32,
@@ -24,6 +45,13 @@ pub struct Snow2Decoder<'a, R: Read> {
     left_buffer: [u8; 4],
     left_buffer_len: usize,
     stream_pos: u64,
+
+    /// Decrypted bytes read ahead by `fill_buf` and not consumed yet.
+    window: Vec<u8>,
+    window_pos: usize,
+    /// Source error hit after `fill_buf` had already read part of a window; returned
+    /// once that window is used up.
+    pending_error: Option<io::Error>,
 }
 
 impl<'a, R: Read> Snow2Decoder<'a, R> {
@@ -36,6 +64,7 @@ impl<'a, R: Read> Snow2Decoder<'a, R> {
             state_table: [0; 18],
             keystream: [0; 16],
             cur_index: 0,
+            keystream_blocks: 0,
             rd: reader,
             key_ref: key.to_vec(),
             iv0,
@@ -43,24 +72,62 @@ impl<'a, R: Read> Snow2Decoder<'a, R> {
             left_buffer: [0; 4],
             left_buffer_len: 0,
             stream_pos: 0,
+            window: Vec::new(),
+            window_pos: 0,
+            pending_error: None,
         };
+        r.load_key();
+        r
+    }
+
+    /// Restart the keystream from the beginning.
+    fn load_key(&mut self) {
         unsafe {
-            c_snow2_loadkey_iv(r.state_table.as_mut_ptr(), key.as_ptr(), iv0, mode as i32);
-            c_snow2_generate_keystream(r.state_table.as_mut_ptr(), r.keystream.as_mut_ptr());
+            c_snow2_loadkey_iv(self.state_table.as_mut_ptr(), self.key_ref.as_ptr(), self.iv0, self.mode as i32);
         }
-        r
+        self.keystream_blocks = 0;
+        self.cur_index = 0;
+        self.generate_key_stream();
     }
 
     fn generate_key_stream(&mut self) {
         unsafe {
             c_snow2_generate_keystream(self.state_table.as_mut_ptr(), self.keystream.as_mut_ptr());
         }
+        self.keystream_blocks += 1;
+    }
+
+    /// Index of the keystream word the next stored word is decrypted with.
+    fn keystream_word(&self) -> u64 {
+        (self.keystream_blocks - 1) * 16 + self.cur_index as u64
+    }
+
+    /// Move the keystream to word `word` without touching the source: forwards from
+    /// where it is, or from the start when `word` is behind it.
+    fn seek_keystream(&mut self, word: u64) {
+        if word < self.keystream_word() {
+            self.load_key();
+        }
+        let block = word / 16;
+        while self.keystream_blocks - 1 < block {
+            self.generate_key_stream();
+        }
+        self.cur_index = (word % 16) as usize;
     }
 
     pub fn current_stream_position(&self) -> u64 { self.stream_pos }
 
     pub fn skip_keystream(&mut self, n: u64) {
         let mut remaining = n;
+
+        // Discard read-ahead bytes, whose keystream was already used
+        let buffered = (self.window.len() - self.window_pos) as u64;
+        if buffered > 0 {
+            let take = std::cmp::min(remaining, buffered);
+            self.window_pos += take as usize;
+            remaining -= take;
+            self.stream_pos += take;
+        }
         
         // Discard from leftover buffer first
         if self.left_buffer_len > 0 {
@@ -110,6 +177,18 @@ impl<'a, R: Read> Read for Snow2Decoder<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if buf.is_empty() { return Ok(0); }
 
+        // Serve from the fill_buf window, like BufReader does
+        if self.window_pos < self.window.len() {
+            let avail = &self.window[self.window_pos..];
+            let n = std::cmp::min(buf.len(), avail.len());
+            buf[..n].copy_from_slice(&avail[..n]);
+            self.consume(n);
+            return Ok(n);
+        }
+        if let Some(e) = self.pending_error.take() {
+            return Err(e);
+        }
+
         let mut total_written = 0;
 
         // Serve from leftover buffer
@@ -123,52 +202,122 @@ impl<'a, R: Read> Read for Snow2Decoder<'a, R> {
             if total_written == buf.len() { return Ok(total_written); }
         }
 
-        let remaining = buf.len() - total_written;
-        let words_needed = (remaining + 3) / 4;
-        let bytes_to_read = words_needed * 4;
+        // Full words are read straight into `buf` and decrypted in place.
+        let out = &mut buf[total_written..];
+        let full = out.len() / 4 * 4;
+        if full > 0 {
+            if let Err(e) = self.rd.read_exact(&mut out[..full]) {
+                if total_written > 0 { return Ok(total_written); }
+                return Err(e);
+            }
+            for chunk in out[..full].chunks_exact_mut(4) {
+                let dec_word = self.decrypt_word(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+                chunk.copy_from_slice(&dec_word.to_le_bytes());
+            }
+            total_written += full;
+            self.stream_pos += full as u64;
+        }
 
-        let mut raw_buf = vec![0u8; bytes_to_read];
-        match self.rd.read_exact(&mut raw_buf) {
-            Ok(_) => {},
-            Err(e) => {
+        // A 1-3 byte tail still consumes a whole word; the rest is kept for the next read.
+        let tail = out.len() - full;
+        if tail > 0 {
+            let mut word = [0u8; 4];
+            if let Err(e) = self.rd.read_exact(&mut word) {
                 if total_written > 0 { return Ok(total_written); }
                 return Err(e);
             }
+            let dec = self.decrypt_word(u32::from_le_bytes(word)).to_le_bytes();
+            out[full..].copy_from_slice(&dec[..tail]);
+            self.left_buffer_len = 4 - tail;
+            self.left_buffer[..self.left_buffer_len].copy_from_slice(&dec[tail..]);
+            total_written += tail;
+            self.stream_pos += tail as u64;
         }
+        Ok(total_written)
+    }
+}
 
-        let mut decrypted = Vec::with_capacity(bytes_to_read);
-        let mut cur = io::Cursor::new(raw_buf);
+impl<'a, R: Read> BufRead for Snow2Decoder<'a, R> {
+    /// Unlike `read`, a refill accepts a stream that ends mid-word; the final partial
+    /// word is decrypted as a tail. Returns an empty slice at end of stream.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.window_pos >= self.window.len() {
+            if let Some(e) = self.pending_error.take() {
+                return Err(e);
+            }
+            self.fill_window()?;
+        }
+        Ok(&self.window[self.window_pos..])
+    }
 
-        for _ in 0..words_needed {
-            let enc_word = cur.read_u32::<LittleEndian>()?;
-            let ks = self.keystream[self.cur_index];
-            let dec_word = match self.mode {
-                Snow2Mode::Sub => enc_word.wrapping_sub(ks),
-                _ => enc_word ^ ks,
-            };
-            decrypted.write_u32::<LittleEndian>(dec_word)?;
+    fn consume(&mut self, amt: usize) {
+        let amt = std::cmp::min(amt, self.window.len() - self.window_pos);
+        self.window_pos += amt;
+        self.stream_pos += amt as u64;
+    }
+}
 
-            self.cur_index += 1;
-            if self.cur_index >= 16 {
-                self.generate_key_stream();
-                self.cur_index = 0;
+impl<'a, R: Read> Snow2Decoder<'a, R> {
+    /// Refill the (empty) window: leftover bytes of a split word first, then up to
+    /// `DECODER_WINDOW` raw bytes decrypted in place.
+    fn fill_window(&mut self) -> io::Result<()> {
+        let mut window = std::mem::take(&mut self.window);
+        window.clear();
+        self.window_pos = 0;
+        window.extend_from_slice(&self.left_buffer[..self.left_buffer_len]);
+        self.left_buffer_len = 0;
+
+        let start = window.len();
+        window.resize(start + DECODER_WINDOW, 0);
+        let mut filled = 0;
+        // Keep reading until the raw bytes end on a word boundary or the source runs dry.
+        while filled == 0 || filled % 4 != 0 {
+            match self.rd.read(&mut window[start + filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if filled == 0 && start == 0 => {
+                    window.clear();
+                    self.window = window;
+                    return Err(e);
+                }
+                // Hand out what was read first; the error follows on the next call.
+                Err(e) => {
+                    self.pending_error = Some(e);
+                    break;
+                }
             }
         }
+        window.truncate(start + filled);
 
-        let n = std::cmp::min(remaining, decrypted.len());
-        buf[total_written..total_written+n].copy_from_slice(&decrypted[..n]);
-        
-        if decrypted.len() > n {
-            self.left_buffer_len = decrypted.len() - n;
-            self.left_buffer[..self.left_buffer_len].copy_from_slice(&decrypted[n..]);
+        for chunk in window[start..].chunks_mut(4) {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            let dec = self.decrypt_word(u32::from_le_bytes(word)).to_le_bytes();
+            chunk.copy_from_slice(&dec[..chunk.len()]);
+        }
+        self.window = window;
+        Ok(())
+    }
+
+    /// Decrypt one stored word and advance the keystream.
+    #[inline]
+    fn decrypt_word(&mut self, enc_word: u32) -> u32 {
+        let ks = self.keystream[self.cur_index];
+        self.cur_index += 1;
+        if self.cur_index >= 16 {
+            self.generate_key_stream();
+            self.cur_index = 0;
+        }
+        match self.mode {
+            Snow2Mode::Sub => enc_word.wrapping_sub(ks),
+            _ => enc_word ^ ks,
         }
-        
-        total_written += n;
-        self.stream_pos += n as u64;
-        Ok(total_written)
     }
 }
 
+/// Encrypting writer. Call `finish` to write the last partial word; nothing is written
+/// when the encoder is dropped.
 pub struct Snow2Encoder<'a, W: Write> {
     state_table: [u32; 18],
     keystream: [u32; 16],
@@ -178,6 +327,10 @@ pub struct Snow2Encoder<'a, W: Write> {
 
     left_buffer: [u8; 4],
     left_buffer_len: usize,
+    written: u64,
+    finished: bool,
+    /// A write to `wr` failed; the keystream position is lost, so every later call fails.
+    failed: bool,
 }
 
 impl<'a, W: Write> Snow2Encoder<'a, W> {
@@ -194,6 +347,9 @@ impl<'a, W: Write> Snow2Encoder<'a, W> {
             mode,
             left_buffer: [0; 4],
             left_buffer_len: 0,
+            written: 0,
+            finished: false,
+            failed: false,
         };
         unsafe {
             c_snow2_loadkey_iv(r.state_table.as_mut_ptr(), key.as_ptr(), iv0, mode as i32);
@@ -208,28 +364,48 @@ impl<'a, W: Write> Snow2Encoder<'a, W> {
         }
     }
 
-    pub fn finish(&mut self) -> io::Result<()> {
+    /// Encrypt one plain word, write it out and advance the keystream.
+    fn put_word(&mut self, word: u32) -> io::Result<()> {
+        let ks = self.keystream[self.cur_index];
+        let out_word = match self.mode {
+            Snow2Mode::Sub => word.wrapping_add(ks),
+            _ => word ^ ks,
+        };
+        self.wr.write_u32::<LittleEndian>(out_word)?;
+        self.written += 4;
+        self.cur_index = (self.cur_index + 1) % 16;
+        if self.cur_index == 0 { self.generate_keystream(); }
+        Ok(())
+    }
+
+    /// Write out a buffered partial word (zero-padded to 4 bytes, as the client expects)
+    /// and flush. Returns the number of bytes written to the underlying writer, padding
+    /// included, so callers can check it against the space they reserved.
+    /// Further writes fail; calling `finish` again just returns the same count.
+    pub fn finish(&mut self) -> io::Result<u64> {
+        if self.failed {
+            return Err(io::Error::other("Snow2Encoder finished after a failed write"));
+        }
+        if self.finished {
+            return Ok(self.written);
+        }
         if self.left_buffer_len > 0 {
-            // Pad with zeros as per legacy logic
             let mut final_block = [0u8; 4];
             final_block[..self.left_buffer_len].copy_from_slice(&self.left_buffer[..self.left_buffer_len]);
-            let word = u32::from_le_bytes(final_block);
-            let ks = self.keystream[self.cur_index];
-            let out_word = match self.mode {
-                Snow2Mode::Sub => word.wrapping_add(ks),
-                _ => word ^ ks,
-            };
-            self.wr.write_u32::<LittleEndian>(out_word)?;
-            self.cur_index = (self.cur_index + 1) % 16;
-            if self.cur_index == 0 { self.generate_keystream(); }
+            if let Err(e) = self.put_word(u32::from_le_bytes(final_block)) {
+                self.failed = true;
+                return Err(e);
+            }
             self.left_buffer_len = 0;
         }
-        self.wr.flush()
+        self.wr.flush()?;
+        self.finished = true;
+        Ok(self.written)
     }
-}
 
-impl<'a, W: Write> Write for Snow2Encoder<'a, W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    /// `Write::write` without the error bookkeeping: returns how much of `buf` was taken
+    /// before an error, if one happened.
+    fn encode(&mut self, buf: &[u8]) -> (usize, io::Result<()>) {
         let mut processed = 0;
         let len = buf.len();
 
@@ -238,33 +414,21 @@ impl<'a, W: Write> Write for Snow2Encoder<'a, W> {
             let n = std::cmp::min(4 - self.left_buffer_len, len);
             self.left_buffer[self.left_buffer_len..self.left_buffer_len+n].copy_from_slice(&buf[..n]);
             self.left_buffer_len += n;
-            processed += n;
 
             if self.left_buffer_len == 4 {
-                let word = u32::from_le_bytes(self.left_buffer);
-                let ks = self.keystream[self.cur_index];
-                let out_word = match self.mode {
-                    Snow2Mode::Sub => word.wrapping_add(ks),
-                    _ => word ^ ks,
-                };
-                self.wr.write_u32::<LittleEndian>(out_word)?;
-                self.cur_index = (self.cur_index + 1) % 16;
-                if self.cur_index == 0 { self.generate_keystream(); }
+                if let Err(e) = self.put_word(u32::from_le_bytes(self.left_buffer)) {
+                    return (processed, Err(e));
+                }
                 self.left_buffer_len = 0;
             }
+            processed += n;
         }
 
         // Process full words
         while len - processed >= 4 {
-            let word = u32::from_le_bytes(buf[processed..processed+4].try_into().unwrap());
-            let ks = self.keystream[self.cur_index];
-            let out_word = match self.mode {
-                Snow2Mode::Sub => word.wrapping_add(ks),
-                _ => word ^ ks,
-            };
-            self.wr.write_u32::<LittleEndian>(out_word)?;
-            self.cur_index = (self.cur_index + 1) % 16;
-            if self.cur_index == 0 { self.generate_keystream(); }
+            if let Err(e) = self.put_word(u32::from_le_bytes(buf[processed..processed+4].try_into().unwrap())) {
+                return (processed, Err(e));
+            }
             processed += 4;
         }
 
@@ -275,17 +439,42 @@ impl<'a, W: Write> Write for Snow2Encoder<'a, W> {
             self.left_buffer_len = rem;
             processed += rem;
         }
+        (processed, Ok(()))
+    }
+}
 
-        Ok(processed)
+impl<'a, W: Write> Write for Snow2Encoder<'a, W> {
+    /// An error means none of `buf` was taken. If the underlying writer fails part-way,
+    /// the bytes taken so far are reported and the error comes from the next call.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::other("Snow2Encoder written to after a failed write"));
+        }
+        if self.finished {
+            return Err(io::Error::other("Snow2Encoder written to after finish()"));
+        }
+        let (processed, result) = self.encode(buf);
+        match result {
+            Ok(()) => Ok(processed),
+            Err(e) => {
+                self.failed = true;
+                if processed > 0 { Ok(processed) } else { Err(e) }
+            }
+        }
     }
 
     fn flush(&mut self) -> io::Result<()> { self.wr.flush() }
 }
 
 
+/// Dropping never writes (errors would be lost); debug builds catch encoders dropped
+/// with bytes still buffered.
 impl<'a, W: Write> Drop for Snow2Encoder<'a, W> {
     fn drop(&mut self) {
-        let _ = self.finish();
+        debug_assert!(
+            self.finished || self.failed || self.left_buffer_len == 0 || std::thread::panicking(),
+            "Snow2Encoder dropped with {} unwritten byte(s); call finish()", self.left_buffer_len
+        );
     }
 }
 
@@ -319,6 +508,28 @@ pub fn gen_entries_offset(name: &str) -> u32 {
     (r % 212 + 42) as u32
 }
 
+/// What the derivation functions above give for one pack name and pair of salts, for
+/// checking another implementation against this one (the `keys` command).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivedKeys {
+    pub header_offset: u32,
+    /// Entries table position relative to the header.
+    pub entries_offset: u32,
+    pub header_key: [u8; 16],
+    pub entries_key: [u8; 16],
+}
+
+impl DerivedKeys {
+    pub fn new(name: &str, header_salt: &str, entries_salt: &str) -> Self {
+        DerivedKeys {
+            header_offset: gen_header_offset(name),
+            entries_offset: gen_entries_offset(name),
+            header_key: gen_header_key(name, header_salt),
+            entries_key: gen_entries_key(name, entries_salt),
+        }
+    }
+}
+
 pub fn gen_file_key(file_name: &str, archive_key: &[u8; 16]) -> [u8; 16] {
     let input: Vec<u16> = file_name.encode_utf16().collect();
     let bytes: Vec<u8> = (0..128).map(|i| {
@@ -336,14 +547,24 @@ pub fn snow2_decrypt(key: &[u8], iv0: u32, data: &mut [u8]) {
 }
 
 pub fn snow2_decrypt_mode(key: &[u8], iv0: u32, mode: Snow2Mode, data: &mut [u8]) {
+    snow2_decrypt_mode_at(key, iv0, mode, 0, data);
+}
+
+/// Decrypt `data` that was stored `pos` bytes into the stream (a multiple of 4). The
+/// keystream is fast-forwarded a block at a time, so nothing before `pos` is decrypted.
+pub fn snow2_decrypt_mode_at(key: &[u8], iv0: u32, mode: Snow2Mode, pos: u64, data: &mut [u8]) {
+    debug_assert!(pos.is_multiple_of(4), "keystream position must be word aligned");
     let mut state = [0u32; 18];
     let mut ks = [0u32; 16];
     unsafe {
         c_snow2_loadkey_iv(state.as_mut_ptr(), key.as_ptr(), iv0, mode as i32);
+        for _ in 0..pos / 64 {
+            c_snow2_generate_keystream(state.as_mut_ptr(), ks.as_mut_ptr());
+        }
         c_snow2_generate_keystream(state.as_mut_ptr(), ks.as_mut_ptr());
     }
     
-    let mut word_idx = 0;
+    let mut word_idx = (pos % 64 / 4) as usize;
     let mut processed = 0;
     let len = data.len();
 
@@ -379,6 +600,34 @@ pub fn snow2_decrypt_mode(key: &[u8], iv0: u32, mode: Snow2Mode, data: &mut [u8]
     }
 }
 
+/// Write `len` bytes of the raw SNOW2 keystream for `key`, `iv0` and `mode`, starting
+/// `skip` bytes into it. Each keystream word is written little-endian, the way stored data
+/// is read: with `Snow2Mode::Sub` a plaintext word is the stored word minus the keystream
+/// word (wrapping), with every other mode the bytes are XORed.
+pub fn write_keystream<W: Write>(key: &[u8], iv0: u32, mode: Snow2Mode, skip: u64, len: u64, writer: &mut W) -> io::Result<()> {
+    const BLOCK: u64 = 64;
+    let mut state = [0u32; 18];
+    let mut ks = [0u32; 16];
+    unsafe { c_snow2_loadkey_iv(state.as_mut_ptr(), key.as_ptr(), iv0, mode as i32); }
+    for _ in 0..skip / BLOCK {
+        unsafe { c_snow2_generate_keystream(state.as_mut_ptr(), ks.as_mut_ptr()); }
+    }
+    let mut from = (skip % BLOCK) as usize;
+    let mut remaining = len;
+    let mut block = [0u8; BLOCK as usize];
+    while remaining > 0 {
+        unsafe { c_snow2_generate_keystream(state.as_mut_ptr(), ks.as_mut_ptr()); }
+        for (chunk, word) in block.chunks_exact_mut(4).zip(ks) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        let take = (BLOCK - from as u64).min(remaining) as usize;
+        writer.write_all(&block[from..from + take])?;
+        remaining -= take as u64;
+        from = 0;
+    }
+    Ok(())
+}
+
 pub fn snow2_encrypt(key: &[u8], iv0: u32, data: &mut [u8]) {
     snow2_encrypt_mode(key, iv0, Snow2Mode::Sub, data);
 }
@@ -430,31 +679,51 @@ pub fn snow2_encrypt_mode(key: &[u8], iv0: u32, mode: Snow2Mode, data: &mut [u8]
 impl<'a, T: Write + Seek> Seek for Snow2Encoder<'a, T> { 
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> { 
         if self.left_buffer_len > 0 {
-            return Err(io::Error::new(io::ErrorKind::Other, "Cannot seek while leftover buffer is not empty"));
+            return Err(io::Error::other("Cannot seek while leftover buffer is not empty"));
         }
         self.wr.seek(pos) 
     } 
 }
 
+/// Positions count from the start of the source, where the encrypted stream begins. The
+/// keystream is advanced (or restarted, going backwards) without reading the data
+/// skipped over, and the source is seeked straight to the target.
 impl<'a, R: Read + Seek> Seek for Snow2Decoder<'a, R> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        match pos {
-            SeekFrom::Start(offset) => {
-                self.rd.seek(SeekFrom::Start(0))?;
-                unsafe {
-                    c_snow2_loadkey_iv(self.state_table.as_mut_ptr(), self.key_ref.as_ptr(), self.iv0, self.mode as i32);
-                    c_snow2_generate_keystream(self.state_table.as_mut_ptr(), self.keystream.as_mut_ptr());
-                }
-                self.cur_index = 0;
-                self.left_buffer_len = 0;
-                self.stream_pos = 0;
-                if offset > 0 {
-                    let mut discard = vec![0u8; offset as usize];
-                    self.read_exact(&mut discard)?;
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.stream_pos.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.rd.seek(SeekFrom::End(0))?.checked_add_signed(delta),
+        };
+        let target = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position"))?;
+
+        self.window.clear();
+        self.window_pos = 0;
+        self.left_buffer_len = 0;
+        self.pending_error = None;
+        self.seek_keystream(target / 4);
+        self.rd.seek(SeekFrom::Start(target - target % 4))?;
+        self.stream_pos = target;
+
+        // Landing inside a word: decrypt it and keep the bytes after the target.
+        let skip = (target % 4) as usize;
+        if skip > 0 {
+            let mut word = [0u8; 4];
+            let mut got = 0;
+            while got < 4 {
+                match self.rd.read(&mut word[got..]) {
+                    Ok(0) => break,
+                    Ok(n) => got += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
                 }
-                Ok(self.stream_pos)
             }
-            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "Seek only supported from start")),
+            if got > 0 {
+                let dec = self.decrypt_word(u32::from_le_bytes(word)).to_le_bytes();
+                self.left_buffer_len = got.saturating_sub(skip);
+                self.left_buffer[..self.left_buffer_len].copy_from_slice(&dec[skip..skip + self.left_buffer_len]);
+            }
         }
+        Ok(target)
     }
 }
@@ -1,21 +1,83 @@
 // extract.rs - Robust Multi-Stage Archive Extraction
 
-use crate::common::{self, FileEntry, FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED, FLAG_HEAD_ENCRYPTED};
-use crate::encryption;
+use crate::common::{self, FileEntry, FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED, FLAG_HEAD_ENCRYPTED, FLAG_SYMLINK};
+use crate::{codec, common_ext, encryption, run_summary};
+use crate::backup::BackupManifest;
+use crate::dedupe::{ObjectStore, OutputDedupe};
+use crate::hook::EntryHook;
+use crate::index;
+use crate::names::{self, NameMap, SanitizeMode};
+use crate::key_cache;
+use crate::verify::{self, ContentHashes};
+use crate::vfs::{DiskFs, Vfs};
 use anyhow::Error;
-use miniz_oxide::inflate::decompress_to_vec_zlib;
 use rayon::prelude::*;
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
+use std::collections::{HashMap, HashSet};
 use std::fs::File as StdFile;
-use std::io::{BufReader as StdBufReader, Cursor, Read, Seek, SeekFrom};
+use std::io::{BufReader as StdBufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, WriteBytesExt};
 use log::{info, debug, warn, trace};
 use memmap2::Mmap;
 use flate2::read::ZlibDecoder;
 
 pub type ProgressFn = dyn Fn(usize, usize, &str) + Send + Sync;
 
+/// Cheap plausibility check of an entry's stored data without decoding all of it:
+/// the block must lie inside the archive and its first (decrypted) KB must look like
+/// zlib for compressed entries, or be non-zero for stored ones. Mirrors the decryption
+/// fallback of `extract_single_file_to_memory`. Returns the reason on failure.
+pub fn probe_entry_data(
+    mmap: &[u8],
+    content_data_start_offset: u64,
+    ent: &FileEntry,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+) -> Result<(), String> {
+    let start = content_data_start_offset + ent.offset as u64 * 1024;
+    let end = start + ent.raw_size as u64;
+    if end > mmap.len() as u64 {
+        return Err(format!("data block 0x{:X}..0x{:X} extends beyond archive end 0x{:X}", start, end, mmap.len()));
+    }
+    if ent.raw_size == 0 {
+        return if ent.original_size == 0 { Ok(()) } else { Err("stored data is empty".to_string()) };
+    }
+    if (ent.flags & FLAG_COMPRESSED) == 0 && ent.raw_size != ent.original_size {
+        return Err(format!("uncompressed entry stores {} bytes but expects {}", ent.raw_size, ent.original_size));
+    }
+
+    let original = &mmap[start as usize..std::cmp::min(end, start + 1024) as usize];
+    let fkey = encryption::gen_file_key(&ent.name, &ent.key);
+    let mut head = original.to_vec();
+    if (ent.flags & FLAG_ALL_ENCRYPTED) != 0 {
+        encryption::snow2_decrypt_mode(&fkey, iv0, mode, &mut head);
+    }
+    if (ent.flags & FLAG_HEAD_ENCRYPTED) != 0 {
+        encryption::snow2_decrypt_mode(&fkey, iv0, mode, &mut head);
+    }
+
+    if (ent.flags & FLAG_COMPRESSED) != 0 {
+        if common::looks_like_zlib(&head) {
+            return Ok(());
+        }
+        let mut fallback = original.to_vec();
+        if (ent.flags & FLAG_ALL_ENCRYPTED) == 0 {
+            encryption::snow2_decrypt_mode(&fkey, iv0, mode, &mut fallback);
+        }
+        if common::looks_like_zlib(&fallback) {
+            return Ok(());
+        }
+        return Err(format!("compressed data does not start with a zlib stream (first bytes {:02X?})", &head[..std::cmp::min(4, head.len())]));
+    }
+    if head.iter().all(|b| *b == 0) {
+        return Err("data is all zeros".to_string());
+    }
+    Ok(())
+}
+
 pub fn extract_single_file_to_memory(
-    mmap: &Mmap,
+    mmap: &[u8],
     content_data_start_offset: u64,
     ent: &FileEntry,
     iv0: u32,
@@ -49,9 +111,8 @@ pub fn extract_single_file_to_memory(
         if content.len() >= 2 {
             debug!("[EXTRACT_MEM] '{}' post-decrypt first bytes: {:02X} {:02X}", ent.name, content[0], content[1]);
         }
-        let mut decoder = ZlibDecoder::new(&content[..]);
-        let mut decompressed = Vec::with_capacity(ent.original_size as usize);
-        if decoder.read_to_end(&mut decompressed).is_err() {
+        let inflated = inflate_entry(&content, ent.original_size);
+        if inflated.is_err() {
             // Primary failed: try fallback with the opposite encryption state.
             // Mirrors extract_file's fallback: use original (pre-decryption) bytes if the
             // file was marked encrypted, or try decrypting if marked unencrypted.
@@ -62,18 +123,108 @@ pub fn extract_single_file_to_memory(
             if fallback.len() >= 2 {
                 debug!("[EXTRACT_MEM] '{}' fallback first bytes: {:02X} {:02X}", ent.name, fallback[0], fallback[1]);
             }
-            let mut dec2 = ZlibDecoder::new(&fallback[..]);
-            let mut d2 = Vec::with_capacity(ent.original_size as usize);
-            dec2.read_to_end(&mut d2).map_err(|_| Error::msg(format!("Zlib fail: {}", ent.name)))?;
-            Ok(d2)
+            inflate_entry(&fallback, ent.original_size).map_err(|e| Error::msg(format!("Zlib fail: {}: {}", ent.name, e)))
         } else {
-            Ok(decompressed)
+            inflated
         }
     } else {
         Ok(content)
     }
 }
 
+/// Decode bytes `offset..offset + len` of an entry (clamped to its size) without decoding
+/// the rest. Uncompressed entries are decrypted from a fast-forwarded keystream. Compressed
+/// ones are decrypted and inflated as a stream that stops at the end of the range; deflate
+/// can't be entered mid-stream, so the data before the range is inflated and discarded.
+pub fn extract_range_to_memory(
+    mmap: &[u8],
+    content_data_start_offset: u64,
+    ent: &FileEntry,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>, Error> {
+    let start_pos = content_data_start_offset + (ent.offset as u64 * 1024);
+    let end_pos = start_pos + ent.raw_size as u64;
+    if end_pos > mmap.len() as u64 {
+        return Err(Error::msg(format!("Raw size for '{}' extends beyond archive length.", ent.name)));
+    }
+    let stored = &mmap[start_pos as usize..end_pos as usize];
+    let compressed = (ent.flags & FLAG_COMPRESSED) != 0;
+    let size = if compressed { ent.original_size as u64 } else { stored.len() as u64 };
+    if offset > size {
+        return Err(Error::msg(format!("Range starts at {} but '{}' is {} bytes", offset, ent.name, size)));
+    }
+    let end = offset.saturating_add(len).min(size);
+    let fkey = encryption::gen_file_key(&ent.name, &ent.key);
+
+    debug!("[EXTRACT_RANGE] '{}' flags=0x{:02X} range={}..{} of {} iv0={} mode={:?}",
+        ent.name, ent.flags, offset, end, size, iv0, mode);
+
+    if !compressed {
+        let encrypted = if (ent.flags & FLAG_ALL_ENCRYPTED) != 0 {
+            size
+        } else if (ent.flags & FLAG_HEAD_ENCRYPTED) != 0 {
+            size.min(1024)
+        } else {
+            0
+        };
+        let mut out = stored[offset as usize..end as usize].to_vec();
+        if offset < encrypted.min(end) {
+            // Sub mode works on whole words, so decrypt from the word the range starts in.
+            let aligned = offset & !3;
+            let mut span = stored[aligned as usize..encrypted.min(end) as usize].to_vec();
+            encryption::snow2_decrypt_mode_at(&fkey, iv0, mode, aligned, &mut span);
+            let span = &span[(offset - aligned) as usize..];
+            out[..span.len()].copy_from_slice(span);
+        }
+        return Ok(out);
+    }
+
+    let inflate = |all_encrypted: bool, head_encrypted: bool| -> Result<Vec<u8>, Error> {
+        let mut raw = stored;
+        let input: Box<dyn std::io::BufRead + '_> = if all_encrypted {
+            Box::new(encryption::Snow2Decoder::new_iv_mode(&fkey, iv0, mode, &mut raw))
+        } else if head_encrypted {
+            let mut head = stored[..stored.len().min(1024)].to_vec();
+            encryption::snow2_decrypt_mode(&fkey, iv0, mode, &mut head);
+            Box::new(Cursor::new(head).chain(&stored[stored.len().min(1024)..]))
+        } else {
+            Box::new(stored)
+        };
+        let mut dec = flate2::bufread::ZlibDecoder::new(input);
+        let skipped = std::io::copy(&mut (&mut dec).take(offset), &mut std::io::sink())?;
+        let mut out = Vec::with_capacity((end - offset) as usize);
+        (&mut dec).take(end - offset).read_to_end(&mut out)?;
+        if skipped + (out.len() as u64) < end {
+            return Err(Error::msg(format!("inflated to {} bytes, expected {}", skipped + out.len() as u64, size)));
+        }
+        Ok(out)
+    };
+    // Same fallback as extract_single_file_to_memory: the opposite encryption state.
+    let all_encrypted = (ent.flags & FLAG_ALL_ENCRYPTED) != 0;
+    inflate(all_encrypted, (ent.flags & FLAG_HEAD_ENCRYPTED) != 0)
+        .or_else(|_| inflate(!all_encrypted, false))
+        .map_err(|e| Error::msg(format!("Zlib fail: {}: {}", ent.name, e)))
+}
+
+/// Inflate a zlib stream that must produce exactly `original_size` bytes. Decoding stops
+/// as soon as the output would grow past that size, so a corrupt entry never takes more
+/// memory than its recorded size; a short result fails as well.
+pub fn inflate_entry(data: &[u8], original_size: u32) -> Result<Vec<u8>, Error> {
+    let limit = original_size as u64;
+    let mut out = Vec::with_capacity(original_size as usize);
+    ZlibDecoder::new(data).take(limit + 1).read_to_end(&mut out)?;
+    if out.len() as u64 > limit {
+        return Err(Error::msg(format!("inflates past its recorded size of {} bytes", limit)));
+    }
+    if (out.len() as u64) < limit {
+        return Err(Error::msg(format!("inflated to {} bytes, expected {}", out.len(), limit)));
+    }
+    Ok(out)
+}
+
 fn extract_file<R: Read + Seek>(
     main_file_reader: &mut R,
     content_data_start_offset: u64,
@@ -81,14 +232,133 @@ fn extract_file<R: Read + Seek>(
     root_dir: &str,
     iv0: u32,
     mode: encryption::Snow2Mode,
+    out_name: &str,
     auto_convert_png: bool,
+    dedupe: Option<&OutputDedupe>,
+    objects: Option<&ObjectStore>,
+    sink: Option<&dyn Vfs>,
+    hook: Option<&dyn EntryHook>,
+    settings: &common::PackSettings,
 ) -> Result<(), Error> {
-    let entry_abs_offset = content_data_start_offset + (ent.offset as u64 * 1024);
-    main_file_reader.seek(SeekFrom::Start(entry_abs_offset))?;
+    let content = read_raw_entry(main_file_reader, content_data_start_offset, ent, settings)?;
+    write_entry(content, ent, root_dir, iv0, mode, out_name, auto_convert_png, dedupe, objects, sink, hook, settings)
+}
 
+/// The stored bytes of `ent`.
+fn read_raw_entry<R: Read + Seek>(reader: &mut R, content_data_start_offset: u64, ent: &FileEntry, settings: &common::PackSettings) -> Result<Vec<u8>, Error> {
+    let start = content_data_start_offset + (ent.offset as u64 * 1024);
+    common::wait_for_len(reader, start + ent.raw_size as u64, &ent.name, settings)?;
+    reader.seek(SeekFrom::Start(start))?;
     let mut content = vec![0u8; ent.raw_size as usize];
-    main_file_reader.read_exact(&mut content)?;
+    reader.read_exact(&mut content)?;
+    Ok(content)
+}
+
+/// Entries whose stored or decoded size exceeds this are streamed to disk by default
+/// (see `ExtractOptions::stream_threshold`).
+pub const STREAM_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Whether `ent` is written by `stream_entry` instead of being decoded in memory: it is
+/// large, and written as is to a file (no hook, PNG conversion, dedupe or other target).
+fn streams(ent: &FileEntry, opts: &ExtractOptions) -> bool {
+    let threshold = opts.stream_threshold.unwrap_or(STREAM_THRESHOLD);
+    (ent.raw_size as u64).max(ent.original_size as u64) > threshold
+        && ent.raw_size > 0
+        && opts.writes_files()
+        && opts.dedupe.is_none()
+        && opts.hook.is_none()
+        && !(opts.auto_convert_png && ent.name.to_lowercase().ends_with(".dds"))
+}
+
+/// Decrypt, inflate and write `ent` to `root_dir/out_name` a window at a time, so memory
+/// stays bounded however large the entry is. Same fallback as `write_entry`: when
+/// inflating fails, the file is written again assuming the opposite encryption state.
+fn stream_entry<R: Read + Seek>(
+    reader: &mut R,
+    content_data_start_offset: u64,
+    ent: &FileEntry,
+    root_dir: &str,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+    out_name: &str,
+    settings: &common::PackSettings,
+) -> Result<(), Error> {
+    let start = content_data_start_offset + (ent.offset as u64 * 1024);
+    common::wait_for_len(reader, start + ent.raw_size as u64, &ent.name, settings)?;
+    let full_path = common::output_path(root_dir, out_name, settings)?;
+    if let Some(parent) = full_path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    let fkey = encryption::gen_file_key(&ent.name, &ent.key);
+    let compressed = (ent.flags & FLAG_COMPRESSED) != 0;
+    let expected = if compressed { ent.original_size as u64 } else { ent.raw_size as u64 };
+    debug!("[EXTRACT_STREAM] '{}' flags=0x{:02X} raw={} orig={}", ent.name, ent.flags, ent.raw_size, ent.original_size);
+
+    let mut attempt = |all_encrypted: bool, head_encrypted: bool| -> Result<(), Error> {
+        reader.seek(SeekFrom::Start(start))?;
+        let mut stored = StdBufReader::new(reader.by_ref().take(ent.raw_size as u64));
+        let input: Box<dyn std::io::BufRead + '_> = if all_encrypted {
+            Box::new(encryption::Snow2Decoder::new_iv_mode(&fkey, iv0, mode, &mut stored))
+        } else if head_encrypted {
+            let mut head = vec![0u8; (ent.raw_size as usize).min(1024)];
+            stored.read_exact(&mut head)?;
+            encryption::snow2_decrypt_mode(&fkey, iv0, mode, &mut head);
+            Box::new(Cursor::new(head).chain(stored))
+        } else {
+            Box::new(stored)
+        };
+        // Copied through `fill_buf`, which (unlike `Snow2Decoder::read`) accepts data ending mid-word.
+        let mut decoded: Box<dyn std::io::BufRead + '_> = if compressed {
+            Box::new(StdBufReader::new(flate2::bufread::ZlibDecoder::new(input)))
+        } else {
+            input
+        };
+        let mut out = std::io::BufWriter::new(StdFile::create(&full_path)?);
+        let mut written = 0u64;
+        while written <= expected {
+            let chunk = decoded.fill_buf()?;
+            if chunk.is_empty() {
+                break;
+            }
+            let n = chunk.len().min((expected + 1 - written) as usize);
+            out.write_all(&chunk[..n])?;
+            decoded.consume(n);
+            written += n as u64;
+        }
+        out.flush()?;
+        if written > expected {
+            return Err(Error::msg(format!("decodes past its recorded size of {} bytes", expected)));
+        }
+        if written < expected {
+            return Err(Error::msg(format!("decoded to {} bytes, expected {}", written, expected)));
+        }
+        Ok(())
+    };
+    let all_encrypted = (ent.flags & FLAG_ALL_ENCRYPTED) != 0;
+    match attempt(all_encrypted, (ent.flags & FLAG_HEAD_ENCRYPTED) != 0) {
+        Ok(()) => Ok(()),
+        Err(e) if compressed => attempt(!all_encrypted, false)
+            .map_err(|_| Error::msg(format!("Decompression failed for {}: {}", ent.name, e))),
+        Err(e) => Err(e),
+    }
+}
 
+/// Decrypt and decompress the stored bytes of `ent` and write them to `root_dir/out_name`
+/// (or into `objects`, indexed as `out_name`, or to `out_name` in `sink`).
+fn write_entry(
+    mut content: Vec<u8>,
+    ent: &FileEntry,
+    root_dir: &str,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+    out_name: &str,
+    auto_convert_png: bool,
+    dedupe: Option<&OutputDedupe>,
+    objects: Option<&ObjectStore>,
+    sink: Option<&dyn Vfs>,
+    hook: Option<&dyn EntryHook>,
+    settings: &common::PackSettings,
+) -> Result<(), Error> {
     let original_content = content.clone();
     let fkey = encryption::gen_file_key(&ent.name, &ent.key);
 
@@ -105,16 +375,16 @@ fn extract_file<R: Read + Seek>(
     let mut final_content = if (ent.flags & FLAG_COMPRESSED) != 0 {
         if ent.raw_size == 0 { Vec::new() }
         else {
-            match decompress_to_vec_zlib(&content) {
+            match inflate_entry(&content, ent.original_size) {
                 Ok(v) => v,
                 Err(e) => {
                     let mut fallback_content = original_content.clone();
                     if (ent.flags & FLAG_ALL_ENCRYPTED) == 0 {
                         encryption::snow2_decrypt_mode(&fkey, iv0, mode, &mut fallback_content);
                     }
-                    match decompress_to_vec_zlib(&fallback_content) {
+                    match inflate_entry(&fallback_content, ent.original_size) {
                         Ok(dec) => dec,
-                        Err(_) => return Err(Error::msg(format!("Decompression failed for {}: {:?}", ent.name, e))),
+                        Err(_) => return Err(Error::msg(format!("Decompression failed for {}: {}", ent.name, e))),
                     }
                 }
             }
@@ -122,25 +392,1069 @@ fn extract_file<R: Read + Seek>(
     } else {
         content
     };
+    if let Some(hook) = hook {
+        final_content = hook.transform(&ent.name, final_content)?;
+    }
 
-    let mut final_name = ent.name.clone();
+    let mut final_name = out_name.to_string();
     if auto_convert_png && final_name.to_lowercase().ends_with(".dds") {
-        if let Ok(dds) = image_dds::ddsfile::Dds::read(&mut Cursor::new(&final_content)) {
-            if let Ok(img) = image_dds::image_from_dds(&dds, 0) {
-                let mut buf = std::io::Cursor::new(Vec::new());
-                if img.write_to(&mut buf, image::ImageFormat::Png).is_ok() {
-                    final_content = buf.into_inner();
-                    final_name = final_name.replace(".dds", ".png").replace(".DDS", ".png");
+        if let Ok(png) = codec::dds_to_png(&final_content) {
+            final_content = png;
+            final_name = final_name.replace(".dds", ".png").replace(".DDS", ".png");
+        }
+    }
+
+    if let Some(sink) = sink {
+        return sink.write(&final_name, &final_content);
+    }
+    if let Some(store) = objects {
+        return store.write(root_dir, &final_name, &final_content).map(|_| ());
+    }
+    match dedupe {
+        Some(d) => d.write(root_dir, &final_name, &final_content, settings).map(|_| ()),
+        None => DiskFs::new(root_dir).with_settings(settings).write(&final_name, &final_content),
+    }
+}
+
+/// `extract --manifest-only`: find the keys of `fname_str`, decode its entries table and
+/// write the layout of the entries matching `filters_cli` (minus `exclude_cli`) to `out`
+/// as pretty-printed JSON (see `reader::PackManifest`), without decoding any entry data.
+pub fn run_extract_manifest(
+    fname_str: &str,
+    cli_skey: Option<String>,
+    loaded_salts: &[String],
+    settings: &common::PackSettings,
+    filters_cli: Vec<String>,
+    exclude_cli: Vec<String>,
+    with_keys: bool,
+    out: &mut dyn Write,
+) -> Result<crate::reader::PackManifest, Error> {
+    let filters = make_regex(filters_cli, settings)?;
+    let exclude = make_regex(exclude_cli, settings)?;
+    let reader = crate::reader::PackReader::open_with_settings(fname_str, cli_skey, loaded_salts, settings)?;
+    let selected = reader.select(&filters).into_iter().filter(|e| !exclude.iter().any(|re| re.is_match(&e.name)));
+    let manifest = reader.manifest(selected, with_keys);
+    serde_json::to_writer_pretty(&mut *out, &manifest)?;
+    writeln!(out)?;
+    out.flush()?;
+    info!("[MANIFEST] Wrote the layout of {} of {} entries from '{}'", manifest.entries.len(), reader.entries().len(), fname_str);
+    Ok(manifest)
+}
+
+/// How entries are delimited when several are streamed into one writer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFraming {
+    /// Decoded bytes back to back, no delimiters.
+    None,
+    /// Per entry: u32 LE name length, UTF-8 name, u64 LE data length, data.
+    LengthPrefixed,
+    /// A ustar archive (GNU long-name records for names over 100 bytes).
+    Tar,
+}
+
+impl std::str::FromStr for StreamFraming {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_lowercase().as_str() {
+            "none" | "raw" => Ok(StreamFraming::None),
+            "length" | "len" => Ok(StreamFraming::LengthPrefixed),
+            "tar" => Ok(StreamFraming::Tar),
+            other => Err(Error::msg(format!("Unknown framing '{}' (expected none, length or tar)", other))),
+        }
+    }
+}
+
+fn tar_header(name: &[u8], size: u64, typeflag: u8) -> [u8; 512] {
+    let mut hdr = [0u8; 512];
+    hdr[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+    hdr[100..107].copy_from_slice(b"0000644");
+    hdr[108..115].copy_from_slice(b"0000000");
+    hdr[116..123].copy_from_slice(b"0000000");
+    hdr[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+    hdr[136..147].copy_from_slice(b"00000000000");
+    hdr[156] = typeflag;
+    hdr[257..263].copy_from_slice(b"ustar\0");
+    hdr[263..265].copy_from_slice(b"00");
+    // Checksum is computed with the checksum field itself filled with spaces.
+    hdr[148..156].copy_from_slice(b"        ");
+    let sum: u32 = hdr.iter().map(|b| *b as u32).sum();
+    hdr[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    hdr
+}
+
+fn write_tar_padding(out: &mut dyn Write, len: u64) -> Result<(), Error> {
+    let pad = (512 - (len % 512) as usize) % 512;
+    out.write_all(&vec![0u8; pad])?;
+    Ok(())
+}
+
+fn write_tar_entry(out: &mut dyn Write, name: &str, data: &[u8]) -> Result<(), Error> {
+    let name = name.replace('\\', "/");
+    let name_bytes = name.as_bytes();
+    if name_bytes.len() > 100 {
+        // GNU long-name record: the real name travels as the payload of a 'L' entry.
+        let mut long_name = name_bytes.to_vec();
+        long_name.push(0);
+        out.write_all(&tar_header(b"././@LongLink", long_name.len() as u64, b'L'))?;
+        out.write_all(&long_name)?;
+        write_tar_padding(out, long_name.len() as u64)?;
+    }
+    out.write_all(&tar_header(name_bytes, data.len() as u64, b'0'))?;
+    out.write_all(data)?;
+    write_tar_padding(out, data.len() as u64)
+}
+
+/// Decode every entry matching `filters_cli` and none of `exclude_cli`, and stream it into
+/// `out` with the given framing. Nothing is written to disk. Entries that fail to decode
+/// are skipped and counted in `failed`.
+pub fn run_extract_to_writer(
+    fname_str: &str,
+    cli_skey: Option<String>,
+    loaded_salts: &[String],
+    settings: &common::PackSettings,
+    filters_cli: Vec<String>,
+    exclude_cli: Vec<String>,
+    framing: StreamFraming,
+    out: &mut dyn Write,
+) -> Result<common::OperationSummary, Error> {
+    let filters = make_regex(filters_cli, settings)?;
+    let exclude = make_regex(exclude_cli, settings)?;
+    let reader = crate::reader::PackReader::open_with_settings(fname_str, cli_skey, loaded_salts, settings)?;
+
+    let mut summary = common::OperationSummary::default();
+    for ent in reader.select(&filters).into_iter().filter(|e| !exclude.iter().any(|re| re.is_match(&e.name))) {
+        if ent.is_dir_marker() || ent.flags & FLAG_SYMLINK != 0 {
+            continue;
+        }
+        let data = match reader.read_entry(ent) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("[EXTRACT_STDOUT] Failed to decode {}: {}", ent.name, e);
+                summary.warnings.push(format!("{}: {}", ent.name, e));
+                summary.failed += 1;
+                continue;
+            }
+        };
+        match framing {
+            StreamFraming::None => out.write_all(&data)?,
+            StreamFraming::LengthPrefixed => {
+                out.write_u32::<LittleEndian>(ent.name.len() as u32)?;
+                out.write_all(ent.name.as_bytes())?;
+                out.write_u64::<LittleEndian>(data.len() as u64)?;
+                out.write_all(&data)?;
+            }
+            StreamFraming::Tar => write_tar_entry(out, &ent.name, &data)?,
+        }
+        summary.entries += 1;
+        summary.bytes += data.len() as u64;
+    }
+    if framing == StreamFraming::Tar {
+        out.write_all(&[0u8; 1024])?;
+    }
+    out.flush()?;
+
+    info!("[EXTRACT_STDOUT] Streamed {} entries from '{}'", summary.entries, fname_str);
+    Ok(summary.with_salts(&reader.header_salt, &reader.entries_salt))
+}
+
+/// How `make_regex` compiles user filters. The regex engine matches in linear time, so
+/// the compiled size is what bounds the work a pathological pattern can cause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterSettings {
+    /// Match filters as literal substrings (`--fixed-strings`).
+    pub fixed_strings: bool,
+    /// Largest compiled program (and lazy DFA cache) per filter, in bytes (`--regex-size-limit`).
+    pub size_limit: usize,
+}
+
+impl FilterSettings {
+    pub const DEFAULT_SIZE_LIMIT: usize = 1 << 20;
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        FilterSettings { fixed_strings: false, size_limit: Self::DEFAULT_SIZE_LIMIT }
+    }
+}
+
+/// Filters starting with this are globs (see `glob_to_regex`) rather than regexes.
+pub const GLOB_FILTER_PREFIX: &str = "glob:";
+
+/// Compile user filters with `settings`. A `glob:` filter matches whole entry names with
+/// either separator and is never affected by `fixed_strings`.
+pub fn compile_filters(filters: &[String], settings: FilterSettings) -> Result<Vec<Regex>, Error> {
+    filters.iter().map(|s| {
+        let pattern = match s.strip_prefix(GLOB_FILTER_PREFIX) {
+            Some(glob) => glob_to_regex(glob)
+                .replace("[^/]", "\0")
+                .replace('/', r"[/\\]")
+                .replace('\0', r"[^/\\]"),
+            None if settings.fixed_strings => regex::escape(s),
+            None => s.clone(),
+        };
+        RegexBuilder::new(&pattern)
+            .size_limit(settings.size_limit)
+            .dfa_size_limit(settings.size_limit)
+            .build()
+            .map_err(|e| Error::msg(format!("Invalid filter '{}': {}", s, e)))
+    }).collect()
+}
+
+pub(crate) fn make_regex(filters: Vec<String>, settings: &common::PackSettings) -> Result<Vec<Regex>, Error> {
+    compile_filters(&filters, settings.filters)
+}
+
+/// Read a `--filter-file`: one filter per line, with blank lines and lines starting with
+/// `#` ignored. Surrounding whitespace is trimmed, so a pattern cannot start or end with a
+/// space.
+pub fn read_filter_file(path: &str) -> Result<Vec<String>, Error> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::msg(format!("Cannot read filter file '{}': {}", path, e)))?;
+    Ok(text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// What to do when entries differ only in letter case and would land on the same
+/// file of a case-insensitive filesystem (Windows and macOS by default).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaseCollisionPolicy {
+    /// Write the later entries as `name~2.ext`, `name~3.ext`, ...
+    #[default]
+    Suffix,
+    /// Refuse to extract anything.
+    Error,
+    /// Keep the first entry, drop the later ones.
+    Skip,
+    /// Let the later entry replace the earlier file (the old behaviour).
+    Overwrite,
+}
+
+impl std::str::FromStr for CaseCollisionPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_lowercase().as_str() {
+            "suffix" | "rename" => Ok(CaseCollisionPolicy::Suffix),
+            "error" | "fail" => Ok(CaseCollisionPolicy::Error),
+            "skip" => Ok(CaseCollisionPolicy::Skip),
+            "overwrite" => Ok(CaseCollisionPolicy::Overwrite),
+            other => Err(Error::msg(format!("Unknown case collision policy '{}' (expected suffix, error, skip or overwrite)", other))),
+        }
+    }
+}
+
+fn path_key(name: &str, fold_case: bool) -> String {
+    let key = name.replace('/', "\\");
+    if fold_case { key.to_lowercase() } else { key }
+}
+
+/// Output names for `names` on a case-insensitive target, in order. `None` means the
+/// entry is skipped. An entry name listed twice is not a collision; both copies get
+/// the same output name.
+pub fn plan_output_names(names: &[&str], policy: CaseCollisionPolicy) -> Result<Vec<Option<String>>, Error> {
+    plan_output_paths(names, names, policy, true)
+}
+
+/// Like `plan_output_names` for entries `sources` that will be written to `outputs`
+/// (e.g. after a path template). Outputs collide when they match exactly, or ignoring
+/// case when `fold_case` is set, and come from different entry names.
+pub fn plan_output_paths(outputs: &[&str], sources: &[&str], policy: CaseCollisionPolicy, fold_case: bool) -> Result<Vec<Option<String>>, Error> {
+    let mut first_by_key: HashMap<String, &str> = HashMap::new();
+    let mut taken: HashSet<String> = outputs.iter().map(|n| path_key(n, fold_case)).collect();
+    let mut planned: Vec<Option<String>> = Vec::with_capacity(outputs.len());
+    let mut by_source: HashMap<&str, usize> = HashMap::new();
+    for (&name, &source) in outputs.iter().zip(sources) {
+        // A name listed twice is the same entry again: keep whatever it got the first time.
+        if let Some(&prev) = by_source.get(source) {
+            planned.push(planned[prev].clone());
+            continue;
+        }
+        by_source.insert(source, planned.len());
+        let first = *first_by_key.entry(path_key(name, fold_case)).or_insert(source);
+        if first == source || policy == CaseCollisionPolicy::Overwrite {
+            planned.push(Some(name.to_string()));
+            continue;
+        }
+        match policy {
+            CaseCollisionPolicy::Error => {
+                return Err(Error::msg(format!("'{}' and '{}' would both be written to '{}'; choose another --on-case-collision policy", first, source, name)));
+            }
+            CaseCollisionPolicy::Skip => {
+                warn!("[EXTRACT] Skipping '{}': '{}' is already written to '{}'", source, first, name);
+                planned.push(None);
+            }
+            _ => {
+                let split = name.rfind('.').filter(|&dot| !name[dot..].contains(['\\', '/'])).unwrap_or(name.len());
+                let (stem, ext) = name.split_at(split);
+                let renamed = (2..).map(|n| format!("{}~{}{}", stem, n, ext))
+                    .find(|candidate| taken.insert(path_key(candidate, fold_case)))
+                    .unwrap();
+                warn!("[EXTRACT] '{}' collides with '{}' at '{}'; writing it as '{}'", source, first, name, renamed);
+                planned.push(Some(renamed));
+            }
+        }
+    }
+    Ok(planned)
+}
+
+/// How symbolic link entries (`FLAG_SYMLINK`) are extracted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Create a symbolic link; falls back to `Materialize` where that isn't allowed, when a
+    /// path template is in use and when the target would leave the output folder on disk.
+    Recreate,
+    /// Write a copy of the entry the link points to.
+    #[default]
+    Materialize,
+    Skip,
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_lowercase().as_str() {
+            "recreate" | "link" => Ok(SymlinkPolicy::Recreate),
+            "materialize" | "copy" => Ok(SymlinkPolicy::Materialize),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            other => Err(Error::msg(format!("Unknown symlink policy '{}' (expected materialize, recreate or skip)", other))),
+        }
+    }
+}
+
+/// The entry name a link named `link_name` with relative `target` points to
+/// (backslash-separated), or `None` if it is absolute or leaves the pack root.
+pub fn resolve_link_target(link_name: &str, target: &str) -> Option<String> {
+    if target.starts_with(['/', '\\']) || target.contains(':') {
+        return None;
+    }
+    let mut parts: Vec<&str> = link_name.split(['\\', '/']).filter(|p| !p.is_empty()).collect();
+    parts.pop();
+    for seg in target.split(['\\', '/']) {
+        match seg {
+            "" | "." => {}
+            ".." => { parts.pop()?; }
+            s => parts.push(s),
+        }
+    }
+    Some(parts.join("\\"))
+}
+
+/// Whether a link at `link` with relative `target` lands inside `root` on disk. Unlike
+/// `resolve_link_target` this follows what is already there: the link's folder and each
+/// existing step of the target are canonicalized, so a link extracted earlier can't carry
+/// the target out of the output folder.
+pub fn link_stays_inside(root: &Path, link: &Path, target: &str) -> bool {
+    let (Ok(root), Some(Ok(mut at))) = (root.canonicalize(), link.parent().map(Path::canonicalize)) else { return false };
+    for seg in target.split(['\\', '/']) {
+        match seg {
+            "" | "." => {}
+            ".." => { at.pop(); }
+            s => {
+                at.push(s);
+                if let Ok(real) = at.canonicalize() {
+                    at = real;
                 }
             }
         }
     }
+    at.starts_with(&root)
+}
 
-    common::write_file_to_disk(root_dir, &final_name, &final_content)
+fn read_symlink_target<R: Read + Seek>(reader: &mut R, content_offset: u64, ent: &FileEntry) -> Result<String, Error> {
+    reader.seek(SeekFrom::Start(content_offset + ent.offset as u64 * 1024))?;
+    let mut target = vec![0u8; ent.raw_size as usize];
+    reader.read_exact(&mut target)?;
+    String::from_utf8(target).map_err(|_| Error::msg(format!("Link target of {} is not UTF-8", ent.name)))
 }
 
-fn make_regex(filters: Vec<String>) -> Result<Vec<Regex>, Error> {
-    filters.into_iter().map(|s| Regex::new(&s).map_err(Error::new)).collect()
+#[cfg(unix)]
+fn create_symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target.replace('/', "\\"), link)
+}
+
+/// Extract one `FLAG_SYMLINK` entry to `out_name` according to `opts.symlinks`.
+fn extract_symlink<R: Read + Seek>(
+    reader: &mut R,
+    content_offset: u64,
+    ent: &FileEntry,
+    entries: &[FileEntry],
+    root_dir: &str,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+    out_name: &str,
+    opts: &ExtractOptions,
+) -> Result<(), Error> {
+    let target = read_symlink_target(reader, content_offset, ent)?;
+    let path = common::output_path(root_dir, out_name, &opts.settings)?;
+    // Links leaving the output folder could redirect later writes outside it.
+    if resolve_link_target(&ent.name, &target).is_none() {
+        return Err(Error::msg(format!("Link {} points outside the pack ({})", ent.name, target)));
+    }
+    // A path template moves the link, so its relative target would no longer match, and
+    // an object store or sink only holds file contents.
+    if opts.symlinks == SymlinkPolicy::Recreate && opts.path_template.is_none() && opts.writes_files() {
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        if !link_stays_inside(Path::new(root_dir), &path, &target) {
+            warn!("[EXTRACT] Link {} -> {} would leave '{}' on disk; writing a copy", ent.name, target, root_dir);
+        } else {
+            let _ = std::fs::remove_file(&path);
+            match create_symlink(&target, &path) {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("[EXTRACT] Cannot create link {} -> {}: {}; writing a copy", ent.name, target, e),
+            }
+        }
+    }
+
+    // Materialize: follow the chain of links inside the pack to a regular entry.
+    let (mut link, mut target) = (ent.name.clone(), target);
+    for _ in 0..8 {
+        let resolved = resolve_link_target(&link, &target)
+            .ok_or_else(|| Error::msg(format!("Link {} leads outside the pack ({})", ent.name, target)))?;
+        let key = resolved.to_lowercase();
+        let dest = entries.iter()
+            .find(|e| e.name.replace('/', "\\").to_lowercase() == key)
+            .ok_or_else(|| Error::msg(format!("Link {} points to {}, which is not in the pack", ent.name, resolved)))?;
+        if dest.flags & FLAG_SYMLINK == 0 {
+            return extract_file(reader, content_offset, dest, root_dir, iv0, mode, out_name, opts.auto_convert_png, opts.dedupe, opts.objects, opts.sink, opts.hook, &opts.settings);
+        }
+        target = read_symlink_target(reader, content_offset, dest)?;
+        link = dest.name.clone();
+    }
+    Err(Error::msg(format!("Too many levels of links resolving {}", ent.name)))
+}
+
+/// Translate a `--protect` glob into an (anchored, case-insensitive) regex over output
+/// paths with `/` separators. `*` and `?` stay within one folder, `**` spans folders and
+/// `[...]` is a character class (`[!...]` negated). A pattern without `/` matches the file
+/// name in any folder; a trailing `/` protects everything under that folder.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut glob = glob.replace('\\', "/");
+    if glob.ends_with('/') {
+        glob.push_str("**");
+    }
+    let mut re = String::from("(?i)^");
+    if !glob.contains('/') {
+        re.push_str("(?:.*/)?");
+    }
+    let mut chars = glob.trim_start_matches('/').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                let class: String = chars.clone().take_while(|&c| c != ']').collect();
+                if class.is_empty() || chars.clone().nth(class.chars().count()) != Some(']') {
+                    re.push_str("\\[");
+                    continue;
+                }
+                chars.nth(class.chars().count());
+                let (negate, body) = match class.strip_prefix('!') {
+                    Some(rest) => ("^", rest),
+                    None => ("", class.as_str()),
+                };
+                re.push('[');
+                re.push_str(negate);
+                re.push_str(&body.replace('\\', "\\\\").replace('[', "\\["));
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Compiled `--protect` patterns.
+pub struct ProtectList {
+    patterns: Vec<Regex>,
+}
+
+impl ProtectList {
+    pub fn new(globs: &[String]) -> Result<Self, Error> {
+        let patterns = globs.iter()
+            .map(|g| Regex::new(&glob_to_regex(g)).map_err(|e| Error::msg(format!("Invalid --protect pattern '{}': {}", g, e))))
+            .collect::<Result<_, _>>()?;
+        Ok(ProtectList { patterns })
+    }
+
+    /// Whether `out_name` (relative output path, either slash style) matches a pattern.
+    pub fn matches(&self, out_name: &str) -> bool {
+        let path = out_name.replace('\\', "/");
+        self.patterns.iter().any(|re| re.is_match(&path))
+    }
+}
+
+/// Fields available in `--path-template`.
+pub const PATH_TEMPLATE_FIELDS: &[&str] = &["path", "dir", "top", "name", "stem", "ext", "flat"];
+
+/// Check that `template` only uses known `{field}`s.
+pub fn validate_path_template(template: &str) -> Result<(), Error> {
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let close = rest[open..].find('}').ok_or_else(|| Error::msg(format!("Unclosed '{{' in path template '{}'", template)))?;
+        let field = &rest[open + 1..open + close];
+        if !PATH_TEMPLATE_FIELDS.contains(&field) {
+            return Err(Error::msg(format!("Unknown path template field '{{{}}}' (known: {})", field, PATH_TEMPLATE_FIELDS.join(", "))));
+        }
+        rest = &rest[open + close + 1..];
+    }
+    Ok(())
+}
+
+/// Output path for `entry_name` under `template`, e.g. `{ext}/{name}`:
+/// `path` full entry path, `dir` its folder, `top` first folder, `name` file name,
+/// `stem` file name without extension, `ext` extension without dot (`noext` if none),
+/// `flat` full path with folders joined by `_`. Empty, `.` and `..` segments are dropped.
+pub fn apply_path_template(template: &str, entry_name: &str) -> String {
+    let path = entry_name.replace('\\', "/");
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", &path));
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, "noext"),
+    };
+    let top = dir.split('/').next().unwrap_or("");
+    let expanded = template
+        .replace("{path}", &path)
+        .replace("{dir}", dir)
+        .replace("{top}", top)
+        .replace("{name}", name)
+        .replace("{stem}", stem)
+        .replace("{ext}", ext)
+        .replace("{flat}", &path.replace('/', "_"));
+    expanded.split(['/', '\\']).filter(|seg| !seg.is_empty() && *seg != "." && *seg != "..").collect::<Vec<_>>().join("/")
+}
+
+/// Optional behaviour for `run_extract_with_options` and `run_extract_with_hit`.
+#[derive(Default)]
+pub struct ExtractOptions<'a> {
+    /// Regexes; only matching entries are extracted (all when empty).
+    pub filters: Vec<String>,
+    /// Entries matching any of these (same syntax as `filters`) are skipped even when selected.
+    pub exclude: Vec<String>,
+    /// Extra archive name to derive keys from (see `run_extract_with_key_search`).
+    pub region_key: Option<String>,
+    pub auto_convert_png: bool,
+    pub progress_cb: Option<&'a ProgressFn>,
+    /// Records every probe of the salt search (see `common::format_search_report`).
+    pub search_trace: Option<&'a common::SearchTrace>,
+    /// Re-read each written file and compare it against these digests.
+    pub verify_hashes: Option<&'a ContentHashes>,
+    /// Link or skip files identical to ones already written (shared across a batch).
+    pub dedupe: Option<&'a OutputDedupe>,
+    /// Store contents by digest instead of at their paths (`--content-addressed`).
+    pub objects: Option<&'a ObjectStore>,
+    /// Write files into this tree (e.g. a `vfs::ZipFs`) instead of the output folder. The
+    /// caller calls `Vfs::finish` once every pack has been extracted into it.
+    pub sink: Option<&'a dyn Vfs>,
+    /// Rewrite names Windows can't create (recorded in `names::NAME_MAP_FILE`).
+    pub sanitize_names: SanitizeMode,
+    /// Applied only when the output folder is on a case-insensitive filesystem.
+    pub case_collisions: CaseCollisionPolicy,
+    /// Rewrites each entry's output path (see `apply_path_template`).
+    pub path_template: Option<String>,
+    /// Globs (see `glob_to_regex`) of existing files that must never be overwritten.
+    pub protect: Vec<String>,
+    /// Copy files about to be overwritten here first (same relative path).
+    pub backup_dir: Option<String>,
+    pub symlinks: SymlinkPolicy,
+    /// Retry output files another process has locked this many times, `LOCKED_RETRY_DELAY`
+    /// apart, once every other entry is written.
+    pub retry_locked: u32,
+    /// Stage files that stay locked as `name` + `STAGED_SUFFIX` and have Windows move them
+    /// into place at the next reboot.
+    pub schedule_on_reboot: bool,
+    /// Write entries in entries-table order instead of the order of their data in the pack.
+    pub table_order: bool,
+    /// Save the key search result and decoded entries here (see `index::save`).
+    pub save_index: Option<String>,
+    /// Take keys and entries from this index instead of searching, if it matches the pack.
+    pub use_index: Option<String>,
+    /// Renames entries and rewrites their decoded content before they are written.
+    pub hook: Option<&'a dyn EntryHook>,
+    /// Stream entries larger than this many bytes straight to disk instead of decoding them
+    /// in memory; `None` uses `STREAM_THRESHOLD`.
+    pub stream_threshold: Option<u64>,
+    /// How the pack is opened and read (see `common::PackSettings`).
+    pub settings: common::PackSettings,
+}
+
+impl ExtractOptions<'_> {
+    /// Whether entries become files at their paths in the output folder (no object store or sink).
+    fn writes_files(&self) -> bool {
+        self.objects.is_none() && self.sink.is_none()
+    }
+}
+
+/// Extract the entries matching `filters` once the archive's keys are known.
+/// Individual entry failures are logged, counted in the summary and skipped; digest
+/// mismatches fail the call after every entry has been written.
+fn extract_entries(
+    fname_str: &str,
+    output_folder_str: &str,
+    entries: &[FileEntry],
+    content_offset: u64,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+    filters: &[Regex],
+    opts: &ExtractOptions,
+) -> Result<common::OperationSummary, Error> {
+    common::check_entry_policy(entries, &opts.settings)?;
+    let total = entries.len();
+    let exclude = make_regex(opts.exclude.clone(), &opts.settings)?;
+    let mut selected: Vec<usize> = (0..total)
+        .filter(|&i| filters.is_empty() || filters.iter().any(|re| re.find(&entries[i].name).is_some()))
+        .filter(|&i| !exclude.iter().any(|re| re.is_match(&entries[i].name)))
+        .collect();
+    if let Some(template) = &opts.path_template {
+        validate_path_template(template)?;
+    }
+    if opts.sink.is_some() && (opts.objects.is_some() || opts.verify_hashes.is_some() || opts.backup_dir.is_some() || !opts.protect.is_empty()) {
+        return Err(Error::msg("Content-addressed output, content verification, backups and protected files need an output folder"));
+    }
+    if opts.schedule_on_reboot && (!cfg!(windows) || opts.auto_convert_png) {
+        return Err(Error::msg("Replacing locked files at reboot is only supported on Windows, without PNG conversion"));
+    }
+    let mut entry_warnings = common::entry_warnings(selected.iter().map(|&i| &entries[i]), &opts.settings);
+    if opts.settings.force {
+        let wanted: HashSet<usize> = selected.iter().copied().collect();
+        for failure in common::validate_all_entries(entries, &opts.settings).into_iter().filter(|f| wanted.contains(&f.index)) {
+            warn!("[EXTRACT] {}: {}; extracting it anyway (--force)", failure.name, failure);
+            entry_warnings.push(common::EntryWarning { entry: failure.name.clone(), kind: common::EntryWarningKind::BadChecksum, detail: failure.to_string() });
+        }
+    }
+    let hooked: Vec<String> = selected.iter()
+        .map(|&i| match opts.hook {
+            Some(hook) => Ok(hook.rename(&entries[i].name)?.unwrap_or_else(|| entries[i].name.clone())),
+            None => Ok(entries[i].name.clone()),
+        })
+        .collect::<Result<_, Error>>()?;
+    let templated: Vec<String> = hooked.iter()
+        .map(|name| match &opts.path_template {
+            Some(template) => apply_path_template(template, name),
+            None => name.clone(),
+        })
+        .map(|name| names::sanitize_name(&name, opts.sanitize_names))
+        .collect();
+    let names: Vec<&str> = templated.iter().map(|n| n.as_str()).collect();
+    if opts.sink.is_none() {
+        let _ = std::fs::create_dir_all(output_folder_str);
+    }
+    let fold_case = opts.case_collisions != CaseCollisionPolicy::Overwrite
+        && opts.writes_files()
+        && common::is_case_insensitive_dir(Path::new(output_folder_str));
+    let renamed = opts.path_template.is_some() || opts.sanitize_names != SanitizeMode::None || opts.hook.is_some();
+    let mut out_names = if fold_case || renamed {
+        let sources: Vec<&str> = selected.iter().map(|&i| entries[i].name.as_str()).collect();
+        plan_output_paths(&names, &sources, opts.case_collisions, fold_case)?
+    } else {
+        names.iter().map(|n| Some(n.to_string())).collect()
+    };
+
+    // Entries that would be written outside the output folder (`..`, absolute names, or
+    // through a link) are left out.
+    let mut rejected = Vec::new();
+    if opts.objects.is_none() {
+        for out in out_names.iter_mut() {
+            let Some(name) = out else { continue };
+            if let Some(reason) = common::unsafe_path_reason(output_folder_str, name, &opts.settings) {
+                warn!("[EXTRACT] Rejected {}: {}", name, reason);
+                rejected.push(out.take().unwrap());
+            }
+        }
+    }
+
+    // Files already in the output folder that match --protect are left as they are.
+    let mut protected = Vec::new();
+    if !opts.protect.is_empty() {
+        let protect = ProtectList::new(&opts.protect)?;
+        for out in out_names.iter_mut() {
+            let Some(name) = out else { continue };
+            let existing = Path::new(output_folder_str).join(name.replace(['/', '\\'], std::path::MAIN_SEPARATOR_STR));
+            if protect.matches(name) && std::fs::symlink_metadata(&existing).is_ok() {
+                debug!("[PROTECT] Leaving {} untouched", existing.display());
+                protected.push(out.take().unwrap());
+            }
+        }
+        if !protected.is_empty() {
+            info!("[PROTECT] Skipped {} protected file(s) already in '{}'", protected.len(), output_folder_str);
+        }
+    }
+
+    if !opts.table_order {
+        order_by_data_offset(&mut selected, &mut out_names, entries);
+    }
+
+    // Read-ahead: a second thread reads the stored bytes of the next regular entries
+    // while this one decrypts, decompresses and writes the current one. Entries that are
+    // streamed are read by this thread instead, so the read-ahead sends `None` for them.
+    let plain_files: Vec<usize> = selected.iter().zip(&out_names)
+        .filter(|(&i, out)| out.is_some() && !entries[i].is_dir_marker() && entries[i].flags & FLAG_SYMLINK == 0)
+        .map(|(&i, _)| i)
+        .collect();
+    let mut rd_for_content = StdBufReader::new(StdFile::open(fname_str)?);
+    let mut prefetch_rd = StdBufReader::new(StdFile::open(fname_str)?);
+    std::thread::scope(|scope| {
+        let (tx, prefetched) = std::sync::mpsc::sync_channel::<Result<Option<Vec<u8>>, Error>>(READ_AHEAD_ENTRIES);
+        scope.spawn(move || {
+            for &i in &plain_files {
+                let raw = match streams(&entries[i], opts) {
+                    true => Ok(None),
+                    false => common::catch_panic(&entries[i].name, || read_raw_entry(&mut prefetch_rd, content_offset, &entries[i], &opts.settings)).map(Some),
+                };
+                if tx.send(raw).is_err() {
+                    break;
+                }
+            }
+        });
+        write_selected_entries(&mut rd_for_content, &prefetched, entries, &selected, &out_names, content_offset, iv0, mode, output_folder_str, opts)
+    })
+    .and_then(|summary| {
+        // Record sanitized names so `pack` can restore the originals.
+        if opts.sanitize_names != SanitizeMode::None && opts.writes_files() {
+            let mut map = NameMap::default();
+            for (&i, out) in selected.iter().zip(&out_names) {
+                if let Some(out) = out {
+                    map.record(out, &entries[i].name);
+                }
+            }
+            map.merge_into(output_folder_str)?;
+        }
+        Ok(common::OperationSummary { protected, rejected, entry_warnings, ..summary })
+    })
+}
+
+/// Reorder `selected` (and its output names) by data offset, so extracting a whole pack
+/// reads it front to back instead of seeking around it. Table order is kept when two
+/// entries write the same path, because the later one in the table has to win.
+fn order_by_data_offset(selected: &mut Vec<usize>, out_names: &mut Vec<Option<String>>, entries: &[FileEntry]) {
+    let mut seen = HashSet::new();
+    if !out_names.iter().flatten().all(|name| seen.insert(name.to_lowercase())) {
+        debug!("[EXTRACT] Output paths repeat; writing in table order");
+        return;
+    }
+    let mut pairs: Vec<(usize, Option<String>)> = selected.drain(..).zip(out_names.drain(..)).collect();
+    pairs.sort_by_key(|(i, _)| entries[*i].offset);
+    (*selected, *out_names) = pairs.into_iter().unzip();
+}
+
+/// How many entries' stored bytes may wait in memory ahead of the one being written.
+const READ_AHEAD_ENTRIES: usize = 2;
+
+/// Pause between passes over locked output files (`ExtractOptions::retry_locked`).
+pub const LOCKED_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Appended to a locked file's name for the copy that replaces it at reboot.
+pub const STAGED_SUFFIX: &str = ".mabi-new";
+
+/// Whether writing failed because another process holds the output file open.
+fn is_locked_failure(err: &Error) -> bool {
+    err.chain().filter_map(|e| e.downcast_ref::<std::io::Error>()).any(common::is_locked_error)
+}
+
+fn write_selected_entries<R: Read + Seek>(
+    rd_for_content: &mut R,
+    prefetched: &std::sync::mpsc::Receiver<Result<Option<Vec<u8>>, Error>>,
+    entries: &[FileEntry],
+    selected: &[usize],
+    out_names: &[Option<String>],
+    content_offset: u64,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+    output_folder_str: &str,
+    opts: &ExtractOptions,
+) -> Result<common::OperationSummary, Error> {
+    let mut summary = common::OperationSummary::default();
+    let failed = |summary: &mut common::OperationSummary, message: String| {
+        warn!("[EXTRACT] {}", message);
+        summary.failed += 1;
+        summary.warnings.push(message);
+    };
+    let (mut verified, mut mismatched, mut unlisted) = (0usize, 0usize, 0usize);
+    let mut backup = match &opts.backup_dir {
+        Some(dir) => Some(BackupManifest::open(dir, output_folder_str)?),
+        None => None,
+    };
+    let mut locked: Vec<(usize, String)> = Vec::new();
+    for (pos, (&i, out_name)) in selected.iter().zip(out_names).enumerate() {
+        let ent = &entries[i];
+        let Some(out_name) = out_name else { continue };
+        if ent.is_dir_marker() {
+            // Folders only exist relative to the original layout; a path template regroups files.
+            if opts.path_template.is_none() && opts.writes_files() {
+                let created = common::output_path(output_folder_str, out_name.trim_end_matches(['\\', '/']), &opts.settings)
+                    .and_then(|dir| Ok(std::fs::create_dir_all(dir)?));
+                if let Err(e) = created {
+                    warn!("[EXTRACT] Failed to create folder {}: {}", ent.name, e);
+                }
+            }
+            continue;
+        }
+        if let Some(cb) = opts.progress_cb { cb(pos, selected.len(), ""); }
+        if ent.flags & FLAG_SYMLINK != 0 {
+            if opts.symlinks != SymlinkPolicy::Skip {
+                match backup_before_write(&mut backup, output_folder_str, out_name, opts) {
+                    Ok(n) => summary.backed_up += n,
+                    Err(e) => {
+                        failed(&mut summary, format!("Not overwriting {}: {}", out_name, e));
+                        continue;
+                    }
+                }
+                let linked = common::catch_panic(&ent.name, || extract_symlink(rd_for_content, content_offset, ent, entries, output_folder_str, iv0, mode, out_name, opts));
+                record_written(&mut backup, output_folder_str, out_name, opts);
+                match linked {
+                    Ok(()) => summary.entries += 1,
+                    Err(e) => failed(&mut summary, format!("Failed to extract link {}: {}", ent.name, e)),
+                }
+            }
+            continue;
+        }
+        let written = prefetched.recv()
+            .map_err(|_| Error::msg("read-ahead stopped early"))
+            .and_then(|raw| {
+                let raw = raw?;
+                summary.backed_up += backup_before_write(&mut backup, output_folder_str, out_name, opts)?;
+                let written = common::catch_panic(&ent.name, || match raw {
+                    Some(raw) => write_entry(raw, ent, output_folder_str, iv0, mode, out_name, opts.auto_convert_png, opts.dedupe, opts.objects, opts.sink, opts.hook, &opts.settings),
+                    None => stream_entry(rd_for_content, content_offset, ent, output_folder_str, iv0, mode, out_name, &opts.settings),
+                });
+                record_written(&mut backup, output_folder_str, out_name, opts);
+                written
+            });
+        if let Err(e) = written {
+            if opts.writes_files() && is_locked_failure(&e) {
+                debug!("[LOCKED] {} is in use: {}", out_name, e);
+                locked.push((i, out_name.clone()));
+            } else {
+                failed(&mut summary, format!("Failed to extract {}: {}", ent.name, e));
+            }
+            continue;
+        }
+        summary.entries += 1;
+        summary.bytes += ent.original_size as u64;
+        summary.count_extension(&ent.name, ent.original_size as u64);
+        let Some(hashes) = opts.verify_hashes else { continue };
+        let converted = opts.auto_convert_png && ent.name.to_lowercase().ends_with(".dds");
+        match hashes.get(&verify::normalize_name(&ent.name)) {
+            Some(expected) if !converted => match verify::verify_written_file(output_folder_str, out_name, expected) {
+                Ok(()) => verified += 1,
+                Err(reason) => {
+                    mismatched += 1;
+                    warn!("[VERIFY] {}: {}", ent.name, reason);
+                    summary.warnings.push(format!("{}: {}", ent.name, reason));
+                }
+            },
+            _ => unlisted += 1,
+        }
+    }
+    if !locked.is_empty() {
+        write_locked_entries(rd_for_content, locked, entries, content_offset, iv0, mode, output_folder_str, opts, &mut summary);
+    }
+    if let Some(cb) = opts.progress_cb { cb(selected.len(), selected.len(), "Complete"); }
+    if let (Some(manifest), Some(dir)) = (&backup, &opts.backup_dir) {
+        manifest.save(dir)?;
+        if summary.backed_up > 0 {
+            info!("[BACKUP] Copied {} file(s) about to be overwritten to '{}'", summary.backed_up, dir);
+        }
+    }
+    if opts.verify_hashes.is_some() {
+        info!("[VERIFY] {} file(s) verified, {} mismatched, {} without a digest.", verified, mismatched, unlisted);
+        if mismatched > 0 {
+            return Err(Error::msg(format!("{} extracted file(s) failed content verification", mismatched)));
+        }
+    }
+    Ok(summary)
+}
+
+/// Retry entries whose output file was locked (`retry_locked`), then stage those still
+/// locked for replacement at reboot (`schedule_on_reboot`). The rest end up in
+/// `summary.locked` and count as failed.
+fn write_locked_entries<R: Read + Seek>(
+    rd: &mut R,
+    mut pending: Vec<(usize, String)>,
+    entries: &[FileEntry],
+    content_offset: u64,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+    output_folder_str: &str,
+    opts: &ExtractOptions,
+    summary: &mut common::OperationSummary,
+) {
+    let mut write = |i: usize, name: &str| -> Result<(), Error> {
+        if streams(&entries[i], opts) {
+            return stream_entry(rd, content_offset, &entries[i], output_folder_str, iv0, mode, name, &opts.settings);
+        }
+        let raw = read_raw_entry(rd, content_offset, &entries[i], &opts.settings)?;
+        write_entry(raw, &entries[i], output_folder_str, iv0, mode, name, opts.auto_convert_png, opts.dedupe, None, None, opts.hook, &opts.settings)
+    };
+    for attempt in 1..=opts.retry_locked {
+        if pending.is_empty() {
+            break;
+        }
+        info!("[LOCKED] {} file(s) in use by another process; retry {} of {}", pending.len(), attempt, opts.retry_locked);
+        std::thread::sleep(LOCKED_RETRY_DELAY);
+        pending.retain(|(i, name)| match write(*i, name) {
+            Ok(()) => {
+                let ent = &entries[*i];
+                summary.entries += 1;
+                summary.bytes += ent.original_size as u64;
+                summary.count_extension(&ent.name, ent.original_size as u64);
+                false
+            }
+            Err(e) if is_locked_failure(&e) => true,
+            Err(e) => {
+                let message = format!("Failed to extract {}: {}", entries[*i].name, e);
+                warn!("[EXTRACT] {}", message);
+                summary.failed += 1;
+                summary.warnings.push(message);
+                false
+            }
+        });
+    }
+    for (i, name) in pending {
+        if opts.schedule_on_reboot {
+            let staged = format!("{}{}", name, STAGED_SUFFIX);
+            let root = Path::new(output_folder_str);
+            let local = |n: &str| root.join(n.replace(['/', '\\'], std::path::MAIN_SEPARATOR_STR));
+            match write(i, &staged).and_then(|()| common::replace_on_reboot(&local(&staged), &local(&name))) {
+                Ok(()) => {
+                    info!("[LOCKED] {} will be replaced at the next reboot", name);
+                    summary.entries += 1;
+                    summary.bytes += entries[i].original_size as u64;
+                    summary.scheduled.push(name);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("[LOCKED] Could not schedule {} for replacement: {:#}", name, e);
+                    let _ = std::fs::remove_file(local(&staged));
+                }
+            }
+        }
+        let message = format!("{} is in use by another process and was not replaced", name);
+        warn!("[LOCKED] {}", message);
+        summary.failed += 1;
+        summary.warnings.push(message);
+        summary.locked.push(name);
+    }
+}
+
+/// Output names that writing `out_name` may produce (the `.png` too when DDS files are converted).
+fn written_names(out_name: &str, auto_convert_png: bool) -> Vec<String> {
+    let mut names = vec![out_name.to_string()];
+    if auto_convert_png && out_name.to_lowercase().ends_with(".dds") {
+        names.push(out_name[..out_name.len() - 4].to_string() + ".png");
+    }
+    names
+}
+
+/// Back up what writing `out_name` would overwrite (see `BackupManifest::before_write`).
+fn backup_before_write(backup: &mut Option<BackupManifest>, root_dir: &str, out_name: &str, opts: &ExtractOptions) -> Result<usize, Error> {
+    let (Some(manifest), Some(dir)) = (backup.as_mut(), &opts.backup_dir) else { return Ok(0) };
+    let mut copied = 0;
+    for name in written_names(out_name, opts.auto_convert_png) {
+        copied += usize::from(manifest.before_write(root_dir, dir, &name)?);
+    }
+    Ok(copied)
+}
+
+fn record_written(backup: &mut Option<BackupManifest>, root_dir: &str, out_name: &str, opts: &ExtractOptions) {
+    if let Some(manifest) = backup.as_mut() {
+        for name in written_names(out_name, opts.auto_convert_png) {
+            manifest.after_write(root_dir, &name);
+        }
+    }
+}
+
+/// Extract using an already-chosen key combination (e.g. from `common_ext::search_all_keys`).
+pub fn run_extract_with_hit(
+    fname_str: &str,
+    output_folder_str: &str,
+    hit: &crate::common_ext::KeySearchHit,
+    opts: &ExtractOptions,
+) -> Result<common::OperationSummary, Error> {
+    let filters = make_regex(opts.filters.clone(), &opts.settings)?;
+    info!("[EXTRACT] Using HEADER='{}', ENTRIES='{}', Variant='{}', Offset=0x{:X}, IV={}, Mode={:?}",
+        hit.header_salt, hit.entries_salt, hit.name_variant, hit.header_offset, hit.iv0, hit.mode);
+    Ok(extract_entries(fname_str, output_folder_str, &hit.entries, hit.content_offset, hit.iv0, hit.mode, &filters, opts)?
+        .with_salts(&hit.header_salt, &hit.entries_salt))
+}
+
+/// What `run_extract_overlay` took from one of its inputs.
+#[derive(Debug, Clone)]
+pub struct OverlayPack {
+    pub path: String,
+    /// Entries written from this pack.
+    pub extracted: usize,
+    /// Entries left out because a later input holds the same name.
+    pub shadowed: usize,
+}
+
+/// Extract several packs into one folder as the client sees them: `inputs` are in
+/// priority order, and an entry of a later input replaces an earlier input's entry of the
+/// same name (compared case-insensitively, either slash). Shadowed entries are never
+/// written. Every pack's keys are found before anything is written.
+pub fn run_extract_overlay(
+    inputs: &[String],
+    output_folder_str: &str,
+    cli_skey: Option<String>,
+    loaded_salts: &[String],
+    opts: &ExtractOptions,
+) -> Result<(common::OperationSummary, Vec<OverlayPack>), Error> {
+    if opts.save_index.is_some() || opts.use_index.is_some() || opts.verify_hashes.is_some() {
+        return Err(Error::msg("Index files and content verification work on one pack at a time"));
+    }
+    let mut hits = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let file = StdFile::open(input)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        if mmap.len() >= 4 && (&mmap[0..4] == b"MABI" || &mmap[0..4] == b"PACK") {
+            return Err(Error::msg(format!("{} is a legacy .pack archive, which can't be part of an overlay", input)));
+        }
+        hits.push(common_ext::search_keys(&mmap, input, cli_skey.clone(), loaded_salts, opts.region_key.clone(), &opts.settings)?);
+    }
+
+    // Walk from the highest priority down: an entry survives unless a later pack has its name.
+    let mut seen = HashSet::new();
+    let mut shadowed = vec![0; hits.len()];
+    for (idx, hit) in hits.iter_mut().enumerate().rev() {
+        let names: Vec<String> = hit.entries.iter().map(|e| e.name.replace('\\', "/").to_lowercase()).collect();
+        let before = hit.entries.len();
+        let mut keep = names.iter().map(|n| !seen.contains(n));
+        hit.entries.retain(|_| keep.next().unwrap());
+        shadowed[idx] = before - hit.entries.len();
+        seen.extend(names);
+    }
+
+    let mut summary = common::OperationSummary::default();
+    let mut packs = Vec::with_capacity(inputs.len());
+    for ((input, hit), shadowed) in inputs.iter().zip(&hits).zip(shadowed) {
+        info!("[OVERLAY] {}: {} entries, {} shadowed by later packs", input, hit.entries.len(), shadowed);
+        let part = run_extract_with_hit(input, output_folder_str, hit, opts)?;
+        packs.push(OverlayPack { path: input.clone(), extracted: part.entries, shadowed });
+        if summary.salt.is_none() {
+            summary.salt = part.salt.clone();
+            summary.entries_salt = part.entries_salt.clone();
+        }
+        summary.absorb(part);
+    }
+    Ok((summary, packs))
 }
 
 pub fn run_extract_with_key_search(
@@ -152,9 +1466,30 @@ pub fn run_extract_with_key_search(
     region_key_override: Option<String>,
     auto_convert_png: bool,
     progress_cb: Option<&ProgressFn>,
-) -> Result<String, Error> {
+) -> Result<common::OperationSummary, Error> {
+    let opts = ExtractOptions {
+        filters: filters_cli,
+        region_key: region_key_override,
+        auto_convert_png,
+        progress_cb,
+        ..ExtractOptions::default()
+    };
+    run_extract_with_options(fname_str, output_folder_str, cli_skey, loaded_salts, &opts)
+}
+
+/// Search the keys of `fname_str` (CLI key first, then `loaded_salts`) and extract it
+/// into `output_folder_str`. The summary carries the salts that worked (none for legacy packs).
+pub fn run_extract_with_options(
+    fname_str: &str,
+    output_folder_str: &str,
+    cli_skey: Option<String>,
+    loaded_salts: &[String],
+    opts: &ExtractOptions,
+) -> Result<common::OperationSummary, Error> {
+    let progress_cb = opts.progress_cb;
+    let search_trace = opts.search_trace;
     debug!("[EXTRACT_SEARCH] Sequence: User Key -> Regional Filename -> Hardcoded Salts -> Salts.txt");
-    let filters = make_regex(filters_cli)?;
+    let filters = make_regex(opts.filters.clone(), &opts.settings)?;
 
     let mut keys_to_try: Vec<String> = Vec::new();
     if let Some(ref key) = cli_skey {
@@ -164,41 +1499,60 @@ pub fn run_extract_with_key_search(
     for salt in loaded_salts {
         if !keys_to_try.contains(salt) { keys_to_try.push(salt.clone()); }
     }
+    let cache_name = common::get_final_file_name(fname_str).unwrap_or_default();
+    key_cache::prioritize(&cache_name, &mut keys_to_try[usize::from(cli_skey.is_some())..]);
 
     let file = StdFile::open(fname_str)?;
     let mmap = unsafe { Mmap::map(&file)? };
     
     // Check for Legacy PACK/MABI magic signature
+    if mmap.len() >= 4 && (&mmap[0..4] == b"MABI" || &mmap[0..4] == b"PACK") && opts.sink.is_some() {
+        return Err(Error::msg(format!("{} is a legacy .pack archive, which can only be extracted into a folder", fname_str)));
+    }
     if mmap.len() >= 4 {
         if &mmap[0..4] == b"MABI" {
             debug!("[EXTRACT_SEARCH] Legacy MABI .pack detected.");
-            crate::pack_v1::run_extract_v1(fname_str, output_folder_str)?;
+            crate::pack_v1::run_extract_v1(fname_str, output_folder_str, &opts.settings)?;
             if let Some(cb) = progress_cb { cb(1, 1, "Complete"); }
-            return Ok("LEGACY_MABI".to_string());
+            return Ok(common::OperationSummary::legacy("mabi", &crate::pack_v1::run_list_v1_data(fname_str, &opts.settings)?));
         }
         if &mmap[0..4] == b"PACK" {
             // Try Logue format first
-            if let Ok(_) = crate::pack_v1::run_list_logue_data(fname_str) {
+            if let Ok(logue_entries) = crate::pack_v1::run_list_logue_data(fname_str, &opts.settings) {
                  debug!("[EXTRACT_SEARCH] Logue/MabinogiResource .pack detected.");
-                 crate::pack_v1::run_extract_logue(fname_str, output_folder_str)?;
+                 crate::pack_v1::run_extract_logue(fname_str, output_folder_str, &opts.settings)?;
                  if let Some(cb) = progress_cb { cb(1, 1, "Complete"); }
-                 return Ok("LOGUE_PACK".to_string());
+                 return Ok(common::OperationSummary::legacy("logue", &logue_entries));
             }
 
             debug!("[EXTRACT_SEARCH] Legacy Standard .pack detected.");
-            crate::pack_v1::run_extract_v1(fname_str, output_folder_str)?;
+            crate::pack_v1::run_extract_v1(fname_str, output_folder_str, &opts.settings)?;
             if let Some(cb) = progress_cb { cb(1, 1, "Complete"); }
-            return Ok("LEGACY_PACK".to_string());
+            return Ok(common::OperationSummary::legacy("pack", &crate::pack_v1::run_list_v1_data(fname_str, &opts.settings)?));
         }
     }
     
-    let final_fname = common::get_final_file_name(fname_str)?;
-    let mut name_variants = vec![final_fname.clone()];
-    if let Some(r) = region_key_override {
-        if !name_variants.contains(&r) { name_variants.push(r); }
+    let extract_hit = |hit: common_ext::KeySearchHit| -> Result<common::OperationSummary, Error> {
+        run_summary::record_key(fname_str, mmap.len() as u64, &hit);
+        if let Some(path) = &opts.save_index {
+            index::save(path, &mmap, fname_str, &hit)?;
+        }
+        Ok(extract_entries(fname_str, output_folder_str, &hit.entries, hit.content_offset, hit.iv0, hit.mode, &filters, opts)?.with_salts(&hit.header_salt, &hit.entries_salt))
+    };
+    if let Some(path) = &opts.use_index {
+        match index::load(path, &mmap, fname_str)? {
+            Some(hit) => {
+                info!("[INDEX] Using '{}' for '{}': HEADER='{}', ENTRIES='{}'", path, fname_str, hit.header_salt, hit.entries_salt);
+                return extract_hit(hit);
+            }
+            None => warn!("[INDEX] '{}' was saved from a different version of '{}'; searching keys", path, fname_str),
+        }
+    }
+    if let Some(hit) = search_trace.is_none().then(|| common_ext::cached_key_hit(&mmap, fname_str, cli_skey.as_deref(), &opts.settings)).flatten() {
+        return extract_hit(hit);
     }
-    name_variants.push("data.it".to_string());
-    name_variants.push("".to_string());
+
+    let name_variants = common::key_name_variants(fname_str, opts.region_key.clone(), &opts.settings)?;
 
     debug!("[EXTRACT_SEARCH] Will attempt extraction with {} unique salt key(s).", keys_to_try.len());
 
@@ -212,7 +1566,7 @@ pub fn run_extract_with_key_search(
             .collect();
         for entries_skey in entries_candidates {
             let mut rd2 = Cursor::new(&mmap[..]);
-            if let Ok((_, entries, c_off)) = common::read_meta_iv_mode_two_key(name, header_skey, entries_skey, &mut rd2, h_off, iv0, mode) {
+            if let Ok((_, entries, c_off)) = common::read_meta_iv_mode_two_key_traced(name, header_skey, entries_skey, &mut rd2, h_off, iv0, mode, &opts.settings, search_trace) {
                 trace!("[EXTRACT_SEARCH] Entries validated with skey: '{}'", entries_skey);
                 return Some((entries, header_skey.to_string(), entries_skey.to_string(), c_off));
             }
@@ -226,7 +1580,7 @@ pub fn run_extract_with_key_search(
         let cli_result = name_variants.iter().find_map(|name| {
             debug!("[EXTRACT_SEARCH] Trying HEADER skey: '{}' for file '{}'", specific_key, fname_str);
             let mut rd = Cursor::new(&mmap[..]);
-            if let Ok(Some((_header, h_off, iv0, mode))) = common::find_header_only(&mut rd, name, specific_key) {
+            if let Ok(Some((_header, h_off, iv0, mode))) = common::find_header_only_traced(&mut rd, name, specific_key, search_trace) {
                 if let Some((entries, h_key, e_key, c_off)) = try_entries_extract(name, specific_key, h_off, iv0, mode) {
                     return Some((entries, h_key, e_key, h_off, name.clone(), iv0, mode, c_off));
                 }
@@ -234,55 +1588,48 @@ pub fn run_extract_with_key_search(
             None
         });
 
-        if let Some((entries, h_key, e_key, _final_offset, _name_variant, final_iv0, mode, content_offset)) = cli_result {
-            info!("[EXTRACT_SEARCH] >>> SUCCESS (CLI)! HEADER='{}', ENTRIES='{}', Offset=0x{:X}, IV={}, Mode={:?}", h_key, e_key, _final_offset, final_iv0, mode);
+        if let Some((entries, h_key, e_key, final_offset, name_variant, final_iv0, mode, content_offset)) = cli_result {
+            info!("[EXTRACT_SEARCH] >>> SUCCESS (CLI)! HEADER='{}', ENTRIES='{}', Offset=0x{:X}, IV={}, Mode={:?}", h_key, e_key, final_offset, final_iv0, mode);
+            common::report_name_guess(fname_str, &name_variant, &h_key, &e_key, &opts.settings);
+            key_cache::record_success(&cache_name, &h_key, &e_key);
+            key_cache::record_pack(fname_str, &name_variant, &h_key, &e_key, final_offset, final_iv0, mode);
 
-            let total = entries.len();
-            for (i, ent) in entries.iter().enumerate() {
-                if filters.is_empty() || filters.iter().any(|re| re.find(&ent.name).is_some()) {
-                    if let Some(cb) = progress_cb { cb(i, total, ""); }
-                    let mut rd_for_content = StdBufReader::new(StdFile::open(fname_str)?);
-                    if let Err(e) = extract_file(&mut rd_for_content, content_offset, ent, output_folder_str, final_iv0, mode, auto_convert_png) {
-                        warn!("[EXTRACT] Failed to extract {}: {}", ent.name, e);
-                    }
-                }
-            }
-            if let Some(cb) = progress_cb { cb(total, total, "Complete"); }
-            return Ok(h_key);
+            return extract_hit(common_ext::KeySearchHit { entries, header_salt: h_key, entries_salt: e_key, name_variant, iv0: final_iv0, header_offset: final_offset, mode, content_offset });
         }
         warn!("[EXTRACT_SEARCH] Provided key failed. Proceeding to exhaustive search...");
     }
 
     // Phase 2: Exhaustive two-phase parallel search
-    let result = name_variants.into_iter().find_map(|name| {
+    let progress = common::SearchProgress::new((name_variants.len() * keys_to_try.len()) as u64);
+    let result = name_variants.iter().find_map(|name| {
         debug!("[PROBE] Testing derivation variant: '{}'", name);
-        keys_to_try.par_iter().find_map_any(|header_skey| {
+        keys_to_try.par_iter().find_map_first(|header_skey| {
             debug!("[EXTRACT_SEARCH] Trying HEADER skey: '{}' for file '{}'", header_skey, fname_str);
             let mut rd = Cursor::new(&mmap[..]);
-            if let Ok(Some((_header, h_off, iv0, mode))) = common::find_header_only(&mut rd, &name, header_skey) {
-                if let Some((entries, h_key, e_key, c_off)) = try_entries_extract(&name, header_skey, h_off, iv0, mode) {
+            if let Ok(Some((_header, h_off, iv0, mode))) = common::find_header_only_traced(&mut rd, name, header_skey, search_trace) {
+                if let Some((entries, h_key, e_key, c_off)) = try_entries_extract(name, header_skey, h_off, iv0, mode) {
                     return Some((entries, h_key, e_key, h_off, name.clone(), iv0, mode, c_off));
                 }
             }
+            progress.tick(header_skey, name);
             None
         })
     });
+    drop(progress);
+
+    // Phase 3: --scan-offsets / --auto-escalate
+    let result = result.or_else(|| {
+        common_ext::escalate_search(&mmap, &name_variants, &keys_to_try, &opts.settings)
+            .map(|hit| (hit.entries, hit.header_salt, hit.entries_salt, hit.header_offset, hit.name_variant, hit.iv0, hit.mode, hit.content_offset))
+    });
 
     if let Some((entries, h_key, e_key, final_offset, name_variant, final_iv0, mode, content_offset)) = result {
         info!("[EXTRACT_SEARCH] >>> SUCCESS! Variant={}, HEADER='{}', ENTRIES='{}', Offset=0x{:X}, IV={}, Mode={:?}", name_variant, h_key, e_key, final_offset, final_iv0, mode);
-        
-        let total = entries.len();
-        for (i, ent) in entries.iter().enumerate() {
-            if filters.is_empty() || filters.iter().any(|re| re.find(&ent.name).is_some()) {
-                if let Some(cb) = progress_cb { cb(i, total, ""); }
-                let mut rd_for_content = StdBufReader::new(StdFile::open(fname_str)?);
-                if let Err(e) = extract_file(&mut rd_for_content, content_offset, ent, output_folder_str, final_iv0, mode, auto_convert_png) {
-                    warn!("[EXTRACT] Failed to extract {}: {}", ent.name, e);
-                }
-            }
-        }
-        if let Some(cb) = progress_cb { cb(total, total, "Complete"); }
-        return Ok(h_key);
+        common::report_name_guess(fname_str, &name_variant, &h_key, &e_key, &opts.settings);
+        key_cache::record_success(&cache_name, &h_key, &e_key);
+        key_cache::record_pack(fname_str, &name_variant, &h_key, &e_key, final_offset, final_iv0, mode);
+
+        return extract_hit(common_ext::KeySearchHit { entries, header_salt: h_key, entries_salt: e_key, name_variant, iv0: final_iv0, header_offset: final_offset, mode, content_offset });
     }
 
     Err(Error::msg(format!("Exhausted all key combinations for '{}'. No working set of parameters found.", fname_str)))
@@ -0,0 +1,95 @@
+// hook.rs - User hooks that rewrite entry names and payloads during pack and extract
+
+use anyhow::Error;
+
+/// Rewrites entries as they flow through `pack` (input file -> entry) or `extract`
+/// (entry -> output file). `name` is the entry name as stored in the pack.
+pub trait EntryHook: Send + Sync + std::fmt::Debug {
+    /// The name to use instead of `name`, or `None` to keep it.
+    fn rename(&self, name: &str) -> Result<Option<String>, Error>;
+    /// The payload to store or write instead of `data` (decoded content).
+    fn transform(&self, name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error>;
+}
+
+/// Which way entries flow; scripts see it as the `DIRECTION` constant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookDirection {
+    Pack,
+    Extract,
+}
+
+impl HookDirection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HookDirection::Pack => "pack",
+            HookDirection::Extract => "extract",
+        }
+    }
+}
+
+/// A Rhai script defining `rename(name)` and/or `transform(name, data)`. `rename` returns
+/// the new name (a string) or `()` to keep it; `transform` gets the payload as a blob and
+/// returns a blob, a string (stored as UTF-8) or `()` to keep it.
+#[cfg(feature = "scripting")]
+#[derive(Debug)]
+pub struct ScriptHook {
+    engine: rhai::Engine,
+    ast: rhai::AST,
+    direction: HookDirection,
+    has_rename: bool,
+    has_transform: bool,
+}
+
+#[cfg(feature = "scripting")]
+impl ScriptHook {
+    pub fn load(path: &str, direction: HookDirection) -> Result<Self, Error> {
+        let engine = rhai::Engine::new();
+        let ast = engine.compile_file(path.into()).map_err(|e| Error::msg(format!("Script {}: {}", path, e)))?;
+        let defines = |name: &str, params: usize| ast.iter_functions().any(|f| f.name == name && f.params.len() == params);
+        let (has_rename, has_transform) = (defines("rename", 1), defines("transform", 2));
+        if !has_rename && !has_transform {
+            return Err(Error::msg(format!("Script {} defines neither rename(name) nor transform(name, data)", path)));
+        }
+        Ok(ScriptHook { engine, ast, direction, has_rename, has_transform })
+    }
+
+    fn call(&self, func: &str, args: impl rhai::FuncArgs) -> Result<rhai::Dynamic, Error> {
+        let mut scope = rhai::Scope::new();
+        scope.push_constant("DIRECTION", self.direction.as_str());
+        self.engine.call_fn(&mut scope, &self.ast, func, args).map_err(|e| Error::msg(format!("Script {}(): {}", func, e)))
+    }
+}
+
+#[cfg(feature = "scripting")]
+impl EntryHook for ScriptHook {
+    fn rename(&self, name: &str) -> Result<Option<String>, Error> {
+        if !self.has_rename {
+            return Ok(None);
+        }
+        let result = self.call("rename", (name.to_string(),))?;
+        if result.is_unit() {
+            return Ok(None);
+        }
+        let new_name = result.into_string().map_err(|t| Error::msg(format!("Script rename() returned {} for {}, expected a string", t, name)))?;
+        if new_name.is_empty() {
+            return Err(Error::msg(format!("Script rename() returned an empty name for {}", name)));
+        }
+        Ok(Some(new_name))
+    }
+
+    fn transform(&self, name: &str, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if !self.has_transform {
+            return Ok(data);
+        }
+        let result = self.call("transform", (name.to_string(), rhai::Blob::from(data.clone())))?;
+        if result.is_unit() {
+            Ok(data)
+        } else if result.is_blob() {
+            Ok(result.cast::<rhai::Blob>())
+        } else if result.is_string() {
+            Ok(result.cast::<rhai::ImmutableString>().as_bytes().to_vec())
+        } else {
+            Err(Error::msg(format!("Script transform() returned {} for {}, expected a blob or string", result.type_name(), name)))
+        }
+    }
+}
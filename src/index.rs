@@ -0,0 +1,120 @@
+// index.rs - Saved entry tables for repeated extraction from an unchanged pack
+
+use crate::common::{self, FileEntry};
+use crate::common_ext::KeySearchHit;
+use crate::encryption;
+use crate::verify;
+use anyhow::{Context, Error};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+/// Starts every index file written by `save`: `[INDEX_MAGIC][encrypted JSON body]`.
+pub const INDEX_MAGIC: &[u8; 4] = b"MP2I";
+
+/// Salt for the key the body is encrypted with; combined with the pack's file name
+/// like the pack's own table keys.
+const INDEX_SALT: &str = "mabi-pack2 index";
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    name: String,
+    checksum: u32,
+    flags: u32,
+    offset: u32,
+    original_size: u32,
+    raw_size: u32,
+    key: [u8; 16],
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexBody {
+    pack_size: u64,
+    /// MD5 of the pack from its header to the first payload, which covers the entries table.
+    table_md5: String,
+    name_variant: String,
+    header_salt: String,
+    entries_salt: String,
+    iv0: u32,
+    header_offset: u64,
+    mode: encryption::Snow2Mode,
+    content_offset: u64,
+    entries: Vec<IndexEntry>,
+}
+
+fn table_md5(data: &[u8], header_offset: u64, content_offset: u64) -> String {
+    let end = (content_offset as usize).min(data.len());
+    let start = (header_offset as usize).min(end);
+    verify::md5_hex(&data[start..end])
+}
+
+fn index_key(fname_str: &str) -> Result<[u8; 16], Error> {
+    Ok(encryption::gen_header_key(&common::get_final_file_name(fname_str)?, INDEX_SALT))
+}
+
+/// Write the key search result `hit` for the pack image `data` (read from `fname_str`) to `path`.
+pub fn save(path: &str, data: &[u8], fname_str: &str, hit: &KeySearchHit) -> Result<(), Error> {
+    let body = IndexBody {
+        pack_size: data.len() as u64,
+        table_md5: table_md5(data, hit.header_offset, hit.content_offset),
+        name_variant: hit.name_variant.clone(),
+        header_salt: hit.header_salt.clone(),
+        entries_salt: hit.entries_salt.clone(),
+        iv0: hit.iv0,
+        header_offset: hit.header_offset,
+        mode: hit.mode,
+        content_offset: hit.content_offset,
+        entries: hit.entries.iter().map(|e| IndexEntry {
+            name: e.name.clone(),
+            checksum: e.checksum,
+            flags: e.flags,
+            offset: e.offset,
+            original_size: e.original_size,
+            raw_size: e.raw_size,
+            key: e.key,
+        }).collect(),
+    };
+    let mut json = serde_json::to_vec(&body)?;
+    encryption::snow2_encrypt_mode(&index_key(fname_str)?, 0, encryption::Snow2Mode::Sub, &mut json);
+    let mut out = INDEX_MAGIC.to_vec();
+    out.extend_from_slice(&json);
+    std::fs::write(path, out).context(format!("writing index {} failed", path))?;
+    info!("[INDEX] Saved {} entries of '{}' to '{}'", hit.entries.len(), fname_str, path);
+    Ok(())
+}
+
+/// The key search result saved in `path`, or `None` if the pack image `data` is not the
+/// one it was saved from (different size or entries table). Fails when `path` is not an
+/// index, or one saved for a pack with another file name.
+pub fn load(path: &str, data: &[u8], fname_str: &str) -> Result<Option<KeySearchHit>, Error> {
+    let raw = std::fs::read(path).context(format!("reading index {} failed", path))?;
+    let Some(body) = raw.strip_prefix(INDEX_MAGIC.as_slice()) else {
+        return Err(Error::msg(format!("'{}' is not a pack index", path)));
+    };
+    let mut json = body.to_vec();
+    encryption::snow2_decrypt_mode(&index_key(fname_str)?, 0, encryption::Snow2Mode::Sub, &mut json);
+    let body: IndexBody = serde_json::from_slice(&json)
+        .map_err(|_| Error::msg(format!("'{}' is not an index of '{}'", path, fname_str)))?;
+
+    if body.pack_size != data.len() as u64 || body.table_md5 != table_md5(data, body.header_offset, body.content_offset) {
+        debug!("[INDEX] '{}' no longer matches '{}'", path, fname_str);
+        return Ok(None);
+    }
+    Ok(Some(KeySearchHit {
+        entries: body.entries.into_iter().map(|e| FileEntry {
+            name: e.name,
+            checksum: e.checksum,
+            flags: e.flags,
+            offset: e.offset,
+            original_size: e.original_size,
+            raw_size: e.raw_size,
+            key: e.key,
+        }).collect(),
+        header_salt: body.header_salt,
+        entries_salt: body.entries_salt,
+        name_variant: body.name_variant,
+        iv0: body.iv0,
+        header_offset: body.header_offset,
+        mode: body.mode,
+        content_offset: body.content_offset,
+    }))
+}
@@ -0,0 +1,386 @@
+// info.rs - Archive Summary Module
+
+use crate::common::{self, extension_of, FileEntry, FLAG_COMPRESSED, KNOWN_FLAGS};
+use crate::reader::PackReader;
+use crate::verify::{self, normalize_name};
+use anyhow::Error;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Print a summary of `input`: resolved keys and layout, entry totals and the
+/// build-info record left by `pack` (if any). With `layout` the block map from
+/// `pack_layout` follows.
+pub fn run_info(input: &str, cli_key: Option<String>, loaded_salts: &[String], settings: &common::PackSettings, layout: bool, out: &mut dyn Write) -> Result<(), Error> {
+    let reader = PackReader::open_with_settings(input, cli_key, loaded_salts, settings)?;
+    let entries = reader.entries();
+    let original: u64 = entries.iter().map(|e| e.original_size as u64).sum();
+    let stored: u64 = entries.iter().map(|e| e.raw_size as u64).sum();
+
+    writeln!(out, "Archive:        {}", reader.path)?;
+    writeln!(out, "Size:           {} bytes", reader.file_len())?;
+    writeln!(out, "Key name:       {}", if reader.name_variant.is_empty() { "<empty>" } else { &reader.name_variant })?;
+    writeln!(out, "Header salt:    {}", reader.header_salt)?;
+    writeln!(out, "Entries salt:   {}", reader.entries_salt)?;
+    writeln!(out, "Header offset:  0x{:X}", reader.header_offset)?;
+    let header = reader.header()?;
+    writeln!(out, "Header:         version {}, {} files, checksum 0x{:08X}", header.version, header.file_cnt, header.checksum)?;
+    writeln!(out, "Content offset: 0x{:X}", reader.content_offset)?;
+    writeln!(out, "IV / mode:      {} / {:?}", reader.iv0, reader.mode)?;
+    writeln!(out, "Entries:        {}", entries.len())?;
+    writeln!(out, "Original size:  {} bytes", original)?;
+    writeln!(out, "Stored size:    {} bytes", stored)?;
+    let stats = compression_stats(entries);
+    writeln!(out, "Compression:    {} of {} entries, {} -> {} bytes ({:.1}% of original)",
+        stats.compressed, entries.len(), stats.original, stats.stored, stats.percent())?;
+    if let Some((best, worst)) = &stats.range {
+        writeln!(out, "Best / worst:   {:.1}% ({}) / {:.1}% ({})", best.1, best.0, worst.1, worst.0)?;
+    }
+    match data_block_span(entries) {
+        Some((first, last)) => writeln!(out, "Data blocks:    {} (0x{:X}) to {} (0x{:X})",
+            first, reader.content_offset + first * BLOCK_SIZE, last, reader.content_offset + last * BLOCK_SIZE)?,
+        None => writeln!(out, "Data blocks:    none")?,
+    }
+    writeln!(out, "Fingerprint:    {}", pack_fingerprint(entries))?;
+    let unknown: Vec<u32> = entries.iter().map(|e| e.flags & !KNOWN_FLAGS).filter(|&bits| bits != 0).collect();
+    if !unknown.is_empty() {
+        writeln!(out, "Unknown flags:  0x{:X} on {} entr{} (a format feature this tool may not handle)",
+            unknown.iter().fold(0, |all, bits| all | bits), unknown.len(), if unknown.len() == 1 { "y" } else { "ies" })?;
+    }
+
+    match reader.metadata() {
+        Some(meta) => {
+            writeln!(out, "Built by:       {} {}", meta.tool, meta.version)?;
+            writeln!(out, "Pack options:   iv={} mode={} auto_dds={} prefix={} compress_ext=[{}]",
+                meta.iv, meta.mode, meta.auto_dds, meta.path_prefix.as_deref().unwrap_or("-"), meta.compress_ext.join(", "))?;
+            if let Some(algo) = &meta.entry_checksum {
+                writeln!(out, "Entry checksum: {} (experimental; the game client can't read this pack)", algo)?;
+            }
+        }
+        None => writeln!(out, "Built by:       unknown (no build-info record)")?,
+    }
+    if layout {
+        writeln!(out)?;
+        let map = pack_layout(entries, reader.content_end() - reader.content_offset);
+        write_layout(&map, reader.content_offset, out)?;
+    }
+    Ok(())
+}
+
+/// Entry offsets count in blocks of this size from the content offset.
+pub const BLOCK_SIZE: u64 = 1024;
+
+/// How well the compressed entries of a pack compress.
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    pub compressed: usize,
+    /// Original and stored bytes of the compressed entries only.
+    pub original: u64,
+    pub stored: u64,
+    /// The compressed entries with the smallest and the largest stored/original percentage.
+    pub range: Option<((String, f64), (String, f64))>,
+}
+
+impl CompressionStats {
+    /// Stored size as a percentage of the original size (100 with nothing compressed).
+    pub fn percent(&self) -> f64 {
+        if self.original == 0 { 100.0 } else { self.stored as f64 * 100.0 / self.original as f64 }
+    }
+}
+
+pub fn compression_stats(entries: &[FileEntry]) -> CompressionStats {
+    let mut stats = CompressionStats::default();
+    for ent in entries.iter().filter(|e| e.flags & FLAG_COMPRESSED != 0 && e.original_size > 0) {
+        stats.compressed += 1;
+        stats.original += ent.original_size as u64;
+        stats.stored += ent.raw_size as u64;
+        let pct = ent.raw_size as f64 * 100.0 / ent.original_size as f64;
+        let this = (ent.name.clone(), pct);
+        stats.range = Some(match stats.range.take() {
+            None => (this.clone(), this),
+            Some((best, worst)) => (if pct < best.1 { this.clone() } else { best }, if pct > worst.1 { this } else { worst }),
+        });
+    }
+    stats
+}
+
+/// First and last content block holding entry data, counted from the content offset.
+pub fn data_block_span(entries: &[FileEntry]) -> Option<(u64, u64)> {
+    let stored = entries.iter().filter(|e| e.raw_size > 0);
+    let first = stored.clone().map(|e| e.offset as u64).min()?;
+    let last = stored.map(|e| e.offset as u64 + (e.raw_size as u64).div_ceil(BLOCK_SIZE) - 1).max()?;
+    Some((first, last))
+}
+
+/// Hashed ahead of the entry lines; a new canonical form gets a new tag.
+const FINGERPRINT_TAG: &str = "mabi-pack2 fingerprint v1\n";
+
+/// Stable id of what a pack holds, for naming client pack versions in reports: MD5 of
+/// the sorted `name<TAB>original size` lines (names with `/`). Content, keys, salts,
+/// IV, entry order and compression don't affect it, so repacking the same files keeps it.
+pub fn pack_fingerprint(entries: &[FileEntry]) -> String {
+    let mut lines: Vec<String> = entries.iter().map(|e| format!("{}\t{}\n", normalize_name(&e.name), e.original_size)).collect();
+    lines.sort_unstable();
+    verify::md5_hex(format!("{}{}", FINGERPRINT_TAG, lines.concat()).as_bytes())
+}
+
+/// Print `<fingerprint>  <pack>` for each of `inputs` (see `pack_fingerprint`).
+pub fn run_fingerprint(inputs: &[String], cli_key: Option<String>, loaded_salts: &[String], settings: &common::PackSettings, out: &mut dyn Write) -> Result<(), Error> {
+    for input in inputs {
+        let reader = PackReader::open_with_settings(input, cli_key.clone(), loaded_salts, settings)?;
+        writeln!(out, "{}  {}", pack_fingerprint(reader.entries()), input)?;
+    }
+    Ok(())
+}
+
+/// A run of content blocks and what occupies it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutRow {
+    pub first_block: u64,
+    pub blocks: u64,
+    /// Stored bytes in the run (the largest owner's); 0 for a gap.
+    pub used: u64,
+    /// Entries whose data is the run. Empty for a gap; several when entries share the same data.
+    pub owners: Vec<String>,
+    /// The run starts inside blocks an earlier run already owns.
+    pub overlaps: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct PackLayout {
+    pub rows: Vec<LayoutRow>,
+    /// Blocks in the content area (the last one may be partial).
+    pub total_blocks: u64,
+    pub gap_blocks: u64,
+    /// Unused bytes after the data in each entry's last block.
+    pub padding: u64,
+    /// Bytes no entry owns, including any after the last entry.
+    pub gap_bytes: u64,
+    /// Entries reusing blocks an earlier entry already owns.
+    pub reused_entries: usize,
+    /// Entries without stored data (directory markers, empty files); they own no blocks.
+    pub empty_entries: usize,
+    /// Entries whose data runs past the end of the content area.
+    pub past_end: usize,
+}
+
+impl PackLayout {
+    /// Bytes of the content area that hold no entry data.
+    pub fn slack(&self) -> u64 {
+        self.padding + self.gap_bytes
+    }
+}
+
+/// Map the `content_len` bytes after the content offset to the entries owning each run
+/// of blocks, in block order, with the gaps between them.
+pub fn pack_layout(entries: &[FileEntry], content_len: u64) -> PackLayout {
+    let mut layout = PackLayout { total_blocks: content_len.div_ceil(BLOCK_SIZE), ..PackLayout::default() };
+    let mut spans: Vec<(u64, u64, &str)> = Vec::new();
+    for ent in entries {
+        if ent.raw_size == 0 {
+            layout.empty_entries += 1;
+        } else {
+            spans.push((ent.offset as u64, ent.raw_size as u64, &ent.name));
+        }
+    }
+    // Largest first within a block so a shared run reports its biggest owner's size.
+    spans.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)).then(a.2.cmp(b.2)));
+
+    let mut next_free = 0u64;
+    for (first, size, name) in spans {
+        let blocks = size.div_ceil(BLOCK_SIZE);
+        if (first * BLOCK_SIZE + size) > content_len {
+            layout.past_end += 1;
+        }
+        if let Some(last) = layout.rows.last_mut() {
+            if !last.owners.is_empty() && last.first_block == first && last.blocks == blocks {
+                last.owners.push(name.to_string());
+                layout.reused_entries += 1;
+                continue;
+            }
+        }
+        if first > next_free {
+            layout.rows.push(LayoutRow { first_block: next_free, blocks: first - next_free, used: 0, owners: Vec::new(), overlaps: false });
+            layout.gap_blocks += first - next_free;
+            layout.gap_bytes += (first - next_free) * BLOCK_SIZE;
+        }
+        let overlaps = first < next_free;
+        if overlaps {
+            layout.reused_entries += 1;
+        } else {
+            // A trailing block the file doesn't fully contain isn't padding on disk.
+            let end = ((first + blocks) * BLOCK_SIZE).min(content_len.max(first * BLOCK_SIZE + size));
+            layout.padding += end - first * BLOCK_SIZE - size;
+        }
+        layout.rows.push(LayoutRow { first_block: first, blocks, used: size, owners: vec![name.to_string()], overlaps });
+        next_free = next_free.max(first + blocks);
+    }
+    if next_free * BLOCK_SIZE < content_len {
+        let blocks = layout.total_blocks - next_free;
+        layout.rows.push(LayoutRow { first_block: next_free, blocks, used: 0, owners: Vec::new(), overlaps: false });
+        layout.gap_blocks += blocks;
+        layout.gap_bytes += content_len - next_free * BLOCK_SIZE;
+    }
+    layout
+}
+
+/// Print a `pack_layout` map: one line per run of blocks, then the totals.
+pub fn write_layout(layout: &PackLayout, content_offset: u64, out: &mut dyn Write) -> Result<(), Error> {
+    writeln!(out, "Layout ({}-byte blocks from 0x{:X}):", BLOCK_SIZE, content_offset)?;
+    for row in &layout.rows {
+        let range = if row.blocks <= 1 {
+            row.first_block.to_string()
+        } else {
+            format!("{}-{}", row.first_block, row.first_block + row.blocks - 1)
+        };
+        if row.owners.is_empty() {
+            writeln!(out, "  {:<17} {:>12}  <gap, {} block(s)>", range, "-", row.blocks)?;
+        } else {
+            let note = match (row.owners.len() > 1, row.overlaps) {
+                (_, true) => "  (overlaps earlier data)",
+                (true, false) => "  (shared)",
+                _ => "",
+            };
+            writeln!(out, "  {:<17} {:>12}  {}{}", range, row.used, row.owners.join(", "), note)?;
+        }
+    }
+    writeln!(out)?;
+    writeln!(out, "Blocks:         {} ({} in gaps)", layout.total_blocks, layout.gap_blocks)?;
+    writeln!(out, "Slack:          {} bytes ({} padding, {} in gaps)", layout.slack(), layout.padding, layout.gap_bytes)?;
+    writeln!(out, "Reused entries: {}", layout.reused_entries)?;
+    writeln!(out, "Empty entries:  {}", layout.empty_entries)?;
+    if layout.past_end > 0 {
+        writeln!(out, "Past the end:   {} entries run past the content area", layout.past_end)?;
+    }
+    Ok(())
+}
+
+/// One directory of a `size_tree`. `size` and `files` include everything below it.
+#[derive(Debug, Default)]
+pub struct SizeNode {
+    pub size: u64,
+    pub files: usize,
+    pub children: BTreeMap<String, SizeNode>,
+}
+
+/// Files sitting directly in a directory that also has subdirectories are grouped
+/// under this pseudo-child so the children of a node add up to its total.
+pub const FILES_NODE: &str = "(files)";
+
+/// Sum entry sizes (stored or original) into a directory tree at most `depth` levels deep.
+pub fn size_tree(entries: &[FileEntry], stored: bool, depth: usize) -> SizeNode {
+    let mut root = SizeNode::default();
+    for ent in entries.iter().filter(|e| !e.is_dir_marker()) {
+        let size = if stored { ent.raw_size } else { ent.original_size } as u64;
+        let parts: Vec<&str> = ent.name.split(['\\', '/']).filter(|p| !p.is_empty()).collect();
+        let dirs = &parts[..parts.len().saturating_sub(1)];
+        let mut node = &mut root;
+        node.size += size;
+        node.files += 1;
+        for dir in dirs.iter().take(depth) {
+            node = node.children.entry(dir.to_lowercase()).or_default();
+            node.size += size;
+            node.files += 1;
+        }
+        if dirs.len() < depth {
+            let files = node.children.entry(FILES_NODE.to_string()).or_default();
+            files.size += size;
+            files.files += 1;
+        }
+    }
+    // A "(files)" node is only useful next to real subdirectories.
+    fn prune(node: &mut SizeNode) {
+        if node.children.len() == 1 && node.children.contains_key(FILES_NODE) {
+            node.children.clear();
+        }
+        node.children.values_mut().for_each(prune);
+    }
+    prune(&mut root);
+    root
+}
+
+/// (extension, size, file count) sorted by size, largest first.
+pub fn extension_sizes(entries: &[FileEntry], stored: bool) -> Vec<(String, u64, usize)> {
+    let mut by_ext: BTreeMap<String, (u64, usize)> = BTreeMap::new();
+    for ent in entries.iter().filter(|e| !e.is_dir_marker()) {
+        let slot = by_ext.entry(extension_of(&ent.name)).or_default();
+        slot.0 += if stored { ent.raw_size } else { ent.original_size } as u64;
+        slot.1 += 1;
+    }
+    let mut list: Vec<_> = by_ext.into_iter().map(|(ext, (size, files))| (ext, size, files)).collect();
+    list.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    list
+}
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
+
+fn percent_bar(part: u64, total: u64, width: usize) -> (f64, String) {
+    let frac = if total == 0 { 0.0 } else { part as f64 / total as f64 };
+    let filled = ((frac * width as f64).round() as usize).min(width);
+    (frac * 100.0, format!("[{}{}]", "#".repeat(filled), ".".repeat(width - filled)))
+}
+
+const BAR_WIDTH: usize = 30;
+
+fn render_children(node: &SizeNode, indent: usize, top: usize, out: &mut dyn Write) -> Result<(), Error> {
+    let mut children: Vec<(&String, &SizeNode)> = node.children.iter().collect();
+    children.sort_by(|a, b| b.1.size.cmp(&a.1.size).then_with(|| a.0.cmp(b.0)));
+    for (name, child) in children.iter().take(top) {
+        let (pct, bar) = percent_bar(child.size, node.size, BAR_WIDTH);
+        let label = format!("{}{}", "  ".repeat(indent), name);
+        writeln!(out, "{:<40} {:>10} {:>6.1}% {} {} files", label, human_size(child.size), pct, bar, child.files)?;
+        render_children(child, indent + 1, top, out)?;
+    }
+    if children.len() > top {
+        let rest: Vec<_> = children[top..].iter().collect();
+        let size: u64 = rest.iter().map(|(_, c)| c.size).sum();
+        let (pct, bar) = percent_bar(size, node.size, BAR_WIDTH);
+        let label = format!("{}({} more)", "  ".repeat(indent), rest.len());
+        writeln!(out, "{:<40} {:>10} {:>6.1}% {}", label, human_size(size), pct, bar)?;
+    }
+    Ok(())
+}
+
+/// Print a percent-bar breakdown of `input` by directory (down to `depth` levels,
+/// `top` largest per level) and by extension. Sizes are the stored (compressed)
+/// sizes when `stored` is set, otherwise the extracted sizes.
+pub fn run_tree_size(
+    input: &str,
+    cli_key: Option<String>,
+    loaded_salts: &[String],
+    settings: &common::PackSettings,
+    depth: usize,
+    top: usize,
+    stored: bool,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let reader = PackReader::open_with_settings(input, cli_key, loaded_salts, settings)?;
+    let entries = reader.entries();
+    let tree = size_tree(entries, stored, depth);
+
+    writeln!(out, "{}: {} in {} files ({} size)", reader.path, human_size(tree.size), tree.files, if stored { "stored" } else { "extracted" })?;
+    writeln!(out)?;
+    writeln!(out, "By directory:")?;
+    render_children(&tree, 1, top, out)?;
+    writeln!(out)?;
+    writeln!(out, "By extension:")?;
+    let exts = extension_sizes(entries, stored);
+    for (ext, size, files) in exts.iter().take(top) {
+        let (pct, bar) = percent_bar(*size, tree.size, BAR_WIDTH);
+        writeln!(out, "  {:<38} {:>10} {:>6.1}% {} {} files", ext, human_size(*size), pct, bar, files)?;
+    }
+    if exts.len() > top {
+        let size: u64 = exts[top..].iter().map(|e| e.1).sum();
+        let (pct, bar) = percent_bar(size, tree.size, BAR_WIDTH);
+        writeln!(out, "  {:<38} {:>10} {:>6.1}% {}", format!("({} more)", exts.len() - top), human_size(size), pct, bar)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,234 @@
+// key_cache.rs - Persistent record of which salts unlocked which archives
+
+use crate::encryption;
+use anyhow::Error;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub const KEY_CACHE_FILE: &str = "key_cache.json";
+
+/// Success counters for one salt. `names` counts hits per archive file name (lowercased).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SaltStats {
+    #[serde(default)]
+    pub hits: u64,
+    #[serde(default)]
+    pub names: BTreeMap<String, u64>,
+}
+
+/// The combination that opened one archive. Trusted only while the file keeps the
+/// recorded size and modification time (seconds since the epoch).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackHit {
+    pub size: u64,
+    pub mtime: u64,
+    pub name_variant: String,
+    pub header_salt: String,
+    pub entries_salt: String,
+    pub header_offset: u64,
+    pub iv0: u32,
+    pub mode: encryption::Snow2Mode,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyCache {
+    #[serde(default)]
+    pub salts: BTreeMap<String, SaltStats>,
+    /// Last working combination per archive, keyed by its absolute path.
+    #[serde(default)]
+    pub packs: BTreeMap<String, PackHit>,
+}
+
+impl KeyCache {
+    /// Load a cache file; a missing or unreadable file yields an empty cache.
+    pub fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                warn!("[KEY_CACHE] Ignoring unreadable cache '{}': {}", path.display(), e);
+                KeyCache::default()
+            }),
+            Err(_) => KeyCache::default(),
+        }
+    }
+
+    /// Write the cache through a temporary file named after this process, so concurrent
+    /// saves never rename each other's half-written files into place.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        static SAVES: AtomicU64 = AtomicU64::new(0);
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() { std::fs::create_dir_all(parent)?; }
+        }
+        let tmp = path.with_extension(format!("json.{}-{}.tmp", std::process::id(), SAVES.fetch_add(1, Ordering::Relaxed)));
+        let written = std::fs::write(&tmp, serde_json::to_string_pretty(self)?).and_then(|_| std::fs::rename(&tmp, path));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        Ok(written?)
+    }
+
+    /// Add the counters and pack records of `other` to this cache; `other`'s pack records
+    /// replace older ones for the same path.
+    pub fn merge(&mut self, other: &KeyCache) {
+        for (salt, stats) in &other.salts {
+            let mine = self.salts.entry(salt.clone()).or_default();
+            mine.hits += stats.hits;
+            for (name, hits) in &stats.names {
+                *mine.names.entry(name.clone()).or_insert(0) += hits;
+            }
+        }
+        for (path, hit) in &other.packs {
+            self.packs.insert(path.clone(), hit.clone());
+        }
+    }
+
+    /// Merge this cache (the records of one run) into the file at `path` as it is now,
+    /// keeping what other processes saved since it was loaded. Returns the merged cache.
+    pub fn merge_into_file(&self, path: &Path) -> Result<KeyCache, Error> {
+        let mut merged = KeyCache::load(path);
+        merged.merge(self);
+        merged.save(path)?;
+        Ok(merged)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.salts.is_empty() && self.packs.is_empty()
+    }
+
+    /// Count a successful unlock of `archive_name` for the header salt and,
+    /// when it differs, the entries salt.
+    pub fn record_success(&mut self, archive_name: &str, header_salt: &str, entries_salt: &str) {
+        let name = archive_name.to_lowercase();
+        for salt in [header_salt, entries_salt] {
+            let stats = self.salts.entry(salt.to_string()).or_default();
+            stats.hits += 1;
+            *stats.names.entry(name.clone()).or_insert(0) += 1;
+            if header_salt == entries_salt { break; }
+        }
+    }
+
+    /// The combination recorded for `path`, if the file still has the recorded size and mtime.
+    pub fn pack_hit(&self, path: &str, size: u64, mtime: u64) -> Option<&PackHit> {
+        self.packs.get(path).filter(|hit| hit.size == size && hit.mtime == mtime)
+    }
+
+    pub fn record_pack(&mut self, path: &str, hit: PackHit) {
+        self.packs.insert(path.to_string(), hit);
+    }
+
+    /// Stable-sort `keys` so salts that unlocked `archive_name` before come first,
+    /// then salts by overall success count. Unknown salts keep their relative order.
+    pub fn prioritize(&self, archive_name: &str, keys: &mut [String]) {
+        let name = archive_name.to_lowercase();
+        keys.sort_by_cached_key(|salt| {
+            let stats = self.salts.get(salt);
+            let same_name = stats.and_then(|s| s.names.get(&name)).copied().unwrap_or(0);
+            let total = stats.map_or(0, |s| s.hits);
+            (std::cmp::Reverse(same_name), std::cmp::Reverse(total))
+        });
+    }
+}
+
+/// Default cache location, next to the GUI config: `%APPDATA%\mabi-pack2` or `~/.mabi-pack2`.
+pub fn default_path() -> PathBuf {
+    #[cfg(windows)]
+    { PathBuf::from(std::env::var("APPDATA").unwrap_or_else(|_| ".".into())).join("mabi-pack2").join(KEY_CACHE_FILE) }
+    #[cfg(not(windows))]
+    { PathBuf::from(std::env::var("HOME").unwrap_or_else(|_| ".".into())).join(".mabi-pack2").join(KEY_CACHE_FILE) }
+}
+
+/// The cache searches use: its file, what was loaded plus this run's records, and the
+/// records not saved yet.
+struct ActiveCache {
+    path: PathBuf,
+    cache: KeyCache,
+    pending: KeyCache,
+}
+
+static ACTIVE_CACHE: Lazy<Mutex<Option<ActiveCache>>> = Lazy::new(|| Mutex::new(None));
+
+/// Turn on salt prioritization for the key searches in this process, backed by `path`.
+/// Until this is called, `prioritize` and `record_success` are no-ops. Records are kept
+/// in memory until `flush`.
+pub fn enable(path: &Path) {
+    flush();
+    let cache = KeyCache::load(path);
+    debug!("[KEY_CACHE] Loaded {} salt record(s) from '{}'", cache.salts.len(), path.display());
+    *ACTIVE_CACHE.lock().unwrap() = Some(ActiveCache { path: path.to_path_buf(), cache, pending: KeyCache::default() });
+}
+
+/// Save what was recorded so far and stop using the cache.
+pub fn disable() {
+    flush();
+    *ACTIVE_CACHE.lock().unwrap() = None;
+}
+
+/// Merge the records of this run into the cache file (see `KeyCache::merge_into_file`).
+/// Call it once at the end of a run or batch.
+pub fn flush() {
+    let mut guard = ACTIVE_CACHE.lock().unwrap();
+    let Some(active) = guard.as_mut().filter(|a| !a.pending.is_empty()) else { return };
+    match active.pending.merge_into_file(&active.path) {
+        Ok(merged) => {
+            debug!("[KEY_CACHE] Saved '{}'", active.path.display());
+            active.cache = merged;
+            active.pending = KeyCache::default();
+        }
+        Err(e) => warn!("[KEY_CACHE] Failed to save '{}': {}", active.path.display(), e),
+    }
+}
+
+/// Reorder `keys` using the active cache, if any.
+pub fn prioritize(archive_name: &str, keys: &mut [String]) {
+    if let Some(active) = ACTIVE_CACHE.lock().unwrap().as_ref() {
+        active.cache.prioritize(archive_name, keys);
+    }
+}
+
+/// Cache key and (size, mtime) of the archive at `path`, or `None` if it can't be inspected.
+fn pack_stamp(path: &str) -> Option<(String, u64, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    let key = std::fs::canonicalize(path).ok()?.to_string_lossy().into_owned();
+    Some((key, meta.len(), mtime))
+}
+
+/// The combination the active cache recorded for the archive at `path`, if it is still
+/// the same file (size and mtime).
+pub fn cached_pack(path: &str) -> Option<PackHit> {
+    let guard = ACTIVE_CACHE.lock().unwrap();
+    let active = guard.as_ref()?;
+    let (key, size, mtime) = pack_stamp(path)?;
+    active.cache.pack_hit(&key, size, mtime).cloned()
+}
+
+/// Remember the combination that opened the archive at `path` in the active cache, if any.
+pub fn record_pack(path: &str, name_variant: &str, header_salt: &str, entries_salt: &str, header_offset: u64, iv0: u32, mode: encryption::Snow2Mode) {
+    if let Some(active) = ACTIVE_CACHE.lock().unwrap().as_mut() {
+        let Some((key, size, mtime)) = pack_stamp(path) else { return };
+        let hit = PackHit {
+            size,
+            mtime,
+            name_variant: name_variant.to_string(),
+            header_salt: header_salt.to_string(),
+            entries_salt: entries_salt.to_string(),
+            header_offset,
+            iv0,
+            mode,
+        };
+        active.cache.record_pack(&key, hit.clone());
+        active.pending.record_pack(&key, hit);
+    }
+}
+
+/// Record a successful search in the active cache, if any. `flush` saves it.
+pub fn record_success(archive_name: &str, header_salt: &str, entries_salt: &str) {
+    if let Some(active) = ACTIVE_CACHE.lock().unwrap().as_mut() {
+        active.cache.record_success(archive_name, header_salt, entries_salt);
+        active.pending.record_success(archive_name, header_salt, entries_salt);
+    }
+}
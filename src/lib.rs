@@ -1,12 +1,50 @@
+//! Reading and writing Mabinogi `.it` packs.
+//!
+//! The top-level functions cover the common cases; the modules hold the rest
+//! (`pack` for writing, `reader::PackReader` for random access, `verify`, `patch`...).
+//!
+//! ```no_run
+//! mabi_pack2::pack_folder("data_00000", "data_00000.it", "my salt")?;
+//! let entries = mabi_pack2::list_entries("data_00000.it", None)?;
+//! println!("{} entries", entries.len());
+//! let summary = mabi_pack2::extract_all("data_00000.it", "out", None)?;
+//! println!("{} files written", summary.entries);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
+pub mod backup;
+pub mod checksum;
+pub mod codec;
 pub mod common;
 pub mod common_ext;
+pub mod daemon;
+pub mod dedupe;
+pub mod describe;
+pub mod doctor;
+pub mod edit;
 pub mod encryption;
 pub mod extract;
+pub mod hook;
+pub mod index;
+pub mod info;
+pub mod key_cache;
+pub mod names;
 pub mod list;
 pub mod pack;
+pub mod pack_lock;
 pub mod pack_v1;
 pub mod patch;
 pub mod pmg;
+pub mod reader;
+pub mod remote;
+pub mod run_summary;
+pub mod snapshot;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod verify;
+pub mod vfs;
 
 pub const SALTS_URL: &str = "https://shaggyze.website/files/salts.txt";
 
@@ -49,10 +87,81 @@ pub const HARDCODED_SALTS: &[&str] = &[
 ];
 
 use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
 use std::sync::Mutex;
 
 static CACHED_SALTS: Lazy<Mutex<Option<Vec<String>>>> = Lazy::new(|| Mutex::new(None));
+/// Lowercased alias -> salt, filled from `salts.txt` / remote lines of the form `SALT<TAB>alias`.
+static SALT_ALIASES: Lazy<Mutex<BTreeMap<String, String>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Parse one salts list line: `SALT` or `SALT<TAB>alias`. Blank lines and `#` comments yield `None`.
+pub fn parse_salt_line(line: &str) -> Option<(String, Option<String>)> {
+    let (salt, alias) = match line.split_once('\t') {
+        Some((salt, alias)) => (salt.trim(), Some(alias.trim())),
+        None => (line.trim(), None),
+    };
+    if salt.is_empty() || salt.starts_with('#') {
+        return None;
+    }
+    Some((salt.to_string(), alias.filter(|a| !a.is_empty()).map(|a| a.to_string())))
+}
+
+/// Add the salts from `lines` to `salts` (skipping duplicates) and record their aliases.
+fn merge_salt_lines<'a>(lines: impl Iterator<Item = &'a str>, salts: &mut Vec<String>) {
+    let mut aliases = SALT_ALIASES.lock().unwrap();
+    for (salt, alias) in lines.filter_map(parse_salt_line) {
+        if let Some(alias) = alias {
+            aliases.entry(alias.to_lowercase()).or_insert_with(|| salt.clone());
+        }
+        if !salts.contains(&salt) {
+            salts.push(salt);
+        }
+    }
+}
+
+/// The remote salts list (`SALTS_URL`); never fetched with `settings.untrusted`.
+fn fetch_remote_salts(settings: &common::PackSettings) -> Option<String> {
+    if settings.untrusted {
+        return None;
+    }
+    let client = reqwest::blocking::Client::builder()
+        .timeout(std::time::Duration::from_secs(3))
+        .build()
+        .ok()?;
+    let response = client.get(SALTS_URL).send().ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    response.text().ok()
+}
 
+/// Resolve a `--key-id` alias to its salt, checking aliases already loaded, then the
+/// local `salts.txt`, then the remote list (unless `settings.untrusted`).
+pub fn resolve_key_id(id: &str, settings: &common::PackSettings) -> Result<String, anyhow::Error> {
+    let lookup = |id: &str| SALT_ALIASES.lock().unwrap().get(&id.to_lowercase()).cloned();
+    if let Some(salt) = lookup(id) {
+        return Ok(salt);
+    }
+    let mut scratch = Vec::new();
+    if let Ok(text) = std::fs::read_to_string("salts.txt") {
+        merge_salt_lines(text.lines(), &mut scratch);
+        if let Some(salt) = lookup(id) {
+            return Ok(salt);
+        }
+    }
+    if let Some(text) = fetch_remote_salts(settings) {
+        merge_salt_lines(text.lines(), &mut scratch);
+        if let Some(salt) = lookup(id) {
+            return Ok(salt);
+        }
+    }
+    Err(anyhow::Error::msg(format!("Unknown key id '{}'. Add a line 'SALT<TAB>{}' to salts.txt.", id, id)))
+}
+
+/// The salts the CLI searches with. The first call returns `HARDCODED_SALTS` at once and
+/// starts a background thread that adds `salts.txt` from the current folder and the
+/// remote list (`SALTS_URL`); later calls see what it has added so far.
+/// `load_salts_with_settings` stays offline for untrusted input.
 pub fn load_salts() -> Vec<String> {
     let mut cache = CACHED_SALTS.lock().unwrap();
     if cache.is_none() {
@@ -69,34 +178,13 @@ pub fn load_salts() -> Vec<String> {
             if local_path.exists() {
                 if let Ok(file) = StdFile::open(local_path) {
                     let reader = StdBufReader::new(file);
-                    for line in reader.lines() {
-                        if let Ok(salt) = line {
-                            let s = salt.trim().to_string();
-                            if !s.is_empty() && !s.starts_with('#') && !salts.contains(&s) {
-                                salts.push(s);
-                            }
-                        }
-                    }
+                    let lines: Vec<String> = reader.lines().map_while(Result::ok).collect();
+                    merge_salt_lines(lines.iter().map(|l| l.as_str()), &mut salts);
                 }
             }
 
-            let client = reqwest::blocking::Client::builder()
-                .timeout(std::time::Duration::from_secs(3))
-                .build();
-
-            if let Ok(c) = client {
-                if let Ok(response) = c.get(SALTS_URL).send() {
-                    if response.status().is_success() {
-                        if let Ok(text) = response.text() {
-                            for line in text.lines() {
-                                let s = line.trim().to_string();
-                                if !s.is_empty() && !s.starts_with('#') && !salts.contains(&s) {
-                                    salts.push(s);
-                                }
-                            }
-                        }
-                    }
-                }
+            if let Some(text) = fetch_remote_salts(&common::PackSettings::default()) {
+                merge_salt_lines(text.lines(), &mut salts);
             }
 
             let mut cache = CACHED_SALTS.lock().unwrap();
@@ -111,6 +199,50 @@ pub fn load_salts() -> Vec<String> {
     HARDCODED_SALTS.iter().map(|s| s.to_string()).collect()
 }
 
+/// `load_salts` for `settings`. With `untrusted` nothing is fetched: the salts are
+/// `HARDCODED_SALTS` plus the local `salts.txt`, read right away.
+pub fn load_salts_with_settings(settings: &common::PackSettings) -> Vec<String> {
+    if !settings.untrusted {
+        return load_salts();
+    }
+    let mut salts = builtin_salts();
+    if let Ok(text) = std::fs::read_to_string("salts.txt") {
+        merge_salt_lines(text.lines(), &mut salts);
+    }
+    salts
+}
+
+/// `HARDCODED_SALTS` as a list. Unlike `load_salts` this reads no file and never goes
+/// online, so the top-level functions below behave the same on every call.
+pub fn builtin_salts() -> Vec<String> {
+    HARDCODED_SALTS.iter().map(|s| s.to_string()).collect()
+}
+
+/// Open a pack for reading entries, trying `key` first and then the built-in salts
+/// (`builtin_salts`). Pass other salts to `reader::PackReader::open` directly. The key
+/// search depends on the file name, so don't rename packs.
+pub fn open_pack(path: &str, key: Option<&str>) -> Result<reader::PackReader, anyhow::Error> {
+    reader::PackReader::open(path, key.map(str::to_string), &builtin_salts())
+}
+
+/// The entries of the pack at `path`, in archive order.
+pub fn list_entries(path: &str, key: Option<&str>) -> Result<Vec<common::FileEntry>, anyhow::Error> {
+    Ok(open_pack(path, key)?.entries().to_vec())
+}
+
+/// Extract every entry of the pack at `path` into `output_dir` with the default options.
+/// Use `extract::run_extract_with_options` for filters, progress and the rest.
+pub fn extract_all(path: &str, output_dir: &str, key: Option<&str>) -> Result<common::OperationSummary, anyhow::Error> {
+    extract::run_extract_with_options(path, output_dir, key.map(str::to_string), &builtin_salts(), &extract::ExtractOptions::default())
+}
+
+/// Pack every file under `input_dir` into a new pack at `output` with the default options,
+/// encrypted with `salt`. The keys derive from the file name of `output`, so name it as the
+/// game expects. Use `pack::run_pack_with_options` for compression, prefixes and the rest.
+pub fn pack_folder(input_dir: &str, output: &str, salt: &str) -> Result<common::OperationSummary, anyhow::Error> {
+    pack::run_pack_with_options(input_dir, output, salt, &pack::PackOptions::default(), None)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -123,7 +255,7 @@ mod tests {
     fn test_snow2_roundtrip() {
         let key = [0u8; 16];
         let mut data = [0xAA; 16];
-        let original = data.clone();
+        let original = data;
         encryption::snow2_encrypt(&key, 1, &mut data);
         encryption::snow2_decrypt(&key, 1, &mut data);
         assert_eq!(data, original);
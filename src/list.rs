@@ -1,4 +1,6 @@
-use crate::common;
+use crate::{common, common_ext, key_cache, run_summary};
+use crate::reader::PackReader;
+use crate::extract::make_regex;
  
 
 use anyhow::Error;
@@ -7,6 +9,7 @@ use std::fs::File as StdFile;
 use std::io::{self, Cursor, Write};
 use log::{debug, info, warn};
 use memmap2::Mmap;
+use regex::Regex;
 
 pub fn perform_listing(writer: &mut dyn Write, names: &[String]) -> Result<(), Error> {
     for name in names {
@@ -15,12 +18,34 @@ pub fn perform_listing(writer: &mut dyn Write, names: &[String]) -> Result<(), E
     Ok(())
 }
 
+/// Keep the entries matching `filters` (all of them if there are none).
+pub fn filter_entries(entries: Vec<common::FileEntry>, filters: &[Regex]) -> Vec<common::FileEntry> {
+    if filters.is_empty() {
+        return entries;
+    }
+    entries.into_iter().filter(|e| filters.iter().any(|re| re.is_match(&e.name))).collect()
+}
+
 pub fn run_list_with_key_search(
     input: &str,
     cli_key: Option<String>,
     loaded_salts: &[String],
     output_file_path: Option<&str>,
-) -> Result<(), Error> {
+) -> Result<common::OperationSummary, Error> {
+    run_list_with_key_search_traced(input, cli_key, loaded_salts, &common::PackSettings::default(), output_file_path, None, Vec::new())
+}
+
+/// `run_list_with_key_search` with `settings` that records every probe of the salt search
+/// into `search_trace` and lists only the entries matching `filters_cli`.
+pub fn run_list_with_key_search_traced(
+    input: &str,
+    cli_key: Option<String>,
+    loaded_salts: &[String],
+    settings: &common::PackSettings,
+    output_file_path: Option<&str>,
+    search_trace: Option<&common::SearchTrace>,
+    filters_cli: Vec<String>,
+) -> Result<common::OperationSummary, Error> {
     debug!("[LIST_SEARCH] Starting search for archive: '{}'", input);
 
     let mut keys_to_try: Vec<String> = Vec::new();
@@ -28,64 +53,70 @@ pub fn run_list_with_key_search(
     for salt in loaded_salts {
         if !keys_to_try.contains(salt) { keys_to_try.push(salt.clone()); }
     }
+    let cache_name = common::get_final_file_name(input).unwrap_or_default();
+    key_cache::prioritize(&cache_name, &mut keys_to_try[usize::from(cli_key.is_some())..]);
 
+    let filters = make_regex(filters_cli, settings)?;
     let file = StdFile::open(input)?;
     let mmap = unsafe { Mmap::map(&file)? };
 
     if mmap.len() >= 4 {
         if &mmap[0..4] == b"MABI" {
             debug!("[LIST_SEARCH] Legacy MABI detected.");
-            let entries = crate::pack_v1::run_list_v1_data(input)?;
+            let entries = filter_entries(crate::pack_v1::run_list_v1_data(input, settings)?, &filters);
             let mut writer: Box<dyn Write> = if let Some(out_path) = output_file_path {
                 Box::new(StdFile::create(out_path)?)
             } else {
                 Box::new(io::stdout())
             };
-            let names: Vec<String> = entries.into_iter().map(|e| e.name).collect();
-            return perform_listing(&mut writer, &names);
+            let names: Vec<String> = entries.iter().map(|e| e.name.clone()).collect();
+            perform_listing(&mut writer, &names)?;
+            return Ok(common::OperationSummary::legacy("mabi", &entries));
         }
         if &mmap[0..4] == b"PACK" {
             // Try Logue format first
-            if let Ok(entries) = crate::pack_v1::run_list_logue_data(input) {
+            if let Ok(entries) = crate::pack_v1::run_list_logue_data(input, settings) {
                 debug!("[LIST_SEARCH] Logue/MabinogiResource .pack detected.");
+                let entries = filter_entries(entries, &filters);
                 let mut writer: Box<dyn Write> = if let Some(out_path) = output_file_path {
                     Box::new(StdFile::create(out_path)?)
                 } else {
                     Box::new(io::stdout())
                 };
-                let names: Vec<String> = entries.into_iter().map(|e| e.name).collect();
-                return perform_listing(&mut writer, &names);
+                let names: Vec<String> = entries.iter().map(|e| e.name.clone()).collect();
+                perform_listing(&mut writer, &names)?;
+                return Ok(common::OperationSummary::legacy("logue", &entries));
             }
             
             // Standard .pack
             debug!("[LIST_SEARCH] Legacy Standard .pack detected.");
-            let entries = crate::pack_v1::run_list_v1_data(input)?;
+            let entries = filter_entries(crate::pack_v1::run_list_v1_data(input, settings)?, &filters);
             let mut writer: Box<dyn Write> = if let Some(out_path) = output_file_path {
                 Box::new(StdFile::create(out_path)?)
             } else {
                 Box::new(io::stdout())
             };
-            let names: Vec<String> = entries.into_iter().map(|e| e.name).collect();
-            return perform_listing(&mut writer, &names);
+            let names: Vec<String> = entries.iter().map(|e| e.name.clone()).collect();
+            perform_listing(&mut writer, &names)?;
+            return Ok(common::OperationSummary::legacy("pack", &entries));
         }
     }
 
-    let it_name = common::get_final_file_name(input).unwrap_or_default();
-    let name_variants = vec![it_name.clone(), "data.it".to_string(), "".to_string()];
+    let name_variants = common::key_name_variants(input, None, settings)?;
 
     let fname_for_log = input;
 
     // Two-phase search helper: header key located, now find the entries salt.
     // Tries header_skey first (common case), then all other salts.
-    let try_entries = |name: &str, header_skey: &str, h_off: u64, iv0: u32, mode: crate::encryption::Snow2Mode| -> Option<(Vec<crate::common::FileEntry>, String, String)> {
+    let try_entries = |name: &str, header_skey: &str, h_off: u64, iv0: u32, mode: crate::encryption::Snow2Mode| -> Option<(Vec<crate::common::FileEntry>, String, String, u64)> {
         debug!("[LIST_SEARCH] Header VALIDATED with skey: '{}'. Now trying entries...", header_skey);
         let entries_candidates: Vec<&str> = std::iter::once(header_skey)
             .chain(keys_to_try.iter().filter(|s| s.as_str() != header_skey).map(|s| s.as_str()))
             .collect();
         for entries_skey in entries_candidates {
             let mut rd2 = Cursor::new(&mmap[..]);
-            if let Ok((_, entries, _)) = common::read_meta_iv_mode_two_key(name, header_skey, entries_skey, &mut rd2, h_off, iv0, mode) {
-                return Some((entries, header_skey.to_string(), entries_skey.to_string()));
+            if let Ok((_, entries, c_off)) = common::read_meta_iv_mode_two_key_traced(name, header_skey, entries_skey, &mut rd2, h_off, iv0, mode, settings, search_trace) {
+                return Some((entries, header_skey.to_string(), entries_skey.to_string(), c_off));
             }
         }
         None
@@ -93,15 +124,21 @@ pub fn run_list_with_key_search(
 
     debug!("[LIST_SEARCH] Will attempt listing with {} unique salt key(s).", keys_to_try.len());
 
+    let cached = search_trace.is_none().then(|| common_ext::cached_key_hit(&mmap, input, cli_key.as_deref(), settings)).flatten()
+        .map(|hit| (hit.entries, hit.header_salt, hit.entries_salt, hit.header_offset, hit.name_variant, hit.iv0, hit.mode, hit.content_offset));
+    let from_cache = cached.is_some();
+
     // Phase 1: Try CLI key specifically if provided (Highest Priority)
-    let result = if let Some(ref specific_key) = cli_key {
+    let result = if cached.is_some() {
+        cached
+    } else if let Some(ref specific_key) = cli_key {
         debug!("[LIST_SEARCH] Prioritizing provided key: {}", specific_key);
         name_variants.iter().find_map(|name| {
             debug!("[LIST_SEARCH] Trying HEADER skey: '{}' for file '{}'", specific_key, fname_for_log);
             let mut rd = Cursor::new(&mmap[..]);
-            if let Ok(Some((_header, h_off, iv0, mode))) = common::find_header_only(&mut rd, name, specific_key) {
-                if let Some((entries, h_key, e_key)) = try_entries(name, specific_key, h_off, iv0, mode) {
-                    return Some((entries, h_key, e_key, h_off, name.clone(), iv0));
+            if let Ok(Some((_header, h_off, iv0, mode))) = common::find_header_only_traced(&mut rd, name, specific_key, search_trace) {
+                if let Some((entries, h_key, e_key, c_off)) = try_entries(name, specific_key, h_off, iv0, mode) {
+                    return Some((entries, h_key, e_key, h_off, name.clone(), iv0, mode, c_off));
                 }
             }
             None
@@ -112,31 +149,326 @@ pub fn run_list_with_key_search(
 
     // Phase 2: Exhaustive parallel search
     let result = result.or_else(|| {
-        name_variants.into_iter().find_map(|name| {
-            keys_to_try.par_iter().find_map_any(|header_skey| {
+        let progress = common::SearchProgress::new((name_variants.len() * keys_to_try.len()) as u64);
+        name_variants.iter().find_map(|name| {
+            keys_to_try.par_iter().find_map_first(|header_skey| {
                 debug!("[LIST_SEARCH] Trying HEADER skey: '{}' for file '{}'", header_skey, fname_for_log);
                 let mut rd = Cursor::new(&mmap[..]);
-                if let Ok(Some((_header, h_off, iv0, mode))) = common::find_header_only(&mut rd, &name, header_skey) {
-                    if let Some((entries, h_key, e_key)) = try_entries(&name, header_skey, h_off, iv0, mode) {
-                        return Some((entries, h_key, e_key, h_off, name.clone(), iv0));
+                if let Ok(Some((_header, h_off, iv0, mode))) = common::find_header_only_traced(&mut rd, name, header_skey, search_trace) {
+                    if let Some((entries, h_key, e_key, c_off)) = try_entries(name, header_skey, h_off, iv0, mode) {
+                        return Some((entries, h_key, e_key, h_off, name.clone(), iv0, mode, c_off));
                     }
                 }
+                progress.tick(header_skey, name);
                 None
             })
         })
     });
 
-    if let Some((entries, h_key, e_key, final_offset, _variant, iv0)) = result {
+    // Phase 3: --scan-offsets / --auto-escalate
+    let result = result.or_else(|| {
+        common_ext::escalate_search(&mmap, &name_variants, &keys_to_try, settings)
+            .map(|hit| (hit.entries, hit.header_salt, hit.entries_salt, hit.header_offset, hit.name_variant, hit.iv0, hit.mode, hit.content_offset))
+    });
+
+    if let Some((entries, h_key, e_key, final_offset, variant, iv0, mode, content_offset)) = result {
         info!("[LIST_SEARCH] >>> SUCCESS! HEADER='{}', ENTRIES='{}', Offset=0x{:X}, IV={}", h_key, e_key, final_offset, iv0);
+        common::check_entry_policy(&entries, settings)?;
+        run_summary::record_key(input, mmap.len() as u64, &common_ext::KeySearchHit {
+            entries: Vec::new(),
+            header_salt: h_key.clone(),
+            entries_salt: e_key.clone(),
+            name_variant: variant.clone(),
+            iv0,
+            header_offset: final_offset,
+            mode,
+            content_offset,
+        });
+        if !from_cache {
+            common::report_name_guess(input, &variant, &h_key, &e_key, settings);
+            key_cache::record_success(&cache_name, &h_key, &e_key);
+            key_cache::record_pack(input, &variant, &h_key, &e_key, final_offset, iv0, mode);
+        }
         let mut writer: Box<dyn Write> = if let Some(out_path) = output_file_path {
             Box::new(StdFile::create(out_path)?)
         } else {
             Box::new(io::stdout())
         };
-        let names: Vec<String> = entries.into_iter().map(|e| e.name).collect();
-        return perform_listing(&mut writer, &names);
+        let entries = filter_entries(entries, &filters);
+        let names: Vec<String> = entries.iter().map(|e| e.name.clone()).collect();
+        perform_listing(&mut writer, &names)?;
+        return Ok(common::OperationSummary {
+            entries: entries.len(),
+            bytes: entries.iter().map(|e| e.original_size as u64).sum(),
+            entry_warnings: common::entry_warnings(&entries, settings),
+            ..common::OperationSummary::default()
+        }.with_salts(&h_key, &e_key));
     }
 
     warn!("[LIST_SEARCH] FAILED: Search exhausted all combinations.");
     Err(Error::msg("Failed to find valid header/key combination"))
 }
+
+/// One `list --json-lines` record, and one entry of `list --format json`.
+#[derive(serde::Serialize)]
+struct EntryLine<'a> {
+    name: &'a str,
+    /// Entries table checksum (see `FileEntry::calc_checksum`).
+    checksum: u32,
+    flags: u32,
+    /// Start of the data in 1024-byte blocks from the content offset.
+    offset: u32,
+    original_size: u32,
+    raw_size: u32,
+}
+
+impl<'a> From<&'a common::FileEntry> for EntryLine<'a> {
+    fn from(ent: &'a common::FileEntry) -> Self {
+        EntryLine { name: &ent.name, checksum: ent.checksum, flags: ent.flags, offset: ent.offset, original_size: ent.original_size, raw_size: ent.raw_size }
+    }
+}
+
+fn write_entry_line(writer: &mut dyn Write, ent: &common::FileEntry) -> Result<(), Error> {
+    serde_json::to_writer(&mut *writer, &EntryLine::from(ent))?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// The `list --format json` document.
+#[derive(serde::Serialize)]
+struct JsonListing<'a> {
+    /// Salts that decoded the header and the entries table; `null` for legacy packs.
+    header_salt: Option<&'a str>,
+    entries_salt: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    legacy_format: Option<&'static str>,
+    entries: Vec<EntryLine<'a>>,
+}
+
+/// Entries of a legacy .pack archive (`PACK`/`MABI` magic) with its format name, or
+/// `None` for anything else.
+fn legacy_entries(input: &str, magic: &[u8], settings: &common::PackSettings) -> Result<Option<(&'static str, Vec<common::FileEntry>)>, Error> {
+    if magic.len() < 4 || (&magic[0..4] != b"PACK" && &magic[0..4] != b"MABI") {
+        return Ok(None);
+    }
+    Ok(Some(if &magic[0..4] == b"MABI" {
+        ("mabi", crate::pack_v1::run_list_v1_data(input, settings)?)
+    } else if let Ok(entries) = crate::pack_v1::run_list_logue_data(input, settings) {
+        ("logue", entries)
+    } else {
+        ("pack", crate::pack_v1::run_list_v1_data(input, settings)?)
+    }))
+}
+
+/// List `input` as a single JSON document: the salts that opened it and every entry
+/// matching `filters_cli` with its table fields, in archive order.
+pub fn run_list_json(
+    input: &str,
+    cli_key: Option<String>,
+    loaded_salts: &[String],
+    settings: &common::PackSettings,
+    filters_cli: Vec<String>,
+    writer: &mut dyn Write,
+) -> Result<common::OperationSummary, Error> {
+    let filters = make_regex(filters_cli, settings)?;
+    let mut magic = [0u8; 4];
+    let magic_len = io::Read::read(&mut StdFile::open(input)?, &mut magic)?;
+    if let Some((format, entries)) = legacy_entries(input, &magic[..magic_len], settings)? {
+        let entries = filter_entries(entries, &filters);
+        let listing = JsonListing { header_salt: None, entries_salt: None, legacy_format: Some(format), entries: entries.iter().map(EntryLine::from).collect() };
+        serde_json::to_writer_pretty(&mut *writer, &listing)?;
+        writeln!(writer)?;
+        return Ok(common::OperationSummary::legacy(format, &entries));
+    }
+
+    let reader = PackReader::open_with_settings(input, cli_key, loaded_salts, settings)?;
+    let entries = reader.select(&filters);
+    let listing = JsonListing {
+        header_salt: Some(&reader.header_salt),
+        entries_salt: Some(&reader.entries_salt),
+        legacy_format: None,
+        entries: entries.iter().map(|e| EntryLine::from(*e)).collect(),
+    };
+    serde_json::to_writer_pretty(&mut *writer, &listing)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(common::OperationSummary {
+        entries: entries.len(),
+        bytes: entries.iter().map(|e| e.original_size as u64).sum(),
+        entry_warnings: common::entry_warnings(entries.iter().copied(), settings),
+        ..common::OperationSummary::default()
+    }.with_salts(&reader.header_salt, &reader.entries_salt))
+}
+
+/// One field of a `list --format csv/tsv` row, quoted when it holds the separator, a
+/// quote or a line break.
+fn table_field(value: &str, sep: char) -> String {
+    if value.contains([sep, '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// List `input` as a table with a header row, fields separated by `sep` (`,` for CSV,
+/// tab for TSV): name, flags as text, absolute data offset in bytes, sizes and the
+/// entry key in hex (empty for legacy packs, which have none).
+pub fn run_list_table(
+    input: &str,
+    cli_key: Option<String>,
+    loaded_salts: &[String],
+    settings: &common::PackSettings,
+    filters_cli: Vec<String>,
+    sep: char,
+    writer: &mut dyn Write,
+) -> Result<common::OperationSummary, Error> {
+    let filters = make_regex(filters_cli, settings)?;
+    let mut write_row = |fields: [String; 6]| -> Result<(), Error> {
+        let row: Vec<String> = fields.iter().map(|f| table_field(f, sep)).collect();
+        writeln!(writer, "{}", row.join(&sep.to_string()))?;
+        Ok(())
+    };
+    write_row(["name", "flags", "offset", "original_size", "raw_size", "key"].map(String::from))?;
+
+    let mut magic = [0u8; 4];
+    let magic_len = io::Read::read(&mut StdFile::open(input)?, &mut magic)?;
+    if let Some((format, entries)) = legacy_entries(input, &magic[..magic_len], settings)? {
+        let entries = filter_entries(entries, &filters);
+        for ent in &entries {
+            // Legacy offsets are already absolute.
+            write_row([ent.name.clone(), common::flag_names(ent.flags), ent.offset.to_string(),
+                ent.original_size.to_string(), ent.raw_size.to_string(), String::new()])?;
+        }
+        return Ok(common::OperationSummary::legacy(format, &entries));
+    }
+
+    let reader = PackReader::open_with_settings(input, cli_key, loaded_salts, settings)?;
+    let entries = reader.select(&filters);
+    for ent in &entries {
+        write_row([ent.name.clone(), common::flag_names(ent.flags), (reader.content_offset + ent.offset as u64 * 1024).to_string(),
+            ent.original_size.to_string(), ent.raw_size.to_string(), crate::reader::hex(&ent.key)])?;
+    }
+    writer.flush()?;
+    Ok(common::OperationSummary {
+        entries: entries.len(),
+        bytes: entries.iter().map(|e| e.original_size as u64).sum(),
+        entry_warnings: common::entry_warnings(entries.iter().copied(), settings),
+        ..common::OperationSummary::default()
+    }.with_salts(&reader.header_salt, &reader.entries_salt))
+}
+
+/// List `input` as newline-delimited JSON, one object per entry. Keys are chosen from the
+/// header and the first entry record only, and each entry is written as soon as it is
+/// decoded, so consumers of a huge table can start before it has been read to the end.
+pub fn run_list_json_lines(
+    input: &str,
+    cli_key: Option<String>,
+    loaded_salts: &[String],
+    settings: &common::PackSettings,
+    filters_cli: Vec<String>,
+    writer: &mut dyn Write,
+) -> Result<common::OperationSummary, Error> {
+    let filters = make_regex(filters_cli, settings)?;
+    let mut keys_to_try: Vec<String> = Vec::new();
+    if let Some(ref key) = cli_key { keys_to_try.push(key.clone()); }
+    for salt in loaded_salts {
+        if !keys_to_try.contains(salt) { keys_to_try.push(salt.clone()); }
+    }
+    let cache_name = common::get_final_file_name(input).unwrap_or_default();
+    key_cache::prioritize(&cache_name, &mut keys_to_try[usize::from(cli_key.is_some())..]);
+
+    let file = StdFile::open(input)?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    if let Some((format, entries)) = legacy_entries(input, &mmap, settings)? {
+        let entries = filter_entries(entries, &filters);
+        for ent in &entries {
+            write_entry_line(writer, ent)?;
+        }
+        return Ok(common::OperationSummary::legacy(format, &entries));
+    }
+
+    let name_variants = common::key_name_variants(input, None, settings)?;
+    let found = name_variants.iter().find_map(|name| {
+        keys_to_try.par_iter().find_map_first(|header_skey| {
+            let mut rd = Cursor::new(&mmap[..]);
+            let (header, h_off, iv0, mode) = common::find_header_only(&mut rd, name, header_skey).ok()??;
+            std::iter::once(header_skey).chain(keys_to_try.iter().filter(|s| *s != header_skey)).find_map(|entries_skey| {
+                common::probe_first_entry(&mut rd, name, entries_skey, &header, h_off, iv0, mode, settings)
+                    .map(|e_off| (name.clone(), header_skey.clone(), entries_skey.clone(), header.clone(), e_off, iv0, mode))
+            })
+        })
+    });
+
+    let mut summary = common::OperationSummary::default();
+    let mut emit = |ent: common::FileEntry| -> Result<(), Error> {
+        common::check_entry_policy([&ent], settings)?;
+        if !filters.is_empty() && !filters.iter().any(|re| re.is_match(&ent.name)) {
+            return Ok(());
+        }
+        summary.entries += 1;
+        summary.bytes += ent.original_size as u64;
+        summary.entry_warnings.extend(ent.warnings(settings));
+        write_entry_line(writer, &ent)
+    };
+    let (name, h_key, e_key) = match found {
+        Some((name, h_key, e_key, header, e_off, iv0, mode)) => {
+            info!("[LIST_SEARCH] >>> SUCCESS! HEADER='{}', ENTRIES='{}', streaming {} entries", h_key, e_key, header.file_cnt);
+            common::stream_entries(&mut Cursor::new(&mmap[..]), &name, &e_key, &header, e_off, iv0, mode, settings, &mut emit)?;
+            (name, h_key, e_key)
+        }
+        None => {
+            // The escalated search decodes the whole table before it can tell it found one.
+            let hit = common_ext::escalate_search(&mmap, &name_variants, &keys_to_try, settings)
+                .ok_or_else(|| Error::msg("Failed to find valid header/key combination"))?;
+            for ent in hit.entries {
+                emit(ent)?;
+            }
+            (hit.name_variant, hit.header_salt, hit.entries_salt)
+        }
+    };
+    common::report_name_guess(input, &name, &h_key, &e_key, settings);
+    key_cache::record_success(&cache_name, &h_key, &e_key);
+    writer.flush()?;
+    Ok(summary.with_salts(&h_key, &e_key))
+}
+
+/// List the entries of `input` matching `filters_cli` while probing their data blocks
+/// (`PackReader::probe_entry`). Entries that will likely fail to extract are written as
+/// `name<TAB>BAD: reason`. Returns the number of flagged entries.
+pub fn run_list_validate_data(
+    input: &str,
+    cli_key: Option<String>,
+    loaded_salts: &[String],
+    settings: &common::PackSettings,
+    output_file_path: Option<&str>,
+    filters_cli: Vec<String>,
+) -> Result<usize, Error> {
+    let filters = make_regex(filters_cli, settings)?;
+    let reader = PackReader::open_with_settings(input, cli_key, loaded_salts, settings)?;
+    let selected = reader.select(&filters);
+    let results: Vec<Result<(), String>> = selected.par_iter()
+        .map(|ent| common::catch_panic(&ent.name, || Ok(reader.probe_entry(ent))).unwrap_or_else(|e| Err(e.to_string())))
+        .collect();
+
+    let mut writer: Box<dyn Write> = if let Some(out_path) = output_file_path {
+        Box::new(StdFile::create(out_path)?)
+    } else {
+        Box::new(io::stdout())
+    };
+    let mut bad = 0;
+    for (ent, res) in selected.iter().zip(results) {
+        match res {
+            Ok(()) => writeln!(writer, "{}", ent.name)?,
+            Err(reason) => {
+                bad += 1;
+                writeln!(writer, "{}\tBAD: {}", ent.name, reason)?;
+            }
+        }
+    }
+    if bad > 0 {
+        warn!("[LIST_VALIDATE] {} of {} entries will likely fail to extract.", bad, selected.len());
+    } else {
+        info!("[LIST_VALIDATE] All {} entries look valid.", selected.len());
+    }
+    Ok(bad)
+}
@@ -0,0 +1,161 @@
+// names.rs - Windows-safe output names, and the sidecar that maps them back
+
+use anyhow::{Context, Error};
+use log::debug;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Written into the output folder by `extract --sanitize-names`; read back by `pack`.
+pub const NAME_MAP_FILE: &str = ".mabi-names.json";
+
+/// Characters Windows refuses in file names (besides control characters).
+pub fn is_reserved_char(c: char) -> bool {
+    matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*') || c.is_control()
+}
+
+/// `CON`, `PRN`, `AUX`, `NUL`, `COM1`-`COM9` and `LPT1`-`LPT9`, with or without an
+/// extension, in any case.
+pub fn is_device_name(segment: &str) -> bool {
+    let stem = segment.split('.').next().unwrap_or(segment).trim_end().to_ascii_uppercase();
+    let numbered = (stem.starts_with("COM") || stem.starts_with("LPT"))
+        && stem.len() == 4
+        && stem.as_bytes()[3].is_ascii_digit()
+        && stem.as_bytes()[3] != b'0';
+    numbered || ["CON", "PRN", "AUX", "NUL"].contains(&stem.as_str())
+}
+
+/// How `extract --sanitize-names` rewrites path segments Windows can't create.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SanitizeMode {
+    /// Keep names as they are.
+    #[default]
+    None,
+    /// `%XX` for reserved characters, `%` itself, the first letter of a device name and
+    /// a trailing dot or space (`a?b` -> `a%3Fb`, `con.txt` -> `%63on.txt`).
+    Percent,
+    /// `_` instead of reserved characters and a trailing dot or space, and a leading `_`
+    /// for device names (`a?b` -> `a_b`, `con.txt` -> `_con.txt`).
+    Replace,
+}
+
+impl std::str::FromStr for SanitizeMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_lowercase().as_str() {
+            "none" | "off" => Ok(SanitizeMode::None),
+            "percent" | "encode" => Ok(SanitizeMode::Percent),
+            "replace" | "underscore" => Ok(SanitizeMode::Replace),
+            other => Err(Error::msg(format!("Unknown name sanitization '{}' (expected none, percent or replace)", other))),
+        }
+    }
+}
+
+fn percent(c: char) -> String {
+    let mut buf = [0u8; 4];
+    c.encode_utf8(&mut buf).bytes().map(|b| format!("%{:02X}", b)).collect()
+}
+
+fn sanitize_segment(segment: &str, mode: SanitizeMode) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for c in segment.chars() {
+        match mode {
+            SanitizeMode::Percent if is_reserved_char(c) || c == '%' => out.push_str(&percent(c)),
+            SanitizeMode::Replace if is_reserved_char(c) => out.push('_'),
+            _ => out.push(c),
+        }
+    }
+    if is_device_name(&out) {
+        out = match mode {
+            SanitizeMode::Percent => {
+                let first = out.chars().next().unwrap();
+                percent(first) + &out[first.len_utf8()..]
+            }
+            _ => format!("_{}", out),
+        };
+    }
+    if let Some(last) = out.chars().last().filter(|c| *c == '.' || *c == ' ') {
+        out.pop();
+        match mode {
+            SanitizeMode::Percent => out.push_str(&percent(last)),
+            _ => out.push('_'),
+        }
+    }
+    out
+}
+
+/// `name` with every path segment made creatable on Windows according to `mode`.
+/// Separators are kept as they are.
+pub fn sanitize_name(name: &str, mode: SanitizeMode) -> String {
+    if mode == SanitizeMode::None {
+        return name.to_string();
+    }
+    let mut out = String::with_capacity(name.len());
+    let mut segment = String::new();
+    for c in name.chars() {
+        if c == '/' || c == '\\' {
+            out.push_str(&sanitize_segment(&segment, mode));
+            segment.clear();
+            out.push(c);
+        } else {
+            segment.push(c);
+        }
+    }
+    out.push_str(&sanitize_segment(&segment, mode));
+    out
+}
+
+/// Output path (`/` separators) -> original entry name, for files whose name was changed
+/// on extraction. Stored as JSON in `NAME_MAP_FILE` at the output folder's root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameMap {
+    pub names: BTreeMap<String, String>,
+}
+
+/// Serializes read-merge-write cycles of the sidecar between extraction threads.
+static NAME_MAP_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+impl NameMap {
+    /// The map in `dir`, or an empty one if there is none.
+    pub fn load(dir: &str) -> Result<Self, Error> {
+        let path = Path::new(dir).join(NAME_MAP_FILE);
+        if !path.exists() {
+            return Ok(NameMap::default());
+        }
+        let text = std::fs::read(&path).context(format!("reading {} failed", path.display()))?;
+        Self::from_json(&text).context(format!("{} is not a name map", path.display()))
+    }
+
+    /// Parse the contents of a `NAME_MAP_FILE`.
+    pub fn from_json(text: &[u8]) -> Result<Self, Error> {
+        Ok(NameMap { names: serde_json::from_slice(text)? })
+    }
+
+    /// Add these renames to the map already in `dir` (later entries win) and write it back.
+    pub fn merge_into(&self, dir: &str) -> Result<(), Error> {
+        if self.names.is_empty() {
+            return Ok(());
+        }
+        let _guard = NAME_MAP_LOCK.lock().unwrap();
+        let mut merged = Self::load(dir)?;
+        merged.names.extend(self.names.iter().map(|(k, v)| (k.clone(), v.clone())));
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(Path::new(dir).join(NAME_MAP_FILE), serde_json::to_string_pretty(&merged.names)?)?;
+        debug!("[NAMES] {} renamed file(s) recorded in {}", merged.names.len(), NAME_MAP_FILE);
+        Ok(())
+    }
+
+    /// Record that `out_name` holds entry `entry_name`, if the two differ.
+    pub fn record(&mut self, out_name: &str, entry_name: &str) {
+        let out = out_name.replace('\\', "/");
+        if out != entry_name.replace('\\', "/") {
+            self.names.insert(out, entry_name.to_string());
+        }
+    }
+
+    /// The entry name for the file at `rel_path` (either separator), if it was renamed.
+    pub fn original(&self, rel_path: &str) -> Option<&str> {
+        self.names.get(&rel_path.replace('\\', "/")).map(|s| s.as_str())
+    }
+}
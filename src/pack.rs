@@ -1,77 +1,104 @@
+use crate::checksum::EntryChecksum;
+use crate::codec;
 use crate::common::{self, FileEntry};
 use crate::encryption;
 use crate::extract::ProgressFn;
+use crate::hook::EntryHook;
+use crate::names::{self, NameMap};
+use crate::pack_lock::PackLock;
+use crate::reader::PackReader;
+use crate::vfs::{DiskFs, Vfs};
+use crate::verify;
 use anyhow::{Context, Error};
 use byte_slice_cast::AsByteSlice;
 use byteorder::{LittleEndian, WriteBytesExt};
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Read, Seek, SeekFrom, Write, Cursor};
-use std::path::Path;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use walkdir::WalkDir;
-use log::{info, debug, trace};
-use image_dds::dds_from_image;
+use log::{info, debug, trace, warn};
+use serde::{Deserialize, Serialize};
 
 fn get_rel_path(root_dir: &str, full_path: &str) -> Result<String, Error> {
-    let rel_name = Path::new(full_path).strip_prefix(root_dir).expect(&format!(
+    let rel_name = Path::new(full_path).strip_prefix(root_dir).unwrap_or_else(|_| panic!(
         "strip path error, full:{}, root:{}",
         full_path, root_dir
     ));
     Ok(rel_name.to_string_lossy().into_owned())
 }
 
-fn need_compress(fname: &str, extra_ext_list: &[&str]) -> bool {
+pub(crate) fn need_compress(fname: &str, extra_ext_list: &[&str]) -> bool {
     [".txt", ".xml", ".dds", ".pmg", ".set", ".raw"]
         .iter()
         .chain(extra_ext_list.iter())
         .any(|ext| fname.ends_with(ext))
 }
 
-fn pack_file(
-    root_dir: &str,
+/// zlib level `pack` uses unless told otherwise (`flate2::Compression::default()`).
+pub const DEFAULT_LEVEL: u32 = 6;
+
+/// Compression has to save at least this share (in percent) of an entry, or it is stored as is.
+pub const MIN_SAVING_PERCENT: usize = 3;
+
+/// zlib-compress `data` at `level`, or `None` when that saves less than
+/// `MIN_SAVING_PERCENT` (already-compressed DDS/OGG payloads, tiny files).
+pub(crate) fn compress_if_smaller(data: &[u8], level: u32) -> Result<Option<Vec<u8>>, Error> {
+    let mut e = ZlibEncoder::new(Vec::new(), Compression::new(level));
+    e.write_all(data)?;
+    let packed = e.finish()?;
+    Ok((packed.len() * 100 < data.len() * (100 - MIN_SAVING_PERCENT)).then_some(packed))
+}
+
+/// Read-to-stored for one input file, as `pack` does it: auto-DDS conversion, then zlib
+/// when it pays off. Returns the entry (offset and checksum are filled in by `PackWriter`),
+/// its payload and, with `content_hash`, the MD5 of the input.
+pub(crate) fn pack_file(
+    mut data: Vec<u8>,
     disk_rel: &str,
     archive_name: &str,
     need_compress: bool,
+    level: u32,
     auto_dds: bool,
     _encrypt: bool,
     _skey: &str,
     _final_file_name: &str,
     _iv: u32,
-) -> Result<(FileEntry, Vec<u8>), Error> {
+    content_hash: bool,
+) -> Result<(FileEntry, Vec<u8>, Option<String>), Error> {
     trace!("[PACK_FILE] Processing: {} (archive: {})", disk_rel, archive_name);
-    let full_path = Path::new(root_dir).join(disk_rel);
-    
-    let mut data = vec![];
-    let mut fp = File::open(&full_path)?;
-    fp.read_to_end(&mut data)?;
-    
     let mut final_archive_name = archive_name.to_owned();
 
     if auto_dds && disk_rel.to_lowercase().ends_with(".png") {
         debug!("[PACK_FILE] Auto-DDS: Converting {} to DXT5...", disk_rel);
-        let img = image::open(&full_path).context("Failed to open PNG")?.to_rgba8();
-        let dds = dds_from_image(&img, image_dds::ImageFormat::BC3RgbaUnormSrgb, image_dds::Quality::Fast, image_dds::Mipmaps::GeneratedAutomatic)
-            .map_err(|e| Error::msg(format!("DDS conversion failed: {:?}", e)))?;
-
-        let mut dds_buf = Cursor::new(Vec::new());
-        dds.write(&mut dds_buf).map_err(|e| Error::msg(format!("DDS write failed: {:?}", e)))?;
-        data = dds_buf.into_inner();
+        data = codec::png_to_dds(&data)?;
 
         final_archive_name = archive_name.trim_end_matches(".png").to_owned() + ".dds";
         debug!("[PACK_FILE] Auto-DDS: Renamed entry to {}", final_archive_name);
     }
 
     let original_size = data.len();
+    let digest = content_hash.then(|| verify::md5_hex(&data));
     let mut flags = 0;
     
-    let raw_stm = if need_compress || final_archive_name.ends_with(".dds") {
-        flags |= common::FLAG_COMPRESSED;
-        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
-        e.write_all(&data)?;
-        e.finish()?
+    let packed = if level > 0 && (need_compress || final_archive_name.ends_with(".dds")) {
+        let packed = compress_if_smaller(&data, level)?;
+        if packed.is_none() {
+            debug!("[PACK_FILE] Compression doesn't pay off for {}; storing it uncompressed", final_archive_name);
+        }
+        packed
     } else {
-        data
+        None
+    };
+    let raw_stm = match packed {
+        Some(packed) => {
+            flags |= common::FLAG_COMPRESSED;
+            packed
+        }
+        None => data,
     };
 
     let fkey = [0u8; 16];
@@ -87,31 +114,33 @@ fn pack_file(
             key: fkey,
         },
         raw_stm,
+        digest,
     ))
 }
 
-fn write_header<T>(file_cnt: u32, key: &[u8], wr: &mut T, iv: u32) -> Result<(), Error>
+/// Returns the bytes written, including the padding of the final word.
+pub(crate) fn write_header<T>(file_cnt: u32, key: &[u8], wr: &mut T, iv: u32, mode: encryption::Snow2Mode) -> Result<u64, Error>
 where
     T: Write,
 {
     const IT_VERSION: u8 = 2;
     let checksum = file_cnt + IT_VERSION as u32;
-    let mut enc_stm = encryption::Snow2Encoder::new_iv(key, iv, wr);
+    let mut enc_stm = encryption::Snow2Encoder::new_iv_mode(key, iv, mode, wr);
     enc_stm.write_u32::<LittleEndian>(checksum)?;
     enc_stm.write_u8(IT_VERSION)?;
     enc_stm.write_u32::<LittleEndian>(file_cnt)?;
-    enc_stm.finish()?; // Explicitly finish to pad and flush
-    Ok(())
+    Ok(enc_stm.finish()?)
 }
 
-fn write_entries<T>(entries: &[FileEntry], key: &[u8], wr: &mut T, iv: u32) -> Result<(), Error>
+/// Returns the bytes written, including the padding of the final word.
+pub(crate) fn write_entries<T>(entries: &[FileEntry], key: &[u8], wr: &mut T, iv: u32, mode: encryption::Snow2Mode) -> Result<u64, Error>
 where
     T: Write,
 {
-    let mut enc_stm = encryption::Snow2Encoder::new_iv(key, iv, wr);
+    let mut enc_stm = encryption::Snow2Encoder::new_iv_mode(key, iv, mode, wr);
     entries
         .iter()
-        .map(|ent| -> Result<(), Error> {
+        .try_for_each(|ent| -> Result<(), Error> {
             let u16_str: Vec<u16> = ent.name.chars().map(|c| c as u32 as u16).collect();
             enc_stm.write_u32::<LittleEndian>(u16_str.len() as u32)?;
             enc_stm.write_all(u16_str.as_byte_slice())?;
@@ -122,16 +151,320 @@ where
             enc_stm.write_u32::<LittleEndian>(ent.raw_size)?;
             enc_stm.write_all(&ent.key)?;
             Ok(())
-        })
-        .collect::<Result<(), Error>>()?;
-    enc_stm.finish()?;
-    Ok(())
+        })?;
+    Ok(enc_stm.finish()?)
 }
 
 fn ceil_1024(v: u64) -> u64 {
     (v + 1023) & 0u64.wrapping_sub(1024)
 }
 
+/// Apply entry flags to a plain payload: zlib compression, then head/full
+/// encryption with the per-file key. Inverse of `extract::extract_single_file_to_memory`.
+pub fn encode_entry_data(
+    name: &str,
+    key: &[u8; 16],
+    plain: &[u8],
+    flags: u32,
+    iv0: u32,
+    mode: encryption::Snow2Mode,
+) -> Result<Vec<u8>, Error> {
+    let mut content = if (flags & common::FLAG_COMPRESSED) != 0 {
+        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+        e.write_all(plain)?;
+        e.finish()?
+    } else {
+        plain.to_vec()
+    };
+
+    let fkey = encryption::gen_file_key(name, key);
+    if (flags & common::FLAG_HEAD_ENCRYPTED) != 0 {
+        let len = std::cmp::min(content.len(), 1024);
+        encryption::snow2_encrypt_mode(&fkey, iv0, mode, &mut content[..len]);
+    }
+    if (flags & common::FLAG_ALL_ENCRYPTED) != 0 {
+        encryption::snow2_encrypt_mode(&fkey, iv0, mode, &mut content);
+    }
+    Ok(content)
+}
+
+/// Marks the optional build-info record stored just before the footer:
+/// `[json][u32 json length][META_MAGIC][footer]`.
+pub const META_MAGIC: &[u8; 4] = b"MP2M";
+
+/// Which tool and settings produced a pack. Readers that don't know the record ignore it:
+/// entries address content by offset and the footer is still the last 4 bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackMetadata {
+    pub tool: String,
+    pub version: String,
+    pub iv: u32,
+    pub mode: String,
+    #[serde(default)]
+    pub compress_ext: Vec<String>,
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    #[serde(default)]
+    pub auto_dds: bool,
+    /// Entry name -> MD5 of the stored content (see `verify`), when packed with content hashes.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub content_hashes: BTreeMap<String, String>,
+    /// The `--entry-checksum` algorithm, when it isn't the game's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry_checksum: Option<String>,
+    /// The zlib level (`--level`), when it isn't `DEFAULT_LEVEL`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<u32>,
+}
+
+impl PackMetadata {
+    /// Metadata describing this build of the tool packing with `opts`.
+    pub fn current(opts: &PackOptions, mode: encryption::Snow2Mode) -> Self {
+        PackMetadata {
+            tool: "mabi-pack2".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            iv: opts.iv,
+            mode: format!("{:?}", mode),
+            compress_ext: opts.compress_ext.clone(),
+            path_prefix: opts.path_prefix.clone(),
+            auto_dds: opts.auto_dds,
+            content_hashes: BTreeMap::new(),
+            entry_checksum: opts.settings.entry_checksum_name().map(str::to_string),
+            level: (opts.level != DEFAULT_LEVEL).then_some(opts.level),
+        }
+    }
+
+    /// Read the record from a whole .it image, if one is present.
+    pub fn read_from(data: &[u8]) -> Option<Self> {
+        let len = data.len();
+        if len < 12 || &data[len - 8..len - 4] != META_MAGIC {
+            return None;
+        }
+        let json_len = u32::from_le_bytes(data[len - 12..len - 8].try_into().ok()?) as usize;
+        let start = (len - 12).checked_sub(json_len)?;
+        serde_json::from_slice(&data[start..len - 12]).ok()
+    }
+}
+
+/// Settings for `run_pack_with_options`.
+#[derive(Debug, Clone)]
+pub struct PackOptions {
+    /// Extensions compressed in addition to the built-in list.
+    pub compress_ext: Vec<String>,
+    pub auto_dds: bool,
+    pub iv: u32,
+    /// Store every entry under this folder (`data\...`).
+    pub path_prefix: Option<String>,
+    /// Write a `PackMetadata` record before the footer.
+    pub embed_metadata: bool,
+    /// Record an MD5 per entry in the metadata record (for `extract --verify-content`).
+    pub content_hashes: bool,
+    /// Add a directory marker entry (see `FileEntry::is_dir_marker`) for every empty folder.
+    pub keep_empty_dirs: bool,
+    /// Store symbolic links as `FLAG_SYMLINK` entries instead of the files they point to.
+    pub store_symlinks: bool,
+    /// Write a pack with no entries when the input has nothing to pack, instead of failing.
+    pub allow_empty: bool,
+    /// Pack files renamed by `extract --sanitize-names` under their original names
+    /// (from `names::NAME_MAP_FILE` in the input folder, which is never packed itself).
+    pub restore_names: bool,
+    /// Start from the entries of an existing pack (`--from-it`).
+    pub base: Option<BasePack>,
+    /// Leave out input files whose entry name matches any of these `--filter`-style patterns.
+    pub exclude: Vec<String>,
+    /// Renames input files' entries and rewrites their content before it is encoded.
+    pub hook: Option<Arc<dyn EntryHook>>,
+    /// zlib level 0-9 for compressed entries; 0 stores every entry uncompressed (`--store`).
+    pub level: u32,
+    /// Pack even when the output lies inside the input folder, leaving the output (and
+    /// its lock file) out. Without it that is an error.
+    pub allow_output_in_input: bool,
+    /// How the base pack is opened (see `common::PackSettings`).
+    pub settings: common::PackSettings,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        PackOptions { compress_ext: Vec::new(), auto_dds: false, iv: 0, path_prefix: None, embed_metadata: true, content_hashes: false, keep_empty_dirs: false, store_symlinks: false, allow_empty: false, restore_names: true, base: None, exclude: Vec::new(), hook: None, allow_output_in_input: false, level: DEFAULT_LEVEL, settings: common::PackSettings::default() }
+    }
+}
+
+/// An existing pack whose entries are carried into the new one, ahead of the input
+/// folder's files. Payloads are copied without decoding unless the IV or cipher mode
+/// changes. Folder files replace entries of the same name (ignoring case and slash style).
+#[derive(Debug, Clone, Default)]
+pub struct BasePack {
+    pub path: String,
+    /// Salt to try first when opening it (`--key-old`), before `salts`.
+    pub key: Option<String>,
+    pub salts: Vec<String>,
+    /// Leave out entries matching any of these `--filter`-style patterns (`--remove`).
+    pub remove: Vec<String>,
+}
+
+/// Streams entry payloads into a new .it archive. The entries table, header
+/// and footer pointer are written by `finish` once every offset is known.
+pub struct PackWriter<W: Write + Seek = BufWriter<File>> {
+    stm: W,
+    header_key: [u8; 16],
+    entries_key: [u8; 16],
+    header_off: u32,
+    entries_off: u32,
+    start_content_off: u64,
+    content_off: u64,
+    entries: Vec<FileEntry>,
+    iv: u32,
+    mode: encryption::Snow2Mode,
+    metadata: Option<Vec<u8>>,
+    checksum: Option<Arc<dyn EntryChecksum>>,
+}
+
+impl PackWriter {
+    /// `key_name` is the archive name that keys and offsets derive from (normally the
+    /// final component of `output_fname`). `archive_names` must list every entry that
+    /// will be added so the entries table can be sized before any payload is written.
+    pub fn create(
+        output_fname: &str,
+        key_name: &str,
+        header_skey: &str,
+        entries_skey: &str,
+        iv: u32,
+        mode: encryption::Snow2Mode,
+        archive_names: &[String],
+    ) -> Result<Self, Error> {
+        let fs = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(output_fname)?;
+        Ok(PackWriter::new(BufWriter::new(fs), key_name, header_skey, entries_skey, iv, mode, archive_names))
+    }
+}
+
+impl<W: Write + Seek> PackWriter<W> {
+    /// Like `create` but writes into `sink`, for example a `Cursor<Vec<u8>>`.
+    pub fn new(
+        sink: W,
+        key_name: &str,
+        header_skey: &str,
+        entries_skey: &str,
+        iv: u32,
+        mode: encryption::Snow2Mode,
+        archive_names: &[String],
+    ) -> Self {
+        let entries_size = archive_names
+            .iter()
+            .map(|archive| archive.chars().count() * 2 + 40)
+            .sum::<usize>();
+        // The encoder pads the table's last word, which must not spill into the first payload.
+        let entries_size = entries_size.div_ceil(4) * 4;
+
+        let header_off = encryption::gen_header_offset(key_name);
+        let entries_off = encryption::gen_entries_offset(key_name);
+
+        let start_content_off = ceil_1024((header_off as u64) + (entries_off as u64) + (entries_size as u64));
+
+        PackWriter {
+            stm: sink,
+            header_key: encryption::gen_header_key(key_name, header_skey),
+            entries_key: encryption::gen_entries_key(key_name, entries_skey),
+            header_off,
+            entries_off,
+            start_content_off,
+            content_off: start_content_off,
+            entries: Vec::with_capacity(archive_names.len()),
+            iv,
+            mode,
+            metadata: None,
+            checksum: None,
+        }
+    }
+
+    /// Where the first payload goes; entry offsets count 1024-byte blocks from here.
+    pub fn content_offset(&self) -> u64 {
+        self.start_content_off
+    }
+
+    /// Store `meta` in the pack when it is finished.
+    pub fn set_metadata(&mut self, meta: &PackMetadata) -> Result<(), Error> {
+        self.metadata = Some(serde_json::to_vec(meta)?);
+        Ok(())
+    }
+
+    /// Compute entry checksums with `algo` (`--entry-checksum`) rather than the game's.
+    pub fn set_entry_checksum(&mut self, algo: Option<Arc<dyn EntryChecksum>>) {
+        self.checksum = algo;
+    }
+
+    fn entry_checksum(&self, ent: &FileEntry) -> u32 {
+        match &self.checksum {
+            Some(algo) => algo.checksum(ent),
+            None => ent.calc_checksum(),
+        }
+    }
+
+    /// Append an already encoded payload. `offset`, `raw_size` and `checksum` are filled in here.
+    pub fn add_raw(&mut self, mut ent: FileEntry, content: &[u8]) -> Result<(), Error> {
+        self.stm.seek(SeekFrom::Start(self.content_off))?;
+        self.stm.write_all(content)?;
+
+        ent.raw_size = content.len() as u32;
+        ent.offset = ((self.content_off - self.start_content_off) / 1024) as u32;
+        ent.checksum = self.entry_checksum(&ent);
+
+        self.content_off = ceil_1024(self.content_off + ent.raw_size as u64);
+        self.entries.push(ent);
+        Ok(())
+    }
+
+    /// Add an entry whose payload is the one already written for the `index`-th entry
+    /// added, without writing it again.
+    pub fn add_shared(&mut self, mut ent: FileEntry, index: usize) -> Result<(), Error> {
+        let owner = self.entries.get(index).ok_or_else(|| Error::msg(format!("no entry #{} to share data with", index)))?;
+        ent.offset = owner.offset;
+        ent.raw_size = owner.raw_size;
+        ent.checksum = self.entry_checksum(&ent);
+        self.entries.push(ent);
+        Ok(())
+    }
+
+    /// Write the entries table, header and footer pointer. Returns the final entries.
+    pub fn finish(self) -> Result<Vec<FileEntry>, Error> {
+        Ok(self.finish_into_inner()?.0)
+    }
+
+    /// `finish`, also handing back the sink.
+    pub fn finish_into_inner(mut self) -> Result<(Vec<FileEntry>, W), Error> {
+        let entries_start = (self.header_off + self.entries_off) as u64;
+        self.stm.seek(SeekFrom::Start(entries_start))?;
+        let entries_len = write_entries(&self.entries, &self.entries_key, &mut self.stm, self.iv, self.mode).context("writing entries failed")?;
+        if entries_start + entries_len > self.start_content_off {
+            return Err(Error::msg(format!(
+                "entries table ({} bytes) overruns the space reserved before content at 0x{:X}; were all entry names passed to PackWriter::create?",
+                entries_len, self.start_content_off)));
+        }
+
+        self.stm.seek(SeekFrom::Start(self.header_off as u64))?;
+        write_header(self.entries.len() as u32, &self.header_key, &mut self.stm, self.iv, self.mode).context("writing header failed")?;
+
+        self.stm.seek(SeekFrom::End(0))?;
+        if let Some(meta) = &self.metadata {
+            self.stm.write_all(meta)?;
+            self.stm.write_u32::<LittleEndian>(meta.len() as u32)?;
+            self.stm.write_all(META_MAGIC)?;
+        }
+        let footer_val = self.header_off;
+        {
+            let mut enc = encryption::Snow2Encoder::new_iv_mode(&self.header_key, self.iv, self.mode, &mut self.stm);
+            enc.write_u32::<LittleEndian>(footer_val)?;
+            enc.finish()?;
+        }
+        self.stm.flush()?;
+
+        Ok((self.entries, self.stm))
+    }
+}
+
 pub fn run_pack(
     input_folder: &str,
     output_fname: &str,
@@ -141,95 +474,271 @@ pub fn run_pack(
     iv: u32,
     path_prefix: Option<&str>,
     progress_cb: Option<&ProgressFn>,
-) -> Result<(), Error> {
+) -> Result<common::OperationSummary, Error> {
+    let opts = PackOptions {
+        compress_ext: compress_ext.iter().map(|s| s.to_string()).collect(),
+        auto_dds,
+        iv,
+        path_prefix: path_prefix.map(|p| p.to_string()),
+        ..PackOptions::default()
+    };
+    run_pack_with_options(input_folder, output_fname, skey, &opts, progress_cb)
+}
+
+/// Where `output` lies inside the `input` folder, relative to it, or `None` when it is
+/// elsewhere (or `input` is not a folder).
+pub fn output_in_input(input: &str, output: &str) -> Option<PathBuf> {
+    let input = Path::new(input).canonicalize().ok().filter(|p| p.is_dir())?;
+    let output = Path::new(output);
+    let parent = output.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let full = parent.canonicalize().ok()?.join(output.file_name()?);
+    full.strip_prefix(&input).ok().map(Path::to_path_buf)
+}
+
+/// Pack `input_folder` into `output_fname` with the given options.
+pub fn run_pack_with_options(
+    input_folder: &str,
+    output_fname: &str,
+    skey: &str,
+    opts: &PackOptions,
+    progress_cb: Option<&ProgressFn>,
+) -> Result<common::OperationSummary, Error> {
+    let iv = opts.iv;
+    let path_prefix = opts.path_prefix.as_deref();
     info!("[PACK] Starting pack operation from '{}' to '{}' (IV={}, Prefix={:?})", input_folder, output_fname, iv, path_prefix);
 
     let input_path = Path::new(input_folder);
+    let inside = output_in_input(input_folder, output_fname);
+    if let Some(rel) = inside.as_ref().filter(|_| !opts.allow_output_in_input) {
+        return Err(Error::msg(format!(
+            "The output '{}' lies inside the input folder '{}' (as '{}') and would be packed into itself; write it elsewhere, or pass --allow-output-in-input to leave it out",
+            output_fname, input_folder, rel.display())));
+    }
     let input_root = if input_path.is_file() {
         input_path.parent().map(|p| p.to_string_lossy().into_owned()).unwrap_or_else(|| input_folder.to_string())
     } else {
         input_folder.to_string()
     };
 
-    let disk_names: Vec<String> = WalkDir::new(input_folder)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| !e.file_type().is_dir())
-        .map(|e| get_rel_path(&input_root, e.into_path().to_str().unwrap()))
-        .collect::<Result<Vec<String>, Error>>()
-        .context("traversing dir failed")?;
+    // With a base pack the input folder is optional.
+    let mut disk_names: Vec<String> = if input_folder.is_empty() && opts.base.is_some() {
+        Vec::new()
+    } else {
+        WalkDir::new(input_folder)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| !e.file_type().is_dir())
+            .map(|e| get_rel_path(&input_root, e.into_path().to_str().unwrap()))
+            .collect::<Result<Vec<String>, Error>>()
+            .context("traversing dir failed")?
+    };
+
+    if let Some(rel) = inside {
+        let lock = PathBuf::from(format!("{}{}", rel.display(), crate::pack_lock::LOCK_SUFFIX));
+        disk_names.retain(|n| Path::new(n) != rel && Path::new(n) != lock);
+        debug!("[PACK] Leaving the output '{}' out of its own input", rel.display());
+    }
+
+    if opts.keep_empty_dirs && !input_folder.is_empty() {
+        let empty_dirs: Vec<String> = WalkDir::new(input_folder)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_dir() && std::fs::read_dir(e.path()).is_ok_and(|mut d| d.next().is_none()))
+            .map(|e| get_rel_path(&input_root, e.into_path().to_str().unwrap()).map(|n| n.replace('/', "\\") + "\\"))
+            .collect::<Result<Vec<String>, Error>>()?;
+        debug!("[PACK] Keeping {} empty folder(s)", empty_dirs.len());
+        disk_names.extend(empty_dirs);
+    }
+
+    let name_map = if input_path.is_dir() {
+        disk_names.retain(|n| n != names::NAME_MAP_FILE);
+        if opts.restore_names { NameMap::load(input_folder)? } else { NameMap::default() }
+    } else {
+        NameMap::default()
+    };
+    let file_names = entry_names(disk_names, path_prefix, &name_map);
+    pack_tree(&DiskFs::new(&input_root), file_names, &format!("'{}'", input_folder), output_fname, skey, opts, progress_cb)
+}
 
-    let file_names: Vec<(String, String)> = if let Some(prefix) = path_prefix {
+/// Pack every file of `source` (see `vfs`), such as a zip file or an in-memory tree.
+/// Entry names get `\` separators; a `names::NAME_MAP_FILE` in the tree is applied as for folders.
+pub fn run_pack_from_vfs(
+    source: &dyn Vfs,
+    output_fname: &str,
+    skey: &str,
+    opts: &PackOptions,
+    progress_cb: Option<&ProgressFn>,
+) -> Result<common::OperationSummary, Error> {
+    info!("[PACK] Starting pack operation from a file tree to '{}' (IV={}, Prefix={:?})", output_fname, opts.iv, opts.path_prefix);
+    let mut source_names = source.list()?;
+    let has_map = source_names.iter().any(|n| n == names::NAME_MAP_FILE);
+    source_names.retain(|n| n != names::NAME_MAP_FILE);
+    let name_map = if has_map && opts.restore_names { NameMap::from_json(&source.read(names::NAME_MAP_FILE)?)? } else { NameMap::default() };
+    let file_names = entry_names(source_names, opts.path_prefix.as_deref(), &name_map).into_iter()
+        .map(|(n, archive)| (n, archive.replace('/', "\\")))
+        .collect();
+    pack_tree(source, file_names, "The input", output_fname, skey, opts, progress_cb)
+}
+
+/// (name in the source tree, entry name) for each of `source_names`.
+pub(crate) fn entry_names(source_names: Vec<String>, path_prefix: Option<&str>, name_map: &NameMap) -> Vec<(String, String)> {
+    if !name_map.names.is_empty() {
+        info!("[PACK] Restoring {} original entry name(s) from {}", name_map.names.len(), names::NAME_MAP_FILE);
+    }
+    let entry_name = |n: &String| name_map.original(n).map_or_else(|| n.clone(), str::to_string);
+    if let Some(prefix) = path_prefix {
         debug!("[PACK] Prefixing all entries under '{}\\'...", prefix);
-        disk_names.into_iter().map(|n| {
-            let archive_name = format!("{}\\{}", prefix, n.replace("/", "\\"));
+        source_names.into_iter().map(|n| {
+            let archive_name = format!("{}\\{}", prefix, entry_name(&n).replace("/", "\\"));
             (n, archive_name)
         }).collect()
     } else {
-        disk_names.into_iter().map(|n| (n.clone(), n)).collect()
+        source_names.into_iter().map(|n| {
+            let archive_name = entry_name(&n);
+            (n, archive_name)
+        }).collect()
+    }
+}
+
+/// Write `file_names` (see `entry_names`) from `source` into `output_fname`, after the
+/// entries kept from `opts.base`. Names ending in `\` become folder markers.
+fn pack_tree(
+    source: &dyn Vfs,
+    mut file_names: Vec<(String, String)>,
+    label: &str,
+    output_fname: &str,
+    skey: &str,
+    opts: &PackOptions,
+    progress_cb: Option<&ProgressFn>,
+) -> Result<common::OperationSummary, Error> {
+    let iv = opts.iv;
+    let auto_dds = opts.auto_dds;
+    let compress_ext: Vec<&str> = opts.compress_ext.iter().map(|s| s.as_str()).collect();
+    if opts.level > 9 {
+        return Err(Error::msg(format!("Compression level {} is out of range (0-9)", opts.level)));
+    }
+    let _lock = PackLock::acquire(output_fname, "pack")?;
+
+    if !opts.exclude.is_empty() {
+        let exclude = crate::extract::make_regex(opts.exclude.clone(), &opts.settings)?;
+        let before = file_names.len();
+        file_names.retain(|(_, archive)| !exclude.iter().any(|re| re.is_match(&archive.replace('/', "\\"))));
+        info!("[PACK] Excluding {} of {} input file(s)", before - file_names.len(), before);
+    }
+    if let Some(hook) = &opts.hook {
+        for (_, archive) in file_names.iter_mut() {
+            if let Some(renamed) = hook.rename(archive)? {
+                debug!("[PACK] Script renamed {} -> {}", archive, renamed);
+                *archive = renamed;
+            }
+        }
+    }
+
+    let base = match &opts.base {
+        Some(b) => {
+            let reader = PackReader::open_with_settings(&b.path, b.key.clone(), &b.salts, &opts.settings).context(format!("opening base pack {} failed", b.path))?;
+            let remove = crate::extract::make_regex(b.remove.clone(), &opts.settings)?;
+            let replaced: std::collections::HashSet<String> = file_names.iter().map(|(_, a)| verify::normalize_name(a).to_lowercase()).collect();
+            let unremoved: Vec<&FileEntry> = reader.entries().iter().filter(|e| !remove.iter().any(|re| re.is_match(&e.name))).collect();
+            let kept: Vec<FileEntry> = unremoved.iter()
+                .filter(|e| !replaced.contains(&verify::normalize_name(&e.name).to_lowercase()))
+                .map(|e| (*e).clone())
+                .collect();
+            info!("[PACK] Keeping {} of {} entries from '{}' ({} removed, {} replaced from the input folder)",
+                kept.len(), reader.entries().len(), b.path, reader.entries().len() - unremoved.len(), unremoved.len() - kept.len());
+            Some((reader, kept))
+        }
+        None => None,
     };
+    let base_count = base.as_ref().map_or(0, |(_, kept)| kept.len());
 
-    let entries_size = file_names
-        .iter()
-        .map(|(_, archive)| archive.chars().count() * 2 + 40)
-        .sum::<usize>();
+    if file_names.is_empty() && base_count == 0 {
+        if !opts.allow_empty {
+            return Err(Error::msg(format!("{} has nothing to pack (use --allow-empty to write an empty pack)", label)));
+        }
+        warn!("[PACK] {} is empty; writing a pack with no entries", label);
+    }
 
     let final_file_name = common::get_final_file_name(output_fname)?;
-    let header_off = encryption::gen_header_offset(&final_file_name);
-    let entries_off = encryption::gen_entries_offset(&final_file_name);
-    let header_key = encryption::gen_header_key(&final_file_name, skey);
-    let entries_key = encryption::gen_entries_key(&final_file_name, skey);
+    let archive_names: Vec<String> = base.iter().flat_map(|(_, kept)| kept.iter().map(|e| e.name.clone()))
+        .chain(file_names.iter().map(|(_, archive)| archive.clone()))
+        .collect();
+    // The base pack stays mapped while writing, so rewriting it goes through a temporary file.
+    let in_place = opts.base.as_ref().is_some_and(|b| std::fs::canonicalize(&b.path).ok() == std::fs::canonicalize(output_fname).ok());
+    let write_path = if in_place { format!("{}.tmp", output_fname) } else { output_fname.to_string() };
+    let mut writer = PackWriter::create(&write_path, &final_file_name, skey, skey, iv, encryption::Snow2Mode::Sub, &archive_names)?;
+    writer.set_entry_checksum(opts.settings.entry_checksum.clone());
+    let mut metadata = PackMetadata::current(opts, encryption::Snow2Mode::Sub);
+    let record_hashes = opts.embed_metadata && opts.content_hashes;
 
-    let fs = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(output_fname)?;
-    let mut stm = BufWriter::new(fs);
+    let total = base_count + file_names.len();
 
-    let start_content_off = ceil_1024((header_off as u64) + (entries_off as u64) + (entries_size as u64));
+    if let Some((reader, kept)) = &base {
+        let base_hashes = reader.metadata().map(|m| m.content_hashes).unwrap_or_default();
+        for (idx, ent) in kept.iter().enumerate() {
+            if let Some(cb) = progress_cb {
+                cb(idx, total, &format!("Copying: {}", ent.name));
+            }
+            if reader.block_range(ent)?.portable_to(iv, encryption::Snow2Mode::Sub) {
+                writer.add_raw(ent.clone(), reader.read_raw(ent)?)?;
+            } else {
+                let plain = reader.read_entry(ent).context(format!("decoding {} failed", ent.name))?;
+                writer.add_raw(ent.clone(), &encode_entry_data(&ent.name, &ent.key, &plain, ent.flags, iv, encryption::Snow2Mode::Sub)?)?;
+            }
+            if let Some(digest) = base_hashes.get(&verify::normalize_name(&ent.name)).filter(|_| record_hashes) {
+                metadata.content_hashes.insert(verify::normalize_name(&ent.name), digest.clone());
+            }
+        }
+    }
 
-    let total = file_names.len();
-    
-    let mut content_off = start_content_off;
-    let mut entries = Vec::<FileEntry>::with_capacity(file_names.len());
-    
     for (idx, (disk_name, archive_name)) in file_names.iter().enumerate() {
         if let Some(cb) = progress_cb {
-            cb(idx, total, &format!("Packing: {}", archive_name));
+            cb(base_count + idx, total, &format!("Packing: {}", archive_name));
+        }
+        if disk_name.ends_with('\\') {
+            let marker = FileEntry { name: archive_name.clone(), checksum: 0, flags: 0, offset: 0, original_size: 0, raw_size: 0, key: [0u8; 16] };
+            writer.add_raw(marker, &[])?;
+            continue;
+        }
+        let link = if opts.store_symlinks { source.link_target(disk_name)? } else { None };
+        if let Some(target) = link {
+            debug!("[PACK] Storing link {} -> {}", archive_name, target);
+            let link = FileEntry { name: archive_name.clone(), checksum: 0, flags: common::FLAG_SYMLINK, offset: 0, original_size: target.len() as u32, raw_size: 0, key: [0u8; 16] };
+            writer.add_raw(link, target.as_bytes())?;
+            continue;
         }
         let encrypt_this_file = output_fname.to_lowercase().ends_with(".it") && !skey.is_empty();
-        let (mut ent, content) = pack_file(&input_root, disk_name, archive_name, need_compress(disk_name, &compress_ext), auto_dds, encrypt_this_file, skey, &final_file_name, iv)
+        let (ent, content, digest) = source.read(disk_name)
+            .and_then(|data| match &opts.hook {
+                Some(hook) => hook.transform(archive_name, data),
+                None => Ok(data),
+            })
+            .and_then(|data| pack_file(data, disk_name, archive_name, need_compress(disk_name, &compress_ext), opts.level, auto_dds, encrypt_this_file, skey, &final_file_name, iv, record_hashes))
             .context(format!("packing {} failed", archive_name))?;
 
-        stm.seek(SeekFrom::Start(content_off))?;
-        stm.write_all(&content)?;
-        
-        ent.offset = ((content_off - start_content_off) / 1024) as u32;
-        let key_sum = ent.key.iter().fold(0u32, |s, v| s.wrapping_add(*v as u32));
-        ent.checksum = ent.flags.wrapping_add(ent.offset).wrapping_add(ent.original_size).wrapping_add(ent.raw_size).wrapping_add(key_sum);
-        
-        content_off = ceil_1024(content_off + ent.raw_size as u64);
-        entries.push(ent);
+        if let Some(digest) = digest {
+            metadata.content_hashes.insert(verify::normalize_name(&ent.name), digest);
+        }
+        writer.add_raw(ent, &content)?;
     }
 
-    stm.seek(SeekFrom::Start((header_off + entries_off) as u64))?;
-    write_entries(&entries, &entries_key, &mut stm, iv).context("writing entries failed")?;
-
-    stm.seek(SeekFrom::Start(header_off as u64))?;
-    write_header(entries.len() as u32, &header_key, &mut stm, iv).context("writing header failed")?;
-
-    stm.seek(SeekFrom::End(0))?;
-    let footer_val = header_off as u32;
-    {
-        let mut enc = encryption::Snow2Encoder::new_iv(&header_key, iv, &mut stm);
-        enc.write_u32::<LittleEndian>(footer_val)?;
-        enc.finish()?;
+    if opts.embed_metadata {
+        writer.set_metadata(&metadata)?;
+    }
+    let entries = writer.finish()?;
+    if in_place {
+        drop(base);
+        std::fs::rename(&write_path, output_fname).context(format!("replacing {} failed", output_fname))?;
     }
 
     if let Some(cb) = progress_cb {
         cb(total, total, "Complete");
     }
 
-    Ok(())
+    Ok(common::OperationSummary {
+        entries: entries.len(),
+        bytes: entries.iter().map(|e| e.original_size as u64).sum(),
+        ..common::OperationSummary::default()
+    }.with_salts(skey, skey))
 }
@@ -0,0 +1,129 @@
+// pack_lock.rs - Advisory lock files around commands that write a pack
+
+use anyhow::{Context, Error};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind as IoErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Appended to the pack path to name its lock file (`data_00.it.lock`).
+pub const LOCK_SUFFIX: &str = ".lock";
+
+/// How old a lock file without readable contents must be before it is taken over.
+const UNREADABLE_LOCK_AGE: Duration = Duration::from_secs(60);
+
+/// Contents of a lock file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub pid: u32,
+    /// The operation holding the lock (`pack`, `update`, `set-flags`...).
+    pub operation: String,
+    /// When the lock was taken (RFC 3339).
+    pub since: String,
+}
+
+/// Held while a pack is written; the lock file is removed when this is dropped. Only
+/// other mabi-pack2 runs honour it, and a lock left behind by a process that has exited
+/// is taken over.
+#[derive(Debug)]
+pub struct PackLock {
+    path: PathBuf,
+}
+
+impl PackLock {
+    /// Lock `pack_path` for `operation`, or fail naming the process that holds it.
+    pub fn acquire(pack_path: &str, operation: &str) -> Result<Self, Error> {
+        let path = PathBuf::from(format!("{}{}", pack_path, LOCK_SUFFIX));
+        let info = LockInfo { pid: std::process::id(), operation: operation.to_string(), since: chrono::Local::now().to_rfc3339() };
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(&serde_json::to_vec(&info)?).context(format!("writing lock file {} failed", path.display()))?;
+                    debug!("[LOCK] Locked '{}' for {}", pack_path, operation);
+                    return Ok(PackLock { path });
+                }
+                Err(e) if e.kind() == IoErrorKind::AlreadyExists => {
+                    let holder = std::fs::read(&path).ok().and_then(|data| serde_json::from_slice::<LockInfo>(&data).ok());
+                    match holder {
+                        Some(h) if h.pid == info.pid || process_alive(h.pid) => {
+                            return Err(Error::msg(format!(
+                                "'{}' is being written by PID {} ({}, since {}); if that process is gone, delete {}",
+                                pack_path, h.pid, h.operation, h.since, path.display())));
+                        }
+                        Some(h) => warn!("[LOCK] Taking over the lock on '{}' left by PID {} ({}), which has exited", pack_path, h.pid, h.operation),
+                        // Possibly still being written by the process that just created it.
+                        None if !older_than(&path, UNREADABLE_LOCK_AGE) => {
+                            return Err(Error::msg(format!("'{}' is locked by another process ({})", pack_path, path.display())));
+                        }
+                        None => warn!("[LOCK] Replacing unreadable lock file {}", path.display()),
+                    }
+                    std::fs::remove_file(&path).context(format!("removing stale lock file {} failed", path.display()))?;
+                }
+                Err(e) => return Err(Error::new(e).context(format!("creating lock file {} failed", path.display()))),
+            }
+        }
+        Err(Error::msg(format!("'{}' was locked again while taking over its stale lock", pack_path)))
+    }
+}
+
+impl Drop for PackLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("[LOCK] Could not remove lock file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn older_than(path: &Path, age: Duration) -> bool {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+        .and_then(|t| t.elapsed().ok())
+        .is_some_and(|elapsed| elapsed > age)
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn OpenProcess(access: u32, inherit: i32, pid: u32) -> *mut std::ffi::c_void;
+    fn GetExitCodeProcess(process: *mut std::ffi::c_void, code: *mut u32) -> i32;
+    fn CloseHandle(handle: *mut std::ffi::c_void) -> i32;
+}
+
+/// Whether a process with this id is running. When that can't be told, assume it is.
+#[cfg(windows)]
+pub fn process_alive(pid: u32) -> bool {
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    const STILL_ACTIVE: u32 = 259;
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if handle.is_null() {
+        return std::io::Error::last_os_error().raw_os_error() == Some(ERROR_ACCESS_DENIED);
+    }
+    let mut code = 0u32;
+    let queried = unsafe { GetExitCodeProcess(handle, &mut code) } != 0;
+    unsafe { CloseHandle(handle) };
+    !queried || code == STILL_ACTIVE
+}
+
+/// Whether a process with this id is running. When that can't be told, assume it is.
+#[cfg(target_os = "linux")]
+pub fn process_alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+/// Whether a process with this id is running. When that can't be told, assume it is.
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn process_alive(pid: u32) -> bool {
+    std::process::Command::new("ps")
+        .args(["-p", &pid.to_string()])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map_or(true, |s| s.success())
+}
+
+#[cfg(not(any(windows, unix)))]
+pub fn process_alive(_pid: u32) -> bool {
+    true
+}
@@ -12,7 +12,7 @@ use anyhow::{Context, Error};
 use log::{info, debug, trace, error, warn};
 use memmap2::Mmap;
 use rayon::prelude::*;
-use crate::common::FileEntry;
+use crate::common::{FileEntry, PackSettings};
 
 pub const PACK_HEADER_MAGIC_REG: &[u8; 4] = b"PACK";
 pub const PACK_HEADER_MAGIC_MABI: &[u8; 4] = b"MABI";
@@ -25,11 +25,25 @@ pub struct PackEntryV1 {
     pub compressed_size: u32,
 }
 
-fn write_file(root_dir: &str, rel_path: &str, content: Vec<u8>) -> Result<(), Error> {
+/// Legacy tables carry no checksums to catch a crafted file, so `--untrusted` refuses them.
+fn refuse_untrusted(input_path: &str, settings: &PackSettings) -> Result<(), Error> {
+    if settings.untrusted {
+        return Err(Error::msg(format!("'{}' is a legacy .pack archive, which is refused with --untrusted", input_path)));
+    }
+    Ok(())
+}
+
+fn write_file(root_dir: &str, rel_path: &str, content: Vec<u8>, settings: &PackSettings) -> Result<(), Error> {
     // Normalize regional separators: ¥, \, /
-    let normalized_path = rel_path.replace(['¥', '\\', '/'], &std::path::MAIN_SEPARATOR.to_string());
+    let normalized_path = rel_path.replace(['¥', '\\', '/'], std::path::MAIN_SEPARATOR_STR);
     trace!("[PACK_V1_WRITE] Preparing to write {} bytes to {}/{}", content.len(), root_dir, normalized_path);
-    let fname = Path::new(root_dir).join(normalized_path);
+    let fname = match crate::common::output_path(root_dir, &normalized_path, settings) {
+        Ok(fname) => fname,
+        Err(e) => {
+            warn!("[PACK_V1_WRITE] Skipping entry: {}", e);
+            return Ok(());
+        }
+    };
     let par = fname.parent().ok_or_else(|| {
         error!("[PACK_V1_WRITE] Could not get parent directory for {:?}", fname);
         Error::msg(format!("unrecognized path: {}", fname.to_string_lossy()))
@@ -40,7 +54,8 @@ fn write_file(root_dir: &str, rel_path: &str, content: Vec<u8>) -> Result<(), Er
     Ok(())
 }
 
-pub fn run_list_v1_data(input_path: &str) -> Result<Vec<FileEntry>, Error> {
+pub fn run_list_v1_data(input_path: &str, settings: &PackSettings) -> Result<Vec<FileEntry>, Error> {
+    refuse_untrusted(input_path, settings)?;
     info!("[PACK_V1] Listing metadata for .pack file: '{}'", input_path);
     let mut file = StdFile::open(input_path)?;
     let mut magic = [0u8; 4];
@@ -110,17 +125,17 @@ pub fn extract_single_v1(mmap: &Mmap, ent: &FileEntry) -> Result<Vec<u8>, Error>
     }
 }
 
-pub fn run_extract_v1(input_path: &str, output_dir: &str) -> Result<(), Error> {
+pub fn run_extract_v1(input_path: &str, output_dir: &str, settings: &PackSettings) -> Result<(), Error> {
     info!("[PACK_V1] Starting extraction of .pack file: '{}'", input_path);
     let file = StdFile::open(input_path).context(format!("Failed to open file: {}", input_path))?;
     let mmap = unsafe { Mmap::map(&file).context("Failed to memory map the file")? };
     
-    let entries = run_list_v1_data(input_path)?;
+    let entries = run_list_v1_data(input_path, settings)?;
     info!("[PACK_V1] Index parsed ({} entries). Starting parallel extraction...", entries.len());
 
     entries.par_iter().try_for_each(|ent| {
         let data = extract_single_v1(&mmap, ent)?;
-        write_file(output_dir, &ent.name, data)?;
+        write_file(output_dir, &ent.name, data, settings)?;
         Ok::<(), Error>(())
     })?;
 
@@ -128,8 +143,8 @@ pub fn run_extract_v1(input_path: &str, output_dir: &str) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn run_list_v1(input_path: &str) -> Result<Vec<String>, Error> {
-    run_list_v1_data(input_path).map(|v| v.into_iter().map(|e| e.name).collect())
+pub fn run_list_v1(input_path: &str, settings: &PackSettings) -> Result<Vec<String>, Error> {
+    run_list_v1_data(input_path, settings).map(|v| v.into_iter().map(|e| e.name).collect())
 }
 
 pub fn run_pack_v1(input_dir: &str, output_path: &str, version: u32) -> Result<(), Error> {
@@ -137,6 +152,7 @@ pub fn run_pack_v1(input_dir: &str, output_path: &str, version: u32) -> Result<(
     if let Some(parent) = Path::new(output_path).parent() {
         let _ = fs::create_dir_all(parent);
     }
+    let _lock = crate::pack_lock::PackLock::acquire(output_path, "pack")?;
     let mut output_file = StdFile::create(output_path).context("Failed to create output file")?;
     
     // Write MABI header (Header size: 16 bytes)
@@ -153,7 +169,7 @@ pub fn run_pack_v1(input_dir: &str, output_path: &str, version: u32) -> Result<(
             effective_root = parent.to_path_buf();
             debug!("[PACK_V1] Input is a file. Using parent as root: {:?}", effective_root);
         }
-    } else if input_path_obj.file_name().map_or(false, |n| n.to_string_lossy().to_lowercase() == "data") {
+    } else if input_path_obj.file_name().is_some_and(|n| n.to_string_lossy().to_lowercase() == "data") {
         if let Some(parent) = input_path_obj.parent() {
             effective_root = parent.to_path_buf();
             debug!("[PACK_V1] 'data' folder detected. Using parent as root: {:?}", effective_root);
@@ -235,7 +251,8 @@ pub fn run_pack_v1(input_dir: &str, output_path: &str, version: u32) -> Result<(
     Ok(())
 }
 
-pub fn run_list_logue_data(input_path: &str) -> Result<Vec<FileEntry>, Error> {
+pub fn run_list_logue_data(input_path: &str, settings: &PackSettings) -> Result<Vec<FileEntry>, Error> {
+    refuse_untrusted(input_path, settings)?;
     info!("[PACK_LOGUE] Listing metadata for Logue .pack: '{}'", input_path);
     let mut file = StdFile::open(input_path)?;
     
@@ -314,12 +331,12 @@ pub fn run_list_logue_data(input_path: &str) -> Result<Vec<FileEntry>, Error> {
     Ok(entries)
 }
 
-pub fn run_extract_logue(input_path: &str, output_dir: &str) -> Result<(), Error> {
+pub fn run_extract_logue(input_path: &str, output_dir: &str, settings: &PackSettings) -> Result<(), Error> {
     info!("[PACK_LOGUE] Starting extraction of Logue .pack: '{}'", input_path);
     let file = StdFile::open(input_path)?;
     let mmap = unsafe { Mmap::map(&file)? };
     
-    let entries = run_list_logue_data(input_path)?;
+    let entries = run_list_logue_data(input_path, settings)?;
     info!("[PACK_LOGUE] Index parsed ({} entries).", entries.len());
 
     entries.par_iter().try_for_each(|ent| {
@@ -346,7 +363,7 @@ pub fn run_extract_logue(input_path: &str, output_dir: &str) -> Result<(), Error
             data.to_vec()
         };
         
-        write_file(output_dir, &ent.name, final_data)?;
+        write_file(output_dir, &ent.name, final_data, settings)?;
         Ok::<(), Error>(())
     })?;
 
@@ -0,0 +1,446 @@
+// reader.rs - Opened .it archive with resolved keys
+
+use crate::common::{self, FileEntry, FileHeader, FLAG_ALL_ENCRYPTED, FLAG_HEAD_ENCRYPTED};
+use crate::{common_ext, encryption, extract, pack};
+use anyhow::Error;
+use log::debug;
+use memmap2::Mmap;
+use regex::Regex;
+use std::fs::File as StdFile;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Entry names (with `/` turned into `\\`) sorted for binary search, built once per
+/// opened pack so name lookups and prefix filters don't scan every entry.
+pub struct NameIndex {
+    keys: Vec<(String, usize)>,
+}
+
+impl NameIndex {
+    pub fn build(entries: &[FileEntry]) -> Self {
+        let mut keys: Vec<(String, usize)> = entries.iter().enumerate().map(|(i, e)| (e.name.replace('/', "\\"), i)).collect();
+        keys.sort_unstable();
+        NameIndex { keys }
+    }
+
+    /// Index of the first entry named `name` (either slash style).
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        let key = name.replace('/', "\\");
+        let pos = self.keys.partition_point(|(k, _)| k.as_str() < key.as_str());
+        self.keys.get(pos).filter(|(k, _)| *k == key).map(|(_, i)| *i)
+    }
+
+    /// Indices of the entries whose name starts with `prefix`, in entry order.
+    pub fn with_prefix(&self, prefix: &str) -> Vec<usize> {
+        let prefix = prefix.replace('/', "\\");
+        let start = self.keys.partition_point(|(k, _)| k.as_str() < prefix.as_str());
+        let mut found: Vec<usize> = self.keys[start..].iter()
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, i)| *i)
+            .collect();
+        found.sort_unstable();
+        found
+    }
+}
+
+/// The literal text every match of `pattern` must start with, if it is anchored with
+/// `^` (e.g. `^data\\gfx\\.*\.dds$` gives `data\\gfx\\`). `None` when no such prefix
+/// can be read off safely (no anchor, alternation, flags, ...).
+pub fn literal_prefix(pattern: &str) -> Option<String> {
+    let body = pattern.strip_prefix('^')?;
+    if body.contains('|') {
+        return None;
+    }
+    let mut prefix = String::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        let literal = match c {
+            '\\' => match chars.peek() {
+                Some(&n) if "\\./-()[]{}*+?|^$ ".contains(n) => { chars.next(); n }
+                _ => break,
+            },
+            '.' | '[' | ']' | '(' | ')' | '{' | '}' | '*' | '+' | '?' | '^' | '$' => break,
+            c => c,
+        };
+        // A quantifier makes the character before it optional or repeatable.
+        if matches!(chars.peek(), Some('?') | Some('*') | Some('{')) {
+            break;
+        }
+        prefix.push(literal);
+    }
+    Some(prefix)
+}
+
+/// Bytes a `PackReader` decodes from: a mapped file, or a buffer the caller already holds.
+pub type PackBytes = Box<dyn AsRef<[u8]> + Send + Sync>;
+
+/// A `.it` archive (memory-mapped or in memory) whose header/entries salts have already
+/// been found. Entry payloads are decoded lazily on request.
+pub struct PackReader {
+    data: PackBytes,
+    pub path: String,
+    pub name_variant: String,
+    pub header_salt: String,
+    pub entries_salt: String,
+    pub iv0: u32,
+    pub mode: encryption::Snow2Mode,
+    pub header_offset: u64,
+    pub content_offset: u64,
+    pub entries: Vec<FileEntry>,
+    /// The settings the pack was opened with; entry reads go on using them.
+    pub settings: common::PackSettings,
+    index: NameIndex,
+}
+
+impl PackReader {
+    /// Open `path` and run the two-phase salt search (CLI key first, then `salts`) with the
+    /// default `PackSettings`.
+    pub fn open(path: &str, cli_key: Option<String>, salts: &[String]) -> Result<Self, Error> {
+        Self::open_with_settings(path, cli_key, salts, &common::PackSettings::default())
+    }
+
+    /// `open` with the settings from the global options.
+    pub fn open_with_settings(path: &str, cli_key: Option<String>, salts: &[String], settings: &common::PackSettings) -> Result<Self, Error> {
+        let file = StdFile::open(path)?;
+        let data = unsafe { Mmap::map(&file)? };
+        Self::from_bytes_with_settings(path, data, cli_key, salts, settings)
+    }
+
+    /// Like `open` for an archive already in memory (a `Vec<u8>`, `Arc<[u8]>`, a mapping...).
+    /// `path` is only used for key derivation, which depends on the file name, and messages.
+    pub fn from_bytes(path: &str, data: impl AsRef<[u8]> + Send + Sync + 'static, cli_key: Option<String>, salts: &[String]) -> Result<Self, Error> {
+        Self::from_bytes_with_settings(path, data, cli_key, salts, &common::PackSettings::default())
+    }
+
+    /// `from_bytes` with the settings from the global options.
+    pub fn from_bytes_with_settings(path: &str, data: impl AsRef<[u8]> + Send + Sync + 'static, cli_key: Option<String>, salts: &[String], settings: &common::PackSettings) -> Result<Self, Error> {
+        let data: PackBytes = Box::new(data);
+        let bytes = (*data).as_ref();
+        if bytes.len() >= 4 && (&bytes[0..4] == b"PACK" || &bytes[0..4] == b"MABI") {
+            return Err(Error::msg(format!("'{}' is a legacy .pack archive, not an .it archive", path)));
+        }
+
+        let hit = common_ext::search_keys(bytes, path, cli_key, salts, None, settings)?;
+        common::check_entry_policy(&hit.entries, settings)?;
+        debug!("[READER] Opened '{}': {} entries, HEADER='{}', ENTRIES='{}', Offset=0x{:X}, IV={}, Mode={:?}",
+            path, hit.entries.len(), hit.header_salt, hit.entries_salt, hit.header_offset, hit.iv0, hit.mode);
+
+        Ok(PackReader {
+            data,
+            path: path.to_string(),
+            name_variant: hit.name_variant,
+            header_salt: hit.header_salt,
+            entries_salt: hit.entries_salt,
+            iv0: hit.iv0,
+            mode: hit.mode,
+            header_offset: hit.header_offset,
+            content_offset: hit.content_offset,
+            index: NameIndex::build(&hit.entries),
+            entries: hit.entries,
+            settings: settings.clone(),
+        })
+    }
+
+    /// Like `from_bytes` for any `Read + Seek` source (a `Cursor`, a network stream
+    /// buffered to a temp file...). The whole archive is read into memory from the start.
+    pub fn from_reader<R: Read + Seek>(path: &str, mut rd: R, cli_key: Option<String>, salts: &[String]) -> Result<Self, Error> {
+        let mut data = Vec::new();
+        rd.seek(SeekFrom::Start(0))?;
+        rd.read_to_end(&mut data)?;
+        Self::from_bytes(path, data, cli_key, salts)
+    }
+
+    /// The whole archive image.
+    pub fn bytes(&self) -> &[u8] {
+        (*self.data).as_ref()
+    }
+
+    pub fn entries(&self) -> &[FileEntry] {
+        &self.entries
+    }
+
+    /// Records whose stored checksum is wrong. Always empty unless the pack was opened
+    /// with `force` in its settings.
+    pub fn checksum_failures(&self) -> Vec<common::EntryChecksumFailure> {
+        common::validate_all_entries(&self.entries, &self.settings)
+    }
+
+    /// The header record at `header_offset`, decoded again with the resolved key.
+    pub fn header(&self) -> Result<FileHeader, Error> {
+        let mut rd = std::io::Cursor::new(self.bytes());
+        common::probe_header_iv(&mut rd, &self.name_variant, &self.header_salt, self.header_offset, self.iv0, self.mode)
+            .map_err(|stage| Error::msg(format!("Header of '{}' no longer decodes ({:?})", self.path, stage)))
+    }
+
+    pub fn find(&self, name: &str) -> Option<&FileEntry> {
+        self.index.lookup(name).map(|i| &self.entries[i])
+    }
+
+    /// Entries matching any of `filters` (all entries when empty), in entry order.
+    /// When every filter starts with an anchored literal, only entries under those
+    /// prefixes are tested.
+    pub fn select(&self, filters: &[Regex]) -> Vec<&FileEntry> {
+        if filters.is_empty() {
+            return self.entries.iter().collect();
+        }
+        let prefixes: Option<Vec<String>> = filters.iter().map(|re| literal_prefix(re.as_str())).collect();
+        let candidates: Vec<usize> = match prefixes {
+            Some(prefixes) => {
+                let mut found: Vec<usize> = prefixes.iter().flat_map(|p| self.index.with_prefix(p)).collect();
+                found.sort_unstable();
+                found.dedup();
+                found
+            }
+            None => (0..self.entries.len()).collect(),
+        };
+        candidates.into_iter()
+            .map(|i| &self.entries[i])
+            .filter(|e| filters.iter().any(|re| re.is_match(&e.name)))
+            .collect()
+    }
+
+    /// The stored (still encrypted/compressed) bytes of an entry.
+    pub fn read_raw(&self, ent: &FileEntry) -> Result<&[u8], Error> {
+        let start = self.content_offset + ent.offset as u64 * 1024;
+        let end = start + ent.raw_size as u64;
+        if end > self.bytes().len() as u64 {
+            return Err(Error::msg(format!("Raw size for '{}' extends beyond archive length.", ent.name)));
+        }
+        Ok(&self.bytes()[start as usize..end as usize])
+    }
+
+    /// Where an entry's stored bytes are and how they are encrypted, without decoding
+    /// anything. The bytes themselves are `read_raw(ent)`.
+    pub fn block_range(&self, ent: &FileEntry) -> Result<BlockRange, Error> {
+        let start = self.content_offset + ent.offset as u64 * 1024;
+        let len = ent.raw_size as u64;
+        if start + len > self.file_len() {
+            return Err(Error::msg(format!("Raw size for '{}' extends beyond archive length.", ent.name)));
+        }
+        let encrypted_len = if ent.flags & FLAG_ALL_ENCRYPTED != 0 {
+            len
+        } else if ent.flags & FLAG_HEAD_ENCRYPTED != 0 {
+            len.min(1024)
+        } else {
+            0
+        };
+        Ok(BlockRange {
+            name: ent.name.clone(),
+            flags: ent.flags,
+            start,
+            len,
+            encrypted_len,
+            blocks: ent.raw_size.div_ceil(1024),
+            entry_key: ent.key,
+            file_key: encryption::gen_file_key(&ent.name, &ent.key),
+            iv0: self.iv0,
+            mode: self.mode,
+        })
+    }
+
+    /// `block_range` for every entry, in entry order.
+    pub fn block_ranges(&self) -> Result<Vec<BlockRange>, Error> {
+        self.entries.iter().map(|ent| self.block_range(ent)).collect()
+    }
+
+    /// Size of the archive file in bytes.
+    pub fn file_len(&self) -> u64 {
+        self.bytes().len() as u64
+    }
+
+    /// End of the content area: only the build-info record (if any) and the footer follow it.
+    pub fn content_end(&self) -> u64 {
+        let len = self.bytes().len();
+        let meta = if len >= 12 && &self.bytes()[len - 8..len - 4] == pack::META_MAGIC {
+            u32::from_le_bytes(self.bytes()[len - 12..len - 8].try_into().unwrap()) as u64 + 8
+        } else {
+            0
+        };
+        (len as u64).saturating_sub(4 + meta).max(self.content_offset)
+    }
+
+    /// The build-info record written by `pack`, if the archive has one.
+    pub fn metadata(&self) -> Option<pack::PackMetadata> {
+        pack::PackMetadata::read_from(self.bytes())
+    }
+
+    /// Shallow check that an entry's data is plausible (see `extract::probe_entry_data`).
+    pub fn probe_entry(&self, ent: &FileEntry) -> Result<(), String> {
+        extract::probe_entry_data(self.bytes(), self.content_offset, ent, self.iv0, self.mode)
+    }
+
+    /// Decrypt and decompress an entry into memory.
+    pub fn read_entry(&self, ent: &FileEntry) -> Result<Vec<u8>, Error> {
+        extract::extract_single_file_to_memory(self.bytes(), self.content_offset, ent, self.iv0, self.mode)
+    }
+
+    /// Bytes `offset..offset + len` of the entry called `name`, decoding only as much of it
+    /// as the range needs: for previews of large entries or HTTP range requests. The range
+    /// is clamped to the entry's size, so `len` of `u64::MAX` reads to the end.
+    pub fn read_entry_range(&self, name: &str, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        let ent = self.find(name).ok_or_else(|| Error::msg(format!("No entry '{}' in '{}'", name, self.path)))?;
+        extract::extract_range_to_memory(self.bytes(), self.content_offset, ent, self.iv0, self.mode, offset, len)
+    }
+
+    /// The layout of `entries` (see `PackManifest`), with their keys when `with_keys`.
+    /// Nothing of the data region is read.
+    pub fn manifest<'e>(&self, entries: impl IntoIterator<Item = &'e FileEntry>, with_keys: bool) -> PackManifest {
+        PackManifest {
+            archive: self.path.clone(),
+            name_variant: self.name_variant.clone(),
+            header_salt: self.header_salt.clone(),
+            entries_salt: self.entries_salt.clone(),
+            iv0: self.iv0,
+            mode: format!("{:?}", self.mode),
+            header_offset: self.header_offset,
+            content_offset: self.content_offset,
+            file_len: self.file_len(),
+            entries: entries.into_iter().map(|ent| ManifestEntry {
+                name: ent.name.clone(),
+                checksum: ent.checksum,
+                flags: ent.flags,
+                data_offset: self.content_offset + ent.offset as u64 * 1024,
+                raw_size: ent.raw_size,
+                original_size: ent.original_size,
+                entry_key: with_keys.then(|| hex(&ent.key)),
+                file_key: with_keys.then(|| hex(&encryption::gen_file_key(&ent.name, &ent.key))),
+            }).collect(),
+        }
+    }
+
+    /// Everything an external tool needs to decode entries with SNOW2 alone: the
+    /// cipher settings and, per entry, its 16-byte key and the file key derived from it.
+    pub fn key_export(&self) -> KeyExport {
+        KeyExport {
+            archive: self.path.clone(),
+            name_variant: self.name_variant.clone(),
+            header_salt: self.header_salt.clone(),
+            entries_salt: self.entries_salt.clone(),
+            iv0: self.iv0,
+            mode: format!("{:?}", self.mode),
+            content_offset: self.content_offset,
+            entries: self.entries.iter().map(|ent| EntryKeys {
+                name: ent.name.clone(),
+                flags: ent.flags,
+                data_offset: self.content_offset + ent.offset as u64 * 1024,
+                raw_size: ent.raw_size,
+                original_size: ent.original_size,
+                entry_key: hex(&ent.key),
+                file_key: hex(&encryption::gen_file_key(&ent.name, &ent.key)),
+            }).collect(),
+        }
+    }
+}
+
+/// Written by `extract --manifest-only`: the layout of a pack, decoded from its header and
+/// entries table alone, for mirroring it and fetching entries later by byte range. Entry
+/// data spans `data_offset..data_offset + raw_size`, encoded as described on `KeyExport`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PackManifest {
+    pub archive: String,
+    pub name_variant: String,
+    pub header_salt: String,
+    pub entries_salt: String,
+    pub iv0: u32,
+    pub mode: String,
+    pub header_offset: u64,
+    pub content_offset: u64,
+    pub file_len: u64,
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub checksum: u32,
+    pub flags: u32,
+    pub data_offset: u64,
+    pub raw_size: u32,
+    pub original_size: u32,
+    /// The key stored in the entries table (hex); only with keys requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry_key: Option<String>,
+    /// SNOW2 key for the payload (hex); only with keys requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_key: Option<String>,
+}
+
+/// The stored region of one entry, for patchers and delta tools that copy or splice
+/// data between packs byte for byte. Offsets are absolute file offsets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockRange {
+    pub name: String,
+    pub flags: u32,
+    /// First stored byte; always on a 1024-byte block boundary of the content area.
+    pub start: u64,
+    /// Stored length (`raw_size`).
+    pub len: u64,
+    /// Leading bytes encrypted with `file_key`: all of them with `FLAG_ALL_ENCRYPTED`,
+    /// at most 1024 with `FLAG_HEAD_ENCRYPTED`, none otherwise.
+    pub encrypted_len: u64,
+    /// 1024-byte blocks the data spans, padding included.
+    pub blocks: u32,
+    /// The key stored in the entries table.
+    pub entry_key: [u8; 16],
+    /// SNOW2 key for the payload, derived from the name and `entry_key`.
+    pub file_key: [u8; 16],
+    pub iv0: u32,
+    pub mode: encryption::Snow2Mode,
+}
+
+impl BlockRange {
+    /// One past the last stored byte.
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+
+    /// The encrypted part of the stored bytes, as absolute offsets.
+    pub fn encrypted(&self) -> std::ops::Range<u64> {
+        self.start..self.start + self.encrypted_len
+    }
+
+    /// The stored bytes can be moved to another pack unchanged only when it uses the
+    /// same IV and cipher mode; the keys travel with the entry.
+    pub fn portable_to(&self, iv0: u32, mode: encryption::Snow2Mode) -> bool {
+        self.encrypted_len == 0 || (self.iv0 == iv0 && self.mode == mode)
+    }
+}
+
+/// Lowercase hex digits of `bytes`, as keys are written in `--export-keys` files.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Written by `--export-keys`. Entry data starts at `data_offset` and is `raw_size`
+/// bytes; with `FLAG_HEAD_ENCRYPTED` only its first 1024 bytes are SNOW2-encrypted with
+/// `file_key`, with `FLAG_ALL_ENCRYPTED` all of it, and `FLAG_COMPRESSED` data is zlib after that.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct KeyExport {
+    pub archive: String,
+    pub name_variant: String,
+    pub header_salt: String,
+    pub entries_salt: String,
+    pub iv0: u32,
+    pub mode: String,
+    pub content_offset: u64,
+    pub entries: Vec<EntryKeys>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EntryKeys {
+    pub name: String,
+    pub flags: u32,
+    pub data_offset: u64,
+    pub raw_size: u32,
+    pub original_size: u32,
+    /// The key stored in the entries table (hex).
+    pub entry_key: String,
+    /// SNOW2 key for the payload, derived from the name and `entry_key` (hex).
+    pub file_key: String,
+}
+
+impl KeyExport {
+    pub fn write_json(&self, path: &str) -> Result<(), Error> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
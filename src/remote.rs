@@ -0,0 +1,227 @@
+// remote.rs - Packs read from a web server with HTTP Range requests
+
+use crate::common::{self, FileEntry, StdioSpool};
+use crate::common_ext::KeySearchHit;
+use crate::key_cache;
+use anyhow::Error;
+use log::{debug, info};
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Remote bytes are fetched in whole chunks of this size, so the many small reads of a
+/// key search share a few requests.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Whether `input` names a pack on a web server (`http://` or `https://`).
+pub fn is_url(input: &str) -> bool {
+    let lower = input.to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// A pack on a web server, mirrored into a sparse local file of the same name. Only the
+/// ranges that are read get downloaded; the rest of the mirror stays zero-filled, so the
+/// usual list and extract code can open `path()` once the needed ranges are fetched.
+pub struct RemotePack {
+    url: String,
+    client: Client,
+    len: u64,
+    file: File,
+    fetched: Vec<bool>,
+    downloaded: u64,
+    // Dropped last: removes the mirror's temporary folder.
+    spool: StdioSpool,
+}
+
+impl RemotePack {
+    /// Open `url` without downloading anything but its first and last chunk, where the
+    /// header candidates live. The mirror is named after the URL's last path segment, or
+    /// `as_name` when the URL doesn't end with the pack's real name. Refused with
+    /// `settings.untrusted`.
+    pub fn open(url: &str, as_name: Option<&str>, settings: &common::PackSettings) -> Result<Self, Error> {
+        if settings.untrusted {
+            return Err(Error::msg("Remote packs can't be read with --untrusted; download the pack first"));
+        }
+        let name = match as_name {
+            Some(name) => name.to_string(),
+            None => url_file_name(url).ok_or_else(|| Error::msg(format!("'{}' doesn't end with a pack file name; name it with --as-name", url)))?,
+        };
+        let client = Client::builder().build()?;
+        let len = remote_len(&client, url)?;
+        let spool = StdioSpool::new(&name)?;
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(spool.path())?;
+        file.set_len(len)?;
+        let chunks = len.div_ceil(CHUNK_SIZE) as usize;
+        let mut pack = RemotePack { url: url.to_string(), client, len, file, fetched: vec![false; chunks], downloaded: 0, spool };
+        pack.fetch(0, CHUNK_SIZE)?;
+        pack.fetch(len.saturating_sub(CHUNK_SIZE), CHUNK_SIZE)?;
+        Ok(pack)
+    }
+
+    /// The local mirror; only fetched ranges hold the pack's bytes.
+    pub fn path(&self) -> &str {
+        self.spool.path()
+    }
+
+    /// Size of the remote pack in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes downloaded so far.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// Make sure `len` bytes from `start` are in the mirror. Runs of missing chunks are
+    /// fetched with one request each.
+    pub fn fetch(&mut self, start: u64, len: u64) -> Result<(), Error> {
+        let end = start.saturating_add(len).min(self.len);
+        if start >= end {
+            return Ok(());
+        }
+        let (first, last) = ((start / CHUNK_SIZE) as usize, ((end - 1) / CHUNK_SIZE) as usize);
+        let mut chunk = first;
+        while chunk <= last {
+            if self.fetched[chunk] {
+                chunk += 1;
+                continue;
+            }
+            let run_start = chunk;
+            while chunk <= last && !self.fetched[chunk] {
+                chunk += 1;
+            }
+            let from = run_start as u64 * CHUNK_SIZE;
+            let to = (chunk as u64 * CHUNK_SIZE).min(self.len);
+            self.fetch_range(from, to)?;
+            self.fetched[run_start..chunk].iter_mut().for_each(|f| *f = true);
+        }
+        Ok(())
+    }
+
+    fn fetch_range(&mut self, from: u64, to: u64) -> Result<(), Error> {
+        debug!("[REMOTE] GET {} bytes={}-{}", self.url, from, to - 1);
+        let mut response = self.client.get(&self.url).header(RANGE, format!("bytes={}-{}", from, to - 1)).send()?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::msg(format!("'{}' answered {} to a range request; the server must support HTTP Range requests", self.url, response.status())));
+        }
+        self.file.seek(SeekFrom::Start(from))?;
+        let copied = std::io::copy(&mut (&mut response).take(to - from), &mut self.file)?;
+        if copied != to - from {
+            return Err(Error::msg(format!("'{}' sent {} of the {} bytes requested at offset {}", self.url, copied, to - from, from)));
+        }
+        self.downloaded += copied;
+        Ok(())
+    }
+
+    /// Find the salts that open the pack, reading the header and entries table through
+    /// range requests. Salts are tried one at a time, CLI key first, since each probe
+    /// may download.
+    pub fn search_keys(&mut self, cli_skey: Option<String>, loaded_salts: &[String], settings: &common::PackSettings) -> Result<KeySearchHit, Error> {
+        let name = common::get_final_file_name(self.path())?;
+        let mut keys_to_try: Vec<String> = Vec::new();
+        let has_cli_key = cli_skey.is_some();
+        if let Some(key) = cli_skey { keys_to_try.push(key); }
+        for salt in loaded_salts {
+            if !keys_to_try.contains(salt) { keys_to_try.push(salt.clone()); }
+        }
+        key_cache::prioritize(&name, &mut keys_to_try[usize::from(has_cli_key)..]);
+        for variant in common::key_name_variants(self.path(), None, settings)? {
+            for header_skey in &keys_to_try {
+                let Ok(Some((_, h_off, iv0, mode))) = common::find_header_only(&mut self.reader(), &variant, header_skey) else { continue };
+                let entries_candidates = std::iter::once(header_skey).chain(keys_to_try.iter().filter(|s| *s != header_skey));
+                for entries_skey in entries_candidates {
+                    if let Ok((_, entries, c_off)) = common::read_meta_iv_mode_two_key(&variant, header_skey, entries_skey, &mut self.reader(), h_off, iv0, mode, settings) {
+                        info!("[REMOTE] Opened '{}': HEADER='{}', ENTRIES='{}'", self.url, header_skey, entries_skey);
+                        return Ok(KeySearchHit {
+                            entries,
+                            header_salt: header_skey.clone(),
+                            entries_salt: entries_skey.clone(),
+                            name_variant: variant,
+                            iv0,
+                            header_offset: h_off,
+                            mode,
+                            content_offset: c_off,
+                        });
+                    }
+                }
+            }
+        }
+        Err(Error::msg(format!("No salt opens '{}'", self.url)))
+    }
+
+    /// Download the stored bytes of `entries`.
+    pub fn fetch_entries<'a>(&mut self, content_offset: u64, entries: impl IntoIterator<Item = &'a FileEntry>) -> Result<(), Error> {
+        for ent in entries {
+            self.fetch(content_offset + ent.offset as u64 * 1024, ent.raw_size as u64)?;
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn reader(&mut self) -> RemoteReader<'_> {
+        RemoteReader { pack: self, pos: 0 }
+    }
+}
+
+/// `Read + Seek` over a remote pack, fetching what is read.
+struct RemoteReader<'a> {
+    pack: &'a mut RemotePack,
+    pos: u64,
+}
+
+impl Read for RemoteReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = (buf.len() as u64).min(self.pack.len.saturating_sub(self.pos));
+        if n == 0 {
+            return Ok(0);
+        }
+        self.pack.fetch(self.pos, n).map_err(std::io::Error::other)?;
+        self.pack.file.seek(SeekFrom::Start(self.pos))?;
+        let read = self.pack.file.read(&mut buf[..n as usize])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for RemoteReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.pack.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = target.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the pack"))?;
+        Ok(self.pos)
+    }
+}
+
+/// The last path segment of `url`, without query or fragment.
+fn url_file_name(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next()?;
+    let name = path.rsplit('/').next()?;
+    (!name.is_empty() && !name.contains(':')).then(|| name.to_string())
+}
+
+/// The size of the resource at `url`, from a one-byte range request.
+fn remote_len(client: &Client, url: &str) -> Result<u64, Error> {
+    let response = client.get(url).header(RANGE, "bytes=0-0").send()?;
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => response.headers().get(CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|total| total.parse().ok())
+            .ok_or_else(|| Error::msg(format!("'{}' sent no usable Content-Range header", url))),
+        status if status.is_success() => {
+            let len = response.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).unwrap_or("?");
+            Err(Error::msg(format!("'{}' ignores HTTP Range requests (it would send all {} bytes); download the pack instead", url, len)))
+        }
+        status => Err(Error::msg(format!("'{}' answered {}", url, status))),
+    }
+}
@@ -0,0 +1,160 @@
+// run_summary.rs - Machine-readable summary of a whole run (`--json-summary`)
+
+use crate::common::OperationSummary;
+use crate::common_ext::KeySearchHit;
+use anyhow::Error;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The summary being collected and where `finish` writes it.
+static ACTIVE: Lazy<Mutex<Option<(RunSummary, String)>>> = Lazy::new(|| Mutex::new(None));
+/// Kept apart from `ACTIVE`: it is bumped from every key search worker.
+static SALTS_TRIED: AtomicU64 = AtomicU64::new(0);
+
+/// What one run did, for CI jobs that assert on results instead of parsing logs.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct RunSummary {
+    /// Subcommand that ran.
+    pub command: String,
+    pub success: bool,
+    /// The error that ended the run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub elapsed_ms: u64,
+    /// Entries packed, extracted or listed, and those skipped because they failed.
+    pub entries: usize,
+    pub failed: usize,
+    /// Packs opened plus files packed; decoded entries extracted plus packs written.
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Salt/name combinations probed by key searches.
+    pub salts_tried: u64,
+    /// The salts, offsets and cipher settings that opened each pack.
+    pub keys: Vec<KeyRecord>,
+    /// Every pack opened or written, with the error that stopped it, if any.
+    pub files: Vec<FileRecord>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct KeyRecord {
+    pub path: String,
+    pub name_variant: String,
+    pub header_salt: String,
+    pub entries_salt: String,
+    pub header_offset: u64,
+    pub content_offset: u64,
+    pub iv0: u32,
+    pub mode: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FileRecord {
+    pub path: String,
+    pub bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Start collecting a summary for `command`, to be written to `target` (`-` for stdout)
+/// by `finish`. Until this is called, the `record_*` functions are no-ops.
+pub fn enable(command: &str, target: &str) {
+    SALTS_TRIED.store(0, Ordering::Relaxed);
+    *ACTIVE.lock().unwrap() = Some((RunSummary { command: command.to_string(), ..RunSummary::default() }, target.to_string()));
+}
+
+fn with_active(f: impl FnOnce(&mut RunSummary)) {
+    if let Some((summary, _)) = ACTIVE.lock().unwrap().as_mut() {
+        f(summary);
+    }
+}
+
+fn file_record<'a>(summary: &'a mut RunSummary, path: &str) -> &'a mut FileRecord {
+    match summary.files.iter().position(|f| f.path == path) {
+        Some(i) => &mut summary.files[i],
+        None => {
+            summary.files.push(FileRecord { path: path.to_string(), bytes: 0, error: None });
+            summary.files.last_mut().unwrap()
+        }
+    }
+}
+
+/// One salt/name combination probed by a key search.
+pub fn count_salt_tried() {
+    SALTS_TRIED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The pack at `path` (`len` bytes) was opened with `hit`.
+pub fn record_key(path: &str, len: u64, hit: &KeySearchHit) {
+    with_active(|summary| {
+        if summary.files.iter().all(|f| f.path != path) {
+            summary.bytes_read += len;
+        }
+        file_record(summary, path).bytes = len;
+        summary.keys.retain(|k| k.path != path);
+        summary.keys.push(KeyRecord {
+            path: path.to_string(),
+            name_variant: hit.name_variant.clone(),
+            header_salt: hit.header_salt.clone(),
+            entries_salt: hit.entries_salt.clone(),
+            header_offset: hit.header_offset,
+            content_offset: hit.content_offset,
+            iv0: hit.iv0,
+            mode: format!("{:?}", hit.mode),
+        });
+    });
+}
+
+/// A pack of `len` bytes was written to `path`.
+pub fn record_written(path: &str, len: u64) {
+    with_active(|summary| {
+        summary.bytes_written += len;
+        file_record(summary, path).bytes = len;
+    });
+}
+
+/// Processing the pack at `path` failed with `error`; the run went on with other packs.
+pub fn record_file_error(path: &str, error: &Error) {
+    with_active(|summary| file_record(summary, path).error = Some(error.to_string()));
+}
+
+/// Add the entry counts and warnings of one operation. Its decoded bytes count as
+/// written for `extract` and `batch` and as read for `pack`.
+pub fn record_operation(operation: &OperationSummary) {
+    with_active(|summary| {
+        summary.entries += operation.entries;
+        summary.failed += operation.failed;
+        match summary.command.as_str() {
+            "extract" | "batch" => summary.bytes_written += operation.bytes,
+            "pack" => summary.bytes_read += operation.bytes,
+            _ => {}
+        }
+        summary.warnings.extend(operation.warnings.iter().cloned());
+    });
+}
+
+/// Stop collecting and, if `enable` was called, write the summary with the run's
+/// outcome filled in.
+pub fn finish(result: &Result<(), Error>, elapsed: Duration) -> Result<(), Error> {
+    let Some((mut summary, target)) = ACTIVE.lock().unwrap().take() else { return Ok(()) };
+    summary.success = result.is_ok();
+    summary.error = result.as_ref().err().map(|e| format!("{:#}", e));
+    summary.elapsed_ms = elapsed.as_millis() as u64;
+    summary.salts_tried = SALTS_TRIED.load(Ordering::Relaxed);
+    summary.write_json(&target)
+}
+
+impl RunSummary {
+    /// Write the summary as pretty-printed JSON to `path`, or to stdout for `-`.
+    pub fn write_json(&self, path: &str) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(self)?;
+        if path == "-" {
+            println!("{}", json);
+        } else {
+            std::fs::write(path, json)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+// snapshot.rs - Entry snapshots of a client's packs, and comparing two snapshots or two packs
+
+use crate::common::{self, FileEntry, FLAG_SYMLINK};
+use crate::reader::PackReader;
+use crate::verify::{md5_hex, normalize_name};
+use anyhow::{Context, Error};
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Bumped when the snapshot JSON changes incompatibly.
+pub const SNAPSHOT_FORMAT: u32 = 1;
+
+/// One decoded entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntrySnapshot {
+    pub size: u32,
+    pub flags: u32,
+    /// MD5 of the decoded content; empty when the entry could not be decoded.
+    pub md5: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PackSnapshot {
+    /// Header salt that opened the pack.
+    #[serde(default)]
+    pub salt: String,
+    /// Entry name (forward slashes) -> entry.
+    pub entries: BTreeMap<String, EntrySnapshot>,
+}
+
+/// Every entry of a set of packs, keyed by pack file name, as written by `snapshot`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub format: u32,
+    pub packs: BTreeMap<String, PackSnapshot>,
+    /// Packs that could not be opened, with the reason.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub failed: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path).context(format!("reading snapshot {} failed", path))?;
+        let snapshot: Snapshot = serde_json::from_str(&text).context(format!("{} is not a snapshot", path))?;
+        if snapshot.format != SNAPSHOT_FORMAT {
+            return Err(Error::msg(format!("{} has snapshot format {}, expected {}", path, snapshot.format, SNAPSHOT_FORMAT)));
+        }
+        Ok(snapshot)
+    }
+
+    pub fn write_json(&self, path: &str) -> Result<(), Error> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// The entries the client sees: when several packs hold the same name (compared
+    /// case-insensitively), the pack that sorts last wins, as when extracting them in
+    /// order into one folder. Maps lowercase name -> (pack, name, entry).
+    pub fn effective_entries(&self) -> BTreeMap<String, (&str, &str, &EntrySnapshot)> {
+        let mut effective = BTreeMap::new();
+        for (pack, snap) in &self.packs {
+            for (name, ent) in &snap.entries {
+                effective.insert(name.to_lowercase(), (pack.as_str(), name.as_str(), ent));
+            }
+        }
+        effective
+    }
+}
+
+/// Decode every entry of `archives`, `pack_parallel` packs at a time on a shared pool of
+/// `threads` workers (see `common::map_packs`), and record its size, flags and digest.
+/// Packs that can't be opened are listed in `failed` rather than failing the whole snapshot.
+pub fn run_snapshot(archives: &[String], cli_key: Option<String>, loaded_salts: &[String], settings: &common::PackSettings, threads: usize, pack_parallel: usize) -> Result<Snapshot, Error> {
+    let per_pack = common::map_packs(archives, pack_parallel, threads, |archive, pool| {
+        let reader = match common::catch_panic(archive, || PackReader::open_with_settings(archive, cli_key.clone(), loaded_salts, settings)) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("[SNAPSHOT] {}: cannot open: {}", archive, e);
+                return Err(format!("cannot open: {}", e));
+            }
+        };
+        let entries: BTreeMap<String, EntrySnapshot> = pool.install(|| {
+            reader.entries().par_iter()
+                .filter(|e| !e.is_dir_marker() && e.flags & FLAG_SYMLINK == 0)
+                .map(|ent| {
+                    let (md5, error) = match common::catch_panic(&ent.name, || reader.read_entry(ent)) {
+                        Ok(data) => (md5_hex(&data), None),
+                        Err(e) => (String::new(), Some(e.to_string())),
+                    };
+                    (normalize_name(&ent.name), EntrySnapshot { size: ent.original_size, flags: ent.flags, md5, error })
+                })
+                .collect()
+        });
+        let unreadable = entries.values().filter(|e| e.error.is_some()).count();
+        if unreadable > 0 {
+            warn!("[SNAPSHOT] {}: {} of {} entries could not be decoded", archive, unreadable, entries.len());
+        }
+        info!("[SNAPSHOT] {}: {} entries", archive, entries.len());
+        Ok(PackSnapshot { salt: reader.key_export().header_salt, entries })
+    })?;
+    let mut snapshot = Snapshot { format: SNAPSHOT_FORMAT, ..Snapshot::default() };
+    for (archive, result) in archives.iter().zip(per_pack) {
+        let pack_name = Path::new(archive).file_name().map_or_else(|| archive.clone(), |n| n.to_string_lossy().into_owned());
+        match result {
+            Ok(pack) => { snapshot.packs.insert(pack_name, pack); }
+            Err(reason) => { snapshot.failed.insert(pack_name, reason); }
+        }
+    }
+    Ok(snapshot)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    /// Different content (or size).
+    Modified,
+    /// Same content, now served from another pack.
+    Moved,
+}
+
+impl ChangeKind {
+    /// One-letter code used in the `compare` listing.
+    pub fn code(self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Removed => 'D',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Moved => 'P',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryChange {
+    pub kind: ChangeKind,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_pack: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_pack: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_size: Option<u32>,
+}
+
+/// What changed between two snapshots, entry by entry, in name order.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Comparison {
+    pub packs_added: Vec<String>,
+    pub packs_removed: Vec<String>,
+    pub changes: Vec<EntryChange>,
+    pub unchanged: usize,
+}
+
+impl Comparison {
+    pub fn count(&self, kind: ChangeKind) -> usize {
+        self.changes.iter().filter(|c| c.kind == kind).count()
+    }
+
+    pub fn write_json(&self, path: &str) -> Result<(), Error> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Compare the entries the client sees in `old` and `new` (see `Snapshot::effective_entries`).
+pub fn compare(old: &Snapshot, new: &Snapshot) -> Comparison {
+    let mut cmp = Comparison {
+        packs_added: new.packs.keys().filter(|p| !old.packs.contains_key(*p)).cloned().collect(),
+        packs_removed: old.packs.keys().filter(|p| !new.packs.contains_key(*p)).cloned().collect(),
+        ..Comparison::default()
+    };
+    let old_entries = old.effective_entries();
+    let new_entries = new.effective_entries();
+    let mut keys: Vec<&String> = old_entries.keys().chain(new_entries.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let change = |kind, name: &str| EntryChange {
+            kind,
+            name: name.to_string(),
+            old_pack: old_entries.get(key).map(|(p, _, _)| p.to_string()),
+            new_pack: new_entries.get(key).map(|(p, _, _)| p.to_string()),
+            old_size: old_entries.get(key).map(|(_, _, e)| e.size),
+            new_size: new_entries.get(key).map(|(_, _, e)| e.size),
+        };
+        match (old_entries.get(key), new_entries.get(key)) {
+            (None, Some((_, name, _))) => cmp.changes.push(change(ChangeKind::Added, name)),
+            (Some((_, name, _)), None) => cmp.changes.push(change(ChangeKind::Removed, name)),
+            (Some((old_pack, _, a)), Some((new_pack, name, b))) => {
+                // An entry that could not be decoded has no digest to compare.
+                if a.md5 != b.md5 || a.size != b.size || a.md5.is_empty() {
+                    cmp.changes.push(change(ChangeKind::Modified, name));
+                } else if old_pack != new_pack {
+                    cmp.changes.push(change(ChangeKind::Moved, name));
+                } else {
+                    cmp.unchanged += 1;
+                }
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    cmp
+}
+
+/// Digest of what an entry stores: its flags, key and stored bytes. Equal digests mean
+/// equal content; different ones may not (the same file re-encrypted with a new key).
+fn stored_digest(reader: &PackReader, ent: &FileEntry) -> Option<String> {
+    let raw = reader.read_raw(ent).ok()?;
+    let mut ctx = md5::Context::new();
+    ctx.consume(ent.flags.to_le_bytes());
+    ctx.consume(ent.key);
+    ctx.consume(raw);
+    Some(format!("{:x}", ctx.compute()))
+}
+
+/// Entries in both packs whose content differs, decoding both only when nothing cheaper
+/// decides it: sizes first, then the content hashes both packs recorded (`pack
+/// --content-hashes`), then the stored bytes. With `content` differing stored bytes are
+/// decoded and compared byte for byte; without it they count as a change.
+fn entry_changed(old: &PackReader, a: &FileEntry, new: &PackReader, b: &FileEntry, hashes: Option<(&BTreeMap<String, String>, &BTreeMap<String, String>)>, content: bool) -> bool {
+    if a.original_size != b.original_size {
+        return true;
+    }
+    if let Some((old_hashes, new_hashes)) = hashes {
+        if let (Some(x), Some(y)) = (old_hashes.get(&normalize_name(&a.name)), new_hashes.get(&normalize_name(&b.name))) {
+            return x != y;
+        }
+    }
+    match (stored_digest(old, a), stored_digest(new, b)) {
+        (Some(x), Some(y)) if x == y => return false,
+        _ if !content => return true,
+        _ => {}
+    }
+    match (old.read_entry(a), new.read_entry(b)) {
+        (Ok(x), Ok(y)) => x != y,
+        (x, y) => {
+            warn!("[DIFF] {}: cannot decode ({}), counted as changed", b.name, x.err().or(y.err()).map(|e| e.to_string()).unwrap_or_default());
+            true
+        }
+    }
+}
+
+/// Compare two opened packs entry by entry (names compared case-insensitively). Changes
+/// come out in name order; `old_pack`/`new_pack` are left empty. See `entry_changed` for
+/// what counts as a change with and without `content`.
+pub fn diff_packs(old: &PackReader, new: &PackReader, content: bool) -> Comparison {
+    let by_name = |reader: &PackReader| -> BTreeMap<String, FileEntry> {
+        reader.entries().iter()
+            .filter(|e| !e.is_dir_marker())
+            .map(|e| (normalize_name(&e.name).to_lowercase(), e.clone()))
+            .collect()
+    };
+    let (old_entries, new_entries) = (by_name(old), by_name(new));
+    let (old_meta, new_meta) = (old.metadata(), new.metadata());
+    let hashes = old_meta.as_ref().zip(new_meta.as_ref()).map(|(a, b)| (&a.content_hashes, &b.content_hashes));
+
+    let mut keys: Vec<&String> = old_entries.keys().chain(new_entries.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    let results: Vec<Option<EntryChange>> = keys.par_iter().map(|key| {
+        let (a, b) = (old_entries.get(*key), new_entries.get(*key));
+        let kind = match (a, b) {
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(_), None) => ChangeKind::Removed,
+            (Some(a), Some(b)) if entry_changed(old, a, new, b, hashes, content) => ChangeKind::Modified,
+            _ => return None,
+        };
+        Some(EntryChange {
+            kind,
+            name: normalize_name(&b.or(a).unwrap().name),
+            old_pack: None,
+            new_pack: None,
+            old_size: a.map(|e| e.original_size),
+            new_size: b.map(|e| e.original_size),
+        })
+    }).collect();
+    let mut cmp = Comparison { unchanged: results.iter().filter(|r| r.is_none()).count(), ..Comparison::default() };
+    cmp.changes = results.into_iter().flatten().collect();
+    cmp
+}
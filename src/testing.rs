@@ -0,0 +1,239 @@
+// testing.rs - Small packs built in memory, and broken in known ways, for tests of tools built on this crate
+
+//! Enabled with the `testing` feature:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! mabi-pack2-core = { git = "https://github.com/shaggyze/mabi-pack2", features = ["testing"] }
+//! ```
+//!
+//! ```ignore
+//! use mabi_pack2::testing::{Corruption, PackBuilder};
+//! let pack = PackBuilder::new("data_00.it", "my salt").entry("db/a.xml", "<a/>").build()?;
+//! assert_eq!(pack.reader()?.read_entry(&pack.entries[0])?, b"<a/>");
+//! let broken = pack.corrupted(Corruption::EntryChecksum(0))?;
+//! assert!(broken.reader().is_err());
+//! ```
+
+use crate::common::{FileEntry, FLAG_COMPRESSED};
+use crate::encryption::{self, Snow2Mode};
+use crate::pack::{self, PackWriter};
+use crate::reader::PackReader;
+use anyhow::Error;
+use std::io::{Cursor, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Builds a pack from entries held in memory. Entries are stored in the order added,
+/// each with a key derived from its name, so builds are reproducible.
+#[derive(Debug, Clone)]
+pub struct PackBuilder {
+    name: String,
+    header_salt: String,
+    entries_salt: String,
+    iv: u32,
+    mode: Snow2Mode,
+    entries: Vec<(String, Vec<u8>, u32)>,
+}
+
+impl PackBuilder {
+    /// A pack published as `name` (keys and offsets derive from it) with `salt` for both
+    /// the header and the entries table, IV 0 and `Snow2Mode::Sub`, like `pack` writes.
+    pub fn new(name: &str, salt: &str) -> Self {
+        PackBuilder {
+            name: name.to_string(),
+            header_salt: salt.to_string(),
+            entries_salt: salt.to_string(),
+            iv: 0,
+            mode: Snow2Mode::Sub,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Encrypt the entries table with a salt other than the header's.
+    pub fn entries_salt(mut self, salt: &str) -> Self {
+        self.entries_salt = salt.to_string();
+        self
+    }
+
+    pub fn iv(mut self, iv: u32) -> Self {
+        self.iv = iv;
+        self
+    }
+
+    pub fn mode(mut self, mode: Snow2Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Add an entry, compressed when `pack` would compress a file of that name.
+    pub fn entry(self, name: &str, data: impl Into<Vec<u8>>) -> Self {
+        let flags = if pack::need_compress(name, &[]) { FLAG_COMPRESSED } else { 0 };
+        self.entry_with_flags(name, data, flags)
+    }
+
+    /// Add an entry stored with `flags` (`FLAG_COMPRESSED`, `FLAG_HEAD_ENCRYPTED`...).
+    pub fn entry_with_flags(mut self, name: &str, data: impl Into<Vec<u8>>, flags: u32) -> Self {
+        self.entries.push((name.to_string(), data.into(), flags));
+        self
+    }
+
+    pub fn build(&self) -> Result<FixturePack, Error> {
+        let names: Vec<String> = self.entries.iter().map(|(name, _, _)| name.clone()).collect();
+        let mut writer = PackWriter::new(Cursor::new(Vec::new()), &self.name, &self.header_salt, &self.entries_salt, self.iv, self.mode, &names);
+        let content_offset = writer.content_offset();
+        for (name, data, flags) in &self.entries {
+            let key = md5::compute(name.as_bytes()).0;
+            let content = pack::encode_entry_data(name, &key, data, *flags, self.iv, self.mode)?;
+            let ent = FileEntry { name: name.clone(), checksum: 0, flags: *flags, offset: 0, original_size: data.len() as u32, raw_size: 0, key };
+            writer.add_raw(ent, &content)?;
+        }
+        let (entries, sink) = writer.finish_into_inner()?;
+        Ok(FixturePack {
+            name: self.name.clone(),
+            header_salt: self.header_salt.clone(),
+            entries_salt: self.entries_salt.clone(),
+            iv: self.iv,
+            mode: self.mode,
+            content_offset,
+            entries,
+            bytes: sink.into_inner(),
+        })
+    }
+}
+
+/// A controlled defect for `FixturePack::corrupted`. Entry indexes are positions in
+/// `FixturePack::entries`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// Cut the pack to this many bytes.
+    Truncate(u64),
+    /// Invert the byte at this offset.
+    FlipByte(u64),
+    /// The header no longer passes its checksum.
+    HeaderChecksum,
+    /// The header claims this many entries, with a valid checksum; the table is unchanged.
+    FileCount(u32),
+    /// The entry's stored checksum is off by one.
+    EntryChecksum(usize),
+    /// The entry's flags are replaced (checksum updated, payload unchanged), e.g. with
+    /// unknown bits or an encryption state the payload doesn't have.
+    EntryFlags(usize, u32),
+    /// The first stored byte of the entry's payload is inverted.
+    Payload(usize),
+    /// The footer no longer points at the header. Readers fall back to the derived offset.
+    FooterPointer,
+    /// This many zero bytes are inserted at the start, moving every structure off the
+    /// offsets the name gives (see `--scan-offsets`).
+    Shift(u64),
+}
+
+/// A pack made by `PackBuilder`, with everything needed to open and check it.
+#[derive(Debug, Clone)]
+pub struct FixturePack {
+    pub name: String,
+    pub header_salt: String,
+    pub entries_salt: String,
+    pub iv: u32,
+    pub mode: Snow2Mode,
+    /// Offset of the first payload, as written (`Shift` doesn't update it).
+    pub content_offset: u64,
+    /// The entries table as stored, with offsets, sizes and checksums filled in.
+    pub entries: Vec<FileEntry>,
+    pub bytes: Vec<u8>,
+}
+
+impl FixturePack {
+    pub fn header_offset(&self) -> u64 {
+        encryption::gen_header_offset(&self.name) as u64
+    }
+
+    pub fn entries_offset(&self) -> u64 {
+        self.header_offset() + encryption::gen_entries_offset(&self.name) as u64
+    }
+
+    /// Absolute offset of entry `idx`'s stored payload.
+    pub fn payload_offset(&self, idx: usize) -> u64 {
+        self.content_offset + self.entries[idx].offset as u64 * 1024
+    }
+
+    /// Open the pack from memory with its own salts, searching keys as `open_pack` does.
+    pub fn reader(&self) -> Result<PackReader, Error> {
+        let salts = [self.header_salt.clone(), self.entries_salt.clone()];
+        PackReader::from_bytes(&self.name, self.bytes.clone(), Some(self.header_salt.clone()), &salts)
+    }
+
+    /// Write the pack into `dir` under its name (keys derive from it) and return the path.
+    pub fn write_to(&self, dir: &Path) -> Result<PathBuf, Error> {
+        let path = dir.join(&self.name);
+        std::fs::write(&path, &self.bytes)?;
+        Ok(path)
+    }
+
+    /// A copy of this pack with `how` applied. Defects stack when chained.
+    pub fn corrupted(&self, how: Corruption) -> Result<FixturePack, Error> {
+        let mut out = self.clone();
+        let entry = |idx: usize| {
+            if idx < out.entries.len() {
+                Ok(idx)
+            } else {
+                Err(Error::msg(format!("entry {} out of range; the pack has {} entries", idx, out.entries.len())))
+            }
+        };
+        match how {
+            Corruption::Truncate(len) => out.bytes.truncate(len as usize),
+            Corruption::FlipByte(off) => out.flip(off)?,
+            Corruption::HeaderChecksum => out.flip(self.header_offset())?,
+            Corruption::FileCount(count) => {
+                let key = encryption::gen_header_key(&out.name, &out.header_salt);
+                let mut cur = Cursor::new(&mut out.bytes);
+                cur.seek(SeekFrom::Start(self.header_offset()))?;
+                pack::write_header(count, &key, &mut cur, out.iv, out.mode)?;
+            }
+            Corruption::EntryChecksum(idx) => {
+                let idx = entry(idx)?;
+                out.entries[idx].checksum = out.entries[idx].checksum.wrapping_add(1);
+                out.rewrite_entries()?;
+            }
+            Corruption::EntryFlags(idx, flags) => {
+                let idx = entry(idx)?;
+                out.entries[idx].flags = flags;
+                out.entries[idx].checksum = out.entries[idx].calc_checksum();
+                out.rewrite_entries()?;
+            }
+            Corruption::Payload(idx) => {
+                let idx = entry(idx)?;
+                if out.entries[idx].raw_size == 0 {
+                    return Err(Error::msg(format!("entry '{}' has no payload to corrupt", out.entries[idx].name)));
+                }
+                out.flip(self.payload_offset(idx))?;
+            }
+            Corruption::FooterPointer => {
+                let len = out.bytes.len();
+                if len < 4 {
+                    return Err(Error::msg("pack too short for a footer"));
+                }
+                out.bytes[len - 4..].fill(0);
+            }
+            Corruption::Shift(n) => {
+                out.bytes.splice(0..0, vec![0u8; n as usize]);
+            }
+        }
+        Ok(out)
+    }
+
+    fn flip(&mut self, off: u64) -> Result<(), Error> {
+        let len = self.bytes.len();
+        let byte = self.bytes.get_mut(off as usize).ok_or_else(|| Error::msg(format!("offset 0x{:X} is past the end of the pack ({} bytes)", off, len)))?;
+        *byte ^= 0xFF;
+        Ok(())
+    }
+
+    fn rewrite_entries(&mut self) -> Result<(), Error> {
+        let key = encryption::gen_entries_key(&self.name, &self.entries_salt);
+        let start = self.entries_offset();
+        let mut cur = Cursor::new(&mut self.bytes);
+        cur.seek(SeekFrom::Start(start))?;
+        pack::write_entries(&self.entries, &key, &mut cur, self.iv, self.mode)?;
+        Ok(())
+    }
+}
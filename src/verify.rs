@@ -0,0 +1,231 @@
+// verify.rs - Content Digest and Archive Integrity Verification Module
+
+use crate::common::{self, FileEntry, FLAG_SYMLINK};
+use crate::reader::PackReader;
+use anyhow::{Context, Error};
+use log::{info, warn};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Entry name (forward slashes) -> lowercase hex MD5 of the decoded content.
+pub type ContentHashes = BTreeMap<String, String>;
+
+pub fn normalize_name(name: &str) -> String {
+    name.replace('\\', "/")
+}
+
+pub fn md5_hex(data: &[u8]) -> String {
+    format!("{:x}", md5::compute(data))
+}
+
+/// Parse an `md5sum`-style manifest: `<hex>  <name>` or `<hex> *<name>` per line.
+pub fn load_manifest(path: &str) -> Result<ContentHashes, Error> {
+    let text = std::fs::read_to_string(path).context(format!("reading manifest {} failed", path))?;
+    let mut hashes = ContentHashes::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (hash, name) = line.split_once(' ')
+            .ok_or_else(|| Error::msg(format!("{}:{}: expected '<md5>  <name>'", path, idx + 1)))?;
+        let name = name.trim_start_matches(' ').trim_start_matches('*');
+        if hash.len() != 32 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::msg(format!("{}:{}: '{}' is not an MD5 digest", path, idx + 1, hash)));
+        }
+        hashes.insert(normalize_name(name), hash.to_ascii_lowercase());
+    }
+    Ok(hashes)
+}
+
+/// Re-read `root_dir/name` from disk and compare its digest with `expected`.
+/// Returns a description of the mismatch on failure.
+pub fn verify_written_file(root_dir: &str, name: &str, expected: &str) -> Result<(), String> {
+    let path = Path::new(root_dir).join(name.replace(['/', '\\'], std::path::MAIN_SEPARATOR_STR));
+    let data = std::fs::read(&path).map_err(|e| format!("cannot re-read {}: {}", path.display(), e))?;
+    let actual = md5_hex(&data);
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(format!("digest {} != expected {}", actual, expected))
+    }
+}
+
+/// An entry (or whole archive, when `entry` is empty) that failed `run_verify`.
+#[derive(Debug, Clone)]
+pub struct VerifyFailure {
+    pub archive: String,
+    pub entry: String,
+    pub reason: String,
+}
+
+/// Outcome of one archive of `run_verify`.
+#[derive(Debug, Clone)]
+pub struct PackVerify {
+    pub archive: String,
+    /// Entries checked (0 when the archive could not be opened).
+    pub entries: usize,
+    /// Entries that failed, or 1 when the archive could not be opened.
+    pub failed: usize,
+}
+
+impl PackVerify {
+    pub fn passed(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub archives: usize,
+    pub entries: usize,
+    pub failures: Vec<VerifyFailure>,
+    /// One result per archive, in the order given.
+    pub packs: Vec<PackVerify>,
+}
+
+/// Check every entry of `archives`, `pack_parallel` packs at a time, on a shared pool of
+/// `threads` workers (see `common::map_packs`). The default check decrypts the first block
+/// of each entry (see `PackReader::probe_entry`); `deep` decodes every entry completely,
+/// checks its size and, when the pack stores content hashes, its digest. Archives that
+/// can't be opened are reported as failures.
+pub fn run_verify(archives: &[String], cli_key: Option<String>, loaded_salts: &[String], settings: &common::PackSettings, deep: bool, threads: usize, pack_parallel: usize) -> Result<VerifyReport, Error> {
+    let per_pack = common::map_packs(archives, pack_parallel, threads, |archive, pool| {
+        let reader = match common::catch_panic(archive, || PackReader::open_with_settings(archive, cli_key.clone(), loaded_salts, settings)) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("[VERIFY] {}: cannot open: {}", archive, e);
+                return (0, vec![VerifyFailure { archive: archive.clone(), entry: String::new(), reason: format!("cannot open: {}", e) }]);
+            }
+        };
+        let hashes = reader.metadata().map(|m| m.content_hashes).unwrap_or_default();
+        let checked: Vec<&FileEntry> = reader.entries().iter().filter(|e| !e.is_dir_marker() && e.flags & FLAG_SYMLINK == 0).collect();
+        // Bad table records only get this far with --force; list them all before the data checks.
+        let mut failures: Vec<VerifyFailure> = reader.checksum_failures().into_iter()
+            .map(|f| VerifyFailure { archive: archive.clone(), entry: f.name.clone(), reason: f.to_string() })
+            .collect();
+        failures.extend(pool.install(|| {
+            checked.par_iter()
+                .filter_map(|ent| {
+                    let result = common::catch_panic(&ent.name, || Ok(if deep { deep_check(&reader, ent, &hashes) } else { reader.probe_entry(ent) }))
+                        .unwrap_or_else(|e| Err(e.to_string()));
+                    result.err().map(|reason| VerifyFailure { archive: archive.clone(), entry: ent.name.clone(), reason })
+                })
+                .collect::<Vec<_>>()
+        }));
+        info!("[VERIFY] {}: {} entries checked, {} failed", archive, checked.len(), failures.len());
+        (checked.len(), failures)
+    })?;
+    let mut report = VerifyReport { archives: archives.len(), ..VerifyReport::default() };
+    for (archive, (entries, failures)) in archives.iter().zip(per_pack) {
+        let failed_entries: std::collections::HashSet<&str> = failures.iter().map(|f| f.entry.as_str()).collect();
+        report.packs.push(PackVerify { archive: archive.clone(), entries, failed: failed_entries.len() });
+        report.entries += entries;
+        report.failures.extend(failures);
+    }
+    Ok(report)
+}
+
+fn deep_check(reader: &PackReader, ent: &FileEntry, hashes: &ContentHashes) -> Result<(), String> {
+    let data = reader.read_entry(ent).map_err(|e| e.to_string())?;
+    if data.len() != ent.original_size as usize {
+        return Err(format!("decoded {} bytes, expected {}", data.len(), ent.original_size));
+    }
+    match hashes.get(&normalize_name(&ent.name)) {
+        Some(expected) if !md5_hex(&data).eq_ignore_ascii_case(expected) => Err(format!("digest {} != stored {}", md5_hex(&data), expected)),
+        _ => Ok(()),
+    }
+}
+
+/// A difference between a folder and what `run_roundtrip` extracted from its pack.
+/// Names are relative to the folder, with forward slashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoundTripIssue {
+    /// In the folder but not extracted.
+    Missing(String),
+    /// Extracted but not in the folder.
+    Extra(String),
+    /// Extracted with other content; `first_difference` is the first byte offset that differs.
+    Changed { name: String, source_size: u64, extracted_size: u64, first_difference: u64 },
+}
+
+impl std::fmt::Display for RoundTripIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoundTripIssue::Missing(name) => write!(f, "missing  {}", name),
+            RoundTripIssue::Extra(name) => write!(f, "extra    {}", name),
+            RoundTripIssue::Changed { name, source_size, extracted_size, first_difference } => write!(f,
+                "changed  {} ({} -> {} bytes, first difference at byte {})", name, source_size, extracted_size, first_difference),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RoundTripReport {
+    /// Files in the folder.
+    pub files: usize,
+    pub bytes: u64,
+    pub issues: Vec<RoundTripIssue>,
+}
+
+/// Pack `folder` with `skey` and `opts` into a temporary .it file, extract it again and
+/// compare the extracted files with the folder's, by name and content. The temporary
+/// pack and folder are removed afterwards.
+pub fn run_roundtrip(folder: &str, skey: &str, opts: &crate::pack::PackOptions) -> Result<RoundTripReport, Error> {
+    if !Path::new(folder).is_dir() {
+        return Err(Error::msg(format!("'{}' is not a folder", folder)));
+    }
+    let work = std::env::temp_dir().join(format!("mabi_roundtrip_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&work);
+    std::fs::create_dir_all(&work)?;
+    let result = roundtrip_in(folder, skey, opts, &work);
+    let _ = std::fs::remove_dir_all(&work);
+    result
+}
+
+fn roundtrip_in(folder: &str, skey: &str, opts: &crate::pack::PackOptions, work: &Path) -> Result<RoundTripReport, Error> {
+    let stem = Path::new(folder).file_name().map_or("roundtrip".into(), |n| n.to_string_lossy());
+    let pack_path = work.join(format!("{}.it", stem));
+    let pack_path = pack_path.to_str().ok_or_else(|| Error::msg("temporary path is not valid UTF-8"))?;
+    let out_dir = work.join("extracted");
+    crate::pack::run_pack_with_options(folder, pack_path, skey, opts, None).context("packing failed")?;
+    crate::extract::run_extract_with_options(pack_path, out_dir.to_str().unwrap(), Some(skey.to_string()), &[], &crate::extract::ExtractOptions::default())
+        .context("extracting failed")?;
+
+    let source = tree_files(Path::new(folder))?;
+    let extracted = tree_files(&out_dir)?;
+    let mut report = RoundTripReport { files: source.len(), ..RoundTripReport::default() };
+    for (name, path) in &source {
+        let data = std::fs::read(path)?;
+        report.bytes += data.len() as u64;
+        let Some(out_path) = extracted.get(name) else {
+            report.issues.push(RoundTripIssue::Missing(name.clone()));
+            continue;
+        };
+        let back = std::fs::read(out_path)?;
+        if back != data {
+            let first_difference = data.iter().zip(&back).position(|(a, b)| a != b).unwrap_or(data.len().min(back.len())) as u64;
+            report.issues.push(RoundTripIssue::Changed { name: name.clone(), source_size: data.len() as u64, extracted_size: back.len() as u64, first_difference });
+        }
+    }
+    report.issues.extend(extracted.keys().filter(|n| !source.contains_key(*n)).map(|n| RoundTripIssue::Extra(n.clone())));
+    Ok(report)
+}
+
+/// Relative name (forward slashes) -> path of every file under `root`. A name map left by
+/// `extract --sanitize-names` is not content and is skipped.
+fn tree_files(root: &Path) -> Result<BTreeMap<String, std::path::PathBuf>, Error> {
+    let mut files = BTreeMap::new();
+    for e in walkdir::WalkDir::new(root).min_depth(1) {
+        let e = e?;
+        if e.file_type().is_dir() {
+            continue;
+        }
+        let rel = e.path().strip_prefix(root)?.to_string_lossy().replace('\\', "/");
+        if rel != crate::names::NAME_MAP_FILE {
+            files.insert(rel, e.into_path());
+        }
+    }
+    Ok(files)
+}
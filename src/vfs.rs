@@ -0,0 +1,372 @@
+// vfs.rs - File trees that pack reads from and extract writes to: folders, memory, zip files
+
+use crate::common;
+use anyhow::{Context, Error};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use log::debug;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+/// A tree of files addressed by relative names with `/` separators.
+pub trait Vfs: Send + Sync {
+    /// Every file in the tree (no folders), sorted.
+    fn list(&self) -> Result<Vec<String>, Error>;
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error>;
+
+    /// Create or replace `name`, along with any folders above it.
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Where `name` points if it is a symbolic link. Only real folders have links.
+    fn link_target(&self, _name: &str) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    /// Complete the tree after the last `write` (a zip file gets its central directory).
+    fn finish(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.replace('\\', "/")
+}
+
+/// A folder on disk.
+pub struct DiskFs {
+    root: PathBuf,
+    settings: common::PackSettings,
+}
+
+impl DiskFs {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        DiskFs { root: root.as_ref().to_path_buf(), settings: common::PackSettings::default() }
+    }
+
+    /// Write with `settings` (`--allow-unsafe-paths` lets names leave the folder).
+    pub fn with_settings(mut self, settings: &common::PackSettings) -> Self {
+        self.settings = settings.clone();
+        self
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.root.join(name.replace(['/', '\\'], std::path::MAIN_SEPARATOR_STR))
+    }
+}
+
+impl Vfs for DiskFs {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        for entry in WalkDir::new(&self.root).min_depth(1) {
+            let entry = entry.context(format!("traversing {} failed", self.root.display()))?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let rel = entry.path().strip_prefix(&self.root)?;
+            names.push(normalize(&rel.to_string_lossy()));
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let path = self.path(name);
+        std::fs::read(&path).context(format!("reading {} failed", path.display()))
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        common::write_file_to_disk(&self.root.to_string_lossy(), name, data, &self.settings)
+    }
+
+    fn link_target(&self, name: &str) -> Result<Option<String>, Error> {
+        let path = self.path(name);
+        if !path.is_symlink() {
+            return Ok(None);
+        }
+        Ok(Some(std::fs::read_link(&path)?.to_string_lossy().into_owned()))
+    }
+}
+
+/// Files held in memory, for tests and for callers that build or inspect packs without disk I/O.
+#[derive(Default)]
+pub struct MemoryFs {
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        MemoryFs::default()
+    }
+
+    pub fn from_files<N: AsRef<str>, D: Into<Vec<u8>>>(files: impl IntoIterator<Item = (N, D)>) -> Self {
+        let files = files.into_iter().map(|(n, d)| (normalize(n.as_ref()), d.into())).collect();
+        MemoryFs { files: Mutex::new(files) }
+    }
+
+    pub fn into_files(self) -> BTreeMap<String, Vec<u8>> {
+        self.files.into_inner().unwrap()
+    }
+}
+
+impl Vfs for MemoryFs {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error> {
+        self.files.lock().unwrap().get(&normalize(name)).cloned()
+            .ok_or_else(|| Error::msg(format!("{} is not in the tree", name)))
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        self.files.lock().unwrap().insert(normalize(name), data.to_vec());
+        Ok(())
+    }
+}
+
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP_END_OF_DIRECTORY: u32 = 0x0605_4b50;
+/// General purpose flag: names are UTF-8.
+const ZIP_UTF8_NAMES: u16 = 0x0800;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+
+/// One member of a zip file, as recorded in its central directory.
+#[derive(Debug, Clone)]
+struct ZipMember {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    local_offset: u32,
+}
+
+enum ZipState {
+    Reading { data: Vec<u8>, members: BTreeMap<String, ZipMember> },
+    Writing { out: BufWriter<File>, offset: u64, members: Vec<ZipMember>, names: HashSet<String>, timestamp: (u16, u16) },
+    Finished,
+}
+
+/// A zip file, either opened for reading (`open`) or being written (`create`). Members are
+/// stored or deflated; zip64 archives (over 4 GB or 65535 members) are not supported.
+pub struct ZipFs {
+    path: PathBuf,
+    state: Mutex<ZipState>,
+}
+
+impl ZipFs {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let data = std::fs::read(&path).context(format!("reading {} failed", path.display()))?;
+        let members = read_central_directory(&data).context(format!("{} is not a usable zip file", path.display()))?;
+        debug!("[VFS] {}: {} zip member(s)", path.display(), members.len());
+        Ok(ZipFs { path, state: Mutex::new(ZipState::Reading { data, members }) })
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let out = BufWriter::new(File::create(&path).context(format!("creating {} failed", path.display()))?);
+        Ok(ZipFs { path, state: Mutex::new(ZipState::Writing { out, offset: 0, members: Vec::new(), names: HashSet::new(), timestamp: dos_timestamp() }) })
+    }
+}
+
+fn read_central_directory(data: &[u8]) -> Result<BTreeMap<String, ZipMember>, Error> {
+    // The end record is 22 bytes plus a comment of up to 65535 bytes.
+    let earliest = data.len().saturating_sub(22 + 0xFFFF);
+    let end = (earliest..data.len().saturating_sub(21)).rev()
+        .find(|&i| data[i..i + 4] == ZIP_END_OF_DIRECTORY.to_le_bytes())
+        .ok_or_else(|| Error::msg("no end of central directory record"))?;
+    let mut rd = Cursor::new(&data[end + 10..end + 20]);
+    let count = rd.read_u16::<LittleEndian>()?;
+    let _size = rd.read_u32::<LittleEndian>()?;
+    let offset = rd.read_u32::<LittleEndian>()?;
+    if count == 0xFFFF || offset == 0xFFFF_FFFF {
+        return Err(Error::msg("zip64 archives are not supported"));
+    }
+
+    let mut members = BTreeMap::new();
+    let mut rd = Cursor::new(data);
+    rd.set_position(offset as u64);
+    for _ in 0..count {
+        if rd.read_u32::<LittleEndian>()? != ZIP_CENTRAL_HEADER {
+            return Err(Error::msg("damaged central directory"));
+        }
+        let mut fixed = [0u8; 42];
+        rd.read_exact(&mut fixed)?;
+        let mut f = Cursor::new(&fixed[..]);
+        f.set_position(6);
+        let method = f.read_u16::<LittleEndian>()?;
+        f.set_position(12);
+        let crc = f.read_u32::<LittleEndian>()?;
+        let compressed_size = f.read_u32::<LittleEndian>()?;
+        let size = f.read_u32::<LittleEndian>()?;
+        let name_len = f.read_u16::<LittleEndian>()? as usize;
+        let extra_len = f.read_u16::<LittleEndian>()? as u64;
+        let comment_len = f.read_u16::<LittleEndian>()? as u64;
+        f.set_position(38);
+        let local_offset = f.read_u32::<LittleEndian>()?;
+        let mut name = vec![0u8; name_len];
+        rd.read_exact(&mut name)?;
+        rd.set_position(rd.position() + extra_len + comment_len);
+        let name = normalize(&String::from_utf8_lossy(&name));
+        if name.ends_with('/') {
+            continue;
+        }
+        members.insert(name.clone(), ZipMember { name, method, crc, compressed_size, size, local_offset });
+    }
+    Ok(members)
+}
+
+fn read_member(data: &[u8], member: &ZipMember) -> Result<Vec<u8>, Error> {
+    let header = member.local_offset as usize;
+    let fixed = data.get(header..header + 30).ok_or_else(|| Error::msg("member header out of range"))?;
+    if fixed[..4] != ZIP_LOCAL_HEADER.to_le_bytes() {
+        return Err(Error::msg("damaged member header"));
+    }
+    let name_len = u16::from_le_bytes([fixed[26], fixed[27]]) as usize;
+    let extra_len = u16::from_le_bytes([fixed[28], fixed[29]]) as usize;
+    let start = header + 30 + name_len + extra_len;
+    let stored = data.get(start..start + member.compressed_size as usize).ok_or_else(|| Error::msg("member data out of range"))?;
+    let content = match member.method {
+        ZIP_STORED => stored.to_vec(),
+        ZIP_DEFLATED => {
+            // The sizes come from the archive: grow the buffer as data arrives and stop one
+            // byte past the recorded size, so a forged header can't make us allocate or
+            // inflate without bound.
+            let mut out = Vec::new();
+            DeflateDecoder::new(stored).take(member.size as u64 + 1).read_to_end(&mut out)?;
+            out
+        }
+        other => return Err(Error::msg(format!("compression method {} is not supported", other))),
+    };
+    let mut crc = Crc::new();
+    crc.update(&content);
+    if crc.sum() != member.crc || content.len() != member.size as usize {
+        return Err(Error::msg("CRC or size mismatch"));
+    }
+    Ok(content)
+}
+
+/// MS-DOS time and date of the local clock, as zip headers store them. Every member of
+/// a zip written here gets the time it was created.
+fn dos_timestamp() -> (u16, u16) {
+    use chrono::{Datelike, Timelike};
+    let now = chrono::Local::now();
+    let time = ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16;
+    let date = (((now.year().clamp(1980, 2107) - 1980) as u32) << 9) | (now.month() << 5) | now.day();
+    (time, date as u16)
+}
+
+fn write_member_header(out: &mut dyn Write, central: bool, member: &ZipMember, (time, date): (u16, u16)) -> Result<(), Error> {
+    out.write_u32::<LittleEndian>(if central { ZIP_CENTRAL_HEADER } else { ZIP_LOCAL_HEADER })?;
+    if central {
+        out.write_u16::<LittleEndian>(20)?; // made by
+    }
+    out.write_u16::<LittleEndian>(20)?; // needed to extract
+    out.write_u16::<LittleEndian>(ZIP_UTF8_NAMES)?;
+    out.write_u16::<LittleEndian>(member.method)?;
+    out.write_u16::<LittleEndian>(time)?;
+    out.write_u16::<LittleEndian>(date)?;
+    out.write_u32::<LittleEndian>(member.crc)?;
+    out.write_u32::<LittleEndian>(member.compressed_size)?;
+    out.write_u32::<LittleEndian>(member.size)?;
+    out.write_u16::<LittleEndian>(member.name.len() as u16)?;
+    out.write_u16::<LittleEndian>(0)?; // extra field
+    if central {
+        out.write_u16::<LittleEndian>(0)?; // comment
+        out.write_u16::<LittleEndian>(0)?; // disk
+        out.write_u16::<LittleEndian>(0)?; // internal attributes
+        out.write_u32::<LittleEndian>(0)?; // external attributes
+        out.write_u32::<LittleEndian>(member.local_offset)?;
+    }
+    out.write_all(member.name.as_bytes())?;
+    Ok(())
+}
+
+impl Vfs for ZipFs {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        match &*self.state.lock().unwrap() {
+            ZipState::Reading { members, .. } => Ok(members.keys().cloned().collect()),
+            _ => Err(Error::msg(format!("{} was opened for writing", self.path.display()))),
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error> {
+        match &*self.state.lock().unwrap() {
+            ZipState::Reading { data, members } => {
+                let member = members.get(&normalize(name)).ok_or_else(|| Error::msg(format!("{} is not in {}", name, self.path.display())))?;
+                read_member(data, member).context(format!("reading {} from {} failed", name, self.path.display()))
+            }
+            _ => Err(Error::msg(format!("{} was opened for writing", self.path.display()))),
+        }
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let ZipState::Writing { out, offset, members, names, timestamp } = &mut *state else {
+            return Err(Error::msg(format!("{} is not open for writing", self.path.display())));
+        };
+        let name = normalize(name);
+        if !names.insert(name.to_lowercase()) {
+            return Err(Error::msg(format!("{} is already in {}", name, self.path.display())));
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let deflated = encoder.finish()?;
+        let (method, stored) = if deflated.len() < data.len() { (ZIP_DEFLATED, &deflated[..]) } else { (ZIP_STORED, data) };
+        let mut crc = Crc::new();
+        crc.update(data);
+        if *offset + 30 + name.len() as u64 + stored.len() as u64 > u32::MAX as u64 {
+            return Err(Error::msg(format!("{} would exceed 4 GB, which needs zip64", self.path.display())));
+        }
+        let member = ZipMember { name, method, crc: crc.sum(), compressed_size: stored.len() as u32, size: data.len() as u32, local_offset: *offset as u32 };
+        write_member_header(out, false, &member, *timestamp)?;
+        out.write_all(stored)?;
+        *offset += 30 + member.name.len() as u64 + stored.len() as u64;
+        members.push(member);
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let ZipState::Writing { mut out, offset, members, timestamp, .. } = std::mem::replace(&mut *state, ZipState::Finished) else {
+            return Ok(());
+        };
+        if members.len() >= 0xFFFF {
+            return Err(Error::msg(format!("{} has {} members, which needs zip64", self.path.display(), members.len())));
+        }
+        let mut directory = Vec::new();
+        for member in &members {
+            write_member_header(&mut directory, true, member, timestamp)?;
+        }
+        out.write_all(&directory)?;
+        out.write_u32::<LittleEndian>(ZIP_END_OF_DIRECTORY)?;
+        out.write_u16::<LittleEndian>(0)?; // this disk
+        out.write_u16::<LittleEndian>(0)?; // disk with the directory
+        out.write_u16::<LittleEndian>(members.len() as u16)?;
+        out.write_u16::<LittleEndian>(members.len() as u16)?;
+        out.write_u32::<LittleEndian>(directory.len() as u32)?;
+        out.write_u32::<LittleEndian>(offset as u32)?;
+        out.write_u16::<LittleEndian>(0)?; // comment
+        out.flush()?;
+        debug!("[VFS] {}: wrote {} zip member(s)", self.path.display(), members.len());
+        Ok(())
+    }
+}
+
+/// Whether `path` names a zip file rather than a folder.
+pub fn is_zip_path(path: &str) -> bool {
+    path.to_lowercase().ends_with(".zip") && !Path::new(path).is_dir()
+}
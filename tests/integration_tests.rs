@@ -39,7 +39,7 @@ const KNOWN_SALT: &str = "})wWb4?-sVGHNoPKpc";
 #[test]
 fn test_load_salts_no_duplicate_threads() {
     let handles: Vec<_> = (0..5)
-        .map(|_| std::thread::spawn(|| mabi_pack2::load_salts()))
+        .map(|_| std::thread::spawn(mabi_pack2::load_salts))
         .collect();
 
     for h in handles {
@@ -111,6 +111,177 @@ fn test_snow2_roundtrip_xor() {
     assert_eq!(data, original, "Xor/iv0 roundtrip failed: data not restored");
 }
 
+/// Streaming decode in odd-sized reads (tails of 1-3 bytes) matches the bulk decrypt.
+#[test]
+fn test_snow2_decoder_odd_reads() {
+    use mabi_pack2::encryption::{gen_header_key, snow2_decrypt_mode, Snow2Decoder, Snow2Mode};
+    use std::io::Read;
+
+    let key = gen_header_key("data.it", KNOWN_SALT);
+    let stored: Vec<u8> = (0..200u32).map(|i| (i * 7 + 3) as u8).collect();
+    for mode in [Snow2Mode::Sub, Snow2Mode::Xor] {
+        let mut expected = stored.clone();
+        snow2_decrypt_mode(&key, 0, mode, &mut expected);
+
+        let mut src = std::io::Cursor::new(stored.clone());
+        let mut dec = Snow2Decoder::new_iv_mode(&key, 0, mode, &mut src);
+        let mut got = Vec::new();
+        for size in [1usize, 3, 2, 5, 7, 4, 9, 64, 1].iter().cycle() {
+            if got.len() == stored.len() {
+                break;
+            }
+            let mut chunk = vec![0u8; (*size).min(stored.len() - got.len())];
+            let n = dec.read(&mut chunk).unwrap();
+            got.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!(got, expected, "{:?}: chunked decoder output differs", mode);
+    }
+}
+
+/// `finish` pads the last partial word, reports the bytes written and refuses later writes.
+#[test]
+fn test_snow2_encoder_finish() {
+    use mabi_pack2::encryption::{gen_header_key, snow2_decrypt_mode, Snow2Encoder, Snow2Mode};
+    use std::io::Write;
+
+    let key = gen_header_key("data.it", KNOWN_SALT);
+    let mut out = Vec::new();
+    let mut enc = Snow2Encoder::new_iv_mode(&key, 0, Snow2Mode::Sub, &mut out);
+    enc.write_all(b"nine byte").unwrap();
+    assert_eq!(enc.finish().unwrap(), 12, "9 bytes pad to 3 words");
+    assert_eq!(enc.finish().unwrap(), 12, "finish is idempotent");
+    assert!(enc.write_all(b"x").is_err(), "writing after finish must fail");
+    drop(enc);
+
+    assert_eq!(out.len(), 12);
+    snow2_decrypt_mode(&key, 0, Snow2Mode::Sub, &mut out);
+    assert_eq!(&out[..9], b"nine byte");
+    assert_eq!(&out[9..], &[0, 0, 0]);
+}
+
+/// A failing writer surfaces through `write_all` and `finish` instead of being lost.
+#[test]
+fn test_snow2_encoder_propagates_write_errors() {
+    use mabi_pack2::encryption::{gen_header_key, Snow2Encoder, Snow2Mode};
+    use std::io::Write;
+
+    /// Accepts `room` bytes, then fails.
+    struct Full { room: usize }
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.room == 0 {
+                return Err(std::io::Error::other("disk full"));
+            }
+            let n = buf.len().min(self.room);
+            self.room -= n;
+            Ok(n)
+        }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    let key = gen_header_key("data.it", KNOWN_SALT);
+    let mut sink = Full { room: 8 };
+    let mut enc = Snow2Encoder::new_iv_mode(&key, 0, Snow2Mode::Sub, &mut sink);
+    let err = enc.write_all(&[7u8; 64]).unwrap_err();
+    assert!(err.to_string().contains("failed write") || err.to_string().contains("disk full"), "{}", err);
+    assert!(enc.write_all(b"x").is_err());
+    assert!(enc.finish().is_err(), "finish after a failed write must fail");
+
+    // Finishing is what writes the final partial word.
+    let mut sink = Full { room: 4 };
+    let mut enc = Snow2Encoder::new_iv_mode(&key, 0, Snow2Mode::Sub, &mut sink);
+    enc.write_all(b"four").unwrap();
+    enc.write_all(b"x").unwrap();
+    assert!(enc.finish().is_err());
+}
+
+/// BufRead on the decoder: fill_buf/consume, read and read_until interleave and the
+/// final partial word of a stream that is not word-aligned is still decoded.
+#[test]
+fn test_snow2_decoder_bufread() {
+    use mabi_pack2::encryption::{gen_header_key, snow2_decrypt_mode, snow2_encrypt_mode, Snow2Decoder, Snow2Mode};
+    use std::io::{BufRead, Read};
+
+    let key = gen_header_key("data.it", KNOWN_SALT);
+    let plain: Vec<u8> = b"first line\nsecond\n".iter().cycle().take(20_003).copied().collect();
+    let mut stored = plain.clone();
+    snow2_encrypt_mode(&key, 0, Snow2Mode::Sub, &mut stored);
+    let mut check = stored.clone();
+    snow2_decrypt_mode(&key, 0, Snow2Mode::Sub, &mut check);
+    assert_eq!(check, plain);
+
+    let mut src = std::io::Cursor::new(stored);
+    let mut dec = Snow2Decoder::new_iv_mode(&key, 0, Snow2Mode::Sub, &mut src);
+    let mut got = Vec::new();
+
+    // A plain read first leaves a split word behind for fill_buf to pick up.
+    let mut head = [0u8; 5];
+    dec.read_exact(&mut head).unwrap();
+    got.extend_from_slice(&head);
+
+    let avail = dec.fill_buf().unwrap();
+    assert!(!avail.is_empty());
+    let take = avail.len().min(7);
+    got.extend_from_slice(&avail[..take]);
+    dec.consume(take);
+
+    let mut line = Vec::new();
+    dec.read_until(b'\n', &mut line).unwrap();
+    got.extend_from_slice(&line);
+
+    loop {
+        let avail = dec.fill_buf().unwrap();
+        if avail.is_empty() {
+            break;
+        }
+        let n = avail.len();
+        got.extend_from_slice(avail);
+        dec.consume(n);
+    }
+    assert_eq!(got, plain, "BufRead decode differs from plaintext");
+    assert_eq!(dec.current_stream_position(), plain.len() as u64);
+}
+
+/// A source error after part of a window was read is kept for the next call instead
+/// of passing for end of stream; `Interrupted` is retried.
+#[test]
+fn test_snow2_decoder_partial_read_error() {
+    use mabi_pack2::encryption::{gen_header_key, snow2_encrypt_mode, Snow2Decoder, Snow2Mode};
+    use std::io::{self, BufRead, Read};
+
+    struct Flaky {
+        data: Vec<u8>,
+        calls: usize,
+    }
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.calls += 1;
+            match self.calls {
+                1 => Err(io::Error::from(io::ErrorKind::Interrupted)),
+                2 => {
+                    buf[..6].copy_from_slice(&self.data[..6]);
+                    Ok(6)
+                }
+                3 => Err(io::Error::new(io::ErrorKind::ConnectionReset, "source went away")),
+                _ => Ok(0),
+            }
+        }
+    }
+
+    let key = gen_header_key("data.it", KNOWN_SALT);
+    let plain = b"0123456789abcdef".to_vec();
+    let mut stored = plain.clone();
+    snow2_encrypt_mode(&key, 0, Snow2Mode::Sub, &mut stored);
+
+    let mut src = Flaky { data: stored, calls: 0 };
+    let mut dec = Snow2Decoder::new_iv_mode(&key, 0, Snow2Mode::Sub, &mut src);
+    let avail = dec.fill_buf().unwrap();
+    assert_eq!(avail, &plain[..6]);
+    dec.consume(6);
+    let err = dec.fill_buf().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+}
+
 // --------------------------------------------------------------------------
 // 5. Key derivation is deterministic
 // --------------------------------------------------------------------------
@@ -327,7 +498,7 @@ fn test_pack_roundtrip_pack_v1() {
     assert!(pack_result.is_ok(), "run_pack_v1 failed: {:?}", pack_result.err());
     assert!(output.exists(), "Output .pack file was not created");
 
-    let list_result = mabi_pack2::pack_v1::run_list_v1_data(output.to_str().unwrap());
+    let list_result = mabi_pack2::pack_v1::run_list_v1_data(output.to_str().unwrap(), &mabi_pack2::common::PackSettings::default());
     assert!(list_result.is_ok(), "run_list_v1_data failed: {:?}", list_result.err());
     let entries = list_result.unwrap();
     assert_eq!(
@@ -529,3 +700,4283 @@ fn test_concurrent_convert_no_stomp() {
     let _ = std::fs::remove_file(&out1);
     let _ = std::fs::remove_file(&out2);
 }
+
+// --------------------------------------------------------------------------
+// 15. set-flags re-encodes payloads  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Pack 2 files, mark one all-encrypted + compressed and clear compression on
+/// the other (written to a second archive), then read both back unchanged.
+#[test]
+fn test_set_flags_roundtrip() {
+    use mabi_pack2::common::{FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED};
+
+    let dir = common::temp_dir_for_test("set_flags");
+    let packed = std::env::temp_dir().join("mabi_test_set_flags.it");
+    let edited = std::env::temp_dir().join("mabi_test_set_flags_2.it");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let bin: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+    std::fs::write(dir.join("data.bin"), &bin).unwrap();
+    let xml = "<root><item>flags</item></root>".repeat(40);
+    std::fs::write(dir.join("text.xml"), &xml).unwrap();
+
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None)
+        .expect("run_pack failed");
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let changed = mabi_pack2::edit::run_set_flags(
+        packed.to_str().unwrap(), None, None, &salts, &mabi_pack2::common::PackSettings::default(),
+        vec![r"data\.bin".to_string()], FLAG_ALL_ENCRYPTED | FLAG_COMPRESSED, 0,
+    ).expect("in-place set-flags failed");
+    assert_eq!(changed, 1);
+
+    let changed = mabi_pack2::edit::run_set_flags(
+        packed.to_str().unwrap(), Some(edited.to_str().unwrap()), None, &salts, &mabi_pack2::common::PackSettings::default(),
+        vec![r"text\.xml".to_string()], 0, FLAG_COMPRESSED,
+    ).expect("set-flags to new file failed");
+    assert_eq!(changed, 1);
+
+    let reader = mabi_pack2::reader::PackReader::open(edited.to_str().unwrap(), None, &salts).expect("open failed");
+    let bin_ent = reader.find("data.bin").expect("data.bin missing");
+    assert_eq!(bin_ent.flags, FLAG_ALL_ENCRYPTED | FLAG_COMPRESSED);
+    assert_eq!(reader.read_entry(bin_ent).unwrap(), bin);
+    let xml_ent = reader.find("text.xml").expect("text.xml missing");
+    assert_eq!(xml_ent.flags & FLAG_COMPRESSED, 0);
+    assert_eq!(reader.read_entry(xml_ent).unwrap(), xml.as_bytes());
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+    let _ = std::fs::remove_file(&edited);
+}
+
+// --------------------------------------------------------------------------
+// 16. --explain-search report  (in-memory, fast)
+// --------------------------------------------------------------------------
+
+/// A junk buffer never gets past the header checksum; an entries-stage
+/// failure is reported as the furthest stage reached.
+#[test]
+fn test_search_report_stages() {
+    use mabi_pack2::common::{self as core, ProbeAttempt, ProbeStage, SearchTrace};
+
+    let trace = SearchTrace::default();
+    let junk = vec![0x5Au8; 4096];
+    let mut rd = std::io::Cursor::new(&junk[..]);
+    let found = core::find_header_only_traced(&mut rd, "junk.it", KNOWN_SALT, Some(&trace)).unwrap();
+    assert!(found.is_none());
+    let attempts = trace.into_inner().unwrap();
+    assert!(!attempts.is_empty());
+    assert!(attempts.iter().all(|a| a.stage < ProbeStage::EntriesRead));
+    let report = core::format_search_report(&attempts);
+    assert!(report.contains(KNOWN_SALT));
+    assert!(report.contains("Diagnosis: Headers decrypted but never validated"), "{}", report);
+
+    let mut attempts = attempts;
+    attempts.push(ProbeAttempt {
+        name_variant: "junk.it".to_string(),
+        header_salt: KNOWN_SALT.to_string(),
+        entries_salt: Some("other".to_string()),
+        offset: 0x65,
+        iv0: 0,
+        mode: mabi_pack2::encryption::Snow2Mode::Sub,
+        stage: ProbeStage::EntriesChecksum,
+        times: Default::default(),
+    });
+    let report = core::format_search_report(&attempts);
+    assert!(report.contains("entries checksum"));
+    assert!(report.contains("Diagnosis: Entries decoded but failed their checksums"), "{}", report);
+}
+
+// --------------------------------------------------------------------------
+// 17. Key cache salt ordering  (in-memory, fast)
+// --------------------------------------------------------------------------
+
+/// Salts that unlocked the same archive name go first, then by total hits;
+/// salts with no history keep their original order.
+#[test]
+fn test_key_cache_prioritize() {
+    use mabi_pack2::key_cache::KeyCache;
+
+    let mut cache = KeyCache::default();
+    cache.record_success("data_00001.it", "often", "often");
+    cache.record_success("data_00002.it", "often", "often");
+    cache.record_success("Uotiara_00001.it", "header", "entries");
+
+    let mut keys: Vec<String> = ["a", "often", "b", "entries", "header"].iter().map(|s| s.to_string()).collect();
+    cache.prioritize("uotiara_00001.it", &mut keys);
+    assert_eq!(keys, ["entries", "header", "often", "a", "b"]);
+
+    let mut keys: Vec<String> = ["a", "header", "often"].iter().map(|s| s.to_string()).collect();
+    cache.prioritize("data_00003.it", &mut keys);
+    assert_eq!(keys, ["often", "header", "a"]);
+}
+
+/// A recorded combination is only returned while the archive keeps its size and mtime,
+/// and cache files written before per-pack records existed still load.
+#[test]
+fn test_key_cache_pack_hit() {
+    use mabi_pack2::encryption::Snow2Mode;
+    use mabi_pack2::key_cache::{KeyCache, PackHit};
+
+    let mut cache: KeyCache = serde_json::from_str(r#"{"salts":{"a":{"hits":1}}}"#).unwrap();
+    assert!(cache.packs.is_empty());
+    let hit = PackHit {
+        size: 2048, mtime: 1_700_000_000, name_variant: "data_00001.it".to_string(),
+        header_salt: "h".to_string(), entries_salt: "e".to_string(), header_offset: 0x69, iv0: 0, mode: Snow2Mode::Sub,
+    };
+    cache.record_pack("/packs/data_00001.it", hit.clone());
+    assert_eq!(cache.pack_hit("/packs/data_00001.it", 2048, 1_700_000_000), Some(&hit));
+    assert_eq!(cache.pack_hit("/packs/data_00001.it", 2049, 1_700_000_000), None);
+    assert_eq!(cache.pack_hit("/packs/data_00001.it", 2048, 1_700_000_001), None);
+    assert_eq!(cache.pack_hit("/packs/data_00002.it", 2048, 1_700_000_000), None);
+
+    let reloaded: KeyCache = serde_json::from_str(&serde_json::to_string(&cache).unwrap()).unwrap();
+    assert_eq!(reloaded.packs["/packs/data_00001.it"], hit);
+}
+
+/// Two runs saving into one cache file add their counters to what is on disk instead of
+/// overwriting each other, and leave no temporary files behind.
+#[test]
+fn test_key_cache_merge_into_file() {
+    use mabi_pack2::key_cache::KeyCache;
+
+    let dir = common::temp_dir_for_test("key_cache_merge");
+    common::cleanup(&dir);
+    let path = dir.join("key_cache.json");
+    let mut first = KeyCache::default();
+    first.record_success("data_00001.it", "a", "a");
+    let mut second = KeyCache::default();
+    second.record_success("data_00001.it", "a", "a");
+    second.record_success("data_00002.it", "b", "b");
+
+    first.merge_into_file(&path).unwrap();
+    let merged = second.merge_into_file(&path).unwrap();
+    assert_eq!(merged.salts["a"].hits, 2);
+    assert_eq!(merged.salts["a"].names["data_00001.it"], 2);
+    assert_eq!(merged.salts["b"].hits, 1);
+    assert_eq!(KeyCache::load(&path).salts["a"].hits, 2);
+    let leftovers: Vec<_> = std::fs::read_dir(&dir).unwrap().flatten().map(|e| e.file_name()).collect();
+    assert_eq!(leftovers, [std::ffi::OsString::from("key_cache.json")]);
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 18. Exhaustive search with mismatched salts  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Build an archive whose header and entries use different salts and check
+/// that `search_all_keys` reports every pairing that validates and
+/// `select_hit` honours a preferred salt.
+#[test]
+fn test_search_all_keys_mismatched_salts() {
+    use mabi_pack2::common::{FileEntry, FLAG_COMPRESSED};
+    use mabi_pack2::encryption::Snow2Mode;
+
+    const ENTRIES_SALT: &str = "@6QeTuOaDgJlZcBm#9";
+    let packed = std::env::temp_dir().join("mabi_test_mismatch.it");
+    let names = vec!["db\\mismatch.xml".to_string()];
+    let mut writer = mabi_pack2::pack::PackWriter::create(
+        packed.to_str().unwrap(), "mabi_test_mismatch.it", KNOWN_SALT, ENTRIES_SALT, 0, Snow2Mode::Sub, &names,
+    ).expect("create failed");
+    let plain = b"<mismatch/>";
+    let ent = FileEntry { name: names[0].clone(), checksum: 0, flags: FLAG_COMPRESSED, offset: 0, original_size: plain.len() as u32, raw_size: 0, key: [7u8; 16] };
+    let content = mabi_pack2::pack::encode_entry_data(&ent.name, &ent.key, plain, ent.flags, 0, Snow2Mode::Sub).unwrap();
+    writer.add_raw(ent, &content).unwrap();
+    writer.finish().expect("finish failed");
+
+    // The header key only uses the first 16 chars of name + salt, so with a name
+    // this long every salt validates the header; only the entries salt matters.
+    let data = std::fs::read(&packed).unwrap();
+    let salts = vec![ENTRIES_SALT.to_string(), KNOWN_SALT.to_string()];
+    let matches = mabi_pack2::common_ext::search_all_keys(&data, packed.to_str().unwrap(), None, &salts, None, &mabi_pack2::common::PackSettings::default()).unwrap();
+    assert_eq!(matches.hits.len(), 2);
+    assert!(matches.hits.iter().all(|h| h.entries_salt == ENTRIES_SALT));
+
+    let hit = mabi_pack2::common_ext::select_hit(matches.hits, Some(KNOWN_SALT)).unwrap();
+    assert_eq!(hit.header_salt, KNOWN_SALT);
+    assert_eq!(hit.entries[0].name, names[0]);
+
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 19. salts.txt alias column  (fast)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_parse_salt_line_aliases() {
+    use mabi_pack2::parse_salt_line;
+
+    assert_eq!(parse_salt_line(KNOWN_SALT), Some((KNOWN_SALT.to_string(), None)));
+    assert_eq!(
+        parse_salt_line(&format!("{}\tna_main\r", KNOWN_SALT)),
+        Some((KNOWN_SALT.to_string(), Some("na_main".to_string())))
+    );
+    // '=' and spaces are legal inside salts; only a tab starts the alias.
+    assert_eq!(parse_salt_line("smh=Pdw+%?wk?m4&(y "), Some(("smh=Pdw+%?wk?m4&(y".to_string(), None)));
+    assert_eq!(parse_salt_line("# comment\tnot_an_alias"), None);
+    assert_eq!(parse_salt_line("   "), None);
+}
+
+// --------------------------------------------------------------------------
+// 20. Build-info record  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// `pack` stores the tool version before the footer unless disabled, and the
+/// archive still opens and extracts either way.
+#[test]
+fn test_pack_metadata_record() {
+    use mabi_pack2::pack::{run_pack_with_options, PackOptions};
+    use mabi_pack2::reader::PackReader;
+
+    let dir = common::temp_dir_for_test("pack_meta");
+    let with_meta = std::env::temp_dir().join("mabi_test_meta.it");
+    let without_meta = std::env::temp_dir().join("mabi_test_nometa.it");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.xml"), b"<meta/>").unwrap();
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let opts = PackOptions { compress_ext: vec![".bin".to_string()], ..PackOptions::default() };
+    run_pack_with_options(dir.to_str().unwrap(), with_meta.to_str().unwrap(), KNOWN_SALT, &opts, None).unwrap();
+    let reader = PackReader::open(with_meta.to_str().unwrap(), None, &salts).unwrap();
+    let meta = reader.metadata().expect("metadata missing");
+    assert_eq!(meta.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(meta.compress_ext, vec![".bin".to_string()]);
+    assert_eq!(reader.read_entry(reader.find("a.xml").unwrap()).unwrap(), b"<meta/>");
+
+    let opts = PackOptions { embed_metadata: false, ..PackOptions::default() };
+    run_pack_with_options(dir.to_str().unwrap(), without_meta.to_str().unwrap(), KNOWN_SALT, &opts, None).unwrap();
+    let reader = PackReader::open(without_meta.to_str().unwrap(), None, &salts).unwrap();
+    assert!(reader.metadata().is_none());
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&with_meta);
+    let _ = std::fs::remove_file(&without_meta);
+}
+
+// --------------------------------------------------------------------------
+// 21. list --validate-data  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Clobbering the start of a compressed entry's data block is flagged by the
+/// shallow data probe while the other entry still passes.
+#[test]
+fn test_list_validate_data_flags_corruption() {
+    use mabi_pack2::reader::PackReader;
+
+    let dir = common::temp_dir_for_test("validate_data");
+    let packed = std::env::temp_dir().join("mabi_test_validate.it");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("good.bin"), vec![0x42u8; 300]).unwrap();
+    std::fs::write(dir.join("bad.xml"), "<root>soon to be garbage</root>".repeat(40)).unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let (start, name) = {
+        let reader = PackReader::open(packed.to_str().unwrap(), None, &salts).unwrap();
+        assert!(reader.entries().iter().all(|e| reader.probe_entry(e).is_ok()));
+        let ent = reader.find("bad.xml").unwrap();
+        ((reader.content_offset + ent.offset as u64 * 1024) as usize, ent.name.clone())
+    };
+    let mut data = std::fs::read(&packed).unwrap();
+    data[start..start + 4].copy_from_slice(&[0xFF; 4]);
+    std::fs::write(&packed, &data).unwrap();
+
+    let reader = PackReader::open(packed.to_str().unwrap(), None, &salts).unwrap();
+    assert!(reader.probe_entry(reader.find(&name).unwrap()).is_err());
+    assert!(reader.probe_entry(reader.find("good.bin").unwrap()).is_ok());
+    drop(reader);
+    let out = std::env::temp_dir().join("mabi_test_validate.txt");
+    let bad = mabi_pack2::list::run_list_validate_data(packed.to_str().unwrap(), None, &salts, &mabi_pack2::common::PackSettings::default(), out.to_str(), Vec::new()).unwrap();
+    assert_eq!(bad, 1);
+    assert!(std::fs::read_to_string(&out).unwrap().contains("bad.xml\tBAD:"));
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+    let _ = std::fs::remove_file(&out);
+}
+
+// --------------------------------------------------------------------------
+// 22. extract --verify-content  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Digests stored by `pack --content-hashes` verify cleanly, and a manifest
+/// with a wrong digest makes the extraction fail after writing the files.
+#[test]
+fn test_extract_verify_content() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use mabi_pack2::pack::{run_pack_with_options, PackMetadata, PackOptions};
+
+    let dir = common::temp_dir_for_test("verify_content");
+    let out = common::temp_dir_for_test("verify_content_out");
+    let packed = std::env::temp_dir().join("mabi_test_verify.it");
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("a.txt"), b"alpha").unwrap();
+    std::fs::write(dir.join("sub").join("b.bin"), vec![7u8; 2000]).unwrap();
+    let opts = PackOptions { content_hashes: true, ..PackOptions::default() };
+    run_pack_with_options(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, &opts, None).unwrap();
+
+    let meta = PackMetadata::read_from(&std::fs::read(&packed).unwrap()).unwrap();
+    assert_eq!(meta.content_hashes.len(), 2);
+    assert_eq!(meta.content_hashes["a.txt"], mabi_pack2::verify::md5_hex(b"alpha"));
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let ok = ExtractOptions { verify_hashes: Some(&meta.content_hashes), ..ExtractOptions::default() };
+    run_extract_with_options(packed.to_str().unwrap(), out.to_str().unwrap(), None, &salts, &ok).unwrap();
+
+    let mut wrong = meta.content_hashes.clone();
+    wrong.insert("a.txt".to_string(), "0".repeat(32));
+    let bad = ExtractOptions { verify_hashes: Some(&wrong), ..ExtractOptions::default() };
+    let err = run_extract_with_options(packed.to_str().unwrap(), out.to_str().unwrap(), None, &salts, &bad).unwrap_err();
+    assert!(err.to_string().contains("1 extracted file(s) failed"));
+
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 23. batch --dedupe-output  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Identical content is hard-linked (or skipped) once written, and rewriting a
+/// linked path with new content leaves the original copy untouched.
+#[test]
+fn test_output_dedupe_modes() {
+    use mabi_pack2::dedupe::{DedupeMode, OutputDedupe};
+
+    let dir = common::temp_dir_for_test("dedupe_output");
+    common::cleanup(&dir);
+    let root = dir.to_str().unwrap();
+    let settings = mabi_pack2::common::PackSettings::default();
+
+    let dedupe = OutputDedupe::new(DedupeMode::Hardlink);
+    assert!(!dedupe.write(root, "v1/a.txt", b"same", &settings).unwrap());
+    assert!(dedupe.write(root, "v2/a.txt", b"same", &settings).unwrap());
+    assert!(!dedupe.write(root, "v2/a.txt", b"changed", &settings).unwrap());
+    assert_eq!(std::fs::read(dir.join("v1").join("a.txt")).unwrap(), b"same");
+    assert_eq!(std::fs::read(dir.join("v2").join("a.txt")).unwrap(), b"changed");
+    assert_eq!(dedupe.saved(), (1, 4));
+
+    let skip = OutputDedupe::new("skip".parse().unwrap());
+    assert!(!skip.write(root, "s1/b.bin", &[1, 2, 3], &settings).unwrap());
+    assert!(skip.write(root, "s2/b.bin", &[1, 2, 3], &settings).unwrap());
+    assert!(!dir.join("s2").join("b.bin").exists());
+    assert!("bogus".parse::<DedupeMode>().is_err());
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 24. tree-size aggregation  (in-memory, fast)
+// --------------------------------------------------------------------------
+
+/// Directory totals include everything below them, loose files next to
+/// subdirectories get a "(files)" node, and extensions sort largest first.
+#[test]
+fn test_tree_size_breakdown() {
+    use mabi_pack2::common::FileEntry;
+    use mabi_pack2::info::{extension_sizes, human_size, size_tree, FILES_NODE};
+
+    let ent = |name: &str, size: u32| FileEntry { name: name.to_string(), checksum: 0, flags: 0, offset: 0, original_size: size, raw_size: size / 2, key: [0u8; 16] };
+    let entries = vec![
+        ent("data\\gfx\\char\\a.dds", 600),
+        ent("data\\gfx\\b.dds", 200),
+        ent("data\\db\\items.xml", 150),
+        ent("data\\readme.txt", 50),
+    ];
+
+    let tree = size_tree(&entries, false, 2);
+    assert_eq!((tree.size, tree.files), (1000, 4));
+    let data = &tree.children["data"];
+    assert_eq!(data.children["gfx"].size, 800);
+    assert_eq!(data.children["gfx"].files, 2);
+    assert!(data.children["gfx"].children.is_empty());
+    assert_eq!(data.children[FILES_NODE].size, 50);
+    assert_eq!(size_tree(&entries, true, 1).children["data"].size, 500);
+
+    let exts = extension_sizes(&entries, false);
+    assert_eq!(exts[0], (".dds".to_string(), 800, 2));
+    assert_eq!(exts.last().unwrap().0, ".txt");
+    assert_eq!(human_size(512), "512 B");
+    assert_eq!(human_size(1536), "1.5 KiB");
+}
+
+// --------------------------------------------------------------------------
+// 25. Case-collision planning  (in-memory, fast; the folder probe needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Names that differ only in case (or slash direction) are renamed, skipped or
+/// rejected per policy; exact duplicates and distinct names pass through.
+#[test]
+fn test_plan_output_names_case_collisions() {
+    use mabi_pack2::extract::{plan_output_names, CaseCollisionPolicy};
+
+    let names = ["db\\Item.xml", "db/item.xml", "db\\item~2.xml", "DB\\ITEM.XML", "gfx\\a.dds", "gfx\\a.dds"];
+    let planned = plan_output_names(&names, CaseCollisionPolicy::Suffix).unwrap();
+    assert_eq!(planned, vec![
+        Some("db\\Item.xml".to_string()),
+        Some("db/item~3.xml".to_string()),
+        Some("db\\item~2.xml".to_string()),
+        Some("DB\\ITEM~4.XML".to_string()),
+        Some("gfx\\a.dds".to_string()),
+        Some("gfx\\a.dds".to_string()),
+    ]);
+
+    let skipped = plan_output_names(&names, CaseCollisionPolicy::Skip).unwrap();
+    assert_eq!(skipped.iter().filter(|n| n.is_none()).count(), 2);
+    assert!(plan_output_names(&names, CaseCollisionPolicy::Error).is_err());
+    assert!(plan_output_names(&["a.txt", "b.txt"], CaseCollisionPolicy::Error).is_ok());
+    assert_eq!("overwrite".parse::<CaseCollisionPolicy>().unwrap(), CaseCollisionPolicy::Overwrite);
+}
+
+/// Concurrent probes of one folder each use their own probe file: they all agree and
+/// leave nothing behind.
+#[test]
+fn test_case_probe_concurrent() {
+    use mabi_pack2::common::is_case_insensitive_dir;
+
+    let dir = common::temp_dir_for_test("case_probe");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let expected = is_case_insensitive_dir(&dir);
+    let results: Vec<bool> = std::thread::scope(|s| {
+        let probes: Vec<_> = (0..16).map(|_| s.spawn(|| (0..20).all(|_| is_case_insensitive_dir(&dir) == expected))).collect();
+        probes.into_iter().map(|p| p.join().unwrap()).collect()
+    });
+    assert!(results.iter().all(|&agreed| agreed));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 26. --path-template expansion  (in-memory, fast)
+// --------------------------------------------------------------------------
+
+/// Every field expands from a backslash entry name, empty or `..` segments are
+/// dropped, and distinct entries flattened onto one path are treated as collisions.
+#[test]
+fn test_path_template_fields() {
+    use mabi_pack2::extract::{apply_path_template, plan_output_paths, validate_path_template, CaseCollisionPolicy};
+
+    let name = "data\\gfx\\char\\hair.dds";
+    assert_eq!(apply_path_template("{ext}/{name}", name), "dds/hair.dds");
+    assert_eq!(apply_path_template("{top}/{stem}.{ext}", name), "data/hair.dds");
+    assert_eq!(apply_path_template("{dir}", name), "data/gfx/char");
+    assert_eq!(apply_path_template("{flat}", name), "data_gfx_char_hair.dds");
+    assert_eq!(apply_path_template("{path}", name), "data/gfx/char/hair.dds");
+    assert_eq!(apply_path_template("{top}/{ext}/{name}", "README"), "noext/README");
+    assert_eq!(apply_path_template("../{dir}/{name}", "a.txt"), "a.txt");
+    assert!(validate_path_template("{ext}/{name}").is_ok());
+    assert!(validate_path_template("{size}/{name}").is_err());
+    assert!(validate_path_template("{name").is_err());
+
+    let sources = ["a\\x.txt", "b\\x.txt", "b\\x.txt"];
+    let outputs: Vec<String> = sources.iter().map(|s| apply_path_template("{name}", s)).collect();
+    let outputs: Vec<&str> = outputs.iter().map(|s| s.as_str()).collect();
+    let planned = plan_output_paths(&outputs, &sources, CaseCollisionPolicy::Suffix, false).unwrap();
+    assert_eq!(planned, vec![Some("x.txt".to_string()), Some("x~2.txt".to_string()), Some("x~2.txt".to_string())]);
+}
+
+// --------------------------------------------------------------------------
+// 27. cp / mv between packs  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Moving entries creates the destination with the source's salt, drops them from
+/// the rewritten source, and a later copy replaces same-named destination entries.
+#[test]
+fn test_move_entries_between_packs() {
+    use mabi_pack2::edit::run_transfer_entries;
+    use mabi_pack2::reader::PackReader;
+
+    let dir = common::temp_dir_for_test("transfer");
+    let src = std::env::temp_dir().join("mabi_test_transfer_src.it");
+    let dst = std::env::temp_dir().join("mabi_test_transfer_dst.it");
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&dst);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    std::fs::write(dir.join("db").join("items.xml"), b"<items/>").unwrap();
+    std::fs::write(dir.join("readme.txt"), b"v1").unwrap();
+    std::fs::write(dir.join("skin.bin"), vec![3u8; 1500]).unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), src.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let (src_s, dst_s) = (src.to_str().unwrap(), dst.to_str().unwrap());
+    let moved = run_transfer_entries(src_s, dst_s, None, None, &salts, &mabi_pack2::common::PackSettings::default(), vec![r"\.(xml|txt)$".to_string()], true).unwrap();
+    assert_eq!(moved, 2);
+
+    let source = PackReader::open(src_s, None, &salts).unwrap();
+    assert_eq!(source.entries().len(), 1);
+    assert_eq!(source.read_entry(source.find("skin.bin").unwrap()).unwrap(), vec![3u8; 1500]);
+    drop(source);
+    let dest = PackReader::open(dst_s, None, &salts).unwrap();
+    assert_eq!(dest.header_salt, KNOWN_SALT);
+    assert_eq!(dest.read_entry(dest.find("readme.txt").unwrap()).unwrap(), b"v1");
+    drop(dest);
+
+    assert_eq!(run_transfer_entries(src_s, dst_s, None, None, &salts, &mabi_pack2::common::PackSettings::default(), vec![], false).unwrap(), 1);
+    let dest = PackReader::open(dst_s, None, &salts).unwrap();
+    assert_eq!(dest.entries().len(), 3);
+    drop(dest);
+    assert!(run_transfer_entries(src_s, src_s, None, None, &salts, &mabi_pack2::common::PackSettings::default(), vec![], true).is_err());
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&src);
+    let _ = std::fs::remove_file(&dst);
+}
+
+// --------------------------------------------------------------------------
+// 28. Empty folders round-trip  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// `keep_empty_dirs` stores only the leaf empty folders as markers, extract
+/// recreates them, and packs built without the option have no markers.
+#[test]
+fn test_pack_keep_empty_dirs_roundtrip() {
+    use mabi_pack2::extract::run_extract_with_key_search;
+    use mabi_pack2::pack::{run_pack_with_options, PackOptions};
+    use mabi_pack2::reader::PackReader;
+
+    let dir = common::temp_dir_for_test("empty_dirs");
+    let out = common::temp_dir_for_test("empty_dirs_out");
+    let packed = std::env::temp_dir().join("mabi_test_empty_dirs.it");
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    std::fs::create_dir_all(dir.join("maps").join("unused")).unwrap();
+    std::fs::create_dir_all(dir.join("cache")).unwrap();
+    std::fs::write(dir.join("maps").join("a.xml"), b"<map/>").unwrap();
+    let (src, dst) = (dir.to_str().unwrap(), packed.to_str().unwrap());
+    let salts = vec![KNOWN_SALT.to_string()];
+
+    run_pack_with_options(src, dst, KNOWN_SALT, &PackOptions::default(), None).unwrap();
+    let reader = PackReader::open(dst, None, &salts).unwrap();
+    assert!(!reader.entries().iter().any(|e| e.is_dir_marker()));
+    drop(reader);
+
+    let opts = PackOptions { keep_empty_dirs: true, ..PackOptions::default() };
+    run_pack_with_options(src, dst, KNOWN_SALT, &opts, None).unwrap();
+    let reader = PackReader::open(dst, None, &salts).unwrap();
+    let mut markers: Vec<&str> = reader.entries().iter().filter(|e| e.is_dir_marker()).map(|e| e.name.as_str()).collect();
+    markers.sort();
+    assert_eq!(markers, ["cache\\", "maps\\unused\\"]);
+    drop(reader);
+
+    run_extract_with_key_search(dst, out.to_str().unwrap(), None, &salts, vec![], None, false, None).unwrap();
+    assert!(out.join("cache").is_dir());
+    assert!(out.join("maps").join("unused").is_dir());
+    assert!(out.join("maps").join("a.xml").is_file());
+
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 29. Symbolic link entries  (link resolution fast; roundtrip needs temp filesystem)
+// --------------------------------------------------------------------------
+
+/// Relative targets resolve against the link's folder; absolute targets and
+/// ones climbing above the pack root don't resolve.
+#[test]
+fn test_resolve_link_target() {
+    use mabi_pack2::extract::{resolve_link_target, SymlinkPolicy};
+
+    assert_eq!(resolve_link_target("alias\\link.dds", "../tex/real.dds").as_deref(), Some("tex\\real.dds"));
+    assert_eq!(resolve_link_target("alias/link.dds", "./other.dds").as_deref(), Some("alias\\other.dds"));
+    assert_eq!(resolve_link_target("top.dds", "../escape.dds"), None);
+    assert_eq!(resolve_link_target("a\\b.dds", "/etc/passwd"), None);
+    assert_eq!(resolve_link_target("a\\b.dds", "C:\\Windows\\x"), None);
+    assert_eq!("copy".parse::<SymlinkPolicy>().unwrap(), SymlinkPolicy::Materialize);
+    assert_eq!(SymlinkPolicy::default(), SymlinkPolicy::Materialize);
+}
+
+/// A target that stays inside the output folder by name but leaves it through a
+/// folder link already on disk is caught once the path is canonicalized.
+#[cfg(unix)]
+#[test]
+fn test_link_stays_inside() {
+    use mabi_pack2::extract::link_stays_inside;
+
+    let root = common::temp_dir_for_test("link_inside");
+    let outside = common::temp_dir_for_test("link_inside_outside");
+    common::cleanup(&root);
+    common::cleanup(&outside);
+    std::fs::create_dir_all(root.join("tex")).unwrap();
+    std::fs::create_dir_all(&outside).unwrap();
+    std::os::unix::fs::symlink(&outside, root.join("tex").join("up")).unwrap();
+
+    let link = root.join("alias").join("link.dds");
+    std::fs::create_dir_all(link.parent().unwrap()).unwrap();
+    assert!(link_stays_inside(&root, &link, "../tex/real.dds"));
+    assert!(!link_stays_inside(&root, &link, "../tex/up/real.dds"));
+    assert!(!link_stays_inside(&root, &link, "../../real.dds"));
+
+    common::cleanup(&root);
+    common::cleanup(&outside);
+}
+
+/// Links stored with `store_symlinks` come back as links (`recreate`) or as
+/// copies of their target, following link chains (`materialize`).
+#[cfg(unix)]
+#[test]
+fn test_symlink_entries_roundtrip() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions, SymlinkPolicy};
+    use mabi_pack2::pack::{run_pack_with_options, PackOptions};
+
+    let dir = common::temp_dir_for_test("symlinks");
+    let out = common::temp_dir_for_test("symlinks_out");
+    let packed = std::env::temp_dir().join("mabi_test_symlinks.it");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("tex")).unwrap();
+    std::fs::create_dir_all(dir.join("alias")).unwrap();
+    std::fs::write(dir.join("tex").join("real.dds"), b"pixels").unwrap();
+    std::os::unix::fs::symlink("../tex/real.dds", dir.join("alias").join("link.dds")).unwrap();
+    std::os::unix::fs::symlink("link.dds", dir.join("alias").join("chain.dds")).unwrap();
+    let opts = PackOptions { store_symlinks: true, ..PackOptions::default() };
+    run_pack_with_options(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, &opts, None).unwrap();
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    for policy in [SymlinkPolicy::Recreate, SymlinkPolicy::Materialize] {
+        common::cleanup(&out);
+        let opts = ExtractOptions { symlinks: policy, ..ExtractOptions::default() };
+        run_extract_with_options(packed.to_str().unwrap(), out.to_str().unwrap(), None, &salts, &opts).unwrap();
+        let chain = out.join("alias").join("chain.dds");
+        assert_eq!(std::fs::symlink_metadata(&chain).unwrap().file_type().is_symlink(), policy == SymlinkPolicy::Recreate);
+        assert_eq!(std::fs::read(&chain).unwrap(), b"pixels");
+    }
+
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 30. Entry sanity limits per header version  (fast)
+// --------------------------------------------------------------------------
+
+/// v2 packs keep the historical limits; newer header versions get relaxed ones.
+#[test]
+fn test_entry_limits_per_version() {
+    use mabi_pack2::common::{EntryLimits, FileEntry};
+
+    assert_eq!(EntryLimits::for_version(2), EntryLimits::V2);
+    assert_eq!(EntryLimits::for_version(1), EntryLimits::V2);
+    assert_eq!(EntryLimits::for_version(3), EntryLimits::V3);
+
+    let ent = |name: String, size: u32| FileEntry { name, checksum: 0, flags: 0, offset: 0, original_size: size, raw_size: 0, key: [0u8; 16] };
+    let long_name = "d\\".repeat(600) + "x.xml";
+    assert!(EntryLimits::V2.plausible(&ent("db\\a.xml".into(), 10)));
+    assert!(!EntryLimits::V2.plausible(&ent(String::new(), 10)));
+    assert!(!EntryLimits::V2.plausible(&ent(long_name.clone(), 10)));
+    assert!(!EntryLimits::V2.plausible(&ent("big.bin".into(), 600_000_000)));
+    assert!(EntryLimits::V3.plausible(&ent(long_name, 600_000_000)));
+
+    let untrusted = mabi_pack2::common::PackSettings { untrusted: true, ..Default::default() };
+    assert_eq!(EntryLimits::effective(3, &untrusted).max_entries, EntryLimits::UNTRUSTED.max_entries);
+    assert_eq!(EntryLimits::effective(2, &untrusted).max_original_size, EntryLimits::UNTRUSTED.max_original_size);
+    assert_eq!(EntryLimits::effective(2, &Default::default()), EntryLimits::V2);
+}
+
+// --------------------------------------------------------------------------
+// 31. PackReader name index  (in-memory, fast)
+// --------------------------------------------------------------------------
+
+/// Lookups accept either slash style and return the first of duplicate names;
+/// prefix queries come back in entry order; only safe anchored literals are
+/// used as prefixes.
+#[test]
+fn test_name_index_and_literal_prefix() {
+    use mabi_pack2::common::FileEntry;
+    use mabi_pack2::reader::{literal_prefix, NameIndex};
+
+    let ent = |name: &str| FileEntry { name: name.to_string(), checksum: 0, flags: 0, offset: 0, original_size: 0, raw_size: 0, key: [0u8; 16] };
+    let entries = vec![ent("gfx\\b.dds"), ent("db\\items.xml"), ent("gfx\\a.dds"), ent("db/items.xml"), ent("gfxold\\c.dds")];
+    let index = NameIndex::build(&entries);
+    assert_eq!(index.lookup("db/items.xml"), Some(1));
+    assert_eq!(index.lookup("gfx\\a.dds"), Some(2));
+    assert_eq!(index.lookup("gfx\\missing.dds"), None);
+    assert_eq!(index.with_prefix("gfx/"), vec![0, 2]);
+    assert_eq!(index.with_prefix("gfx"), vec![0, 2, 4]);
+
+    assert_eq!(literal_prefix(r"^gfx\\char\\.*\.dds$").as_deref(), Some("gfx\\char\\"));
+    assert_eq!(literal_prefix(r"^data/items\.xml").as_deref(), Some("data/items.xml"));
+    assert_eq!(literal_prefix(r"^dbs?/").as_deref(), Some("db"));
+    assert_eq!(literal_prefix(r"^\d+").as_deref(), Some(""));
+    assert_eq!(literal_prefix(r"\.xml$"), None);
+    assert_eq!(literal_prefix(r"^a|^b"), None);
+}
+
+// --------------------------------------------------------------------------
+// 32. Operation summaries  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// pack, list and extract report entry counts, decoded bytes and the salts used;
+/// extraction also counts files and bytes per extension.
+#[test]
+fn test_operation_summaries() {
+    use mabi_pack2::extract::run_extract_with_key_search;
+    use mabi_pack2::list::run_list_with_key_search;
+    use mabi_pack2::pack::run_pack;
+
+    let dir = common::temp_dir_for_test("summary");
+    let out = common::temp_dir_for_test("summary_out");
+    let packed = std::env::temp_dir().join("mabi_test_summary.it");
+    let listing = std::env::temp_dir().join("mabi_test_summary.txt");
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    std::fs::write(dir.join("db").join("a.xml"), b"<a/>").unwrap();
+    std::fs::write(dir.join("b.txt"), vec![b'b'; 3000]).unwrap();
+    let (src, dst) = (dir.to_str().unwrap(), packed.to_str().unwrap());
+    let salts = vec![KNOWN_SALT.to_string()];
+
+    let packed_summary = run_pack(src, dst, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    assert_eq!((packed_summary.entries, packed_summary.bytes), (2, 3004));
+    assert_eq!(packed_summary.salt.as_deref(), Some(KNOWN_SALT));
+
+    let listed = run_list_with_key_search(dst, None, &salts, listing.to_str()).unwrap();
+    assert_eq!((listed.entries, listed.bytes), (2, 3004));
+    assert_eq!(listed.entries_salt.as_deref(), Some(KNOWN_SALT));
+
+    let extracted = run_extract_with_key_search(dst, out.to_str().unwrap(), None, &salts, vec!["xml$".into()], None, false, None).unwrap();
+    assert_eq!((extracted.entries, extracted.failed, extracted.bytes), (1, 0, 4));
+    assert_eq!(extracted.salt.as_deref(), Some(KNOWN_SALT));
+    assert!(extracted.legacy_format.is_none() && extracted.warnings.is_empty());
+    assert_eq!(extracted.by_extension.len(), 1);
+    assert_eq!(extracted.by_extension[".xml"], mabi_pack2::common::ExtensionCount { files: 1, bytes: 4 });
+
+    let everything = run_extract_with_key_search(dst, out.to_str().unwrap(), None, &salts, vec![], None, false, None).unwrap();
+    assert_eq!(everything.by_extension[".txt"].bytes, 3000);
+    assert_eq!(mabi_pack2::common::extension_of("data\\gfx\\Tex.DDS"), ".dds");
+    assert_eq!(mabi_pack2::common::extension_of("dir.d/README"), "(none)");
+
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+    let _ = std::fs::remove_file(&listing);
+}
+
+// --------------------------------------------------------------------------
+// 33. Parallel deep verification  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// A flipped byte at the end of a payload slips past the first-block probe but
+/// `deep` decodes it fully and reports the digest mismatch.
+#[test]
+fn test_verify_deep_finds_damaged_entry() {
+    use mabi_pack2::pack::{run_pack_with_options, PackOptions};
+    use mabi_pack2::reader::PackReader;
+    use mabi_pack2::verify::run_verify;
+
+    let dir = common::temp_dir_for_test("verify_deep");
+    let packed = std::env::temp_dir().join("mabi_test_verify_deep.it");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("big.bin"), (0..5000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>()).unwrap();
+    std::fs::write(dir.join("small.txt"), b"fine").unwrap();
+    let dst = packed.to_str().unwrap().to_string();
+    let salts = vec![KNOWN_SALT.to_string()];
+    let opts = PackOptions { content_hashes: true, ..PackOptions::default() };
+    run_pack_with_options(dir.to_str().unwrap(), &dst, KNOWN_SALT, &opts, None).unwrap();
+
+    let clean = run_verify(std::slice::from_ref(&dst), None, &salts, &mabi_pack2::common::PackSettings::default(), true, 2, 1).unwrap();
+    assert_eq!((clean.archives, clean.entries, clean.failures.len()), (1, 2, 0));
+
+    let reader = PackReader::open(&dst, None, &salts).unwrap();
+    let ent = reader.find("big.bin").unwrap().clone();
+    let last = (reader.content_offset + ent.offset as u64 * 1024 + ent.raw_size as u64 - 1) as usize;
+    drop(reader);
+    let mut data = std::fs::read(&packed).unwrap();
+    data[last] ^= 0xFF;
+    std::fs::write(&packed, data).unwrap();
+
+    let shallow = run_verify(std::slice::from_ref(&dst), None, &salts, &mabi_pack2::common::PackSettings::default(), false, 2, 1).unwrap();
+    assert!(shallow.failures.is_empty());
+    let deep = run_verify(std::slice::from_ref(&dst), None, &salts, &mabi_pack2::common::PackSettings::default(), true, 2, 1).unwrap();
+    assert_eq!(deep.failures.len(), 1);
+    assert_eq!(deep.failures[0].entry, "big.bin");
+    assert_eq!(deep.packs.len(), 1);
+    assert_eq!((deep.packs[0].entries, deep.packs[0].failed), (2, 1));
+    assert!(!deep.packs[0].passed());
+    assert!(clean.packs[0].passed());
+
+    let missing = std::env::temp_dir().join("mabi_test_verify_missing.it").to_str().unwrap().to_string();
+    let both = run_verify(&[dst.clone(), missing], None, &salts, &mabi_pack2::common::PackSettings::default(), false, 2, 1).unwrap();
+    let packs: Vec<(usize, bool)> = both.packs.iter().map(|p| (p.entries, p.passed())).collect();
+    assert_eq!(packs, vec![(2, true), (0, false)]);
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 34. --protect globs  (fast)
+// --------------------------------------------------------------------------
+
+/// Bare patterns match file names in any folder, `*` stays in one folder, `**`
+/// and a trailing `/` cover whole subtrees, matching ignores case and slash style.
+#[test]
+fn test_protect_globs() {
+    use mabi_pack2::extract::ProtectList;
+
+    let protect = ProtectList::new(&["*.ini".into(), "config/".into(), "db/*.xml".into(), "ui/**/skin?.[pd]ds".into()]).unwrap();
+    assert!(protect.matches("options.ini"));
+    assert!(protect.matches("data\\local\\User.INI"));
+    assert!(protect.matches("config/keys/a.txt"));
+    assert!(protect.matches("db/items.xml"));
+    assert!(!protect.matches("db/sub/items.xml"));
+    assert!(!protect.matches("data/db/items.xml"));
+    assert!(protect.matches("ui/skin1.dds"));
+    assert!(protect.matches("ui/a/b/skin2.pds"));
+    assert!(!protect.matches("ui/skin12.dds"));
+    assert!(!protect.matches("options.ini.bak"));
+    assert!(ProtectList::new(&["[unclosed".into()]).unwrap().matches("[unclosed"));
+}
+
+// --------------------------------------------------------------------------
+// 35. Backups of overwritten files  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Files about to be overwritten are copied to the backup folder first; a second
+/// extraction keeps the original backup and doesn't back up files the first one created.
+#[test]
+fn test_extract_backup_dir() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use mabi_pack2::pack::run_pack;
+
+    let dir = common::temp_dir_for_test("backup_src");
+    let out = common::temp_dir_for_test("backup_out");
+    let backup = common::temp_dir_for_test("backup_bak");
+    let packed = std::env::temp_dir().join("mabi_test_backup.it");
+    for d in [&dir, &out, &backup] {
+        common::cleanup(d);
+    }
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    std::fs::write(dir.join("db").join("a.xml"), b"<new/>").unwrap();
+    std::fs::write(dir.join("b.txt"), b"new").unwrap();
+    std::fs::create_dir_all(out.join("db")).unwrap();
+    std::fs::write(out.join("db").join("a.xml"), b"<user/>").unwrap();
+    run_pack(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let opts = ExtractOptions { backup_dir: Some(backup.to_str().unwrap().to_string()), ..ExtractOptions::default() };
+    let first = run_extract_with_options(packed.to_str().unwrap(), out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    assert_eq!(first.backed_up, 1);
+    assert_eq!(std::fs::read(backup.join("db").join("a.xml")).unwrap(), b"<user/>");
+    assert!(!backup.join("b.txt").exists());
+    assert_eq!(std::fs::read(out.join("db").join("a.xml")).unwrap(), b"<new/>");
+
+    let second = run_extract_with_options(packed.to_str().unwrap(), out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    assert_eq!(second.backed_up, 0, "b.txt was created by the first run, a.xml already has a backup");
+    assert_eq!(std::fs::read(backup.join("db").join("a.xml")).unwrap(), b"<user/>");
+
+    for d in [&dir, &out, &backup] {
+        common::cleanup(d);
+    }
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 36. Restore from a backup folder  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Restoring puts overwritten files back, deletes the files extraction created
+/// (and folders left empty) and leaves unrelated files alone.
+#[test]
+fn test_restore_undoes_extraction() {
+    use mabi_pack2::backup::run_restore;
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use mabi_pack2::pack::run_pack;
+
+    let dir = common::temp_dir_for_test("restore_src");
+    let out = common::temp_dir_for_test("restore_out");
+    let backup = common::temp_dir_for_test("restore_bak");
+    let packed = std::env::temp_dir().join("mabi_test_restore.it");
+    for d in [&dir, &out, &backup] {
+        common::cleanup(d);
+    }
+    std::fs::create_dir_all(dir.join("new").join("deep")).unwrap();
+    std::fs::write(dir.join("new").join("deep").join("n.txt"), b"added").unwrap();
+    std::fs::write(dir.join("c.xml"), b"<mod/>").unwrap();
+    std::fs::create_dir_all(&out).unwrap();
+    std::fs::write(out.join("c.xml"), b"<game/>").unwrap();
+    std::fs::write(out.join("keep.txt"), b"mine").unwrap();
+    run_pack(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let opts = ExtractOptions { backup_dir: Some(backup.to_str().unwrap().to_string()), ..ExtractOptions::default() };
+    run_extract_with_options(packed.to_str().unwrap(), out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    assert_eq!(std::fs::read(out.join("c.xml")).unwrap(), b"<mod/>");
+
+    let dry = run_restore(backup.to_str().unwrap(), None, true).unwrap();
+    assert_eq!((dry.restored, dry.deleted), (1, 1));
+    assert!(out.join("new").join("deep").join("n.txt").exists());
+
+    let report = run_restore(backup.to_str().unwrap(), None, false).unwrap();
+    assert_eq!((report.restored, report.deleted), (1, 1));
+    assert_eq!(std::fs::read(out.join("c.xml")).unwrap(), b"<game/>");
+    assert_eq!(std::fs::read(out.join("keep.txt")).unwrap(), b"mine");
+    assert!(!out.join("new").exists());
+
+    for d in [&dir, &out, &backup] {
+        common::cleanup(d);
+    }
+    let _ = std::fs::remove_file(&packed);
+}
+
+/// A manifest naming paths outside its target folder is refused before anything is
+/// restored or deleted.
+#[test]
+fn test_restore_refuses_paths_outside_target() {
+    use mabi_pack2::backup::{run_restore, BackupManifest, MANIFEST_FILE};
+
+    let base = common::temp_dir_for_test("restore_escape");
+    common::cleanup(&base);
+    let (out, backup) = (base.join("out"), base.join("bak"));
+    std::fs::create_dir_all(&out).unwrap();
+    std::fs::create_dir_all(&backup).unwrap();
+    std::fs::write(base.join("victim.txt"), b"keep me").unwrap();
+    for (backed_up, created) in [(vec![], vec!["../victim.txt"]), (vec!["/victim.txt"], vec![])] {
+        let manifest = BackupManifest {
+            target: out.to_str().unwrap().to_string(),
+            backed_up: backed_up.into_iter().map(str::to_string).collect(),
+            created: created.into_iter().map(str::to_string).collect(),
+        };
+        std::fs::write(backup.join(MANIFEST_FILE), serde_json::to_vec(&manifest).unwrap()).unwrap();
+        let err = run_restore(backup.to_str().unwrap(), None, false).unwrap_err().to_string();
+        assert!(err.contains("Refusing to restore"), "{}", err);
+        assert_eq!(std::fs::read(base.join("victim.txt")).unwrap(), b"keep me");
+    }
+
+    common::cleanup(&base);
+}
+
+// --------------------------------------------------------------------------
+// 37. info --layout block map  (in-memory, fast)
+// --------------------------------------------------------------------------
+
+/// Runs come out in block order with gaps between them; entries sharing data are
+/// merged, overlapping ones flagged, and slack counts padding plus gap bytes.
+#[test]
+fn test_pack_layout_block_map() {
+    use mabi_pack2::common::FileEntry;
+    use mabi_pack2::info::pack_layout;
+
+    let ent = |name: &str, offset: u32, raw: u32| FileEntry { name: name.to_string(), checksum: 0, flags: 0, offset, original_size: raw, raw_size: raw, key: [0u8; 16] };
+    let entries = vec![
+        ent("b.dds", 4, 1024),
+        ent("a.xml", 0, 1500),
+        ent("copy.xml", 0, 1500),
+        ent("inner.bin", 1, 100),
+        ent("dir\\", 0, 0),
+        ent("tail.txt", 5, 10),
+    ];
+    // The content area ends right after tail.txt, then 1 KiB of extra space.
+    let layout = pack_layout(&entries, 5 * 1024 + 10 + 1024);
+
+    let runs: Vec<(u64, u64, usize, bool)> = layout.rows.iter().map(|r| (r.first_block, r.blocks, r.owners.len(), r.overlaps)).collect();
+    assert_eq!(runs, vec![(0, 2, 2, false), (1, 1, 1, true), (2, 2, 0, false), (4, 1, 1, false), (5, 1, 1, false), (6, 1, 0, false)]);
+    assert_eq!(layout.rows[0].owners, vec!["a.xml", "copy.xml"]);
+    assert_eq!(layout.total_blocks, 7);
+    assert_eq!(layout.gap_blocks, 3);
+    assert_eq!(layout.padding, (2048 - 1500) + (1024 - 10));
+    assert_eq!(layout.gap_bytes, 2 * 1024 + 10);
+    assert_eq!(layout.slack(), layout.padding + layout.gap_bytes);
+    assert_eq!((layout.reused_entries, layout.empty_entries, layout.past_end), (2, 1, 0));
+
+    assert_eq!(pack_layout(&entries, 4 * 1024).past_end, 2);
+}
+
+// --------------------------------------------------------------------------
+// 38. Header-only match diagnosis  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Record scoring separates entry records from noise; a header whose entries use an
+/// unknown salt reads as genuine, one with an impossible file count as a coincidence,
+/// and a good first record with the right salt points at a broken table.
+#[test]
+fn test_assess_header_only_match() {
+    use mabi_pack2::common::{FileEntry, FileHeader};
+    use mabi_pack2::common_ext::{assess_header_only, score_entry_record, search_all_keys};
+    use mabi_pack2::encryption::Snow2Mode;
+
+    let mut rec = 9u32.to_le_bytes().to_vec();
+    rec.extend("db\\a.xml".encode_utf16().chain(std::iter::once(b'x' as u16)).flat_map(|u| u.to_le_bytes()));
+    assert_eq!(score_entry_record(&rec), (9, 1.0));
+    assert_eq!(score_entry_record(&[0xAB, 0xCD, 0x12, 0x7F, 0, 0]).1, 0.0);
+
+    const ENTRIES_SALT: &str = "@6QeTuOaDgJlZcBm#9";
+    let packed = std::env::temp_dir().join("mabi_test_header_only.it");
+    let names = vec!["db\\header_only.xml".to_string()];
+    let mut writer = mabi_pack2::pack::PackWriter::create(
+        packed.to_str().unwrap(), "mabi_test_header_only.it", KNOWN_SALT, ENTRIES_SALT, 0, Snow2Mode::Sub, &names,
+    ).unwrap();
+    let plain = b"<x/>";
+    let ent = FileEntry { name: names[0].clone(), checksum: 0, flags: 0, offset: 0, original_size: plain.len() as u32, raw_size: 0, key: [3u8; 16] };
+    let content = mabi_pack2::pack::encode_entry_data(&ent.name, &ent.key, plain, ent.flags, 0, Snow2Mode::Sub).unwrap();
+    writer.add_raw(ent, &content).unwrap();
+    writer.finish().unwrap();
+    let data = std::fs::read(&packed).unwrap();
+
+    let matches = search_all_keys(&data, packed.to_str().unwrap(), None, &[KNOWN_SALT.to_string()], None, &mabi_pack2::common::PackSettings::default()).unwrap();
+    assert!(matches.hits.is_empty());
+    let genuine = matches.header_only.iter().find(|m| m.name_variant == "mabi_test_header_only.it").expect("header should validate");
+    assert!(genuine.false_positive < 0.5, "{:?}", genuine);
+    assert!(genuine.advice().contains("isn't in salts.txt"));
+
+    let header = FileHeader { checksum: 0, version: 2, file_cnt: 1 };
+    let salts = vec![KNOWN_SALT.to_string(), ENTRIES_SALT.to_string()];
+    let broken = assess_header_only(&data, &genuine.name_variant, KNOWN_SALT, &header, genuine.header_offset, 0, Snow2Mode::Sub, &salts);
+    assert_eq!(broken.best_entries_salt, ENTRIES_SALT);
+    assert_eq!(broken.name_units, names[0].len() as u32);
+    assert!(broken.advice().contains("decrypts the first entry"));
+
+    let huge = FileHeader { checksum: 0, version: 1, file_cnt: 0x7F00_0000 };
+    let coincidence = assess_header_only(&data, &genuine.name_variant, KNOWN_SALT, &huge, genuine.header_offset, 0, Snow2Mode::Sub, &salts[..1]);
+    assert!(coincidence.false_positive > 0.9);
+    assert!(coincidence.advice().contains("coincidence"));
+
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 39. Key derivation names for renamed packs  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Copy and download suffixes are stripped to guess the published name, and
+/// `--original-name` opens a pack renamed beyond guessing.
+#[test]
+fn test_renamed_pack_name_guesses() {
+    use mabi_pack2::common::{canonical_name_guesses, key_name_variants, PackSettings};
+    use mabi_pack2::reader::PackReader;
+
+    assert_eq!(canonical_name_guesses("data_00 (1).it"), vec!["data_00.it"]);
+    assert_eq!(canonical_name_guesses("Data_00 - Copy (2).it.bak"), vec!["Data_00.it", "data_00.it"]);
+    assert_eq!(canonical_name_guesses("data_00(1).it.crdownload"), vec!["data_00.it"]);
+    assert!(canonical_name_guesses("data_00.it").is_empty());
+    assert_eq!(key_name_variants("dl/data_00 (1).it", None, &PackSettings::default()).unwrap(), vec!["data_00 (1).it", "data.it", "", "data_00.it"]);
+
+    let dir = common::temp_dir_for_test("renamed_src");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("r.xml"), b"<renamed/>").unwrap();
+    let tmp = std::env::temp_dir();
+    let packed = tmp.join("mabi_test_renamed.it");
+    let copied = tmp.join("mabi_test_renamed (1).it");
+    let renamed = tmp.join("mabi_test_other.it");
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    std::fs::copy(&packed, &copied).unwrap();
+    std::fs::copy(&packed, &renamed).unwrap();
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let reader = PackReader::open(copied.to_str().unwrap(), None, &salts).unwrap();
+    assert_eq!(reader.name_variant, "mabi_test_renamed.it");
+    assert!(PackReader::open(renamed.to_str().unwrap(), None, &salts).is_err());
+    let settings = PackSettings { original_name: Some("mabi_test_renamed.it".to_string()), ..PackSettings::default() };
+    let reader = PackReader::open_with_settings(renamed.to_str().unwrap(), None, &salts, &settings).unwrap();
+    assert_eq!(reader.entries()[0].name, "r.xml");
+
+    common::cleanup(&dir);
+    for p in [&packed, &copied, &renamed] {
+        let _ = std::fs::remove_file(p);
+    }
+}
+
+// --------------------------------------------------------------------------
+// 40. Key export  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// The exported file key and offsets alone are enough to decrypt an entry.
+#[test]
+fn test_key_export_decodes_entry() {
+    use mabi_pack2::common::{FileEntry, FLAG_ALL_ENCRYPTED};
+    use mabi_pack2::encryption::{snow2_decrypt_mode, Snow2Mode};
+    use mabi_pack2::reader::PackReader;
+
+    let packed = std::env::temp_dir().join("mabi_test_key_export.it");
+    let names = vec!["db\\secret.xml".to_string()];
+    let mut writer = mabi_pack2::pack::PackWriter::create(
+        packed.to_str().unwrap(), "mabi_test_key_export.it", KNOWN_SALT, KNOWN_SALT, 0, Snow2Mode::Sub, &names,
+    ).unwrap();
+    let plain = b"<secret>payload</secret>";
+    let ent = FileEntry { name: names[0].clone(), checksum: 0, flags: FLAG_ALL_ENCRYPTED, offset: 0, original_size: plain.len() as u32, raw_size: 0, key: [0x5Au8; 16] };
+    let content = mabi_pack2::pack::encode_entry_data(&ent.name, &ent.key, plain, ent.flags, 0, Snow2Mode::Sub).unwrap();
+    writer.add_raw(ent, &content).unwrap();
+    writer.finish().unwrap();
+
+    let export = PackReader::open(packed.to_str().unwrap(), None, &[KNOWN_SALT.to_string()]).unwrap().key_export();
+    assert_eq!(export.mode, "Sub");
+    let keys = &export.entries[0];
+    assert_eq!(keys.entry_key, "5a".repeat(16));
+    let file_key: Vec<u8> = (0..32).step_by(2).map(|i| u8::from_str_radix(&keys.file_key[i..i + 2], 16).unwrap()).collect();
+
+    let data = std::fs::read(&packed).unwrap();
+    let start = keys.data_offset as usize;
+    let mut payload = data[start..start + keys.raw_size as usize].to_vec();
+    snow2_decrypt_mode(&file_key, export.iv0, Snow2Mode::Sub, &mut payload);
+    assert_eq!(payload, plain);
+
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 41. Opening packs held in memory  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// `from_bytes` and `from_reader` find the keys and decode entries without a file;
+/// the name passed in still drives key derivation.
+#[test]
+fn test_pack_reader_from_memory() {
+    use mabi_pack2::reader::PackReader;
+    use std::io::{Cursor, Seek, SeekFrom};
+
+    let dir = common::temp_dir_for_test("in_memory_src");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("m.xml"), b"<memory/>").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_in_memory.it");
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let data = std::fs::read(&packed).unwrap();
+    let _ = std::fs::remove_file(&packed);
+    common::cleanup(&dir);
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let reader = PackReader::from_bytes("mabi_test_in_memory.it", data.clone(), None, &salts).unwrap();
+    assert_eq!(reader.bytes().len(), data.len());
+    assert_eq!(reader.read_entry(reader.find("m.xml").unwrap()).unwrap(), b"<memory/>");
+
+    let shared: std::sync::Arc<[u8]> = data.clone().into();
+    assert_eq!(PackReader::from_bytes("mabi_test_in_memory.it", shared, None, &salts).unwrap().entries().len(), 1);
+
+    let mut cursor = Cursor::new(data);
+    cursor.seek(SeekFrom::End(0)).unwrap();
+    let reader = PackReader::from_reader("mabi_test_in_memory.it", cursor, None, &salts).unwrap();
+    assert_eq!(reader.read_entry(&reader.entries()[0]).unwrap(), b"<memory/>");
+
+    let legacy = PackReader::from_bytes("old.pack", b"PACK\0\0\0\0".to_vec(), None, &salts);
+    assert!(legacy.err().unwrap().to_string().contains("legacy"));
+}
+
+// --------------------------------------------------------------------------
+// 42. Empty packs  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Packing an empty folder needs `allow_empty`; the resulting zero-entry pack
+/// opens, lists, extracts and verifies as empty.
+#[test]
+fn test_empty_pack_roundtrip() {
+    use mabi_pack2::pack::{run_pack_with_options, PackOptions};
+
+    let dir = common::temp_dir_for_test("empty_src");
+    let out = common::temp_dir_for_test("empty_out");
+    let packed = std::env::temp_dir().join("mabi_test_empty.it");
+    let listed = std::env::temp_dir().join("mabi_test_empty.txt");
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    std::fs::create_dir_all(&dir).unwrap();
+    let _ = std::fs::remove_file(&packed);
+
+    let err = run_pack_with_options(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, &PackOptions::default(), None).unwrap_err();
+    assert!(err.to_string().contains("--allow-empty"));
+    assert!(!packed.exists());
+    let opts = PackOptions { allow_empty: true, ..PackOptions::default() };
+    assert_eq!(run_pack_with_options(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, &opts, None).unwrap().entries, 0);
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let path = packed.to_str().unwrap();
+    assert!(mabi_pack2::reader::PackReader::open(path, None, &salts).unwrap().entries().is_empty());
+    let summary = mabi_pack2::list::run_list_with_key_search(path, None, &salts, listed.to_str()).unwrap();
+    assert_eq!((summary.entries, summary.salt.as_deref()), (0, Some(KNOWN_SALT)));
+    assert_eq!(std::fs::read_to_string(&listed).unwrap(), "");
+    let summary = mabi_pack2::extract::run_extract_with_key_search(path, out.to_str().unwrap(), None, &salts, vec![], None, false, None).unwrap();
+    assert_eq!((summary.entries, summary.failed), (0, 0));
+    let report = mabi_pack2::verify::run_verify(&[path.to_string()], None, &salts, &mabi_pack2::common::PackSettings::default(), true, 1, 1).unwrap();
+    assert_eq!((report.archives, report.entries, report.failures.len()), (1, 0, 0));
+
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+    let _ = std::fs::remove_file(&listed);
+}
+
+// --------------------------------------------------------------------------
+// 43. list --json-lines  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Each entry becomes one JSON object on its own line, in table order, and a
+/// record that fails validation stops the stream after the good ones.
+#[test]
+fn test_list_json_lines() {
+    use mabi_pack2::common::{find_header_only, probe_first_entry, stream_entries};
+    use mabi_pack2::reader::PackReader;
+
+    let dir = common::temp_dir_for_test("json_lines");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    for i in 0..5 {
+        std::fs::write(dir.join("db").join(format!("item{}.xml", i)), format!("<item id=\"{}\"/>", i)).unwrap();
+    }
+    let packed = std::env::temp_dir().join("mabi_test_json_lines.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+
+    let mut out = Vec::new();
+    let summary = mabi_pack2::list::run_list_json_lines(path, None, &salts, &mabi_pack2::common::PackSettings::default(), Vec::new(), &mut out).unwrap();
+    assert_eq!(summary.entries, 5);
+    let reader = PackReader::open(path, None, &salts).unwrap();
+    let lines: Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 5);
+    for (line, ent) in lines.iter().zip(reader.entries()) {
+        assert_eq!(line["name"], ent.name.as_str());
+        assert_eq!(line["original_size"], ent.original_size);
+        assert_eq!(line["offset"], ent.offset);
+    }
+
+    // Corrupt the third record's checksum: the first two are still delivered.
+    let mut data = std::fs::read(&packed).unwrap();
+    let name = "mabi_test_json_lines.it";
+    let mut rd = std::io::Cursor::new(&data[..]);
+    let (header, h_off, iv0, mode) = find_header_only(&mut rd, name, KNOWN_SALT).unwrap().unwrap();
+    let e_off = probe_first_entry(&mut rd, name, KNOWN_SALT, &header, h_off, iv0, mode, &mabi_pack2::common::PackSettings::default()).unwrap();
+    let record = |ent: &mabi_pack2::common::FileEntry| 4 + ent.name.encode_utf16().count() * 2 + 5 * 4 + 16;
+    let third = e_off as usize + record(&reader.entries()[0]) + record(&reader.entries()[1]);
+    data[third + 4 + reader.entries()[2].name.encode_utf16().count() * 2] ^= 0xFF;
+    let mut seen = Vec::new();
+    let err = stream_entries(&mut std::io::Cursor::new(&data[..]), name, KNOWN_SALT, &header, e_off, iv0, mode, &mabi_pack2::common::PackSettings::default(), |ent| {
+        seen.push(ent.name);
+        Ok(())
+    });
+    assert!(err.is_err());
+    assert_eq!(seen.len(), 2);
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 44. Filter compilation limits  (fast)
+// --------------------------------------------------------------------------
+
+/// Fixed strings match literally, and patterns that compile too large are rejected.
+#[test]
+fn test_compile_filters_settings() {
+    use mabi_pack2::extract::{compile_filters, FilterSettings};
+
+    let names = ["db\\a+b.txt", "db\\aab.txt"];
+    let matching = |settings: FilterSettings| -> Vec<&str> {
+        let filters = compile_filters(&["a+b.txt".to_string()], settings).unwrap();
+        names.iter().copied().filter(|n| filters.iter().any(|re| re.is_match(n))).collect()
+    };
+    assert_eq!(matching(FilterSettings::default()), vec!["db\\aab.txt"]);
+    assert_eq!(matching(FilterSettings { fixed_strings: true, ..FilterSettings::default() }), vec!["db\\a+b.txt"]);
+    assert!(compile_filters(&["(".to_string()], FilterSettings { fixed_strings: true, ..FilterSettings::default() }).is_ok());
+
+    let huge = vec!["a{1000}{1000}".to_string()];
+    let err = compile_filters(&huge, FilterSettings::default()).unwrap_err();
+    assert!(err.to_string().contains("a{1000}{1000}"));
+    assert!(compile_filters(&["\\.xml$".to_string()], FilterSettings { size_limit: 1 << 16, ..FilterSettings::default() }).is_ok());
+}
+
+// --------------------------------------------------------------------------
+// 45. --filter-file  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Patterns read from a filter file select the same entries for list and extract;
+/// comments and blank lines are skipped and `glob:` lines are globs.
+#[test]
+fn test_filter_file() {
+    use mabi_pack2::extract::{compile_filters, read_filter_file, run_extract_with_key_search, FilterSettings};
+
+    let dir = common::temp_dir_for_test("filter_file_src");
+    let out = common::temp_dir_for_test("filter_file_out");
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    for (name, body) in [("db/item.xml", "<item/>"), ("db/notes.txt", "notes"), ("gfx/a.dds", "DDS a"), ("gfx/b.dds", "DDS b")] {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, body).unwrap();
+    }
+    let packed = std::env::temp_dir().join("mabi_test_filter_file.it");
+    let list_file = std::env::temp_dir().join("mabi_test_filter_file.lst");
+    let listed = std::env::temp_dir().join("mabi_test_filter_file.txt");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    std::fs::write(&list_file, "# curated assets\n\n\\.xml$\n  glob:gfx/a.*  \n#gfx/b\n").unwrap();
+
+    let filters = read_filter_file(list_file.to_str().unwrap()).unwrap();
+    assert_eq!(filters, vec!["\\.xml$".to_string(), "glob:gfx/a.*".to_string()]);
+    let globs = compile_filters(&filters[1..], FilterSettings { fixed_strings: true, ..FilterSettings::default() }).unwrap();
+    assert!(globs[0].is_match("gfx\\A.dds") && !globs[0].is_match("old/gfx/a.dds"));
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let summary = mabi_pack2::list::run_list_with_key_search_traced(path, None, &salts, &mabi_pack2::common::PackSettings::default(), listed.to_str(), None, filters.clone()).unwrap();
+    assert_eq!(summary.entries, 2);
+    let mut names: Vec<String> = std::fs::read_to_string(&listed).unwrap().lines().map(str::to_string).collect();
+    names.sort();
+    assert_eq!(names, vec!["db/item.xml", "gfx/a.dds"]);
+    let mut lines = Vec::new();
+    assert_eq!(mabi_pack2::list::run_list_json_lines(path, None, &salts, &mabi_pack2::common::PackSettings::default(), filters.clone(), &mut lines).unwrap().entries, 2);
+
+    let summary = run_extract_with_key_search(path, out.to_str().unwrap(), None, &salts, filters, None, false, None).unwrap();
+    assert_eq!(summary.entries, 2);
+    assert!(out.join("db/item.xml").exists() && out.join("gfx/a.dds").exists());
+    assert!(!out.join("db/notes.txt").exists() && !out.join("gfx/b.dds").exists());
+
+    assert!(read_filter_file(dir.join("missing.lst").to_str().unwrap()).unwrap_err().to_string().contains("missing.lst"));
+
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+    let _ = std::fs::remove_file(&list_file);
+    let _ = std::fs::remove_file(&listed);
+}
+
+// --------------------------------------------------------------------------
+// 46. Content-addressed extraction  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Identical contents are stored once under objects/, the index maps every entry to
+/// its digest, and a second run only indexes objects that are already there.
+#[test]
+fn test_content_addressed_extract() {
+    use mabi_pack2::dedupe::ObjectStore;
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use mabi_pack2::verify::{load_manifest, md5_hex};
+
+    let dir = common::temp_dir_for_test("objects_src");
+    let out = common::temp_dir_for_test("objects_out");
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::create_dir_all(dir.join("b")).unwrap();
+    std::fs::write(dir.join("a/same.txt"), "shared content").unwrap();
+    std::fs::write(dir.join("b/copy.txt"), "shared content").unwrap();
+    std::fs::write(dir.join("b/other.xml"), "<other/>").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_objects.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+
+    let store = ObjectStore::new();
+    let opts = ExtractOptions { objects: Some(&store), ..ExtractOptions::default() };
+    let summary = run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    assert_eq!(summary.entries, 3);
+    assert_eq!(store.counts(), (2, 1));
+    let index = out.join("index.md5");
+    assert_eq!(store.write_index(index.to_str().unwrap()).unwrap(), 3);
+    assert!(!out.join("a/same.txt").exists());
+
+    let hashes = load_manifest(index.to_str().unwrap()).unwrap();
+    let shared = md5_hex(b"shared content");
+    assert_eq!(hashes["a/same.txt"], shared);
+    assert_eq!(hashes["b/copy.txt"], shared);
+    let object = out.join(ObjectStore::object_path(&hashes["b/other.xml"]));
+    assert_eq!(std::fs::read(object).unwrap(), b"<other/>");
+
+    let again = ObjectStore::new();
+    let opts = ExtractOptions { objects: Some(&again), filters: vec!["xml$".to_string()], ..ExtractOptions::default() };
+    run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    assert_eq!(again.counts(), (0, 1));
+    assert_eq!(again.write_index(index.to_str().unwrap()).unwrap(), 3);
+
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 47. snapshot / compare
+// --------------------------------------------------------------------------
+
+fn snapshot_of(packs: &[(&str, &[(&str, &str)])]) -> mabi_pack2::snapshot::Snapshot {
+    use mabi_pack2::snapshot::{EntrySnapshot, PackSnapshot, Snapshot, SNAPSHOT_FORMAT};
+    let mut snap = Snapshot { format: SNAPSHOT_FORMAT, ..Snapshot::default() };
+    for (pack, entries) in packs {
+        let entries = entries.iter()
+            .map(|(name, md5)| (name.to_string(), EntrySnapshot { size: md5.len() as u32, flags: 1, md5: md5.to_string(), error: None }))
+            .collect();
+        snap.packs.insert(pack.to_string(), PackSnapshot { salt: KNOWN_SALT.to_string(), entries });
+    }
+    snap
+}
+
+/// Entries are compared as the client sees them: the last pack holding a name wins,
+/// names match case-insensitively, and a content move between packs is not a change.
+#[test]
+fn test_compare_snapshots() {
+    use mabi_pack2::snapshot::{compare, ChangeKind};
+
+    let old = snapshot_of(&[
+        ("data_00.it", &[("db/a.xml", "aaaa"), ("db/b.xml", "bbbb"), ("db/c.xml", "cccc"), ("db/gone.xml", "gggg")]),
+        ("data_01.it", &[("db/a.xml", "a2a2")]),
+    ]);
+    let new = snapshot_of(&[
+        ("data_00.it", &[("db/a.xml", "a2a2"), ("DB/B.xml", "bbbb"), ("db/c.xml", "cccc")]),
+        ("data_02.it", &[("db/c.xml", "CCCCC"), ("db/new.xml", "nnnn"), ("db/b.xml", "bbbb")]),
+    ]);
+    let cmp = compare(&old, &new);
+    assert_eq!(cmp.packs_added, vec!["data_02.it"]);
+    assert_eq!(cmp.packs_removed, vec!["data_01.it"]);
+    let changes: Vec<(ChangeKind, &str)> = cmp.changes.iter().map(|c| (c.kind, c.name.as_str())).collect();
+    assert_eq!(changes, vec![
+        (ChangeKind::Moved, "db/a.xml"),
+        (ChangeKind::Moved, "db/b.xml"),
+        (ChangeKind::Modified, "db/c.xml"),
+        (ChangeKind::Removed, "db/gone.xml"),
+        (ChangeKind::Added, "db/new.xml"),
+    ]);
+    assert_eq!((cmp.changes[2].old_size, cmp.changes[2].new_size), (Some(4), Some(5)));
+    assert_eq!(cmp.unchanged, 0);
+    assert!(compare(&new, &new).changes.is_empty());
+}
+
+/// A snapshot records every decoded entry with its digest and loads back unchanged.
+#[test]
+fn test_snapshot_roundtrip() {
+    use mabi_pack2::snapshot::{run_snapshot, Snapshot};
+
+    let dir = common::temp_dir_for_test("snapshot_src");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    std::fs::write(dir.join("db/a.xml"), "<a/>").unwrap();
+    std::fs::write(dir.join("db/b.txt"), "plain b").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_snapshot.it");
+    let missing = std::env::temp_dir().join("mabi_test_snapshot_missing.it");
+    let json = std::env::temp_dir().join("mabi_test_snapshot.json");
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let archives = vec![packed.to_string_lossy().into_owned(), missing.to_string_lossy().into_owned()];
+    let snap = run_snapshot(&archives, None, &[KNOWN_SALT.to_string()], &mabi_pack2::common::PackSettings::default(), 2, 2).unwrap();
+    let pack = &snap.packs["mabi_test_snapshot.it"];
+    assert_eq!(pack.salt, KNOWN_SALT);
+    assert_eq!(pack.entries["db/a.xml"].md5, mabi_pack2::verify::md5_hex(b"<a/>"));
+    assert_eq!(pack.entries["db/b.txt"].size, 7);
+    assert!(snap.failed.contains_key("mabi_test_snapshot_missing.it"));
+
+    snap.write_json(json.to_str().unwrap()).unwrap();
+    let loaded = Snapshot::load(json.to_str().unwrap()).unwrap();
+    assert_eq!(loaded.packs["mabi_test_snapshot.it"].entries, pack.entries);
+    assert_eq!(mabi_pack2::snapshot::compare(&snap, &loaded).unchanged, 2);
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+    let _ = std::fs::remove_file(&json);
+}
+
+// --------------------------------------------------------------------------
+// 48. --pack-parallel  (fast)
+// --------------------------------------------------------------------------
+
+/// `batch` has its own `-j`; `--pack-parallel` only exists where it shares `--threads`.
+#[test]
+fn test_batch_has_no_pack_parallel() {
+    let out = std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2"))
+        .args(["batch", "-i", "mabi_test_no_such_input", "-o", "mabi_test_no_such_output", "--pack-parallel", "2"])
+        .output().unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--pack-parallel"), "{}", String::from_utf8_lossy(&out.stderr));
+}
+
+/// Packs run concurrently up to the limit, results keep archive order, and every
+/// pack gets the same entry-worker pool.
+#[test]
+fn test_map_packs_parallelism() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let archives: Vec<String> = (0..6).map(|i| format!("data_{:02}.it", i)).collect();
+    let run = |pack_parallel: usize| {
+        let (in_flight, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let pools = std::sync::Mutex::new(std::collections::HashSet::new());
+        let out = mabi_pack2::common::map_packs(&archives, pack_parallel, 3, |archive, pool| {
+            let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            pools.lock().unwrap().insert(pool as *const rayon::ThreadPool as usize);
+            std::thread::sleep(std::time::Duration::from_millis(30));
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            (archive.clone(), pool.current_num_threads())
+        }).unwrap();
+        (out, peak.into_inner(), pools.into_inner().unwrap().len())
+    };
+
+    let (out, peak, pools) = run(1);
+    assert_eq!(peak, 1);
+    assert_eq!(pools, 1);
+    assert_eq!(out.iter().map(|(a, _)| a.clone()).collect::<Vec<_>>(), archives);
+    assert!(out.iter().all(|(_, n)| *n == 3));
+
+    let (out, peak, pools) = run(3);
+    assert!((2..=3).contains(&peak), "peak {}", peak);
+    assert_eq!(pools, 1);
+    assert_eq!(out.iter().map(|(a, _)| a.clone()).collect::<Vec<_>>(), archives);
+}
+
+// --------------------------------------------------------------------------
+// 49. Entry warnings  (fast)
+// --------------------------------------------------------------------------
+
+/// Empty compressed data, unknown flag bits and names Windows can't create are
+/// reported as structured warnings; ordinary entries and folder markers are not.
+#[test]
+fn test_entry_warnings() {
+    use mabi_pack2::common::{entry_warnings, EntryWarningKind, FileEntry, PackSettings, UnknownFlagsPolicy, FLAG_COMPRESSED};
+
+    let ent = |name: &str, flags: u32, raw_size: u32| FileEntry {
+        name: name.to_string(), checksum: 0, flags, offset: 0, original_size: 10, raw_size, key: [0; 16],
+    };
+    let settings = PackSettings::default();
+    let kinds = |e: FileEntry| e.warnings(&settings).into_iter().map(|w| w.kind).collect::<Vec<_>>();
+
+    assert!(kinds(ent("db\\item.xml", FLAG_COMPRESSED, 8)).is_empty());
+    assert!(kinds(ent("gfx/com0.dds", 0, 10)).is_empty());
+    assert!(kinds(FileEntry { original_size: 0, ..ent("empty\\", FLAG_COMPRESSED, 0) }).is_empty());
+    assert_eq!(kinds(ent("db\\item.xml", FLAG_COMPRESSED, 0)), vec![EntryWarningKind::EmptyCompressed]);
+    assert_eq!(kinds(ent("db\\item.xml", 0x41, 8)), vec![EntryWarningKind::UnknownFlags]);
+    let ignore = PackSettings { unknown_flags: UnknownFlagsPolicy::Ignore, ..PackSettings::default() };
+    assert!(ent("db\\item.xml", 0x41, 8).warnings(&ignore).is_empty());
+    for name in ["db\\a:b.xml", "aux\\x.txt", "db/LPT3.log", "db/name. ", "tab\there"] {
+        assert_eq!(kinds(ent(name, 0, 10)), vec![EntryWarningKind::ReservedName], "{}", name);
+    }
+
+    let all = entry_warnings(&[ent("ok.txt", 0, 10), ent("nul", 0x101, 0)], &settings);
+    let kinds: Vec<EntryWarningKind> = all.iter().map(|w| w.kind).collect();
+    assert_eq!(kinds, vec![EntryWarningKind::EmptyCompressed, EntryWarningKind::UnknownFlags, EntryWarningKind::ReservedName]);
+    assert!(all.iter().all(|w| w.entry == "nul"));
+    assert!(all[1].detail.contains("0x100"));
+}
+
+// --------------------------------------------------------------------------
+// 50. --sanitize-names and the name map  (fast + real round trip)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_sanitize_name_modes() {
+    use mabi_pack2::names::{sanitize_name, SanitizeMode};
+
+    assert_eq!(sanitize_name("db\\a?b.xml", SanitizeMode::None), "db\\a?b.xml");
+    assert_eq!(sanitize_name("db\\a?b.xml", SanitizeMode::Percent), "db\\a%3Fb.xml");
+    assert_eq!(sanitize_name("db\\a?b.xml", SanitizeMode::Replace), "db\\a_b.xml");
+    assert_eq!(sanitize_name("con.txt", SanitizeMode::Percent), "%63on.txt");
+    assert_eq!(sanitize_name("aux/x.txt", SanitizeMode::Replace), "_aux/x.txt");
+    assert_eq!(sanitize_name("dir./50%.txt", SanitizeMode::Percent), "dir%2E/50%25.txt");
+    assert_eq!(sanitize_name("gfx/com0.dds", SanitizeMode::Percent), "gfx/com0.dds");
+    assert!("bogus".parse::<SanitizeMode>().is_err());
+}
+
+/// Extracting with --sanitize-names writes creatable names plus the sidecar map, and
+/// packing that folder restores the original entry names without packing the map.
+#[test]
+fn test_sanitize_names_round_trip() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use mabi_pack2::names::{NameMap, SanitizeMode, NAME_MAP_FILE};
+    use mabi_pack2::reader::PackReader;
+
+    let dir = common::temp_dir_for_test("names_src");
+    let out = common::temp_dir_for_test("names_out");
+    let repacked = common::temp_dir_for_test("names_repacked");
+    for d in [&dir, &out, &repacked] {
+        common::cleanup(d);
+    }
+    std::fs::create_dir_all(dir.join("d")).unwrap();
+    std::fs::create_dir_all(&repacked).unwrap();
+    std::fs::write(dir.join("d/a?b.txt"), "one").unwrap();
+    std::fs::write(dir.join("con.txt"), "two").unwrap();
+    std::fs::write(dir.join("plain.txt"), "three").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_names.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+
+    let opts = ExtractOptions { sanitize_names: SanitizeMode::Percent, ..ExtractOptions::default() };
+    run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    assert_eq!(std::fs::read(out.join("d/a%3Fb.txt")).unwrap(), b"one");
+    assert_eq!(std::fs::read(out.join("%63on.txt")).unwrap(), b"two");
+    let map = NameMap::load(out.to_str().unwrap()).unwrap();
+    assert_eq!(map.names.len(), 2);
+    assert_eq!(map.original("d\\a%3Fb.txt"), Some("d/a?b.txt"));
+    assert_eq!(map.original("plain.txt"), None);
+
+    let again = repacked.join("mabi_test_names.it");
+    mabi_pack2::pack::run_pack(out.to_str().unwrap(), again.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let reader = PackReader::open(again.to_str().unwrap(), None, &salts).unwrap();
+    let mut names: Vec<String> = reader.entries().iter().map(|e| e.name.replace('\\', "/")).collect();
+    names.sort();
+    assert_eq!(names, vec!["con.txt", "d/a?b.txt", "plain.txt"]);
+    assert!(!names.iter().any(|n| n.ends_with(NAME_MAP_FILE)));
+
+    for d in [&dir, &out, &repacked] {
+        common::cleanup(d);
+    }
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 51. Top-level library API
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_library_api() {
+    let dir = common::temp_dir_for_test("api_src");
+    let out = common::temp_dir_for_test("api_out");
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    std::fs::write(dir.join("db/item.xml"), "<items/>").unwrap();
+    std::fs::write(dir.join("readme.txt"), "hello").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_api.it");
+    let path = packed.to_str().unwrap();
+    assert_eq!(mabi_pack2::pack_folder(dir.to_str().unwrap(), path, KNOWN_SALT).unwrap().entries, 2);
+
+    let reader = mabi_pack2::open_pack(path, Some(KNOWN_SALT)).unwrap();
+    let ent = reader.entries().iter().find(|e| e.name.ends_with("item.xml")).unwrap();
+    assert_eq!(reader.read_entry(ent).unwrap(), b"<items/>");
+    assert_eq!(mabi_pack2::list_entries(path, None).unwrap().len(), 2);
+
+    let summary = mabi_pack2::extract_all(path, out.to_str().unwrap(), Some(KNOWN_SALT)).unwrap();
+    assert_eq!(summary.entries, 2);
+    assert_eq!(std::fs::read(out.join("readme.txt")).unwrap(), b"hello");
+
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 52. pack --from-it
+// --------------------------------------------------------------------------
+
+/// Entries of the base pack are carried over under a new salt, minus `remove`
+/// matches and the names the input folder replaces; rewriting the base itself works.
+#[test]
+fn test_pack_from_existing_pack() {
+    use mabi_pack2::pack::{run_pack_with_options, BasePack, PackOptions};
+    use mabi_pack2::reader::PackReader;
+
+    let src = common::temp_dir_for_test("from_it_src");
+    let add = common::temp_dir_for_test("from_it_add");
+    let out = common::temp_dir_for_test("from_it_out");
+    for d in [&src, &add, &out] {
+        common::cleanup(d);
+    }
+    std::fs::create_dir_all(src.join("db")).unwrap();
+    std::fs::create_dir_all(add.join("DB")).unwrap();
+    std::fs::create_dir_all(&out).unwrap();
+    std::fs::write(src.join("db/item.xml"), "old item").unwrap();
+    std::fs::write(src.join("db/skill.xml"), "skill").unwrap();
+    std::fs::write(src.join("readme.txt"), "drop me").unwrap();
+    std::fs::write(add.join("DB/item.xml"), "new item").unwrap();
+    std::fs::write(add.join("extra.txt"), "extra").unwrap();
+    let base = std::env::temp_dir().join("mabi_test_from_it.it");
+    mabi_pack2::pack::run_pack(src.to_str().unwrap(), base.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    const NEW_SALT: &str = "@6QeTuOaDgJlZcBm#9";
+    let dst = out.join("mabi_test_from_it.it");
+    let opts = PackOptions {
+        base: Some(BasePack { path: base.to_str().unwrap().to_string(), key: Some(KNOWN_SALT.to_string()), salts: vec![], remove: vec!["^readme".to_string()] }),
+        ..PackOptions::default()
+    };
+    let summary = run_pack_with_options(add.to_str().unwrap(), dst.to_str().unwrap(), NEW_SALT, &opts, None).unwrap();
+    assert_eq!(summary.entries, 3);
+
+    let salts = vec![NEW_SALT.to_string()];
+    let contents = |path: &Path| {
+        let reader = PackReader::open(path.to_str().unwrap(), None, &salts).unwrap();
+        let mut all: Vec<(String, Vec<u8>)> = reader.entries().iter()
+            .map(|e| (e.name.replace('\\', "/"), reader.read_entry(e).unwrap()))
+            .collect();
+        all.sort();
+        all
+    };
+    assert_eq!(contents(&dst), vec![
+        ("DB/item.xml".to_string(), b"new item".to_vec()),
+        ("db/skill.xml".to_string(), b"skill".to_vec()),
+        ("extra.txt".to_string(), b"extra".to_vec()),
+    ]);
+
+    let in_place = PackOptions {
+        base: Some(BasePack { path: dst.to_str().unwrap().to_string(), key: None, salts: salts.clone(), remove: vec!["extra".to_string()] }),
+        ..PackOptions::default()
+    };
+    run_pack_with_options("", dst.to_str().unwrap(), NEW_SALT, &in_place, None).unwrap();
+    let names: Vec<String> = contents(&dst).into_iter().map(|(n, _)| n).collect();
+    assert_eq!(names, vec!["DB/item.xml", "db/skill.xml"]);
+    assert!(!out.join("mabi_test_from_it.it.tmp").exists());
+
+    for d in [&src, &add, &out] {
+        common::cleanup(d);
+    }
+    let _ = std::fs::remove_file(&base);
+}
+
+// --------------------------------------------------------------------------
+// 53. list --format json
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_list_json_document() {
+    let dir = common::temp_dir_for_test("list_json");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    std::fs::write(dir.join("db/item.xml"), "<items/>").unwrap();
+    std::fs::write(dir.join("readme.txt"), "hello").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_list_json.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let mut out = Vec::new();
+    let salts = vec![KNOWN_SALT.to_string()];
+    let summary = mabi_pack2::list::run_list_json(path, None, &salts, &mabi_pack2::common::PackSettings::default(), vec!["xml$".to_string()], &mut out).unwrap();
+    assert_eq!(summary.entries, 1);
+    let doc: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(doc["header_salt"], KNOWN_SALT);
+    assert_eq!(doc["entries_salt"], KNOWN_SALT);
+    let entries = doc["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["name"].as_str().unwrap().replace('\\', "/"), "db/item.xml");
+    assert_eq!(entries[0]["original_size"], 8);
+
+    let reader = mabi_pack2::reader::PackReader::open(path, None, &salts).unwrap();
+    let ent = reader.entries().iter().find(|e| e.name.ends_with("item.xml")).unwrap();
+    assert_eq!(entries[0]["checksum"], ent.checksum);
+    assert_eq!(entries[0]["raw_size"], ent.raw_size);
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 54. Vfs sources and sinks  (fast + real round trip)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_memory_fs_lists_sorted_files() {
+    use mabi_pack2::vfs::{MemoryFs, Vfs};
+
+    let fs = MemoryFs::from_files([("b/two.txt", "2"), ("a.txt", "1")]);
+    fs.write("c\\three.txt", b"3").unwrap();
+    assert_eq!(fs.list().unwrap(), vec!["a.txt", "b/two.txt", "c/three.txt"]);
+    assert_eq!(fs.read("b/two.txt").unwrap(), b"2");
+    assert!(fs.read("missing").is_err());
+    assert!(mabi_pack2::vfs::is_zip_path("mods/Out.ZIP"));
+    assert!(!mabi_pack2::vfs::is_zip_path("mods/out.it"));
+}
+
+#[test]
+fn test_vfs_memory_and_zip_round_trip() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use mabi_pack2::pack::{run_pack_from_vfs, PackOptions};
+    use mabi_pack2::vfs::{MemoryFs, Vfs, ZipFs};
+
+    let files = [("db/item.xml", b"<items/>".to_vec()), ("readme.txt", vec![9u8; 5000])];
+    let packed = std::env::temp_dir().join("mabi_test_vfs.it");
+    let path = packed.to_str().unwrap();
+    run_pack_from_vfs(&MemoryFs::from_files(files.clone()), path, KNOWN_SALT, &PackOptions::default(), None).unwrap();
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let memory = MemoryFs::new();
+    let opts = ExtractOptions { sink: Some(&memory), ..ExtractOptions::default() };
+    let summary = run_extract_with_options(path, "unused", None, &salts, &opts).unwrap();
+    assert_eq!(summary.entries, 2);
+    assert!(!std::path::Path::new("unused").exists());
+    let extracted: Vec<(String, Vec<u8>)> = memory.into_files().into_iter().collect();
+    let expected: Vec<(String, Vec<u8>)> = files.iter().map(|(n, d)| (n.to_string(), d.clone())).collect();
+    assert_eq!(extracted, expected);
+
+    let zip_path = std::env::temp_dir().join("mabi_test_vfs.zip");
+    let zip = ZipFs::create(&zip_path).unwrap();
+    let opts = ExtractOptions { sink: Some(&zip), ..ExtractOptions::default() };
+    run_extract_with_options(path, zip_path.to_str().unwrap(), None, &salts, &opts).unwrap();
+    zip.finish().unwrap();
+
+    let reopened = ZipFs::open(&zip_path).unwrap();
+    assert_eq!(reopened.list().unwrap(), vec!["db/item.xml", "readme.txt"]);
+    assert_eq!(reopened.read("readme.txt").unwrap(), vec![9u8; 5000]);
+    let repacked = std::env::temp_dir().join("mabi_vfs_repack").join("mabi_test_vfs.it");
+    let _ = std::fs::create_dir_all(repacked.parent().unwrap());
+    run_pack_from_vfs(&reopened, repacked.to_str().unwrap(), KNOWN_SALT, &PackOptions::default(), None).unwrap();
+    assert_eq!(std::fs::read(&repacked).unwrap(), std::fs::read(&packed).unwrap());
+
+    let _ = std::fs::remove_file(&packed);
+    let _ = std::fs::remove_file(&zip_path);
+    common::cleanup(repacked.parent().unwrap());
+}
+
+/// A member whose recorded size disagrees with its data is refused, whichever way the
+/// size is off, and inflating stops at the recorded size.
+#[test]
+fn test_zip_member_size_is_checked() {
+    use mabi_pack2::vfs::{Vfs, ZipFs};
+
+    let zip_path = std::env::temp_dir().join("mabi_test_zip_sizes.zip");
+    let zip = ZipFs::create(&zip_path).unwrap();
+    zip.write("zeros.bin", &[0u8; 100_000]).unwrap();
+    zip.finish().unwrap();
+    let original = std::fs::read(&zip_path).unwrap();
+    let central = original.windows(4).position(|w| w == b"PK\x01\x02").unwrap();
+
+    for size in [10u32, u32::MAX] {
+        let mut forged = original.clone();
+        forged[central + 24..central + 28].copy_from_slice(&size.to_le_bytes());
+        std::fs::write(&zip_path, &forged).unwrap();
+        let err = ZipFs::open(&zip_path).unwrap().read("zeros.bin").unwrap_err();
+        assert!(format!("{:#}", err).contains("size mismatch"), "{:#}", err);
+    }
+
+    let _ = std::fs::remove_file(&zip_path);
+}
+
+// --------------------------------------------------------------------------
+// 55. Raw block ranges  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Block ranges describe the stored bytes exactly: decrypting `encrypted()` with
+/// `file_key` and inflating gives the file, and the bytes splice into another pack.
+#[test]
+fn test_block_ranges_decode_and_splice() {
+    use mabi_pack2::common::{FileEntry, FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED, FLAG_HEAD_ENCRYPTED};
+    use mabi_pack2::encryption::{snow2_decrypt_mode, Snow2Mode};
+    use mabi_pack2::pack::{encode_entry_data, PackWriter};
+    use mabi_pack2::reader::PackReader;
+    use std::io::Read;
+
+    let files: Vec<(String, u32, Vec<u8>)> = vec![
+        ("db\\big.xml".to_string(), FLAG_COMPRESSED | FLAG_HEAD_ENCRYPTED, (0..20000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect()),
+        ("small.txt".to_string(), FLAG_ALL_ENCRYPTED, b"small file".to_vec()),
+        ("plain.bin".to_string(), 0, vec![5u8; 1500]),
+    ];
+    let write_pack = |path: &std::path::Path, raw: &dyn Fn(&FileEntry, &[u8]) -> Vec<u8>| {
+        let names: Vec<String> = files.iter().map(|f| f.0.clone()).collect();
+        let mut writer = PackWriter::create(path.to_str().unwrap(), "mabi_test_blocks.it", KNOWN_SALT, KNOWN_SALT, 0, Snow2Mode::Sub, &names).unwrap();
+        for (i, (name, flags, plain)) in files.iter().enumerate() {
+            let ent = FileEntry { name: name.clone(), checksum: 0, flags: *flags, offset: 0, original_size: plain.len() as u32, raw_size: 0, key: [i as u8 + 1; 16] };
+            let content = raw(&ent, plain);
+            writer.add_raw(ent, &content).unwrap();
+        }
+        writer.finish().unwrap();
+    };
+
+    let packed = std::env::temp_dir().join("mabi_test_blocks.it");
+    write_pack(&packed, &|ent, plain| encode_entry_data(&ent.name, &ent.key, plain, ent.flags, 0, Snow2Mode::Sub).unwrap());
+    let salts = vec![KNOWN_SALT.to_string()];
+    let reader = PackReader::open(packed.to_str().unwrap(), None, &salts).unwrap();
+    let ranges = reader.block_ranges().unwrap();
+    assert_eq!(ranges.len(), 3);
+    assert_eq!(ranges[0].encrypted_len, 1024);
+    assert_eq!(ranges[1].encrypted_len, ranges[1].len);
+    assert_eq!(ranges[2].encrypted_len, 0);
+    assert!(ranges.windows(2).all(|w| w[0].start + w[0].blocks as u64 * 1024 <= w[1].start));
+
+    let bytes = std::fs::read(&packed).unwrap();
+    for (range, (name, _, plain)) in ranges.iter().zip(&files) {
+        assert_eq!((range.start - reader.content_offset) % 1024, 0);
+        let ent = reader.find(name).unwrap();
+        assert_eq!(&bytes[range.start as usize..range.end() as usize], reader.read_raw(ent).unwrap());
+        let mut data = bytes[range.start as usize..range.end() as usize].to_vec();
+        let enc = range.encrypted();
+        snow2_decrypt_mode(&range.file_key, range.iv0, range.mode, &mut data[..(enc.end - enc.start) as usize]);
+        if range.flags & FLAG_COMPRESSED != 0 {
+            let mut out = Vec::new();
+            flate2::read::ZlibDecoder::new(&data[..]).read_to_end(&mut out).unwrap();
+            data = out;
+        }
+        assert_eq!(&data, plain, "{}", name);
+        assert!(range.portable_to(0, Snow2Mode::Sub));
+        assert_eq!(range.portable_to(1, Snow2Mode::Sub), range.encrypted_len == 0);
+    }
+
+    // Splice the stored bytes into a second pack unchanged.
+    let spliced = std::env::temp_dir().join("mabi_blocks_splice").join("mabi_test_blocks.it");
+    let _ = std::fs::create_dir_all(spliced.parent().unwrap());
+    let by_name = |ent: &FileEntry| ranges.iter().find(|r| r.name == ent.name).unwrap().clone();
+    write_pack(&spliced, &|ent, _| {
+        let r = by_name(ent);
+        bytes[r.start as usize..r.end() as usize].to_vec()
+    });
+    let copy = PackReader::open(spliced.to_str().unwrap(), None, &salts).unwrap();
+    for (name, _, plain) in &files {
+        assert_eq!(&copy.read_entry(copy.find(name).unwrap()).unwrap(), plain);
+    }
+
+    let _ = std::fs::remove_file(&packed);
+    common::cleanup(spliced.parent().unwrap());
+}
+
+// --------------------------------------------------------------------------
+// 56. Content offset detection  (fast + real shifted pack)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_content_offset_candidates_order() {
+    use mabi_pack2::common::content_offset_candidates;
+    assert_eq!(content_offset_candidates(0x1400), vec![0x1400, 0x1800, 0x1000, 0x1200, 0x2000, 0x1C00]);
+    assert_eq!(content_offset_candidates(0x2000)[..3], [0x2000, 0x2400, 0x1C00]);
+    assert_eq!(content_offset_candidates(0), vec![0, 1024, 2048]);
+}
+
+/// A pack whose entry data starts one block later than the table end suggests still
+/// opens: the first compressed entry is probed at the candidate bases.
+#[test]
+fn test_shifted_content_offset_is_detected() {
+    use mabi_pack2::reader::PackReader;
+
+    let dir = common::temp_dir_for_test("shifted_content");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    std::fs::write(dir.join("db/item.xml"), "<items>".repeat(300)).unwrap();
+    std::fs::write(dir.join("readme.txt"), "hello").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_shifted.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let assumed = PackReader::open(path, None, &salts).unwrap().content_offset;
+    let mut data = std::fs::read(&packed).unwrap();
+    data.splice(assumed as usize..assumed as usize, vec![0u8; 1024]);
+    std::fs::write(&packed, &data).unwrap();
+
+    let reader = PackReader::open(path, None, &salts).unwrap();
+    assert_eq!(reader.content_offset, assumed + 1024);
+    // The single-salt path finds the same base as the reader's search.
+    let settings = mabi_pack2::common::PackSettings::default();
+    let (_, _, _, _, content_offset) = mabi_pack2::common::read_meta("mabi_test_shifted.it", KNOWN_SALT, &mut std::io::Cursor::new(&data), reader.header_offset, &settings).unwrap();
+    assert_eq!(content_offset, assumed + 1024);
+    for (name, expected) in [("db\\item.xml", "<items>".repeat(300)), ("readme.txt", "hello".to_string())] {
+        assert_eq!(reader.read_entry(reader.find(name).unwrap()).unwrap(), expected.as_bytes());
+    }
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 57. Locked output files  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// An output file another process holds (here a running executable, which Linux
+/// refuses to overwrite) is reported as locked, and `retry_locked` writes it once
+/// the process has exited.
+#[cfg(unix)]
+#[test]
+fn test_extract_locked_output_retry() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let src = common::temp_dir_for_test("locked_src");
+    let out = common::temp_dir_for_test("locked_out");
+    common::cleanup(&src);
+    common::cleanup(&out);
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::create_dir_all(&out).unwrap();
+    std::fs::write(src.join("tool"), b"new tool").unwrap();
+    std::fs::write(src.join("other.txt"), b"other").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_locked.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(src.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let busy = out.join("tool");
+    let run_busy = |secs: &str| {
+        std::fs::copy("/bin/sleep", &busy).unwrap();
+        std::fs::set_permissions(&busy, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::process::Command::new(&busy).arg(secs).spawn().unwrap()
+    };
+    let salts = vec![KNOWN_SALT.to_string()];
+
+    let mut child = run_busy("5");
+    let summary = run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &ExtractOptions::default()).unwrap();
+    assert_eq!(summary.locked, vec!["tool"]);
+    assert_eq!((summary.entries, summary.failed), (1, 1));
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let mut child = run_busy("1");
+    let opts = ExtractOptions { retry_locked: 5, ..ExtractOptions::default() };
+    let summary = run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    child.wait().unwrap();
+    assert!(summary.locked.is_empty());
+    assert_eq!((summary.entries, summary.failed), (2, 0));
+    assert_eq!(std::fs::read(&busy).unwrap(), b"new tool");
+
+    common::cleanup(&src);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 58. Size-bounded inflation  (in-memory, fast)
+// --------------------------------------------------------------------------
+
+/// `inflate_entry` accepts exactly the recorded size and rejects streams that are
+/// larger (stopping at the limit) or smaller.
+#[test]
+fn test_inflate_entry_enforces_size() {
+    use mabi_pack2::extract::inflate_entry;
+    use std::io::Write;
+
+    let plain = vec![b'a'; 100_000];
+    let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    enc.write_all(&plain).unwrap();
+    let packed = enc.finish().unwrap();
+
+    assert_eq!(inflate_entry(&packed, 100_000).unwrap(), plain);
+    let err = inflate_entry(&packed, 1000).unwrap_err().to_string();
+    assert!(err.contains("past its recorded size of 1000"), "{}", err);
+    let err = inflate_entry(&packed, 200_000).unwrap_err().to_string();
+    assert!(err.contains("inflated to 100000 bytes, expected 200000"), "{}", err);
+    assert!(inflate_entry(&packed[..packed.len() / 2], 100_000).is_err());
+    assert!(inflate_entry(b"not zlib", 8).is_err());
+}
+
+// --------------------------------------------------------------------------
+// 59. Data-order extraction  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Extracting in data order and in table order writes the same files, and progress
+/// counts the selected entries in the order they are written.
+#[test]
+fn test_extract_data_order_matches_table_order() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use std::sync::{Arc, Mutex};
+
+    let src = common::temp_dir_for_test("order_src");
+    common::cleanup(&src);
+    std::fs::create_dir_all(src.join("sub")).unwrap();
+    for (name, body) in [("b.txt", "bee"), ("a.dat", "ay"), ("sub/c.xml", "<c/>"), ("z.bin", "zed")] {
+        std::fs::write(src.join(name), body).unwrap();
+    }
+    let packed = std::env::temp_dir().join("mabi_test_order.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(src.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+
+    for table_order in [false, true] {
+        let out = common::temp_dir_for_test(if table_order { "order_table" } else { "order_data" });
+        common::cleanup(&out);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let cb = move |i: usize, n: usize, _: &str| log.lock().unwrap().push((i, n));
+        let opts = ExtractOptions { table_order, progress_cb: Some(&cb), ..ExtractOptions::default() };
+        let summary = run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &opts).unwrap();
+        assert_eq!((summary.entries, summary.failed), (4, 0), "{:?}", summary.warnings);
+        assert_eq!(*seen.lock().unwrap(), vec![(0, 4), (1, 4), (2, 4), (3, 4), (4, 4)]);
+        for (name, body) in [("b.txt", "bee"), ("a.dat", "ay"), ("sub/c.xml", "<c/>"), ("z.bin", "zed")] {
+            assert_eq!(std::fs::read_to_string(out.join(name)).unwrap(), body);
+        }
+        common::cleanup(&out);
+    }
+
+    common::cleanup(&src);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 60. Incremental update  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// `run_update` re-encodes changed files, appends new ones, drops missing ones only
+/// with `prune`, and leaves an up-to-date pack byte for byte as it was.
+#[test]
+fn test_update_rewrites_only_changes() {
+    use mabi_pack2::edit::{run_update, UpdateOptions};
+    use mabi_pack2::reader::PackReader;
+
+    let src = common::temp_dir_for_test("update_src");
+    common::cleanup(&src);
+    std::fs::create_dir_all(src.join("sub")).unwrap();
+    std::fs::write(src.join("a.txt"), b"alpha").unwrap();
+    std::fs::write(src.join("b.dat"), b"bravo").unwrap();
+    std::fs::write(src.join("sub").join("c.xml"), b"<c/>").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_update.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(src.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+    let folder = src.to_str().unwrap();
+
+    let before = std::fs::read(&packed).unwrap();
+    let summary = run_update(folder, path, None, &salts, &UpdateOptions::default()).unwrap();
+    assert!(summary.is_empty());
+    assert_eq!(summary.unchanged, 3);
+    assert_eq!(std::fs::read(&packed).unwrap(), before);
+
+    std::fs::write(src.join("a.txt"), b"alpha, edited").unwrap();
+    std::fs::write(src.join("d.txt"), b"delta").unwrap();
+    std::fs::remove_file(src.join("b.dat")).unwrap();
+    let dry = UpdateOptions { dry_run: true, ..UpdateOptions::default() };
+    let summary = run_update(folder, path, None, &salts, &dry).unwrap();
+    assert_eq!((summary.changed.len(), summary.added.len(), summary.removed.len()), (1, 1, 0));
+    assert_eq!(std::fs::read(&packed).unwrap(), before);
+
+    let prune = UpdateOptions { prune: true, ..UpdateOptions::default() };
+    let summary = run_update(folder, path, None, &salts, &prune).unwrap();
+    assert_eq!(summary.changed, vec!["a.txt"]);
+    assert_eq!(summary.added, vec!["d.txt"]);
+    assert_eq!(summary.removed, vec!["b.dat"]);
+    assert_eq!(summary.unchanged, 1);
+
+    let reader = PackReader::open(path, None, &salts).unwrap();
+    assert_eq!(reader.entries().len(), 3);
+    assert_eq!(reader.read_entry(reader.find("a.txt").unwrap()).unwrap(), b"alpha, edited");
+    assert_eq!(reader.read_entry(reader.find("d.txt").unwrap()).unwrap(), b"delta");
+    assert!(reader.find("b.dat").is_none());
+    drop(reader);
+    assert!(run_update(folder, path, None, &salts, &prune).unwrap().is_empty());
+
+    common::cleanup(&src);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 61. Saved entry indexes  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// An index saved by one extraction opens the same pack without a key search, is
+/// ignored once the pack changes, and is refused for a pack with another name.
+#[test]
+fn test_extract_save_and_use_index() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use mabi_pack2::index;
+
+    let src = common::temp_dir_for_test("index_src");
+    let out = common::temp_dir_for_test("index_out");
+    common::cleanup(&src);
+    common::cleanup(&out);
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::write(src.join("a.txt"), b"alpha").unwrap();
+    std::fs::write(src.join("b.dat"), b"bravo").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_index.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(src.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let idx = std::env::temp_dir().join("mabi_test_index.bin");
+    let idx_path = idx.to_str().unwrap().to_string();
+    let salts = vec![KNOWN_SALT.to_string()];
+
+    let save = ExtractOptions { save_index: Some(idx_path.clone()), ..ExtractOptions::default() };
+    run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &save).unwrap();
+    let data = std::fs::read(&packed).unwrap();
+    let hit = index::load(&idx_path, &data, path).unwrap().expect("index matches the pack");
+    assert_eq!(hit.header_salt, KNOWN_SALT);
+    assert_eq!(hit.entries.len(), 2);
+    assert!(index::load(&idx_path, &data, "other.it").is_err());
+
+    // No salts at all: only the index can open the pack.
+    common::cleanup(&out);
+    let use_idx = ExtractOptions { use_index: Some(idx_path.clone()), filters: vec!["a\\.txt".to_string()], ..ExtractOptions::default() };
+    let summary = run_extract_with_options(path, out.to_str().unwrap(), None, &[], &use_idx).unwrap();
+    assert_eq!(summary.entries, 1);
+    assert_eq!(std::fs::read(out.join("a.txt")).unwrap(), b"alpha");
+
+    std::fs::write(src.join("a.txt"), b"alpha, edited").unwrap();
+    mabi_pack2::pack::run_pack(src.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    assert!(index::load(&idx_path, &std::fs::read(&packed).unwrap(), path).unwrap().is_none());
+
+    common::cleanup(&src);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+    let _ = std::fs::remove_file(&idx);
+}
+
+// --------------------------------------------------------------------------
+// 62. Exclude patterns  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// `PackOptions::exclude` leaves input files out and `ExtractOptions::exclude` skips
+/// entries a filter (or no filter) would select; regexes and `glob:` patterns both work.
+#[test]
+fn test_pack_and_extract_exclude() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use mabi_pack2::pack::{run_pack_with_options, PackOptions};
+
+    let src = common::temp_dir_for_test("exclude_src");
+    let out = common::temp_dir_for_test("exclude_out");
+    common::cleanup(&src);
+    common::cleanup(&out);
+    for name in [".git/HEAD", "Thumbs.db", "db/item.xml", "db/item.xml.bak", "sound/a.wav", "sound/sub/b.wav", "movie/m.wmv"] {
+        let path = src.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, name.as_bytes()).unwrap();
+    }
+    let packed = std::env::temp_dir().join("mabi_test_exclude.it");
+    let path = packed.to_str().unwrap();
+    let opts = PackOptions {
+        exclude: vec!["glob:.git/".to_string(), "glob:Thumbs.db".to_string(), r"\.bak$".to_string()],
+        ..PackOptions::default()
+    };
+    let summary = run_pack_with_options(src.to_str().unwrap(), path, KNOWN_SALT, &opts, None).unwrap();
+    assert_eq!(summary.entries, 4);
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let opts = ExtractOptions { exclude: vec!["glob:sound/".to_string(), r"^movie[/\\]".to_string()], ..ExtractOptions::default() };
+    let summary = run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    assert_eq!(summary.entries, 1);
+    assert!(out.join("db").join("item.xml").exists());
+    assert!(!out.join("sound").exists() && !out.join("movie").exists());
+
+    common::cleanup(&out);
+    let opts = ExtractOptions { filters: vec!["wav$".to_string()], exclude: vec!["glob:sound/sub/".to_string()], ..ExtractOptions::default() };
+    let summary = run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    assert_eq!(summary.entries, 1);
+    assert!(out.join("sound").join("a.wav").exists());
+
+    common::cleanup(&src);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 63. Pack write locks  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// A held lock makes other writers fail with the holder's PID, is removed on drop,
+/// and a lock left by a process that no longer exists is taken over.
+#[test]
+fn test_pack_lock_blocks_concurrent_writers() {
+    use mabi_pack2::pack_lock::{PackLock, LOCK_SUFFIX};
+
+    let src = common::temp_dir_for_test("lock_src");
+    common::cleanup(&src);
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::write(src.join("a.txt"), b"alpha").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_lock.it");
+    let path = packed.to_str().unwrap();
+    let lock_path = format!("{}{}", path, LOCK_SUFFIX);
+    let _ = std::fs::remove_file(&lock_path);
+
+    let lock = PackLock::acquire(path, "update").unwrap();
+    assert!(std::path::Path::new(&lock_path).exists());
+    let err = mabi_pack2::pack::run_pack(src.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap_err().to_string();
+    assert!(err.contains(&format!("being written by PID {} (update", std::process::id())), "{}", err);
+    assert!(!packed.exists());
+    drop(lock);
+    assert!(!std::path::Path::new(&lock_path).exists());
+
+    // Not a running process on any platform the tests run on.
+    std::fs::write(&lock_path, br#"{"pid":4294967295,"operation":"pack","since":"2020-01-01T00:00:00+00:00"}"#).unwrap();
+    mabi_pack2::pack::run_pack(src.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    assert!(!std::path::Path::new(&lock_path).exists());
+
+    common::cleanup(&src);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 64. Entry hooks  (needs temp filesystem access; scripts need `--features scripting`)
+// --------------------------------------------------------------------------
+
+/// Upper-cases `.txt` payloads and moves `old\` entries to `new\`.
+#[derive(Debug)]
+struct ShoutHook;
+
+impl mabi_pack2::hook::EntryHook for ShoutHook {
+    fn rename(&self, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(name.strip_prefix("old").map(|rest| format!("new{}", rest)))
+    }
+
+    fn transform(&self, name: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Ok(if name.ends_with(".txt") { data.to_ascii_uppercase() } else { data })
+    }
+}
+
+/// A hook renames entries and rewrites payloads on the way into a pack and out of it.
+#[test]
+fn test_entry_hook_on_pack_and_extract() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use mabi_pack2::pack::{run_pack_with_options, PackOptions};
+    use mabi_pack2::reader::PackReader;
+
+    let src = common::temp_dir_for_test("hook_src");
+    let out = common::temp_dir_for_test("hook_out");
+    common::cleanup(&src);
+    common::cleanup(&out);
+    std::fs::create_dir_all(src.join("old")).unwrap();
+    std::fs::write(src.join("old").join("a.txt"), b"hello").unwrap();
+    std::fs::write(src.join("b.dat"), b"bytes").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_hook.it");
+    let path = packed.to_str().unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+
+    let opts = PackOptions { hook: Some(std::sync::Arc::new(ShoutHook)), ..PackOptions::default() };
+    run_pack_with_options(src.to_str().unwrap(), path, KNOWN_SALT, &opts, None).unwrap();
+    let reader = PackReader::open(path, None, &salts).unwrap();
+    let moved = reader.entries().iter().find(|e| e.name.starts_with("new")).expect("renamed entry");
+    assert_eq!(reader.read_entry(moved).unwrap(), b"HELLO");
+    assert_eq!(reader.read_entry(reader.find("b.dat").unwrap()).unwrap(), b"bytes");
+    drop(reader);
+
+    // Pack the original names again, then apply the hook while extracting.
+    run_pack_with_options(src.to_str().unwrap(), path, KNOWN_SALT, &PackOptions::default(), None).unwrap();
+    let opts = ExtractOptions { hook: Some(&ShoutHook), ..ExtractOptions::default() };
+    run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    assert_eq!(std::fs::read(out.join("new").join("a.txt")).unwrap(), b"HELLO");
+    assert!(!out.join("old").exists());
+
+    common::cleanup(&src);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+}
+
+/// `ScriptHook` calls the script's functions; `()` keeps the name or payload.
+#[cfg(feature = "scripting")]
+#[test]
+fn test_script_hook() {
+    use mabi_pack2::hook::{EntryHook, HookDirection, ScriptHook};
+
+    let script = std::env::temp_dir().join(format!("mabi_test_hook_{}.rhai", std::process::id()));
+    std::fs::write(&script, r#"
+        fn rename(name) { if name.ends_with(".bak") { () } else { DIRECTION + "/" + name } }
+        fn transform(name, data) { if name.ends_with(".txt") { data.as_string().to_upper() } }
+    "#).unwrap();
+    let hook = ScriptHook::load(script.to_str().unwrap(), HookDirection::Extract).unwrap();
+    assert_eq!(hook.rename("db\\a.txt").unwrap().as_deref(), Some("extract/db\\a.txt"));
+    assert_eq!(hook.rename("a.bak").unwrap(), None);
+    assert_eq!(hook.transform("a.txt", b"abc".to_vec()).unwrap(), b"ABC");
+    assert_eq!(hook.transform("a.dat", vec![0, 1]).unwrap(), vec![0, 1]);
+
+    std::fs::write(&script, "fn unrelated() {}").unwrap();
+    assert!(ScriptHook::load(script.to_str().unwrap(), HookDirection::Pack).is_err());
+    let _ = std::fs::remove_file(&script);
+}
+
+// --------------------------------------------------------------------------
+// 65. list --format csv/tsv  (flag names fast; listing needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_flag_names() {
+    use mabi_pack2::common::flag_names;
+    assert_eq!(flag_names(0), "");
+    assert_eq!(flag_names(1), "compressed");
+    assert_eq!(flag_names(5), "compressed|head-encrypted");
+    assert_eq!(flag_names(2 | 0x40), "all-encrypted|0x40");
+}
+
+#[test]
+fn test_list_csv_and_tsv() {
+    let dir = common::temp_dir_for_test("list_csv");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a,b.txt"), "hello").unwrap();
+    std::fs::write(dir.join("item.xml"), "<items/>").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_list_csv.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+    let reader = mabi_pack2::reader::PackReader::open(path, None, &salts).unwrap();
+    let ent = reader.entries().iter().find(|e| e.name == "item.xml").unwrap();
+
+    let mut out = Vec::new();
+    let summary = mabi_pack2::list::run_list_table(path, None, &salts, &mabi_pack2::common::PackSettings::default(), vec![], ',', &mut out).unwrap();
+    assert_eq!(summary.entries, 2);
+    let csv = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "name,flags,offset,original_size,raw_size,key");
+    assert!(lines.iter().any(|l| l.starts_with("\"a,b.txt\",")), "{}", csv);
+    let key: String = ent.key.iter().map(|b| format!("{:02x}", b)).collect();
+    let expected = format!("item.xml,{},{},{},{},{}", mabi_pack2::common::flag_names(ent.flags),
+        reader.content_offset + ent.offset as u64 * 1024, ent.original_size, ent.raw_size, key);
+    assert!(lines.contains(&expected.as_str()), "{}", csv);
+
+    let mut out = Vec::new();
+    mabi_pack2::list::run_list_table(path, None, &salts, &mabi_pack2::common::PackSettings::default(), vec!["xml$".to_string()], '\t', &mut out).unwrap();
+    let tsv = String::from_utf8(out).unwrap();
+    assert_eq!(tsv, format!("name\tflags\toffset\toriginal_size\traw_size\tkey\n{}\n", expected.replace(',', "\t")));
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 66. Output path containment  (lexical checks fast; extraction needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_unsafe_path_reason_lexical() {
+    use mabi_pack2::common::{unsafe_path_reason, PackSettings};
+    let root = "mabi_test_no_such_output_folder";
+    let settings = PackSettings::default();
+    assert!(unsafe_path_reason(root, "..\\evil.txt", &settings).is_some());
+    assert!(unsafe_path_reason(root, "data/../../evil.txt", &settings).is_some());
+    assert!(unsafe_path_reason(root, "/etc/evil.txt", &settings).is_some());
+    assert!(unsafe_path_reason(root, "\\Windows\\evil.dll", &settings).is_some());
+    assert!(unsafe_path_reason(root, "C:\\evil.txt", &settings).is_some());
+    assert!(unsafe_path_reason(root, "data\\..\\db\\item.xml", &settings).is_none());
+    assert!(unsafe_path_reason(root, "db/item..xml", &settings).is_none());
+    let allowed = PackSettings { allow_unsafe_paths: true, ..PackSettings::default() };
+    assert!(unsafe_path_reason(root, "..\\evil.txt", &allowed).is_none());
+}
+
+/// Moves entries out of the output folder by name.
+#[derive(Debug)]
+struct EscapeHook;
+
+impl mabi_pack2::hook::EntryHook for EscapeHook {
+    fn rename(&self, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(name.strip_prefix("evil").map(|rest| format!("..\\mabi_test_escaped{}", rest)))
+    }
+
+    fn transform(&self, _name: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Ok(data)
+    }
+}
+
+/// Entries named `..\...` and paths through a link to elsewhere are rejected and reported.
+#[test]
+fn test_extract_rejects_escaping_paths() {
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+    use mabi_pack2::pack::{run_pack_with_options, PackOptions};
+
+    let src = common::temp_dir_for_test("zipslip_src");
+    let out = common::temp_dir_for_test("zipslip_out");
+    common::cleanup(&src);
+    common::cleanup(&out);
+    std::fs::create_dir_all(src.join("linked")).unwrap();
+    std::fs::write(src.join("evil.txt"), b"gotcha").unwrap();
+    std::fs::write(src.join("linked").join("x.txt"), b"through a link").unwrap();
+    std::fs::write(src.join("fine.txt"), b"ok").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_zipslip.it");
+    let path = packed.to_str().unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+    let opts = PackOptions { hook: Some(std::sync::Arc::new(EscapeHook)), ..PackOptions::default() };
+    run_pack_with_options(src.to_str().unwrap(), path, KNOWN_SALT, &opts, None).unwrap();
+
+    let escaped = out.parent().unwrap().join("mabi_test_escaped.txt");
+    let _ = std::fs::remove_file(&escaped);
+    std::fs::create_dir_all(&out).unwrap();
+    let elsewhere = common::temp_dir_for_test("zipslip_elsewhere");
+    common::cleanup(&elsewhere);
+    std::fs::create_dir_all(&elsewhere).unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&elsewhere, out.join("linked")).unwrap();
+
+    let summary = run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &ExtractOptions::default()).unwrap();
+    assert_eq!(std::fs::read(out.join("fine.txt")).unwrap(), b"ok");
+    assert!(!escaped.exists());
+    assert!(summary.rejected.iter().any(|n| n.contains("mabi_test_escaped")), "{:?}", summary.rejected);
+    #[cfg(unix)]
+    {
+        assert!(!elsewhere.join("x.txt").exists());
+        assert_eq!(summary.rejected.len(), 2, "{:?}", summary.rejected);
+    }
+
+    common::cleanup(&src);
+    common::cleanup(&out);
+    common::cleanup(&elsewhere);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 67. --untrusted  (runs the CLI; needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// A pack with an unknown flag bit lists normally but is refused with --untrusted, and
+/// --untrusted rules out --allow-unsafe-paths.
+#[test]
+fn test_untrusted_refuses_unknown_flags() {
+    let dir = common::temp_dir_for_test("untrusted");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_untrusted.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+    mabi_pack2::edit::run_set_flags(path, None, None, &salts, &mabi_pack2::common::PackSettings::default(), vec![], 0x40, 0).unwrap();
+
+    let list = |extra: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2"))
+            .args(extra)
+            .args(["--no-key-cache", "list", "-i", path, "-k", KNOWN_SALT])
+            .output()
+            .unwrap()
+    };
+    assert!(list(&[]).status.success());
+    let refused = list(&["--untrusted"]);
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("unknown flag bits"), "{}", String::from_utf8_lossy(&refused.stderr));
+    assert!(!list(&["--untrusted", "--allow-unsafe-paths"]).status.success());
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 68. Panic isolation  (in-memory, fast)
+// --------------------------------------------------------------------------
+
+/// A panic inside the closure comes back as an error naming the entry; results pass through.
+#[test]
+fn test_catch_panic_isolates_entry() {
+    use mabi_pack2::common::catch_panic;
+    let err = catch_panic("db/broken.xml", || -> anyhow::Result<()> { panic!("attempt to add with overflow") }).unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains("db/broken.xml") && message.contains("overflow"), "{}", message);
+    let err = catch_panic("db/index.xml", || -> anyhow::Result<()> { panic!("index {} out of range", 9) }).unwrap_err();
+    assert!(err.to_string().contains("index 9 out of range"));
+    assert_eq!(catch_panic("ok.txt", || Ok(7)).unwrap(), 7);
+    assert!(catch_panic("bad.txt", || -> anyhow::Result<()> { Err(anyhow::anyhow!("plain error")) }).unwrap_err().to_string() == "plain error");
+}
+
+// --------------------------------------------------------------------------
+// 69. Keystream export  (in-memory keystream fast; pack round trip needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Any window of the keystream matches the same bytes of a longer export.
+#[test]
+fn test_keystream_skip_matches_full_stream() {
+    use mabi_pack2::encryption::{write_keystream, Snow2Mode};
+    let key = [7u8; 16];
+    let mut full = Vec::new();
+    write_keystream(&key, 0, Snow2Mode::Sub, 0, 300, &mut full).unwrap();
+    assert_eq!(full.len(), 300);
+    for (skip, len) in [(0u64, 5u64), (3, 64), (64, 100), (130, 170)] {
+        let mut part = Vec::new();
+        write_keystream(&key, 0, Snow2Mode::Sub, skip, len, &mut part).unwrap();
+        assert_eq!(part, full[skip as usize..(skip + len) as usize]);
+    }
+    let mut other = Vec::new();
+    write_keystream(&key, 1, Snow2Mode::Sub, 0, 300, &mut other).unwrap();
+    assert_ne!(other, full);
+}
+
+/// Subtracting the exported keystream word by word from a fully encrypted, uncompressed
+/// entry's stored bytes gives its content.
+#[test]
+fn test_keystream_decrypts_entry() {
+    use mabi_pack2::common::{FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED};
+    use mabi_pack2::encryption::write_keystream;
+
+    let dir = common::temp_dir_for_test("keystream");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let content: Vec<u8> = (0..3001u32).map(|i| (i * 13 % 256) as u8).collect();
+    std::fs::write(dir.join("data.bin"), &content).unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_keystream.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+    mabi_pack2::edit::run_set_flags(path, None, None, &salts, &mabi_pack2::common::PackSettings::default(), vec![], FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED).unwrap();
+
+    let reader = mabi_pack2::reader::PackReader::open(path, None, &salts).unwrap();
+    let range = reader.block_range(reader.find("data.bin").unwrap()).unwrap();
+    assert_eq!(range.encrypted_len, content.len() as u64);
+    let stored = std::fs::read(path).unwrap()[range.start as usize..range.end() as usize].to_vec();
+    let mut ks = Vec::new();
+    write_keystream(&range.file_key, range.iv0, range.mode, 0, range.encrypted_len.next_multiple_of(4), &mut ks).unwrap();
+    let mut padded = stored.clone();
+    padded.resize(ks.len(), 0);
+    let mut plain: Vec<u8> = padded.chunks(4).zip(ks.chunks(4))
+        .flat_map(|(s, k)| u32::from_le_bytes(s.try_into().unwrap()).wrapping_sub(u32::from_le_bytes(k.try_into().unwrap())).to_le_bytes())
+        .collect();
+    plain.truncate(stored.len());
+    assert_eq!(plain, content);
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 70. describe-format  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// The description tiles the whole file: structures are contiguous from byte 0 to the
+/// footer, every entry record matches the parsed entries and each field sits inside its structure.
+#[test]
+fn test_describe_format_covers_pack() {
+    use mabi_pack2::describe::{describe_pack, write_markdown};
+
+    let dir = common::temp_dir_for_test("describe_format");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("a.txt"), "hello describe".repeat(100)).unwrap();
+    std::fs::write(dir.join("sub").join("b.bin"), vec![3u8; 2500]).unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_describe.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let reader = mabi_pack2::reader::PackReader::open(path, None, &[KNOWN_SALT.to_string()]).unwrap();
+    let desc = describe_pack(&reader).unwrap();
+    assert_eq!(desc.file_len, reader.file_len());
+    let mut next = 0;
+    for s in &desc.structures {
+        assert_eq!(s.offset, next, "structure '{}' ({}) does not start where the previous one ended", s.label, s.kind);
+        for f in &s.fields {
+            assert!(f.offset >= s.offset && f.offset + f.size <= s.offset + s.size, "field {} outside '{}'", f.name, s.label);
+        }
+        next = s.offset + s.size;
+    }
+    assert_eq!(next, desc.file_len);
+
+    let header = desc.structures.iter().find(|s| s.kind == "header").unwrap();
+    assert_eq!(header.offset, reader.header_offset);
+    assert!(header.fields[0].value.ends_with("(ok)"));
+    let records: Vec<&str> = desc.structures.iter().filter(|s| s.kind == "entry").map(|s| s.label.as_str()).collect();
+    let names: Vec<&str> = reader.entries().iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(records, names);
+    let data = desc.structures.iter().filter(|s| s.kind == "data").count();
+    assert_eq!(data, 2);
+    let footer = desc.structures.last().unwrap();
+    assert_eq!(footer.kind, "footer");
+    assert!(footer.fields[0].value.contains("matches the header"));
+
+    let mut md = Vec::new();
+    write_markdown(&desc, &mut md).unwrap();
+    let md = String::from_utf8(md).unwrap();
+    assert!(md.contains("| Offset | Size | Structure | Value |"));
+    assert!(md.contains("**header** file header"));
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 71. Pack fingerprint  (pure, fast)
+// --------------------------------------------------------------------------
+
+/// Only names and original sizes count: order, slash style, keys, flags and stored
+/// sizes leave the fingerprint alone; a renamed or resized entry changes it.
+#[test]
+fn test_pack_fingerprint() {
+    use mabi_pack2::common::FileEntry;
+    use mabi_pack2::info::pack_fingerprint;
+    let ent = |name: &str, size: u32| FileEntry { name: name.to_string(), checksum: 0, flags: 0, offset: 0, original_size: size, raw_size: size, key: [0u8; 16] };
+    let base = vec![ent("db\\a.xml", 10), ent("gfx\\b.dds", 2000)];
+    let fp = pack_fingerprint(&base);
+    assert_eq!(fp.len(), 32);
+
+    let mut shuffled = vec![ent("gfx/b.dds", 2000), ent("db/a.xml", 10)];
+    shuffled[0].key = [9u8; 16];
+    shuffled[0].flags = 1;
+    shuffled[0].raw_size = 700;
+    shuffled[1].offset = 42;
+    assert_eq!(pack_fingerprint(&shuffled), fp);
+
+    assert_ne!(pack_fingerprint(&[ent("db\\a.xml", 11), ent("gfx\\b.dds", 2000)]), fp);
+    assert_ne!(pack_fingerprint(&[ent("db\\c.xml", 10), ent("gfx\\b.dds", 2000)]), fp);
+    assert_ne!(pack_fingerprint(&base[..1]), fp);
+    assert_ne!(pack_fingerprint(&[]), fp);
+}
+
+// --------------------------------------------------------------------------
+// 72. Original-name guessing  (name list pure; search needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Guesses cut extra extensions, build package names from the numbers in the name and
+/// skip names the normal search already tries.
+#[test]
+fn test_original_name_guesses() {
+    use mabi_pack2::common::{key_name_variants, original_name_guesses, PackSettings};
+    assert_eq!(original_name_guesses("dl/Data_00012.it.zip").unwrap(),
+        vec!["data_00012.it", "12_full.it", "language.it", "data_00000.it"]);
+    assert_eq!(original_name_guesses("pack 210-211.bin").unwrap(),
+        vec!["pack 210-211.it", "210_to_211.it", "210_full.it", "data_00210.it", "211_full.it", "data_00211.it", "language.it", "data_00000.it"]);
+    assert_eq!(original_name_guesses("language.it").unwrap(), vec!["data_00000.it"]);
+    assert!(!key_name_variants("language.it", None, &PackSettings::default()).unwrap().contains(&"data_00000.it".to_string()));
+    let guessing = PackSettings { guess_names: true, ..PackSettings::default() };
+    assert_eq!(key_name_variants("language.it", None, &guessing).unwrap().last().unwrap(), "data_00000.it");
+}
+
+/// A pack renamed beyond the usual guesses opens with `--guess-name`, which reports the name.
+#[test]
+fn test_guess_name_opens_renamed_pack() {
+    let dir = common::temp_dir_for_test("guess_name");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("g.xml"), b"<guess/>").unwrap();
+    let tmp = std::env::temp_dir();
+    let packed = tmp.join("321_full.it");
+    let renamed = tmp.join("mabi_test_client_321.dat");
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    std::fs::rename(&packed, &renamed).unwrap();
+
+    let list = |extra: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2"))
+            .args(extra)
+            .args(["--no-key-cache", "list", "-i", renamed.to_str().unwrap(), "-k", KNOWN_SALT])
+            .output()
+            .unwrap()
+    };
+    assert!(!list(&[]).status.success());
+    let guessed = list(&["--guess-name"]);
+    let stdout = String::from_utf8_lossy(&guessed.stdout);
+    assert!(guessed.status.success(), "{}", String::from_utf8_lossy(&guessed.stderr));
+    assert!(stdout.contains("g.xml"));
+    assert!(stdout.contains("original name '321_full.it'"), "{}", stdout);
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&renamed);
+}
+
+// --------------------------------------------------------------------------
+// 73. Key derivation report  (pure, fast)
+// --------------------------------------------------------------------------
+
+/// `DerivedKeys` pins the derivation for a known name and salt: the name is
+/// case-insensitive and each salt only feeds its own key.
+#[test]
+fn test_derived_keys() {
+    use mabi_pack2::encryption::DerivedKeys;
+    use mabi_pack2::reader::hex;
+    let keys = DerivedKeys::new("data_00.it", "abc", "abc");
+    assert_eq!(keys.header_offset, 0x112);
+    assert_eq!(keys.entries_offset, 0x66);
+    assert_eq!(hex(&keys.header_key), "6462766463353635717d6b6d6f716f83");
+    assert_eq!(hex(&keys.entries_key), "c62786eb3fbd669784cb668fd43696d1");
+    assert_eq!(DerivedKeys::new("DATA_00.IT", "abc", "abc"), keys);
+
+    let split = DerivedKeys::new("data_00.it", "abc", "xyz");
+    assert_eq!(split.header_key, keys.header_key);
+    assert_ne!(split.entries_key, keys.entries_key);
+}
+
+// --------------------------------------------------------------------------
+// 74. Packs through pipes: pack -o -, list/extract -i -  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// A pack written to stdout with `--as-name` lists and extracts from stdin under the same name.
+#[test]
+fn test_pack_to_stdout_and_read_from_stdin() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let dir = common::temp_dir_for_test("stdio_src");
+    let out = common::temp_dir_for_test("stdio_out");
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    std::fs::write(dir.join("db").join("piped.xml"), b"<piped/>").unwrap();
+    let bin = env!("CARGO_BIN_EXE_mabi-pack2");
+
+    let packed = Command::new(bin)
+        .args(["--no-key-cache", "pack", "-i", dir.to_str().unwrap(), "-o", "-", "--as-name", "piped_00.it", "-k", KNOWN_SALT])
+        .output()
+        .unwrap();
+    assert!(packed.status.success(), "{}", String::from_utf8_lossy(&packed.stderr));
+    let pack_bytes = packed.stdout;
+    let reader = mabi_pack2::reader::PackReader::from_bytes("piped_00.it", pack_bytes.clone(), None, &[KNOWN_SALT.to_string()]).unwrap();
+    assert!(reader.find("db/piped.xml").is_some());
+
+    let with_stdin = |args: &[&str]| {
+        let mut child = Command::new(bin).args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+        // A run that fails before reading stdin closes the pipe early.
+        let _ = child.stdin.take().unwrap().write_all(&pack_bytes);
+        child.wait_with_output().unwrap()
+    };
+    let listed = with_stdin(&["--no-key-cache", "list", "-i", "-", "--as-name", "piped_00.it", "-k", KNOWN_SALT]);
+    assert!(listed.status.success(), "{}", String::from_utf8_lossy(&listed.stderr));
+    assert!(String::from_utf8_lossy(&listed.stdout).contains("piped.xml"));
+    let extracted = with_stdin(&["--no-key-cache", "extract", "-i", "-", "--as-name", "piped_00.it", "-k", KNOWN_SALT, "-o", out.to_str().unwrap()]);
+    assert!(extracted.status.success(), "{}", String::from_utf8_lossy(&extracted.stderr));
+    assert_eq!(std::fs::read(out.join("db").join("piped.xml")).unwrap(), b"<piped/>");
+    assert!(!with_stdin(&["--no-key-cache", "list", "-i", "-"]).status.success());
+
+    common::cleanup(&dir);
+    common::cleanup(&out);
+}
+
+// --------------------------------------------------------------------------
+// 75. --unknown-flags  (runs the CLI; needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Unknown flag bits are reported by default, silent with `ignore`, refused with `error`,
+/// and `info` shows them.
+#[test]
+fn test_unknown_flags_policy() {
+    let dir = common::temp_dir_for_test("unknown_flags");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+    std::fs::write(dir.join("b.txt"), b"world").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_unknown_flags.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+    mabi_pack2::edit::run_set_flags(path, None, None, &salts, &mabi_pack2::common::PackSettings::default(), vec!["^a".to_string()], 0x40, 0).unwrap();
+
+    let run = |args: &[&str]| {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2")).arg("--no-key-cache").args(args).output().unwrap();
+        let text = format!("{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+        (out.status.success(), text)
+    };
+    let (ok, text) = run(&["list", "-i", path, "-k", KNOWN_SALT]);
+    assert!(ok && text.contains("unknown flag bits 0x40"), "{}", text);
+    let (ok, text) = run(&["--unknown-flags", "ignore", "list", "-i", path, "-k", KNOWN_SALT]);
+    assert!(ok && !text.contains("unknown flag bits"), "{}", text);
+    let (ok, text) = run(&["--unknown-flags", "error", "list", "-i", path, "-k", KNOWN_SALT]);
+    assert!(!ok && text.contains("refused with --unknown-flags error"), "{}", text);
+    let (ok, text) = run(&["info", "-i", path, "-k", KNOWN_SALT]);
+    assert!(ok && text.contains("Unknown flags:  0x40 on 1 entry"), "{}", text);
+    assert!(!run(&["--unknown-flags", "bogus", "list", "-i", path]).0);
+    assert!(!run(&["--untrusted", "--unknown-flags", "warn", "list", "-i", path]).0);
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 76. --scan-offsets / --auto-escalate  (runs the CLI; needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_scan_header_offsets() {
+    use mabi_pack2::common::{scan_header_offsets, PackSettings, SCAN_OFFSETS_WINDOW};
+    let settings = PackSettings::default();
+    assert!(scan_header_offsets(9, &settings).is_empty());
+    assert_eq!(scan_header_offsets(20, &settings), (0..11).collect::<Vec<u64>>());
+    let offsets = scan_header_offsets(10 << 20, &settings);
+    assert_eq!(offsets.len() as u64, SCAN_OFFSETS_WINDOW + ((1 << 20) - SCAN_OFFSETS_WINDOW) / 1024);
+    assert_eq!(offsets[SCAN_OFFSETS_WINDOW as usize], SCAN_OFFSETS_WINDOW);
+    assert_eq!(*offsets.last().unwrap(), (1 << 20) - 1024);
+    let deep = PackSettings { deep_scan: Some(100..5000), ..PackSettings::default() };
+    assert_eq!(scan_header_offsets(10 << 20, &deep), (100..5000).collect::<Vec<u64>>());
+}
+
+/// A header moved off its derived offset is found by the offset scan, and an entries
+/// table moved off its derived offset only by the second stage of `--auto-escalate`.
+#[test]
+fn test_auto_escalate() {
+    let dir = common::temp_dir_for_test("escalate");
+    let work = common::temp_dir_for_test("escalate_packs");
+    common::cleanup(&dir);
+    common::cleanup(&work);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    std::fs::create_dir_all(&work).unwrap();
+    std::fs::write(dir.join("db").join("a.txt"), b"hello").unwrap();
+    let packed = work.join("data_00.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let original = std::fs::read(&packed).unwrap();
+    let content_offset = mabi_pack2::reader::PackReader::open(path, Some(KNOWN_SALT.to_string()), &[]).unwrap().content_offset as usize;
+
+    let run = |args: &[&str]| {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2")).arg("--no-key-cache").args(args).output().unwrap();
+        let text = format!("{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+        (out.status.success(), text)
+    };
+
+    // Header 7 bytes later; the footer pointer still names the old offset.
+    let mut shifted = vec![0u8; 7];
+    shifted.extend_from_slice(&original);
+    std::fs::write(&packed, &shifted).unwrap();
+    let (ok, text) = run(&["list", "-i", path, "-k", KNOWN_SALT]);
+    assert!(!ok && text.contains("--auto-escalate"), "{}", text);
+    let (ok, text) = run(&["--scan-offsets", "list", "-i", path, "-k", KNOWN_SALT]);
+    assert!(ok && text.contains("SUCCESS in stage 1") && text.contains("db/a.txt"), "{}", text);
+
+    // Entries table 5 bytes later, the padding before the content 5 bytes shorter.
+    let h = mabi_pack2::encryption::gen_header_offset("data_00.it") as usize + 9;
+    let mut gapped = original[..h].to_vec();
+    gapped.extend_from_slice(&[0u8; 5]);
+    gapped.extend_from_slice(&original[h..content_offset - 5]);
+    gapped.extend_from_slice(&original[content_offset..]);
+    std::fs::write(&packed, &gapped).unwrap();
+    let (ok, text) = run(&["--scan-offsets", "list", "-i", path, "-k", KNOWN_SALT]);
+    assert!(!ok && text.contains("Stage 1/1 failed"), "{}", text);
+    let out = work.join("out");
+    let (ok, text) = run(&["--auto-escalate", "extract", "-i", path, "-k", KNOWN_SALT, "-o", out.to_str().unwrap()]);
+    assert!(ok && text.contains("SUCCESS in stage 2"), "{}", text);
+    assert_eq!(std::fs::read(out.join("db").join("a.txt")).unwrap(), b"hello");
+
+    common::cleanup(&dir);
+    common::cleanup(&work);
+}
+
+/// When several salts open the pack in the offset scan, the one earliest in the key order
+/// wins, whichever worker finished first.
+#[test]
+fn test_escalate_search_keeps_salt_priority() {
+    use mabi_pack2::common::{Escalation, FileEntry, PackSettings, FLAG_COMPRESSED};
+    use mabi_pack2::encryption::Snow2Mode;
+
+    // The header key only uses the first 16 chars of name + salt, so with a name this
+    // long every salt validates the header.
+    const ENTRIES_SALT: &str = "@6QeTuOaDgJlZcBm#9";
+    const NAME: &str = "mabi_test_escalate_priority.it";
+    let packed = std::env::temp_dir().join(NAME);
+    let names = vec!["db\\priority.xml".to_string()];
+    let mut writer = mabi_pack2::pack::PackWriter::create(packed.to_str().unwrap(), NAME, KNOWN_SALT, ENTRIES_SALT, 0, Snow2Mode::Sub, &names).unwrap();
+    let plain = b"<priority/>";
+    let ent = FileEntry { name: names[0].clone(), checksum: 0, flags: FLAG_COMPRESSED, offset: 0, original_size: plain.len() as u32, raw_size: 0, key: [7u8; 16] };
+    let content = mabi_pack2::pack::encode_entry_data(&ent.name, &ent.key, plain, ent.flags, 0, Snow2Mode::Sub).unwrap();
+    writer.add_raw(ent, &content).unwrap();
+    writer.finish().unwrap();
+    let data = std::fs::read(&packed).unwrap();
+
+    let settings = PackSettings { escalation: Escalation::ScanOffsets, ..PackSettings::default() };
+    let mut keys: Vec<String> = mabi_pack2::builtin_salts().into_iter().take(12).collect();
+    keys.push(ENTRIES_SALT.to_string());
+    for _ in 0..4 {
+        let hit = mabi_pack2::common_ext::escalate_search(&data, &[NAME.to_string()], &keys, &settings).expect("no hit");
+        assert_eq!(hit.header_salt, keys[0]);
+        assert_eq!(hit.entries_salt, ENTRIES_SALT);
+        keys.rotate_left(1);
+    }
+
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 77. Fixture packs for downstream tests  (needs --features testing; in memory, fast)
+// --------------------------------------------------------------------------
+
+/// Built packs open and read back; each corruption breaks exactly what it says.
+#[cfg(feature = "testing")]
+#[test]
+fn test_fixture_packs() {
+    use mabi_pack2::common::{FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED};
+    use mabi_pack2::testing::{Corruption, PackBuilder};
+
+    let pack = PackBuilder::new("fixture_00.it", KNOWN_SALT)
+        .entries_salt("@6QeTuOaDgJlZcBm#9")
+        .entry("db/a.xml", "<a/>")
+        .entry_with_flags("db/b.bin", vec![7u8; 3000], FLAG_COMPRESSED | FLAG_ALL_ENCRYPTED)
+        .build()
+        .unwrap();
+    let reader = pack.reader().unwrap();
+    assert_eq!(reader.content_offset, pack.content_offset);
+    assert_eq!(reader.entries_salt, "@6QeTuOaDgJlZcBm#9");
+    assert_eq!(reader.read_entry(reader.find("db/a.xml").unwrap()).unwrap(), b"<a/>");
+    assert_eq!(reader.read_entry(reader.find("db/b.bin").unwrap()).unwrap(), vec![7u8; 3000]);
+    let checksums = |entries: &[mabi_pack2::common::FileEntry]| entries.iter().map(|e| (e.name.clone(), e.offset, e.checksum)).collect::<Vec<_>>();
+    assert_eq!(checksums(reader.entries()), checksums(&pack.entries));
+    assert_eq!(PackBuilder::new("fixture_00.it", KNOWN_SALT).entry("db/a.xml", "<a/>").build().unwrap().bytes,
+        PackBuilder::new("fixture_00.it", KNOWN_SALT).entry("db/a.xml", "<a/>").build().unwrap().bytes);
+
+    let broken = |how| pack.corrupted(how).unwrap();
+    assert!(broken(Corruption::HeaderChecksum).reader().is_err());
+    assert!(broken(Corruption::FileCount(5)).reader().is_err());
+    assert!(broken(Corruption::EntryChecksum(1)).reader().is_err());
+    assert!(broken(Corruption::Truncate(pack.entries_offset() + 10)).reader().is_err());
+    assert!(broken(Corruption::FooterPointer).reader().is_ok());
+    assert!(broken(Corruption::Shift(7)).reader().is_err());
+
+    let flagged = broken(Corruption::EntryFlags(0, FLAG_COMPRESSED | 0x40));
+    assert_eq!(flagged.entries[0].flags, FLAG_COMPRESSED | 0x40);
+    let reader = flagged.reader().unwrap();
+    assert_eq!(reader.read_entry(reader.find("db/a.xml").unwrap()).unwrap(), b"<a/>");
+
+    let damaged = broken(Corruption::Payload(0)).reader().unwrap();
+    assert!(damaged.read_entry(damaged.find("db/a.xml").unwrap()).is_err());
+    assert!(pack.corrupted(Corruption::Payload(9)).is_err());
+    assert!(pack.corrupted(Corruption::FlipByte(1 << 20)).is_err());
+}
+
+// --------------------------------------------------------------------------
+// 78. Content codecs  (codecs and rules pure, fast; pack/extract round trip needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_utf8_codec_round_trip() {
+    use mabi_pack2::codec::{ContentCodec, Utf8Codec};
+    let mut native = vec![0xFF, 0xFE];
+    for unit in "<?xml version=\"1.0\" encoding=\"UTF-16\"?><a t=\"ナオ\"/>".encode_utf16() {
+        native.extend_from_slice(&unit.to_le_bytes());
+    }
+    let decoded = Utf8Codec.decode("a.xml", native.clone()).unwrap();
+    assert_eq!(&decoded[..3], &[0xEF, 0xBB, 0xBF]);
+    assert_eq!(std::str::from_utf8(&decoded[3..]).unwrap(), "<?xml version=\"1.0\" encoding=\"UTF-8\"?><a t=\"ナオ\"/>");
+    assert_eq!(Utf8Codec.encode("a.xml", decoded).unwrap(), native);
+    // Content that isn't UTF-16 (or UTF-8 with a mark, when packing) passes through.
+    assert_eq!(Utf8Codec.decode("a.xml", b"<a/>".to_vec()).unwrap(), b"<a/>");
+    assert_eq!(Utf8Codec.encode("a.xml", b"<a/>".to_vec()).unwrap(), b"<a/>");
+    assert!(Utf8Codec.decode("a.xml", vec![0xFF, 0xFE, 0x00, 0xD8]).is_err());
+}
+
+#[test]
+fn test_codec_registry_rules() {
+    use mabi_pack2::codec::CodecRegistry;
+    use mabi_pack2::hook::{EntryHook, HookDirection};
+    let mut registry = CodecRegistry::new();
+    registry.add_rule("dds=png").unwrap();
+    registry.add_rule(" .XML = utf8 ").unwrap();
+    assert_eq!(registry.codec_for("db\\a.Xml").unwrap().name(), "utf8");
+    assert!(registry.codec_for("db\\a.txt").is_none());
+    assert!(registry.codec_for("db.dds\\readme").is_none());
+    assert_eq!(registry.disk_name("gfx\\icon.dds").as_deref(), Some("gfx\\icon.png"));
+    assert_eq!(registry.disk_name("db\\a.xml"), None);
+    assert_eq!(registry.entry_name("gfx\\icon.png").as_deref(), Some("gfx\\icon.dds"));
+    assert_eq!(registry.entry_name("gfx\\icon.jpg"), None);
+    assert!(registry.add_rule("tga=png").is_err());
+    assert!(registry.add_rule("xml").is_err());
+    assert!(registry.add_rule("xml=zip").is_err());
+
+    let hook = registry.clone().hook(HookDirection::Pack);
+    assert_eq!(hook.rename("gfx\\icon.png").unwrap().as_deref(), Some("gfx\\icon.dds"));
+    assert_eq!(hook.transform("gfx\\icon.dds", b"DDS not png".to_vec()).unwrap(), b"DDS not png");
+    let hook = registry.hook(HookDirection::Extract);
+    assert_eq!(hook.rename("gfx\\icon.dds").unwrap().as_deref(), Some("gfx\\icon.png"));
+}
+
+#[test]
+fn test_png_codec_round_trip() {
+    use mabi_pack2::codec::{ContentCodec, PngCodec};
+    let img = image::RgbaImage::from_pixel(8, 8, image::Rgba([255, 0, 0, 255]));
+    let mut png = std::io::Cursor::new(Vec::new());
+    img.write_to(&mut png, image::ImageFormat::Png).unwrap();
+    let dds = PngCodec.encode("icon.dds", png.into_inner()).unwrap();
+    assert!(dds.starts_with(b"DDS "));
+    let back = image::load_from_memory(&PngCodec.decode("icon.dds", dds).unwrap()).unwrap();
+    assert_eq!((back.width(), back.height()), (8, 8));
+}
+
+/// Files packed with `--codec xml=utf8` are stored as UTF-16 and extract back unchanged.
+#[test]
+fn test_codec_pack_extract_round_trip() {
+    use mabi_pack2::codec::CodecRegistry;
+    use mabi_pack2::hook::HookDirection;
+    let dir = common::temp_dir_for_test("codec_src");
+    let out = common::temp_dir_for_test("codec_out");
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    let text = b"\xEF\xBB\xBF<?xml version=\"1.0\" encoding=\"utf-8\"?><a/>".to_vec();
+    std::fs::write(dir.join("db").join("a.xml"), &text).unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_codec.it");
+    let path = packed.to_str().unwrap();
+
+    let mut registry = CodecRegistry::new();
+    registry.add_rule("xml=utf8").unwrap();
+    let opts = mabi_pack2::pack::PackOptions { hook: Some(std::sync::Arc::new(registry.clone().hook(HookDirection::Pack))), ..Default::default() };
+    mabi_pack2::pack::run_pack_with_options(dir.to_str().unwrap(), path, KNOWN_SALT, &opts, None).unwrap();
+    let reader = mabi_pack2::reader::PackReader::open(path, Some(KNOWN_SALT.to_string()), &[]).unwrap();
+    let stored = reader.read_entry(reader.find("db\\a.xml").or_else(|| reader.find("db/a.xml")).unwrap()).unwrap();
+    assert!(stored.starts_with(&[0xFF, 0xFE]));
+
+    let hook = registry.hook(HookDirection::Extract);
+    let opts = mabi_pack2::extract::ExtractOptions { hook: Some(&hook), ..Default::default() };
+    mabi_pack2::extract::run_extract_with_options(path, out.to_str().unwrap(), Some(KNOWN_SALT.to_string()), &[], &opts).unwrap();
+    assert_eq!(std::fs::read(out.join("db").join("a.xml")).unwrap(), text);
+
+    common::cleanup(&dir);
+    common::cleanup(&out);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 79. Partial entry reads  (keystream pure, fast; range reads need --features testing, in memory)
+// --------------------------------------------------------------------------
+
+/// Decrypting from a word-aligned position matches the same bytes of a full decrypt.
+#[test]
+fn test_snow2_decrypt_at_position() {
+    use mabi_pack2::encryption::{gen_header_key, snow2_decrypt_mode, snow2_decrypt_mode_at, Snow2Mode};
+    let key = gen_header_key("data.it", KNOWN_SALT);
+    let stored: Vec<u8> = (0..300u32).map(|i| (i * 37 % 256) as u8).collect();
+    for mode in [Snow2Mode::Sub, Snow2Mode::Xor] {
+        let mut full = stored.clone();
+        snow2_decrypt_mode(&key, 3, mode, &mut full);
+        for (pos, end) in [(0, 5), (4, 64), (60, 131), (128, 300), (296, 299)] {
+            let mut part = stored[pos..end].to_vec();
+            snow2_decrypt_mode_at(&key, 3, mode, pos as u64, &mut part);
+            assert_eq!(part, &full[pos..end], "{:?} {}..{}", mode, pos, end);
+        }
+    }
+}
+
+/// `read_entry_range` returns the same bytes as slicing `read_entry`, for every storage
+/// layout, and clamps ranges that run past the end.
+#[cfg(feature = "testing")]
+#[test]
+fn test_read_entry_range() {
+    use mabi_pack2::common::{FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED, FLAG_HEAD_ENCRYPTED};
+    use mabi_pack2::encryption::Snow2Mode;
+    use mabi_pack2::testing::PackBuilder;
+
+    let data: Vec<u8> = (0..5000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let layouts = [0, FLAG_ALL_ENCRYPTED, FLAG_HEAD_ENCRYPTED, FLAG_COMPRESSED, FLAG_COMPRESSED | FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED | FLAG_HEAD_ENCRYPTED];
+    for mode in [Snow2Mode::Sub, Snow2Mode::Xor] {
+        let mut builder = PackBuilder::new("range_00.it", KNOWN_SALT).mode(mode);
+        for flags in layouts {
+            builder = builder.entry_with_flags(&format!("db/{}.bin", flags), data.clone(), flags);
+        }
+        let reader = builder.build().unwrap().reader().unwrap();
+        for flags in layouts {
+            let name = format!("db/{}.bin", flags);
+            assert_eq!(reader.read_entry(reader.find(&name).unwrap()).unwrap(), data);
+            for (offset, len) in [(0, 10), (3, 1500), (1021, 7), (2049, 1), (4990, 100), (5000, 5), (0, u64::MAX)] {
+                let end = (offset + len.min(5000)).min(5000) as usize;
+                assert_eq!(reader.read_entry_range(&name, offset, len).unwrap(), &data[offset as usize..end], "{:?} {} {}+{}", mode, name, offset, len);
+            }
+            assert!(reader.read_entry_range(&name, 5001, 1).is_err());
+        }
+        assert!(reader.read_entry_range("db/missing.bin", 0, 1).is_err());
+    }
+}
+
+// --------------------------------------------------------------------------
+// 80. roundtrip  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_roundtrip_issue_lines() {
+    use mabi_pack2::verify::RoundTripIssue;
+    assert_eq!(RoundTripIssue::Missing("db/a.xml".into()).to_string(), "missing  db/a.xml");
+    let changed = RoundTripIssue::Changed { name: "db/b.bin".into(), source_size: 10, extracted_size: 8, first_difference: 8 };
+    assert_eq!(changed.to_string(), "changed  db/b.bin (10 -> 8 bytes, first difference at byte 8)");
+}
+
+/// A folder of text, binary and nested files comes back from its pack unchanged.
+#[test]
+fn test_roundtrip_folder() {
+    let dir = common::temp_dir_for_test("roundtrip_src");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("db").join("sub")).unwrap();
+    std::fs::write(dir.join("db").join("a.xml"), "<a/>").unwrap();
+    std::fs::write(dir.join("db").join("sub").join("b.bin"), (0..5000u32).map(|i| (i * 7 % 256) as u8).collect::<Vec<_>>()).unwrap();
+    std::fs::write(dir.join("readme.txt"), "").unwrap();
+
+    let report = mabi_pack2::verify::run_roundtrip(dir.to_str().unwrap(), KNOWN_SALT, &mabi_pack2::pack::PackOptions::default()).unwrap();
+    assert_eq!(report.files, 3);
+    assert_eq!(report.bytes, 5004);
+    assert!(report.issues.is_empty(), "{:?}", report.issues);
+    assert!(mabi_pack2::verify::run_roundtrip(dir.join("readme.txt").to_str().unwrap(), KNOWN_SALT, &Default::default()).is_err());
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 81. --entry-checksum  (algorithms pure, fast; the CLI round trip needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_entry_checksum_algorithms() {
+    use mabi_pack2::checksum::{self, EntryChecksum, StandardChecksum};
+    let ent = mabi_pack2::common::FileEntry { name: "db/a.xml".into(), checksum: 0, flags: 1, offset: 2, original_size: 300, raw_size: 120, key: [7; 16] };
+    assert_eq!(StandardChecksum.checksum(&ent), 1 + 2 + 300 + 120 + 7 * 16);
+    assert_eq!(ent.calc_checksum(), StandardChecksum.checksum(&ent));
+
+    let renamed = mabi_pack2::common::FileEntry { name: "db/b.xml".into(), ..ent.clone() };
+    let md5 = checksum::from_spec("md5").unwrap();
+    assert_eq!(StandardChecksum.checksum(&renamed), StandardChecksum.checksum(&ent));
+    assert_ne!(md5.checksum(&renamed), md5.checksum(&ent));
+    let keyed = checksum::from_spec("keyed:secret").unwrap();
+    assert_ne!(keyed.checksum(&ent), checksum::from_spec("keyed:other").unwrap().checksum(&ent));
+    assert_ne!(keyed.checksum(&ent), md5.checksum(&ent));
+    assert!(checksum::from_spec("keyed:").is_err());
+    assert!(checksum::from_spec("crc32").is_err());
+}
+
+/// A pack written with `--entry-checksum md5` only opens with the same setting, and
+/// `info` says which algorithm it uses.
+#[test]
+fn test_entry_checksum_cli() {
+    let dir = common::temp_dir_for_test("entry_checksum");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_entry_checksum.it");
+    let path = packed.to_str().unwrap();
+
+    let run = |args: &[&str]| {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2")).arg("--no-key-cache").args(args).output().unwrap();
+        let text = format!("{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+        (out.status.success(), text)
+    };
+    assert!(run(&["--entry-checksum", "md5", "pack", "-i", dir.to_str().unwrap(), "-o", path, "-k", KNOWN_SALT]).0);
+    let (ok, text) = run(&["--entry-checksum", "md5", "list", "-i", path, "-k", KNOWN_SALT]);
+    assert!(ok && text.contains("a.txt"), "{}", text);
+    assert!(!run(&["list", "-i", path, "-k", KNOWN_SALT]).0);
+    let (ok, text) = run(&["--entry-checksum", "md5", "info", "-i", path, "-k", KNOWN_SALT]);
+    assert!(ok && text.contains("Entry checksum: md5"), "{}", text);
+    assert!(!run(&["--entry-checksum", "sha9", "list", "-i", path]).0);
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 82. Key search progress  (pure, fast)
+// --------------------------------------------------------------------------
+
+/// Progress counts every combination marked as tried, and concurrent searches are
+/// reported separately until each one ends.
+#[test]
+fn test_search_progress_counts() {
+    use mabi_pack2::common::{search_progress, SearchProgress};
+    let progress = SearchProgress::new(4);
+    let other = SearchProgress::new(10);
+    for salt in ["a", "b", "c"] {
+        progress.tick(salt, "data_00.it");
+    }
+    other.tick("a", "data_01.it");
+    assert_eq!(progress.done(), 3);
+    let active = search_progress();
+    assert_eq!(active.get(&progress.id()), Some(&(3, 4)));
+    assert_eq!(active.get(&other.id()), Some(&(1, 10)));
+
+    let id = progress.id();
+    drop(progress);
+    let active = search_progress();
+    assert!(!active.contains_key(&id));
+    assert_eq!(active.get(&other.id()), Some(&(1, 10)));
+}
+
+// --------------------------------------------------------------------------
+// 83. info header and compression statistics  (pure, fast; the header read needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Only compressed entries count towards the ratio; the block span ends at the last
+/// block an entry's data reaches.
+#[test]
+fn test_info_compression_and_block_span() {
+    use mabi_pack2::common::{FileEntry, FLAG_COMPRESSED};
+    use mabi_pack2::info::{compression_stats, data_block_span};
+
+    let ent = |name: &str, flags: u32, offset: u32, original: u32, raw: u32| FileEntry { name: name.to_string(), checksum: 0, flags, offset, original_size: original, raw_size: raw, key: [0u8; 16] };
+    let entries = vec![
+        ent("a.xml", FLAG_COMPRESSED, 2, 1000, 250),
+        ent("b.txt", FLAG_COMPRESSED, 0, 100, 80),
+        ent("c.dds", 0, 3, 3000, 3000),
+        ent("dir\\", 0, 0, 0, 0),
+    ];
+    let stats = compression_stats(&entries);
+    assert_eq!((stats.compressed, stats.original, stats.stored), (2, 1100, 330));
+    assert!((stats.percent() - 30.0).abs() < 1e-9);
+    let (best, worst) = stats.range.unwrap();
+    assert_eq!((best.0.as_str(), worst.0.as_str()), ("a.xml", "b.txt"));
+    assert_eq!(data_block_span(&entries), Some((0, 5)));
+
+    assert_eq!(compression_stats(&entries[2..]).percent(), 100.0);
+    assert!(compression_stats(&entries[2..]).range.is_none());
+    assert_eq!(data_block_span(&entries[3..]), None);
+}
+
+#[test]
+fn test_reader_header_record() {
+    use mabi_pack2::reader::PackReader;
+
+    let src = common::temp_dir_for_test("header_record_src");
+    common::cleanup(&src);
+    std::fs::create_dir_all(src.join("db")).unwrap();
+    std::fs::write(src.join("db/a.xml"), "<a/>").unwrap();
+    std::fs::write(src.join("b.txt"), "b").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_header_record.it");
+    mabi_pack2::pack::run_pack(src.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let header = PackReader::open(packed.to_str().unwrap(), None, &[KNOWN_SALT.to_string()]).unwrap().header().unwrap();
+    assert_eq!(header.file_cnt, 2);
+    assert_eq!(header.checksum, header.version as u32 + 2);
+    common::cleanup(&src);
+    let _ = std::fs::remove_file(&packed);
+}
+
+// --------------------------------------------------------------------------
+// 84. Snow2Decoder seeking  (pure, fast)
+// --------------------------------------------------------------------------
+
+/// Seeks forwards, backwards, into the middle of a word and relative to the current
+/// position or the end all land on the same bytes a full decrypt gives.
+#[test]
+fn test_snow2_decoder_seek() {
+    use mabi_pack2::encryption::{gen_header_key, snow2_encrypt_mode, Snow2Decoder, Snow2Mode};
+    use std::io::{BufRead, Read, Seek, SeekFrom};
+
+    for mode in [Snow2Mode::Sub, Snow2Mode::Xor] {
+        let key = gen_header_key("data.it", KNOWN_SALT);
+        let plain: Vec<u8> = (0..50_003u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut stored = plain.clone();
+        snow2_encrypt_mode(&key, 3, mode, &mut stored);
+        let mut src = std::io::Cursor::new(stored);
+        let mut dec = Snow2Decoder::new_iv_mode(&key, 3, mode, &mut src);
+
+        let mut buf = [0u8; 9];
+        for (pos, expect) in [
+            (SeekFrom::Start(40_000), 40_000),
+            (SeekFrom::Start(1_027), 1_027),
+            (SeekFrom::Current(-5), 1_031),
+            (SeekFrom::Current(64 * 100 + 1), 1_040 + 6_401),
+            (SeekFrom::End(-12), 49_991),
+            (SeekFrom::Start(0), 0),
+        ] {
+            assert_eq!(dec.seek(pos).unwrap(), expect);
+            dec.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &plain[expect as usize..expect as usize + 9], "{:?} at {}", mode, expect);
+        }
+        // `read` needs whole words; `fill_buf` also returns the stream's last partial one.
+        dec.seek(SeekFrom::End(-2)).unwrap();
+        assert_eq!(dec.fill_buf().unwrap(), &plain[plain.len() - 2..]);
+        assert!(dec.seek(SeekFrom::Current(-100_000)).is_err());
+    }
+}
+
+// --------------------------------------------------------------------------
+// 85. doctor  (missing pack pure, fast; the CLI run needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// A pack that can't be opened is a failure with a hint, and stops the checks there.
+#[test]
+fn test_doctor_missing_pack() {
+    use mabi_pack2::doctor::{check_pack, Severity};
+
+    let findings = check_pack("/nonexistent/mabi_doctor/data_00.it", &[KNOWN_SALT.to_string()], &mabi_pack2::common::PackSettings::default());
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].severity, Severity::Fail);
+    let line = findings[0].to_string();
+    assert!(line.starts_with("[FAIL] pack") && line.contains("->"), "{}", line);
+}
+
+/// `doctor -i` reports the salt that decodes a pack's header, and fails for a pack no
+/// salt decodes.
+#[test]
+fn test_doctor_cli() {
+    let dir = common::temp_dir_for_test("doctor");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+    let packed = std::env::temp_dir().join("mabi_test_doctor.it");
+    let path = packed.to_str().unwrap();
+
+    let run = |args: &[&str]| {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2")).args(["--no-key-cache", "--untrusted"]).args(args).output().unwrap();
+        (out.status.success(), String::from_utf8_lossy(&out.stdout).into_owned())
+    };
+    assert!(run(&["pack", "-i", dir.to_str().unwrap(), "-o", path, "-k", KNOWN_SALT]).0);
+    let (ok, text) = run(&["doctor", "-i", path]);
+    assert!(ok, "{}", text);
+    assert!(text.contains("[ ok ] network     skipped"), "{}", text);
+    assert!(text.contains(&format!("salt '{}' decodes it", KNOWN_SALT)), "{}", text);
+
+    let (ok, text) = run(&["doctor", "-i", path, "-k", "not the salt"]);
+    assert!(ok, "{}", text);
+    std::fs::write(&packed, vec![0u8; 4096]).unwrap();
+    let (ok, text) = run(&["doctor", "-i", path]);
+    assert!(!ok);
+    assert!(text.contains("[FAIL] header"), "{}", text);
+
+    common::cleanup(&dir);
+    let _ = std::fs::remove_file(&packed);
+}
+
+/// Library callers get the offline doctor and salts of `--untrusted` from their settings.
+#[test]
+fn test_untrusted_settings_stay_offline() {
+    let untrusted = mabi_pack2::common::PackSettings { untrusted: true, ..Default::default() };
+    let mut out = Vec::new();
+    mabi_pack2::doctor::run_doctor(None, None, &untrusted, &mut out).unwrap();
+    let text = String::from_utf8_lossy(&out);
+    assert!(text.contains("[ ok ] network     skipped"), "{}", text);
+    let salts = mabi_pack2::load_salts_with_settings(&untrusted);
+    assert!(mabi_pack2::builtin_salts().iter().all(|s| salts.contains(s)));
+}
+
+// --------------------------------------------------------------------------
+// 86. diff between two packs  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Added, removed and changed entries are found, and `--content` agrees on what changed.
+#[test]
+fn test_diff_packs() {
+    use mabi_pack2::reader::PackReader;
+    use mabi_pack2::snapshot::{diff_packs, ChangeKind};
+
+    let salts = vec![KNOWN_SALT.to_string()];
+    let build = |tag: &str, files: &[(&str, &str)]| {
+        let dir = common::temp_dir_for_test(&format!("diff_{}", tag));
+        common::cleanup(&dir);
+        for (name, text) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        let packed = std::env::temp_dir().join(format!("mabi_test_diff_{}_pack", tag)).join("data_00.it");
+        std::fs::create_dir_all(packed.parent().unwrap()).unwrap();
+        mabi_pack2::pack::run_pack(dir.to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+        common::cleanup(&dir);
+        PackReader::open(packed.to_str().unwrap(), None, &salts).unwrap()
+    };
+    let old = build("old", &[("db/same.xml", "<same/>"), ("db/edit.xml", "<old/>"), ("gone.txt", "bye")]);
+    let new = build("new", &[("DB/Same.xml", "<same/>"), ("db/edit.xml", "<new/>"), ("added.txt", "hi")]);
+    let cmp = diff_packs(&old, &new, false);
+    let changes: Vec<(ChangeKind, &str)> = cmp.changes.iter().map(|c| (c.kind, c.name.as_str())).collect();
+    // Names match case-insensitively.
+    assert_eq!(changes, vec![(ChangeKind::Added, "added.txt"), (ChangeKind::Modified, "db/edit.xml"), (ChangeKind::Removed, "gone.txt")]);
+    let cmp = diff_packs(&old, &new, true);
+    assert_eq!(cmp.count(ChangeKind::Modified), 1);
+    assert_eq!(cmp.unchanged, 1);
+    for tag in ["old", "new"] {
+        let _ = std::fs::remove_dir_all(std::env::temp_dir().join(format!("mabi_test_diff_{}_pack", tag)));
+    }
+}
+
+// --------------------------------------------------------------------------
+// 87. Overlay extraction  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Later packs win for names both hold (in any case), and the entries they shadow are
+/// not written at all.
+#[test]
+fn test_extract_overlay() {
+    let base = common::temp_dir_for_test("overlay");
+    common::cleanup(&base);
+    let write = |dir: &str, files: &[(&str, &str)]| {
+        for (name, content) in files {
+            let path = base.join(dir).join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let pack = base.join(format!("{}.it", dir));
+        mabi_pack2::pack::run_pack(base.join(dir).to_str().unwrap(), pack.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+        pack.to_str().unwrap().to_string()
+    };
+    let low = write("low", &[("db/a.xml", "<low/>"), ("only_low.txt", "low"), ("Mixed.txt", "low")]);
+    let high = write("high", &[("db/a.xml", "<high/>"), ("mixed.txt", "high")]);
+    let out = base.join("out");
+
+    let (summary, packs) = mabi_pack2::extract::run_extract_overlay(&[low, high], out.to_str().unwrap(), Some(KNOWN_SALT.to_string()), &[], &Default::default()).unwrap();
+    assert_eq!(summary.entries, 3);
+    assert_eq!((packs[0].extracted, packs[0].shadowed), (1, 2));
+    assert_eq!((packs[1].extracted, packs[1].shadowed), (2, 0));
+    assert_eq!(std::fs::read_to_string(out.join("db").join("a.xml")).unwrap(), "<high/>");
+    assert_eq!(std::fs::read_to_string(out.join("only_low.txt")).unwrap(), "low");
+    assert_eq!(std::fs::read_to_string(out.join("mixed.txt")).unwrap(), "high");
+    let mut names: Vec<String> = std::fs::read_dir(&out).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+    names.sort();
+    assert_eq!(names, vec!["db", "mixed.txt", "only_low.txt"]);
+
+    common::cleanup(&base);
+}
+
+// --------------------------------------------------------------------------
+// 88. compact  (needs --features testing and temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Data no entry owns any more is dropped and the remaining entries still decode; a
+/// pack without gaps is left alone.
+#[cfg(feature = "testing")]
+#[test]
+fn test_compact_pack() {
+    use mabi_pack2::testing::{Corruption, PackBuilder};
+
+    let base = common::temp_dir_for_test("compact");
+    common::cleanup(&base);
+    std::fs::create_dir_all(&base).unwrap();
+    // Cutting the file count leaves the last entry's data behind, as removals do.
+    let fixture = PackBuilder::new("data_00.it", KNOWN_SALT)
+        .entry("a.txt", "first").entry("b.txt", "second").entry("big.bin", vec![7u8; 5000])
+        .build().unwrap().corrupted(Corruption::FileCount(2)).unwrap();
+    let path = fixture.write_to(&base).unwrap();
+    let path = path.to_str().unwrap();
+    let salts = [KNOWN_SALT.to_string()];
+
+    let summary = mabi_pack2::edit::run_compact(path, None, None, &salts, &mabi_pack2::common::PackSettings::default()).unwrap();
+    assert_eq!(summary.entries, 2);
+    assert!(summary.gap_bytes >= 5000, "gap {} bytes", summary.gap_bytes);
+    assert!(summary.reclaimed() >= 5000, "{} -> {}", summary.before, summary.after);
+    assert_eq!(summary.after, std::fs::metadata(path).unwrap().len());
+    let reader = mabi_pack2::reader::PackReader::open(path, None, &salts).unwrap();
+    let contents: Vec<Vec<u8>> = reader.entries.iter().map(|e| reader.read_entry(e).unwrap()).collect();
+    assert_eq!(contents, vec![b"first".to_vec(), b"second".to_vec()]);
+    drop(reader);
+
+    let again = mabi_pack2::edit::run_compact(path, None, None, &salts, &mabi_pack2::common::PackSettings::default()).unwrap();
+    assert_eq!((again.gap_bytes, again.reclaimed()), (0, 0));
+
+    common::cleanup(&base);
+}
+
+// --------------------------------------------------------------------------
+// 89. --glob filters  (pure, fast)
+// --------------------------------------------------------------------------
+
+/// A `--glob` value becomes a `glob:` filter: `.` is literal, `**` crosses folders and
+/// either separator matches. An entry matching any glob or regex is taken.
+#[test]
+fn test_glob_filters_with_regex() {
+    use mabi_pack2::extract::{compile_filters, FilterSettings, GLOB_FILTER_PREFIX};
+
+    let filters = vec![format!("{}gfx/char/**/*.dds", GLOB_FILTER_PREFIX), r"^db\\.*\.xml$".to_string()];
+    let compiled = compile_filters(&filters, FilterSettings::default()).unwrap();
+    let taken = |name: &str| compiled.iter().any(|re| re.is_match(name));
+    assert!(taken("gfx\\char\\human\\male\\face.dds"));
+    assert!(taken("GFX/Char/hair.DDS"));
+    assert!(taken("db\\itemdb.xml"));
+    assert!(!taken("gfx\\char\\face_dds"));
+    assert!(!taken("gfx\\charm\\face.dds"));
+    assert!(!taken("old\\gfx\\char\\face.dds"));
+}
+
+// --------------------------------------------------------------------------
+// 90. Output inside the input folder  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Packing into the folder being packed is refused unless allowed, and then the output
+/// (even one left by an earlier run) is not packed into itself.
+#[test]
+fn test_pack_output_in_input() {
+    use mabi_pack2::pack::{output_in_input, run_pack_with_options, PackOptions};
+
+    let base = common::temp_dir_for_test("output_in_input");
+    common::cleanup(&base);
+    std::fs::create_dir_all(base.join("in").join("sub")).unwrap();
+    std::fs::write(base.join("in").join("a.txt"), "a").unwrap();
+    std::fs::write(base.join("in").join("sub").join("b.txt"), "b").unwrap();
+    let input = base.join("in");
+    let input = input.to_str().unwrap();
+    let inside = base.join("in").join("sub").join("..").join("out.it");
+    let inside = inside.to_str().unwrap();
+    let beside = base.join("out.it");
+
+    assert_eq!(output_in_input(input, inside), Some(std::path::PathBuf::from("out.it")));
+    assert_eq!(output_in_input(input, beside.to_str().unwrap()), None);
+    let err = run_pack_with_options(input, inside, KNOWN_SALT, &PackOptions::default(), None).unwrap_err();
+    assert!(err.to_string().contains("--allow-output-in-input"), "{}", err);
+    assert!(!base.join("in").join("out.it").exists());
+
+    let opts = PackOptions { allow_output_in_input: true, ..PackOptions::default() };
+    for _ in 0..2 {
+        let summary = run_pack_with_options(input, inside, KNOWN_SALT, &opts, None).unwrap();
+        assert_eq!(summary.entries, 2);
+    }
+
+    common::cleanup(&base);
+}
+
+// --------------------------------------------------------------------------
+// 91. --deep-scan  (range parsing pure, fast; the CLI run needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_parse_offset_range() {
+    use mabi_pack2::common::parse_offset_range;
+    assert_eq!(parse_offset_range("0..4096").unwrap(), 0..4096);
+    assert_eq!(parse_offset_range("0x400..0x10000").unwrap(), 0x400..0x10000);
+    assert_eq!(parse_offset_range(" 16 .. 32 ").unwrap(), 16..32);
+    assert!(parse_offset_range("4096").is_err());
+    assert!(parse_offset_range("10..10").unwrap_err().to_string().contains("empty"));
+    assert!(parse_offset_range("0..lots").is_err());
+}
+
+/// A header past the byte-by-byte part of `--scan-offsets` and off the 1 KiB boundaries
+/// is found only by a deep scan whose range covers it.
+#[test]
+fn test_deep_scan() {
+    let dir = common::temp_dir_for_test("deep_scan");
+    let work = common::temp_dir_for_test("deep_scan_packs");
+    common::cleanup(&dir);
+    common::cleanup(&work);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::create_dir_all(&work).unwrap();
+    std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+    let packed = work.join("data_00.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let mut shifted = vec![0u8; 5003];
+    shifted.extend_from_slice(&std::fs::read(&packed).unwrap());
+    std::fs::write(&packed, &shifted).unwrap();
+
+    let run = |args: &[&str]| {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2")).arg("--no-key-cache").args(args).output().unwrap();
+        let text = format!("{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+        (out.status.success(), text)
+    };
+    let (ok, text) = run(&["--scan-offsets", "list", "-i", path, "-k", KNOWN_SALT]);
+    assert!(!ok && text.contains("Stage 1/1 failed"), "{}", text);
+    let (ok, text) = run(&["--deep-scan=0..4096", "list", "-i", path, "-k", KNOWN_SALT]);
+    assert!(!ok && text.contains("scanning 4096 header offsets"), "{}", text);
+    let (ok, text) = run(&["--deep-scan=0x1000..0x2000", "list", "-i", path, "-k", KNOWN_SALT]);
+    assert!(ok && text.contains("SUCCESS in stage 1") && text.contains("a.txt"), "{}", text);
+    assert!(!run(&["--deep-scan=9..5", "list", "-i", path]).0);
+
+    common::cleanup(&dir);
+    common::cleanup(&work);
+}
+
+// --------------------------------------------------------------------------
+// 92. daemon requests  (protocol errors pure, fast; the pack requests need temp filesystem access)
+// --------------------------------------------------------------------------
+
+#[test]
+fn test_daemon_protocol_errors() {
+    use mabi_pack2::daemon::{Daemon, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, PARSE_ERROR, UNAUTHORIZED};
+
+    let daemon = Daemon::new(Vec::new(), Default::default(), "secret".to_string());
+    let call = |line: &str| serde_json::from_str::<serde_json::Value>(&daemon.handle_line(line).unwrap()).unwrap();
+    let pong = call(r#"{"jsonrpc":"2.0","id":1,"token":"secret","method":"ping"}"#);
+    assert_eq!((pong["id"].as_i64(), pong["result"]["open_packs"].as_u64()), (Some(1), Some(0)));
+    assert_eq!(call("not json")["error"]["code"].as_i64(), Some(PARSE_ERROR));
+    assert_eq!(call(r#"{"id":5,"token":"secret","method":"ping"}"#)["error"]["code"].as_i64(), Some(INVALID_REQUEST));
+    assert_eq!(call(r#"{"jsonrpc":"2.0","id":6,"token":"secret"}"#)["error"]["code"].as_i64(), Some(INVALID_REQUEST));
+    assert_eq!(call(r#"{"jsonrpc":"2.0","id":7,"method":"ping"}"#)["error"]["code"].as_i64(), Some(UNAUTHORIZED));
+    assert_eq!(call(r#"{"jsonrpc":"2.0","id":8,"token":"secreT","method":"ping"}"#)["error"]["code"].as_i64(), Some(UNAUTHORIZED));
+    assert_eq!(call(r#"{"jsonrpc":"2.0","id":2,"token":"secret","method":"bogus"}"#)["error"]["code"].as_i64(), Some(METHOD_NOT_FOUND));
+    assert_eq!(call(r#"{"jsonrpc":"2.0","id":3,"token":"secret","method":"list","params":{}}"#)["error"]["code"].as_i64(), Some(INVALID_PARAMS));
+    assert!(daemon.handle_line(r#"{"jsonrpc":"2.0","token":"secret","method":"ping"}"#).is_none());
+    assert!(!daemon.stopping());
+    call(r#"{"jsonrpc":"2.0","id":9,"method":"shutdown"}"#);
+    assert!(!daemon.stopping());
+    call(r#"{"jsonrpc":"2.0","id":4,"token":"secret","method":"shutdown"}"#);
+    assert!(daemon.stopping());
+}
+
+/// A connection is closed at its first rejected line, so the JSON body of an HTTP request
+/// (a web page posting to the TCP port) is never run.
+#[test]
+fn test_daemon_connection_closes_on_rejected_line() {
+    use mabi_pack2::daemon::{Daemon, PARSE_ERROR, UNAUTHORIZED};
+    use std::io::{Cursor, Read, Write};
+
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> { self.input.read(buf) }
+    }
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.output.write(buf) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+    let responses = |daemon: &Daemon, input: &str| {
+        let mut conn = Duplex { input: Cursor::new(input.as_bytes().to_vec()), output: Vec::new() };
+        daemon.serve_connection(&mut conn).unwrap();
+        String::from_utf8(conn.output).unwrap().lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let daemon = Daemon::new(Vec::new(), Default::default(), "secret".to_string());
+    let http = "POST / HTTP/1.1\r\nHost: 127.0.0.1:7878\r\nContent-Type: text/plain\r\n\r\n\
+        {\"jsonrpc\":\"2.0\",\"id\":1,\"token\":\"secret\",\"method\":\"shutdown\"}\n";
+    let got = responses(&daemon, http);
+    assert_eq!(got.len(), 1);
+    assert_eq!(got[0]["error"]["code"].as_i64(), Some(PARSE_ERROR));
+    assert!(!daemon.stopping());
+
+    let wrong = "{\"jsonrpc\":\"2.0\",\"id\":1,\"token\":\"guess\",\"method\":\"ping\"}\n\
+        {\"jsonrpc\":\"2.0\",\"id\":2,\"token\":\"secret\",\"method\":\"shutdown\"}\n";
+    let got = responses(&daemon, wrong);
+    assert_eq!(got.len(), 1);
+    assert_eq!(got[0]["error"]["code"].as_i64(), Some(UNAUTHORIZED));
+    assert!(!daemon.stopping());
+
+    let good = "{\"jsonrpc\":\"2.0\",\"id\":1,\"token\":\"secret\",\"method\":\"ping\"}\n\
+        {\"jsonrpc\":\"2.0\",\"id\":2,\"token\":\"secret\",\"method\":\"bogus\"}\n\
+        {\"jsonrpc\":\"2.0\",\"id\":3,\"token\":\"secret\",\"method\":\"ping\"}\n";
+    assert_eq!(responses(&daemon, good).len(), 3);
+}
+
+/// The token file holds a fresh 64-digit hex token, readable by its owner only.
+#[test]
+fn test_daemon_token_file() {
+    use mabi_pack2::daemon::{new_token, write_token};
+
+    let dir = common::temp_dir_for_test("daemon_token");
+    common::cleanup(&dir);
+    let path = dir.join("sub").join("daemon.token");
+    let token = new_token().unwrap();
+    assert_eq!(token.len(), 64);
+    assert!(token.bytes().all(|b| b.is_ascii_hexdigit()));
+    assert_ne!(token, new_token().unwrap());
+    write_token(&path, "old").unwrap();
+    write_token(&path, &token).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), token);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    common::cleanup(&dir);
+}
+
+/// Packs are opened once and reused until closed or rewritten; pack, list and extract
+/// round-trip a folder.
+#[test]
+fn test_daemon_pack_list_extract() {
+    use mabi_pack2::daemon::Daemon;
+    use serde_json::json;
+
+    let base = common::temp_dir_for_test("daemon");
+    common::cleanup(&base);
+    std::fs::create_dir_all(base.join("in").join("db")).unwrap();
+    std::fs::write(base.join("in").join("db").join("a.xml"), "<a/>").unwrap();
+    std::fs::write(base.join("in").join("b.txt"), "b").unwrap();
+    let pack = base.join("data_00.it");
+    let pack = pack.to_str().unwrap();
+    let out = base.join("out");
+
+    let daemon = Daemon::new(vec![KNOWN_SALT.to_string()], Default::default(), "secret".to_string());
+    let call = |method: &str, params: serde_json::Value| {
+        let line = json!({ "jsonrpc": "2.0", "id": 1, "token": "secret", "method": method, "params": params }).to_string();
+        let response: serde_json::Value = serde_json::from_str(&daemon.handle_line(&line).unwrap()).unwrap();
+        assert!(response.get("error").is_none(), "{}", response);
+        response["result"].clone()
+    };
+    assert_eq!(call("pack", json!({ "input": base.join("in").to_str().unwrap(), "output": pack, "key": KNOWN_SALT }))["entries"], 2);
+    let listed = call("list", json!({ "pack": pack, "filters": ["glob:db/"] }));
+    assert_eq!(listed["entries"].as_array().unwrap().len(), 1);
+    assert_eq!(listed["entries"][0]["name"], "db/a.xml");
+    assert_eq!(daemon.open_packs(), 1);
+    assert_eq!(call("extract", json!({ "pack": pack, "output": out.to_str().unwrap() }))["entries"], 2);
+    assert_eq!(daemon.open_packs(), 1);
+    assert_eq!(std::fs::read_to_string(out.join("db").join("a.xml")).unwrap(), "<a/>");
+    assert_eq!(call("close", json!({ "pack": pack }))["closed"], true);
+    assert_eq!(daemon.open_packs(), 0);
+
+    common::cleanup(&base);
+}
+
+/// The daemon only listens on loopback addresses.
+#[test]
+fn test_daemon_refuses_non_loopback() {
+    use mabi_pack2::daemon::{serve, Daemon};
+
+    let daemon = std::sync::Arc::new(Daemon::new(Vec::new(), Default::default(), "secret".to_string()));
+    for addr in ["0.0.0.0:0", "192.168.1.10:7878", "[::]:0"] {
+        let err = serve(daemon.clone(), addr).unwrap_err();
+        assert!(err.to_string().contains("loopback"), "{}: {}", addr, err);
+    }
+}
+
+/// A `--socket` path holding anything but a socket is refused and left alone.
+#[cfg(unix)]
+#[test]
+fn test_daemon_socket_keeps_regular_file() {
+    use mabi_pack2::daemon::{serve, Daemon};
+
+    let dir = common::temp_dir_for_test("daemon_socket");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("important.txt");
+    std::fs::write(&file, "keep me").unwrap();
+    let daemon = std::sync::Arc::new(Daemon::new(Vec::new(), Default::default(), "secret".to_string()));
+    let err = serve(daemon, file.to_str().unwrap()).unwrap_err();
+    assert!(err.to_string().contains("not a socket"), "{}", err);
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 93. --wait-for-data  (settings fast; CLI run needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Short data only blocks when the settings ask for it.
+#[test]
+fn test_wait_for_len_settings() {
+    use mabi_pack2::common::{wait_for_len, PackSettings};
+    let mut rd = std::io::Cursor::new(vec![0u8; 16]);
+    assert!(wait_for_len(&mut rd, 64, "x", &PackSettings::default()).is_ok());
+    assert!(wait_for_len(&mut rd, 16, "x", &PackSettings::default()).is_ok());
+    let waiting = PackSettings { wait_for_data: Some(std::time::Duration::ZERO), ..PackSettings::default() };
+    assert!(wait_for_len(&mut rd, 16, "x", &waiting).is_ok());
+    assert!(wait_for_len(&mut rd, 64, "x", &waiting).unwrap_err().to_string().contains("stopped growing"));
+}
+
+/// Entry data still being downloaded is waited for; a download that stalls fails the
+/// entry once the idle time is up.
+#[test]
+fn test_wait_for_data() {
+    let dir = common::temp_dir_for_test("wait_for_data");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("in")).unwrap();
+    let big: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    std::fs::write(dir.join("in").join("big.bin"), &big).unwrap();
+    let packed = dir.join("data_00.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.join("in").to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let full = std::fs::read(&packed).unwrap();
+    let head = mabi_pack2::reader::PackReader::open(path, Some(KNOWN_SALT.to_string()), &[]).unwrap().content_offset as usize + 1024;
+
+    let extract = |out: &str, wait: &str| std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2"))
+        .args(["--no-key-cache", wait, "extract", "-i", path, "-k", KNOWN_SALT, "-o", out])
+        .output().unwrap();
+
+    std::fs::write(&packed, &full[..head]).unwrap();
+    let out = dir.join("out");
+    let child = std::thread::scope(|scope| {
+        let run = scope.spawn(|| extract(out.to_str().unwrap(), "--wait-for-data=10"));
+        std::thread::sleep(std::time::Duration::from_millis(700));
+        std::io::Write::write_all(&mut std::fs::OpenOptions::new().append(true).open(&packed).unwrap(), &full[head..]).unwrap();
+        run.join().unwrap()
+    });
+    assert!(child.status.success(), "{}", String::from_utf8_lossy(&child.stderr));
+    assert_eq!(std::fs::read(out.join("big.bin")).unwrap(), big);
+
+    std::fs::write(&packed, &full[..head]).unwrap();
+    let stalled = extract(dir.join("stalled").to_str().unwrap(), "--wait-for-data=1");
+    let text = format!("{}{}", String::from_utf8_lossy(&stalled.stdout), String::from_utf8_lossy(&stalled.stderr));
+    assert!(text.contains("stopped growing"), "{}", text);
+    assert!(!dir.join("stalled").join("big.bin").exists());
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 94. pack --level / --store  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// Level 0 stores compressible entries raw, level 9 compresses them and records the
+/// level in the metadata; both read back unchanged, and levels above 9 are refused.
+#[test]
+fn test_pack_level() {
+    use mabi_pack2::pack::{run_pack_with_options, PackOptions};
+    use mabi_pack2::reader::PackReader;
+
+    let dir = common::temp_dir_for_test("pack_level");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("in")).unwrap();
+    let text = "<level>compress me</level>\n".repeat(500);
+    std::fs::write(dir.join("in").join("a.xml"), &text).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+
+    for (level, compressed) in [(0, false), (9, true)] {
+        let out = dir.join(format!("level_{}.it", level));
+        let opts = PackOptions { level, ..PackOptions::default() };
+        run_pack_with_options(dir.join("in").to_str().unwrap(), out.to_str().unwrap(), KNOWN_SALT, &opts, None).unwrap();
+        let reader = PackReader::open(out.to_str().unwrap(), None, &salts).unwrap();
+        let ent = reader.find("a.xml").unwrap();
+        assert_eq!(ent.flags & mabi_pack2::common::FLAG_COMPRESSED != 0, compressed, "level {}", level);
+        assert_eq!(reader.read_entry(ent).unwrap(), text.as_bytes());
+        assert_eq!(reader.metadata().unwrap().level, Some(level));
+    }
+
+    let opts = PackOptions { level: 10, ..PackOptions::default() };
+    let err = run_pack_with_options(dir.join("in").to_str().unwrap(), dir.join("bad.it").to_str().unwrap(), KNOWN_SALT, &opts, None).unwrap_err();
+    assert!(err.to_string().contains("out of range"), "{}", err);
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 95. Incompressible entries stored as is  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// An entry zlib can't shrink by `MIN_SAVING_PERCENT` is stored without
+/// FLAG_COMPRESSED even though its extension asks for compression.
+#[test]
+fn test_pack_skips_useless_compression() {
+    use mabi_pack2::common::FLAG_COMPRESSED;
+    use mabi_pack2::reader::PackReader;
+
+    let dir = common::temp_dir_for_test("useless_compression");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("in")).unwrap();
+    let mut seed = 0x2545_f491u32;
+    let noise: Vec<u8> = (0..20_000).map(|_| { seed ^= seed << 13; seed ^= seed >> 17; seed ^= seed << 5; seed as u8 }).collect();
+    let text = "<root>squeeze</root>\n".repeat(200);
+    std::fs::write(dir.join("in").join("noise.dds"), &noise).unwrap();
+    std::fs::write(dir.join("in").join("text.xml"), &text).unwrap();
+    let packed = dir.join("out.it");
+    mabi_pack2::pack::run_pack(dir.join("in").to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+
+    let reader = PackReader::open(packed.to_str().unwrap(), None, &[KNOWN_SALT.to_string()]).unwrap();
+    let ent = reader.find("noise.dds").unwrap();
+    assert_eq!(ent.flags & FLAG_COMPRESSED, 0);
+    assert_eq!(ent.raw_size as usize, noise.len());
+    assert_eq!(reader.read_entry(ent).unwrap(), noise);
+    let ent = reader.find("text.xml").unwrap();
+    assert_ne!(ent.flags & FLAG_COMPRESSED, 0);
+    assert_eq!(reader.read_entry(ent).unwrap(), text.as_bytes());
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 96. Streaming extraction of large entries  (needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// With a low `stream_threshold`, compressed, stored, head- and fully-encrypted entries
+/// are streamed to disk and come out identical to the input.
+#[test]
+fn test_extract_streams_large_entries() {
+    use mabi_pack2::common::{FLAG_ALL_ENCRYPTED, FLAG_COMPRESSED, FLAG_HEAD_ENCRYPTED};
+    use mabi_pack2::extract::{run_extract_with_options, ExtractOptions};
+
+    let dir = common::temp_dir_for_test("stream_extract");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("in")).unwrap();
+    let text = "<stream>a large entry</stream>\n".repeat(20_000);
+    let mut seed = 0x9e37_79b9u32;
+    let noise: Vec<u8> = (0..300_001).map(|_| { seed ^= seed << 13; seed ^= seed >> 17; seed ^= seed << 5; seed as u8 }).collect();
+    for name in ["plain.xml", "head.xml"] {
+        std::fs::write(dir.join("in").join(name), &text).unwrap();
+    }
+    for name in ["plain.bin", "all.bin"] {
+        std::fs::write(dir.join("in").join(name), &noise).unwrap();
+    }
+    let packed = dir.join("data_00.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.join("in").to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let salts = vec![KNOWN_SALT.to_string()];
+    mabi_pack2::edit::run_set_flags(path, None, None, &salts, &mabi_pack2::common::PackSettings::default(), vec![r"head\.xml".to_string()], FLAG_HEAD_ENCRYPTED, 0).unwrap();
+    mabi_pack2::edit::run_set_flags(path, None, None, &salts, &mabi_pack2::common::PackSettings::default(), vec![r"all\.bin".to_string()], FLAG_ALL_ENCRYPTED, 0).unwrap();
+    let reader = mabi_pack2::reader::PackReader::open(path, None, &salts).unwrap();
+    assert_eq!(reader.find("head.xml").unwrap().flags, FLAG_COMPRESSED | FLAG_HEAD_ENCRYPTED);
+    assert_eq!(reader.find("all.bin").unwrap().flags, FLAG_ALL_ENCRYPTED);
+    drop(reader);
+
+    let out = dir.join("out");
+    let opts = ExtractOptions { stream_threshold: Some(1024), ..ExtractOptions::default() };
+    let summary = run_extract_with_options(path, out.to_str().unwrap(), None, &salts, &opts).unwrap();
+    assert_eq!((summary.entries, summary.failed), (4, 0), "{:?}", summary.warnings);
+    for name in ["plain.xml", "head.xml"] {
+        assert_eq!(std::fs::read(out.join(name)).unwrap(), text.as_bytes(), "{}", name);
+    }
+    for name in ["plain.bin", "all.bin"] {
+        assert_eq!(std::fs::read(out.join(name)).unwrap(), noise, "{}", name);
+    }
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 97. --as-name for renamed packs on disk  (runs the CLI; needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// A pack renamed beyond guessing fails to open, and `list`, `verify` and `extract`
+/// open it with `--as-name` set to the name it was packed under.
+#[test]
+fn test_as_name_renamed_pack() {
+    let dir = common::temp_dir_for_test("as_name");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("in")).unwrap();
+    std::fs::write(dir.join("in").join("r.xml"), b"<renamed/>").unwrap();
+    let packed = dir.join("data_00.it");
+    mabi_pack2::pack::run_pack(dir.join("in").to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let renamed = dir.join("backup_200_full.it.bak");
+    std::fs::rename(&packed, &renamed).unwrap();
+    let path = renamed.to_str().unwrap();
+
+    let run = |args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2"))
+        .args(["--no-key-cache"]).args(args)
+        .output().unwrap();
+    assert!(!run(&["list", "-i", path, "-k", KNOWN_SALT]).status.success());
+
+    let listed = run(&["list", "-i", path, "-k", KNOWN_SALT, "--as-name", "data_00.it"]);
+    assert!(listed.status.success(), "{}", String::from_utf8_lossy(&listed.stderr));
+    assert!(String::from_utf8_lossy(&listed.stdout).contains("r.xml"));
+    let verified = run(&["verify", "-i", path, "-k", KNOWN_SALT, "--as-name", "data_00.it"]);
+    assert!(verified.status.success(), "{}", String::from_utf8_lossy(&verified.stderr));
+    let out = dir.join("out");
+    let extracted = run(&["extract", "-i", path, "-k", KNOWN_SALT, "--as-name", "data_00.it", "-o", out.to_str().unwrap()]);
+    assert!(extracted.status.success(), "{}", String::from_utf8_lossy(&extracted.stderr));
+    assert_eq!(std::fs::read(out.join("r.xml")).unwrap(), b"<renamed/>");
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 98. Every bad entries table checksum, and --force  (pure part fast; the CLI part
+//     needs --features testing and temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// `validate_all_entries` lists every bad record with its index and both sums, while
+/// `validate_entries` still stops at the first.
+#[test]
+fn test_validate_all_entries() {
+    use mabi_pack2::common::{entries_accepted, validate_all_entries, validate_entries, FileEntry, PackSettings};
+
+    let settings = PackSettings::default();
+    let mut entries: Vec<FileEntry> = (0..4u32)
+        .map(|i| FileEntry { name: format!("e{}.xml", i), checksum: 0, flags: 1, offset: i, original_size: 10 + i, raw_size: 8, key: [i as u8; 16] })
+        .collect();
+    for ent in entries.iter_mut() {
+        ent.checksum = ent.calc_checksum();
+    }
+    assert!(validate_entries(&entries, &settings).is_ok());
+    assert!(validate_all_entries(&entries, &settings).is_empty());
+    let md5 = PackSettings { entry_checksum: Some(mabi_pack2::checksum::from_spec("md5").unwrap()), ..PackSettings::default() };
+    assert_eq!(validate_all_entries(&entries, &md5).len(), 4);
+
+    entries[1].checksum ^= 0x10;
+    entries[3].checksum = 7;
+    let failures = validate_all_entries(&entries, &settings);
+    assert_eq!(failures.iter().map(|f| (f.index, f.name.as_str())).collect::<Vec<_>>(), vec![(1, "e1.xml"), (3, "e3.xml")]);
+    assert_eq!((failures[1].expected, failures[1].actual), (7, entries[3].calc_checksum()));
+    assert!(failures[0].to_string().starts_with("entry 1 checksum"));
+    assert!(validate_entries(&entries, &settings).unwrap_err().to_string().contains("e1.xml"));
+
+    // --force takes a table in which most records pass.
+    let forced = PackSettings { force: true, ..PackSettings::default() };
+    assert!(!entries_accepted(&entries, &forced));
+    entries[3].checksum = entries[3].calc_checksum();
+    assert!(!entries_accepted(&entries, &settings));
+    assert!(entries_accepted(&entries, &forced));
+}
+
+/// A pack with a minority of bad records only opens with `--force`; `verify` then
+/// reports each of them and `extract` still writes every entry.
+#[cfg(feature = "testing")]
+#[test]
+fn test_force_bad_entry_checksums() {
+    use mabi_pack2::testing::{Corruption, PackBuilder};
+
+    let dir = common::temp_dir_for_test("force_entries");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let pack = (0..5).fold(PackBuilder::new("force_00.it", KNOWN_SALT), |b, i| b.entry(&format!("db/{}.xml", i), format!("<e{}/>", i)))
+        .build().unwrap()
+        .corrupted(Corruption::EntryChecksum(1)).unwrap()
+        .corrupted(Corruption::EntryChecksum(3)).unwrap();
+    let path = pack.write_to(&dir).unwrap();
+    let path = path.to_str().unwrap();
+    let run = |args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2"))
+        .args(["--no-key-cache"]).args(args)
+        .output().unwrap();
+
+    let plain = run(&["verify", "-i", path, "-k", KNOWN_SALT]);
+    assert!(String::from_utf8_lossy(&plain.stdout).contains("cannot open"));
+    let forced = run(&["--force", "verify", "-i", path, "-k", KNOWN_SALT]);
+    let stdout = String::from_utf8_lossy(&forced.stdout);
+    assert!(!forced.status.success());
+    assert!(stdout.contains("db/1.xml: entry 1 checksum") && stdout.contains("db/3.xml: entry 3 checksum"), "{}", stdout);
+    assert!(stdout.contains("FAIL") && stdout.contains("(2 of 5 entries)"), "{}", stdout);
+
+    let out = dir.join("out");
+    let extracted = run(&["--force", "extract", "-i", path, "-k", KNOWN_SALT, "-o", out.to_str().unwrap()]);
+    assert!(extracted.status.success(), "{}", String::from_utf8_lossy(&extracted.stderr));
+    for i in 0..5 {
+        assert_eq!(std::fs::read_to_string(out.join("db").join(format!("{}.xml", i))).unwrap(), format!("<e{}/>", i));
+    }
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 99. extract --manifest-only  (runs the CLI; needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// The manifest gives every entry's layout as the reader sees it, keys only with
+/// `--with-keys`, and is written even when the data region is garbage.
+#[test]
+fn test_extract_manifest_only() {
+    use mabi_pack2::reader::PackReader;
+
+    let dir = common::temp_dir_for_test("manifest_only");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("in").join("db")).unwrap();
+    std::fs::write(dir.join("in").join("db").join("a.xml"), "<a/>".repeat(300)).unwrap();
+    std::fs::write(dir.join("in").join("b.bin"), vec![3u8; 5000]).unwrap();
+    let packed = dir.join("data_00.it");
+    let path = packed.to_str().unwrap();
+    mabi_pack2::pack::run_pack(dir.join("in").to_str().unwrap(), path, KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let reader = PackReader::open(path, Some(KNOWN_SALT.to_string()), &[]).unwrap();
+    let expected: Vec<(String, u64, u32, u32)> = reader.entries().iter()
+        .map(|e| (e.name.clone(), reader.content_offset + e.offset as u64 * 1024, e.raw_size, e.flags))
+        .collect();
+    let (start, end) = (reader.content_offset as usize, reader.content_end() as usize);
+    drop(reader);
+    let mut bytes = std::fs::read(&packed).unwrap();
+    bytes[start..end].fill(0xA5);
+    std::fs::write(&packed, &bytes).unwrap();
+
+    let run = |args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2"))
+        .args(["--no-key-cache", "extract", "-i", path, "-k", KNOWN_SALT, "--manifest-only"]).args(args)
+        .output().unwrap();
+    let out = dir.join("manifest.json");
+    let written = run(&["-o", out.to_str().unwrap()]);
+    assert!(written.status.success(), "{}", String::from_utf8_lossy(&written.stderr));
+    let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&out).unwrap()).unwrap();
+    assert_eq!(manifest["header_salt"], KNOWN_SALT);
+    let entries: Vec<(String, u64, u32, u32)> = manifest["entries"].as_array().unwrap().iter()
+        .map(|e| (e["name"].as_str().unwrap().to_string(), e["data_offset"].as_u64().unwrap(), e["raw_size"].as_u64().unwrap() as u32, e["flags"].as_u64().unwrap() as u32))
+        .collect();
+    assert_eq!(entries, expected);
+    assert!(manifest["entries"][0].get("entry_key").is_none());
+    assert!(!dir.join("data_00").exists());
+
+    let piped = run(&["--with-keys", "-f", r"\.xml$"]);
+    assert!(piped.status.success(), "{}", String::from_utf8_lossy(&piped.stderr));
+    let manifest: serde_json::Value = serde_json::from_slice(&piped.stdout).unwrap();
+    assert_eq!(manifest["entries"].as_array().unwrap().len(), 1);
+    assert_eq!(manifest["entries"][0]["file_key"].as_str().unwrap().len(), 32);
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 100. Remote packs over HTTP Range requests  (runs the CLI against a local
+//      server; needs temp filesystem and loopback access)
+// --------------------------------------------------------------------------
+
+/// Serve `bytes` at any path on a loopback port, answering `Range` requests with 206.
+/// Returns the base URL and the number of body bytes sent so far.
+fn serve_ranges(bytes: Vec<u8>) -> (String, std::sync::Arc<std::sync::atomic::AtomicU64>) {
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::atomic::Ordering;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let sent = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let counter = sent.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut range = None;
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 0) && line != "\r\n" {
+                if let Some(spec) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                    let (from, to) = spec.trim().split_once('-').unwrap();
+                    range = Some((from.parse::<usize>().unwrap(), to.parse::<usize>().unwrap().min(bytes.len() - 1)));
+                }
+                line.clear();
+            }
+            let mut stream = stream;
+            let (head, body) = match range {
+                Some((from, to)) => (format!("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n", from, to, bytes.len()), &bytes[from..=to]),
+                None => ("HTTP/1.1 200 OK\r\n".to_string(), &bytes[..]),
+            };
+            let _ = write!(stream, "{}Content-Length: {}\r\nConnection: close\r\n\r\n", head, body.len());
+            if stream.write_all(body).is_ok() {
+                counter.fetch_add(body.len() as u64, Ordering::Relaxed);
+            }
+        }
+    });
+    (url, sent)
+}
+
+/// Listing and extracting a pack by URL downloads the header, entries table and the
+/// selected entries, not the large entry left out.
+#[test]
+fn test_remote_pack_ranges() {
+    use std::sync::atomic::Ordering;
+
+    let dir = common::temp_dir_for_test("remote_pack");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("in").join("db")).unwrap();
+    std::fs::write(dir.join("in").join("db").join("a.xml"), "<a/>".repeat(300)).unwrap();
+    let mut noise = Vec::with_capacity(2 << 20);
+    let mut x = 0x1234_5678u32;
+    while noise.len() < 2 << 20 {
+        x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        noise.push((x >> 24) as u8);
+    }
+    std::fs::write(dir.join("in").join("big.bin"), &noise).unwrap();
+    let packed = dir.join("data_00.it");
+    mabi_pack2::pack::run_pack(dir.join("in").to_str().unwrap(), packed.to_str().unwrap(), KNOWN_SALT, vec![], false, 0, None, None).unwrap();
+    let (base, sent) = serve_ranges(std::fs::read(&packed).unwrap());
+    let url = format!("{}/packs/data_00.it", base);
+
+    let run = |args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2"))
+        .arg("--no-key-cache").args(args).output().unwrap();
+    let listed = run(&["list", "-i", &url, "-k", KNOWN_SALT]);
+    assert!(listed.status.success(), "{}", String::from_utf8_lossy(&listed.stderr));
+    let names = String::from_utf8_lossy(&listed.stdout);
+    assert!(names.contains("db/a.xml") && names.contains("big.bin"), "{}", names);
+
+    let out = dir.join("out");
+    let extracted = run(&["extract", "-i", &url, "-k", KNOWN_SALT, "-f", r"\.xml$", "-o", out.to_str().unwrap()]);
+    assert!(extracted.status.success(), "{}", String::from_utf8_lossy(&extracted.stderr));
+    assert_eq!(std::fs::read_to_string(out.join("db").join("a.xml")).unwrap(), "<a/>".repeat(300));
+    assert!(!out.join("big.bin").exists());
+    assert!(sent.load(Ordering::Relaxed) < 1 << 20, "{} bytes downloaded", sent.load(Ordering::Relaxed));
+
+    let offline = run(&["--untrusted", "list", "-i", &url, "-k", KNOWN_SALT]);
+    assert!(!offline.status.success());
+    let untrusted = mabi_pack2::common::PackSettings { untrusted: true, ..Default::default() };
+    let before = sent.load(Ordering::Relaxed);
+    assert!(mabi_pack2::remote::RemotePack::open(&url, None, &untrusted).is_err());
+    assert_eq!(sent.load(Ordering::Relaxed), before);
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 101. --json-summary  (runs the CLI; needs temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// The run summary records the outcome, counts, bytes and the key that opened the pack,
+/// and is still written when the run fails.
+#[test]
+fn test_json_summary() {
+    let dir = common::temp_dir_for_test("json_summary");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(dir.join("in").join("db")).unwrap();
+    std::fs::write(dir.join("in").join("db").join("a.xml"), "<a/>".repeat(300)).unwrap();
+    std::fs::write(dir.join("in").join("b.txt"), "hello").unwrap();
+    let packed = dir.join("data_00.it");
+    let path = packed.to_str().unwrap();
+    let summary_path = dir.join("summary.json");
+    let flag = format!("--json-summary={}", summary_path.display());
+    let run = |args: &[&str]| {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2")).args(["--no-key-cache", &flag]).args(args).output().unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(&std::fs::read(&summary_path).unwrap()).unwrap();
+        (out, summary)
+    };
+
+    let (out, summary) = run(&["pack", "-i", dir.join("in").to_str().unwrap(), "-o", path, "-k", KNOWN_SALT]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(summary["command"], "pack");
+    assert_eq!(summary["entries"], 2);
+    assert_eq!(summary["bytes_read"], 1205);
+    assert_eq!(summary["bytes_written"].as_u64().unwrap(), std::fs::metadata(&packed).unwrap().len());
+
+    let (out, summary) = run(&["extract", "-i", path, "-k", KNOWN_SALT, "-o", dir.join("out").to_str().unwrap()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(summary["success"], true);
+    assert!(summary.get("error").is_none());
+    assert_eq!(summary["entries"], 2);
+    assert_eq!(summary["bytes_written"], 1205);
+    assert_eq!(summary["bytes_read"].as_u64().unwrap(), std::fs::metadata(&packed).unwrap().len());
+    assert!(summary["salts_tried"].as_u64().unwrap() >= 1);
+    assert_eq!(summary["keys"][0]["header_salt"], KNOWN_SALT);
+    assert_eq!(summary["keys"][0]["path"], path);
+    assert!(summary["elapsed_ms"].is_u64());
+
+    let (out, summary) = run(&["extract", "-i", dir.join("missing.it").to_str().unwrap(), "-k", KNOWN_SALT]);
+    assert!(!out.status.success());
+    assert_eq!(summary["success"], false);
+    assert!(!summary["error"].as_str().unwrap().is_empty());
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 102. extract --to-stdout with undecodable entries  (needs --features testing and
+//      temp filesystem access)
+// --------------------------------------------------------------------------
+
+/// An entry that fails to decode is left out of the stream and counted, and the CLI
+/// exits with an error instead of reporting success.
+#[cfg(feature = "testing")]
+#[test]
+fn test_extract_to_stdout_counts_failures() {
+    use mabi_pack2::common::FLAG_COMPRESSED;
+    use mabi_pack2::extract::{run_extract_to_writer, StreamFraming};
+    use mabi_pack2::testing::{Corruption, PackBuilder};
+
+    let dir = common::temp_dir_for_test("stdout_failures");
+    common::cleanup(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let pack = PackBuilder::new("data_00.it", KNOWN_SALT)
+        .entry_with_flags("db/a.xml", "<a/>".repeat(100), FLAG_COMPRESSED)
+        .entry("b.txt", "hello")
+        .build().unwrap()
+        .corrupted(Corruption::Payload(0)).unwrap();
+    let packed = pack.write_to(&dir).unwrap();
+    let path = packed.to_str().unwrap();
+
+    let mut out = Vec::new();
+    let summary = run_extract_to_writer(path, Some(KNOWN_SALT.to_string()), &[], &mabi_pack2::common::PackSettings::default(), vec![], vec![], StreamFraming::None, &mut out).unwrap();
+    assert_eq!((summary.entries, summary.failed), (1, 1));
+    assert_eq!(out, b"hello");
+
+    let run = std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2"))
+        .args(["--no-key-cache", "extract", "-i", path, "-k", KNOWN_SALT, "--to-stdout"])
+        .output().unwrap();
+    assert!(!run.status.success());
+    assert_eq!(run.stdout, b"hello");
+    assert!(String::from_utf8_lossy(&run.stderr).contains("1 entries failed to decode"));
+
+    common::cleanup(&dir);
+}
+
+// --------------------------------------------------------------------------
+// 103. Noun-verb commands after global options  (runs the CLI)
+// --------------------------------------------------------------------------
+
+/// Every global option that takes a separate value is skipped when finding the noun and verb.
+#[test]
+fn test_noun_verb_after_global_value_options() {
+    let run = |args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_mabi-pack2")).arg("--no-key-cache").args(args).output().unwrap();
+    for global in [&["--entry-checksum", "standard"][..], &["--original-name", "data_00.it"], &["--max-name-len", "512"]] {
+        let out = run(&[global, &["salt", "list"]].concat());
+        assert!(out.status.success(), "{:?}: {}", global, String::from_utf8_lossy(&out.stderr));
+        assert!(String::from_utf8_lossy(&out.stdout).contains("\tbuilt-in"), "{:?}", global);
+    }
+}